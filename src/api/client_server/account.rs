@@ -1,17 +1,33 @@
 use super::{DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
-use crate::{api::client_server, services, utils, Error, Result, Ruma};
+use crate::{
+    api::client_server, service::users::ThreepidBinding, services, utils, Authenticated, Error,
+    Result, Ruma,
+};
+use axum::{
+    extract::Query,
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use reqwest::Url;
 use ruma::{
     api::client::{
         account::{
-            change_password, deactivate, get_3pids, get_username_availability, register,
+            bind_3pid, change_password, deactivate, get_3pids, get_username_availability, register,
             request_3pid_management_token_via_email, request_3pid_management_token_via_msisdn,
-            whoami, ThirdPartyIdRemovalStatus,
+            request_registration_token_via_email, unbind_3pid, whoami, ThirdPartyIdRemovalStatus,
         },
         error::ErrorKind,
-        uiaa::{AuthFlow, AuthType, UiaaInfo},
+        uiaa::{AuthData, AuthFlow, AuthType, UiaaInfo},
     },
     events::{room::message::RoomMessageEventContent, GlobalAccountDataEventType},
-    push, UserId,
+    thirdparty::{Medium, ThirdPartyIdentifier},
+    CanonicalJsonObject, CanonicalJsonValue, MilliSecondsSinceUnixEpoch, OwnedSessionId,
+    OwnedUserId, UserId,
+};
+use serde::Deserialize;
+use serde_json::{
+    json,
+    value::{to_raw_value, RawValue as RawJsonValue},
 };
 use tracing::{info, warn};
 
@@ -32,38 +48,8 @@ const RANDOM_USER_ID_LENGTH: usize = 10;
 pub async fn get_register_available_route(
     body: Ruma<get_username_availability::v3::Request>,
 ) -> Result<get_username_availability::v3::Response> {
-    // Validate user id
-    let user_id = UserId::parse_with_server_name(
-        body.username.to_lowercase(),
-        services().globals.server_name(),
-    )
-    .ok()
-    .filter(|user_id| {
-        !user_id.is_historical() && user_id.server_name() == services().globals.server_name()
-    })
-    .ok_or(Error::BadRequest(
-        ErrorKind::InvalidUsername,
-        "Username is invalid.",
-    ))?;
-
-    // Check if username is creative enough
-    if services().users.exists(&user_id)? {
-        return Err(Error::BadRequest(
-            ErrorKind::UserInUse,
-            "Desired user ID is already taken.",
-        ));
-    }
-
-    if services()
-        .globals
-        .forbidden_usernames()
-        .is_match(user_id.localpart())
-    {
-        return Err(Error::BadRequest(
-            ErrorKind::Unknown,
-            "Username is forbidden.",
-        ));
-    }
+    // Validate user id and check if username is creative enough
+    new_local_user_id(&body.username)?;
 
     // TODO add check for appservice namespaces
 
@@ -85,6 +71,13 @@ pub async fn get_register_available_route(
 /// - Creates a new account and populates it with default account data
 /// - If `inhibit_login` is false: Creates a device and returns device id and access_token
 pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<register::v3::Response> {
+    if services().oidc.enabled() && !body.from_appservice {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Registration is handled by the authorization server.",
+        ));
+    }
+
     if !services().globals.allow_registration() && !body.from_appservice {
         info!("Registration disabled and request not from known appservice, rejecting registration attempt for username {:?}", body.username);
         return Err(Error::BadRequest(
@@ -117,41 +110,7 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
     }
 
     let user_id = match (&body.username, is_guest) {
-        (Some(username), false) => {
-            let proposed_user_id = UserId::parse_with_server_name(
-                username.to_lowercase(),
-                services().globals.server_name(),
-            )
-            .ok()
-            .filter(|user_id| {
-                !user_id.is_historical()
-                    && user_id.server_name() == services().globals.server_name()
-            })
-            .ok_or(Error::BadRequest(
-                ErrorKind::InvalidUsername,
-                "Username is invalid.",
-            ))?;
-
-            if services().users.exists(&proposed_user_id)? {
-                return Err(Error::BadRequest(
-                    ErrorKind::UserInUse,
-                    "Desired user ID is already taken.",
-                ));
-            }
-
-            if services()
-                .globals
-                .forbidden_usernames()
-                .is_match(proposed_user_id.localpart())
-            {
-                return Err(Error::BadRequest(
-                    ErrorKind::Unknown,
-                    "Username is forbidden.",
-                ));
-            }
-
-            proposed_user_id
-        }
+        (Some(username), false) => new_local_user_id(username)?,
         _ => loop {
             let proposed_user_id = UserId::parse_with_server_name(
                 utils::random_string(RANDOM_USER_ID_LENGTH).to_lowercase(),
@@ -193,6 +152,22 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
         skip_auth = body.from_appservice || is_guest;
     }
 
+    // Require a verified email address, which replaces the dummy stage
+    if services().globals.registration_requires_email() && !is_guest {
+        let stages = &mut uiaainfo.flows[0].stages;
+        stages.retain(|stage| *stage != AuthType::Dummy);
+        stages.push(AuthType::EmailIdentity);
+    }
+
+    // Require accepting the terms of service if any policies are configured
+    if !services().globals.terms_of_service().is_empty() {
+        uiaainfo.flows[0]
+            .stages
+            .push(AuthType::from("m.login.terms"));
+        uiaainfo.params = terms_of_service_params();
+    }
+
+    let mut email = None;
     if !skip_auth {
         if let Some(auth) = &body.auth {
             let (worked, uiaainfo) = services().uiaa.try_auth(
@@ -205,7 +180,11 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
             if !worked {
                 return Err(Error::Uiaa(uiaainfo));
             }
-        // Success!
+            // Success!
+            email = uiaainfo
+                .session
+                .as_deref()
+                .and_then(|session| services().email.take_uiaa_session_email(session));
         } else if let Some(json) = body.json_body {
             uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
             services().uiaa.create(
@@ -221,6 +200,21 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
         }
     }
 
+    if services().globals.registration_requires_email() && !skip_auth && !is_guest {
+        let Some(email) = &email else {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidAuthFailed,
+                "The email address has not been verified.",
+            ));
+        };
+        if services().users.find_user_by_email(email)?.is_some() {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidInUse,
+                "Email address is already in use.",
+            ));
+        }
+    }
+
     let password = if is_guest {
         None
     } else {
@@ -228,33 +222,18 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
     };
 
     // Create user
-    services().users.create(&user_id, password)?;
-
-    // Default to pretty displayname
-    let mut displayname = user_id.localpart().to_owned();
-
-    // If enabled append lightning bolt to display name (default true)
-    if services().globals.enable_lightning_bolt() {
-        displayname.push_str(" ⚡️");
+    let displayname = create_local_user(&user_id, password, None).await?;
+    if is_guest {
+        services().users.set_guest(&user_id)?;
     }
 
-    services()
-        .users
-        .set_displayname(&user_id, Some(displayname.clone()))
-        .await?;
+    if let Some(email) = &email {
+        services().users.set_email(&user_id, email)?;
+    }
 
-    // Initial account data
-    services().account_data.update(
-        None,
-        &user_id,
-        GlobalAccountDataEventType::PushRules.to_string().into(),
-        &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
-            content: ruma::events::push_rules::PushRulesEventContent {
-                global: push::Ruleset::server_default(&user_id),
-            },
-        })
-        .expect("to json always works"),
-    )?;
+    if !body.from_appservice && !is_guest {
+        welcome_local_user(&user_id);
+    }
 
     // Inhibit login does not work for guests
     if !is_guest && body.inhibit_login {
@@ -290,11 +269,15 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 
     // log in conduit admin channel if a non-guest user registered
     if !body.from_appservice && !is_guest {
+        let message = match &email {
+            Some(email) => format!(
+                "New user \"{user_id}\" registered on this server with email address {email}."
+            ),
+            None => format!("New user \"{user_id}\" registered on this server."),
+        };
         services()
             .admin
-            .send_message(RoomMessageEventContent::notice_plain(format!(
-                "New user \"{user_id}\" registered on this server."
-            )));
+            .send_message(RoomMessageEventContent::notice_plain(message));
     }
 
     // log in conduit admin channel if a guest registered
@@ -327,6 +310,185 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
     })
 }
 
+/// Returns the user ID for the username of a new local account.
+///
+/// Fails if the username is invalid, already taken or forbidden.
+pub(crate) fn new_local_user_id(username: &str) -> Result<OwnedUserId> {
+    let user_id =
+        UserId::parse_with_server_name(username.to_lowercase(), services().globals.server_name())
+            .ok()
+            .filter(|user_id| {
+                !user_id.is_historical()
+                    && user_id.server_name() == services().globals.server_name()
+            })
+            .ok_or(Error::BadRequest(
+                ErrorKind::InvalidUsername,
+                "Username is invalid.",
+            ))?;
+
+    if services().users.exists(&user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::UserInUse,
+            "Desired user ID is already taken.",
+        ));
+    }
+
+    if services()
+        .globals
+        .forbidden_usernames()
+        .is_match(user_id.localpart())
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Unknown,
+            "Username is forbidden.",
+        ));
+    }
+
+    Ok(user_id)
+}
+
+/// Creates a local account with a display name and the default push rules. Uses the configured
+/// display name for new users if none is given, and returns the display name that was set. Fails
+/// if the registration user limit has been reached, which is checked after UIAA so clients still
+/// see the registration flows.
+///
+/// The account is recorded as having accepted the current terms of service: interactive
+/// registrations include the terms stage, and accounts created without one (guests, shared-secret
+/// registration, JWT and OIDC logins) have no way to complete it.
+pub(crate) async fn create_local_user(
+    user_id: &UserId,
+    password: Option<&str>,
+    displayname: Option<String>,
+) -> Result<Option<String>> {
+    services().users.create_limited(user_id, password)?;
+    services().users.accept_current_terms(user_id)?;
+
+    let displayname = displayname.or_else(|| services().globals.new_user_displayname(user_id));
+    services()
+        .users
+        .set_displayname(user_id, displayname.clone())
+        .await?;
+
+    // Initial account data
+    services().account_data.update(
+        None,
+        user_id,
+        GlobalAccountDataEventType::PushRules.to_string().into(),
+        &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
+            content: ruma::events::push_rules::PushRulesEventContent {
+                global: services().globals.default_push_ruleset(user_id),
+            },
+        })
+        .expect("to json always works"),
+    )?;
+
+    Ok(displayname)
+}
+
+/// Joins a newly registered user to the auto-join rooms and sends them the welcome message in the
+/// background, so slow remote servers of auto-join rooms don't hold up the registration.
+pub(crate) fn welcome_local_user(user_id: &UserId) {
+    let user_id = user_id.to_owned();
+    tokio::spawn(async move {
+        client_server::auto_join_rooms(&user_id).await;
+
+        if let Err(e) = services().admin.send_welcome_message(&user_id).await {
+            warn!("Failed to send welcome message to {}: {}", user_id, e);
+        }
+    });
+}
+
+#[derive(Deserialize)]
+pub struct AcceptTermsRequest {
+    auth: Option<AuthData>,
+}
+
+/// # `POST /_conduwuit/client/v1/terms/accept`
+///
+/// Accepts the current version of every terms of service policy, e.g. after the server admins
+/// bumped the version of a policy.
+///
+/// - Requires UIAA with the `m.login.terms` stage, which lists the policies
+/// - Succeeds right away if the current terms were already accepted
+pub async fn accept_terms_route(
+    auth: Authenticated,
+    Json(body): Json<AcceptTermsRequest>,
+) -> Result<impl IntoResponse> {
+    let sender_user = &auth.sender_user;
+    let sender_device = auth.sender_device.as_deref().ok_or(Error::BadRequest(
+        ErrorKind::MissingToken,
+        "Accepting the terms of service requires a device.",
+    ))?;
+
+    if services().users.has_accepted_current_terms(sender_user)? {
+        return Ok(Json(json!({})));
+    }
+
+    let mut uiaainfo = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec![AuthType::from("m.login.terms")],
+        }],
+        completed: Vec::new(),
+        params: terms_of_service_params(),
+        session: None,
+        auth_error: None,
+    };
+
+    if let Some(auth) = &body.auth {
+        let (worked, uiaainfo) =
+            services()
+                .uiaa
+                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        if !worked {
+            return Err(Error::Uiaa(uiaainfo));
+        }
+    // Success!
+    } else {
+        uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        services().uiaa.create(
+            sender_user,
+            sender_device,
+            &uiaainfo,
+            &CanonicalJsonValue::Object(CanonicalJsonObject::new()),
+        )?;
+        return Err(Error::Uiaa(uiaainfo));
+    }
+
+    services().users.accept_current_terms(sender_user)?;
+    info!("{sender_user} accepted the current terms of service");
+
+    Ok(Json(json!({})))
+}
+
+/// Builds the `m.login.terms` UIAA params from the configured terms of service policies.
+fn terms_of_service_params() -> Box<RawJsonValue> {
+    let policies: serde_json::Map<String, serde_json::Value> = services()
+        .globals
+        .terms_of_service()
+        .iter()
+        .map(|(policy_id, policy)| {
+            let mut policy_json = serde_json::Map::new();
+            policy_json.insert("version".to_owned(), policy.version.clone().into());
+            policy_json.insert(
+                policy.language.clone(),
+                serde_json::json!({
+                    "name": policy.name,
+                    "url": policy.url,
+                }),
+            );
+
+            (policy_id.clone(), policy_json.into())
+        })
+        .collect();
+
+    to_raw_value(&serde_json::json!({
+        "m.login.terms": {
+            "policies": policies,
+        },
+    }))
+    .expect("terms of service params are valid json")
+}
+
 /// # `POST /_matrix/client/r0/account/password`
 ///
 /// Changes the password of this account.
@@ -466,6 +628,24 @@ pub async fn deactivate_route(
     // Make the user leave all rooms before deactivation
     client_server::leave_all_rooms(sender_user).await?;
 
+    // Make sure the user can't be found through identity servers anymore
+    let mut id_server_unbind_result = ThirdPartyIdRemovalStatus::Success;
+    for binding in services().users.threepid_bindings(sender_user)? {
+        if !unbind_from_identity_server(
+            sender_user,
+            &binding.medium,
+            &binding.address,
+            &binding.id_server,
+        )
+        .await
+        {
+            id_server_unbind_result = ThirdPartyIdRemovalStatus::NoSupport;
+        }
+        services()
+            .users
+            .remove_threepid_binding(sender_user, &binding.medium, &binding.address)?;
+    }
+
     // Remove devices and mark account as deactivated
     services().users.deactivate_account(sender_user)?;
 
@@ -477,7 +657,7 @@ pub async fn deactivate_route(
         )));
 
     Ok(deactivate::v3::Response {
-        id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
+        id_server_unbind_result,
     })
 }
 
@@ -485,13 +665,326 @@ pub async fn deactivate_route(
 ///
 /// Get a list of third party identifiers associated with this account.
 ///
-/// - Currently always returns empty list
+/// - Only contains the third party identifiers the user bound on an identity server
 pub async fn third_party_route(
     body: Ruma<get_3pids::v3::Request>,
 ) -> Result<get_3pids::v3::Response> {
-    let _sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let threepids = services()
+        .users
+        .threepid_bindings(sender_user)?
+        .into_iter()
+        .map(|binding| ThirdPartyIdentifier {
+            address: binding.address,
+            medium: binding.medium,
+            validated_at: binding.added_at,
+            added_at: binding.added_at,
+        })
+        .collect();
+
+    Ok(get_3pids::v3::Response::new(threepids))
+}
+
+/// Response of the bind endpoint of the identity server API
+#[derive(Deserialize)]
+struct IdentityServerBinding {
+    medium: Medium,
+    address: String,
+    mxid: OwnedUserId,
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/bind`
+///
+/// Binds a third party identifier that was validated by an identity server to the user, so other
+/// users can find them by it.
+///
+/// - Only the identity server set in the `identity_server` config option is allowed
+pub async fn bind_3pid_route(
+    body: Ruma<bind_3pid::v3::Request>,
+) -> Result<bind_3pid::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let base_url = identity_server_url(&body.id_server).ok_or(Error::BadRequest(
+        ErrorKind::ThreepidDenied,
+        "Binding third party identifiers on this identity server is not allowed.",
+    ))?;
+
+    let content = json!({
+        "sid": body.sid,
+        "client_secret": body.client_secret,
+        "mxid": sender_user,
+    });
+    let response = services()
+        .globals
+        .default_client()
+        .post(format!("{base_url}/_matrix/identity/v2/3pid/bind"))
+        .bearer_auth(&body.id_access_token)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(content.to_string())
+        .send()
+        .await
+        .map_err(|e| {
+            warn!("Failed to reach identity server {}: {e}", body.id_server);
+            Error::BadRequest(ErrorKind::Unknown, "Failed to reach the identity server.")
+        })?;
+
+    if !response.status().is_success() {
+        warn!(
+            "Identity server {} refused to bind a third party identifier to {sender_user}: {}",
+            body.id_server,
+            response.status()
+        );
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidAuthFailed,
+            "The identity server refused to bind the third party identifier.",
+        ));
+    }
+
+    let binding: IdentityServerBinding =
+        serde_json::from_str(&response.text().await?).map_err(|e| {
+            warn!(
+                "Invalid bind response from identity server {}: {e}",
+                body.id_server
+            );
+            Error::BadServerResponse("Invalid response from the identity server.")
+        })?;
+
+    if binding.mxid != *sender_user {
+        return Err(Error::BadServerResponse(
+            "The identity server bound the third party identifier to another user.",
+        ));
+    }
+
+    services().users.add_threepid_binding(
+        sender_user,
+        &ThreepidBinding {
+            medium: binding.medium,
+            address: binding.address,
+            id_server: body.id_server.clone(),
+            added_at: MilliSecondsSinceUnixEpoch::now(),
+        },
+    )?;
 
-    Ok(get_3pids::v3::Response::new(Vec::new()))
+    Ok(bind_3pid::v3::Response::new())
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/unbind`
+///
+/// Removes the binding of a third party identifier to the user from an identity server.
+///
+/// - Uses the identity server the identifier was bound on if the client doesn't specify one
+/// - Only contacts the identity server the identifier was bound on or the configured one
+pub async fn unbind_3pid_route(
+    body: Ruma<unbind_3pid::v3::Request>,
+) -> Result<unbind_3pid::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let bound_id_server = services()
+        .users
+        .threepid_bindings(sender_user)?
+        .into_iter()
+        .find(|binding| binding.medium == body.medium && binding.address == body.address)
+        .map(|binding| binding.id_server);
+
+    // Never contact a server the client chose freely, that would let it make us send signed
+    // requests anywhere
+    let id_server = match &body.id_server {
+        Some(id_server)
+            if bound_id_server.as_ref() == Some(id_server)
+                || identity_server_url(id_server).is_some() =>
+        {
+            Some(id_server.clone())
+        }
+        Some(_) => None,
+        None => bound_id_server,
+    };
+
+    let unbound = match id_server {
+        Some(id_server) => {
+            unbind_from_identity_server(sender_user, &body.medium, &body.address, &id_server).await
+        }
+        None => false,
+    };
+    let id_server_unbind_result = if unbound {
+        ThirdPartyIdRemovalStatus::Success
+    } else {
+        ThirdPartyIdRemovalStatus::NoSupport
+    };
+
+    services()
+        .users
+        .remove_threepid_binding(sender_user, &body.medium, &body.address)?;
+
+    Ok(unbind_3pid::v3::Response::new(id_server_unbind_result))
+}
+
+/// Returns the base URL of the configured identity server if `id_server` is its server name.
+fn identity_server_url(id_server: &str) -> Option<String> {
+    let identity_server = services().globals.config.identity_server.as_deref()?;
+    let url = Url::parse(identity_server).ok()?;
+
+    let server_name = match url.port() {
+        Some(port) => format!("{}:{port}", url.host_str()?),
+        None => url.host_str()?.to_owned(),
+    };
+
+    server_name
+        .eq_ignore_ascii_case(id_server)
+        .then(|| identity_server.trim_end_matches('/').to_owned())
+}
+
+/// Asks an identity server to remove the binding of a third party identifier to the user. The
+/// request is authenticated with the signing key of this server. Returns false if it failed.
+///
+/// `id_server` must be the configured identity server or the one stored with the binding, never
+/// one chosen by the client.
+async fn unbind_from_identity_server(
+    user_id: &UserId,
+    medium: &Medium,
+    address: &str,
+    id_server: &str,
+) -> bool {
+    const UNBIND_PATH: &str = "/_matrix/identity/v2/3pid/unbind";
+
+    let server_name = services().globals.server_name();
+    let base_url = identity_server_url(id_server).unwrap_or_else(|| format!("https://{id_server}"));
+
+    let content = json!({
+        "mxid": user_id,
+        "threepid": {
+            "medium": medium,
+            "address": address,
+        },
+    });
+
+    let mut request_json: CanonicalJsonObject = serde_json::from_value(json!({
+        "method": "POST",
+        "uri": UNBIND_PATH,
+        "origin": server_name,
+        "destination": id_server,
+        "content": content,
+    }))
+    .expect("valid JSON is valid BTreeMap");
+
+    ruma::signatures::sign_json(
+        server_name.as_str(),
+        services().globals.keypair(),
+        &mut request_json,
+    )
+    .expect("our request json is what ruma expects");
+
+    let request_json = serde_json::to_value(&request_json).expect("canonical json is valid json");
+    let authorization = request_json["signatures"][server_name.as_str()]
+        .as_object()
+        .and_then(|signatures| signatures.iter().next())
+        .and_then(|(key, sig)| {
+            Some(format!(
+                "X-Matrix origin={server_name},destination=\"{id_server}\",key=\"{key}\",sig=\"{}\"",
+                sig.as_str()?
+            ))
+        })
+        .expect("we just signed the request");
+
+    let response = services()
+        .globals
+        .default_client()
+        .post(format!("{base_url}{UNBIND_PATH}"))
+        .header(reqwest::header::AUTHORIZATION, authorization)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(content.to_string())
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => true,
+        Ok(response) => {
+            warn!(
+                "Identity server {id_server} refused to unbind a third party identifier of \
+                 {user_id}: {}",
+                response.status()
+            );
+            false
+        }
+        Err(e) => {
+            warn!("Failed to reach identity server {id_server}: {e}");
+            false
+        }
+    }
+}
+
+/// # `POST /_matrix/client/v3/register/email/requestToken`
+///
+/// Sends a link to the email address that verifies it for registration.
+///
+/// - Only works if registration requires a verified email address
+/// - 400 `M_THREEPID_IN_USE` if another account already uses the address
+/// - 429 `M_LIMIT_EXCEEDED` if the IP address or the server sent too many emails recently
+pub async fn request_registration_token_via_email_route(
+    body: Ruma<request_registration_token_via_email::v3::Request>,
+) -> Result<request_registration_token_via_email::v3::Response> {
+    if !services().globals.allow_registration() || !services().globals.registration_requires_email()
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidDenied,
+            "Registration with an email address is not enabled.",
+        ));
+    }
+
+    if let Some(next_link) = &body.next_link {
+        let valid =
+            Url::parse(next_link).map_or(false, |url| matches!(url.scheme(), "http" | "https"));
+        if !valid {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "next_link is not a valid URL.",
+            ));
+        }
+    }
+
+    let sid = services()
+        .email
+        .request_validation(
+            body.client_ip,
+            &body.email,
+            &body.client_secret,
+            body.send_attempt,
+            body.next_link.clone(),
+        )
+        .await?;
+
+    Ok(request_registration_token_via_email::v3::Response::new(
+        OwnedSessionId::try_from(sid).expect("random strings are valid session IDs"),
+    ))
+}
+
+/// Query parameters of the link in email address validation emails
+#[derive(Deserialize)]
+pub struct SubmitEmailTokenParams {
+    sid: String,
+    client_secret: String,
+    token: String,
+}
+
+/// # `GET /_matrix/client/unstable/registration/email/submit_token`
+///
+/// Verifies the email address of a validation session through the link that was sent to it.
+///
+/// - Redirects to the `next_link` the client sent with the request, if any
+pub async fn submit_registration_email_token_route(
+    Query(params): Query<SubmitEmailTokenParams>,
+) -> Result<Response> {
+    let next_link =
+        services()
+            .email
+            .submit_token(&params.sid, &params.client_secret, &params.token)?;
+
+    Ok(match next_link {
+        Some(next_link) => Redirect::to(&next_link).into_response(),
+        None => {
+            "Your email address is verified, you can go back to your client now.".into_response()
+        }
+    })
 }
 
 /// # `POST /_matrix/client/v3/account/3pid/email/requestToken`
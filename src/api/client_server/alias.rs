@@ -1,9 +1,7 @@
 use crate::{services, Error, Result, Ruma};
 use rand::seq::SliceRandom;
-use regex::Regex;
 use ruma::{
     api::{
-        appservice,
         client::{
             alias::{create_alias, delete_alias, get_alias},
             error::ErrorKind,
@@ -162,49 +160,9 @@ pub(crate) async fn get_alias_helper(
         return Ok(get_alias::v3::Response::new(room_id, servers));
     }
 
-    let mut room_id = None;
-    match services().rooms.alias.resolve_local_alias(&room_alias)? {
-        Some(r) => room_id = Some(r),
-        None => {
-            for (_id, registration) in services().appservice.all()? {
-                let aliases = registration
-                    .namespaces
-                    .aliases
-                    .iter()
-                    .filter_map(|alias| Regex::new(alias.regex.as_str()).ok())
-                    .collect::<Vec<_>>();
-
-                if aliases
-                    .iter()
-                    .any(|aliases| aliases.is_match(room_alias.as_str()))
-                    && if let Some(opt_result) = services()
-                        .sending
-                        .send_appservice_request(
-                            registration,
-                            appservice::query::query_room_alias::v1::Request {
-                                room_alias: room_alias.clone(),
-                            },
-                        )
-                        .await
-                    {
-                        opt_result.is_ok()
-                    } else {
-                        false
-                    }
-                {
-                    room_id = Some(
-                        services()
-                            .rooms
-                            .alias
-                            .resolve_local_alias(&room_alias)?
-                            .ok_or_else(|| {
-                                Error::bad_config("Appservice lied to us. Room does not exist.")
-                            })?,
-                    );
-                    break;
-                }
-            }
-        }
+    let room_id = match services().rooms.alias.resolve_local_alias(&room_alias)? {
+        Some(room_id) => Some(room_id),
+        None => services().appservice.query_room_alias(&room_alias).await?,
     };
 
     let room_id = match room_id {
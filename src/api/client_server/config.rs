@@ -1,3 +1,4 @@
+use super::leave_room;
 use crate::{services, Error, Result, Ruma};
 use ruma::{
     api::client::{
@@ -7,11 +8,16 @@ use ruma::{
         },
         error::ErrorKind,
     },
-    events::{AnyGlobalAccountDataEventContent, AnyRoomAccountDataEventContent},
+    events::{
+        AnyGlobalAccountDataEventContent, AnyRoomAccountDataEventContent,
+        GlobalAccountDataEventType,
+    },
     serde::Raw,
+    UserId,
 };
 use serde::Deserialize;
 use serde_json::{json, value::RawValue as RawJsonValue};
+use tracing::warn;
 
 /// # `PUT /_matrix/client/r0/user/{userId}/account_data/{type}`
 ///
@@ -36,9 +42,42 @@ pub async fn set_global_account_data_route(
         }),
     )?;
 
+    if body.event_type == GlobalAccountDataEventType::IgnoredUserList {
+        reject_invites_of_ignored_users(sender_user).await?;
+    }
+
     Ok(set_global_account_data::v3::Response {})
 }
 
+/// Rejects the pending invites of the user that were sent by users they ignore.
+async fn reject_invites_of_ignored_users(user_id: &UserId) -> Result<()> {
+    let invited_rooms: Vec<_> = services()
+        .rooms
+        .state_cache
+        .rooms_invited(user_id)
+        .filter_map(Result::ok)
+        .map(|(room_id, _)| room_id)
+        .collect();
+
+    for room_id in invited_rooms {
+        let Some(sender) = services()
+            .rooms
+            .state_cache
+            .invite_sender(user_id, &room_id)?
+        else {
+            continue;
+        };
+
+        if services().users.user_is_ignored(&sender, user_id)? {
+            if let Err(e) = leave_room(user_id, &room_id, None).await {
+                warn!("Failed to reject invite to {room_id} of ignored user {sender}: {e}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
 /// # `PUT /_matrix/client/r0/user/{userId}/rooms/{roomId}/account_data/{type}`
 ///
 /// Sets some room account data for the sender user.
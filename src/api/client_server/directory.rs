@@ -1,7 +1,14 @@
-use crate::{services, Error, Result, Ruma};
+use crate::{
+    service::{
+        appservice,
+        rooms::directory::{DirectoryCursor, DirectoryNetwork},
+    },
+    services, Error, Result, Ruma,
+};
 use ruma::{
     api::{
         client::{
+            appservice::set_room_visibility as set_appservice_room_visibility,
             directory::{
                 get_public_rooms, get_public_rooms_filtered, get_room_visibility,
                 set_room_visibility,
@@ -24,7 +31,7 @@ use ruma::{
         },
         StateEventType,
     },
-    ServerName, UInt,
+    RoomId, ServerName, UInt,
 };
 use tracing::{error, info, warn};
 
@@ -119,6 +126,64 @@ pub async fn set_room_visibility_route(
     Ok(set_room_visibility::v3::Response {})
 }
 
+/// # `PUT /_matrix/client/v3/directory/list/appservice/{networkId}/{roomId}`
+///
+/// Publishes a room in the room directory of a third party network bridged by the appservice.
+///
+/// - Appservices can't change the publication of rooms published by another appservice
+pub async fn set_appservice_room_visibility_route(
+    body: Ruma<set_appservice_room_visibility::v1::Request>,
+) -> Result<set_appservice_room_visibility::v1::Response> {
+    let appservice_id = body.appservice_id.as_ref().ok_or(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "Only appservices can publish rooms in third party networks.",
+    ))?;
+
+    if !services().rooms.metadata.exists(&body.room_id)? {
+        // Return 404 if the room doesn't exist
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found"));
+    }
+
+    if let Some((owner, _)) = services()
+        .rooms
+        .directory
+        .public_room_network(&body.room_id)?
+    {
+        if owner != *appservice_id {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "The room was published by another appservice.",
+            ));
+        }
+    }
+
+    match &body.visibility {
+        room::Visibility::Public => {
+            services().rooms.directory.set_public_in_network(
+                &body.room_id,
+                appservice_id,
+                &body.network_id,
+            )?;
+            info!(
+                "Appservice {} published {} in network {}",
+                appservice_id, body.room_id, body.network_id
+            );
+        }
+        room::Visibility::Private => services()
+            .rooms
+            .directory
+            .set_not_public_in_network(&body.room_id)?,
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Room visibility type is not supported.",
+            ));
+        }
+    }
+
+    Ok(set_appservice_room_visibility::v1::Response::new())
+}
+
 /// # `GET /_matrix/client/r0/directory/list/room/{roomId}`
 ///
 /// Gets the visibility of a given room in the room directory.
@@ -144,7 +209,7 @@ pub(crate) async fn get_public_rooms_filtered_helper(
     limit: Option<UInt>,
     since: Option<&str>,
     filter: &Filter,
-    _network: &RoomNetwork,
+    network: &RoomNetwork,
 ) -> Result<get_public_rooms_filtered::v3::Response> {
     if let Some(other_server) =
         server.filter(|server| *server != services().globals.server_name().as_str())
@@ -160,7 +225,7 @@ pub(crate) async fn get_public_rooms_filtered_helper(
                         generic_search_term: filter.generic_search_term.clone(),
                         room_types: filter.room_types.clone(),
                     },
-                    room_network: RoomNetwork::Matrix,
+                    room_network: network.clone(),
                 },
             )
             .await?;
@@ -174,38 +239,39 @@ pub(crate) async fn get_public_rooms_filtered_helper(
     }
 
     let limit = limit.map_or(10, u64::from);
-    let mut num_since = 0_u64;
+    let (from, backwards) = match since {
+        Some(since) => {
+            let (cursor, backwards) = parse_directory_token(since)?;
+            (Some(cursor), backwards)
+        }
+        None => (None, false),
+    };
 
-    if let Some(s) = &since {
-        let mut characters = s.chars();
-        let backwards = match characters.next() {
-            Some('n') => false,
-            Some('p') => true,
-            _ => {
-                return Err(Error::BadRequest(
+    let network = match network {
+        RoomNetwork::Matrix => DirectoryNetwork::Matrix,
+        RoomNetwork::All => DirectoryNetwork::All,
+        RoomNetwork::ThirdParty(instance_id) => {
+            let (appservice_id, network_id) =
+                appservice::parse_third_party_instance_id(instance_id).ok_or(Error::BadRequest(
                     ErrorKind::InvalidParam,
-                    "Invalid `since` token",
-                ))
-            }
-        };
-
-        num_since = characters
-            .collect::<String>()
-            .parse()
-            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `since` token."))?;
-
-        if backwards {
-            num_since = num_since.saturating_sub(limit);
+                    "Invalid third party instance ID.",
+                ))?;
+            DirectoryNetwork::ThirdParty(appservice_id, network_id)
         }
-    }
+    };
+
+    let page = services().rooms.directory.public_rooms_page(
+        network,
+        filter.generic_search_term.as_deref(),
+        from.as_ref(),
+        backwards,
+        limit as usize,
+    )?;
 
-    let mut all_rooms: Vec<_> = services()
+    let chunk: Vec<_> = page
         .rooms
-        .directory
-        .public_rooms()
+        .into_iter()
         .map(|room_id| {
-            let room_id = room_id?;
-
             let chunk = PublicRoomsChunk {
                 canonical_alias: services()
                     .rooms
@@ -322,65 +388,43 @@ pub(crate) async fn get_public_rooms_filtered_helper(
             Ok(chunk)
         })
         .filter_map(|r: Result<_>| r.ok()) // Filter out buggy rooms
-        .filter(|chunk| {
-            if let Some(query) = filter
-                .generic_search_term
-                .as_ref()
-                .map(|q| q.to_lowercase())
-            {
-                if let Some(name) = &chunk.name {
-                    if name.as_str().to_lowercase().contains(&query) {
-                        return true;
-                    }
-                }
-
-                if let Some(topic) = &chunk.topic {
-                    if topic.to_lowercase().contains(&query) {
-                        return true;
-                    }
-                }
-
-                if let Some(canonical_alias) = &chunk.canonical_alias {
-                    if canonical_alias.as_str().to_lowercase().contains(&query) {
-                        return true;
-                    }
-                }
-
-                false
-            } else {
-                // No search term
-                true
-            }
-        })
-        // We need to collect all, so we can sort by member count
         .collect();
 
-    all_rooms.sort_by(|l, r| r.num_joined_members.cmp(&l.num_joined_members));
+    Ok(get_public_rooms_filtered::v3::Response {
+        chunk,
+        prev_batch: page.prev.map(|cursor| directory_token(&cursor, true)),
+        next_batch: page.next.map(|cursor| directory_token(&cursor, false)),
+        total_room_count_estimate: page.total.map(|total| (total as u32).into()),
+    })
+}
 
-    let total_room_count_estimate = (all_rooms.len() as u32).into();
+/// Pagination tokens of the room directory are the direction followed by the position in the
+/// directory order, e.g. `n42_!room:example.org`.
+fn directory_token(cursor: &DirectoryCursor, backwards: bool) -> String {
+    format!(
+        "{}{}_{}",
+        if backwards { 'p' } else { 'n' },
+        cursor.joined_members,
+        cursor.room_id
+    )
+}
 
-    let chunk: Vec<_> = all_rooms
-        .into_iter()
-        .skip(num_since as usize)
-        .take(limit as usize)
-        .collect();
+fn parse_directory_token(token: &str) -> Result<(DirectoryCursor, bool)> {
+    let invalid = || Error::BadRequest(ErrorKind::InvalidParam, "Invalid `since` token.");
 
-    let prev_batch = if num_since == 0 {
-        None
+    let (backwards, position) = if let Some(position) = token.strip_prefix('n') {
+        (false, position)
+    } else if let Some(position) = token.strip_prefix('p') {
+        (true, position)
     } else {
-        Some(format!("p{num_since}"))
+        return Err(invalid());
     };
 
-    let next_batch = if chunk.len() < limit as usize {
-        None
-    } else {
-        Some(format!("n{}", num_since + limit))
+    let (joined_members, room_id) = position.split_once('_').ok_or_else(invalid)?;
+    let cursor = DirectoryCursor {
+        joined_members: joined_members.parse().map_err(|_| invalid())?,
+        room_id: RoomId::parse(room_id).map_err(|_| invalid())?,
     };
 
-    Ok(get_public_rooms_filtered::v3::Response {
-        chunk,
-        prev_batch,
-        next_batch,
-        total_room_count_estimate: Some(total_room_count_estimate),
-    })
+    Ok((cursor, backwards))
 }
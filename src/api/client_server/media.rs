@@ -1,33 +1,69 @@
-use std::{io::Cursor, net::IpAddr, sync::Arc, time::Duration};
+use std::{fmt::Debug, io::Cursor, net::IpAddr, sync::Arc, time::Duration};
 
 use crate::{
-    service::media::{FileMeta, UrlPreviewData},
-    services, utils, Error, Result, Ruma,
+    service::media::{FileMeta, MediaFile, UrlPreviewData},
+    services,
+    utils::{
+        self,
+        content_disposition::{
+            filename_from_content_disposition, make_content_disposition, safe_content_type,
+        },
+    },
+    Authenticated, Error, Result, Ruma, RumaResponse,
 };
+use axum::{
+    body::StreamBody,
+    extract::{BodyStream, Path, Query},
+    http::{header, HeaderMap, HeaderName, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use futures_util::stream;
 use image::io::Reader as ImgReader;
 
 use reqwest::Url;
-use ruma::api::client::{
-    error::ErrorKind,
-    media::{
-        create_content, get_content, get_content_as_filename, get_content_thumbnail,
-        get_media_config, get_media_preview,
+use ruma::{
+    api::{
+        client::{
+            error::ErrorKind,
+            media::{
+                create_content, get_content, get_content_thumbnail, get_media_config,
+                get_media_preview,
+            },
+        },
+        OutgoingRequest,
     },
+    OwnedServerName,
 };
+use serde::Deserialize;
+use tokio::io::AsyncReadExt;
 use tracing::{debug, error, info, warn};
 use webpage::HTML;
 
 /// generated MXC ID (`media-id`) length
 const MXC_LENGTH: usize = 32;
 
+/// Size of the chunks in which files are streamed to clients
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Keeps browsers from running anything in media that is opened directly
+const MEDIA_CONTENT_SECURITY_POLICY: &str =
+    "sandbox; default-src 'none'; script-src 'none'; plugin-types application/pdf; \
+     style-src 'unsafe-inline'; media-src 'self'; object-src 'self';";
+
 /// # `GET /_matrix/media/v3/config`
 ///
 /// Returns max upload size.
 pub async fn get_media_config_route(
-    _body: Ruma<get_media_config::v3::Request>,
+    body: Ruma<get_media_config::v3::Request>,
 ) -> Result<get_media_config::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
     Ok(get_media_config::v3::Response {
-        upload_size: services().globals.max_request_size().into(),
+        upload_size: services()
+            .media
+            .max_upload_size(sender_user, body.appservice_id.as_deref())?
+            .into(),
     })
 }
 
@@ -74,44 +110,70 @@ pub async fn get_media_preview_route(
 ///
 /// - Some metadata will be saved in the database
 /// - Media will be saved in the media/ directory
+/// - The request body is streamed to disk instead of being loaded into memory
 pub async fn create_content_route(
-    body: Ruma<create_content::v3::Request>,
-) -> Result<create_content::v3::Response> {
+    auth: Authenticated,
+    Query(params): Query<UploadParams>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Result<RumaResponse<create_content::v3::Response>> {
     let mxc = format!(
         "mxc://{}/{}",
         services().globals.server_name(),
         utils::random_string(MXC_LENGTH)
     );
 
+    let max_upload_size = services()
+        .media
+        .max_upload_size(&auth.sender_user, auth.appservice_id.as_deref())?;
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+
     services()
         .media
-        .create(
+        .create_from_stream(
+            Some(&auth.sender_user),
             mxc.clone(),
-            body.filename
-                .as_ref()
-                .map(|filename| "inline; filename=".to_owned() + filename)
+            params
+                .filename
+                .as_deref()
+                .map(|filename| make_content_disposition(content_type, Some(filename)))
                 .as_deref(),
-            body.content_type.as_deref(),
-            &body.file,
+            content_type,
+            body,
+            max_upload_size.into(),
         )
         .await?;
 
-    let content_uri = mxc.into();
+    if services().globals.config.media_pregenerate_thumbnails
+        && content_type.is_some_and(|content_type| content_type.starts_with("image/"))
+    {
+        services().media.pregenerate_thumbnails(mxc.clone());
+    }
 
-    Ok(create_content::v3::Response {
-        content_uri,
+    Ok(RumaResponse(create_content::v3::Response {
+        content_uri: mxc.into(),
         blurhash: None,
-    })
+    }))
+}
+
+/// Query parameters of the upload endpoint
+#[derive(Deserialize)]
+pub struct UploadParams {
+    filename: Option<String>,
 }
 
-/// helper method to fetch remote media from other servers over federation
+/// Fetches remote media from another server over federation, streaming it into our media store
+/// without keeping the whole file in memory, and opens the stored file.
 pub async fn get_remote_content(
     mxc: &str,
     server_name: &ruma::ServerName,
     media_id: String,
     allow_redirect: bool,
     timeout_ms: Duration,
-) -> Result<get_content::v3::Response, Error> {
+) -> Result<MediaFile, Error> {
     // we'll lie to the client and say the blocked server's media was not found and log.
     // the client has no way of telling anyways so this is a security bonus.
     if services()
@@ -123,31 +185,129 @@ pub async fn get_remote_content(
         return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
     }
 
-    let content_response = services()
-        .sending
-        .send_federation_request(
-            server_name,
-            get_content::v3::Request {
-                allow_remote: true,
-                server_name: server_name.to_owned(),
-                media_id,
-                timeout_ms,
-                allow_redirect,
-            },
-        )
-        .await?;
+    if services().media.remote_fetch_failed_recently(mxc) {
+        debug!("Not fetching `{mxc}` again, it failed recently");
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
+    }
+
+    let config = &services().globals.config;
+    let timeout = Duration::from_secs(config.remote_media_fetch_timeout_s);
+    let max_size: u64 = config
+        .max_remote_media_size
+        .unwrap_or(config.max_request_size)
+        .into();
+
+    let fetched = tokio::time::timeout(timeout, async {
+        let response = services()
+            .sending
+            .send_federation_request_streamed(
+                server_name,
+                get_content::v3::Request {
+                    allow_remote: true,
+                    server_name: server_name.to_owned(),
+                    media_id,
+                    timeout_ms,
+                    allow_redirect,
+                },
+                timeout,
+            )
+            .await?;
+
+        if response
+            .content_length()
+            .is_some_and(|length| length > max_size)
+        {
+            info!("Remote media `{mxc}` is larger than {max_size} bytes");
+            return Err(Error::BadServerResponse("Response body is too large."));
+        }
+
+        let header_value = |name: HeaderName| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(ToOwned::to_owned)
+        };
+        let content_type = header_value(header::CONTENT_TYPE);
+
+        // Don't trust the file name and disposition type chosen by the remote server
+        let content_disposition = make_content_disposition(
+            content_type.as_deref(),
+            header_value(header::CONTENT_DISPOSITION)
+                .as_deref()
+                .and_then(filename_from_content_disposition)
+                .as_deref(),
+        );
+
+        services()
+            .media
+            .create_from_stream(
+                None,
+                mxc.to_owned(),
+                Some(&content_disposition),
+                content_type.as_deref(),
+                Box::pin(response.bytes_stream()),
+                max_size,
+            )
+            .await
+    })
+    .await
+    .unwrap_or_else(|_| {
+        warn!(
+            "Timeout after {} seconds fetching remote media `{mxc}`",
+            timeout.as_secs()
+        );
+        Err(Error::BadServerResponse("Timeout fetching remote media"))
+    });
 
+    if let Err(e) = fetched {
+        services().media.record_failed_remote_fetch(mxc.to_owned());
+        return Err(e);
+    }
+
+    // The media scanner may have flagged the file, which makes this fail
     services()
         .media
-        .create(
-            mxc.to_owned(),
-            content_response.content_disposition.as_deref(),
-            content_response.content_type.as_deref(),
-            &content_response.file,
+        .open(mxc.to_owned())
+        .await?
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
+}
+
+/// Sends a media request over federation, limited to `max_remote_media_size` bytes and
+/// `remote_media_fetch_timeout_s`. Failed fetches are not retried for a while, `failure_key`
+/// identifies the fetch in that cache.
+async fn fetch_remote_media<T>(
+    server_name: &ruma::ServerName,
+    failure_key: String,
+    request: T,
+) -> Result<T::IncomingResponse>
+where
+    T: OutgoingRequest + Debug,
+{
+    if services().media.remote_fetch_failed_recently(&failure_key) {
+        debug!("Not fetching `{failure_key}` again, it failed recently");
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
+    }
+
+    let config = &services().globals.config;
+    let response = services()
+        .sending
+        .send_federation_request_limited(
+            server_name,
+            request,
+            config
+                .max_remote_media_size
+                .unwrap_or(config.max_request_size)
+                .into(),
+            Duration::from_secs(config.remote_media_fetch_timeout_s),
         )
-        .await?;
+        .await;
+
+    if response.is_err() {
+        services().media.record_failed_remote_fetch(failure_key);
+    }
 
-    Ok(content_response)
+    response
 }
 
 /// # `GET /_matrix/media/v3/download/{serverName}/{mediaId}`
@@ -157,33 +317,34 @@ pub async fn get_remote_content(
 /// - Only allows federation if `allow_remote` is true
 /// - Only redirects if `allow_redirect` is true
 /// - Uses client-provided `timeout_ms` if available, else defaults to 20 seconds
+/// - Files are streamed from disk instead of being loaded into memory, remote files are streamed
+///   into the media store first
 pub async fn get_content_route(
-    body: Ruma<get_content::v3::Request>,
-) -> Result<get_content::v3::Response> {
-    let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
-
-    if let Some(FileMeta {
-        content_disposition,
-        content_type,
-        file,
-    }) = services().media.get(mxc.clone()).await?
-    {
-        Ok(get_content::v3::Response {
-            file,
-            content_type,
-            content_disposition,
-            cross_origin_resource_policy: Some("cross-origin".to_owned()),
-        })
-    } else if &*body.server_name != services().globals.server_name() && body.allow_remote {
-        let remote_content_response = get_remote_content(
+    Path((server_name, media_id)): Path<(OwnedServerName, String)>,
+    Query(params): Query<DownloadParams>,
+) -> Result<Response> {
+    let mxc = format!("mxc://{server_name}/{media_id}");
+
+    if let Some(file) = services().media.open(mxc.clone()).await? {
+        let filename = file
+            .content_disposition
+            .as_deref()
+            .and_then(filename_from_content_disposition);
+        Ok(file_response(file, filename.as_deref()))
+    } else if &*server_name != services().globals.server_name() && params.allow_remote {
+        let file = get_remote_content(
             &mxc,
-            &body.server_name,
-            body.media_id.clone(),
-            body.allow_redirect,
-            body.timeout_ms,
+            &server_name,
+            media_id,
+            params.allow_redirect,
+            params.timeout(),
         )
         .await?;
-        Ok(remote_content_response)
+        let filename = file
+            .content_disposition
+            .as_deref()
+            .and_then(filename_from_content_disposition);
+        Ok(file_response(file, filename.as_deref()))
     } else {
         Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
     }
@@ -196,42 +357,99 @@ pub async fn get_content_route(
 /// - Only allows federation if `allow_remote` is true
 /// - Only redirects if `allow_redirect` is true
 /// - Uses client-provided `timeout_ms` if available, else defaults to 20 seconds
+/// - Files are streamed from disk instead of being loaded into memory, remote files are streamed
+///   into the media store first
 pub async fn get_content_as_filename_route(
-    body: Ruma<get_content_as_filename::v3::Request>,
-) -> Result<get_content_as_filename::v3::Response> {
-    let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
-
-    if let Some(FileMeta {
-        content_type, file, ..
-    }) = services().media.get(mxc.clone()).await?
-    {
-        Ok(get_content_as_filename::v3::Response {
-            file,
-            content_type,
-            content_disposition: Some(format!("inline; filename={}", body.filename)),
-            cross_origin_resource_policy: Some("cross-origin".to_owned()),
-        })
-    } else if &*body.server_name != services().globals.server_name() && body.allow_remote {
-        let remote_content_response = get_remote_content(
+    Path((server_name, media_id, filename)): Path<(OwnedServerName, String, String)>,
+    Query(params): Query<DownloadParams>,
+) -> Result<Response> {
+    let mxc = format!("mxc://{server_name}/{media_id}");
+
+    if let Some(file) = services().media.open(mxc.clone()).await? {
+        Ok(file_response(file, Some(&filename)))
+    } else if &*server_name != services().globals.server_name() && params.allow_remote {
+        let file = get_remote_content(
             &mxc,
-            &body.server_name,
-            body.media_id.clone(),
-            body.allow_redirect,
-            body.timeout_ms,
+            &server_name,
+            media_id,
+            params.allow_redirect,
+            params.timeout(),
         )
         .await?;
-
-        Ok(get_content_as_filename::v3::Response {
-            content_disposition: Some(format!("inline: filename={}", body.filename)),
-            content_type: remote_content_response.content_type,
-            file: remote_content_response.file,
-            cross_origin_resource_policy: Some("cross-origin".to_owned()),
-        })
+        Ok(file_response(file, Some(&filename)))
     } else {
         Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
     }
 }
 
+/// Query parameters of the download endpoints
+#[derive(Deserialize)]
+pub struct DownloadParams {
+    #[serde(default = "true_fn")]
+    allow_remote: bool,
+    #[serde(default)]
+    allow_redirect: bool,
+    timeout_ms: Option<u64>,
+}
+
+impl DownloadParams {
+    fn timeout(&self) -> Duration {
+        self.timeout_ms
+            .map_or(Duration::from_secs(20), Duration::from_millis)
+    }
+}
+
+fn true_fn() -> bool {
+    true
+}
+
+/// Streams a stored file to the client in chunks. Content types that could run scripts in the
+/// origin of the homeserver are served as attachments.
+fn file_response(file: MediaFile, filename: Option<&str>) -> Response {
+    let content_disposition = make_content_disposition(file.content_type.as_deref(), filename);
+    let content_type = safe_content_type(file.content_type.as_deref());
+
+    let body = stream::unfold(Some(file.file), |file| async move {
+        let mut file = file?;
+        let mut buf = vec![0; DOWNLOAD_CHUNK_SIZE];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), Some(file)))
+            }
+            // Stop after the first error
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+
+    let mut response = StreamBody::new(body).into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_LENGTH, file.len.into());
+    headers.insert(
+        HeaderName::from_static("cross-origin-resource-policy"),
+        HeaderValue::from_static("cross-origin"),
+    );
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(MEDIA_CONTENT_SECURITY_POLICY),
+    );
+    for (name, value) in [
+        (header::CONTENT_TYPE, content_type),
+        (header::CONTENT_DISPOSITION, content_disposition),
+    ] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+
+    response
+}
+
 /// # `GET /_matrix/media/v3/thumbnail/{serverName}/{mediaId}`
 ///
 /// Load media thumbnail from our server or over federation.
@@ -261,7 +479,7 @@ pub async fn get_content_thumbnail_route(
     {
         Ok(get_content_thumbnail::v3::Response {
             file,
-            content_type,
+            content_type: Some(safe_content_type(content_type.as_deref())),
             cross_origin_resource_policy: Some("cross-origin".to_owned()),
         })
     } else if &*body.server_name != services().globals.server_name() && body.allow_remote {
@@ -276,27 +494,26 @@ pub async fn get_content_thumbnail_route(
             return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
         }
 
-        let get_thumbnail_response = services()
-            .sending
-            .send_federation_request(
-                &body.server_name,
-                get_content_thumbnail::v3::Request {
-                    allow_remote: body.allow_remote,
-                    height: body.height,
-                    width: body.width,
-                    method: body.method.clone(),
-                    server_name: body.server_name.clone(),
-                    media_id: body.media_id.clone(),
-                    timeout_ms: body.timeout_ms,
-                    allow_redirect: body.allow_redirect,
-                },
-            )
-            .await?;
+        let get_thumbnail_response = fetch_remote_media(
+            &body.server_name,
+            format!("{mxc}#{}x{}", body.width, body.height),
+            get_content_thumbnail::v3::Request {
+                allow_remote: body.allow_remote,
+                height: body.height,
+                width: body.width,
+                method: body.method.clone(),
+                server_name: body.server_name.clone(),
+                media_id: body.media_id.clone(),
+                timeout_ms: body.timeout_ms,
+                allow_redirect: body.allow_redirect,
+            },
+        )
+        .await?;
 
         services()
             .media
             .upload_thumbnail(
-                mxc,
+                mxc.clone(),
                 None,
                 get_thumbnail_response.content_type.as_deref(),
                 body.width.try_into().expect("all UInts are valid u32s"),
@@ -305,7 +522,17 @@ pub async fn get_content_thumbnail_route(
             )
             .await?;
 
-        Ok(get_thumbnail_response)
+        // The media scanner may have flagged the thumbnail
+        if services().media.is_quarantined(&mxc)? {
+            return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
+        }
+
+        Ok(get_content_thumbnail::v3::Response {
+            content_type: Some(safe_content_type(
+                get_thumbnail_response.content_type.as_deref(),
+            )),
+            ..get_thumbnail_response
+        })
     } else {
         Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
     }
@@ -321,7 +548,7 @@ async fn download_image(client: &reqwest::Client, url: &str) -> Result<UrlPrevie
 
     services()
         .media
-        .create(mxc.clone(), None, None, &image)
+        .create(None, mxc.clone(), None, None, &image)
         .await?;
 
     let (width, height) = match ImgReader::new(Cursor::new(&image)).with_guessed_format() {
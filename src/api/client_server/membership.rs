@@ -3,9 +3,10 @@ use ruma::{
         client::{
             error::ErrorKind,
             membership::{
-                ban_user, forget_room, get_member_events, invite_user, join_room_by_id,
-                join_room_by_id_or_alias, joined_members, joined_rooms, kick_user, leave_room,
-                unban_user, ThirdPartySigned,
+                ban_user, forget_room,
+                get_member_events::{self, v3::MembershipEventFilter},
+                invite_user, join_room_by_id, join_room_by_id_or_alias, joined_members,
+                joined_rooms, kick_user, leave_room, unban_user, ThirdPartySigned,
             },
         },
         federation::{self, membership::create_invite},
@@ -14,7 +15,7 @@ use ruma::{
     events::{
         room::{
             join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
-            member::{MembershipState, RoomMemberEventContent},
+            member::{MembershipState, RoomMemberEventContent, ThirdPartyInvite},
             power_levels::RoomPowerLevelsEventContent,
         },
         StateEventType, TimelineEventType,
@@ -32,7 +33,10 @@ use std::{
 use tracing::{debug, error, info, warn};
 
 use crate::{
-    service::pdu::{gen_event_id_canonical_json, PduBuilder},
+    service::{
+        pdu::{gen_event_id_canonical_json, PduBuilder},
+        rooms::timeline::PduCount,
+    },
     services, utils, Error, PduEvent, Result, Ruma,
 };
 
@@ -49,6 +53,10 @@ pub async fn join_room_by_id_route(
 ) -> Result<join_room_by_id::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    services()
+        .users
+        .check_current_terms(sender_user, body.from_appservice)?;
+
     if services().rooms.metadata.is_banned(&body.room_id)?
         && !services().users.is_admin(sender_user)?
     {
@@ -58,6 +66,10 @@ pub async fn join_room_by_id_route(
         ));
     }
 
+    if !body.from_appservice {
+        check_joined_rooms_limit(sender_user, Some(&body.room_id))?;
+    }
+
     let mut servers = Vec::new(); // There is no body.server_name for /roomId/join
     servers.extend(
         services()
@@ -95,8 +107,13 @@ pub async fn join_room_by_id_or_alias_route(
     body: Ruma<join_room_by_id_or_alias::v3::Request>,
 ) -> Result<join_room_by_id_or_alias::v3::Response> {
     let sender_user = body.sender_user.as_deref().expect("user is authenticated");
+    let from_appservice = body.from_appservice;
     let body = body.body;
 
+    services()
+        .users
+        .check_current_terms(sender_user, from_appservice)?;
+
     let (servers, room_id) = match OwnedRoomId::try_from(body.room_id_or_alias) {
         Ok(room_id) => {
             if services().rooms.metadata.is_banned(&room_id)?
@@ -143,6 +160,10 @@ pub async fn join_room_by_id_or_alias_route(
         }
     };
 
+    if !from_appservice {
+        check_joined_rooms_limit(sender_user, Some(&room_id))?;
+    }
+
     let join_room_response = join_room_by_id_helper(
         Some(sender_user),
         &room_id,
@@ -180,6 +201,19 @@ pub async fn invite_user_route(
 ) -> Result<invite_user::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    services()
+        .users
+        .check_current_terms(sender_user, body.from_appservice)?;
+
+    if services().rooms.metadata.is_banned(&body.room_id)?
+        && !services().users.is_admin(sender_user)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room is banned on this homeserver.",
+        ));
+    }
+
     if let invite_user::v3::InvitationRecipient::UserId { user_id } = &body.recipient {
         invite_helper(
             sender_user,
@@ -187,6 +221,7 @@ pub async fn invite_user_route(
             &body.room_id,
             body.reason.clone(),
             false,
+            None,
         )
         .await?;
         Ok(invite_user::v3::Response {})
@@ -224,15 +259,7 @@ pub async fn kick_user_route(
     event.membership = MembershipState::Leave;
     event.reason = body.reason.clone();
 
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(body.room_id.clone())
-            .or_default(),
-    );
+    let mutex_state = services().globals.roomid_mutex_state.mutex(&body.room_id);
     let state_lock = mutex_state.lock().await;
 
     services()
@@ -302,15 +329,7 @@ pub async fn ban_user_route(body: Ruma<ban_user::v3::Request>) -> Result<ban_use
             },
         )?;
 
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(body.room_id.clone())
-            .or_default(),
-    );
+    let mutex_state = services().globals.roomid_mutex_state.mutex(&body.room_id);
     let state_lock = mutex_state.lock().await;
 
     services()
@@ -364,15 +383,7 @@ pub async fn unban_user_route(
     event.membership = MembershipState::Leave;
     event.reason = body.reason.clone();
 
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(body.room_id.clone())
-            .or_default(),
-    );
+    let mutex_state = services().globals.roomid_mutex_state.mutex(&body.room_id);
     let state_lock = mutex_state.lock().await;
 
     services()
@@ -438,9 +449,12 @@ pub async fn joined_rooms_route(
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/members`
 ///
-/// Lists all joined users in a room (TODO: at a specific point in time, with a specific membership).
+/// Lists the member events of a room.
 ///
 /// - Only works if the user is currently joined
+/// - `at` returns the members at a pagination token, e.g. a `prev_batch` of `/sync`
+/// - `membership` and `not_membership` filter by membership, if both are given events have to
+///   match both
 pub async fn get_member_events_route(
     body: Ruma<get_member_events::v3::Request>,
 ) -> Result<get_member_events::v3::Response> {
@@ -457,17 +471,101 @@ pub async fn get_member_events_route(
         ));
     }
 
-    Ok(get_member_events::v3::Response {
-        chunk: services()
-            .rooms
-            .state_accessor
-            .room_state_full(&body.room_id)
-            .await?
-            .iter()
-            .filter(|(key, _)| key.0 == StateEventType::RoomMember)
-            .map(|(_, pdu)| pdu.to_member_event())
-            .collect(),
-    })
+    let state = match &body.at {
+        Some(at) => {
+            let Some(shortstatehash) = shortstatehash_at(sender_user, &body.room_id, at)? else {
+                return Ok(get_member_events::v3::Response { chunk: Vec::new() });
+            };
+
+            services()
+                .rooms
+                .state_accessor
+                .state_full(shortstatehash)
+                .await?
+        }
+        None => {
+            services()
+                .rooms
+                .state_accessor
+                .room_state_full(&body.room_id)
+                .await?
+        }
+    };
+
+    let mut chunk = Vec::new();
+    for ((event_type, _), pdu) in state {
+        if event_type != StateEventType::RoomMember {
+            continue;
+        }
+
+        if body.membership.is_some() || body.not_membership.is_some() {
+            let membership = serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
+                .map_err(|_| Error::bad_database("Invalid member event in database."))?
+                .membership;
+
+            if !membership_filter_matches(
+                &membership,
+                body.membership.as_ref(),
+                body.not_membership.as_ref(),
+            ) {
+                continue;
+            }
+        }
+
+        chunk.push(pdu.to_member_event());
+    }
+
+    Ok(get_member_events::v3::Response { chunk })
+}
+
+/// Whether a membership passes both the `membership` and the `not_membership` filter
+fn membership_filter_matches(
+    membership: &MembershipState,
+    only: Option<&MembershipEventFilter>,
+    not: Option<&MembershipEventFilter>,
+) -> bool {
+    only.map_or(true, |filter| filter.as_str() == membership.as_str())
+        && not.map_or(true, |filter| filter.as_str() != membership.as_str())
+}
+
+/// Returns the state of the room at a pagination token: the state before the first event at or
+/// after the token, or the current state if there is no such event.
+///
+/// Fails if the history visibility doesn't allow the user to see the room at that point.
+fn shortstatehash_at(sender_user: &UserId, room_id: &RoomId, at: &str) -> Result<Option<u64>> {
+    // The event with the count of the token comes after it
+    let before = match PduCount::try_from_string(at)? {
+        PduCount::Normal(count) => PduCount::Normal(count.saturating_sub(1)),
+        PduCount::Backfilled(count) => PduCount::Backfilled(count.saturating_add(1)),
+    };
+
+    let next_pdu = services()
+        .rooms
+        .timeline
+        .pdus_after(sender_user, room_id, before)?
+        .next()
+        .transpose()?;
+
+    match next_pdu {
+        Some((_, pdu)) => {
+            if !services().rooms.state_accessor.user_can_see_event(
+                sender_user,
+                room_id,
+                &pdu.event_id,
+            )? {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "You don't have permission to view this room at this point.",
+                ));
+            }
+
+            services()
+                .rooms
+                .state_accessor
+                .pdu_shortstatehash(&pdu.event_id)
+        }
+        None => services().rooms.state.get_room_shortstatehash(room_id),
+    }
 }
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/joined_members`
@@ -514,6 +612,103 @@ pub async fn joined_members_route(
     Ok(joined_members::v3::Response { joined })
 }
 
+/// Synapse-compatible room complexity: the number of current state events divided by 500.
+fn room_complexity(state_events: usize) -> f64 {
+    state_events as f64 / 500.0
+}
+
+/// Synapse's unstable federation endpoint for querying the complexity of a room before joining it.
+mod get_room_complexity {
+    use ruma::{
+        api::{metadata, request, response, Metadata},
+        OwnedRoomId,
+    };
+
+    const METADATA: Metadata = metadata! {
+        method: GET,
+        rate_limited: false,
+        authentication: ServerSignatures,
+        history: {
+            unstable => "/_matrix/federation/unstable/rooms/:room_id/complexity",
+        }
+    };
+
+    #[request]
+    pub struct Request {
+        #[ruma_api(path)]
+        pub room_id: OwnedRoomId,
+    }
+
+    #[response]
+    pub struct Response {
+        pub v1: f64,
+    }
+}
+
+/// Asks the resident servers for the complexity of a room we are not in yet. Returns `None` if none
+/// of them supports the complexity API.
+async fn remote_room_complexity(room_id: &RoomId, servers: &[OwnedServerName]) -> Option<f64> {
+    for remote_server in servers {
+        if remote_server == services().globals.server_name() {
+            continue;
+        }
+
+        match services()
+            .sending
+            .send_federation_request(
+                remote_server,
+                get_room_complexity::Request {
+                    room_id: room_id.to_owned(),
+                },
+            )
+            .await
+        {
+            Ok(response) => return Some(response.v1),
+            Err(e) => debug!("Could not get complexity of {room_id} from {remote_server}: {e}"),
+        }
+    }
+
+    None
+}
+
+fn too_complex_error() -> Error {
+    Error::resource_limit_exceeded("This room is too complex to be joined from this homeserver.")
+}
+
+/// Fails with `M_RESOURCE_LIMIT_EXCEEDED` if the local user is already joined to
+/// `max_joined_rooms_per_user` rooms. Admins are exempt, and rejoining a room the user is already in
+/// (`room_id`) is always allowed.
+pub(crate) fn check_joined_rooms_limit(user_id: &UserId, room_id: Option<&RoomId>) -> Result<()> {
+    let Some(max_joined_rooms) = services().globals.max_joined_rooms_per_user() else {
+        return Ok(());
+    };
+
+    if let Some(room_id) = room_id {
+        if services().rooms.state_cache.is_joined(user_id, room_id)? {
+            return Ok(());
+        }
+    }
+
+    if services().users.is_admin(user_id)? {
+        return Ok(());
+    }
+
+    let joined_rooms = services()
+        .rooms
+        .state_cache
+        .rooms_joined(user_id)
+        .take(max_joined_rooms)
+        .count();
+
+    if joined_rooms >= max_joined_rooms {
+        return Err(Error::resource_limit_exceeded(
+            "You have joined the maximum number of rooms allowed on this homeserver.",
+        ));
+    }
+
+    Ok(())
+}
+
 async fn join_room_by_id_helper(
     sender_user: Option<&UserId>,
     room_id: &RoomId,
@@ -523,15 +718,7 @@ async fn join_room_by_id_helper(
 ) -> Result<join_room_by_id::v3::Response> {
     let sender_user = sender_user.expect("user is authenticated");
 
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(room_id.to_owned())
-            .or_default(),
-    );
+    let mutex_state = services().globals.roomid_mutex_state.mutex(room_id);
     let state_lock = mutex_state.lock().await;
 
     // Ask a remote server if we are not participating in this room
@@ -542,6 +729,24 @@ async fn join_room_by_id_helper(
     {
         info!("Joining {room_id} over federation.");
 
+        // Refuse too complex rooms before joining. Servers without the complexity API are checked
+        // after send_join instead, which means leaving the room again.
+        let mut max_complexity_after_join = None;
+        if let Some(max_complexity) = services().globals.max_remote_room_complexity() {
+            if !services().users.is_admin(sender_user)? {
+                match remote_room_complexity(room_id, servers).await {
+                    Some(complexity) if complexity > max_complexity => {
+                        warn!(
+                            "Refusing to let {sender_user} join {room_id}, its complexity {complexity} exceeds the limit of {max_complexity}"
+                        );
+                        return Err(too_complex_error());
+                    }
+                    Some(_) => {}
+                    None => max_complexity_after_join = Some(max_complexity),
+                }
+            }
+        }
+
         let (make_join_response, remote_server) =
             make_join_request(sender_user, room_id, servers).await?;
 
@@ -647,6 +852,25 @@ async fn join_room_by_id_helper(
 
         info!("send_join finished");
 
+        if let Some(max_complexity) = max_complexity_after_join {
+            let complexity = room_complexity(send_join_response.room_state.state.len());
+
+            if complexity > max_complexity {
+                warn!(
+                    "Refusing to let {sender_user} join {room_id}, its complexity {complexity} exceeds the limit of {max_complexity}"
+                );
+
+                // The remote server already considers us joined
+                if let Err(e) =
+                    remote_leave_room_via(sender_user, room_id, [remote_server.clone()]).await
+                {
+                    warn!("Failed to leave too complex room {room_id}: {e}");
+                }
+
+                return Err(too_complex_error());
+            }
+        }
+
         if join_authorized_via_users_server.is_some() {
             match &room_version_id {
                 RoomVersionId::V1
@@ -1205,28 +1429,21 @@ fn validate_and_add_event_id(
     ))
     .expect("ruma's reference hashes are valid event ids");
 
-    let back_off = |id| match services()
-        .globals
-        .bad_event_ratelimiter
-        .write()
-        .unwrap()
-        .entry(id)
-    {
-        Entry::Vacant(e) => {
-            e.insert((Instant::now(), 1));
-        }
-        Entry::Occupied(mut e) => *e.get_mut() = (Instant::now(), e.get().1 + 1),
+    let back_off = |id| {
+        services()
+            .globals
+            .bad_event_ratelimiter
+            .with_entry(id, |entry| match entry {
+                Entry::Vacant(e) => {
+                    e.insert((Instant::now(), 1));
+                }
+                Entry::Occupied(mut e) => *e.get_mut() = (Instant::now(), e.get().1 + 1),
+            });
     };
 
-    if let Some((time, tries)) = services()
-        .globals
-        .bad_event_ratelimiter
-        .read()
-        .unwrap()
-        .get(&event_id)
-    {
+    if let Some((time, tries)) = services().globals.bad_event_ratelimiter.get(&event_id) {
         // Exponential backoff
-        let mut min_elapsed_duration = Duration::from_secs(5 * 60) * (*tries) * (*tries);
+        let mut min_elapsed_duration = Duration::from_secs(5 * 60) * tries * tries;
         if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
             min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
         }
@@ -1263,18 +1480,20 @@ pub(crate) async fn invite_helper(
     room_id: &RoomId,
     reason: Option<String>,
     is_direct: bool,
+    third_party_invite: Option<ThirdPartyInvite>,
 ) -> Result<()> {
+    if user_id.server_name() == services().globals.server_name()
+        && !services().users.is_invite_allowed(sender_user, user_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This user does not accept invites from you.",
+        ));
+    }
+
     if user_id.server_name() != services().globals.server_name() {
         let (pdu, pdu_json, invite_room_state) = {
-            let mutex_state = Arc::clone(
-                services()
-                    .globals
-                    .roomid_mutex_state
-                    .write()
-                    .unwrap()
-                    .entry(room_id.to_owned())
-                    .or_default(),
-            );
+            let mutex_state = services().globals.roomid_mutex_state.mutex(room_id);
             let state_lock = mutex_state.lock().await;
 
             let content = to_raw_value(&RoomMemberEventContent {
@@ -1282,7 +1501,7 @@ pub(crate) async fn invite_helper(
                 displayname: None,
                 is_direct: Some(is_direct),
                 membership: MembershipState::Invite,
-                third_party_invite: None,
+                third_party_invite,
                 blurhash: None,
                 reason,
                 join_authorized_via_users_server: None,
@@ -1393,15 +1612,7 @@ pub(crate) async fn invite_helper(
         ));
     }
 
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(room_id.to_owned())
-            .or_default(),
-    );
+    let mutex_state = services().globals.roomid_mutex_state.mutex(room_id);
     let state_lock = mutex_state.lock().await;
 
     services()
@@ -1415,7 +1626,7 @@ pub(crate) async fn invite_helper(
                     displayname: services().users.displayname(user_id)?,
                     avatar_url: services().users.avatar_url(user_id)?,
                     is_direct: Some(is_direct),
-                    third_party_invite: None,
+                    third_party_invite,
                     blurhash: services().users.blurhash(user_id)?,
                     reason,
                     join_authorized_via_users_server: None,
@@ -1436,6 +1647,82 @@ pub(crate) async fn invite_helper(
     Ok(())
 }
 
+/// Joins a newly registered local user to all the rooms configured in `auto_join_rooms`.
+///
+/// Local aliases that do not exist yet are created as public rooms owned by the server user if
+/// `auto_join_rooms_create_if_absent` is enabled. Failures are logged and do not abort the
+/// registration.
+pub(crate) async fn auto_join_rooms(user_id: &UserId) {
+    for room in services().globals.auto_join_rooms() {
+        let (room_id, servers) = match OwnedRoomId::try_from(room.clone()) {
+            Ok(room_id) => {
+                let servers = vec![room_id
+                    .server_name()
+                    .unwrap_or(services().globals.server_name())
+                    .to_owned()];
+                (room_id, servers)
+            }
+            Err(room_alias) => {
+                let local_room_id = if room_alias.server_name() == services().globals.server_name()
+                {
+                    match services().rooms.alias.resolve_local_alias(&room_alias) {
+                        Ok(Some(room_id)) => Some(room_id),
+                        Ok(None) if services().globals.auto_join_rooms_create_if_absent() => {
+                            info!("Auto-join room {room_alias} does not exist, creating it");
+                            match services()
+                                .admin
+                                .create_public_room_with_alias(&room_alias)
+                                .await
+                            {
+                                Ok(room_id) => Some(room_id),
+                                Err(e) => {
+                                    error!("Failed to create auto-join room {room_alias}: {e}");
+                                    continue;
+                                }
+                            }
+                        }
+                        Ok(None) => None,
+                        Err(e) => {
+                            error!("Failed to resolve auto-join room alias {room_alias}: {e}");
+                            continue;
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                match local_room_id {
+                    Some(room_id) => (room_id, vec![services().globals.server_name().to_owned()]),
+                    None => match get_alias_helper(room_alias.clone()).await {
+                        Ok(response) => (response.room_id, response.servers),
+                        Err(e) => {
+                            warn!("Failed to resolve auto-join room alias {room_alias}: {e}");
+                            continue;
+                        }
+                    },
+                }
+            }
+        };
+
+        match services().rooms.metadata.is_banned(&room_id) {
+            Ok(false) => {}
+            Ok(true) => {
+                warn!("Not auto-joining {user_id} to {room_id} as the room is banned");
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to check if auto-join room {room_id} is banned: {e}");
+                continue;
+            }
+        }
+
+        match join_room_by_id_helper(Some(user_id), &room_id, None, &servers, None).await {
+            Ok(_) => info!("Auto-joined {user_id} to {room_id}"),
+            Err(e) => warn!("Failed to auto-join {user_id} to {room_id}: {e}"),
+        }
+    }
+}
+
 // Make a user leave all their joined rooms
 pub async fn leave_all_rooms(user_id: &UserId) -> Result<()> {
     let all_rooms = services()
@@ -1496,15 +1783,7 @@ pub async fn leave_room(user_id: &UserId, room_id: &RoomId, reason: Option<Strin
             )
             .await?;
     } else {
-        let mutex_state = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_state
-                .write()
-                .unwrap()
-                .entry(room_id.to_owned())
-                .or_default(),
-        );
+        let mutex_state = services().globals.roomid_mutex_state.mutex(room_id);
         let state_lock = mutex_state.lock().await;
 
         let member_event = services().rooms.state_accessor.room_state_get(
@@ -1566,10 +1845,6 @@ pub async fn leave_room(user_id: &UserId, room_id: &RoomId, reason: Option<Strin
 }
 
 async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
-    let mut make_leave_response_and_server = Err(Error::BadServerResponse(
-        "No server available to assist in leaving.",
-    ));
-
     let invite_state = services()
         .rooms
         .state_cache
@@ -1588,6 +1863,19 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
         .map(|user| user.server_name().to_owned())
         .collect();
 
+    remote_leave_room_via(user_id, room_id, servers).await
+}
+
+/// Leaves a room we are not participating in by asking the given servers to assist.
+async fn remote_leave_room_via(
+    user_id: &UserId,
+    room_id: &RoomId,
+    servers: impl IntoIterator<Item = OwnedServerName>,
+) -> Result<()> {
+    let mut make_leave_response_and_server = Err(Error::BadServerResponse(
+        "No server available to assist in leaving.",
+    ));
+
     for remote_server in servers {
         let make_leave_response = services()
             .sending
@@ -1682,3 +1970,37 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn membership_filters_apply_together() {
+        use MembershipState::{Ban, Invite, Join, Leave};
+
+        let (join, leave) = (MembershipEventFilter::Join, MembershipEventFilter::Leave);
+        let (join, leave) = (Some(&join), Some(&leave));
+
+        // (membership, membership filter, not_membership filter, expected)
+        let cases = [
+            (Ban, None, None, true),
+            (Join, join, None, true),
+            (Invite, join, None, false),
+            (Invite, None, leave, true),
+            (Leave, None, leave, false),
+            (Join, join, leave, true),
+            (Ban, join, leave, false),
+            (Invite, join, leave, false),
+            (Leave, join, leave, false),
+        ];
+
+        for (membership, only, not, expected) in cases {
+            assert_eq!(
+                membership_filter_matches(&membership, only, not),
+                expected,
+                "{membership:?} with membership={only:?} not_membership={not:?}"
+            );
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use crate::{
     service::{pdu::PduBuilder, rooms::timeline::PduCount},
-    services, utils, Error, Result, Ruma,
+    services,
+    utils::{self, filter::room_event_filter_matches},
+    Error, Result, Ruma,
 };
 use ruma::{
     api::client::{
@@ -10,10 +12,7 @@ use ruma::{
     events::{StateEventType, TimelineEventType},
 };
 use serde_json::from_str;
-use std::{
-    collections::{BTreeMap, HashSet},
-    sync::Arc,
-};
+use std::collections::{BTreeMap, HashSet};
 
 /// # `PUT /_matrix/client/v3/rooms/{roomId}/send/{eventType}/{txnId}`
 ///
@@ -28,17 +27,13 @@ pub async fn send_message_event_route(
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
     let sender_device = body.sender_device.as_deref();
 
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(body.room_id.clone())
-            .or_default(),
-    );
+    let mutex_state = services().globals.roomid_mutex_state.mutex(&body.room_id);
     let state_lock = mutex_state.lock().await;
 
+    services()
+        .users
+        .check_current_terms(sender_user, body.from_appservice)?;
+
     // Forbid m.room.encrypted if encryption is disabled
     if TimelineEventType::RoomEncrypted == body.event_type.to_string().into()
         && !services().globals.allow_encryption()
@@ -184,7 +179,11 @@ pub async fn get_message_events_route(
         from,
     )?;
 
-    let limit = u64::from(body.limit).min(100) as usize;
+    let limit = body
+        .filter
+        .limit
+        .map_or(body.limit, |filter_limit| filter_limit.min(body.limit));
+    let limit = u64::from(limit).min(100) as usize;
 
     let next_token;
 
@@ -198,8 +197,9 @@ pub async fn get_message_events_route(
                 .rooms
                 .timeline
                 .pdus_after(sender_user, &body.room_id, from)?
-                .take(limit)
                 .filter_map(|r| r.ok()) // Filter out buggy events
+                .filter(|(_, pdu)| room_event_filter_matches(&body.filter, pdu))
+                .take(limit)
                 .filter(|(_, pdu)| {
                     services()
                         .rooms
@@ -247,8 +247,9 @@ pub async fn get_message_events_route(
                 .rooms
                 .timeline
                 .pdus_until(sender_user, &body.room_id, from)?
-                .take(limit)
                 .filter_map(|r| r.ok()) // Filter out buggy events
+                .filter(|(_, pdu)| room_event_filter_matches(&body.filter, pdu))
+                .take(limit)
                 .filter(|(_, pdu)| {
                     services()
                         .rooms
@@ -11,6 +11,7 @@ mod keys;
 mod media;
 mod membership;
 mod message;
+mod peek;
 mod presence;
 mod profile;
 mod push;
@@ -23,6 +24,7 @@ mod search;
 mod session;
 mod space;
 mod state;
+mod synapse_admin;
 mod sync;
 mod tag;
 mod thirdparty;
@@ -46,6 +48,7 @@ pub use keys::*;
 pub use media::*;
 pub use membership::*;
 pub use message::*;
+pub use peek::*;
 pub use presence::*;
 pub use profile::*;
 pub use push::*;
@@ -58,6 +61,7 @@ pub use search::*;
 pub use session::*;
 pub use space::*;
 pub use state::*;
+pub use synapse_admin::*;
 pub use sync::*;
 pub use tag::*;
 pub use thirdparty::*;
@@ -0,0 +1,65 @@
+use axum::{extract::Path, response::IntoResponse, Json};
+use ruma::{api::client::error::ErrorKind, OwnedDeviceId, OwnedRoomId, OwnedRoomOrAliasId};
+
+use super::get_alias_helper;
+use crate::{services, Authenticated, Error, Result};
+
+/// # `POST /_matrix/client/unstable/org.matrix.msc2753/peek/{roomIdOrAlias}`
+///
+/// Starts peeking into a room without joining it (MSC2753).
+///
+/// - Only works for rooms with world readable history this server participates in
+/// - Until the device stops peeking, the room is sent in the `peek` section of its syncs
+/// - Guests can peek too
+pub async fn peek_route(
+    auth: Authenticated,
+    Path(room_id_or_alias): Path<OwnedRoomOrAliasId>,
+) -> Result<impl IntoResponse> {
+    let sender_device = peeking_device(auth.sender_device)?;
+
+    let room_id = match OwnedRoomId::try_from(room_id_or_alias) {
+        Ok(room_id) => room_id,
+        Err(room_alias) => get_alias_helper(room_alias).await?.room_id,
+    };
+
+    if services().rooms.metadata.is_banned(&room_id)?
+        && !services().users.is_admin(&auth.sender_user)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room is banned on this homeserver.",
+        ));
+    }
+
+    services()
+        .rooms
+        .peeking
+        .peek(&auth.sender_user, &sender_device, &room_id)?;
+
+    Ok(Json(serde_json::json!({ "room_id": room_id })))
+}
+
+/// # `POST /_matrix/client/unstable/org.matrix.msc2753/rooms/{roomId}/unpeek`
+///
+/// Stops peeking into a room.
+pub async fn unpeek_route(
+    auth: Authenticated,
+    Path(room_id): Path<OwnedRoomId>,
+) -> Result<impl IntoResponse> {
+    let sender_device = peeking_device(auth.sender_device)?;
+
+    services()
+        .rooms
+        .peeking
+        .unpeek(&auth.sender_user, &sender_device, &room_id)?;
+
+    Ok(Json(serde_json::json!({})))
+}
+
+/// Peeks belong to a device, so appservices can only peek when they act as one.
+fn peeking_device(sender_device: Option<OwnedDeviceId>) -> Result<OwnedDeviceId> {
+    sender_device.ok_or(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "Peeking requires a device.",
+    ))
+}
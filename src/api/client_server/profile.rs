@@ -1,19 +1,16 @@
 use crate::{service::pdu::PduBuilder, services, Error, Result, Ruma};
 use ruma::{
-    api::{
-        client::{
-            error::ErrorKind,
-            profile::{
-                get_avatar_url, get_display_name, get_profile, set_avatar_url, set_display_name,
-            },
+    api::client::{
+        error::ErrorKind,
+        profile::{
+            get_avatar_url, get_display_name, get_profile, set_avatar_url, set_display_name,
         },
-        federation::{self, query::get_profile_information::v1::ProfileField},
     },
     events::{room::member::RoomMemberEventContent, StateEventType, TimelineEventType},
     presence::PresenceState,
+    UserId,
 };
 use serde_json::value::to_raw_value;
-use std::sync::Arc;
 
 /// # `PUT /_matrix/client/r0/profile/{userId}/displayname`
 ///
@@ -31,66 +28,11 @@ pub async fn set_displayname_route(
         .await?;
 
     // Send a new membership event and presence update into all joined rooms
-    let all_rooms_joined: Vec<_> = services()
-        .rooms
-        .state_cache
-        .rooms_joined(sender_user)
-        .filter_map(|r| r.ok())
-        .map(|room_id| {
-            Ok::<_, Error>((
-                PduBuilder {
-                    event_type: TimelineEventType::RoomMember,
-                    content: to_raw_value(&RoomMemberEventContent {
-                        displayname: body.displayname.clone(),
-                        ..serde_json::from_str(
-                            services()
-                                .rooms
-                                .state_accessor
-                                .room_state_get(
-                                    &room_id,
-                                    &StateEventType::RoomMember,
-                                    sender_user.as_str(),
-                                )?
-                                .ok_or_else(|| {
-                                    Error::bad_database(
-                                        "Tried to send displayname update for user not in the \
-                                     room.",
-                                    )
-                                })?
-                                .content
-                                .get(),
-                        )
-                        .map_err(|_| Error::bad_database("Database contains invalid PDU."))?
-                    })
-                    .expect("event is valid, we just created it"),
-                    unsigned: None,
-                    state_key: Some(sender_user.to_string()),
-                    redacts: None,
-                },
-                room_id,
-            ))
-        })
-        .filter_map(|r| r.ok())
-        .collect();
-
-    for (pdu_builder, room_id) in all_rooms_joined {
-        let mutex_state = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_state
-                .write()
-                .unwrap()
-                .entry(room_id.clone())
-                .or_default(),
-        );
-        let state_lock = mutex_state.lock().await;
-
-        let _ = services()
-            .rooms
-            .timeline
-            .build_and_append_pdu(pdu_builder, sender_user, &room_id, &state_lock)
-            .await;
-    }
+    update_joined_memberships(sender_user, |content| RoomMemberEventContent {
+        displayname: body.displayname.clone(),
+        ..content
+    })
+    .await;
 
     if services().globals.allow_local_presence() {
         // Presence update
@@ -108,47 +50,18 @@ pub async fn set_displayname_route(
 ///
 /// Returns the displayname of the user.
 ///
-/// - If user is on another server and we do not have a local copy already
-/// fetch displayname over federation
+/// - If user is on another server, fetch displayname over federation, unless the profile is
+/// cached
 pub async fn get_displayname_route(
     body: Ruma<get_display_name::v3::Request>,
 ) -> Result<get_display_name::v3::Response> {
     if (services().users.exists(&body.user_id)?)
         && (body.user_id.server_name() != services().globals.server_name())
     {
-        let response = services()
-            .sending
-            .send_federation_request(
-                body.user_id.server_name(),
-                federation::query::get_profile_information::v1::Request {
-                    user_id: body.user_id.clone(),
-                    field: Some(ProfileField::DisplayName),
-                },
-            )
-            .await?;
-
-        /*
-            TODO: ignore errors properly?
-        // Create and update our local copy of the user
-        // these are `let _` because it's fine if we can't find these for the user.
-        // also these requests are sent on room join so dead servers will make room joins annoying again
-        let _ = services().users.create(&body.user_id, None);
-        let _ = services()
-            .users
-            .set_displayname(&body.user_id, response.displayname.clone())
-            .await;
-        let _ = services()
-            .users
-            .set_avatar_url(&body.user_id, response.avatar_url)
-            .await;
-        let _ = services()
-            .users
-            .set_blurhash(&body.user_id, response.blurhash)
-            .await;
-        */
+        let profile = services().users.remote_profile(&body.user_id).await?;
 
         return Ok(get_display_name::v3::Response {
-            displayname: response.displayname,
+            displayname: profile.displayname,
         });
     }
 
@@ -178,66 +91,11 @@ pub async fn set_avatar_url_route(
         .await?;
 
     // Send a new membership event and presence update into all joined rooms
-    let all_joined_rooms: Vec<_> = services()
-        .rooms
-        .state_cache
-        .rooms_joined(sender_user)
-        .filter_map(|r| r.ok())
-        .map(|room_id| {
-            Ok::<_, Error>((
-                PduBuilder {
-                    event_type: TimelineEventType::RoomMember,
-                    content: to_raw_value(&RoomMemberEventContent {
-                        avatar_url: body.avatar_url.clone(),
-                        ..serde_json::from_str(
-                            services()
-                                .rooms
-                                .state_accessor
-                                .room_state_get(
-                                    &room_id,
-                                    &StateEventType::RoomMember,
-                                    sender_user.as_str(),
-                                )?
-                                .ok_or_else(|| {
-                                    Error::bad_database(
-                                        "Tried to send displayname update for user not in the \
-                                     room.",
-                                    )
-                                })?
-                                .content
-                                .get(),
-                        )
-                        .map_err(|_| Error::bad_database("Database contains invalid PDU."))?
-                    })
-                    .expect("event is valid, we just created it"),
-                    unsigned: None,
-                    state_key: Some(sender_user.to_string()),
-                    redacts: None,
-                },
-                room_id,
-            ))
-        })
-        .filter_map(|r| r.ok())
-        .collect();
-
-    for (pdu_builder, room_id) in all_joined_rooms {
-        let mutex_state = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_state
-                .write()
-                .unwrap()
-                .entry(room_id.clone())
-                .or_default(),
-        );
-        let state_lock = mutex_state.lock().await;
-
-        let _ = services()
-            .rooms
-            .timeline
-            .build_and_append_pdu(pdu_builder, sender_user, &room_id, &state_lock)
-            .await;
-    }
+    update_joined_memberships(sender_user, |content| RoomMemberEventContent {
+        avatar_url: body.avatar_url.clone(),
+        ..content
+    })
+    .await;
 
     if services().globals.allow_local_presence() {
         // Presence update
@@ -255,48 +113,19 @@ pub async fn set_avatar_url_route(
 ///
 /// Returns the avatar_url and blurhash of the user.
 ///
-/// - If user is on another server and we do not have a local copy already
-/// fetch avatar_url and blurhash over federation
+/// - If user is on another server, fetch avatar_url and blurhash over federation, unless the
+/// profile is cached
 pub async fn get_avatar_url_route(
     body: Ruma<get_avatar_url::v3::Request>,
 ) -> Result<get_avatar_url::v3::Response> {
     if (services().users.exists(&body.user_id)?)
         && (body.user_id.server_name() != services().globals.server_name())
     {
-        let response = services()
-            .sending
-            .send_federation_request(
-                body.user_id.server_name(),
-                federation::query::get_profile_information::v1::Request {
-                    user_id: body.user_id.clone(),
-                    field: Some(ProfileField::AvatarUrl),
-                },
-            )
-            .await?;
-
-        /*
-            TODO: ignore errors properly?
-        // Create and update our local copy of the user
-        // these are `let _` because it's fine if we can't find these for the user.
-        // also these requests are sent on room join so dead servers will make room joins annoying again
-        let _ = services().users.create(&body.user_id, None);
-        let _ = services()
-            .users
-            .set_displayname(&body.user_id, response.displayname)
-            .await;
-        let _ = services()
-            .users
-            .set_avatar_url(&body.user_id, response.avatar_url.clone())
-            .await;
-        let _ = services()
-            .users
-            .set_blurhash(&body.user_id, response.blurhash.clone())
-            .await;
-        */
+        let profile = services().users.remote_profile(&body.user_id).await?;
 
         return Ok(get_avatar_url::v3::Response {
-            avatar_url: response.avatar_url,
-            blurhash: response.blurhash,
+            avatar_url: profile.avatar_url,
+            blurhash: profile.blurhash,
         });
     }
 
@@ -310,53 +139,25 @@ pub async fn get_avatar_url_route(
 ///
 /// Returns the displayname, avatar_url and blurhash of the user.
 ///
-/// - If user is on another server and we do not have a local copy already,
-/// fetch profile over federation.
+/// - If user is on another server, fetch profile over federation, unless it is cached.
 pub async fn get_profile_route(
     body: Ruma<get_profile::v3::Request>,
 ) -> Result<get_profile::v3::Response> {
     if (services().users.exists(&body.user_id)?)
         && (body.user_id.server_name() != services().globals.server_name())
     {
-        let response = services()
-            .sending
-            .send_federation_request(
-                body.user_id.server_name(),
-                federation::query::get_profile_information::v1::Request {
-                    user_id: body.user_id.clone(),
-                    field: None,
-                },
-            )
-            .await?;
-
-        /*
-            TODO: ignore errors properly?
-        // Create and update our local copy of the user
-        // these are `let _` because it's fine if we can't find these for the user.
-        // also these requests are sent on room join so dead servers will make room joins annoying again
-        let _ = services().users.create(&body.user_id, None);
-        let _ = services()
-            .users
-            .set_displayname(&body.user_id, response.displayname.clone())
-            .await;
-        let _ = services()
-            .users
-            .set_avatar_url(&body.user_id, response.avatar_url.clone())
-            .await;
-        let _ = services()
-            .users
-            .set_blurhash(&body.user_id, response.blurhash.clone())
-            .await;
-        */
+        let profile = services().users.remote_profile(&body.user_id).await?;
 
         return Ok(get_profile::v3::Response {
-            displayname: response.displayname,
-            avatar_url: response.avatar_url,
-            blurhash: response.blurhash,
+            displayname: profile.displayname,
+            avatar_url: profile.avatar_url,
+            blurhash: profile.blurhash,
         });
     }
 
-    if !services().users.exists(&body.user_id)? {
+    if !services().users.exists(&body.user_id)?
+        && !services().appservice.query_user_id(&body.user_id).await?
+    {
         // Return 404 if this user doesn't exist and we couldn't fetch it over federation
         return Err(Error::BadRequest(
             ErrorKind::NotFound,
@@ -370,3 +171,61 @@ pub async fn get_profile_route(
         displayname: services().users.displayname(&body.user_id)?,
     })
 }
+
+/// Sends a new membership event into every room the user joined, with the update applied to their
+/// current membership event in that room. Used to propagate profile changes.
+pub async fn update_joined_memberships<F>(user_id: &UserId, update: F)
+where
+    F: Fn(RoomMemberEventContent) -> RoomMemberEventContent,
+{
+    let all_rooms_joined: Vec<_> = services()
+        .rooms
+        .state_cache
+        .rooms_joined(user_id)
+        .filter_map(|r| r.ok())
+        .map(|room_id| {
+            Ok::<_, Error>((
+                PduBuilder {
+                    event_type: TimelineEventType::RoomMember,
+                    content: to_raw_value(&update(
+                        serde_json::from_str(
+                            services()
+                                .rooms
+                                .state_accessor
+                                .room_state_get(
+                                    &room_id,
+                                    &StateEventType::RoomMember,
+                                    user_id.as_str(),
+                                )?
+                                .ok_or_else(|| {
+                                    Error::bad_database(
+                                        "Tried to send profile update for user not in the room.",
+                                    )
+                                })?
+                                .content
+                                .get(),
+                        )
+                        .map_err(|_| Error::bad_database("Database contains invalid PDU."))?,
+                    ))
+                    .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some(user_id.to_string()),
+                    redacts: None,
+                },
+                room_id,
+            ))
+        })
+        .filter_map(|r| r.ok())
+        .collect();
+
+    for (pdu_builder, room_id) in all_rooms_joined {
+        let mutex_state = services().globals.roomid_mutex_state.mutex(&room_id);
+        let state_lock = mutex_state.lock().await;
+
+        let _ = services()
+            .rooms
+            .timeline
+            .build_and_append_pdu(pdu_builder, user_id, &room_id, &state_lock)
+            .await;
+    }
+}
@@ -3,9 +3,9 @@ use ruma::{
     api::client::{
         error::ErrorKind,
         push::{
-            delete_pushrule, get_pushers, get_pushrule, get_pushrule_actions, get_pushrule_enabled,
-            get_pushrules_all, set_pusher, set_pushrule, set_pushrule_actions,
-            set_pushrule_enabled, RuleScope,
+            delete_pushrule, get_notifications, get_pushers, get_pushrule, get_pushrule_actions,
+            get_pushrule_enabled, get_pushrules_all, set_pusher, set_pushrule,
+            set_pushrule_actions, set_pushrule_enabled, RuleScope,
         },
     },
     events::{push_rules::PushRulesEvent, GlobalAccountDataEventType},
@@ -430,3 +430,92 @@ pub async fn set_pushers_route(
 
     Ok(set_pusher::v3::Response::default())
 }
+
+/// # `GET /_matrix/client/r0/notifications`
+///
+/// Paginates the events that notified the sender user, newest first.
+///
+/// - Only returns events the user is still allowed to see
+/// - `only=highlight` only returns notifications that were highlighted
+pub async fn get_notifications_route(
+    body: Ruma<get_notifications::v3::Request>,
+) -> Result<get_notifications::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let from = body
+        .from
+        .as_deref()
+        .map(|from| {
+            from.parse()
+                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from` value."))
+        })
+        .transpose()?
+        .unwrap_or(u64::MAX);
+
+    let limit = body.limit.map_or(50, u64::from).min(100) as usize;
+
+    let only_highlight = body.only.as_deref() == Some("highlight");
+
+    let logged: Vec<_> = services()
+        .rooms
+        .user
+        .notifications_until(sender_user, from)
+        .filter_map(Result::ok)
+        .filter(|(_, notification)| !only_highlight || notification.highlight)
+        .filter_map(|(count, notification)| {
+            let mut pdu = services()
+                .rooms
+                .timeline
+                .get_pdu(&notification.event_id)
+                .ok()
+                .flatten()?
+                .as_ref()
+                .clone();
+
+            if !services()
+                .rooms
+                .state_accessor
+                .user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
+                .unwrap_or(false)
+            {
+                return None;
+            }
+
+            if pdu.sender != *sender_user {
+                pdu.remove_transaction_id().ok()?;
+            }
+
+            Some((count, notification, pdu))
+        })
+        .take(limit)
+        .collect();
+
+    let next_token = if logged.len() == limit {
+        logged.last().map(|(count, _, _)| count.to_string())
+    } else {
+        None
+    };
+
+    let mut notifications = Vec::new();
+    for (count, notification, pdu) in logged {
+        let read = count
+            < services()
+                .rooms
+                .user
+                .last_notification_read(sender_user, &notification.room_id)?;
+
+        notifications.push(get_notifications::v3::Notification {
+            actions: notification.actions,
+            event: pdu.to_sync_room_event(),
+            profile_tag: None,
+            read,
+            room_id: notification.room_id,
+            ts: notification.ts,
+        });
+    }
+
+    Ok(get_notifications::v3::Response {
+        next_token,
+        notifications,
+    })
+}
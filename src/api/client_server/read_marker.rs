@@ -90,6 +90,7 @@ pub async fn set_read_marker_route(
                 room_id: body.room_id.clone(),
             },
         )?;
+        services().sending.flush_room_edus(&body.room_id)?;
     }
 
     Ok(set_read_marker::v3::Response {})
@@ -107,10 +108,20 @@ pub async fn create_receipt_route(
         &body.receipt_type,
         create_receipt::v3::ReceiptType::Read | create_receipt::v3::ReceiptType::ReadPrivate
     ) {
-        services()
-            .rooms
-            .user
-            .reset_notification_counts(sender_user, &body.room_id)?;
+        match &body.thread {
+            ReceiptThread::Thread(thread_id) => services()
+                .rooms
+                .user
+                .reset_thread_notification_counts(sender_user, &body.room_id, thread_id)?,
+            ReceiptThread::Main => services()
+                .rooms
+                .user
+                .reset_main_notification_counts(sender_user, &body.room_id)?,
+            _ => services()
+                .rooms
+                .user
+                .reset_notification_counts(sender_user, &body.room_id)?,
+        }
     }
 
     match body.receipt_type {
@@ -133,7 +144,7 @@ pub async fn create_receipt_route(
                 sender_user.clone(),
                 ruma::events::receipt::Receipt {
                     ts: Some(MilliSecondsSinceUnixEpoch::now()),
-                    thread: ReceiptThread::Unthreaded,
+                    thread: body.thread.clone(),
                 },
             );
             let mut receipts = BTreeMap::new();
@@ -150,6 +161,7 @@ pub async fn create_receipt_route(
                     room_id: body.room_id.clone(),
                 },
             )?;
+            services().sending.flush_room_edus(&body.room_id)?;
         }
         create_receipt::v3::ReceiptType::ReadPrivate => {
             let count = services()
@@ -1,33 +1,46 @@
-use std::sync::Arc;
-
-use crate::{service::pdu::PduBuilder, services, Result, Ruma};
+use crate::{
+    service::{pdu::PduBuilder, rooms::timeline::PduCount},
+    services, Error, Result, Ruma,
+};
 use ruma::{
-    api::client::redact::redact_event,
-    events::{room::redaction::RoomRedactionEventContent, TimelineEventType},
+    api::{client::redact::redact_event, Direction},
+    events::{
+        room::{power_levels::RoomPowerLevelsEventContent, redaction::RoomRedactionEventContent},
+        StateEventType, TimelineEventType,
+    },
+    CanonicalJsonValue, EventId, RoomId, UserId,
 };
-
+use serde::Deserialize;
 use serde_json::value::to_raw_value;
+use tokio::sync::MutexGuard;
+
+/// Field of the redaction request that lists the relation types of the events that are redacted
+/// together with the event (MSC3912)
+const WITH_RELATIONS_FIELD: &str = "org.matrix.msc3912.with_relations";
 
 /// # `PUT /_matrix/client/r0/rooms/{roomId}/redact/{eventId}/{txnId}`
 ///
 /// Tries to send a redaction event into the room.
 ///
+/// - Also redacts the events relating to the event with the relation types listed in
+/// `org.matrix.msc3912.with_relations`, as far as the user is allowed to
 /// - TODO: Handle txn id
 pub async fn redact_event_route(
     body: Ruma<redact_event::v3::Request>,
 ) -> Result<redact_event::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let with_relations: Vec<String> = match &body.json_body {
+        Some(CanonicalJsonValue::Object(json)) => json
+            .get(WITH_RELATIONS_FIELD)
+            .and_then(|rel_types| serde_json::from_value(rel_types.clone().into()).ok())
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    };
+
     let body = body.body;
 
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(body.room_id.clone())
-            .or_default(),
-    );
+    let mutex_state = services().globals.roomid_mutex_state.mutex(&body.room_id);
     let state_lock = mutex_state.lock().await;
 
     let event_id = services()
@@ -43,7 +56,7 @@ pub async fn redact_event_route(
                 .expect("event is valid, we just created it"),
                 unsigned: None,
                 state_key: None,
-                redacts: Some(body.event_id.into()),
+                redacts: Some(body.event_id.clone().into()),
             },
             sender_user,
             &body.room_id,
@@ -51,8 +64,106 @@ pub async fn redact_event_route(
         )
         .await?;
 
+    if !with_relations.is_empty() {
+        redact_relations(
+            sender_user,
+            &body.room_id,
+            &body.event_id,
+            &with_relations,
+            body.reason.as_deref(),
+            &state_lock,
+        )
+        .await?;
+    }
+
     drop(state_lock);
 
     let event_id = (*event_id).to_owned();
     Ok(redact_event::v3::Response { event_id })
 }
+
+/// Redacts the events relating to the event with one of the relation types. Events of other users
+/// are skipped if the user isn't allowed to redact them.
+async fn redact_relations(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    event_id: &EventId,
+    rel_types: &[String],
+    reason: Option<&str>,
+    state_lock: &MutexGuard<'_, ()>,
+) -> Result<()> {
+    #[derive(Deserialize)]
+    struct ExtractRelType {
+        rel_type: String,
+    }
+    #[derive(Deserialize)]
+    struct ExtractRelatesTo {
+        #[serde(rename = "m.relates_to")]
+        relates_to: ExtractRelType,
+    }
+
+    let power_levels: RoomPowerLevelsEventContent = services()
+        .rooms
+        .state_accessor
+        .room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+        .map(|ev| {
+            serde_json::from_str(ev.content.get())
+                .map_err(|_| Error::bad_database("Invalid m.room.power_levels event in database."))
+        })
+        .transpose()?
+        .unwrap_or_default();
+    let can_redact_others = power_levels
+        .users
+        .get(sender_user)
+        .unwrap_or(&power_levels.users_default)
+        >= &power_levels.redact;
+
+    let relations: Vec<_> = services()
+        .rooms
+        .pdu_metadata
+        .relations(
+            sender_user,
+            room_id,
+            event_id,
+            PduCount::max(),
+            Direction::Backward,
+            1,
+        )?
+        .collect();
+
+    for (_, pdu, _) in relations {
+        let Ok(content) = serde_json::from_str::<ExtractRelatesTo>(pdu.content.get()) else {
+            // Already redacted
+            continue;
+        };
+
+        if !rel_types.contains(&content.relates_to.rel_type)
+            || (pdu.sender != sender_user && !can_redact_others)
+        {
+            continue;
+        }
+
+        services()
+            .rooms
+            .timeline
+            .build_and_append_pdu(
+                PduBuilder {
+                    event_type: TimelineEventType::RoomRedaction,
+                    content: to_raw_value(&RoomRedactionEventContent {
+                        redacts: Some((*pdu.event_id).to_owned()),
+                        reason: reason.map(ToOwned::to_owned),
+                    })
+                    .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: None,
+                    redacts: Some(pdu.event_id.clone()),
+                },
+                sender_user,
+                room_id,
+                state_lock,
+            )
+            .await?;
+    }
+
+    Ok(())
+}
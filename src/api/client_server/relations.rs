@@ -3,7 +3,7 @@ use ruma::api::client::relations::{
     get_relating_events_with_rel_type_and_event_type,
 };
 
-use crate::{service::rooms::timeline::PduCount, services, Result, Ruma};
+use crate::{services, Result, Ruma};
 
 /// # `GET /_matrix/client/r0/rooms/{roomId}/relations/{eventId}/{relType}/{eventType}`
 pub async fn get_relating_events_with_rel_type_and_event_type_route(
@@ -11,27 +11,6 @@ pub async fn get_relating_events_with_rel_type_and_event_type_route(
 ) -> Result<get_relating_events_with_rel_type_and_event_type::v1::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let from = match body.from.clone() {
-        Some(from) => PduCount::try_from_string(&from)?,
-        None => match ruma::api::Direction::Backward {
-            // TODO: fix ruma so `body.dir` exists
-            ruma::api::Direction::Forward => PduCount::min(),
-            ruma::api::Direction::Backward => PduCount::max(),
-        },
-    };
-
-    let to = body
-        .to
-        .as_ref()
-        .and_then(|t| PduCount::try_from_string(t).ok());
-
-    // Use limit or else 10, with maximum 100
-    let limit = body
-        .limit
-        .and_then(|u| u32::try_from(u).ok())
-        .map_or(10_usize, |u| u as usize)
-        .min(100);
-
     let res = services()
         .rooms
         .pdu_metadata
@@ -41,9 +20,11 @@ pub async fn get_relating_events_with_rel_type_and_event_type_route(
             &body.event_id,
             Some(body.event_type.clone()),
             Some(body.rel_type.clone()),
-            from,
-            to,
-            limit,
+            body.from.as_deref(),
+            body.to.as_deref(),
+            body.limit,
+            body.recurse,
+            body.dir,
         )?;
 
     Ok(
@@ -51,6 +32,7 @@ pub async fn get_relating_events_with_rel_type_and_event_type_route(
             chunk: res.chunk,
             next_batch: res.next_batch,
             prev_batch: res.prev_batch,
+            recursion_depth: res.recursion_depth,
         },
     )
 }
@@ -61,27 +43,6 @@ pub async fn get_relating_events_with_rel_type_route(
 ) -> Result<get_relating_events_with_rel_type::v1::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let from = match body.from.clone() {
-        Some(from) => PduCount::try_from_string(&from)?,
-        None => match ruma::api::Direction::Backward {
-            // TODO: fix ruma so `body.dir` exists
-            ruma::api::Direction::Forward => PduCount::min(),
-            ruma::api::Direction::Backward => PduCount::max(),
-        },
-    };
-
-    let to = body
-        .to
-        .as_ref()
-        .and_then(|t| PduCount::try_from_string(t).ok());
-
-    // Use limit or else 10, with maximum 100
-    let limit = body
-        .limit
-        .and_then(|u| u32::try_from(u).ok())
-        .map_or(10_usize, |u| u as usize)
-        .min(100);
-
     let res = services()
         .rooms
         .pdu_metadata
@@ -91,15 +52,18 @@ pub async fn get_relating_events_with_rel_type_route(
             &body.event_id,
             None,
             Some(body.rel_type.clone()),
-            from,
-            to,
-            limit,
+            body.from.as_deref(),
+            body.to.as_deref(),
+            body.limit,
+            body.recurse,
+            body.dir,
         )?;
 
     Ok(get_relating_events_with_rel_type::v1::Response {
         chunk: res.chunk,
         next_batch: res.next_batch,
         prev_batch: res.prev_batch,
+        recursion_depth: res.recursion_depth,
     })
 }
 
@@ -109,27 +73,6 @@ pub async fn get_relating_events_route(
 ) -> Result<get_relating_events::v1::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let from = match body.from.clone() {
-        Some(from) => PduCount::try_from_string(&from)?,
-        None => match ruma::api::Direction::Backward {
-            // TODO: fix ruma so `body.dir` exists
-            ruma::api::Direction::Forward => PduCount::min(),
-            ruma::api::Direction::Backward => PduCount::max(),
-        },
-    };
-
-    let to = body
-        .to
-        .as_ref()
-        .and_then(|t| PduCount::try_from_string(t).ok());
-
-    // Use limit or else 10, with maximum 100
-    let limit = body
-        .limit
-        .and_then(|u| u32::try_from(u).ok())
-        .map_or(10_usize, |u| u as usize)
-        .min(100);
-
     services()
         .rooms
         .pdu_metadata
@@ -139,8 +82,10 @@ pub async fn get_relating_events_route(
             &body.event_id,
             None,
             None,
-            from,
-            to,
-            limit,
+            body.from.as_deref(),
+            body.to.as_deref(),
+            body.limit,
+            body.recurse,
+            body.dir,
         )
 }
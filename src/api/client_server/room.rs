@@ -1,5 +1,7 @@
 use crate::{
-    api::client_server::invite_helper, service::pdu::PduBuilder, services, Error, Result, Ruma,
+    api::client_server::{check_joined_rooms_limit, invite_helper},
+    service::pdu::PduBuilder,
+    services, Error, Result, Ruma,
 };
 use ruma::{
     api::client::{
@@ -27,7 +29,7 @@ use ruma::{
     RoomVersionId,
 };
 use serde_json::{json, value::to_raw_value};
-use std::{cmp::max, collections::BTreeMap, sync::Arc};
+use std::{cmp::max, collections::BTreeMap};
 use tracing::{debug, error, info, warn};
 
 /// # `POST /_matrix/client/v3/createRoom`
@@ -53,6 +55,10 @@ pub async fn create_room_route(
 
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    services()
+        .users
+        .check_current_terms(sender_user, body.from_appservice)?;
+
     if !services().globals.allow_room_creation()
         && !&body.from_appservice
         && !services().users.is_admin(sender_user)?
@@ -63,6 +69,10 @@ pub async fn create_room_route(
         ));
     }
 
+    if !body.from_appservice {
+        check_joined_rooms_limit(sender_user, None)?;
+    }
+
     let room_id: OwnedRoomId;
 
     // checks if the user specified an explicit (custom) room_id to be created with in request body.
@@ -135,15 +145,7 @@ pub async fn create_room_route(
 
     services().rooms.short.get_or_create_shortroomid(&room_id)?;
 
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(room_id.clone())
-            .or_default(),
-    );
+    let mutex_state = services().globals.roomid_mutex_state.mutex(&room_id);
     let state_lock = mutex_state.lock().await;
 
     let alias: Option<OwnedRoomAliasId> =
@@ -538,6 +540,14 @@ pub async fn create_room_route(
             continue;
         }
 
+        // The default server ACL is sent below and can't be replaced by the room creator
+        if pdu_builder.event_type == TimelineEventType::RoomServerAcl
+            && pdu_builder.state_key.as_deref() == Some("")
+            && services().globals.config.default_room_server_acl.is_some()
+        {
+            continue;
+        }
+
         services()
             .rooms
             .timeline
@@ -545,6 +555,26 @@ pub async fn create_room_route(
             .await?;
     }
 
+    // 6.1 Default server ACL of the server
+    if let Some(acl) = &services().globals.config.default_room_server_acl {
+        services()
+            .rooms
+            .timeline
+            .build_and_append_pdu(
+                PduBuilder {
+                    event_type: TimelineEventType::RoomServerAcl,
+                    content: to_raw_value(acl).expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some("".to_owned()),
+                    redacts: None,
+                },
+                sender_user,
+                &room_id,
+                &state_lock,
+            )
+            .await?;
+    }
+
     // 7. Events implied by name and topic
     if let Some(name) = &body.name {
         services()
@@ -591,7 +621,7 @@ pub async fn create_room_route(
     // 8. Events implied by invite (and TODO: invite_3pid)
     drop(state_lock);
     for user_id in &body.invite {
-        let _ = invite_helper(sender_user, user_id, &room_id, None, body.is_direct).await;
+        let _ = invite_helper(sender_user, user_id, &room_id, None, body.is_direct, None).await;
     }
 
     // Homeserver specific stuff
@@ -710,15 +740,7 @@ pub async fn upgrade_room_route(
         .short
         .get_or_create_shortroomid(&replacement_room)?;
 
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(body.room_id.clone())
-            .or_default(),
-    );
+    let mutex_state = services().globals.roomid_mutex_state.mutex(&body.room_id);
     let state_lock = mutex_state.lock().await;
 
     // Send a m.room.tombstone event to the old room to indicate that it is not intended to be used any further
@@ -746,15 +768,10 @@ pub async fn upgrade_room_route(
 
     // Change lock to replacement room
     drop(state_lock);
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(replacement_room.clone())
-            .or_default(),
-    );
+    let mutex_state = services()
+        .globals
+        .roomid_mutex_state
+        .mutex(&replacement_room);
     let state_lock = mutex_state.lock().await;
 
     // Get the old room creation event
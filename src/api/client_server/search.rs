@@ -1,10 +1,13 @@
-use crate::{services, Error, Result, Ruma};
-use ruma::api::client::{
-    error::ErrorKind,
-    search::search_events::{
-        self,
-        v3::{EventContextResult, ResultCategories, ResultRoomEvents, SearchResult},
+use crate::{services, utils, Error, Result, Ruma};
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        search::search_events::{
+            self,
+            v3::{Criteria, EventContextResult, ResultCategories, ResultRoomEvents, SearchResult},
+        },
     },
+    UserId,
 };
 
 use std::collections::BTreeMap;
@@ -17,9 +20,19 @@ use std::collections::BTreeMap;
 pub async fn search_events_route(
     body: Ruma<search_events::v3::Request>,
 ) -> Result<search_events::v3::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.sender_user.clone().expect("user is authenticated");
+    let search_criteria = body.search_categories.room_events.clone().unwrap();
+    let next_batch = body.next_batch.clone();
+
+    // Search results of all rooms are merged, so this doesn't run on the async runtime
+    utils::spawn_blocking(move || search_events(&sender_user, &search_criteria, next_batch)).await
+}
 
-    let search_criteria = body.search_categories.room_events.as_ref().unwrap();
+fn search_events(
+    sender_user: &UserId,
+    search_criteria: &Criteria,
+    next_batch: Option<String>,
+) -> Result<search_events::v3::Response> {
     let filter = &search_criteria.filter;
 
     let room_ids = filter.rooms.clone().unwrap_or_else(|| {
@@ -57,7 +70,7 @@ pub async fn search_events_route(
         }
     }
 
-    let skip = match body.next_batch.as_ref().map(|s| s.parse()) {
+    let skip = match next_batch.as_ref().map(|s| s.parse()) {
         Some(Ok(s)) => s,
         Some(Err(_)) => {
             return Err(Error::BadRequest(
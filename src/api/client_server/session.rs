@@ -1,4 +1,4 @@
-use super::{DEVICE_ID_LENGTH, TOKEN_LENGTH};
+use super::{create_local_user, welcome_local_user, DEVICE_ID_LENGTH, TOKEN_LENGTH};
 use crate::{services, utils, Error, Result, Ruma};
 use argon2::{PasswordHash, PasswordVerifier};
 use ruma::{
@@ -16,15 +16,8 @@ use ruma::{
     },
     UserId,
 };
-use serde::Deserialize;
 use tracing::{debug, error, info, warn};
 
-#[derive(Debug, Deserialize)]
-struct Claims {
-    sub: String,
-    //exp: usize,
-}
-
 /// # `GET /_matrix/client/v3/login`
 ///
 /// Get the supported login types of this server. One of these should be used as the `type` field
@@ -32,10 +25,20 @@ struct Claims {
 pub async fn get_login_types_route(
     _body: Ruma<get_login_types::v3::Request>,
 ) -> Result<get_login_types::v3::Response> {
-    Ok(get_login_types::v3::Response::new(vec![
+    if services().oidc.enabled() {
+        // Clients have to log in with the authorization server
+        return Ok(get_login_types::v3::Response::new(Vec::new()));
+    }
+
+    let mut login_types = vec![
         get_login_types::v3::LoginType::Password(Default::default()),
         get_login_types::v3::LoginType::ApplicationService(Default::default()),
-    ]))
+    ];
+    if services().jwt.enabled() {
+        login_types.push(get_login_types::v3::LoginType::Token(Default::default()));
+    }
+
+    Ok(get_login_types::v3::Response::new(login_types))
 }
 
 /// # `POST /_matrix/client/v3/login`
@@ -46,10 +49,18 @@ pub async fn get_login_types_route(
 /// - If `device_id` is known: invalidates old access token of that device
 /// - If `device_id` is unknown: creates a new device
 /// - Returns access token that is associated with the user and device
+/// - Disabled if authentication is delegated to an authorization server
 ///
 /// Note: You can use [`GET /_matrix/client/r0/login`](fn.get_supported_versions_route.html) to see
 /// supported login types.
 pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Response> {
+    if services().oidc.enabled() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Login is handled by the authorization server.",
+        ));
+    }
+
     // Validate login method
     // TODO: Other login methods
     let user_id = match &body.login_info {
@@ -79,15 +90,20 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
                         Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
                     })?;
 
-            let hash = services()
-                .users
-                .password_hash(&user_id)?
-                .ok_or(Error::BadRequest(
-                    ErrorKind::Forbidden,
-                    "Wrong username or password.",
-                ))?;
+            let hash = services().users.password_hash(&user_id)?;
+
+            // Only existing accounts are tracked, so made up user IDs can't fill up the memory
+            services()
+                .login_throttle
+                .check(hash.as_ref().map(|_| &*user_id), body.client_ip)?;
+
+            let hash = hash.ok_or(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Wrong username or password.",
+            ))?;
 
             if hash.is_empty() {
+                services().login_throttle.failed(&user_id);
                 return Err(Error::BadRequest(
                     ErrorKind::UserDeactivated,
                     "The user has been deactivated",
@@ -106,41 +122,38 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
                 .is_ok();
 
             if !hash_matches {
+                services().login_throttle.failed(&user_id);
                 return Err(Error::BadRequest(
                     ErrorKind::Forbidden,
                     "Wrong username or password.",
                 ));
             }
 
+            services()
+                .login_throttle
+                .succeeded(&user_id, body.client_ip);
+
             user_id
         }
         login::v3::LoginInfo::Token(login::v3::Token { token }) => {
             debug!("Got token login type");
-            if let Some(jwt_decoding_key) = services().globals.jwt_decoding_key() {
-                let token = jsonwebtoken::decode::<Claims>(
-                    token,
-                    jwt_decoding_key,
-                    &jsonwebtoken::Validation::default(),
-                )
-                .map_err(|e| {
-                    warn!("Failed to parse JWT token from user logging in: {}", e);
-                    Error::BadRequest(ErrorKind::InvalidUsername, "Token is invalid.")
-                })?;
-
-                let username = token.claims.sub.to_lowercase();
-
-                UserId::parse_with_server_name(username, services().globals.server_name()).map_err(
-                    |e| {
+            let username = services().jwt.localpart(token).await?;
+
+            let user_id =
+                UserId::parse_with_server_name(username, services().globals.server_name())
+                    .map_err(|e| {
                         warn!("Failed to parse username from user logging in: {}", e);
                         Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
-                    },
-                )?
-            } else {
-                return Err(Error::BadRequest(
-                    ErrorKind::Unknown,
-                    "Token login is not supported (server has no jwt decoding key).",
-                ));
+                    })?;
+
+            // The token vouches for the user, so the account is created on its first login
+            if !services().users.exists(&user_id)? {
+                create_local_user(&user_id, None, None).await?;
+                welcome_local_user(&user_id);
+                info!("Created user {} on their first JWT login", user_id);
             }
+
+            user_id
         }
         #[allow(deprecated)]
         login::v3::LoginInfo::ApplicationService(login::v3::ApplicationService {
@@ -26,6 +26,10 @@ pub async fn send_state_event_for_key_route(
 ) -> Result<send_state_event::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    services()
+        .users
+        .check_current_terms(sender_user, body.from_appservice)?;
+
     let event_id = send_state_event_for_key_helper(
         sender_user,
         &body.room_id,
@@ -51,6 +55,10 @@ pub async fn send_state_event_for_empty_key_route(
 ) -> Result<RumaResponse<send_state_event::v3::Response>> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    services()
+        .users
+        .check_current_terms(sender_user, body.from_appservice)?;
+
     // Forbid m.room.encryption if encryption is disabled
     if body.event_type == StateEventType::RoomEncryption && !services().globals.allow_encryption() {
         return Err(Error::BadRequest(
@@ -260,15 +268,7 @@ async fn send_state_event_for_key_helper(
         }
     }
 
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(room_id.to_owned())
-            .or_default(),
-    );
+    let mutex_state = services().globals.roomid_mutex_state.mutex(room_id);
     let state_lock = mutex_state.lock().await;
 
     let event_id = services()
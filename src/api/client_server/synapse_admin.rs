@@ -0,0 +1,187 @@
+use std::time::{Duration, Instant};
+
+use axum::{response::IntoResponse, Json};
+use hmac::{Hmac, Mac};
+use ruma::{
+    api::client::error::ErrorKind, events::room::message::RoomMessageEventContent, OwnedDeviceId,
+};
+use serde::Deserialize;
+use sha1::Sha1;
+use tracing::{info, warn};
+
+use super::{
+    create_local_user, new_local_user_id, welcome_local_user, DEVICE_ID_LENGTH, TOKEN_LENGTH,
+};
+use crate::{services, utils, Error, Result};
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// generated shared-secret registration nonce length
+const NONCE_LENGTH: usize = 32;
+
+/// How long a shared-secret registration nonce stays valid, same as Synapse
+const NONCE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many unused nonces may exist at the same time, so unauthenticated requests can't grow the
+/// nonce map without limit
+const MAX_NONCES: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct SharedSecretRegistrationRequest {
+    nonce: String,
+    username: String,
+    password: String,
+    #[serde(default)]
+    admin: bool,
+    mac: String,
+    displayname: Option<String>,
+    user_type: Option<String>,
+}
+
+/// # `GET /_synapse/admin/v1/register`
+///
+/// Synapse-compatible shared-secret registration: returns a nonce that has to be included in the
+/// HMAC of the registration request.
+pub async fn synapse_register_nonce_route() -> Result<impl IntoResponse> {
+    if services().globals.registration_shared_secret().is_none() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Shared secret registration is not enabled.",
+        ));
+    }
+
+    let nonce = utils::random_string(NONCE_LENGTH);
+
+    let mut nonces = services()
+        .globals
+        .shared_secret_registration_nonces
+        .lock()
+        .unwrap();
+    nonces.retain(|_, created| created.elapsed() < NONCE_TIMEOUT);
+    if nonces.len() >= MAX_NONCES {
+        return Err(Error::BadRequest(
+            ErrorKind::LimitExceeded {
+                retry_after_ms: Some(NONCE_TIMEOUT),
+            },
+            "Too many registration nonces were requested, try again later.",
+        ));
+    }
+    nonces.insert(nonce.clone(), Instant::now());
+
+    Ok(Json(serde_json::json!({ "nonce": nonce })))
+}
+
+/// # `POST /_synapse/admin/v1/register`
+///
+/// Synapse-compatible shared-secret registration, used by provisioning scripts and tools like
+/// matrix-registration.
+///
+/// - Only works if `registration_shared_secret` is configured
+/// - The nonce must have been requested with `GET /_synapse/admin/v1/register` and can only be used once
+/// - The mac is the hex encoded HMAC-SHA1 of `nonce\0username\0password\0admin|notadmin[\0user_type]`
+/// keyed with the shared secret
+/// - Registers the user regardless of `allow_registration`, and grants admin privileges if `admin` is set
+pub async fn synapse_register_route(
+    Json(body): Json<SharedSecretRegistrationRequest>,
+) -> Result<impl IntoResponse> {
+    let Some(shared_secret) = services().globals.registration_shared_secret() else {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Shared secret registration is not enabled.",
+        ));
+    };
+
+    let nonce_created = services()
+        .globals
+        .shared_secret_registration_nonces
+        .lock()
+        .unwrap()
+        .remove(&body.nonce);
+    if !nonce_created.is_some_and(|created| created.elapsed() < NONCE_TIMEOUT) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Unrecognised or expired nonce.",
+        ));
+    }
+
+    let mut mac =
+        HmacSha1::new_from_slice(shared_secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(body.nonce.as_bytes());
+    mac.update(b"\x00");
+    mac.update(body.username.as_bytes());
+    mac.update(b"\x00");
+    mac.update(body.password.as_bytes());
+    mac.update(b"\x00");
+    mac.update(if body.admin { "admin" } else { "notadmin" }.as_bytes());
+    if let Some(user_type) = &body.user_type {
+        mac.update(b"\x00");
+        mac.update(user_type.as_bytes());
+    }
+
+    let Some(given_mac) = decode_hex(&body.mac) else {
+        return Err(Error::BadRequest(ErrorKind::InvalidParam, "Invalid mac."));
+    };
+    if mac.verify_slice(&given_mac).is_err() {
+        warn!(
+            "Shared secret registration for username {:?} failed due to an invalid mac",
+            body.username
+        );
+        return Err(Error::BadRequest(ErrorKind::Forbidden, "HMAC incorrect."));
+    }
+
+    let user_id = new_local_user_id(&body.username)?;
+
+    let displayname = create_local_user(&user_id, Some(&body.password), body.displayname).await?;
+    welcome_local_user(&user_id);
+
+    let device_id: OwnedDeviceId = utils::random_string(DEVICE_ID_LENGTH).into();
+    let token = utils::random_string(TOKEN_LENGTH);
+
+    services()
+        .users
+        .create_device(&user_id, &device_id, &token, None)?;
+
+    info!(
+        "New user \"{}\" registered on this server using shared-secret registration.",
+        user_id
+    );
+    services()
+        .admin
+        .send_message(RoomMessageEventContent::notice_plain(format!(
+            "New user \"{user_id}\" registered on this server using shared-secret registration."
+        )));
+
+    if body.admin {
+        services()
+            .admin
+            .make_user_admin(&user_id, displayname)
+            .await?;
+
+        warn!(
+            "Granting {} admin privileges through shared-secret registration",
+            user_id
+        );
+    }
+
+    Ok(Json(serde_json::json!({
+        "access_token": token,
+        "user_id": user_id,
+        "home_server": services().globals.server_name(),
+        "device_id": device_id,
+    })))
+}
+
+/// Decodes a hex string (as sent by Synapse's `register_new_matrix_user` and friends) into bytes.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect()
+}
@@ -1,36 +1,82 @@
 use crate::{
-    service::rooms::timeline::PduCount, services, Error, PduEvent, Result, Ruma, RumaResponse,
+    service::rooms::timeline::PduCount,
+    services,
+    utils::filter::{format_event, room_event_filter_matches},
+    Error, PduEvent, Result, Ruma, RumaResponse,
 };
+use axum::{
+    body::Full,
+    response::{IntoResponse, Response},
+};
+use futures_util::{stream, StreamExt};
+use http::StatusCode;
 use ruma::{
-    api::client::{
-        filter::{FilterDefinition, LazyLoadOptions},
-        sync::sync_events::{
-            self,
-            v3::{
-                Ephemeral, Filter, GlobalAccountData, InviteState, InvitedRoom, JoinedRoom,
-                LeftRoom, Presence, RoomAccountData, RoomSummary, Rooms, State, Timeline, ToDevice,
+    api::{
+        client::{
+            error::ErrorKind,
+            filter::{FilterDefinition, LazyLoadOptions, RoomEventFilter},
+            sync::sync_events::{
+                self,
+                v3::{
+                    Ephemeral, Filter, GlobalAccountData, InviteState, InvitedRoom, JoinedRoom,
+                    LeftRoom, Presence, RoomAccountData, RoomSummary, Rooms, State, Timeline,
+                    ToDevice,
+                },
+                v4::SlidingOp,
+                DeviceLists, UnreadNotificationsCount,
             },
-            v4::SlidingOp,
-            DeviceLists, UnreadNotificationsCount,
+            uiaa::UiaaResponse,
         },
-        uiaa::UiaaResponse,
+        OutgoingResponse,
     },
     events::{
         presence::PresenceEvent,
         room::member::{MembershipState, RoomMemberEventContent},
-        StateEventType, TimelineEventType,
+        AnyStrippedStateEvent, StateEventType, TimelineEventType,
     },
     serde::Raw,
-    uint, DeviceId, OwnedDeviceId, OwnedUserId, RoomId, UInt, UserId,
+    uint, DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
 };
 use std::{
     cmp::Ordering,
     collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
-    sync::Arc,
+    future::Future,
     time::Duration,
 };
 use tokio::sync::watch::Sender;
-use tracing::error;
+use tracing::{error, Instrument};
+
+/// How many rooms are loaded at the same time when building a sync response
+const SYNC_ROOM_CONCURRENCY: usize = 32;
+
+/// Sync response with the rooms the device peeks into (MSC2753), which Ruma's response type has no
+/// field for. They are added to the `rooms` object of the serialized response.
+#[derive(Clone)]
+pub struct SyncResponse {
+    pub response: sync_events::v3::Response,
+    pub peek: BTreeMap<OwnedRoomId, JoinedRoom>,
+}
+
+impl IntoResponse for SyncResponse {
+    fn into_response(self) -> Response {
+        if self.peek.is_empty() {
+            return RumaResponse(self.response).into_response();
+        }
+
+        let Ok(response) = self.response.try_into_http_response::<Vec<u8>>() else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let (parts, body) = response.into_parts();
+
+        let mut body: serde_json::Value =
+            serde_json::from_slice(&body).expect("we just serialized the response");
+        body["rooms"]["peek"] =
+            serde_json::to_value(self.peek).expect("JoinedRoom always serializes successfully");
+
+        let body = serde_json::to_vec(&body).expect("value serialization can't fail");
+        http::Response::from_parts(parts, Full::from(body)).into_response()
+    }
+}
 
 /// # `GET /_matrix/client/r0/sync`
 ///
@@ -64,55 +110,50 @@ use tracing::error;
 /// For left rooms:
 /// - If the user left after `since`: prev_batch token, empty state (TODO: subset of the state at the point of the leave)
 ///
+/// For rooms the device peeks into (MSC2753), in the `peek` section:
+/// - Like joined rooms, with all state if the peek started after `since`
+/// - Only while the history of the room is world readable
+///
 /// - Sync is handled in an async task, multiple requests from the same device with the same
 /// `since` will be cached
 pub async fn sync_events_route(
     body: Ruma<sync_events::v3::Request>,
-) -> Result<sync_events::v3::Response, RumaResponse<UiaaResponse>> {
+) -> Result<SyncResponse, RumaResponse<UiaaResponse>> {
     let sender_user = body.sender_user.expect("user is authenticated");
     let sender_device = body.sender_device.expect("user is authenticated");
     let body = body.body;
 
-    let mut rx = match services()
-        .globals
-        .sync_receivers
-        .write()
-        .unwrap()
-        .entry((sender_user.clone(), sender_device.clone()))
-    {
-        Entry::Vacant(v) => {
-            let (tx, rx) = tokio::sync::watch::channel(None);
+    let mut rx = services().globals.sync_receivers.with_entry(
+        (sender_user.clone(), sender_device.clone()),
+        |entry| match entry {
+            Entry::Vacant(v) => {
+                let (tx, rx) = tokio::sync::watch::channel(None);
 
-            v.insert((body.since.to_owned(), rx.clone()));
+                v.insert((body.since.to_owned(), rx.clone()));
 
-            tokio::spawn(sync_helper_wrapper(
-                sender_user.clone(),
-                sender_device.clone(),
-                body,
-                tx,
-            ));
+                let sync =
+                    sync_helper_wrapper(sender_user.clone(), sender_device.clone(), body, tx);
+                tokio::spawn(sync.in_current_span());
 
-            rx
-        }
-        Entry::Occupied(mut o) => {
-            if o.get().0 != body.since {
-                let (tx, rx) = tokio::sync::watch::channel(None);
+                rx
+            }
+            Entry::Occupied(mut o) => {
+                if o.get().0 != body.since {
+                    let (tx, rx) = tokio::sync::watch::channel(None);
 
-                o.insert((body.since.clone(), rx.clone()));
+                    o.insert((body.since.clone(), rx.clone()));
 
-                tokio::spawn(sync_helper_wrapper(
-                    sender_user.clone(),
-                    sender_device.clone(),
-                    body,
-                    tx,
-                ));
+                    let sync =
+                        sync_helper_wrapper(sender_user.clone(), sender_device.clone(), body, tx);
+                    tokio::spawn(sync.in_current_span());
 
-                rx
-            } else {
-                o.get().1.clone()
+                    rx
+                } else {
+                    o.get().1.clone()
+                }
             }
-        }
-    };
+        },
+    );
 
     let we_have_to_wait = rx.borrow().is_none();
     if we_have_to_wait {
@@ -137,21 +178,19 @@ async fn sync_helper_wrapper(
     sender_user: OwnedUserId,
     sender_device: OwnedDeviceId,
     body: sync_events::v3::Request,
-    tx: Sender<Option<Result<sync_events::v3::Response>>>,
+    tx: Sender<Option<Result<SyncResponse>>>,
 ) {
     let since = body.since.clone();
 
     let r = sync_helper(sender_user.clone(), sender_device.clone(), body).await;
 
-    if let Ok((_, caching_allowed)) = r {
-        if !caching_allowed {
-            match services()
-                .globals
-                .sync_receivers
-                .write()
-                .unwrap()
-                .entry((sender_user, sender_device))
-            {
+    // Errors are not cached either, so the client can retry the request
+    let caching_allowed = matches!(r, Ok((_, true)));
+    if !caching_allowed {
+        services()
+            .globals
+            .sync_receivers
+            .with_entry((sender_user, sender_device), |entry| match entry {
                 Entry::Occupied(o) => {
                     // Only remove if the device didn't start a different /sync already
                     if o.get().0 == since {
@@ -159,19 +198,83 @@ async fn sync_helper_wrapper(
                     }
                 }
                 Entry::Vacant(_) => {}
-            }
-        }
+            });
     }
 
     let _ = tx.send(Some(r.map(|(r, _)| r)));
 }
 
+/// Counts a sync request of the user that waits for new events, until it is dropped
+struct LongPoll(OwnedUserId);
+
+impl LongPoll {
+    /// Fails if the user already has `sync_max_long_polls_per_user` waiting requests.
+    fn start(user_id: &UserId, max: usize) -> Result<Self> {
+        services()
+            .globals
+            .sync_long_polls
+            .with_entry(user_id.to_owned(), |entry| {
+                let count = entry.or_default();
+                if *count >= max {
+                    return Err(Error::BadRequest(
+                        ErrorKind::LimitExceeded {
+                            retry_after_ms: Some(Duration::from_secs(5)),
+                        },
+                        "Too many sync requests are waiting for new events.",
+                    ));
+                }
+
+                *count += 1;
+                Ok(Self(user_id.to_owned()))
+            })
+    }
+}
+
+impl Drop for LongPoll {
+    fn drop(&mut self) {
+        services()
+            .globals
+            .sync_long_polls
+            .with_entry(self.0.clone(), |entry| {
+                if let Entry::Occupied(mut entry) = entry {
+                    *entry.get_mut() -= 1;
+                    if *entry.get() == 0 {
+                        entry.remove();
+                    }
+                }
+            });
+    }
+}
+
+/// Waits until the watcher sees new events, at most for the timeout the client asked for and the
+/// server allows. Returns right away when memory is low.
+async fn long_poll(
+    sender_user: &UserId,
+    timeout: Duration,
+    watcher: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    let timeout = timeout.min(services().globals.sync_max_timeout());
+    if timeout.is_zero() || services().globals.sync_memory_low() {
+        return Ok(());
+    }
+
+    let _long_poll = services()
+        .globals
+        .sync_max_long_polls_per_user()
+        .map(|max| LongPoll::start(sender_user, max))
+        .transpose()?;
+
+    let _ = tokio::time::timeout(timeout, watcher).await;
+
+    Ok(())
+}
+
 async fn sync_helper(
     sender_user: OwnedUserId,
     sender_device: OwnedDeviceId,
     body: sync_events::v3::Request,
     // bool = caching allowed
-) -> Result<(sync_events::v3::Response, bool), Error> {
+) -> Result<(SyncResponse, bool), Error> {
     // Presence update
     if services().globals.allow_local_presence() {
         services()
@@ -232,25 +335,55 @@ async fn sync_helper(
         .rooms
         .state_cache
         .rooms_joined(&sender_user)
-        .collect::<Vec<_>>();
-    for room_id in all_joined_rooms {
-        let room_id = room_id?;
-        if let Ok(joined_room) = load_joined_room(
-            &sender_user,
-            &sender_device,
-            &room_id,
-            since,
-            sincecount,
-            next_batch,
-            next_batchcount,
-            lazy_load_enabled,
-            lazy_load_send_redundant,
-            full_state,
-            &mut device_list_updates,
-            &mut left_encrypted_users,
-        )
-        .await
-        {
+        .collect::<Result<Vec<_>>>()?;
+
+    // Rooms are loaded concurrently, every room collects its own device list changes which are
+    // merged afterwards
+    let joined_results: Vec<_> = {
+        let sender_user = &sender_user;
+        let sender_device = &sender_device;
+        let filter = &filter;
+        stream::iter(all_joined_rooms)
+            .map(|room_id| async move {
+                let mut room_device_list_updates = HashSet::new();
+                let mut room_left_encrypted_users = HashSet::new();
+                let joined_room = load_joined_room(
+                    sender_user,
+                    sender_device,
+                    &room_id,
+                    since,
+                    sincecount,
+                    next_batch,
+                    next_batchcount,
+                    lazy_load_enabled,
+                    lazy_load_send_redundant,
+                    full_state,
+                    filter,
+                    None,
+                    &mut room_device_list_updates,
+                    &mut room_left_encrypted_users,
+                )
+                .await;
+
+                (
+                    room_id,
+                    joined_room,
+                    room_device_list_updates,
+                    room_left_encrypted_users,
+                )
+            })
+            .buffered(SYNC_ROOM_CONCURRENCY)
+            .collect()
+            .await
+    };
+
+    for (room_id, joined_room, room_device_list_updates, room_left_encrypted_users) in
+        joined_results
+    {
+        device_list_updates.extend(room_device_list_updates);
+        left_encrypted_users.extend(room_left_encrypted_users);
+
+        if let Ok(joined_room) = joined_room {
             if !joined_room.is_empty() {
                 joined_rooms.insert(room_id.clone(), joined_room);
             }
@@ -261,183 +394,118 @@ async fn sync_helper(
         }
     }
 
-    let mut left_rooms = BTreeMap::new();
-    let all_left_rooms: Vec<_> = services()
+    let mut peeked_rooms = BTreeMap::new();
+    for (room_id, peeking_since) in services()
         .rooms
-        .state_cache
-        .rooms_left(&sender_user)
-        .collect();
-    for result in all_left_rooms {
-        let (room_id, _) = result?;
-
-        let mut left_state_events = Vec::new();
-
-        {
-            // Get and drop the lock to wait for remaining operations to finish
-            let mutex_insert = Arc::clone(
-                services()
-                    .globals
-                    .roomid_mutex_insert
-                    .write()
-                    .unwrap()
-                    .entry(room_id.clone())
-                    .or_default(),
-            );
-            let insert_lock = mutex_insert.lock().await;
-            drop(insert_lock);
-        }
-
-        let left_count = services()
+        .peeking
+        .peeked_rooms(&sender_user, &sender_device)
+        .collect::<Result<Vec<_>>>()?
+    {
+        // Joining ends the peek, the room is synced like other joined rooms from now on
+        if services()
             .rooms
             .state_cache
-            .get_left_count(&room_id, &sender_user)?;
-
-        // Left before last sync
-        if Some(since) >= left_count {
-            continue;
-        }
-
-        if !services().rooms.metadata.exists(&room_id)? {
-            // This is just a rejected invite, not a room we know
+            .is_joined(&sender_user, &room_id)?
+        {
+            services()
+                .rooms
+                .peeking
+                .unpeek(&sender_user, &sender_device, &room_id)?;
             continue;
         }
 
-        let since_shortstatehash = services()
-            .rooms
-            .user
-            .get_token_shortstatehash(&room_id, since)?;
-
-        let since_state_ids = match since_shortstatehash {
-            Some(s) => services().rooms.state_accessor.state_full_ids(s).await?,
-            None => HashMap::new(),
-        };
-
-        let left_event_id = match services().rooms.state_accessor.room_state_get_id(
-            &room_id,
-            &StateEventType::RoomMember,
-            sender_user.as_str(),
-        )? {
-            Some(e) => e,
-            None => {
-                error!("Left room but no left state event");
-                continue;
-            }
-        };
-
-        let left_shortstatehash = match services()
+        if !services()
             .rooms
             .state_accessor
-            .pdu_shortstatehash(&left_event_id)?
+            .is_world_readable(&room_id)?
         {
-            Some(s) => s,
-            None => {
-                error!("Leave event has no state");
-                continue;
-            }
-        };
-
-        let mut left_state_ids = services()
-            .rooms
-            .state_accessor
-            .state_full_ids(left_shortstatehash)
-            .await?;
-
-        let leave_shortstatekey = services()
-            .rooms
-            .short
-            .get_or_create_shortstatekey(&StateEventType::RoomMember, sender_user.as_str())?;
-
-        left_state_ids.insert(leave_shortstatekey, left_event_id);
-
-        let mut i = 0;
-        for (key, id) in left_state_ids {
-            if full_state || since_state_ids.get(&key) != Some(&id) {
-                let (event_type, state_key) =
-                    services().rooms.short.get_statekey_from_short(key)?;
-
-                if !lazy_load_enabled
-                    || event_type != StateEventType::RoomMember
-                    || full_state
-                    // TODO: Delete the following line when this is resolved: https://github.com/vector-im/element-web/issues/22565
-                    || *sender_user == state_key
-                {
-                    let pdu = match services().rooms.timeline.get_pdu(&id)? {
-                        Some(pdu) => pdu,
-                        None => {
-                            error!("Pdu in state not found: {}", id);
-                            continue;
-                        }
-                    };
+            continue;
+        }
 
-                    left_state_events.push(pdu.to_sync_state_event());
+        // Peeking users don't get the devices of the members
+        let peeked_room = load_joined_room(
+            &sender_user,
+            &sender_device,
+            &room_id,
+            since,
+            sincecount,
+            next_batch,
+            next_batchcount,
+            lazy_load_enabled,
+            lazy_load_send_redundant,
+            full_state,
+            &filter,
+            Some(peeking_since),
+            &mut HashSet::new(),
+            &mut HashSet::new(),
+        )
+        .await?;
 
-                    i += 1;
-                    if i % 100 == 0 {
-                        tokio::task::yield_now().await;
-                    }
-                }
-            }
+        if !peeked_room.is_empty() {
+            peeked_rooms.insert(room_id, peeked_room);
         }
-
-        left_rooms.insert(
-            room_id.clone(),
-            LeftRoom {
-                account_data: RoomAccountData { events: Vec::new() },
-                timeline: Timeline {
-                    limited: false,
-                    prev_batch: Some(next_batch_string.clone()),
-                    events: Vec::new(),
-                },
-                state: State {
-                    events: left_state_events,
-                },
-            },
-        );
     }
 
-    let mut invited_rooms = BTreeMap::new();
-    let all_invited_rooms: Vec<_> = services()
+    let all_left_rooms = services()
         .rooms
         .state_cache
-        .rooms_invited(&sender_user)
-        .collect();
-    for result in all_invited_rooms {
-        let (room_id, invite_state_events) = result?;
-
-        {
-            // Get and drop the lock to wait for remaining operations to finish
-            let mutex_insert = Arc::clone(
-                services()
-                    .globals
-                    .roomid_mutex_insert
-                    .write()
-                    .unwrap()
-                    .entry(room_id.clone())
-                    .or_default(),
-            );
-            let insert_lock = mutex_insert.lock().await;
-            drop(insert_lock);
-        }
-
-        let invite_count = services()
-            .rooms
-            .state_cache
-            .get_invite_count(&room_id, &sender_user)?;
-
-        // Invited before last sync
-        if Some(since) >= invite_count {
-            continue;
-        }
+        .rooms_left(&sender_user)
+        .map(|result| result.map(|(room_id, _)| room_id))
+        .collect::<Result<Vec<_>>>()?;
+    let left_rooms = {
+        let sender_user = &sender_user;
+        let next_batch_string = &next_batch_string;
+        let filter = &filter;
+        stream::iter(all_left_rooms)
+            .map(|room_id| async move {
+                let left_room = load_left_room(
+                    sender_user,
+                    &room_id,
+                    since,
+                    next_batch_string,
+                    lazy_load_enabled,
+                    full_state,
+                    filter,
+                )
+                .await;
+                (room_id, left_room)
+            })
+            .buffered(SYNC_ROOM_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .filter_map(|(room_id, left_room)| {
+                left_room
+                    .transpose()
+                    .map(|left_room| left_room.map(|left_room| (room_id, left_room)))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?
+    };
 
-        invited_rooms.insert(
-            room_id.clone(),
-            InvitedRoom {
-                invite_state: InviteState {
-                    events: invite_state_events,
-                },
-            },
-        );
-    }
+    let all_invited_rooms = services()
+        .rooms
+        .state_cache
+        .rooms_invited(&sender_user)
+        .collect::<Result<Vec<_>>>()?;
+    let invited_rooms = {
+        let sender_user = &sender_user;
+        stream::iter(all_invited_rooms)
+            .map(|(room_id, invite_state_events)| async move {
+                let invited_room =
+                    load_invited_room(sender_user, &room_id, since, invite_state_events).await;
+                (room_id, invited_room)
+            })
+            .buffered(SYNC_ROOM_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .filter_map(|(room_id, invited_room)| {
+                invited_room
+                    .transpose()
+                    .map(|invited_room| invited_room.map(|invited_room| (room_id, invited_room)))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?
+    };
 
     for user_id in left_encrypted_users {
         let dont_share_encrypted_room = services()
@@ -511,20 +579,22 @@ async fn sync_helper(
     };
 
     // TODO: Retry the endpoint instead of returning (waiting for #118)
+    let response = SyncResponse {
+        response,
+        peek: peeked_rooms,
+    };
+
     if !full_state
-        && response.rooms.is_empty()
-        && response.presence.is_empty()
-        && response.account_data.is_empty()
-        && response.device_lists.is_empty()
-        && response.to_device.is_empty()
+        && response.response.rooms.is_empty()
+        && response.peek.is_empty()
+        && response.response.presence.is_empty()
+        && response.response.account_data.is_empty()
+        && response.response.device_lists.is_empty()
+        && response.response.to_device.is_empty()
     {
         // Hang a few seconds so requests are not spammed
         // Stop hanging if new info arrives
-        let mut duration = body.timeout.unwrap_or_default();
-        if duration.as_secs() > 30 {
-            duration = Duration::from_secs(30);
-        }
-        let _ = tokio::time::timeout(duration, watcher).await;
+        long_poll(&sender_user, body.timeout.unwrap_or_default(), watcher).await?;
         Ok((response, false))
     } else {
         Ok((response, since != next_batch)) // Only cache if we made progress
@@ -570,6 +640,158 @@ async fn process_room_presence_updates(
     Ok(())
 }
 
+async fn load_left_room(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    since: u64,
+    next_batch_string: &str,
+    lazy_load_enabled: bool,
+    full_state: bool,
+    filter: &FilterDefinition,
+) -> Result<Option<LeftRoom>> {
+    let mut left_state_events = Vec::new();
+
+    {
+        // Get and drop the lock to wait for remaining operations to finish
+        let mutex_insert = services().globals.roomid_mutex_insert.mutex(room_id);
+        let insert_lock = mutex_insert.lock().await;
+        drop(insert_lock);
+    }
+
+    let left_count = services()
+        .rooms
+        .state_cache
+        .get_left_count(room_id, sender_user)?;
+
+    // Left before last sync
+    if Some(since) >= left_count {
+        return Ok(None);
+    }
+
+    if !services().rooms.metadata.exists(room_id)? {
+        // This is just a rejected invite, not a room we know
+        return Ok(None);
+    }
+
+    let since_shortstatehash = services()
+        .rooms
+        .user
+        .get_token_shortstatehash(room_id, since)?;
+
+    let since_state_ids = match since_shortstatehash {
+        Some(s) => services().rooms.state_accessor.state_full_ids(s).await?,
+        None => HashMap::new(),
+    };
+
+    let left_event_id = match services().rooms.state_accessor.room_state_get_id(
+        room_id,
+        &StateEventType::RoomMember,
+        sender_user.as_str(),
+    )? {
+        Some(e) => e,
+        None => {
+            error!("Left room but no left state event");
+            return Ok(None);
+        }
+    };
+
+    let left_shortstatehash = match services()
+        .rooms
+        .state_accessor
+        .pdu_shortstatehash(&left_event_id)?
+    {
+        Some(s) => s,
+        None => {
+            error!("Leave event has no state");
+            return Ok(None);
+        }
+    };
+
+    let mut left_state_ids = services()
+        .rooms
+        .state_accessor
+        .state_full_ids(left_shortstatehash)
+        .await?;
+
+    let leave_shortstatekey = services()
+        .rooms
+        .short
+        .get_or_create_shortstatekey(&StateEventType::RoomMember, sender_user.as_str())?;
+
+    left_state_ids.insert(leave_shortstatekey, left_event_id);
+
+    let mut i = 0;
+    for (key, id) in left_state_ids {
+        if full_state || since_state_ids.get(&key) != Some(&id) {
+            let (event_type, state_key) = services().rooms.short.get_statekey_from_short(key)?;
+
+            if !lazy_load_enabled
+                || event_type != StateEventType::RoomMember
+                || full_state
+                // TODO: Delete the following line when this is resolved: https://github.com/vector-im/element-web/issues/22565
+                || *sender_user == state_key
+            {
+                let pdu = match services().rooms.timeline.get_pdu(&id)? {
+                    Some(pdu) => pdu,
+                    None => {
+                        error!("Pdu in state not found: {}", id);
+                        continue;
+                    }
+                };
+
+                left_state_events.push(format_event(filter, &pdu, pdu.to_sync_state_event()));
+
+                i += 1;
+                if i % 100 == 0 {
+                    tokio::task::yield_now().await;
+                }
+            }
+        }
+    }
+
+    Ok(Some(LeftRoom {
+        account_data: RoomAccountData { events: Vec::new() },
+        timeline: Timeline {
+            limited: false,
+            prev_batch: Some(next_batch_string.to_owned()),
+            events: Vec::new(),
+        },
+        state: State {
+            events: left_state_events,
+        },
+    }))
+}
+
+async fn load_invited_room(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    since: u64,
+    invite_state_events: Vec<Raw<AnyStrippedStateEvent>>,
+) -> Result<Option<InvitedRoom>> {
+    {
+        // Get and drop the lock to wait for remaining operations to finish
+        let mutex_insert = services().globals.roomid_mutex_insert.mutex(room_id);
+        let insert_lock = mutex_insert.lock().await;
+        drop(insert_lock);
+    }
+
+    let invite_count = services()
+        .rooms
+        .state_cache
+        .get_invite_count(room_id, sender_user)?;
+
+    // Invited before last sync
+    if Some(since) >= invite_count {
+        return Ok(None);
+    }
+
+    Ok(Some(InvitedRoom {
+        invite_state: InviteState {
+            events: invite_state_events,
+        },
+    }))
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn load_joined_room(
     sender_user: &UserId,
@@ -582,32 +804,33 @@ async fn load_joined_room(
     lazy_load_enabled: bool,
     lazy_load_send_redundant: bool,
     full_state: bool,
+    filter: &FilterDefinition,
+    peeking_since: Option<u64>, // count when the device started peeking, if it isn't joined
     device_list_updates: &mut HashSet<OwnedUserId>,
     left_encrypted_users: &mut HashSet<OwnedUserId>,
 ) -> Result<JoinedRoom> {
     {
         // Get and drop the lock to wait for remaining operations to finish
         // This will make sure the we have all events until next_batch
-        let mutex_insert = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_insert
-                .write()
-                .unwrap()
-                .entry(room_id.to_owned())
-                .or_default(),
-        );
+        let mutex_insert = services().globals.roomid_mutex_insert.mutex(room_id);
         let insert_lock = mutex_insert.lock().await;
         drop(insert_lock);
     }
 
-    let (timeline_pdus, limited) = load_timeline(sender_user, room_id, sincecount, 10)?;
+    let timeline_limit = filter.room.timeline.limit.map_or(10, u64::from).min(100);
+    let (timeline_pdus, limited) = load_timeline(
+        sender_user,
+        room_id,
+        sincecount,
+        timeline_limit,
+        &filter.room.timeline,
+    )?;
 
     let send_notification_counts = !timeline_pdus.is_empty()
         || services()
             .rooms
             .user
-            .last_notification_read(sender_user, room_id)?
+            .last_notification_update(sender_user, room_id)?
             > since;
 
     let mut timeline_users = HashSet::new();
@@ -739,8 +962,11 @@ async fn load_joined_room(
                         .ok()
                 });
 
-            let joined_since_last_sync = since_sender_member
-                .map_or(true, |member| member.membership != MembershipState::Join);
+            let joined_since_last_sync = match peeking_since {
+                Some(peeking_since) => peeking_since > since,
+                None => since_sender_member
+                    .map_or(true, |member| member.membership != MembershipState::Join),
+            };
 
             if since_shortstatehash.is_none() || joined_since_last_sync {
                 // Probably since = 0, we will do an initial sync
@@ -1009,12 +1235,24 @@ async fn load_joined_room(
             .filter_map(|r| r.ok()),
     );
 
+    // With per-thread counts (MSC3773) the counts of the room only cover the main timeline
+    let thread_notification_counts =
+        if send_notification_counts && filter.room.timeline.unread_thread_notifications {
+            services()
+                .rooms
+                .user
+                .thread_notification_counts(sender_user, room_id)?
+        } else {
+            Vec::new()
+        };
+
     let notification_count = if send_notification_counts {
         Some(
             services()
                 .rooms
                 .user
                 .notification_count(sender_user, room_id)?
+                .saturating_sub(thread_notification_counts.iter().map(|(_, n, _)| n).sum())
                 .try_into()
                 .expect("notification count can't go that high"),
         )
@@ -1028,6 +1266,7 @@ async fn load_joined_room(
                 .rooms
                 .user
                 .highlight_count(sender_user, room_id)?
+                .saturating_sub(thread_notification_counts.iter().map(|(_, _, h)| h).sum())
                 .try_into()
                 .expect("highlight count can't go that high"),
         )
@@ -1035,6 +1274,27 @@ async fn load_joined_room(
         None
     };
 
+    let unread_thread_notifications = thread_notification_counts
+        .into_iter()
+        .map(|(thread_id, notification_count, highlight_count)| {
+            (
+                thread_id,
+                UnreadNotificationsCount {
+                    highlight_count: Some(
+                        highlight_count
+                            .try_into()
+                            .expect("highlight count can't go that high"),
+                    ),
+                    notification_count: Some(
+                        notification_count
+                            .try_into()
+                            .expect("notification count can't go that high"),
+                    ),
+                },
+            )
+        })
+        .collect();
+
     let prev_batch = timeline_pdus
         .first()
         .map_or(Ok::<_, Error>(None), |(pdu_count, _)| {
@@ -1049,7 +1309,7 @@ async fn load_joined_room(
 
     let room_events: Vec<_> = timeline_pdus
         .iter()
-        .map(|(_, pdu)| pdu.to_sync_room_event())
+        .map(|(_, pdu)| format_event(filter, pdu, pdu.to_sync_room_event()))
         .collect();
 
     let mut edus: Vec<_> = services()
@@ -1108,11 +1368,11 @@ async fn load_joined_room(
         state: State {
             events: state_events
                 .iter()
-                .map(|pdu| pdu.to_sync_state_event())
+                .map(|pdu| format_event(filter, pdu, pdu.to_sync_state_event()))
                 .collect(),
         },
         ephemeral: Ephemeral { events: edus },
-        unread_thread_notifications: BTreeMap::new(),
+        unread_thread_notifications,
     })
 }
 
@@ -1121,6 +1381,7 @@ fn load_timeline(
     room_id: &RoomId,
     roomsincecount: PduCount,
     limit: u64,
+    filter: &RoomEventFilter,
 ) -> Result<(Vec<(PduCount, PduEvent)>, bool), Error> {
     let timeline_pdus;
     let limited;
@@ -1141,7 +1402,8 @@ fn load_timeline(
                 }
                 r.ok()
             })
-            .take_while(|(pducount, _)| pducount > &roomsincecount);
+            .take_while(|(pducount, _)| pducount > &roomsincecount)
+            .filter(|(_, pdu)| room_event_filter_matches(filter, pdu));
 
         // Take the last events for the timeline
         timeline_pdus = non_timeline_pdus
@@ -1538,8 +1800,13 @@ pub async fn sync_events_v4_route(
     for (room_id, (required_state_request, timeline_limit, roomsince)) in &todo_rooms {
         let roomsincecount = PduCount::Normal(*roomsince);
 
-        let (timeline_pdus, limited) =
-            load_timeline(&sender_user, room_id, roomsincecount, *timeline_limit)?;
+        let (timeline_pdus, limited) = load_timeline(
+            &sender_user,
+            room_id,
+            roomsincecount,
+            *timeline_limit,
+            &RoomEventFilter::default(),
+        )?;
 
         if roomsince != &0 && timeline_pdus.is_empty() {
             continue;
@@ -1700,11 +1967,8 @@ pub async fn sync_events_v4_route(
     {
         // Hang a few seconds so requests are not spammed
         // Stop hanging if new info arrives
-        let mut duration = body.timeout.unwrap_or(Duration::from_secs(30));
-        if duration.as_secs() > 30 {
-            duration = Duration::from_secs(30);
-        }
-        let _ = tokio::time::timeout(duration, watcher).await;
+        let timeout = body.timeout.unwrap_or(Duration::from_secs(30));
+        long_poll(&sender_user, timeout, watcher).await?;
     }
 
     Ok(sync_events::v4::Response {
@@ -3,6 +3,10 @@ use ruma::api::client::{error::ErrorKind, threads::get_threads};
 use crate::{services, Error, Result, Ruma};
 
 /// # `GET /_matrix/client/r0/rooms/{roomId}/threads`
+///
+/// Lists the threads of a room, ordered by their latest activity.
+///
+/// - `include=participated` only lists threads the user started or replied to
 pub async fn get_threads_route(
     body: Ruma<get_threads::v1::Request>,
 ) -> Result<get_threads::v1::Response> {
@@ -26,7 +30,6 @@ pub async fn get_threads_route(
         .rooms
         .threads
         .threads_until(sender_user, &body.room_id, from, &body.include)?
-        .take(limit)
         .filter_map(|r| r.ok())
         .filter(|(_, pdu)| {
             services()
@@ -35,9 +38,14 @@ pub async fn get_threads_route(
                 .user_can_see_event(sender_user, &body.room_id, &pdu.event_id)
                 .unwrap_or(false)
         })
+        .take(limit)
         .collect::<Vec<_>>();
 
-    let next_batch = threads.last().map(|(count, _)| count.to_string());
+    let next_batch = if threads.len() == limit {
+        threads.last().map(|(count, _)| count.to_string())
+    } else {
+        None
+    };
 
     Ok(get_threads::v1::Response {
         chunk: threads
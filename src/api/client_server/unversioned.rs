@@ -1,9 +1,9 @@
 use std::{collections::BTreeMap, iter::FromIterator};
 
-use axum::{response::IntoResponse, Json};
+use axum::{http::header::CONTENT_TYPE, response::IntoResponse, Json};
 use ruma::api::client::{discovery::get_supported_versions, error::ErrorKind};
 
-use crate::{services, Error, Result, Ruma};
+use crate::{database::metrics, services, Error, Result, Ruma};
 
 /// # `GET /_matrix/client/versions`
 ///
@@ -39,6 +39,9 @@ pub async fn get_supported_versions_route(
             ("org.matrix.msc2836".to_owned(), true),
             ("org.matrix.msc3827".to_owned(), true),
             ("org.matrix.msc2946".to_owned(), true),
+            ("org.matrix.msc3912".to_owned(), true),
+            ("org.matrix.msc3773".to_owned(), true),
+            ("org.matrix.msc2753".to_owned(), true),
         ]),
     };
 
@@ -52,10 +55,49 @@ pub async fn well_known_client_route() -> Result<impl IntoResponse> {
         None => return Err(Error::BadRequest(ErrorKind::NotFound, "Not found.")),
     };
 
-    Ok(Json(serde_json::json!({
+    let mut well_known = serde_json::json!({
         "m.homeserver": {"base_url": client_url},
         "org.matrix.msc3575.proxy": {"url": client_url}
-    })))
+    });
+
+    if let Some(issuer) = services().globals.oidc_issuer() {
+        well_known["org.matrix.msc2965.authentication"] = serde_json::json!({
+            "issuer": issuer,
+            "account": services().globals.oidc_account_management_url(),
+        });
+    }
+
+    Ok(Json(well_known))
+}
+
+/// # `GET /_matrix/client/unstable/org.matrix.msc2965/auth_issuer`
+///
+/// Returns the OAuth 2.0 authorization server that authentication is delegated to (MSC2965).
+pub async fn get_auth_issuer_route() -> Result<impl IntoResponse> {
+    let issuer = match services().globals.oidc_issuer() {
+        Some(issuer) => issuer,
+        None => return Err(Error::BadRequest(ErrorKind::NotFound, "Not found.")),
+    };
+
+    Ok(Json(serde_json::json!({ "issuer": issuer })))
+}
+
+/// # `GET /_conduwuit/metrics`
+///
+/// Returns the cache, database, outgoing federation request, destination, circuit breaker and
+/// rejected PDU metrics in the Prometheus text format, if enabled.
+pub async fn get_metrics_route() -> Result<impl IntoResponse> {
+    if !services().globals.enable_metrics() {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Not found."));
+    }
+
+    let mut body = metrics::render();
+    body.push_str(&services().sending.request_metrics.render());
+    body.push_str(&services().sending.circuit_breaker.render());
+    body.push_str(&services().sending.render_destination_stats());
+    body.push_str(&services().rooms.event_handler.render_rejected_pdus());
+
+    Ok(([(CONTENT_TYPE, "text/plain; version=0.0.4")], body))
 }
 
 /// # `GET /client/server.json`
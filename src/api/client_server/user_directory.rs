@@ -1,10 +1,11 @@
-use crate::{services, Result, Ruma};
+use crate::{services, utils, Result, Ruma};
 use ruma::{
     api::client::user_directory::search_users,
     events::{
         room::join_rules::{JoinRule, RoomJoinRulesEventContent},
         StateEventType,
     },
+    UserId,
 };
 
 /// # `POST /_matrix/client/r0/user_directory/search`
@@ -16,9 +17,19 @@ use ruma::{
 pub async fn search_users_route(
     body: Ruma<search_users::v3::Request>,
 ) -> Result<search_users::v3::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.sender_user.clone().expect("user is authenticated");
     let limit = u64::from(body.limit) as usize;
+    let search_term = body.search_term.to_lowercase();
 
+    // Every user is looked at, so this doesn't run on the async runtime
+    utils::spawn_blocking(move || Ok(search_users(&sender_user, &search_term, limit))).await
+}
+
+fn search_users(
+    sender_user: &UserId,
+    search_term: &str,
+    limit: usize,
+) -> search_users::v3::Response {
     let mut users = services().users.iter().filter_map(|user_id| {
         // Filter out buggy users (they should not exist, but you never know...)
         let user_id = user_id.ok()?;
@@ -33,15 +44,12 @@ pub async fn search_users_route(
             .user_id
             .to_string()
             .to_lowercase()
-            .contains(&body.search_term.to_lowercase());
+            .contains(search_term);
 
         let user_displayname_matches = user
             .display_name
             .as_ref()
-            .filter(|name| {
-                name.to_lowercase()
-                    .contains(&body.search_term.to_lowercase())
-            })
+            .filter(|name| name.to_lowercase().contains(search_term))
             .is_some();
 
         if !user_id_matches && !user_displayname_matches {
@@ -75,7 +83,7 @@ pub async fn search_users_route(
         let user_is_in_shared_rooms = services()
             .rooms
             .user
-            .get_shared_rooms(vec![sender_user.clone(), user_id])
+            .get_shared_rooms(vec![sender_user.to_owned(), user_id])
             .ok()?
             .next()
             .is_some();
@@ -90,5 +98,5 @@ pub async fn search_users_route(
     let results = users.by_ref().take(limit).collect();
     let limited = users.next().is_some();
 
-    Ok(search_users::v3::Response { results, limited })
+    search_users::v3::Response { results, limited }
 }
@@ -1,4 +1,6 @@
 pub mod appservice_server;
 pub mod client_server;
+pub mod proxy_protocol;
+pub mod request_id;
 pub mod ruma_wrapper;
 pub mod server_server;
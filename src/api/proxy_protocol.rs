@@ -0,0 +1,376 @@
+//! PROXY protocol headers, which TCP-level load balancers send at the start of a connection to
+//! tell the server the address of the client.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum_server::accept::Accept;
+use futures_util::{future::BoxFuture, stream, StreamExt};
+use ipaddress::IPAddress;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::{TcpStream, UnixListener, UnixStream},
+};
+use tower_http::add_extension::AddExtension;
+use tracing::{debug, error};
+
+use crate::{Error, Result};
+
+/// How long the proxy has to send the header after connecting
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest version 1 header, including the line break
+const V1_MAX_LENGTH: usize = 107;
+
+/// Start of version 2 headers
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Number of connections on the UNIX socket whose headers are read at the same time
+const CONCURRENT_HEADERS: usize = 64;
+
+/// Address of the client from the PROXY protocol header of the connection. `None` if the protocol
+/// is disabled or the proxy connected on its own behalf, e.g. for health checks.
+#[derive(Clone, Copy, Debug)]
+pub struct ProxiedAddr(pub Option<SocketAddr>);
+
+/// Address ranges of the proxies that may send PROXY protocol headers over TCP. Anyone else could
+/// make up their address with a header, so connections from other peers are handled as direct
+/// connections without one.
+#[derive(Debug)]
+pub struct TrustedProxies(Vec<IPAddress>);
+
+impl TrustedProxies {
+    /// Parses CIDR ranges like `10.0.0.0/8`.
+    pub fn parse(ranges: &[String]) -> Result<Self> {
+        ranges
+            .iter()
+            .map(|range| {
+                IPAddress::parse(range.as_str()).map_err(|e| {
+                    error!("Invalid trusted PROXY protocol proxy range {range}: {e}");
+                    Error::bad_config("Invalid range in `proxy_protocol_trusted_proxies`.")
+                })
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 peers of dual-stack listeners have IPv4-mapped IPv6 addresses
+        IPAddress::parse(ip.to_canonical().to_string())
+            .map_or(false, |ip| self.0.iter().any(|range| range.includes(&ip)))
+    }
+}
+
+/// Connections whose peer address is known, so it can be checked against the trusted proxies
+pub trait PeerAddr {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl PeerAddr for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+/// Reads the header at the start of the connection, without reading any data after it.
+async fn read_header<I: AsyncRead + Unpin>(stream: &mut I) -> io::Result<Option<SocketAddr>> {
+    let read = async {
+        let mut start = [0; 5];
+        stream.read_exact(&mut start).await?;
+
+        if &start == b"PROXY" {
+            let mut line = start.to_vec();
+            while !line.ends_with(b"\r\n") {
+                if line.len() >= V1_MAX_LENGTH {
+                    return Err(invalid("PROXY protocol v1 header is too long"));
+                }
+                line.push(stream.read_u8().await?);
+            }
+
+            parse_v1(&line[..line.len() - 2])
+        } else if start == V2_SIGNATURE[..5] {
+            let mut header = [0; 16];
+            header[..5].copy_from_slice(&start);
+            stream.read_exact(&mut header[5..]).await?;
+            if header[..12] != V2_SIGNATURE[..] {
+                return Err(invalid("Invalid PROXY protocol v2 signature"));
+            }
+
+            let length = u16::from_be_bytes([header[14], header[15]]);
+            let mut addresses = vec![0; length.into()];
+            stream.read_exact(&mut addresses).await?;
+
+            parse_v2(header[12], header[13], &addresses)
+        } else {
+            Err(invalid(
+                "Connection doesn't start with a PROXY protocol header",
+            ))
+        }
+    };
+
+    tokio::time::timeout(HEADER_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No PROXY protocol header in time"))?
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parses a line like `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443` without the line break.
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line =
+        std::str::from_utf8(line).map_err(|_| invalid("Invalid PROXY protocol v1 header"))?;
+    let mut fields = line.split(' ').skip(1);
+
+    match fields.next() {
+        Some("UNKNOWN") => Ok(None),
+        Some("TCP4" | "TCP6") => {
+            let ip: IpAddr = fields
+                .next()
+                .and_then(|ip| ip.parse().ok())
+                .ok_or_else(|| invalid("Invalid source address in PROXY protocol v1 header"))?;
+            // The destination address comes before the source port
+            let port: u16 = fields
+                .nth(1)
+                .and_then(|port| port.parse().ok())
+                .ok_or_else(|| invalid("Invalid source port in PROXY protocol v1 header"))?;
+
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Unknown protocol in PROXY protocol v1 header")),
+    }
+}
+
+/// Parses the addresses of a version 2 header with its version and command, and its address
+/// family and protocol byte.
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+
+    match version_command & 0x0F {
+        // LOCAL, the proxy connected on its own behalf
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(invalid("Unknown PROXY protocol v2 command")),
+    }
+
+    match family >> 4 {
+        // AF_INET
+        1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().expect("slice has 4 bytes");
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().expect("slice has 16 bytes");
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // AF_UNSPEC or AF_UNIX, there is no IP address
+        0 | 3 => Ok(None),
+        _ => Err(invalid("Invalid addresses in PROXY protocol v2 header")),
+    }
+}
+
+/// Reads the PROXY protocol header of TCP connections from trusted proxies if it is enabled, before
+/// passing them on to the inner acceptor, e.g. for TLS. Requests get the address of the client as
+/// a [`ProxiedAddr`].
+#[derive(Clone)]
+pub struct ProxyProtocolAcceptor<A> {
+    inner: A,
+    /// `None` if the PROXY protocol is disabled
+    trusted_proxies: Option<Arc<TrustedProxies>>,
+}
+
+impl<A> ProxyProtocolAcceptor<A> {
+    pub fn new(inner: A, trusted_proxies: Option<TrustedProxies>) -> Self {
+        Self {
+            inner,
+            trusted_proxies: trusted_proxies.map(Arc::new),
+        }
+    }
+}
+
+impl<A, I, S> Accept<I, S> for ProxyProtocolAcceptor<A>
+where
+    A: Accept<I, S> + Clone + Send + 'static,
+    A::Future: Send,
+    I: AsyncRead + PeerAddr + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = AddExtension<A::Service, ProxiedAddr>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, mut stream: I, service: S) -> Self::Future {
+        let inner = self.inner.clone();
+        let enabled = self
+            .trusted_proxies
+            .as_ref()
+            .map_or(false, |trusted_proxies| {
+                stream
+                    .peer_addr()
+                    .map_or(false, |peer| trusted_proxies.contains(peer.ip()))
+            });
+
+        Box::pin(async move {
+            let addr = if enabled {
+                read_header(&mut stream).await.map_err(|e| {
+                    debug!("Dropping connection without valid PROXY protocol header: {e}");
+                    e
+                })?
+            } else {
+                None
+            };
+
+            let (stream, service) = inner.accept(stream, service).await?;
+            Ok((stream, AddExtension::new(service, ProxiedAddr(addr))))
+        })
+    }
+}
+
+/// Connection on the UNIX socket with the client address from its PROXY protocol header
+pub struct ProxiedUnixStream {
+    stream: UnixStream,
+    pub addr: ProxiedAddr,
+}
+
+impl AsyncRead for ProxiedUnixStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedUnixStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Accepts connections on the UNIX socket and reads their PROXY protocol header if it is enabled.
+/// Only local processes can connect, so they are all trusted. Several headers are read at the same
+/// time, so a slow connection doesn't hold up the others.
+pub fn unix_incoming(
+    listener: UnixListener,
+    enabled: bool,
+) -> impl hyper::server::accept::Accept<Conn = ProxiedUnixStream, Error = io::Error> {
+    let connections = stream::unfold(listener, |listener| async move {
+        let connection = listener.accept().await.map(|(stream, _)| stream);
+        Some((connection, listener))
+    })
+    .map(move |connection| async move {
+        let mut stream = match connection {
+            Ok(stream) => stream,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let addr = if enabled {
+            match read_header(&mut stream).await {
+                Ok(addr) => addr,
+                Err(e) => {
+                    debug!("Dropping connection without valid PROXY protocol header: {e}");
+                    return None;
+                }
+            }
+        } else {
+            None
+        };
+
+        Some(Ok(ProxiedUnixStream {
+            stream,
+            addr: ProxiedAddr(addr),
+        }))
+    })
+    .buffer_unordered(CONCURRENT_HEADERS)
+    .filter_map(std::future::ready);
+
+    hyper::server::accept::from_stream(connections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_v1_header() {
+        let mut data = &b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\nGET / HTTP/1.1\r\n"[..];
+        let addr = read_header(&mut data).await.unwrap();
+
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(data, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn reads_v2_header() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([0x21, 0x21, 0, 36]);
+        data.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend([0xDC, 0x04, 0x01, 0xBB]);
+        data.extend(b"GET");
+
+        let mut data = &data[..];
+        let addr = read_header(&mut data).await.unwrap();
+
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(data, b"GET");
+    }
+
+    #[test]
+    fn trusts_proxy_ranges() {
+        let trusted_proxies =
+            TrustedProxies::parse(&["127.0.0.0/8".to_owned(), "2001:db8::/32".to_owned()]).unwrap();
+
+        assert!(trusted_proxies.contains("127.0.0.1".parse().unwrap()));
+        assert!(trusted_proxies.contains("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(trusted_proxies.contains("2001:db8::1".parse().unwrap()));
+        assert!(!trusted_proxies.contains("192.0.2.1".parse().unwrap()));
+        assert!(!trusted_proxies.contains("::1".parse().unwrap()));
+
+        assert!(TrustedProxies::parse(&["localhost".to_owned()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_header() {
+        let mut data = &b"GET / HTTP/1.1\r\n"[..];
+        assert!(read_header(&mut data).await.is_err());
+    }
+}
@@ -0,0 +1,27 @@
+use std::future::Future;
+
+use http::header::HeaderName;
+
+use crate::utils;
+
+/// Response header that tells clients the ID of their request
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Generates a new ID for an incoming request.
+pub fn generate() -> String {
+    utils::random_string(16)
+}
+
+/// Runs the handling of a request with its ID, so the ID can be added to error responses.
+pub async fn scope<F: Future>(request_id: String, f: F) -> F::Output {
+    REQUEST_ID.scope(request_id, f).await
+}
+
+/// Returns the ID of the request that is currently handled, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
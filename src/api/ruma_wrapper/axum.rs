@@ -1,9 +1,17 @@
-use std::{collections::BTreeMap, iter::FromIterator, str};
+use std::{
+    collections::BTreeMap,
+    iter::FromIterator,
+    net::{IpAddr, SocketAddr},
+    str,
+};
 
 use axum::{
     async_trait,
     body::{Full, HttpBody},
-    extract::{rejection::TypedHeaderRejectionReason, FromRequest, Path, TypedHeader},
+    extract::{
+        rejection::TypedHeaderRejectionReason, ConnectInfo, FromRequest, FromRequestParts, Path,
+        TypedHeader,
+    },
     headers::{
         authorization::{Bearer, Credentials},
         Authorization,
@@ -12,21 +20,37 @@ use axum::{
     BoxError, RequestExt, RequestPartsExt,
 };
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use http::{Request, StatusCode};
+use http::{request::Parts, Method, Request, StatusCode};
 use ruma::{
-    api::{client::error::ErrorKind, AuthScheme, IncomingRequest, OutgoingResponse},
-    CanonicalJsonValue, OwnedDeviceId, OwnedServerName, UserId,
+    api::{
+        appservice::Registration, client::error::ErrorKind, AuthScheme, IncomingRequest,
+        OutgoingResponse,
+    },
+    CanonicalJsonValue, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedServerName, OwnedUserId,
+    ServerName, UserId,
 };
 use serde::Deserialize;
-use tracing::{debug, error, warn};
+use tracing::{debug, error, warn, Span};
 
-use super::{Ruma, RumaResponse};
-use crate::{services, Error, Result};
+use super::{Authenticated, Ruma, RumaResponse};
+use crate::{api::proxy_protocol::ProxiedAddr, services, Error, Result};
+
+/// Endpoints that use POST to send a request body but don't change anything, so they are allowed in
+/// maintenance mode. Filters are stored, but most clients can't sync without creating one.
+const READ_ONLY_POST_SUFFIXES: &[&str] = &[
+    "/filter",
+    "/search",
+    "/keys/query",
+    "/publicRooms",
+    "/key/v2/query",
+];
 
 #[derive(Deserialize)]
 struct QueryParams {
     access_token: Option<String>,
     user_id: Option<String>,
+    #[serde(alias = "org.matrix.msc3202.device_id")]
+    device_id: Option<String>,
 }
 
 #[async_trait]
@@ -57,6 +81,19 @@ where
             }
         };
 
+        // The body limit of the router is the largest one, apply the limit of this API
+        let max_size = if parts.uri.path().starts_with("/_matrix/federation/") {
+            services().globals.max_federation_request_size()
+        } else {
+            services().globals.max_request_size()
+        };
+        if body.len() > max_size as usize {
+            return Err(Error::BadRequest(
+                ErrorKind::TooLarge,
+                "Request body is too large.",
+            ));
+        }
+
         let metadata = T::METADATA;
         let auth_header: Option<TypedHeader<Authorization<Bearer>>> = parts.extract().await?;
         let path_params: Path<Vec<String>> = parts.extract().await?;
@@ -84,31 +121,14 @@ where
         let appservice_registration = appservices
             .iter()
             .find(|(_id, registration)| Some(registration.as_token.as_str()) == token);
+        let appservice_id = appservice_registration.map(|(id, _)| id.clone());
 
         let (sender_user, sender_device, sender_servername, from_appservice) =
             if let Some((_id, registration)) = appservice_registration {
                 match metadata.authentication {
                     AuthScheme::AccessToken => {
-                        let user_id = query_params.user_id.map_or_else(
-                            || {
-                                UserId::parse_with_server_name(
-                                    registration.sender_localpart.as_str(),
-                                    services().globals.server_name(),
-                                )
-                                .unwrap()
-                            },
-                            |s| UserId::parse(s).unwrap(),
-                        );
-
-                        if !services().users.exists(&user_id).unwrap() {
-                            return Err(Error::BadRequest(
-                                ErrorKind::Forbidden,
-                                "User does not exist.",
-                            ));
-                        }
-
-                        // TODO: Check if appservice is allowed to be that user
-                        (Some(user_id), None, None, true)
+                        let (user_id, device_id) = appservice_user(registration, &query_params)?;
+                        (Some(user_id), device_id, None, true)
                     }
                     AuthScheme::ServerSignatures => (None, None, None, true),
                     AuthScheme::None => (None, None, None, true),
@@ -116,30 +136,8 @@ where
             } else {
                 match metadata.authentication {
                     AuthScheme::AccessToken => {
-                        let token = match token {
-                            Some(token) => token,
-                            _ => {
-                                return Err(Error::BadRequest(
-                                    ErrorKind::MissingToken,
-                                    "Missing access token.",
-                                ))
-                            }
-                        };
-
-                        match services().users.find_from_token(token).unwrap() {
-                            None => {
-                                return Err(Error::BadRequest(
-                                    ErrorKind::UnknownToken { soft_logout: false },
-                                    "Unknown access token.",
-                                ))
-                            }
-                            Some((user_id, device_id)) => (
-                                Some(user_id),
-                                Some(OwnedDeviceId::from(device_id)),
-                                None,
-                                false,
-                            ),
-                        }
+                        let (user_id, device_id) = user_from_token(token).await?;
+                        (Some(user_id), Some(device_id), None, false)
                     }
                     AuthScheme::ServerSignatures => {
                         let TypedHeader(Authorization(x_matrix)) = parts
@@ -161,6 +159,14 @@ where
                                 Error::BadRequest(ErrorKind::Forbidden, msg)
                             })?;
 
+                        if services().globals.is_server_blocked(&x_matrix.origin)? {
+                            debug!("Rejecting request from blocked server {}", x_matrix.origin);
+                            return Err(Error::BadRequest(
+                                ErrorKind::Forbidden,
+                                "Your server is blocked by this homeserver.",
+                            ));
+                        }
+
                         let origin_signatures = BTreeMap::from_iter([(
                             x_matrix.key.clone(),
                             CanonicalJsonValue::String(x_matrix.sig),
@@ -216,6 +222,7 @@ where
                             .fetch_signing_keys_for_server(
                                 &x_matrix.origin,
                                 vec![x_matrix.key.to_owned()],
+                                Some(MilliSecondsSinceUnixEpoch::now()),
                             )
                             .await;
 
@@ -264,30 +271,8 @@ where
                                 .config
                                 .allow_public_room_directory_without_auth
                             {
-                                let token = match token {
-                                    Some(token) => token,
-                                    _ => {
-                                        return Err(Error::BadRequest(
-                                            ErrorKind::MissingToken,
-                                            "Missing access token.",
-                                        ))
-                                    }
-                                };
-
-                                match services().users.find_from_token(token).unwrap() {
-                                    None => {
-                                        return Err(Error::BadRequest(
-                                            ErrorKind::UnknownToken { soft_logout: false },
-                                            "Unknown access token.",
-                                        ))
-                                    }
-                                    Some((user_id, device_id)) => (
-                                        Some(user_id),
-                                        Some(OwnedDeviceId::from(device_id)),
-                                        None,
-                                        false,
-                                    ),
-                                }
+                                let (user_id, device_id) = user_from_token(token).await?;
+                                (Some(user_id), Some(device_id), None, false)
                             } else {
                                 (None, None, None, false)
                             }
@@ -297,6 +282,11 @@ where
                 }
             };
 
+        record_sender(sender_user.as_deref(), sender_servername.as_deref());
+        check_maintenance(&parts, sender_user.as_deref())?;
+
+        let client_ip = client_ip(&parts);
+
         let mut http_request = http::Request::builder().uri(parts.uri).method(parts.method);
         *http_request.headers_mut().unwrap() = parts.headers;
 
@@ -346,11 +336,217 @@ where
             sender_device,
             sender_servername,
             from_appservice,
+            appservice_id,
             json_body,
+            client_ip,
         })
     }
 }
 
+/// Find out which user and device an access token belongs to. If authentication is delegated, the
+/// token is validated by the authorization server.
+#[async_trait]
+impl<S: Sync> FromRequestParts<S> for Authenticated {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let auth_header: Option<TypedHeader<Authorization<Bearer>>> = parts.extract().await?;
+
+        let query = parts.uri.query().unwrap_or_default();
+        let query_params: QueryParams = serde_html_form::from_str(query).map_err(|e| {
+            error!(%query, "Failed to deserialize query parameters: {}", e);
+            Error::BadRequest(ErrorKind::Unknown, "Failed to read query parameters")
+        })?;
+
+        let token = match &auth_header {
+            Some(TypedHeader(Authorization(bearer))) => Some(bearer.token()),
+            None => query_params.access_token.as_deref(),
+        };
+
+        let appservices = services().appservice.all()?;
+        if let Some((id, registration)) = appservices
+            .iter()
+            .find(|(_id, registration)| Some(registration.as_token.as_str()) == token)
+        {
+            let (sender_user, sender_device) = appservice_user(registration, &query_params)?;
+            record_sender(Some(&sender_user), None);
+            check_maintenance(parts, Some(&sender_user))?;
+            Ok(Self {
+                sender_user,
+                sender_device,
+                appservice_id: Some(id.clone()),
+            })
+        } else {
+            let (sender_user, sender_device) = user_from_token(token).await?;
+            record_sender(Some(&sender_user), None);
+            check_maintenance(parts, Some(&sender_user))?;
+            Ok(Self {
+                sender_user,
+                sender_device: Some(sender_device),
+                appservice_id: None,
+            })
+        }
+    }
+}
+
+/// Adds the user or server that sent a request to its span, so its logs can be traced back to
+/// them.
+fn record_sender(sender_user: Option<&UserId>, sender_servername: Option<&ServerName>) {
+    let span = Span::current();
+    if let Some(user_id) = sender_user {
+        span.record("user", user_id.as_str());
+    }
+    if let Some(server_name) = sender_servername {
+        span.record("origin", server_name.as_str());
+    }
+}
+
+/// Returns the address of the client, from the PROXY protocol header of the connection if there is
+/// one. Requests forwarded by a reverse proxy on the same host or over the UNIX socket come from
+/// the address the proxy added last to `X-Forwarded-For`.
+fn client_ip(parts: &Parts) -> Option<IpAddr> {
+    let peer = match parts.extensions.get::<ProxiedAddr>() {
+        Some(ProxiedAddr(Some(addr))) => Some(addr.ip()),
+        _ => parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip()),
+    };
+
+    if peer.map_or(true, |ip| ip.is_loopback()) {
+        let forwarded_for = parts
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .and_then(|ip| ip.trim().parse().ok());
+
+        if forwarded_for.is_some() {
+            return forwarded_for;
+        }
+    }
+
+    peer
+}
+
+/// Refuses requests that write while maintenance mode is enabled. Admins are exempt so they can
+/// still use the admin room.
+fn check_maintenance(parts: &Parts, sender_user: Option<&UserId>) -> Result<()> {
+    let Some(message) = services().globals.maintenance_message() else {
+        return Ok(());
+    };
+
+    let path = parts.uri.path();
+    let read_only = match parts.method {
+        Method::GET | Method::HEAD | Method::OPTIONS => true,
+        Method::POST => {
+            READ_ONLY_POST_SUFFIXES
+                .iter()
+                .any(|suffix| path.ends_with(suffix))
+                || path.contains("/get_missing_events/")
+        }
+        _ => false,
+    };
+    if read_only {
+        return Ok(());
+    }
+
+    if let Some(user_id) = sender_user {
+        if services().users.is_admin(user_id)? {
+            return Ok(());
+        }
+    }
+
+    debug!("Refusing {} {} in maintenance mode", parts.method, path);
+    Err(Error::Maintenance(message))
+}
+
+/// Returns the user an appservice acts as, which is its sender user unless the `user_id` query
+/// parameter is given.
+fn appservice_user(
+    registration: &Registration,
+    query_params: &QueryParams,
+) -> Result<(OwnedUserId, Option<OwnedDeviceId>)> {
+    let user_id = query_params.user_id.as_deref().map_or_else(
+        || {
+            UserId::parse_with_server_name(
+                registration.sender_localpart.as_str(),
+                services().globals.server_name(),
+            )
+            .unwrap()
+        },
+        |s| UserId::parse(s).unwrap(),
+    );
+
+    if !services().users.exists(&user_id).unwrap() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "User does not exist.",
+        ));
+    }
+
+    // Appservices may act as one of the user's devices (MSC3202)
+    let device_id = match query_params.device_id.as_deref() {
+        Some(device_id) => {
+            let device_id = OwnedDeviceId::from(device_id);
+            if !services()
+                .users
+                .all_device_ids(&user_id)
+                .filter_map(Result::ok)
+                .any(|existing| existing == device_id)
+            {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "Device does not exist.",
+                ));
+            }
+            Some(device_id)
+        }
+        None => None,
+    };
+
+    // TODO: Check if appservice is allowed to be that user
+    Ok((user_id, device_id))
+}
+
+/// Returns the user and device of an access token that does not belong to an appservice.
+async fn user_from_token(token: Option<&str>) -> Result<(OwnedUserId, OwnedDeviceId)> {
+    let Some(token) = token else {
+        return Err(Error::BadRequest(
+            ErrorKind::MissingToken,
+            "Missing access token.",
+        ));
+    };
+
+    let Some((user_id, device_id)) = find_from_token(token).await? else {
+        return Err(Error::BadRequest(
+            ErrorKind::UnknownToken { soft_logout: false },
+            "Unknown access token.",
+        ));
+    };
+    let device_id = OwnedDeviceId::from(device_id);
+
+    // Delegated tokens expire at the authorization server
+    if !services().oidc.enabled() && !services().users.record_token_use(&user_id, &device_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::UnknownToken { soft_logout: true },
+            "Access token has expired.",
+        ));
+    }
+
+    Ok((user_id, device_id))
+}
+
+async fn find_from_token(token: &str) -> Result<Option<(OwnedUserId, String)>> {
+    if services().oidc.enabled() {
+        services().oidc.find_from_token(token).await
+    } else {
+        services().users.find_from_token(token)
+    }
+}
+
 struct XMatrix {
     origin: OwnedServerName,
     destination: Option<String>,
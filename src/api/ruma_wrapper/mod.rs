@@ -3,7 +3,7 @@ use ruma::{
     api::client::uiaa::UiaaResponse, CanonicalJsonValue, OwnedDeviceId, OwnedServerName,
     OwnedUserId,
 };
-use std::ops::Deref;
+use std::{net::IpAddr, ops::Deref};
 
 #[cfg(feature = "conduit_bin")]
 mod axum;
@@ -17,6 +17,19 @@ pub struct Ruma<T> {
     // This is None when body is not a valid string
     pub json_body: Option<CanonicalJsonValue>,
     pub from_appservice: bool,
+    // The registration ID of the appservice that sent the request
+    pub appservice_id: Option<String>,
+    // The address of the client, None if it is unknown
+    pub client_ip: Option<IpAddr>,
+}
+
+/// Extractor for endpoints that read the request body themselves, e.g. to stream it. Only supports
+/// access token authentication.
+pub struct Authenticated {
+    pub sender_user: OwnedUserId,
+    pub sender_device: Option<OwnedDeviceId>,
+    // The registration ID of the appservice that sent the request
+    pub appservice_id: Option<String>,
 }
 
 impl<T> Deref for Ruma<T> {
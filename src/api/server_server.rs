@@ -4,7 +4,7 @@
 use crate::{
     api::client_server::{self, claim_keys_helper, get_keys_helper},
     service::pdu::{gen_event_id_canonical_json, PduBuilder},
-    services, utils, Error, PduEvent, Result, Ruma,
+    services, utils, Error, PduEvent, PduRejection, Result, Ruma,
 };
 use axum::{response::IntoResponse, Json};
 use futures_util::future::TryFutureExt;
@@ -20,11 +20,15 @@ use ruma::{
             backfill::get_backfill,
             device::get_devices::{self, v1::UserDevice},
             directory::{get_public_rooms, get_public_rooms_filtered},
-            discovery::{get_server_keys, get_server_version, ServerSigningKeys, VerifyKey},
+            discovery::{
+                get_remote_server_keys, get_remote_server_keys_batch, get_server_keys,
+                get_server_version, ServerSigningKeys, VerifyKey,
+            },
             event::{get_event, get_missing_events, get_room_state, get_room_state_ids},
             keys::{claim_keys, get_keys},
             membership::{create_invite, create_join_event, prepare_join_event},
             query::{get_profile_information, get_room_information},
+            thirdparty::{bind_callback, exchange_invite},
             transactions::{
                 edu::{DeviceListUpdateContent, DirectDeviceContent, Edu, SigningKeyUpdateContent},
                 send_transaction_message,
@@ -38,7 +42,8 @@ use ruma::{
         receipt::{ReceiptEvent, ReceiptEventContent, ReceiptType},
         room::{
             join_rules::{JoinRule, RoomJoinRulesEventContent},
-            member::{MembershipState, RoomMemberEventContent},
+            member::{MembershipState, RoomMemberEventContent, ThirdPartyInvite},
+            third_party_invite::RoomThirdPartyInviteEventContent,
         },
         StateEventType, TimelineEventType,
     },
@@ -46,20 +51,22 @@ use ruma::{
     to_device::DeviceIdOrAllDevices,
     uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch,
     OwnedEventId, OwnedRoomId, OwnedServerName, OwnedServerSigningKeyId, OwnedUserId, RoomId,
-    ServerName,
+    ServerName, UserId,
 };
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use std::{
     collections::BTreeMap,
     fmt::Debug,
-    mem,
+    future::Future,
+    iter, mem,
     net::{IpAddr, SocketAddr},
     sync::{Arc, RwLock},
     time::{Duration, Instant, SystemTime},
 };
+use tokio::sync::Semaphore;
 use trust_dns_resolver::{error::ResolveError, lookup::SrvLookup};
 
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, warn, Instrument};
 
 /// Wraps either an literal IP address plus port, or a hostname plus complement
 /// (colon-plus-port if it was specified).
@@ -119,6 +126,169 @@ pub(crate) async fn send_request<T: OutgoingRequest>(
     destination: &ServerName,
     request: T,
 ) -> Result<T::IncomingResponse>
+where
+    T: Debug,
+{
+    send_request_limited(destination, request, None).await
+}
+
+/// Like `send_request`, but fails without reading the whole response if its body is larger than
+/// `max_body_size` bytes.
+pub(crate) async fn send_request_limited<T: OutgoingRequest>(
+    destination: &ServerName,
+    request: T,
+    max_body_size: Option<u64>,
+) -> Result<T::IncomingResponse>
+where
+    T: Debug,
+{
+    let (mut response, sent) = execute_request(destination, request).await?;
+
+    // reqwest::Response -> http::Response conversion
+    let status = response.status();
+    let mut http_response_builder = http::Response::builder()
+        .status(status)
+        .version(response.version());
+    mem::swap(
+        response.headers_mut(),
+        http_response_builder
+            .headers_mut()
+            .expect("http::response::Builder is usable"),
+    );
+
+    debug!("Getting response bytes from {destination}");
+    let body = match max_body_size {
+        Some(max_body_size) => read_limited_body(&mut response, max_body_size, destination)
+            .await?
+            .into(),
+        None => response.bytes().await.unwrap_or_else(|e| {
+            info!("server error {}", e);
+            Vec::new().into()
+        }), // TODO: handle timeout
+    };
+    debug!("Got response bytes from {destination}");
+
+    if !status.is_success() {
+        debug!(
+            "Response not successful\n{} {}: {}",
+            sent.url,
+            status,
+            String::from_utf8_lossy(&body)
+                .lines()
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+
+    let http_response = http_response_builder
+        .body(body)
+        .expect("reqwest body is valid http body");
+
+    if status.is_success() {
+        debug!("Parsing response bytes from {destination}");
+        let response = T::IncomingResponse::try_from_http_response(http_response);
+        if response.is_ok() {
+            sent.remember_destination(destination);
+        }
+
+        response.map_err(|e| {
+            warn!(
+                "Invalid 200 response from {} on: {} {}",
+                &destination, sent.url, e
+            );
+            Error::BadServerResponse("Server returned bad 200 response.")
+        })
+    } else {
+        debug!("Returning error from {destination}");
+
+        sent.forget_destination(destination);
+
+        Err(Error::FederationError(
+            destination.to_owned(),
+            RumaError::from_http_response(http_response),
+        ))
+    }
+}
+
+/// Like `send_request`, but returns a successful response without reading its body, so large
+/// bodies can be streamed.
+pub(crate) async fn send_request_streamed<T: OutgoingRequest>(
+    destination: &ServerName,
+    request: T,
+) -> Result<reqwest::Response>
+where
+    T: Debug,
+{
+    let (mut response, sent) = execute_request(destination, request).await?;
+
+    let status = response.status();
+    if status.is_success() {
+        sent.remember_destination(destination);
+        return Ok(response);
+    }
+
+    let mut http_response_builder = http::Response::builder()
+        .status(status)
+        .version(response.version());
+    mem::swap(
+        response.headers_mut(),
+        http_response_builder
+            .headers_mut()
+            .expect("http::response::Builder is usable"),
+    );
+
+    // Error responses are small JSON objects
+    let body = read_limited_body(&mut response, MAX_ERROR_BODY_SIZE, destination).await?;
+    let http_response = http_response_builder
+        .body(body)
+        .expect("reqwest body is valid http body");
+
+    debug!("Returning error from {destination}");
+    sent.forget_destination(destination);
+
+    Err(Error::FederationError(
+        destination.to_owned(),
+        RumaError::from_http_response(http_response),
+    ))
+}
+
+/// A federation request that was sent, with what is needed to handle its response.
+struct SentRequest {
+    url: reqwest::Url,
+    actual_destination: FedDest,
+    host: String,
+    write_destination_to_cache: bool,
+}
+
+impl SentRequest {
+    /// Caches where the destination was reached after it answered successfully.
+    fn remember_destination(&self, destination: &ServerName) {
+        if self.write_destination_to_cache {
+            services().globals.actual_destination_cache.insert(
+                OwnedServerName::from(destination),
+                (self.actual_destination.clone(), self.host.clone()),
+            );
+        }
+    }
+
+    /// Removes potentially dead destinations from our cache that may be from modified well-knowns.
+    fn forget_destination(&self, destination: &ServerName) {
+        if !self.write_destination_to_cache {
+            info!("Evicting {destination} from our true destination cache due to failed request.");
+            services()
+                .globals
+                .actual_destination_cache
+                .remove(destination);
+        }
+    }
+}
+
+/// Signs and sends a federation request and records whether the destination answered. The body
+/// of the response is not read.
+async fn execute_request<T: OutgoingRequest>(
+    destination: &ServerName,
+    request: T,
+) -> Result<(reqwest::Response, SentRequest)>
 where
     T: Debug,
 {
@@ -132,6 +302,11 @@ where
         ));
     }
 
+    if services().globals.is_server_blocked(destination)? {
+        debug!("Not sending request to {destination}, it is blocked");
+        return Err(Error::BadServerResponse("Destination is blocked"));
+    }
+
     if destination.is_ip_literal() || IPAddress::is_valid(destination.host()) {
         info!(
             "Destination {} is an IP literal, checking against IP range denylist.",
@@ -162,17 +337,23 @@ where
         info!("IP literal {} is allowed.", destination);
     }
 
+    // Dropping the guard on any early return counts as a failed request
+    let Some(request_guard) = services()
+        .sending
+        .circuit_breaker
+        .start_request(destination)
+    else {
+        debug!("Not sending request to {destination}, it failed too often recently");
+        return Err(Error::BadServerResponse(
+            "Destination is backing off after repeated failures",
+        ));
+    };
+
     debug!("Preparing to send request to {destination}");
 
     let mut write_destination_to_cache = false;
 
-    let cached_result = services()
-        .globals
-        .actual_destination_cache
-        .read()
-        .unwrap()
-        .get(destination)
-        .cloned();
+    let cached_result = services().globals.actual_destination_cache.get(destination);
 
     let (actual_destination, host) = if let Some(result) = cached_result {
         result
@@ -270,6 +451,7 @@ where
     let url = reqwest_request.url().clone();
 
     debug!("Sending request to {destination} at {url}");
+    let start = Instant::now();
     let response = services()
         .globals
         .federation_client()
@@ -277,85 +459,28 @@ where
         .await;
     debug!("Received response from {destination} at {url}");
 
-    match response {
-        Ok(mut response) => {
-            // reqwest::Response -> http::Response conversion
-            let status = response.status();
-            let mut http_response_builder = http::Response::builder()
-                .status(status)
-                .version(response.version());
-            mem::swap(
-                response.headers_mut(),
-                http_response_builder
-                    .headers_mut()
-                    .expect("http::response::Builder is usable"),
-            );
-
-            debug!("Getting response bytes from {destination}");
-            let body = response.bytes().await.unwrap_or_else(|e| {
-                info!("server error {}", e);
-                Vec::new().into()
-            }); // TODO: handle timeout
-            debug!("Got response bytes from {destination}");
-
-            if !status.is_success() {
-                debug!(
-                    "Response not successful\n{} {}: {}",
-                    url,
-                    status,
-                    String::from_utf8_lossy(&body)
-                        .lines()
-                        .collect::<Vec<_>>()
-                        .join(" ")
-                );
-            }
-
-            let http_response = http_response_builder
-                .body(body)
-                .expect("reqwest body is valid http body");
-
-            if status.is_success() {
-                debug!("Parsing response bytes from {destination}");
-                let response = T::IncomingResponse::try_from_http_response(http_response);
-                if response.is_ok() && write_destination_to_cache {
-                    services()
-                        .globals
-                        .actual_destination_cache
-                        .write()
-                        .unwrap()
-                        .insert(
-                            OwnedServerName::from(destination),
-                            (actual_destination, host),
-                        );
-                }
-
-                response.map_err(|e| {
-                    warn!(
-                        "Invalid 200 response from {} on: {} {}",
-                        &destination, url, e
-                    );
-                    Error::BadServerResponse("Server returned bad 200 response.")
-                })
-            } else {
-                debug!("Returning error from {destination}");
+    services().sending.request_metrics.record_request(
+        destination,
+        response.as_ref().ok().map(|r| r.status().as_u16()),
+        start.elapsed(),
+    );
 
-                // remove potentially dead destinations from our cache that may be from modified well-knowns
-                if !write_destination_to_cache {
-                    info!("Evicting {destination} from our true destination cache due to failed request.");
-                    services()
-                        .globals
-                        .actual_destination_cache
-                        .write()
-                        .unwrap()
-                        .remove(destination);
-                }
+    // Error responses like 404 still mean the server is up
+    match &response {
+        Ok(response) if !response.status().is_server_error() => request_guard.success(),
+        _ => request_guard.failure(),
+    }
 
-                Err(Error::FederationError(
-                    destination.to_owned(),
-                    RumaError::from_http_response(http_response),
-                ))
-            }
-        }
+    match response {
+        Ok(response) => Ok((
+            response,
+            SentRequest {
+                url,
+                actual_destination,
+                host,
+                write_destination_to_cache,
+            },
+        )),
         Err(e) => {
             // we do not need to log that servers in a room are dead, this is normal in public rooms and just spams the logs.
             match e.is_timeout() {
@@ -388,6 +513,36 @@ where
     }
 }
 
+/// Maximum size of error responses to streamed requests that is read
+const MAX_ERROR_BODY_SIZE: u64 = 64 * 1024;
+
+/// Reads the response body, giving up as soon as it turns out to be larger than `max_body_size`
+/// bytes instead of buffering all of it.
+async fn read_limited_body(
+    response: &mut reqwest::Response,
+    max_body_size: u64,
+    destination: &ServerName,
+) -> Result<Vec<u8>> {
+    if response
+        .content_length()
+        .is_some_and(|length| length > max_body_size)
+    {
+        info!("Response from {destination} announced a body larger than {max_body_size} bytes");
+        return Err(Error::BadServerResponse("Response body is too large."));
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (body.len() + chunk.len()) as u64 > max_body_size {
+            info!("Response from {destination} is larger than {max_body_size} bytes");
+            return Err(Error::BadServerResponse("Response body is too large."));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
 fn get_ip_with_port(destination_str: &str) -> Option<FedDest> {
     if let Ok(destination) = destination_str.parse::<SocketAddr>() {
         Some(FedDest::Literal(destination))
@@ -450,18 +605,13 @@ async fn find_actual_destination(destination: &'_ ServerName) -> (FedDest, FedDe
                                             .lookup_ip(hostname_override.hostname())
                                             .await
                                         {
-                                            services()
-                                                .globals
-                                                .tls_name_override
-                                                .write()
-                                                .unwrap()
-                                                .insert(
-                                                    delegated_hostname.clone(),
-                                                    (
-                                                        override_ip.iter().collect(),
-                                                        force_port.unwrap_or(8448),
-                                                    ),
-                                                );
+                                            services().globals.tls_name_override.insert(
+                                                delegated_hostname.clone(),
+                                                (
+                                                    override_ip.iter().collect(),
+                                                    force_port.unwrap_or(8448),
+                                                ),
+                                            );
                                         } else {
                                             debug!(
                                                 "Using SRV record {}, but could not resolve to IP",
@@ -495,18 +645,10 @@ async fn find_actual_destination(destination: &'_ ServerName) -> (FedDest, FedDe
                                     .lookup_ip(hostname_override.hostname())
                                     .await
                                 {
-                                    services()
-                                        .globals
-                                        .tls_name_override
-                                        .write()
-                                        .unwrap()
-                                        .insert(
-                                            hostname.clone(),
-                                            (
-                                                override_ip.iter().collect(),
-                                                force_port.unwrap_or(8448),
-                                            ),
-                                        );
+                                    services().globals.tls_name_override.insert(
+                                        hostname.clone(),
+                                        (override_ip.iter().collect(), force_port.unwrap_or(8448)),
+                                    );
                                 } else {
                                     debug!(
                                         "Using SRV record {}, but could not resolve to IP",
@@ -645,6 +787,69 @@ pub async fn get_server_keys_route() -> Result<impl IntoResponse> {
         return Err(Error::bad_config("Federation is disabled."));
     }
 
+    Ok(Json(own_server_keys()))
+}
+
+/// # `GET /_matrix/key/v2/server/{keyId}`
+///
+/// Gets the public signing keys of this server.
+///
+/// - Matrix does not support invalidating public keys, so the key returned by this will be valid
+/// forever.
+pub async fn get_server_keys_deprecated_route() -> impl IntoResponse {
+    get_server_keys_route().await
+}
+
+/// # `GET /_matrix/key/v2/query/{serverName}`
+///
+/// Acts as a notary server: gets the public signing keys of another server, signed by us.
+///
+/// - Returns our cached copy of the keys if it is valid until at least `minimum_valid_until_ts`,
+/// otherwise fetches them from the server first
+pub async fn get_remote_server_keys_route(
+    body: Ruma<get_remote_server_keys::v2::Request>,
+) -> Result<get_remote_server_keys::v2::Response> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    let server_keys = notary_server_keys(&body.server_name, body.minimum_valid_until_ts)
+        .await?
+        .into_iter()
+        .collect();
+
+    Ok(get_remote_server_keys::v2::Response::new(server_keys))
+}
+
+/// # `POST /_matrix/key/v2/query`
+///
+/// Acts as a notary server: gets the public signing keys of multiple servers, signed by us.
+///
+/// - Returns all keys of each server regardless of the requested key IDs
+/// - Servers we can't get any keys for are left out of the response
+pub async fn get_remote_server_keys_batch_route(
+    body: Ruma<get_remote_server_keys_batch::v2::Request>,
+) -> Result<get_remote_server_keys_batch::v2::Response> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    let mut server_keys = Vec::new();
+    for (server_name, criteria) in &body.server_keys {
+        let minimum_valid_until_ts = criteria
+            .values()
+            .filter_map(|criteria| criteria.minimum_valid_until_ts)
+            .max()
+            .unwrap_or_else(MilliSecondsSinceUnixEpoch::now);
+
+        server_keys.extend(notary_server_keys(server_name, minimum_valid_until_ts).await?);
+    }
+
+    Ok(get_remote_server_keys_batch::v2::Response::new(server_keys))
+}
+
+/// Returns the current signing keys of this server, signed by us.
+fn own_server_keys() -> CanonicalJsonObject {
     let mut verify_keys: BTreeMap<OwnedServerSigningKeyId, VerifyKey> = BTreeMap::new();
     verify_keys.insert(
         format!("ed25519:{}", services().globals.keypair().version())
@@ -681,17 +886,78 @@ pub async fn get_server_keys_route() -> Result<impl IntoResponse> {
     )
     .unwrap();
 
-    Ok(Json(response))
+    response
 }
 
-/// # `GET /_matrix/key/v2/server/{keyId}`
-///
-/// Gets the public signing keys of this server.
-///
-/// - Matrix does not support invalidating public keys, so the key returned by this will be valid
-/// forever.
-pub async fn get_server_keys_deprecated_route() -> impl IntoResponse {
-    get_server_keys_route().await
+/// Gets the keys of a server as signed by that server, refetching them if our copy expires before
+/// `minimum_valid_until_ts`, and adds our signature.
+async fn notary_server_keys(
+    server_name: &ServerName,
+    minimum_valid_until_ts: MilliSecondsSinceUnixEpoch,
+) -> Result<Option<Raw<ServerSigningKeys>>> {
+    if server_name == services().globals.server_name() {
+        return Ok(Some(Raw::from_json(
+            to_raw_value(&own_server_keys()).expect("CanonicalJson is valid json"),
+        )));
+    }
+
+    let valid_until_ts = |keys: &Raw<ServerSigningKeys>| {
+        keys.deserialize()
+            .ok()
+            .filter(|keys| keys.server_name == server_name)
+            .map(|keys| keys.valid_until_ts)
+    };
+
+    let mut server_keys = services().globals.signed_server_keys(server_name)?;
+
+    if server_keys
+        .as_ref()
+        .and_then(valid_until_ts)
+        .map_or(true, |valid_until_ts| {
+            valid_until_ts < minimum_valid_until_ts
+        })
+    {
+        debug!("Fetching signing keys of {server_name} for notary request");
+        match services()
+            .sending
+            .send_federation_request(server_name, get_server_keys::v2::Request::new())
+            .await
+        {
+            Ok(response) => match services()
+                .globals
+                .verify_server_keys(server_name, &response.server_key)
+            {
+                Ok(keys) => {
+                    services().globals.add_signing_key(server_name, keys)?;
+                    services()
+                        .globals
+                        .add_signed_server_keys(server_name, &response.server_key)?;
+                    server_keys = Some(response.server_key);
+                }
+                Err(e) => warn!("Rejecting signing keys of {server_name}: {e}"),
+            },
+            // An outdated copy is still better than nothing, the requesting server can decide
+            Err(_) => debug!("Failed to fetch signing keys of {server_name}, using cached keys"),
+        }
+    }
+
+    let Some(server_keys) = server_keys.filter(|keys| valid_until_ts(keys).is_some()) else {
+        return Ok(None);
+    };
+
+    let mut server_keys: CanonicalJsonObject = serde_json::from_str(server_keys.json().get())
+        .map_err(|_| Error::bad_database("Invalid signed server keys in db."))?;
+
+    ruma::signatures::sign_json(
+        services().globals.server_name().as_str(),
+        services().globals.keypair(),
+        &mut server_keys,
+    )
+    .map_err(|_| Error::BadServerResponse("Failed to sign server keys"))?;
+
+    Ok(Some(Raw::from_json(
+        to_raw_value(&server_keys).expect("CanonicalJson is valid json"),
+    )))
 }
 
 /// # `POST /_matrix/federation/v1/publicRooms`
@@ -808,18 +1074,6 @@ pub async fn send_transaction_message_route(
         .as_ref()
         .expect("server is authenticated");
 
-    let mut resolved_map = BTreeMap::new();
-
-    let pub_key_map = RwLock::new(BTreeMap::new());
-
-    // This is all the auth_events that have been recursively fetched so they don't have to be
-    // deserialized over and over again.
-    // TODO: make this persist across requests but not in a DB Tree (in globals?)
-    // TODO: This could potentially also be some sort of trie (suffix tree) like structure so
-    // that once an auth event is known it would know (using indexes maybe) all of the auth
-    // events that it references.
-    // let mut auth_cache = EventMap::new();
-
     let mut parsed_pdus = vec![];
     for pdu in &body.pdus {
         let value: CanonicalJsonObject = serde_json::from_str(pdu.get()).map_err(|e| {
@@ -852,69 +1106,45 @@ pub async fn send_transaction_message_route(
         // We do not add the event_id field to the pdu here because of signature and hashes checks
     }
 
-    // We go through all the signatures we see on the PDUs and fetch the corresponding
-    // signing keys
-    services()
-        .rooms
-        .event_handler
-        .fetch_required_signing_keys(
-            parsed_pdus.iter().map(|(_event_id, event, _room_id)| event),
-            &pub_key_map,
-        )
+    let deadline = incoming_request_deadline();
+
+    // Only one transaction of a server is handled at a time, so a server can't pile up work in
+    // the background. It retries the transaction if the previous one is still being handled.
+    let semaphore = services()
+        .globals
+        .servername_transaction
+        .with_entry(sender_servername.to_owned(), |entry| {
+            Arc::clone(entry.or_insert_with(|| Arc::new(Semaphore::new(1))))
+        });
+    let permit = tokio::time::timeout_at(deadline, semaphore.acquire_owned())
         .await
-        .unwrap_or_else(|e| {
+        .map_err(|_| Error::Timeout("A previous transaction is still being processed."))?
+        .expect("semaphore is never closed");
+
+    // Handling the PDUs of big or broken rooms can take minutes, so they are handled on their own
+    // task which finishes in the background when the transaction takes too long
+    let origin = sender_servername.to_owned();
+    let pdus = tokio::spawn(
+        async move {
+            let resolved_map = handle_transaction_pdus(origin, parsed_pdus).await;
+            drop(permit);
+            resolved_map
+        }
+        .in_current_span(),
+    );
+    let resolved_map = match tokio::time::timeout_at(deadline, pdus).await {
+        Ok(resolved_map) => resolved_map.map_err(|e| {
+            error!("Handling the PDUs of a transaction from {sender_servername} failed: {e}");
+            Error::BadServerResponse("Handling the PDUs of the transaction failed.")
+        })?,
+        Err(_) => {
             warn!(
-                "Could not fetch all signatures for PDUs from {}: {:?}",
-                sender_servername, e
-            )
-        });
-
-    for (event_id, value, room_id) in parsed_pdus {
-        let mutex = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_federation
-                .write()
-                .unwrap()
-                .entry(room_id.to_owned())
-                .or_default(),
-        );
-        let mutex_lock = mutex.lock().await;
-        let start_time = Instant::now();
-        resolved_map.insert(
-            event_id.clone(),
-            services()
-                .rooms
-                .event_handler
-                .handle_incoming_pdu(
-                    sender_servername,
-                    &event_id,
-                    &room_id,
-                    value,
-                    true,
-                    &pub_key_map,
-                )
-                .await
-                .map(|_| ()),
-        );
-        drop(mutex_lock);
-
-        let elapsed = start_time.elapsed();
-        debug!(
-            "Handling transaction of event {} took {}m{}s",
-            event_id,
-            elapsed.as_secs() / 60,
-            elapsed.as_secs() % 60
-        );
-    }
-
-    for pdu in &resolved_map {
-        if let Err(e) = pdu.1 {
-            if matches!(e, Error::BadRequest(ErrorKind::NotFound, _)) {
-                warn!("Incoming PDU failed {:?}", pdu);
-            }
+                "Transaction from {sender_servername} took too long, handling the rest of its \
+                 PDUs in the background"
+            );
+            BTreeMap::new()
         }
-    }
+    };
 
     for edu in body
         .edus
@@ -927,18 +1157,11 @@ pub async fn send_transaction_message_route(
                     continue;
                 }
 
-                for update in presence.push {
-                    for room_id in services().rooms.state_cache.rooms_joined(&update.user_id) {
-                        services().rooms.edus.presence.set_presence(
-                            &room_id?,
-                            &update.user_id,
-                            update.presence.clone(),
-                            Some(update.currently_active),
-                            Some(update.last_active_ago),
-                            update.status_msg.clone(),
-                        )?;
-                    }
-                }
+                services()
+                    .rooms
+                    .edus
+                    .presence
+                    .handle_incoming_presence(sender_servername, presence.push)?;
             }
             Edu::Receipt(receipt) => {
                 for (room_id, room_updates) in receipt.receipts {
@@ -1097,21 +1320,158 @@ pub async fn send_transaction_message_route(
     })
 }
 
-/// # `GET /_matrix/federation/v1/event/{eventId}`
-///
-/// Retrieves a single event from the server.
-///
-/// - Only works if a user of this server is currently invited or joined the room
-pub async fn get_event_route(
-    body: Ruma<get_event::v1::Request>,
-) -> Result<get_event::v1::Response> {
-    if !services().globals.allow_federation() {
-        return Err(Error::bad_config("Federation is disabled."));
-    }
+/// Fetches the signing keys for the PDUs of a transaction and handles them. Returns the result of
+/// every PDU that was handled.
+async fn handle_transaction_pdus(
+    origin: OwnedServerName,
+    parsed_pdus: Vec<(OwnedEventId, CanonicalJsonObject, OwnedRoomId)>,
+) -> BTreeMap<OwnedEventId, Result<()>> {
+    let mut resolved_map = BTreeMap::new();
 
-    let sender_servername = body
-        .sender_servername
-        .as_ref()
+    let pub_key_map = RwLock::new(BTreeMap::new());
+
+    // This is all the auth_events that have been recursively fetched so they don't have to be
+    // deserialized over and over again.
+    // TODO: make this persist across requests but not in a DB Tree (in globals?)
+    // TODO: This could potentially also be some sort of trie (suffix tree) like structure so
+    // that once an auth event is known it would know (using indexes maybe) all of the auth
+    // events that it references.
+    // let mut auth_cache = EventMap::new();
+
+    // We go through all the signatures we see on the PDUs and fetch the corresponding
+    // signing keys
+    services()
+        .rooms
+        .event_handler
+        .fetch_required_signing_keys(
+            parsed_pdus.iter().map(|(_event_id, event, _room_id)| event),
+            &pub_key_map,
+        )
+        .await
+        .unwrap_or_else(|e| {
+            warn!(
+                "Could not fetch all signatures for PDUs from {}: {:?}",
+                origin, e
+            )
+        });
+
+    let _preverified = services()
+        .rooms
+        .event_handler
+        .verify_pdus(
+            parsed_pdus
+                .iter()
+                .map(|(event_id, value, room_id)| (&**event_id, value, &**room_id)),
+            &pub_key_map,
+        )
+        .await;
+
+    for (event_id, value, room_id) in parsed_pdus {
+        let mutex = services().globals.roomid_mutex_federation.mutex(&room_id);
+        let mutex_lock = mutex.lock().await;
+        let start_time = Instant::now();
+        resolved_map.insert(
+            event_id.clone(),
+            services()
+                .rooms
+                .event_handler
+                .handle_incoming_pdu(&origin, &event_id, &room_id, value, true, &pub_key_map)
+                .await
+                .map(|_| ()),
+        );
+        drop(mutex_lock);
+
+        let elapsed = start_time.elapsed();
+        debug!(
+            "Handling transaction of event {} took {}m{}s",
+            event_id,
+            elapsed.as_secs() / 60,
+            elapsed.as_secs() % 60
+        );
+    }
+
+    for (event_id, result) in &resolved_map {
+        if let Err(e) = result {
+            let reason = match e {
+                Error::RejectedPdu(reason, _) => Some(*reason),
+                _ => None,
+            };
+            services().rooms.event_handler.count_rejected_pdu(reason);
+
+            if reason != Some(PduRejection::UnknownRoom) {
+                warn!("Incoming PDU {event_id} from {origin} failed: {e}");
+            }
+        }
+    }
+
+    resolved_map
+}
+
+/// Returns when the handling of an incoming request that started now has to be done.
+fn incoming_request_deadline() -> tokio::time::Instant {
+    tokio::time::Instant::now() + services().globals.incoming_federation_timeout()
+}
+
+/// Cancels `work` if it is not done by the deadline, so a pathological room can't keep a request
+/// open for minutes.
+async fn until_deadline<T>(
+    deadline: tokio::time::Instant,
+    work: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    tokio::time::timeout_at(deadline, work)
+        .await
+        .map_err(|_| Error::Timeout("The request took too long."))?
+}
+
+/// Loads the events in their outgoing federation format. Loading is a synchronous loop, so it
+/// checks the deadline and yields to the runtime every few events instead of relying on
+/// `until_deadline`, which can only cancel at an await point.
+///
+/// Missing events are an error if `require_all` is set, otherwise they are skipped.
+async fn outgoing_pdus_until_deadline(
+    deadline: tokio::time::Instant,
+    event_ids: impl IntoIterator<Item = Arc<EventId>>,
+    require_all: bool,
+) -> Result<Vec<Box<RawJsonValue>>> {
+    const DEADLINE_CHECK_INTERVAL: usize = 100;
+
+    let mut pdus = Vec::new();
+    for (i, id) in event_ids.into_iter().enumerate() {
+        if i % DEADLINE_CHECK_INTERVAL == DEADLINE_CHECK_INTERVAL - 1 {
+            if tokio::time::Instant::now() >= deadline {
+                return Err(Error::Timeout("The request took too long."));
+            }
+            tokio::task::yield_now().await;
+        }
+
+        match services().rooms.timeline.get_pdu_json(&id)? {
+            Some(json) => pdus.push(PduEvent::convert_to_outgoing_federation_event(json)),
+            None if require_all => {
+                error!("Could not find event json for state event {id} in db.");
+                return Err(Error::bad_database("Missing state event."));
+            }
+            None => error!("Could not find event json for {id} in db."),
+        }
+    }
+
+    Ok(pdus)
+}
+
+/// # `GET /_matrix/federation/v1/event/{eventId}`
+///
+/// Retrieves a single event from the server.
+///
+/// - Only works if a user of this server is currently invited or joined the room
+pub async fn get_event_route(
+    body: Ruma<get_event::v1::Request>,
+) -> Result<get_event::v1::Response> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    let sender_servername = body
+        .sender_servername
+        .as_ref()
         .expect("server is authenticated");
 
     let event = services()
@@ -1398,6 +1758,8 @@ pub async fn get_room_state_route(
         return Err(Error::bad_config("Federation is disabled."));
     }
 
+    let deadline = incoming_request_deadline();
+
     let sender_servername = body
         .sender_servername
         .as_ref()
@@ -1428,42 +1790,27 @@ pub async fn get_room_state_route(
             "Pdu state not found.",
         ))?;
 
-    let pdus = services()
-        .rooms
-        .state_accessor
-        .state_full_ids(shortstatehash)
-        .await?
-        .into_values()
-        .map(|id| {
-            PduEvent::convert_to_outgoing_federation_event(
-                services()
-                    .rooms
-                    .timeline
-                    .get_pdu_json(&id)
-                    .unwrap()
-                    .unwrap(),
-            )
-        })
-        .collect();
+    let state_ids = until_deadline(
+        deadline,
+        services()
+            .rooms
+            .state_accessor
+            .state_full_ids(shortstatehash),
+    )
+    .await?;
+    let pdus = outgoing_pdus_until_deadline(deadline, state_ids.into_values(), true).await?;
 
-    let auth_chain_ids = services()
-        .rooms
-        .auth_chain
-        .get_auth_chain(&body.room_id, vec![Arc::from(&*body.event_id)])
-        .await?;
+    let auth_chain_ids = until_deadline(
+        deadline,
+        services()
+            .rooms
+            .auth_chain
+            .get_auth_chain(&body.room_id, vec![Arc::from(&*body.event_id)]),
+    )
+    .await?;
 
     Ok(get_room_state::v1::Response {
-        auth_chain: auth_chain_ids
-            .filter_map(
-                |id| match services().rooms.timeline.get_pdu_json(&id).ok()? {
-                    Some(json) => Some(PduEvent::convert_to_outgoing_federation_event(json)),
-                    None => {
-                        error!("Could not find event json for {id} in db.");
-                        None
-                    }
-                },
-            )
-            .collect(),
+        auth_chain: outgoing_pdus_until_deadline(deadline, auth_chain_ids, false).await?,
         pdus,
     })
 }
@@ -1478,6 +1825,8 @@ pub async fn get_room_state_ids_route(
         return Err(Error::bad_config("Federation is disabled."));
     }
 
+    let deadline = incoming_request_deadline();
+
     let sender_servername = body
         .sender_servername
         .as_ref()
@@ -1508,20 +1857,26 @@ pub async fn get_room_state_ids_route(
             "Pdu state not found.",
         ))?;
 
-    let pdu_ids = services()
-        .rooms
-        .state_accessor
-        .state_full_ids(shortstatehash)
-        .await?
-        .into_values()
-        .map(|id| (*id).to_owned())
-        .collect();
+    let pdu_ids = until_deadline(
+        deadline,
+        services()
+            .rooms
+            .state_accessor
+            .state_full_ids(shortstatehash),
+    )
+    .await?
+    .into_values()
+    .map(|id| (*id).to_owned())
+    .collect();
 
-    let auth_chain_ids = services()
-        .rooms
-        .auth_chain
-        .get_auth_chain(&body.room_id, vec![Arc::from(&*body.event_id)])
-        .await?;
+    let auth_chain_ids = until_deadline(
+        deadline,
+        services()
+            .rooms
+            .auth_chain
+            .get_auth_chain(&body.room_id, vec![Arc::from(&*body.event_id)]),
+    )
+    .await?;
 
     Ok(get_room_state_ids::v1::Response {
         auth_chain_ids: auth_chain_ids.map(|id| (*id).to_owned()).collect(),
@@ -1556,15 +1911,7 @@ pub async fn create_join_event_template_route(
         .event_handler
         .acl_check(sender_servername, &body.room_id)?;
 
-    let mutex_state = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(body.room_id.to_owned())
-            .or_default(),
-    );
+    let mutex_state = services().globals.roomid_mutex_state.mutex(&body.room_id);
     let state_lock = mutex_state.lock().await;
 
     // TODO: Conduit does not implement restricted join rules yet, we always reject
@@ -1650,6 +1997,8 @@ async fn create_join_event(
         return Err(Error::bad_config("Federation is disabled."));
     }
 
+    let deadline = incoming_request_deadline();
+
     if !services().rooms.metadata.exists(room_id)? {
         return Err(Error::BadRequest(
             ErrorKind::NotFound,
@@ -1701,9 +2050,6 @@ async fn create_join_event(
             "Pdu state not found.",
         ))?;
 
-    let pub_key_map = RwLock::new(BTreeMap::new());
-    // let mut auth_cache = EventMap::new();
-
     // We do not add the event_id field to the pdu here because of signature and hashes checks
     let room_version_id = services().rooms.state.get_room_version(room_id)?;
     let (event_id, value) = match gen_event_id_canonical_json(pdu, &room_version_id) {
@@ -1726,26 +2072,66 @@ async fn create_join_event(
     )
     .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Origin field is invalid."))?;
 
+    // The join is handled in the background, so it is still accepted and sent to the other
+    // servers if the request runs out of time. The remote server can then retry the request.
+    let join = tokio::spawn(
+        handle_join_pdu(origin, event_id, room_id.to_owned(), value).in_current_span(),
+    );
+
+    tokio::time::timeout_at(deadline, join)
+        .await
+        .map_err(|_| Error::Timeout("The join is still being processed."))?
+        .map_err(|e| {
+            error!("Handling a join to {room_id} failed: {e}");
+            Error::BadServerResponse("Handling the join failed.")
+        })??;
+
+    let state_ids = until_deadline(
+        deadline,
+        services()
+            .rooms
+            .state_accessor
+            .state_full_ids(shortstatehash),
+    )
+    .await?;
+    let auth_chain_ids = until_deadline(
+        deadline,
+        services()
+            .rooms
+            .auth_chain
+            .get_auth_chain(room_id, state_ids.values().cloned().collect()),
+    )
+    .await?;
+
+    Ok(create_join_event::v1::RoomState {
+        auth_chain: outgoing_pdus_until_deadline(deadline, auth_chain_ids, false).await?,
+        state: outgoing_pdus_until_deadline(deadline, state_ids.into_values(), false).await?,
+        event: None, // TODO: handle restricted joins
+    })
+}
+
+/// Accepts a join event into the room and sends it to the other servers in the room.
+async fn handle_join_pdu(
+    origin: OwnedServerName,
+    event_id: OwnedEventId,
+    room_id: OwnedRoomId,
+    value: CanonicalJsonObject,
+) -> Result<()> {
+    let pub_key_map = RwLock::new(BTreeMap::new());
+    // let mut auth_cache = EventMap::new();
+
     services()
         .rooms
         .event_handler
         .fetch_required_signing_keys([&value], &pub_key_map)
         .await?;
 
-    let mutex = Arc::clone(
-        services()
-            .globals
-            .roomid_mutex_federation
-            .write()
-            .unwrap()
-            .entry(room_id.to_owned())
-            .or_default(),
-    );
+    let mutex = services().globals.roomid_mutex_federation.mutex(&room_id);
     let mutex_lock = mutex.lock().await;
     let pdu_id: Vec<u8> = services()
         .rooms
         .event_handler
-        .handle_incoming_pdu(&origin, &event_id, room_id, value, true, &pub_key_map)
+        .handle_incoming_pdu(&origin, &event_id, &room_id, value, true, &pub_key_map)
         .await?
         .ok_or(Error::BadRequest(
             ErrorKind::InvalidParam,
@@ -1753,38 +2139,14 @@ async fn create_join_event(
         ))?;
     drop(mutex_lock);
 
-    let state_ids = services()
-        .rooms
-        .state_accessor
-        .state_full_ids(shortstatehash)
-        .await?;
-    let auth_chain_ids = services()
-        .rooms
-        .auth_chain
-        .get_auth_chain(room_id, state_ids.values().cloned().collect())
-        .await?;
-
     let servers = services()
         .rooms
         .state_cache
-        .room_servers(room_id)
+        .room_servers(&room_id)
         .filter_map(|r| r.ok())
         .filter(|server| &**server != services().globals.server_name());
 
-    services().sending.send_pdu(servers, &pdu_id)?;
-
-    Ok(create_join_event::v1::RoomState {
-        auth_chain: auth_chain_ids
-            .filter_map(|id| services().rooms.timeline.get_pdu_json(&id).ok().flatten())
-            .map(PduEvent::convert_to_outgoing_federation_event)
-            .collect(),
-        state: state_ids
-            .iter()
-            .filter_map(|(_, id)| services().rooms.timeline.get_pdu_json(id).ok().flatten())
-            .map(PduEvent::convert_to_outgoing_federation_event)
-            .collect(),
-        event: None, // TODO: handle restricted joins
-    })
+    services().sending.send_pdu(servers, &pdu_id)
 }
 
 /// # `PUT /_matrix/federation/v1/send_join/{roomId}/{eventId}`
@@ -1850,6 +2212,17 @@ pub async fn create_invite_route(
         .event_handler
         .acl_check(sender_servername, &body.room_id)?;
 
+    if services().rooms.metadata.is_banned(&body.room_id)? {
+        info!(
+            "Received remote invite from server {} for room {} which is banned on this homeserver, rejecting.",
+            sender_servername, &body.room_id
+        );
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room is banned on this homeserver.",
+        ));
+    }
+
     if !services()
         .globals
         .supported_room_versions()
@@ -1914,6 +2287,17 @@ pub async fn create_invite_route(
     )
     .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "state_key is not a user id."))?;
 
+    if !services().users.is_invite_allowed(&sender, &invited_user)? {
+        info!(
+            "Received remote invite from {} to {} for room {} which is refused by the invite filtering of this homeserver or the user, rejecting.",
+            sender, invited_user, &body.room_id
+        );
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This user does not accept invites from you.",
+        ));
+    }
+
     let mut invite_state = body.invite_room_state.clone();
 
     let mut event: JsonObject = serde_json::from_str(body.event.get())
@@ -1953,6 +2337,243 @@ pub async fn create_invite_route(
     })
 }
 
+/// # `PUT /_matrix/federation/v1/exchange_third_party_invite/{roomId}`
+///
+/// Claims an invite of a third party identifier that was created by a user of this server for the
+/// remote user the identifier is now bound to.
+pub async fn exchange_third_party_invite_route(
+    body: Ruma<exchange_invite::v1::Request>,
+) -> Result<exchange_invite::v1::Response> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    let sender_servername = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    services()
+        .rooms
+        .event_handler
+        .acl_check(sender_servername, &body.room_id)?;
+
+    if body.kind != StateEventType::RoomMember {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Only membership events can be exchanged.",
+        ));
+    }
+
+    if body.state_key.server_name() != sender_servername {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Invited user does not belong to the requesting server.",
+        ));
+    }
+
+    claim_third_party_invite(
+        &body.room_id,
+        &body.sender,
+        &body.state_key,
+        body.content.clone(),
+    )
+    .await?;
+
+    Ok(exchange_invite::v1::Response::new())
+}
+
+/// # `PUT /_matrix/federation/v1/3pid/onbind`
+///
+/// Called by the identity server when a third party identifier was bound to a user of this server.
+///
+/// - Claims the pending invites of the identifier, either directly or through the server of the
+/// inviting user
+pub async fn third_party_bind_callback_route(
+    body: Ruma<bind_callback::v1::Request>,
+) -> Result<bind_callback::v1::Response> {
+    if body.mxid.server_name() != services().globals.server_name() {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "User does not belong to this server.",
+        ));
+    }
+
+    for invite in &body.invites {
+        if invite.mxid != body.mxid {
+            warn!(
+                "Identity server sent an invite for {} in the bind callback of {}",
+                invite.mxid, body.mxid
+            );
+            continue;
+        }
+
+        // The inviting server replaces the display name with the one of the invite event
+        let third_party_invite = ThirdPartyInvite {
+            display_name: invite.address.clone(),
+            signed: invite.signed.clone(),
+        };
+
+        let result = if invite.sender.server_name() == services().globals.server_name() {
+            claim_third_party_invite(
+                &invite.room_id,
+                &invite.sender,
+                &invite.mxid,
+                third_party_invite,
+            )
+            .await
+        } else {
+            services()
+                .sending
+                .send_federation_request(
+                    invite.sender.server_name(),
+                    exchange_invite::v1::Request {
+                        room_id: invite.room_id.clone(),
+                        kind: StateEventType::RoomMember,
+                        sender: invite.sender.clone(),
+                        state_key: invite.mxid.clone(),
+                        content: third_party_invite,
+                    },
+                )
+                .await
+                .map(|_| ())
+        };
+
+        if let Err(e) = result {
+            warn!(
+                "Failed to claim third party invite to {} for {}: {e}",
+                invite.room_id, invite.mxid
+            );
+        }
+    }
+
+    Ok(bind_callback::v1::Response::new())
+}
+
+/// Checks a claimed third party invite against the `m.room.third_party_invite` event it belongs
+/// to and invites the user the identifier is bound to.
+async fn claim_third_party_invite(
+    room_id: &RoomId,
+    sender: &UserId,
+    user_id: &UserId,
+    mut third_party_invite: ThirdPartyInvite,
+) -> Result<()> {
+    if sender.server_name() != services().globals.server_name() {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Inviting user does not belong to this server.",
+        ));
+    }
+
+    if *third_party_invite.signed.mxid != *user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Third party invite was signed for another user.",
+        ));
+    }
+
+    let invite_event = services()
+        .rooms
+        .state_accessor
+        .room_state_get(
+            room_id,
+            &StateEventType::RoomThirdPartyInvite,
+            &third_party_invite.signed.token,
+        )?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Unknown third party invite.",
+        ))?;
+
+    if *invite_event.sender != *sender {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Third party invite was created by another user.",
+        ));
+    }
+
+    let invite_content: RoomThirdPartyInviteEventContent =
+        serde_json::from_str(invite_event.content.get())
+            .map_err(|_| Error::bad_database("Invalid third party invite event in database."))?;
+
+    if !third_party_invite_signature_valid(&invite_content, &third_party_invite) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Third party invite is not signed by the identity server.",
+        ));
+    }
+
+    third_party_invite.display_name = invite_content.display_name;
+
+    client_server::invite_helper(
+        sender,
+        user_id,
+        room_id,
+        None,
+        false,
+        Some(third_party_invite),
+    )
+    .await
+}
+
+/// Returns true if the signed part of a third party invite carries a signature of one of the
+/// public keys in the `m.room.third_party_invite` event.
+fn third_party_invite_signature_valid(
+    invite_content: &RoomThirdPartyInviteEventContent,
+    third_party_invite: &ThirdPartyInvite,
+) -> bool {
+    let Ok(signed) = utils::to_canonical_object(&third_party_invite.signed) else {
+        return false;
+    };
+    let Some(CanonicalJsonValue::Object(signatures)) = signed.get("signatures") else {
+        return false;
+    };
+
+    let public_keys: Vec<_> = iter::once(&invite_content.public_key)
+        .chain(
+            invite_content
+                .public_keys
+                .iter()
+                .flatten()
+                .map(|key| &key.public_key),
+        )
+        .collect();
+
+    for (entity, entity_signatures) in signatures {
+        let CanonicalJsonValue::Object(entity_signatures) = entity_signatures else {
+            continue;
+        };
+
+        for (key_id, signature) in entity_signatures {
+            // Check every signature on its own, the keys of the other ones are unknown to us
+            let mut signed = signed.clone();
+            signed.insert(
+                "signatures".to_owned(),
+                CanonicalJsonValue::Object(BTreeMap::from([(
+                    entity.clone(),
+                    CanonicalJsonValue::Object(BTreeMap::from([(
+                        key_id.clone(),
+                        signature.clone(),
+                    )])),
+                )])),
+            );
+
+            for public_key in &public_keys {
+                let pub_key_map = BTreeMap::from([(
+                    entity.clone(),
+                    BTreeMap::from([(key_id.clone(), (*public_key).clone())]),
+                )]);
+
+                if ruma::signatures::verify_json(&pub_key_map, &signed).is_ok() {
+                    return true;
+                }
+            }
+        }
+    }
+
+    false
+}
+
 /// # `GET /_matrix/federation/v1/user/devices/{userId}`
 ///
 /// Gets information on all devices of the user.
@@ -2017,14 +2638,21 @@ pub async fn get_room_information_route(
         return Err(Error::bad_config("Federation is disabled."));
     }
 
-    let room_id = services()
+    let room_id = match services()
         .rooms
         .alias
         .resolve_local_alias(&body.room_alias)?
-        .ok_or(Error::BadRequest(
-            ErrorKind::NotFound,
-            "Room alias not found.",
-        ))?;
+    {
+        Some(room_id) => room_id,
+        None => services()
+            .appservice
+            .query_room_alias(&body.room_alias)
+            .await?
+            .ok_or(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Room alias not found.",
+            ))?,
+    };
 
     Ok(get_room_information::v1::Response {
         room_id,
@@ -2049,6 +2677,15 @@ pub async fn get_profile_information_route(
         ));
     }
 
+    if !services().users.exists(&body.user_id)?
+        && !services().appservice.query_user_id(&body.user_id).await?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Profile was not found.",
+        ));
+    }
+
     let mut displayname = None;
     let mut avatar_url = None;
     let mut blurhash = None;
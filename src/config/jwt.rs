@@ -0,0 +1,81 @@
+use std::path::PathBuf;
+
+use jsonwebtoken::Algorithm;
+use serde::Deserialize;
+
+/// JSON web tokens that can be used to log in with the `m.login.token` login type.
+///
+/// ## Examples:
+/// - Shared secret (HS256):
+/// ```toml
+/// [global.jwt]
+/// secret = "..."
+/// ```
+/// - Keys of an identity provider:
+/// ```toml
+/// [global.jwt]
+/// jwks_url = "https://idp.example.com/.well-known/jwks.json"
+/// algorithm = "ES256"
+/// issuer = "https://idp.example.com/"
+/// audience = ["matrix"]
+/// localpart_claim = "preferred_username"
+/// ```
+///
+/// The old `jwt_secret` option is the same as setting `secret` here.
+#[derive(Clone, Debug, Deserialize)]
+pub struct JwtConfig {
+    /// Shared secret of HMAC signed tokens
+    pub secret: Option<String>,
+    /// PEM file with the RSA, EC or Ed25519 public key tokens are signed with
+    pub public_key_file: Option<PathBuf>,
+    /// JSON web key set of the identity provider, keys are picked by the `kid` of the token
+    pub jwks_url: Option<String>,
+    /// Defaults to HS256 with a secret and RS256 otherwise
+    pub algorithm: Option<Algorithm>,
+    /// Required `iss` claim
+    pub issuer: Option<String>,
+    /// Accepted `aud` claims, not checked if empty
+    #[serde(default)]
+    pub audience: Vec<String>,
+    /// Claim that contains the localpart of the user
+    #[serde(default = "default_localpart_claim")]
+    pub localpart_claim: String,
+}
+
+impl JwtConfig {
+    pub fn from_secret(secret: String) -> Self {
+        Self {
+            secret: Some(secret),
+            public_key_file: None,
+            jwks_url: None,
+            algorithm: None,
+            issuer: None,
+            audience: Vec::new(),
+            localpart_claim: default_localpart_claim(),
+        }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm.unwrap_or(if self.secret.is_some() {
+            Algorithm::HS256
+        } else {
+            Algorithm::RS256
+        })
+    }
+
+    /// Returns how many of the key sources are configured, exactly one is required.
+    pub fn key_sources(&self) -> usize {
+        [
+            self.secret.is_some(),
+            self.public_key_file.is_some(),
+            self.jwks_url.is_some(),
+        ]
+        .into_iter()
+        .filter(|configured| *configured)
+        .count()
+    }
+}
+
+fn default_localpart_claim() -> String {
+    "sub".to_owned()
+}
@@ -27,6 +27,10 @@ pub(crate) trait KeyValueDatabaseEngine: Send + Sync {
     fn clear_caches(&self) {}
 }
 
+/// A tree of the key-value database. Operations are synchronous and run on the calling thread:
+/// point reads and writes are cheap enough for the async runtime workers, only handlers that scan
+/// large parts of the database, like search and the user directory, are moved to the blocking
+/// thread pool with `utils::spawn_blocking`.
 pub(crate) trait KvTree: Send + Sync {
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
 
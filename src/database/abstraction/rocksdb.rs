@@ -23,7 +23,42 @@ struct RocksDbEngineTree<'a> {
     write_lock: RwLock<()>,
 }
 
-fn db_options(rocksdb_cache: &rocksdb::Cache, config: &Config) -> rocksdb::Options {
+/// Memory budget for `optimize_level_style_compaction`, which also sets the defaults of the write
+/// buffer size (a quarter of it) and the size of the first level (all of it)
+const MEMTABLE_MEMORY_BUDGET: usize = 10 * 1024 * 1024;
+
+/// Default of `max_bytes_for_level_multiplier`
+const DEFAULT_LEVEL_MULTIPLIER: f64 = 10.0;
+
+fn compression_type(name: &str) -> Result<rocksdb::DBCompressionType> {
+    Ok(match name {
+        "zstd" => rocksdb::DBCompressionType::Zstd,
+        "lz4" => rocksdb::DBCompressionType::Lz4,
+        "snappy" => rocksdb::DBCompressionType::Snappy,
+        "none" => rocksdb::DBCompressionType::None,
+        _ => {
+            return Err(crate::Error::bad_config(
+                "rocksdb_compression must be \"zstd\", \"lz4\", \"snappy\" or \"none\".",
+            ))
+        }
+    })
+}
+
+fn compaction_style(name: &str) -> Result<rocksdb::DBCompactionStyle> {
+    Ok(match name {
+        "level" => rocksdb::DBCompactionStyle::Level,
+        "universal" => rocksdb::DBCompactionStyle::Universal,
+        // FIFO compaction deletes the oldest data once the database reaches a size limit, which
+        // would lose rooms and accounts
+        _ => {
+            return Err(crate::Error::bad_config(
+                "rocksdb_compaction_style must be \"level\" or \"universal\".",
+            ))
+        }
+    })
+}
+
+fn db_options(rocksdb_cache: &rocksdb::Cache, config: &Config) -> Result<rocksdb::Options> {
     // block-based options: https://docs.rs/rocksdb/latest/rocksdb/struct.BlockBasedOptions.html#
     let mut block_based_options = rocksdb::BlockBasedOptions::default();
 
@@ -34,6 +69,10 @@ fn db_options(rocksdb_cache: &rocksdb::Cache, config: &Config) -> rocksdb::Optio
     block_based_options.set_block_size(64 * 1024);
     block_based_options.set_cache_index_and_filter_blocks(true);
 
+    if let Some(bits) = config.rocksdb_bloom_filter_bits {
+        block_based_options.set_bloom_filter(bits, false);
+    }
+
     // database options: https://docs.rs/rocksdb/latest/rocksdb/struct.Options.html#
     let mut db_opts = rocksdb::Options::default();
 
@@ -73,7 +112,24 @@ fn db_options(rocksdb_cache: &rocksdb::Cache, config: &Config) -> rocksdb::Optio
     //db_opts.set_max_open_files(config.rocksdb_max_open_files);
     db_opts.set_compression_type(rocksdb::DBCompressionType::Zstd);
     db_opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
-    db_opts.optimize_level_style_compaction(10 * 1024 * 1024);
+    db_opts.optimize_level_style_compaction(MEMTABLE_MEMORY_BUDGET);
+
+    // These override what `optimize_level_style_compaction` sets
+    if let Some(compression) = &config.rocksdb_compression {
+        db_opts.set_compression_type(compression_type(compression)?);
+        // Without levels, the compression type is used for all of them
+        db_opts.set_compression_per_level(&[]);
+    }
+    db_opts.set_compaction_style(compaction_style(&config.rocksdb_compaction_style)?);
+    if let Some(size) = config.rocksdb_write_buffer_size_mb {
+        db_opts.set_write_buffer_size(size * 1024 * 1024);
+    }
+    if let Some(size) = config.rocksdb_max_bytes_for_level_base_mb {
+        db_opts.set_max_bytes_for_level_base(size * 1024 * 1024);
+    }
+    if let Some(multiplier) = config.rocksdb_max_bytes_for_level_multiplier {
+        db_opts.set_max_bytes_for_level_multiplier(multiplier);
+    }
 
     // https://github.com/facebook/rocksdb/wiki/Setup-Options-and-Basic-Tuning
     db_opts.set_max_background_jobs(6);
@@ -89,7 +145,40 @@ fn db_options(rocksdb_cache: &rocksdb::Cache, config: &Config) -> rocksdb::Optio
     let prefix_extractor = rocksdb::SliceTransform::create_fixed_prefix(1);
     db_opts.set_prefix_extractor(prefix_extractor);
 
-    db_opts
+    Ok(db_opts)
+}
+
+/// Logs the options that can be configured, with the values RocksDB uses for the unset ones.
+fn log_options(config: &Config, cache_capacity_bytes: usize) {
+    let mb = |size: usize| format!("{:.1}MB", size as f64 / 1024.0 / 1024.0);
+
+    let compression = config
+        .rocksdb_compression
+        .as_deref()
+        .unwrap_or("none for the first two levels, lz4 below");
+    let write_buffer_size = mb(config
+        .rocksdb_write_buffer_size_mb
+        .map_or(MEMTABLE_MEMORY_BUDGET / 4, |size| size * 1024 * 1024));
+    let bloom_filter_bits = config
+        .rocksdb_bloom_filter_bits
+        .map_or_else(|| "disabled".to_owned(), |bits| bits.to_string());
+    let max_bytes_for_level_base = config
+        .rocksdb_max_bytes_for_level_base_mb
+        .map_or_else(|| mb(MEMTABLE_MEMORY_BUDGET), |size| format!("{size}MB"));
+    let max_bytes_for_level_multiplier = config
+        .rocksdb_max_bytes_for_level_multiplier
+        .unwrap_or(DEFAULT_LEVEL_MULTIPLIER);
+
+    info!(
+        compression,
+        compaction_style = %config.rocksdb_compaction_style,
+        %write_buffer_size,
+        %bloom_filter_bits,
+        %max_bytes_for_level_base,
+        max_bytes_for_level_multiplier,
+        block_cache = %mb(cache_capacity_bytes),
+        "RocksDB options"
+    );
 }
 
 impl KeyValueDatabaseEngine for Arc<Engine> {
@@ -97,7 +186,8 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
         let cache_capacity_bytes = (config.db_cache_capacity_mb * 1024.0 * 1024.0) as usize;
         let rocksdb_cache = rocksdb::Cache::new_lru_cache(cache_capacity_bytes);
 
-        let db_opts = db_options(&rocksdb_cache, config);
+        let db_opts = db_options(&rocksdb_cache, config)?;
+        log_options(config, cache_capacity_bytes);
 
         debug!("Listing column families in database");
         let cfs = rocksdb::DBWithThreadMode::<rocksdb::MultiThreaded>::list_cf(
@@ -108,12 +198,19 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
 
         debug!("Opening column family descriptors in database");
         info!("RocksDB database compaction will take place now, a delay in startup is expected");
+        let cf_descriptors = cfs
+            .iter()
+            .map(|name| {
+                Ok(rocksdb::ColumnFamilyDescriptor::new(
+                    name,
+                    db_options(&rocksdb_cache, config)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
         let db = rocksdb::DBWithThreadMode::<rocksdb::MultiThreaded>::open_cf_descriptors(
             &db_opts,
             &config.database_path,
-            cfs.iter().map(|name| {
-                rocksdb::ColumnFamilyDescriptor::new(name, db_options(&rocksdb_cache, config))
-            }),
+            cf_descriptors,
         )?;
 
         Ok(Arc::new(Engine {
@@ -130,7 +227,7 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
             debug!("Creating new column family in database: {}", name);
             let _ = self
                 .rocks
-                .create_cf(name, &db_options(&self.cache, &self.config));
+                .create_cf(name, &db_options(&self.cache, &self.config)?);
         }
 
         Ok(Arc::new(RocksDbEngineTree {
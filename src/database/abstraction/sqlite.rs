@@ -1,5 +1,5 @@
 use super::{watchers::Watchers, KeyValueDatabaseEngine, KvTree};
-use crate::{database::Config, Result};
+use crate::{database::Config, Error, Result};
 use parking_lot::{Mutex, MutexGuard};
 use rusqlite::{Connection, DatabaseName::Main, OptionalExtension};
 use std::{
@@ -8,6 +8,7 @@ use std::{
     path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
+    time::Duration,
 };
 use thread_local::ThreadLocal;
 use tracing::debug;
@@ -39,24 +40,39 @@ impl<T> Drop for NonAliasingBox<T> {
     }
 }
 
+/// How long a connection waits for the lock of another one before failing with SQLITE_BUSY.
+/// Without write-ahead logging, readers can't read while the writer commits.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pragmas every connection is set up with
+struct ConnectionOptions {
+    journal_mode: &'static str,
+    synchronous: &'static str,
+    mmap_size: i64,
+    cache_size_kb: u32,
+    wal_autocheckpoint: u32,
+}
+
 pub struct Engine {
     writer: Mutex<Connection>,
     read_conn_tls: ThreadLocal<Connection>,
     read_iterator_conn_tls: ThreadLocal<Connection>,
 
     path: PathBuf,
-    cache_size_per_thread: u32,
+    options: ConnectionOptions,
 }
 
 impl Engine {
-    fn prepare_conn(path: &Path, cache_size_kb: u32) -> Result<Connection> {
+    fn prepare_conn(path: &Path, options: &ConnectionOptions) -> Result<Connection> {
         let conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
 
         conn.pragma_update(Some(Main), "page_size", 2048)?;
-        conn.pragma_update(Some(Main), "journal_mode", "WAL")?;
-        conn.pragma_update(Some(Main), "synchronous", "NORMAL")?;
-        conn.pragma_update(Some(Main), "cache_size", -i64::from(cache_size_kb))?;
-        conn.pragma_update(Some(Main), "wal_autocheckpoint", 0)?;
+        conn.pragma_update(Some(Main), "journal_mode", options.journal_mode)?;
+        conn.pragma_update(Some(Main), "synchronous", options.synchronous)?;
+        conn.pragma_update(Some(Main), "mmap_size", options.mmap_size)?;
+        conn.pragma_update(Some(Main), "cache_size", -i64::from(options.cache_size_kb))?;
+        conn.pragma_update(Some(Main), "wal_autocheckpoint", options.wal_autocheckpoint)?;
 
         Ok(conn)
     }
@@ -67,12 +83,12 @@ impl Engine {
 
     fn read_lock(&self) -> &Connection {
         self.read_conn_tls
-            .get_or(|| Self::prepare_conn(&self.path, self.cache_size_per_thread).unwrap())
+            .get_or(|| Self::prepare_conn(&self.path, &self.options).unwrap())
     }
 
     fn read_lock_iterator(&self) -> &Connection {
         self.read_iterator_conn_tls
-            .get_or(|| Self::prepare_conn(&self.path, self.cache_size_per_thread).unwrap())
+            .get_or(|| Self::prepare_conn(&self.path, &self.options).unwrap())
     }
 
     pub fn flush_wal(self: &Arc<Self>) -> Result<()> {
@@ -94,14 +110,39 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
             / ((num_cpus::get().max(1) * 2) + 1) as f64)
             as u32;
 
-        let writer = Mutex::new(Engine::prepare_conn(&path, cache_size_per_thread)?);
+        let synchronous = match config.sqlite_synchronous.to_lowercase().as_str() {
+            "off" => "OFF",
+            "normal" => "NORMAL",
+            "full" => "FULL",
+            "extra" => "EXTRA",
+            _ => {
+                return Err(Error::bad_config(
+                    "sqlite_synchronous must be \"off\", \"normal\", \"full\" or \"extra\".",
+                ))
+            }
+        };
+
+        let options = ConnectionOptions {
+            journal_mode: if config.sqlite_wal_mode {
+                "WAL"
+            } else {
+                "DELETE"
+            },
+            synchronous,
+            mmap_size: i64::try_from(config.sqlite_mmap_size_mb.saturating_mul(1024 * 1024))
+                .unwrap_or(i64::MAX),
+            cache_size_kb: config.sqlite_cache_size_kb.unwrap_or(cache_size_per_thread),
+            wal_autocheckpoint: config.sqlite_wal_autocheckpoint,
+        };
+
+        let writer = Mutex::new(Engine::prepare_conn(&path, &options)?);
 
         let arc = Arc::new(Engine {
             writer,
             read_conn_tls: ThreadLocal::new(),
             read_iterator_conn_tls: ThreadLocal::new(),
             path,
-            cache_size_per_thread,
+            options,
         });
 
         Ok(arc)
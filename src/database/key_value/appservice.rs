@@ -77,4 +77,39 @@ impl service::appservice::Data for KeyValueDatabase {
             })
             .collect()
     }
+
+    fn last_txn_id(&self, id: &str) -> Result<u64> {
+        self.appserviceid_lasttxnid
+            .get(id.as_bytes())?
+            .map_or(Ok(0), |bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid transaction id in appserviceid_lasttxnid.")
+                })
+            })
+    }
+
+    fn set_last_txn_id(&self, id: &str, txn_id: u64) -> Result<()> {
+        self.appserviceid_lasttxnid
+            .insert(id.as_bytes(), &txn_id.to_be_bytes())
+    }
+
+    fn device_list_count(&self, id: &str) -> Result<Option<u64>> {
+        self.appserviceid_devicelistcount
+            .get(id.as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid count in appserviceid_devicelistcount.")
+                })
+            })
+            .transpose()
+    }
+
+    fn set_device_list_count(&self, id: &str, count: u64) -> Result<()> {
+        self.appserviceid_devicelistcount
+            .insert(id.as_bytes(), &count.to_be_bytes())
+    }
+
+    fn remove_device_list_count(&self, id: &str) -> Result<()> {
+        self.appserviceid_devicelistcount.remove(id.as_bytes())
+    }
 }
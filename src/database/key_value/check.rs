@@ -0,0 +1,388 @@
+use std::mem::size_of;
+
+use ruma::{OwnedEventId, OwnedRoomAliasId};
+use serde::Deserialize;
+
+use crate::{
+    database::{abstraction::KvTree, KeyValueDatabase},
+    service::globals::DatabaseCheck,
+    services, utils, Result,
+};
+
+#[derive(Deserialize)]
+struct ExtractEventId {
+    event_id: OwnedEventId,
+}
+
+impl KeyValueDatabase {
+    /// Checks that every timeline PDU can be found by its event ID and has state, and that every
+    /// event ID points to a PDU.
+    pub(super) fn check_timeline(&self, repair: bool, check: &mut DatabaseCheck) -> Result<()> {
+        for (pdu_id, pdu) in self.pduid_pdu.iter() {
+            let Ok(ExtractEventId { event_id }) = serde_json::from_slice(&pdu) else {
+                check.found(format!("Timeline PDU {pdu_id:?} has no valid event ID"));
+                continue;
+            };
+
+            match self.eventid_pduid.get(event_id.as_bytes())? {
+                Some(id) if id == pdu_id => {}
+                Some(_) => check.found(format!(
+                    "Timeline PDU {event_id} is stored at another position too"
+                )),
+                None => {
+                    check.found(format!(
+                        "Timeline PDU {event_id} has no eventid_pduid entry"
+                    ));
+                    if repair {
+                        self.eventid_pduid.insert(event_id.as_bytes(), &pdu_id)?;
+                        check.repaired += 1;
+                    }
+                }
+            }
+
+            // Backfilled PDUs have a longer ID and are stored without state
+            if pdu_id.len() == size_of::<u64>() * 2 {
+                let shortstatehash = match self.eventid_shorteventid.get(event_id.as_bytes())? {
+                    Some(shorteventid) => self.shorteventid_shortstatehash.get(&shorteventid)?,
+                    None => None,
+                };
+                if shortstatehash.is_none() {
+                    check.found(format!("Timeline PDU {event_id} has no state"));
+                }
+            }
+        }
+
+        let mut dangling = Vec::new();
+        for (event_id, pdu_id) in self.eventid_pduid.iter() {
+            if self.pduid_pdu.get(&pdu_id)?.is_none() {
+                check.found(format!(
+                    "eventid_pduid entry of {} points to a missing PDU",
+                    String::from_utf8_lossy(&event_id)
+                ));
+                dangling.push(event_id);
+            }
+        }
+        if repair {
+            for event_id in dangling {
+                self.eventid_pduid.remove(&event_id)?;
+                check.repaired += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// See `check_short_event_ids`, which works on the trees alone so it can be tested.
+    pub(super) fn check_short_event_ids(
+        &self,
+        repair: bool,
+        check: &mut DatabaseCheck,
+    ) -> Result<()> {
+        check_short_event_ids(
+            &*self.shorteventid_eventid,
+            &*self.eventid_shorteventid,
+            &*self.shorteventid_shortstatehash,
+            repair,
+            check,
+        )
+    }
+
+    /// Checks that local aliases and the alias lists of rooms agree.
+    pub(super) fn check_aliases(&self, repair: bool, check: &mut DatabaseCheck) -> Result<()> {
+        for (alias_localpart, room_id) in self.alias_roomid.iter() {
+            let mut prefix = room_id.clone();
+            prefix.push(0xff);
+
+            let listed = self
+                .aliasid_alias
+                .scan_prefix(prefix.clone())
+                .any(|(_, alias)| {
+                    utils::string_from_bytes(&alias)
+                        .ok()
+                        .and_then(|alias| OwnedRoomAliasId::try_from(alias).ok())
+                        .map_or(false, |alias| alias.alias().as_bytes() == alias_localpart)
+                });
+            if listed {
+                continue;
+            }
+
+            let alias = format!(
+                "#{}:{}",
+                String::from_utf8_lossy(&alias_localpart),
+                services().globals.server_name()
+            );
+            check.found(format!(
+                "{alias} is missing from the aliases of {}",
+                String::from_utf8_lossy(&room_id)
+            ));
+            if repair {
+                let mut aliasid = prefix;
+                aliasid.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
+                self.aliasid_alias.insert(&aliasid, alias.as_bytes())?;
+                check.repaired += 1;
+            }
+        }
+
+        let mut dangling = Vec::new();
+        for (aliasid, alias) in self.aliasid_alias.iter() {
+            // The key is the room ID, 0xff and a count
+            let room_id = &aliasid[..aliasid.len().saturating_sub(size_of::<u64>() + 1)];
+
+            let alias = utils::string_from_bytes(&alias)
+                .ok()
+                .and_then(|alias| OwnedRoomAliasId::try_from(alias).ok());
+            let resolves = match &alias {
+                Some(alias) => {
+                    self.alias_roomid.get(alias.alias().as_bytes())?.as_deref() == Some(room_id)
+                }
+                None => false,
+            };
+
+            if !resolves {
+                check.found(format!(
+                    "Alias {} of {} doesn't resolve to the room",
+                    alias.map_or_else(|| "(invalid)".to_owned(), |alias| alias.to_string()),
+                    String::from_utf8_lossy(room_id)
+                ));
+                dangling.push(aliasid);
+            }
+        }
+        if repair {
+            for aliasid in dangling {
+                self.aliasid_alias.remove(&aliasid)?;
+                check.repaired += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that short event IDs and event IDs map to each other, and that the state of events
+/// belongs to known short event IDs.
+fn check_short_event_ids(
+    shorteventid_eventid: &dyn KvTree,
+    eventid_shorteventid: &dyn KvTree,
+    shorteventid_shortstatehash: &dyn KvTree,
+    repair: bool,
+    check: &mut DatabaseCheck,
+) -> Result<()> {
+    for (shorteventid, event_id) in shorteventid_eventid.iter() {
+        let event = String::from_utf8_lossy(&event_id);
+        match eventid_shorteventid.get(&event_id)? {
+            Some(id) if id == shorteventid => {}
+            Some(_) => check.found(format!("{event} has more than one short event ID")),
+            None => {
+                check.found(format!("{event} has no eventid_shorteventid entry"));
+                if repair {
+                    eventid_shorteventid.insert(&event_id, &shorteventid)?;
+                    check.repaired += 1;
+                }
+            }
+        }
+    }
+
+    for (event_id, shorteventid) in eventid_shorteventid.iter() {
+        if shorteventid_eventid.get(&shorteventid)?.is_none() {
+            check.found(format!(
+                "Short event ID of {} doesn't resolve to an event ID",
+                String::from_utf8_lossy(&event_id)
+            ));
+            if repair {
+                shorteventid_eventid.insert(&shorteventid, &event_id)?;
+                check.repaired += 1;
+            }
+        }
+    }
+
+    let mut dangling = Vec::new();
+    for (shorteventid, _) in shorteventid_shortstatehash.iter() {
+        if shorteventid_eventid.get(&shorteventid)?.is_none() {
+            check.found(format!(
+                "State is stored for the unknown short event ID {:?}",
+                utils::u64_from_bytes(&shorteventid).ok()
+            ));
+            dangling.push(shorteventid);
+        }
+    }
+    if repair {
+        for shorteventid in dangling {
+            shorteventid_shortstatehash.remove(&shorteventid)?;
+            check.repaired += 1;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, future::Future, pin::Pin, sync::Mutex};
+
+    use super::*;
+
+    /// A tree that only lives in memory, to set up broken databases
+    #[derive(Default)]
+    struct MemoryTree(Mutex<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+    impl MemoryTree {
+        fn with(entries: &[(&[u8], &[u8])]) -> Self {
+            Self(Mutex::new(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.to_vec(), value.to_vec()))
+                    .collect(),
+            ))
+        }
+
+        fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+            self.iter().collect()
+        }
+    }
+
+    impl KvTree for MemoryTree {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.lock().unwrap().get(key).cloned())
+        }
+
+        fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+            self.0.lock().unwrap().insert(key.to_vec(), value.to_vec());
+            Ok(())
+        }
+
+        fn insert_batch(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+            self.0.lock().unwrap().extend(iter);
+            Ok(())
+        }
+
+        fn remove(&self, key: &[u8]) -> Result<()> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+            let entries: Vec<_> = self
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            Box::new(entries.into_iter())
+        }
+
+        fn iter_from<'a>(
+            &'a self,
+            from: &[u8],
+            backwards: bool,
+        ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+            let entries: Vec<_> = if backwards {
+                self.entries()
+                    .into_iter()
+                    .rev()
+                    .filter(|(key, _)| key.as_slice() <= from)
+                    .collect()
+            } else {
+                self.entries()
+                    .into_iter()
+                    .filter(|(key, _)| key.as_slice() >= from)
+                    .collect()
+            };
+            Box::new(entries.into_iter())
+        }
+
+        fn increment(&self, key: &[u8]) -> Result<Vec<u8>> {
+            let mut tree = self.0.lock().unwrap();
+            let new = utils::increment(tree.get(key).map(Vec::as_slice))
+                .expect("utils::increment always returns Some");
+            tree.insert(key.to_vec(), new.clone());
+            Ok(new)
+        }
+
+        fn increment_batch(&self, iter: &mut dyn Iterator<Item = Vec<u8>>) -> Result<()> {
+            for key in iter {
+                self.increment(&key)?;
+            }
+            Ok(())
+        }
+
+        fn scan_prefix<'a>(
+            &'a self,
+            prefix: Vec<u8>,
+        ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+            Box::new(
+                self.iter_from(&prefix, false)
+                    .take_while(move |(key, _)| key.starts_with(&prefix)),
+            )
+        }
+
+        fn watch_prefix<'a>(
+            &'a self,
+            _prefix: &[u8],
+        ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    #[test]
+    fn finds_and_repairs_broken_short_event_ids() {
+        let short = |n: u64| n.to_be_bytes();
+
+        // $a is fine, $b lost its reverse mapping, short event ID 3 lost its event ID and state
+        // is stored for the unknown short event ID 4
+        let shorteventid_eventid = MemoryTree::with(&[(&short(1), b"$a"), (&short(2), b"$b")]);
+        let eventid_shorteventid = MemoryTree::with(&[(b"$a", &short(1)), (b"$c", &short(3))]);
+        let shorteventid_shortstatehash =
+            MemoryTree::with(&[(&short(1), &short(10)), (&short(4), &short(10))]);
+
+        let mut check = DatabaseCheck::default();
+        check_short_event_ids(
+            &shorteventid_eventid,
+            &eventid_shorteventid,
+            &shorteventid_shortstatehash,
+            false,
+            &mut check,
+        )
+        .unwrap();
+        assert_eq!(check.found, 3);
+        assert_eq!(check.problems.len(), 3);
+        assert_eq!(check.repaired, 0);
+
+        let mut check = DatabaseCheck::default();
+        check_short_event_ids(
+            &shorteventid_eventid,
+            &eventid_shorteventid,
+            &shorteventid_shortstatehash,
+            true,
+            &mut check,
+        )
+        .unwrap();
+        assert_eq!((check.found, check.repaired), (3, 3));
+
+        let mut check = DatabaseCheck::default();
+        check_short_event_ids(
+            &shorteventid_eventid,
+            &eventid_shorteventid,
+            &shorteventid_shortstatehash,
+            false,
+            &mut check,
+        )
+        .unwrap();
+        assert_eq!(check.found, 0);
+        assert_eq!(
+            shorteventid_shortstatehash.entries(),
+            vec![(short(1).to_vec(), short(10).to_vec())]
+        );
+    }
+
+    #[test]
+    fn caps_reported_problems() {
+        let mut check = DatabaseCheck::default();
+        for n in 0..DatabaseCheck::MAX_PROBLEMS + 10 {
+            check.found(format!("Problem {n}"));
+        }
+
+        assert_eq!(check.found, DatabaseCheck::MAX_PROBLEMS + 10);
+        assert_eq!(check.problems.len(), DatabaseCheck::MAX_PROBLEMS);
+    }
+}
@@ -1,15 +1,27 @@
-use std::collections::{BTreeMap, HashMap};
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    sync::Arc,
+};
 
 use async_trait::async_trait;
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use lru_cache::LruCache;
 use ruma::{
     api::federation::discovery::{ServerSigningKeys, VerifyKey},
+    events::StateEventType,
+    serde::{Base64, Raw},
     signatures::Ed25519KeyPair,
-    DeviceId, MilliSecondsSinceUnixEpoch, OwnedServerSigningKeyId, ServerName, UserId,
+    DeviceId, EventId, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedServerSigningKeyId,
+    ServerName, UserId,
 };
+use serde::{Deserialize, Serialize};
 
-use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
+use crate::{
+    database::KeyValueDatabase,
+    service::{self, globals::DatabaseCheck, rooms::timeline::PduCount},
+    services, utils, Error, PduEvent, Result,
+};
 
 const COUNTER: &[u8] = b"c";
 const LAST_CHECK_FOR_UPDATES_COUNT: &[u8] = b"u";
@@ -69,12 +81,20 @@ impl service::globals::Data for KeyValueDatabase {
         );
         futures.push(self.userroomid_highlightcount.watch_prefix(&userid_prefix));
 
-        // Events for rooms we are in
+        // Events for rooms we are in or peek into
         for room_id in services()
             .rooms
             .state_cache
             .rooms_joined(user_id)
             .filter_map(|r| r.ok())
+            .chain(
+                services()
+                    .rooms
+                    .peeking
+                    .peeked_rooms(user_id, device_id)
+                    .filter_map(|r| r.ok())
+                    .map(|(room_id, _)| room_id),
+            )
         {
             let short_roomid = services()
                 .rooms
@@ -200,6 +220,152 @@ lasttimelinecount_cache: {lasttimelinecount_cache}\n"
         }
     }
 
+    fn cache_usage(&self) -> Vec<(&'static str, usize, usize)> {
+        let pdu_cache = {
+            let c = self.pdu_cache.lock().unwrap();
+            let bytes = c
+                .iter()
+                .map(|(event_id, pdu)| {
+                    event_id.as_bytes().len()
+                        + mem::size_of::<PduEvent>()
+                        + pdu.content.get().len()
+                        + pdu.unsigned.as_ref().map_or(0, |u| u.get().len())
+                        + pdu.signatures.as_ref().map_or(0, |s| s.get().len())
+                        + pdu.state_key.as_ref().map_or(0, String::len)
+                        + (pdu.prev_events.len() + pdu.auth_events.len())
+                            * (mem::size_of::<Arc<EventId>>() + event_id.as_bytes().len())
+                })
+                .sum();
+            (c.len(), bytes)
+        };
+        let shorteventid_cache = {
+            let c = self.shorteventid_cache.lock().unwrap();
+            let bytes = c
+                .iter()
+                .map(|(_, event_id)| mem::size_of::<u64>() + event_id.as_bytes().len())
+                .sum();
+            (c.len(), bytes)
+        };
+        let auth_chain_cache = {
+            let c = self.auth_chain_cache.lock().unwrap();
+            let bytes = c
+                .iter()
+                .map(|(key, chain)| (key.len() + chain.len()) * mem::size_of::<u64>())
+                .sum();
+            (c.len(), bytes)
+        };
+        let eventidshort_cache = {
+            let c = self.eventidshort_cache.lock().unwrap();
+            let bytes = c
+                .iter()
+                .map(|(event_id, _)| event_id.as_bytes().len() + mem::size_of::<u64>())
+                .sum();
+            (c.len(), bytes)
+        };
+        let statekey_bytes = |(event_type, state_key): &(StateEventType, String)| {
+            event_type.to_string().len() + state_key.len() + mem::size_of::<u64>()
+        };
+        let statekeyshort_cache = {
+            let c = self.statekeyshort_cache.lock().unwrap();
+            let bytes = c.iter().map(|(key, _)| statekey_bytes(key)).sum();
+            (c.len(), bytes)
+        };
+        let shortstatekey_cache = {
+            let c = self.shortstatekey_cache.lock().unwrap();
+            let bytes = c.iter().map(|(_, key)| statekey_bytes(key)).sum();
+            (c.len(), bytes)
+        };
+        let our_real_users_cache = {
+            let c = self.our_real_users_cache.read().unwrap();
+            let bytes = c
+                .iter()
+                .map(|(room_id, users)| {
+                    room_id.as_bytes().len()
+                        + users
+                            .iter()
+                            .map(|user_id| user_id.as_bytes().len())
+                            .sum::<usize>()
+                })
+                .sum();
+            (c.len(), bytes)
+        };
+        let appservice_in_room_cache = {
+            let c = self.appservice_in_room_cache.read().unwrap();
+            let bytes = c
+                .iter()
+                .map(|(room_id, appservices)| {
+                    room_id.as_bytes().len()
+                        + appservices.keys().map(|id| id.len() + 1).sum::<usize>()
+                })
+                .sum();
+            (c.len(), bytes)
+        };
+        let lasttimelinecount_cache = {
+            let c = self.lasttimelinecount_cache.lock().unwrap();
+            let bytes = c
+                .keys()
+                .map(|room_id| room_id.as_bytes().len() + mem::size_of::<PduCount>())
+                .sum();
+            (c.len(), bytes)
+        };
+
+        [
+            ("pdu_cache", pdu_cache),
+            ("shorteventid_cache", shorteventid_cache),
+            ("auth_chain_cache", auth_chain_cache),
+            ("eventidshort_cache", eventidshort_cache),
+            ("statekeyshort_cache", statekeyshort_cache),
+            ("shortstatekey_cache", shortstatekey_cache),
+            ("our_real_users_cache", our_real_users_cache),
+            ("appservice_in_room_cache", appservice_in_room_cache),
+            ("lasttimelinecount_cache", lasttimelinecount_cache),
+        ]
+        .into_iter()
+        .map(|(name, (entries, bytes))| (name, entries, bytes))
+        .collect()
+    }
+
+    fn clear_cache(&self, name: &str) -> bool {
+        match name {
+            "pdu_cache" => {
+                let c = &mut *self.pdu_cache.lock().unwrap();
+                *c = LruCache::new(c.capacity());
+            }
+            "shorteventid_cache" => {
+                let c = &mut *self.shorteventid_cache.lock().unwrap();
+                *c = LruCache::new(c.capacity());
+            }
+            "auth_chain_cache" => {
+                let c = &mut *self.auth_chain_cache.lock().unwrap();
+                *c = LruCache::new(c.capacity());
+            }
+            "eventidshort_cache" => {
+                let c = &mut *self.eventidshort_cache.lock().unwrap();
+                *c = LruCache::new(c.capacity());
+            }
+            "statekeyshort_cache" => {
+                let c = &mut *self.statekeyshort_cache.lock().unwrap();
+                *c = LruCache::new(c.capacity());
+            }
+            "shortstatekey_cache" => {
+                let c = &mut *self.shortstatekey_cache.lock().unwrap();
+                *c = LruCache::new(c.capacity());
+            }
+            "our_real_users_cache" => {
+                *self.our_real_users_cache.write().unwrap() = HashMap::new();
+            }
+            "appservice_in_room_cache" => {
+                *self.appservice_in_room_cache.write().unwrap() = HashMap::new();
+            }
+            "lasttimelinecount_cache" => {
+                *self.lasttimelinecount_cache.lock().unwrap() = HashMap::new();
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
     fn load_keypair(&self) -> Result<Ed25519KeyPair> {
         let keypair_bytes = self.global.get(b"keypair")?.map_or_else(
             || {
@@ -241,37 +407,18 @@ lasttimelinecount_cache: {lasttimelinecount_cache}\n"
         new_keys: ServerSigningKeys,
     ) -> Result<BTreeMap<OwnedServerSigningKeyId, VerifyKey>> {
         // Not atomic, but this is not critical
-        let signingkeys = self.server_signingkeys.get(origin.as_bytes())?;
-
-        let mut keys = signingkeys
-            .and_then(|keys| serde_json::from_slice(&keys).ok())
-            .unwrap_or_else(|| {
-                // Just insert "now", it doesn't matter
-                ServerSigningKeys::new(origin.to_owned(), MilliSecondsSinceUnixEpoch::now())
-            });
-
-        let ServerSigningKeys {
-            verify_keys,
-            old_verify_keys,
-            ..
-        } = new_keys;
-
-        keys.verify_keys.extend(verify_keys);
-        keys.old_verify_keys.extend(old_verify_keys);
+        let mut keys = self.cached_signing_keys(origin)?;
+        merge_signing_keys(&mut keys, new_keys);
 
         self.server_signingkeys.insert(
             origin.as_bytes(),
-            &serde_json::to_vec(&keys).expect("serversigningkeys can be serialized"),
+            &serde_json::to_vec(&keys).expect("cached signing keys can be serialized"),
         )?;
 
-        let mut tree = keys.verify_keys;
-        tree.extend(
-            keys.old_verify_keys
-                .into_iter()
-                .map(|old| (old.0, VerifyKey::new(old.1.key))),
-        );
-
-        Ok(tree)
+        Ok(keys
+            .into_iter()
+            .map(|(key_id, cached)| (key_id, VerifyKey::new(cached.key)))
+            .collect())
     }
 
     /// This returns an empty `Ok(BTreeMap<..>)` when there are no keys found for the server.
@@ -279,22 +426,68 @@ lasttimelinecount_cache: {lasttimelinecount_cache}\n"
         &self,
         origin: &ServerName,
     ) -> Result<BTreeMap<OwnedServerSigningKeyId, VerifyKey>> {
-        let signingkeys = self
-            .server_signingkeys
+        Ok(self
+            .cached_signing_keys(origin)?
+            .into_iter()
+            .map(|(key_id, cached)| (key_id, VerifyKey::new(cached.key)))
+            .collect())
+    }
+
+    fn signing_keys_valid_until(
+        &self,
+        origin: &ServerName,
+    ) -> Result<BTreeMap<OwnedServerSigningKeyId, MilliSecondsSinceUnixEpoch>> {
+        Ok(self
+            .cached_signing_keys(origin)?
+            .into_iter()
+            .map(|(key_id, cached)| (key_id, cached.valid_until_ts))
+            .collect())
+    }
+
+    fn add_signed_server_keys(
+        &self,
+        origin: &ServerName,
+        keys: &Raw<ServerSigningKeys>,
+    ) -> Result<()> {
+        self.server_signedkeys
+            .insert(origin.as_bytes(), keys.json().get().as_bytes())
+    }
+
+    fn signed_server_keys(&self, origin: &ServerName) -> Result<Option<Raw<ServerSigningKeys>>> {
+        self.server_signedkeys
             .get(origin.as_bytes())?
-            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
-            .map(|keys: ServerSigningKeys| {
-                let mut tree = keys.verify_keys;
-                tree.extend(
-                    keys.old_verify_keys
-                        .into_iter()
-                        .map(|old| (old.0, VerifyKey::new(old.1.key))),
-                );
-                tree
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid signed server keys in db."))
             })
-            .unwrap_or_else(BTreeMap::new);
+            .transpose()
+    }
+
+    fn is_server_blocked(&self, server_name: &ServerName) -> Result<bool> {
+        Ok(self
+            .blockedservernames
+            .get(server_name.as_bytes())?
+            .is_some())
+    }
+
+    fn block_server(&self, server_name: &ServerName, blocked: bool) -> Result<()> {
+        if blocked {
+            self.blockedservernames
+                .insert(server_name.as_bytes(), &[])?;
+        } else {
+            self.blockedservernames.remove(server_name.as_bytes())?;
+        }
 
-        Ok(signingkeys)
+        Ok(())
+    }
+
+    fn blocked_servers<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedServerName>> + 'a> {
+        Box::new(self.blockedservernames.iter().map(|(bytes, _)| {
+            utils::string_from_bytes(&bytes)
+                .ok()
+                .and_then(|server_name| OwnedServerName::try_from(server_name).ok())
+                .ok_or_else(|| Error::bad_database("Invalid server name in blockedservernames."))
+        }))
     }
 
     fn database_version(&self) -> Result<u64> {
@@ -308,4 +501,150 @@ lasttimelinecount_cache: {lasttimelinecount_cache}\n"
         self.global.insert(b"version", &new_version.to_be_bytes())?;
         Ok(())
     }
+
+    fn check_database(&self, repair: bool) -> Result<DatabaseCheck> {
+        let mut check = DatabaseCheck::default();
+        self.check_timeline(repair, &mut check)?;
+        self.check_short_event_ids(repair, &mut check)?;
+        self.check_aliases(repair, &mut check)?;
+        Ok(check)
+    }
+}
+
+/// A cached signing key of a remote server with the time until which it may be used to verify
+/// signatures: the `valid_until_ts` of the key responses listing it as current, or its
+/// `expired_ts` once the server lists it as old.
+#[derive(Debug, Deserialize, Serialize)]
+struct CachedVerifyKey {
+    key: Base64,
+    valid_until_ts: MilliSecondsSinceUnixEpoch,
+}
+
+impl KeyValueDatabase {
+    fn cached_signing_keys(
+        &self,
+        origin: &ServerName,
+    ) -> Result<BTreeMap<OwnedServerSigningKeyId, CachedVerifyKey>> {
+        let Some(bytes) = self.server_signingkeys.get(origin.as_bytes())? else {
+            return Ok(BTreeMap::new());
+        };
+
+        if let Ok(keys) = serde_json::from_slice(&bytes) {
+            return Ok(keys);
+        }
+
+        // Older versions stored the merged key responses with a single `valid_until_ts`
+        let mut keys = BTreeMap::new();
+        if let Ok(legacy) = serde_json::from_slice::<ServerSigningKeys>(&bytes) {
+            merge_signing_keys(&mut keys, legacy);
+        }
+
+        Ok(keys)
+    }
+}
+
+/// Adds the keys of a key response to the cached keys of the server.
+fn merge_signing_keys(
+    keys: &mut BTreeMap<OwnedServerSigningKeyId, CachedVerifyKey>,
+    new_keys: ServerSigningKeys,
+) {
+    let ServerSigningKeys {
+        verify_keys,
+        old_verify_keys,
+        valid_until_ts,
+        ..
+    } = new_keys;
+
+    for (key_id, verify_key) in verify_keys {
+        keys.entry(key_id)
+            .and_modify(|cached| {
+                cached.key = verify_key.key.clone();
+                cached.valid_until_ts = cached.valid_until_ts.max(valid_until_ts);
+            })
+            .or_insert(CachedVerifyKey {
+                key: verify_key.key,
+                valid_until_ts,
+            });
+    }
+
+    // The server says when an old key stopped being valid, even if it was listed as current
+    // for longer before
+    for (key_id, old_key) in old_verify_keys {
+        keys.insert(
+            key_id,
+            CachedVerifyKey {
+                key: old_key.key,
+                valid_until_ts: old_key.expired_ts,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{api::federation::discovery::OldVerifyKey, server_name};
+
+    use super::*;
+
+    fn ts(millis: u32) -> MilliSecondsSinceUnixEpoch {
+        MilliSecondsSinceUnixEpoch(millis.into())
+    }
+
+    fn response(valid_until: u32, current: &[&str], old: &[(&str, u32)]) -> ServerSigningKeys {
+        let mut keys =
+            ServerSigningKeys::new(server_name!("example.com").to_owned(), ts(valid_until));
+        for key_id in current {
+            keys.verify_keys.insert(
+                (*key_id).try_into().unwrap(),
+                VerifyKey::new(Base64::new(key_id.as_bytes().to_vec())),
+            );
+        }
+        for (key_id, expired) in old {
+            keys.old_verify_keys.insert(
+                (*key_id).try_into().unwrap(),
+                OldVerifyKey::new(ts(*expired), Base64::new(key_id.as_bytes().to_vec())),
+            );
+        }
+        keys
+    }
+
+    fn valid_until(
+        keys: &BTreeMap<OwnedServerSigningKeyId, CachedVerifyKey>,
+    ) -> BTreeMap<String, MilliSecondsSinceUnixEpoch> {
+        keys.iter()
+            .map(|(key_id, cached)| (key_id.to_string(), cached.valid_until_ts))
+            .collect()
+    }
+
+    #[test]
+    fn rotated_key_keeps_its_own_validity() {
+        let mut keys = BTreeMap::new();
+        merge_signing_keys(&mut keys, response(100, &["ed25519:a"], &[]));
+
+        // The server rotates to a new key and lists the old one as expired
+        merge_signing_keys(
+            &mut keys,
+            response(300, &["ed25519:b"], &[("ed25519:a", 150)]),
+        );
+
+        assert_eq!(
+            valid_until(&keys),
+            BTreeMap::from([
+                ("ed25519:a".to_owned(), ts(150)),
+                ("ed25519:b".to_owned(), ts(300)),
+            ])
+        );
+    }
+
+    #[test]
+    fn current_key_validity_only_grows() {
+        let mut keys = BTreeMap::new();
+        merge_signing_keys(&mut keys, response(300, &["ed25519:a"], &[]));
+        merge_signing_keys(&mut keys, response(200, &["ed25519:a"], &[]));
+
+        assert_eq!(
+            valid_until(&keys),
+            BTreeMap::from([("ed25519:a".to_owned(), ts(300))])
+        );
+    }
 }
@@ -1,8 +1,11 @@
-use ruma::api::client::error::ErrorKind;
+use ruma::{api::client::error::ErrorKind, UserId};
 
 use crate::{
     database::KeyValueDatabase,
-    service::{self, media::UrlPreviewData},
+    service::{
+        self,
+        media::{MediaEntry, UrlPreviewData},
+    },
     utils, Error, Result,
 };
 
@@ -84,6 +87,79 @@ impl service::media::Data for KeyValueDatabase {
         Ok((content_disposition, content_type, key))
     }
 
+    fn content_hash(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.mediaid_contenthash.get(key)
+    }
+
+    fn set_content_hash(&self, key: &[u8], hash: &[u8]) -> Result<()> {
+        self.mediaid_contenthash.insert(key, hash)
+    }
+
+    fn increment_content_refcount(&self, hash: &[u8]) -> Result<u64> {
+        utils::u64_from_bytes(&self.contenthash_refcount.increment(hash)?)
+            .map_err(|_| Error::bad_database("Invalid refcount in contenthash_refcount."))
+    }
+
+    fn decrement_content_refcount(&self, hash: &[u8]) -> Result<u64> {
+        let refcount = self
+            .contenthash_refcount
+            .get(hash)?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid refcount in contenthash_refcount."))
+            })
+            .transpose()?
+            .unwrap_or_default()
+            .saturating_sub(1);
+
+        if refcount == 0 {
+            self.contenthash_refcount.remove(hash)?;
+        } else {
+            self.contenthash_refcount
+                .insert(hash, &refcount.to_be_bytes())?;
+        }
+
+        Ok(refcount)
+    }
+
+    fn all_file_metadata<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(Vec<u8>, MediaEntry)>> + 'a> {
+        Box::new(self.mediaid_file.iter().map(|(key, _)| {
+            let invalid = || Error::bad_database("Media ID in db is invalid.");
+
+            // The dimensions may contain 0xff bytes, so they are skipped by length
+            let mxc_len = key.iter().position(|&b| b == 0xff).ok_or_else(invalid)?;
+            let mxc = utils::string_from_bytes(&key[..mxc_len]).map_err(|_| invalid())?;
+            let dimensions = key.get(mxc_len + 1..mxc_len + 9).ok_or_else(invalid)?;
+            let width = u32::from_be_bytes(dimensions[..4].try_into().expect("4 bytes"));
+            let height = u32::from_be_bytes(dimensions[4..].try_into().expect("4 bytes"));
+
+            let mut parts = key
+                .get(mxc_len + 10..)
+                .ok_or_else(invalid)?
+                .splitn(2, |&b| b == 0xff)
+                .map(|bytes| {
+                    utils::string_from_bytes(bytes)
+                        .map(|s| Some(s).filter(|s| !s.is_empty()))
+                        .map_err(|_| invalid())
+                });
+            let content_disposition = parts.next().transpose()?.flatten();
+            let content_type = parts.next().transpose()?.flatten();
+
+            Ok((
+                key,
+                MediaEntry {
+                    mxc,
+                    width,
+                    height,
+                    content_disposition,
+                    content_type,
+                },
+            ))
+        }))
+    }
+
     fn remove_url_preview(&self, url: &str) -> Result<()> {
         self.url_previews.remove(url.as_bytes())
     }
@@ -191,4 +267,58 @@ impl service::media::Data for KeyValueDatabase {
             image_height,
         })
     }
+
+    fn set_quarantined(&self, mxc: &str, quarantined: bool) -> Result<()> {
+        if quarantined {
+            self.quarantined_media.insert(mxc.as_bytes(), &[])
+        } else {
+            self.quarantined_media.remove(mxc.as_bytes())
+        }
+    }
+
+    fn is_quarantined(&self, mxc: &str) -> Result<bool> {
+        Ok(self.quarantined_media.get(mxc.as_bytes())?.is_some())
+    }
+
+    fn add_upload(&self, user_id: &UserId, mxc: &str, size: u64) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(mxc.as_bytes());
+
+        let mut value = utils::millis_since_unix_epoch().to_be_bytes().to_vec();
+        value.extend_from_slice(&size.to_be_bytes());
+
+        self.userid_mediaupload.insert(&key, &value)
+    }
+
+    fn uploads_of_user<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<(String, u64, u64)>> + 'a> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        let prefix_len = prefix.len();
+
+        Box::new(
+            self.userid_mediaupload
+                .scan_prefix(prefix)
+                .map(move |(key, value)| {
+                    let mxc = utils::string_from_bytes(&key[prefix_len..]).map_err(|_| {
+                        Error::bad_database("MXC in userid_mediaupload is invalid unicode.")
+                    })?;
+
+                    if value.len() != 16 {
+                        return Err(Error::bad_database(
+                            "Invalid upload info in userid_mediaupload.",
+                        ));
+                    }
+                    let timestamp = utils::u64_from_bytes(&value[..8])
+                        .map_err(|_| Error::bad_database("Invalid upload timestamp."))?;
+                    let size = utils::u64_from_bytes(&value[8..])
+                        .map_err(|_| Error::bad_database("Invalid upload size."))?;
+
+                    Ok((mxc, timestamp, size))
+                }),
+        )
+    }
 }
@@ -1,6 +1,7 @@
 mod account_data;
 //mod admin;
 mod appservice;
+mod check;
 mod globals;
 mod key_backups;
 mod media;
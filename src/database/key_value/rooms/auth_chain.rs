@@ -1,13 +1,18 @@
 use std::{collections::HashSet, mem::size_of, sync::Arc};
 
-use crate::{database::KeyValueDatabase, service, utils, Result};
+use crate::{
+    database::{metrics, KeyValueDatabase},
+    service, utils, Result,
+};
 
 impl service::rooms::auth_chain::Data for KeyValueDatabase {
     fn get_cached_eventid_authchain(&self, key: &[u64]) -> Result<Option<Arc<HashSet<u64>>>> {
         // Check RAM cache
         if let Some(result) = self.auth_chain_cache.lock().unwrap().get_mut(key) {
+            metrics::AUTH_CHAIN_CACHE.hit();
             return Ok(Some(Arc::clone(result)));
         }
+        metrics::AUTH_CHAIN_CACHE.miss();
 
         // We only save auth chains for single events in the db
         if key.len() == 1 {
@@ -25,4 +25,61 @@ impl service::rooms::directory::Data for KeyValueDatabase {
             .map_err(|_| Error::bad_database("Room ID in publicroomids is invalid."))
         }))
     }
+
+    fn set_public_in_network(
+        &self,
+        room_id: &RoomId,
+        appservice_id: &str,
+        network_id: &str,
+    ) -> Result<()> {
+        let mut network = appservice_id.as_bytes().to_vec();
+        network.push(0xff);
+        network.extend_from_slice(network_id.as_bytes());
+
+        self.publicroomid_network
+            .insert(room_id.as_bytes(), &network)
+    }
+
+    fn set_not_public_in_network(&self, room_id: &RoomId) -> Result<()> {
+        self.publicroomid_network.remove(room_id.as_bytes())
+    }
+
+    fn public_room_network(&self, room_id: &RoomId) -> Result<Option<(String, String)>> {
+        self.publicroomid_network
+            .get(room_id.as_bytes())?
+            .map(|value| parse_network(&value))
+            .transpose()
+    }
+
+    fn network_public_rooms<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedRoomId, String, String)>> + 'a> {
+        Box::new(self.publicroomid_network.iter().map(|(key, value)| {
+            let room_id = RoomId::parse(utils::string_from_bytes(&key).map_err(|_| {
+                Error::bad_database("Room ID in publicroomid_network is invalid unicode.")
+            })?)
+            .map_err(|_| Error::bad_database("Room ID in publicroomid_network is invalid."))?;
+
+            let (appservice_id, network_id) = parse_network(&value)?;
+
+            Ok((room_id, appservice_id, network_id))
+        }))
+    }
+}
+
+/// Parses the appservice and network ID of a publicroomid_network value.
+fn parse_network(value: &[u8]) -> Result<(String, String)> {
+    let mut parts = value.split(|&b| b == 0xff);
+    let appservice_id = utils::string_from_bytes(
+        parts.next().expect("split always returns one element"),
+    )
+    .map_err(|_| Error::bad_database("Appservice ID in publicroomid_network is invalid."))?;
+    let network_id = utils::string_from_bytes(
+        parts
+            .next()
+            .ok_or_else(|| Error::bad_database("Network in publicroomid_network is invalid."))?,
+    )
+    .map_err(|_| Error::bad_database("Network ID in publicroomid_network is invalid."))?;
+
+    Ok((appservice_id, network_id))
 }
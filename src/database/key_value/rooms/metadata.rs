@@ -76,4 +76,24 @@ impl service::rooms::metadata::Data for KeyValueDatabase {
             },
         ))
     }
+
+    fn frozen_power_levels(&self, room_id: &RoomId) -> Result<Option<String>> {
+        self.roomid_frozenpowerlevels
+            .get(room_id.as_bytes())?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid power levels in roomid_frozenpowerlevels.")
+                })
+            })
+            .transpose()
+    }
+
+    fn set_frozen_power_levels(&self, room_id: &RoomId, power_levels: Option<&str>) -> Result<()> {
+        match power_levels {
+            Some(power_levels) => self
+                .roomid_frozenpowerlevels
+                .insert(room_id.as_bytes(), power_levels.as_bytes()),
+            None => self.roomid_frozenpowerlevels.remove(room_id.as_bytes()),
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use ruma::{CanonicalJsonObject, EventId};
+use ruma::{CanonicalJsonObject, EventId, OwnedRoomId, RoomId};
 
 use crate::{database::KeyValueDatabase, service, Error, PduEvent, Result};
 
@@ -25,4 +25,24 @@ impl service::rooms::outlier::Data for KeyValueDatabase {
             &serde_json::to_vec(&pdu).expect("CanonicalJsonObject is valid"),
         )
     }
+
+    fn room_outlier_pdus<'a>(
+        &'a self,
+        room_id: &RoomId,
+    ) -> Box<dyn Iterator<Item = Result<(PduEvent, CanonicalJsonObject)>> + 'a> {
+        let room_id: OwnedRoomId = room_id.to_owned();
+
+        Box::new(
+            self.eventid_outlierpdu
+                .iter()
+                .map(|(_, pdu)| {
+                    let invalid = |_: serde_json::Error| Error::bad_database("Invalid PDU in db.");
+                    Ok::<_, Error>((
+                        serde_json::from_slice::<PduEvent>(&pdu).map_err(invalid)?,
+                        serde_json::from_slice(&pdu).map_err(invalid)?,
+                    ))
+                })
+                .filter(move |r| !matches!(r, Ok((pdu, _)) if pdu.room_id != room_id)),
+        )
+    }
 }
@@ -1,14 +1,14 @@
 use std::{mem, sync::Arc};
 
-use ruma::{EventId, RoomId, UserId};
+use ruma::{EventId, OwnedEventId, RoomId, UserId};
 
 use crate::{
-    database::KeyValueDatabase,
+    database::{abstraction::KvTree, KeyValueDatabase},
     service::{
         self,
         rooms::timeline::{data::PduData, PduCount},
     },
-    services, utils, Error, Result,
+    services, utils, Error, PduEvent, Result,
 };
 
 impl service::rooms::pdu_metadata::Data for KeyValueDatabase {
@@ -42,26 +42,113 @@ impl service::rooms::pdu_metadata::Data for KeyValueDatabase {
             self.tofrom_relation
                 .iter_from(&current, true)
                 .take_while(move |(k, _)| k.starts_with(&prefix))
-                .map(move |(tofrom, _data)| {
-                    let from = utils::u64_from_bytes(&tofrom[(mem::size_of::<u64>())..])
-                        .map_err(|_| Error::bad_database("Invalid count in tofrom_relation."))?;
-
-                    let mut pduid = shortroomid.to_be_bytes().to_vec();
-                    pduid.extend_from_slice(&from.to_be_bytes());
-
-                    let mut pdu = services()
-                        .rooms
-                        .timeline
-                        .get_pdu_from_id(&pduid)?
-                        .ok_or_else(|| Error::bad_database("Pdu in tofrom_relation is invalid."))?;
-                    if pdu.sender != user_id {
-                        pdu.remove_transaction_id()?;
-                    }
-                    Ok((PduCount::Normal(from), pdu))
-                }),
+                .map(move |(tofrom, _data)| relation_pdu(user_id, shortroomid, &tofrom)),
+        ))
+    }
+
+    fn relations_after<'a>(
+        &'a self,
+        user_id: &'a UserId,
+        shortroomid: u64,
+        target: u64,
+        after: PduCount,
+    ) -> PduData<'a> {
+        let prefix = target.to_be_bytes().to_vec();
+        let mut current = prefix.clone();
+
+        // Relations with backfilled events are not tracked, so they are all before normal ones
+        let count_raw = match after {
+            PduCount::Normal(x) => x.saturating_add(1),
+            PduCount::Backfilled(_) => 0,
+        };
+        current.extend_from_slice(&count_raw.to_be_bytes());
+
+        Ok(Box::new(
+            self.tofrom_relation
+                .iter_from(&current, false)
+                .take_while(move |(k, _)| k.starts_with(&prefix))
+                .map(move |(tofrom, _data)| relation_pdu(user_id, shortroomid, &tofrom)),
         ))
     }
 
+    fn annotations_counted(&self, target: u64) -> Result<bool> {
+        Ok(self
+            .relationannotation_count
+            .get(&target.to_be_bytes())?
+            .is_some())
+    }
+
+    fn mark_annotations_counted(&self, target: u64) -> Result<()> {
+        self.relationannotation_count
+            .insert(&target.to_be_bytes(), &[])
+    }
+
+    fn count_annotation(
+        &self,
+        target: u64,
+        kind: &str,
+        key: &str,
+        sender: &UserId,
+        added: bool,
+    ) -> Result<()> {
+        let mut annotation = target.to_be_bytes().to_vec();
+        annotation.extend_from_slice(kind.as_bytes());
+        annotation.push(0xff);
+        annotation.extend_from_slice(key.as_bytes());
+
+        let mut sender_key = annotation.clone();
+        sender_key.push(0xff);
+        sender_key.extend_from_slice(sender.as_bytes());
+
+        let sent = counter(&*self.relationannotation_sendercount, &sender_key)?;
+        let now_sent = if added {
+            sent.saturating_add(1)
+        } else {
+            sent.saturating_sub(1)
+        };
+        set_counter(&*self.relationannotation_sendercount, &sender_key, now_sent)?;
+
+        // The sender only counts once, no matter how often they sent the annotation
+        if (sent == 0) != (now_sent == 0) {
+            let senders = counter(&*self.relationannotation_count, &annotation)?;
+            let senders = if added {
+                senders.saturating_add(1)
+            } else {
+                senders.saturating_sub(1)
+            };
+            set_counter(&*self.relationannotation_count, &annotation, senders)?;
+        }
+
+        Ok(())
+    }
+
+    fn annotation_counts(&self, target: u64) -> Result<Vec<((String, String), u64)>> {
+        let prefix = target.to_be_bytes().to_vec();
+
+        self.relationannotation_count
+            .scan_prefix(prefix.clone())
+            // Skip the marker of counted annotations
+            .filter(|(key, _)| key.len() > prefix.len())
+            .map(|(key, count)| {
+                let mut parts = key[prefix.len()..].splitn(2, |&b| b == 0xff);
+                let mut next_part = || {
+                    parts
+                        .next()
+                        .and_then(|part| utils::string_from_bytes(part).ok())
+                        .ok_or_else(|| {
+                            Error::bad_database("Invalid annotation in relationannotation_count.")
+                        })
+                };
+                let annotation = (next_part()?, next_part()?);
+                let count = utils::u64_from_bytes(&count).map_err(|_| {
+                    Error::bad_database("Invalid count in relationannotation_count.")
+                })?;
+
+                Ok((annotation, count))
+            })
+            .collect()
+    }
+
     fn mark_as_referenced(&self, room_id: &RoomId, event_ids: &[Arc<EventId>]) -> Result<()> {
         for prev in event_ids {
             let mut key = room_id.as_bytes().to_vec();
@@ -78,8 +165,43 @@ impl service::rooms::pdu_metadata::Data for KeyValueDatabase {
         Ok(self.referencedevents.get(&key)?.is_some())
     }
 
-    fn mark_event_soft_failed(&self, event_id: &EventId) -> Result<()> {
-        self.softfailedeventids.insert(event_id.as_bytes(), &[])
+    fn mark_event_soft_failed(&self, room_id: &RoomId, event_id: &EventId) -> Result<()> {
+        self.softfailedeventids.insert(event_id.as_bytes(), &[])?;
+
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(event_id.as_bytes());
+        self.roomid_softfailedeventid
+            .insert(&key, &utils::millis_since_unix_epoch().to_be_bytes())
+    }
+
+    fn soft_failed_events<'a>(
+        &'a self,
+        room_id: &RoomId,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedEventId, u64)>> + 'a> {
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Box::new(
+            self.roomid_softfailedeventid
+                .scan_prefix(prefix.clone())
+                .map(move |(key, time)| {
+                    let event_id = utils::string_from_bytes(&key[prefix.len()..])
+                        .map_err(|_| {
+                            Error::bad_database("Invalid event id in roomid_softfailedeventid.")
+                        })
+                        .and_then(|event_id| {
+                            EventId::parse(event_id).map_err(|_| {
+                                Error::bad_database("Invalid event id in roomid_softfailedeventid.")
+                            })
+                        })?;
+                    let time = utils::u64_from_bytes(&time).map_err(|_| {
+                        Error::bad_database("Invalid time in roomid_softfailedeventid.")
+                    })?;
+
+                    Ok((event_id, time))
+                }),
+        )
     }
 
     fn is_event_soft_failed(&self, event_id: &EventId) -> Result<bool> {
@@ -88,3 +210,41 @@ impl service::rooms::pdu_metadata::Data for KeyValueDatabase {
             .map(|o| o.is_some())
     }
 }
+
+/// Loads the relating event of a `tofrom_relation` key.
+fn relation_pdu(user_id: &UserId, shortroomid: u64, tofrom: &[u8]) -> Result<(PduCount, PduEvent)> {
+    let from = utils::u64_from_bytes(&tofrom[(mem::size_of::<u64>())..])
+        .map_err(|_| Error::bad_database("Invalid count in tofrom_relation."))?;
+
+    let mut pduid = shortroomid.to_be_bytes().to_vec();
+    pduid.extend_from_slice(&from.to_be_bytes());
+
+    let mut pdu = services()
+        .rooms
+        .timeline
+        .get_pdu_from_id(&pduid)?
+        .ok_or_else(|| Error::bad_database("Pdu in tofrom_relation is invalid."))?;
+    if pdu.sender != user_id {
+        pdu.remove_transaction_id()?;
+    }
+    Ok((PduCount::Normal(from), pdu))
+}
+
+fn counter(tree: &dyn KvTree, key: &[u8]) -> Result<u64> {
+    tree.get(key)?
+        .map(|bytes| {
+            utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("Invalid annotation counter."))
+        })
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
+/// Stores the counter, removing it once it reaches zero.
+fn set_counter(tree: &dyn KvTree, key: &[u8], count: u64) -> Result<()> {
+    if count == 0 {
+        tree.remove(key)
+    } else {
+        tree.insert(key, &count.to_be_bytes())
+    }
+}
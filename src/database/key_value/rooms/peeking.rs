@@ -0,0 +1,64 @@
+use ruma::{DeviceId, OwnedRoomId, RoomId, UserId};
+
+use crate::{database::KeyValueDatabase, service, utils, Error, Result};
+
+impl service::rooms::peeking::Data for KeyValueDatabase {
+    fn add_peek(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        room_id: &RoomId,
+        count: u64,
+    ) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(room_id.as_bytes());
+
+        self.userdeviceroomid_peekcount
+            .insert(&key, &count.to_be_bytes())
+    }
+
+    fn remove_peek(&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(room_id.as_bytes());
+
+        self.userdeviceroomid_peekcount.remove(&key)
+    }
+
+    fn peeked_rooms<'a>(
+        &'a self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedRoomId, u64)>> + 'a> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xff);
+
+        Box::new(
+            self.userdeviceroomid_peekcount
+                .scan_prefix(prefix.clone())
+                .map(move |(key, count)| {
+                    let room_id = RoomId::parse(
+                        utils::string_from_bytes(&key[prefix.len()..]).map_err(|_| {
+                            Error::bad_database("Room ID in userdeviceroomid_peekcount is invalid.")
+                        })?,
+                    )
+                    .map_err(|_| {
+                        Error::bad_database("Room ID in userdeviceroomid_peekcount is invalid.")
+                    })?;
+
+                    let count = utils::u64_from_bytes(&count).map_err(|_| {
+                        Error::bad_database("Count in userdeviceroomid_peekcount is invalid.")
+                    })?;
+
+                    Ok((room_id, count))
+                }),
+        )
+    }
+}
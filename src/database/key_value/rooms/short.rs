@@ -3,13 +3,18 @@ use std::sync::Arc;
 use ruma::{events::StateEventType, EventId, RoomId};
 use tracing::warn;
 
-use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
+use crate::{
+    database::{metrics, KeyValueDatabase},
+    service, services, utils, Error, Result,
+};
 
 impl service::rooms::short::Data for KeyValueDatabase {
     fn get_or_create_shorteventid(&self, event_id: &EventId) -> Result<u64> {
         if let Some(short) = self.eventidshort_cache.lock().unwrap().get_mut(event_id) {
+            metrics::EVENTIDSHORT_CACHE.hit();
             return Ok(*short);
         }
+        metrics::EVENTIDSHORT_CACHE.miss();
 
         let short = match self.eventid_shorteventid.get(event_id.as_bytes())? {
             Some(shorteventid) => utils::u64_from_bytes(&shorteventid)
@@ -43,8 +48,10 @@ impl service::rooms::short::Data for KeyValueDatabase {
             .unwrap()
             .get_mut(&(event_type.clone(), state_key.to_owned()))
         {
+            metrics::STATEKEYSHORT_CACHE.hit();
             return Ok(Some(*short));
         }
+        metrics::STATEKEYSHORT_CACHE.miss();
 
         let mut statekey = event_type.to_string().as_bytes().to_vec();
         statekey.push(0xff);
@@ -80,8 +87,10 @@ impl service::rooms::short::Data for KeyValueDatabase {
             .unwrap()
             .get_mut(&(event_type.clone(), state_key.to_owned()))
         {
+            metrics::STATEKEYSHORT_CACHE.hit();
             return Ok(*short);
         }
+        metrics::STATEKEYSHORT_CACHE.miss();
 
         let mut statekey = event_type.to_string().as_bytes().to_vec();
         statekey.push(0xff);
@@ -115,8 +124,10 @@ impl service::rooms::short::Data for KeyValueDatabase {
             .unwrap()
             .get_mut(&shorteventid)
         {
+            metrics::SHORTEVENTID_CACHE.hit();
             return Ok(Arc::clone(id));
         }
+        metrics::SHORTEVENTID_CACHE.miss();
 
         let bytes = self
             .shorteventid_eventid
@@ -143,8 +154,10 @@ impl service::rooms::short::Data for KeyValueDatabase {
             .unwrap()
             .get_mut(&shortstatekey)
         {
+            metrics::SHORTSTATEKEY_CACHE.hit();
             return Ok(id.clone());
         }
+        metrics::SHORTSTATEKEY_CACHE.miss();
 
         let bytes = self
             .shortstatekey_statekey
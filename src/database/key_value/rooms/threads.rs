@@ -12,7 +12,7 @@ impl service::rooms::threads::Data for KeyValueDatabase {
         user_id: &'a UserId,
         room_id: &'a RoomId,
         until: u64,
-        _include: &'a IncludeThreads,
+        include: &'a IncludeThreads,
     ) -> PduEventIterResult<'a> {
         let prefix = services()
             .rooms
@@ -23,21 +23,35 @@ impl service::rooms::threads::Data for KeyValueDatabase {
             .to_vec();
 
         let mut current = prefix.clone();
-        current.extend_from_slice(&(until - 1).to_be_bytes());
+        current.extend_from_slice(&until.saturating_sub(1).to_be_bytes());
 
         Ok(Box::new(
-            self.threadid_userids
+            self.roomactivity_threadid
                 .iter_from(&current, true)
                 .take_while(move |(k, _)| k.starts_with(&prefix))
-                .map(move |(pduid, _users)| {
-                    let count = utils::u64_from_bytes(&pduid[(mem::size_of::<u64>())..])
-                        .map_err(|_| Error::bad_database("Invalid pduid in threadid_userids."))?;
+                .filter(move |(_, pduid)| match include {
+                    IncludeThreads::Participated => self
+                        .get_participants(pduid)
+                        .ok()
+                        .flatten()
+                        .is_some_and(|participants| {
+                            participants
+                                .iter()
+                                .any(|participant| **participant == *user_id)
+                        }),
+                    _ => true,
+                })
+                .map(move |(roomactivity, pduid)| {
+                    let count = utils::u64_from_bytes(&roomactivity[(mem::size_of::<u64>())..])
+                        .map_err(|_| {
+                            Error::bad_database("Invalid activity in roomactivity_threadid.")
+                        })?;
                     let mut pdu = services()
                         .rooms
                         .timeline
                         .get_pdu_from_id(&pduid)?
                         .ok_or_else(|| {
-                            Error::bad_database("Invalid pduid reference in threadid_userids")
+                            Error::bad_database("Invalid pduid reference in roomactivity_threadid")
                         })?;
                     if pdu.sender != user_id {
                         pdu.remove_transaction_id()?;
@@ -59,6 +73,21 @@ impl service::rooms::threads::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn update_thread_activity(&self, root_id: &[u8], count: u64) -> Result<()> {
+        let shortroomid = &root_id[..mem::size_of::<u64>()];
+
+        if let Some(previous) = self.threadid_activity.get(root_id)? {
+            let mut key = shortroomid.to_vec();
+            key.extend_from_slice(&previous);
+            self.roomactivity_threadid.remove(&key)?;
+        }
+
+        let mut key = shortroomid.to_vec();
+        key.extend_from_slice(&count.to_be_bytes());
+        self.roomactivity_threadid.insert(&key, root_id)?;
+        self.threadid_activity.insert(root_id, &count.to_be_bytes())
+    }
+
     fn get_participants(&self, root_id: &[u8]) -> Result<Option<Vec<OwnedUserId>>> {
         if let Some(users) = self.threadid_userids.get(root_id)? {
             Ok(Some(
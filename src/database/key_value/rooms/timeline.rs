@@ -6,7 +6,7 @@ use ruma::{
 use tracing::error;
 
 use crate::{
-    database::KeyValueDatabase,
+    database::{metrics, KeyValueDatabase},
     service::{self, rooms::timeline::data::PduData},
     services, utils, Error, PduEvent, Result,
 };
@@ -107,8 +107,10 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
     /// Checks the `eventid_outlierpdu` Tree if not found in the timeline.
     fn get_pdu(&self, event_id: &EventId) -> Result<Option<Arc<PduEvent>>> {
         if let Some(p) = self.pdu_cache.lock().unwrap().get_mut(event_id) {
+            metrics::PDU_CACHE.hit();
             return Ok(Some(Arc::clone(p)));
         }
+        metrics::PDU_CACHE.miss();
 
         if let Some(pdu) = self
             .get_non_outlier_pdu(event_id)?
@@ -279,6 +281,7 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
     fn increment_notification_counts(
         &self,
         room_id: &RoomId,
+        thread_id: Option<&EventId>,
         notifies: Vec<OwnedUserId>,
         highlights: Vec<OwnedUserId>,
     ) -> Result<()> {
@@ -297,6 +300,20 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
             highlights_batch.push(userroom_id);
         }
 
+        if let Some(thread_id) = thread_id {
+            let userthread_id = |userroom_id: &Vec<u8>| {
+                let mut userthread_id = userroom_id.clone();
+                userthread_id.push(0xff);
+                userthread_id.extend_from_slice(thread_id.as_bytes());
+                userthread_id
+            };
+
+            self.userthreadid_notificationcount
+                .increment_batch(&mut notifies_batch.iter().map(userthread_id))?;
+            self.userthreadid_highlightcount
+                .increment_batch(&mut highlights_batch.iter().map(userthread_id))?;
+        }
+
         self.userroomid_notificationcount
             .increment_batch(&mut notifies_batch.into_iter())?;
         self.userroomid_highlightcount
@@ -1,6 +1,12 @@
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use std::mem;
 
-use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
+
+use crate::{
+    database::KeyValueDatabase,
+    service::{self, rooms::user::LoggedNotification},
+    services, utils, Error, Result,
+};
 
 impl service::rooms::user::Data for KeyValueDatabase {
     fn reset_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
@@ -16,14 +22,114 @@ impl service::rooms::user::Data for KeyValueDatabase {
         self.userroomid_highlightcount
             .insert(&userroom_id, &0_u64.to_be_bytes())?;
 
-        self.roomuserid_lastnotificationread.insert(
-            &roomuser_id,
-            &services().globals.next_count()?.to_be_bytes(),
-        )?;
+        let mut prefix = userroom_id;
+        prefix.push(0xff);
+        for (key, _) in self
+            .userthreadid_notificationcount
+            .scan_prefix(prefix.clone())
+        {
+            self.userthreadid_notificationcount.remove(&key)?;
+        }
+        for (key, _) in self.userthreadid_highlightcount.scan_prefix(prefix) {
+            self.userthreadid_highlightcount.remove(&key)?;
+        }
+
+        let count = services().globals.next_count()?.to_be_bytes();
+        self.roomuserid_lastnotificationread
+            .insert(&roomuser_id, &count)?;
+        self.roomuserid_lastnotificationupdate
+            .insert(&roomuser_id, &count)?;
 
         Ok(())
     }
 
+    fn reset_main_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        let mut userroom_id = user_id.as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.as_bytes());
+
+        // Only the notifications in threads stay unread
+        let (notifications, highlights) = self
+            .thread_notification_counts(user_id, room_id)?
+            .into_iter()
+            .fold((0_u64, 0_u64), |(n, h), (_, thread_n, thread_h)| {
+                (n + thread_n, h + thread_h)
+            });
+
+        self.userroomid_notificationcount
+            .insert(&userroom_id, &notifications.to_be_bytes())?;
+        self.userroomid_highlightcount
+            .insert(&userroom_id, &highlights.to_be_bytes())?;
+
+        self.notification_counts_updated(user_id, room_id)
+    }
+
+    fn reset_thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        thread_id: &EventId,
+    ) -> Result<()> {
+        let mut userroom_id = user_id.as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.as_bytes());
+
+        let mut userthread_id = userroom_id.clone();
+        userthread_id.push(0xff);
+        userthread_id.extend_from_slice(thread_id.as_bytes());
+
+        // The counts of the room include the notifications in threads
+        for (thread_tree, room_tree) in [
+            (
+                &self.userthreadid_notificationcount,
+                &self.userroomid_notificationcount,
+            ),
+            (
+                &self.userthreadid_highlightcount,
+                &self.userroomid_highlightcount,
+            ),
+        ] {
+            let thread_count = count_from_bytes(thread_tree.get(&userthread_id)?)?;
+            let room_count = count_from_bytes(room_tree.get(&userroom_id)?)?;
+
+            room_tree.insert(
+                &userroom_id,
+                &room_count.saturating_sub(thread_count).to_be_bytes(),
+            )?;
+            thread_tree.remove(&userthread_id)?;
+        }
+
+        self.notification_counts_updated(user_id, room_id)
+    }
+
+    fn thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Vec<(OwnedEventId, u64, u64)>> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(room_id.as_bytes());
+        prefix.push(0xff);
+
+        self.userthreadid_notificationcount
+            .scan_prefix(prefix.clone())
+            .map(|(key, value)| {
+                let thread_id = utils::string_from_bytes(&key[prefix.len()..])
+                    .ok()
+                    .and_then(|thread_id| EventId::parse(thread_id).ok())
+                    .ok_or_else(|| {
+                        Error::bad_database("Invalid thread id in userthreadid_notificationcount.")
+                    })?;
+
+                let notifications = count_from_bytes(Some(value))?;
+                let highlights = count_from_bytes(self.userthreadid_highlightcount.get(&key)?)?;
+
+                Ok((thread_id, notifications, highlights))
+            })
+            .collect()
+    }
+
     fn notification_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
         let mut userroom_id = user_id.as_bytes().to_vec();
         userroom_id.push(0xff);
@@ -69,6 +175,86 @@ impl service::rooms::user::Data for KeyValueDatabase {
             .unwrap_or(0))
     }
 
+    fn add_notification(
+        &self,
+        user_id: &UserId,
+        count: u64,
+        notification: &LoggedNotification,
+    ) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&count.to_be_bytes());
+
+        self.usercount_notification.insert(
+            &key,
+            &serde_json::to_vec(notification).expect("LoggedNotification can be serialized"),
+        )?;
+
+        let mut ts_key = u64::from(notification.ts.get()).to_be_bytes().to_vec();
+        ts_key.extend_from_slice(&key);
+        self.notificationts_usercount.insert(&ts_key, &[])
+    }
+
+    fn remove_notifications_before(&self, until: u64) -> Result<usize> {
+        let mut removed = 0;
+
+        for (ts_key, _) in self
+            .notificationts_usercount
+            .iter()
+            .take_while(|(ts_key, _)| {
+                utils::u64_from_bytes(&ts_key[..mem::size_of::<u64>()]).is_ok_and(|ts| ts < until)
+            })
+        {
+            self.usercount_notification
+                .remove(&ts_key[mem::size_of::<u64>()..])?;
+            self.notificationts_usercount.remove(&ts_key)?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+
+    fn notifications_until<'a>(
+        &'a self,
+        user_id: &UserId,
+        until: u64,
+    ) -> Box<dyn Iterator<Item = Result<(u64, LoggedNotification)>> + 'a> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut current = prefix.clone();
+        current.extend_from_slice(&until.saturating_sub(1).to_be_bytes());
+
+        Box::new(
+            self.usercount_notification
+                .iter_from(&current, true)
+                .take_while(move |(k, _)| k.starts_with(&prefix))
+                .map(|(key, value)| {
+                    let count = utils::u64_from_bytes(&key[key.len() - mem::size_of::<u64>()..])
+                        .map_err(|_| {
+                            Error::bad_database("Invalid count in usercount_notification.")
+                        })?;
+                    let notification = serde_json::from_slice(&value).map_err(|_| {
+                        Error::bad_database("Invalid notification in usercount_notification.")
+                    })?;
+
+                    Ok((count, notification))
+                }),
+        )
+    }
+
+    fn last_notification_update(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(user_id.as_bytes());
+
+        // Resets of the whole room are recorded in both trees
+        Ok(
+            count_from_bytes(self.roomuserid_lastnotificationupdate.get(&key)?)?
+                .max(self.last_notification_read(user_id, room_id)?),
+        )
+    }
+
     fn associate_token_shortstatehash(
         &self,
         room_id: &RoomId,
@@ -147,3 +333,25 @@ impl service::rooms::user::Data for KeyValueDatabase {
         ))
     }
 }
+
+impl KeyValueDatabase {
+    fn notification_counts_updated(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        let mut roomuser_id = room_id.as_bytes().to_vec();
+        roomuser_id.push(0xff);
+        roomuser_id.extend_from_slice(user_id.as_bytes());
+
+        self.roomuserid_lastnotificationupdate.insert(
+            &roomuser_id,
+            &services().globals.next_count()?.to_be_bytes(),
+        )
+    }
+}
+
+fn count_from_bytes(bytes: Option<Vec<u8>>) -> Result<u64> {
+    bytes
+        .map(|bytes| {
+            utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("Invalid notification count in db."))
+        })
+        .unwrap_or(Ok(0))
+}
@@ -1,10 +1,10 @@
-use ruma::{ServerName, UserId};
+use ruma::{OwnedServerName, ServerName, UserId};
 
 use crate::{
     database::KeyValueDatabase,
     service::{
         self,
-        sending::{OutgoingKind, SendingEventType},
+        sending::{DestinationStats, OutgoingKind, SendingEventType},
     },
     services, utils, Error, Result,
 };
@@ -123,6 +123,46 @@ impl service::sending::Data for KeyValueDatabase {
                     .map_err(|_| Error::bad_database("Invalid u64 in servername_educount."))
             })
     }
+
+    fn destination_stats(&self, server_name: &ServerName) -> Result<Option<DestinationStats>> {
+        self.servername_destinationstats
+            .get(server_name.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid stats in servername_destinationstats.")
+                })
+            })
+            .transpose()
+    }
+
+    fn set_destination_stats(
+        &self,
+        server_name: &ServerName,
+        stats: &DestinationStats,
+    ) -> Result<()> {
+        self.servername_destinationstats.insert(
+            server_name.as_bytes(),
+            &serde_json::to_vec(stats).expect("DestinationStats can be serialized"),
+        )
+    }
+
+    fn all_destination_stats<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedServerName, DestinationStats)>> + 'a> {
+        Box::new(self.servername_destinationstats.iter().map(|(key, value)| {
+            let server_name = utils::string_from_bytes(&key)
+                .ok()
+                .and_then(|server_name| ServerName::parse(server_name).ok())
+                .ok_or_else(|| {
+                    Error::bad_database("Invalid server name in servername_destinationstats.")
+                })?;
+            let stats = serde_json::from_slice(&value).map_err(|_| {
+                Error::bad_database("Invalid stats in servername_destinationstats.")
+            })?;
+
+            Ok((server_name, stats))
+        }))
+    }
 }
 
 #[tracing::instrument(skip(key))]
@@ -5,6 +5,7 @@ use ruma::{
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::{AnyToDeviceEvent, StateEventType},
     serde::Raw,
+    thirdparty::Medium,
     DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
     OwnedDeviceKeyId, OwnedMxcUri, OwnedUserId, UInt, UserId,
 };
@@ -12,7 +13,10 @@ use tracing::warn;
 
 use crate::{
     database::KeyValueDatabase,
-    service::{self, users::clean_signatures},
+    service::{
+        self,
+        users::{clean_signatures, ThreepidBinding},
+    },
     services, utils, Error, Result,
 };
 
@@ -193,6 +197,81 @@ impl service::users::Data for KeyValueDatabase {
         Ok(())
     }
 
+    /// Returns the version of the terms of service policy the user has accepted, if any.
+    fn accepted_terms_version(&self, user_id: &UserId, policy_id: &str) -> Result<Option<String>> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(policy_id.as_bytes());
+
+        self.useridpolicyid_termsversion
+            .get(&key)?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Terms of service version in db is invalid."))
+            })
+            .transpose()
+    }
+
+    /// Records that the user has accepted the given version of a terms of service policy.
+    fn set_accepted_terms_version(
+        &self,
+        user_id: &UserId,
+        policy_id: &str,
+        version: &str,
+    ) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(policy_id.as_bytes());
+
+        self.useridpolicyid_termsversion
+            .insert(&key, version.as_bytes())
+    }
+
+    /// Returns the verified email address of the user, if any.
+    fn email(&self, user_id: &UserId) -> Result<Option<String>> {
+        self.userid_email
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Email address in db is invalid."))
+            })
+            .transpose()
+    }
+
+    /// Returns the user with the verified email address, if any.
+    fn find_user_by_email(&self, email: &str) -> Result<Option<OwnedUserId>> {
+        self.email_userid
+            .get(email.as_bytes())?
+            .map(|bytes| {
+                UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in email_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in email_userid is invalid."))
+            })
+            .transpose()
+    }
+
+    /// Stores the verified email address of the user.
+    fn set_email(&self, user_id: &UserId, email: &str) -> Result<()> {
+        if let Some(old_email) = self.email(user_id)? {
+            self.email_userid.remove(old_email.as_bytes())?;
+        }
+
+        self.userid_email
+            .insert(user_id.as_bytes(), email.as_bytes())?;
+        self.email_userid
+            .insert(email.as_bytes(), user_id.as_bytes())
+    }
+
+    /// Removes the verified email address of the user, so it can be used for another account.
+    fn remove_email(&self, user_id: &UserId) -> Result<()> {
+        if let Some(email) = self.email(user_id)? {
+            self.email_userid.remove(email.as_bytes())?;
+        }
+
+        self.userid_email.remove(user_id.as_bytes())
+    }
+
     /// Adds a new device to a user.
     fn create_device(
         &self,
@@ -238,13 +317,14 @@ impl service::users::Data for KeyValueDatabase {
             self.userdeviceid_token.remove(&userdeviceid)?;
             self.token_userdeviceid.remove(&old_token)?;
         }
+        self.userdeviceid_tokentimes.remove(&userdeviceid)?;
 
         // Remove todevice events
         let mut prefix = userdeviceid.clone();
         prefix.push(0xff);
 
         for (key, _) in self.todeviceid_events.scan_prefix(prefix) {
-            self.todeviceid_events.remove(&key)?;
+            self.remove_to_device_event(&key)?;
         }
 
         // TODO: Remove onetimekeys
@@ -303,9 +383,45 @@ impl service::users::Data for KeyValueDatabase {
         self.token_userdeviceid
             .insert(token.as_bytes(), &userdeviceid)?;
 
+        let now = utils::millis_since_unix_epoch();
+        self.set_token_times(user_id, device_id, now, now)?;
+
         Ok(())
     }
 
+    fn token_times(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<(u64, u64)>> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_tokentimes
+            .get(&userdeviceid)?
+            .map(|bytes| {
+                let (issued, last_used) = bytes.split_at(size_of::<u64>().min(bytes.len()));
+                utils::u64_from_bytes(issued)
+                    .and_then(|issued| Ok((issued, utils::u64_from_bytes(last_used)?)))
+                    .map_err(|_| Error::bad_database("Invalid times in userdeviceid_tokentimes."))
+            })
+            .transpose()
+    }
+
+    fn set_token_times(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        issued: u64,
+        last_used: u64,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        let mut times = issued.to_be_bytes().to_vec();
+        times.extend_from_slice(&last_used.to_be_bytes());
+
+        self.userdeviceid_tokentimes.insert(&userdeviceid, &times)
+    }
+
     fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -587,6 +703,18 @@ impl service::users::Data for KeyValueDatabase {
         from: u64,
         to: Option<u64>,
     ) -> Box<dyn Iterator<Item = Result<OwnedUserId>> + 'a> {
+        Box::new(
+            self.keys_changed_with_counts(user_or_room_id, from, to)
+                .map(|r| r.map(|(_, user_id)| user_id)),
+        )
+    }
+
+    fn keys_changed_with_counts<'a>(
+        &'a self,
+        user_or_room_id: &str,
+        from: u64,
+        to: Option<u64>,
+    ) -> Box<dyn Iterator<Item = Result<(u64, OwnedUserId)>> + 'a> {
         let mut prefix = user_or_room_id.as_bytes().to_vec();
         prefix.push(0xff);
 
@@ -598,30 +726,29 @@ impl service::users::Data for KeyValueDatabase {
         Box::new(
             self.keychangeid_userid
                 .iter_from(&start, false)
-                .take_while(move |(k, _)| {
-                    k.starts_with(&prefix)
-                        && if let Some(current) = k.splitn(2, |&b| b == 0xff).nth(1) {
-                            if let Ok(c) = utils::u64_from_bytes(current) {
-                                c <= to
-                            } else {
-                                warn!("BadDatabase: Could not parse keychangeid_userid bytes");
-                                false
-                            }
-                        } else {
-                            warn!("BadDatabase: Could not parse keychangeid_userid");
-                            false
-                        }
+                .take_while(move |(k, _)| k.starts_with(&prefix))
+                .map(|(k, bytes)| {
+                    let count = k
+                        .splitn(2, |&b| b == 0xff)
+                        .nth(1)
+                        .and_then(|count| utils::u64_from_bytes(count).ok())
+                        .ok_or_else(|| {
+                            Error::bad_database("Could not parse keychangeid_userid bytes.")
+                        })?;
+
+                    let user_id =
+                        UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                            Error::bad_database(
+                                "User ID in devicekeychangeid_userid is invalid unicode.",
+                            )
+                        })?)
+                        .map_err(|_| {
+                            Error::bad_database("User ID in devicekeychangeid_userid is invalid.")
+                        })?;
+
+                    Ok((count, user_id))
                 })
-                .map(|(_, bytes)| {
-                    UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
-                        Error::bad_database(
-                            "User ID in devicekeychangeid_userid is invalid unicode.",
-                        )
-                    })?)
-                    .map_err(|_| {
-                        Error::bad_database("User ID in devicekeychangeid_userid is invalid.")
-                    })
-                }),
+                .take_while(move |r| r.as_ref().map_or(true, |(count, _)| *count <= to)),
         )
     }
 
@@ -786,6 +913,15 @@ impl service::users::Data for KeyValueDatabase {
 
         self.todeviceid_events.insert(&key, &value)?;
 
+        // Only needed to delete expired events
+        if services().globals.to_device_message_max_age_s().is_some() {
+            let ts = utils::millis_since_unix_epoch().to_be_bytes();
+            let mut ts_key = ts.to_vec();
+            ts_key.extend_from_slice(&key);
+            self.todevicets_id.insert(&ts_key, &[])?;
+            self.todeviceid_ts.insert(&key, &ts)?;
+        }
+
         Ok(())
     }
 
@@ -839,12 +975,31 @@ impl service::users::Data for KeyValueDatabase {
             .filter_map(|r| r.ok())
             .take_while(|&(_, count)| count <= until)
         {
-            self.todeviceid_events.remove(&key)?;
+            self.remove_to_device_event(&key)?;
         }
 
         Ok(())
     }
 
+    fn remove_to_device_events_before(&self, until: u64) -> Result<usize> {
+        let mut removed = 0;
+
+        // Entries of events that were delivered already are removed here as well
+        for (ts_key, _) in self.todevicets_id.iter().take_while(|(ts_key, _)| {
+            utils::u64_from_bytes(&ts_key[..size_of::<u64>()]).is_ok_and(|ts| ts < until)
+        }) {
+            let key = &ts_key[size_of::<u64>()..];
+            if self.todeviceid_events.get(key)?.is_some() {
+                removed += 1;
+            }
+            self.remove_to_device_event(key)?;
+            // In case the entries don't match
+            self.todevicets_id.remove(&ts_key)?;
+        }
+
+        Ok(removed)
+    }
+
     fn update_device_metadata(
         &self,
         user_id: &UserId,
@@ -946,9 +1101,94 @@ impl service::users::Data for KeyValueDatabase {
             Ok(None)
         }
     }
+
+    fn oidc_subject_user(&self, subject: &str) -> Result<Option<OwnedUserId>> {
+        self.oidcsubject_userid
+            .get(subject.as_bytes())?
+            .map(|bytes| {
+                UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in oidcsubject_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in oidcsubject_userid is invalid."))
+            })
+            .transpose()
+    }
+
+    fn set_oidc_subject_user(&self, subject: &str, user_id: &UserId) -> Result<()> {
+        self.oidcsubject_userid
+            .insert(subject.as_bytes(), user_id.as_bytes())
+    }
+
+    fn set_guest(&self, user_id: &UserId) -> Result<()> {
+        self.guest_userids.insert(user_id.as_bytes(), &[])
+    }
+
+    fn is_guest(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.guest_userids.get(user_id.as_bytes())?.is_some())
+    }
+
+    fn add_threepid_binding(&self, user_id: &UserId, binding: &ThreepidBinding) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(binding.medium.as_str().as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(binding.address.as_bytes());
+
+        self.userthreepid_binding.insert(
+            &key,
+            &serde_json::to_vec(binding).expect("ThreepidBinding::to_vec always works"),
+        )
+    }
+
+    fn remove_threepid_binding(
+        &self,
+        user_id: &UserId,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(medium.as_str().as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(address.as_bytes());
+
+        self.userthreepid_binding.remove(&key)
+    }
+
+    fn threepid_bindings<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<ThreepidBinding>> + 'a> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Box::new(
+            self.userthreepid_binding
+                .scan_prefix(prefix)
+                .map(|(_, bytes)| {
+                    serde_json::from_slice(&bytes).map_err(|_| {
+                        Error::bad_database("Invalid third party identifier binding in db.")
+                    })
+                }),
+        )
+    }
 }
 
-impl KeyValueDatabase {}
+impl KeyValueDatabase {
+    /// Removes a to-device event and its entries in the age index.
+    fn remove_to_device_event(&self, key: &[u8]) -> Result<()> {
+        self.todeviceid_events.remove(key)?;
+
+        if let Some(ts) = self.todeviceid_ts.get(key)? {
+            let mut ts_key = ts;
+            ts_key.extend_from_slice(key);
+            self.todevicets_id.remove(&ts_key)?;
+            self.todeviceid_ts.remove(key)?;
+        }
+
+        Ok(())
+    }
+}
 
 /// Will only return with Some(username) if the password was not empty and the
 /// username could be successfully parsed.
@@ -0,0 +1,389 @@
+use std::{
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::{trace_span, warn};
+
+use super::abstraction::{KeyValueDatabaseEngine, KvTree};
+use crate::{Config, Error, Result};
+
+/// Hits and misses of one of the in-memory caches of the database
+pub(crate) struct CacheMetrics {
+    name: &'static str,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheMetrics {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) static PDU_CACHE: CacheMetrics = CacheMetrics::new("pdu_cache");
+pub(crate) static AUTH_CHAIN_CACHE: CacheMetrics = CacheMetrics::new("auth_chain_cache");
+pub(crate) static SHORTEVENTID_CACHE: CacheMetrics = CacheMetrics::new("shorteventid_cache");
+pub(crate) static EVENTIDSHORT_CACHE: CacheMetrics = CacheMetrics::new("eventidshort_cache");
+pub(crate) static STATEKEYSHORT_CACHE: CacheMetrics = CacheMetrics::new("statekeyshort_cache");
+pub(crate) static SHORTSTATEKEY_CACHE: CacheMetrics = CacheMetrics::new("shortstatekey_cache");
+
+static CACHES: [&CacheMetrics; 6] = [
+    &PDU_CACHE,
+    &AUTH_CHAIN_CACHE,
+    &SHORTEVENTID_CACHE,
+    &EVENTIDSHORT_CACHE,
+    &STATEKEYSHORT_CACHE,
+    &SHORTSTATEKEY_CACHE,
+];
+
+/// Number and total duration of one kind of operation on a tree
+#[derive(Default)]
+struct OperationMetrics {
+    count: AtomicU64,
+    micros: AtomicU64,
+}
+
+impl OperationMetrics {
+    fn record(&self, elapsed: Duration) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct TreeMetrics {
+    get: OperationMetrics,
+    get_misses: AtomicU64,
+    insert: OperationMetrics,
+    remove: OperationMetrics,
+    increment: OperationMetrics,
+    /// Only the creation of iterators is timed, as they are consumed lazily
+    iter: OperationMetrics,
+    rows_read: AtomicU64,
+}
+
+/// Metrics of all trees opened through a [`MeteredEngine`]
+static TREES: Mutex<Vec<(&'static str, Arc<TreeMetrics>)>> = Mutex::new(Vec::new());
+
+/// Database engine that counts the operations on the trees of another engine, runs them in
+/// tracing spans and logs the slow ones
+pub(crate) struct MeteredEngine {
+    inner: Arc<dyn KeyValueDatabaseEngine>,
+    slow_threshold: Option<Duration>,
+}
+
+impl MeteredEngine {
+    pub(crate) fn new(inner: Arc<dyn KeyValueDatabaseEngine>, config: &Config) -> Self {
+        Self {
+            inner,
+            slow_threshold: config
+                .db_slow_operation_threshold_ms
+                .map(Duration::from_millis),
+        }
+    }
+}
+
+impl KeyValueDatabaseEngine for MeteredEngine {
+    fn open(_config: &Config) -> Result<Self> {
+        Err(Error::BadConfig(
+            "The metered database engine can only wrap another engine.",
+        ))
+    }
+
+    fn open_tree(&self, name: &'static str) -> Result<Arc<dyn KvTree>> {
+        let metrics = Arc::new(TreeMetrics::default());
+        TREES.lock().unwrap().push((name, Arc::clone(&metrics)));
+
+        Ok(Arc::new(MeteredTree {
+            name,
+            inner: self.inner.open_tree(name)?,
+            metrics,
+            slow_threshold: self.slow_threshold,
+        }))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn cleanup(&self) -> Result<()> {
+        self.inner.cleanup()
+    }
+
+    fn memory_usage(&self) -> Result<String> {
+        self.inner.memory_usage()
+    }
+
+    fn clear_caches(&self) {
+        self.inner.clear_caches()
+    }
+}
+
+struct MeteredTree {
+    name: &'static str,
+    inner: Arc<dyn KvTree>,
+    metrics: Arc<TreeMetrics>,
+    slow_threshold: Option<Duration>,
+}
+
+impl MeteredTree {
+    /// Runs an operation on the tree in a span, records it and logs it if it was slow.
+    fn timed<T>(&self, op: &'static str, metrics: &OperationMetrics, f: impl FnOnce() -> T) -> T {
+        let _span = trace_span!("db", tree = self.name, op).entered();
+
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+
+        metrics.record(elapsed);
+        self.log_if_slow(op, elapsed);
+
+        result
+    }
+
+    /// Logs the operation if it took longer than the threshold. Keys are not logged, as they can
+    /// contain user IDs.
+    fn log_if_slow(&self, op: &str, elapsed: Duration) {
+        if self
+            .slow_threshold
+            .is_some_and(|threshold| elapsed >= threshold)
+        {
+            warn!("Slow database {} on {} took {:?}", op, self.name, elapsed);
+        }
+    }
+
+    fn metered_iter<'a>(
+        &'a self,
+        op: &'static str,
+        create: impl FnOnce() -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+        let inner = self.timed(op, &self.metrics.iter, create);
+
+        Box::new(MeteredIter {
+            inner,
+            tree: self,
+            op,
+            busy: Duration::ZERO,
+            rows: 0,
+        })
+    }
+}
+
+impl KvTree for MeteredTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.timed("get", &self.metrics.get, || self.inner.get(key));
+
+        if matches!(value, Ok(None)) {
+            self.metrics.get_misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        value
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.timed("insert", &self.metrics.insert, || {
+            self.inner.insert(key, value)
+        })
+    }
+
+    fn insert_batch(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+        self.timed("insert_batch", &self.metrics.insert, || {
+            self.inner.insert_batch(iter)
+        })
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        self.timed("remove", &self.metrics.remove, || self.inner.remove(key))
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+        self.metered_iter("iter", || self.inner.iter())
+    }
+
+    fn iter_from<'a>(
+        &'a self,
+        from: &[u8],
+        backwards: bool,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+        self.metered_iter("iter_from", || self.inner.iter_from(from, backwards))
+    }
+
+    fn increment(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.timed("increment", &self.metrics.increment, || {
+            self.inner.increment(key)
+        })
+    }
+
+    fn increment_batch(&self, iter: &mut dyn Iterator<Item = Vec<u8>>) -> Result<()> {
+        self.timed("increment_batch", &self.metrics.increment, || {
+            self.inner.increment_batch(iter)
+        })
+    }
+
+    fn scan_prefix<'a>(
+        &'a self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+        self.metered_iter("scan_prefix", || self.inner.scan_prefix(prefix))
+    }
+
+    fn watch_prefix<'a>(&'a self, prefix: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        self.inner.watch_prefix(prefix)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.inner.clear()
+    }
+}
+
+/// Iterator over a metered tree that counts the rows it reads. It logs when it was slow once it is
+/// dropped. Only the time spent reading rows counts, not the work the caller does between them.
+struct MeteredIter<'a> {
+    inner: Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>,
+    tree: &'a MeteredTree,
+    op: &'static str,
+    busy: Duration,
+    rows: u64,
+}
+
+impl Iterator for MeteredIter<'_> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = Instant::now();
+        let item = self.inner.next();
+        self.busy += start.elapsed();
+
+        if item.is_some() {
+            self.rows += 1;
+        }
+        item
+    }
+}
+
+impl Drop for MeteredIter<'_> {
+    fn drop(&mut self) {
+        self.tree
+            .metrics
+            .rows_read
+            .fetch_add(self.rows, Ordering::Relaxed);
+        self.tree.log_if_slow(self.op, self.busy);
+    }
+}
+
+/// Renders the metrics of the caches and, if the database is metered, its trees in the
+/// Prometheus text format.
+pub(crate) fn render() -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP conduwuit_cache_hits_total Lookups that found the entry in the cache\n");
+    out.push_str("# TYPE conduwuit_cache_hits_total counter\n");
+    for cache in CACHES {
+        let hits = cache.hits.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "conduwuit_cache_hits_total{{cache=\"{}\"}} {hits}",
+            cache.name
+        )
+        .unwrap();
+    }
+
+    out.push_str("# HELP conduwuit_cache_misses_total Lookups that had to go to the database\n");
+    out.push_str("# TYPE conduwuit_cache_misses_total counter\n");
+    for cache in CACHES {
+        let misses = cache.misses.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "conduwuit_cache_misses_total{{cache=\"{}\"}} {misses}",
+            cache.name
+        )
+        .unwrap();
+    }
+
+    let trees = TREES.lock().unwrap();
+    if trees.is_empty() {
+        return out;
+    }
+
+    out.push_str("# HELP conduwuit_db_operations_total Operations on a database tree\n");
+    out.push_str("# TYPE conduwuit_db_operations_total counter\n");
+    for (name, metrics) in trees.iter() {
+        for (op, metrics) in operations(metrics) {
+            let count = metrics.count.load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "conduwuit_db_operations_total{{tree=\"{name}\",op=\"{op}\"}} {count}"
+            )
+            .unwrap();
+        }
+    }
+
+    out.push_str(
+        "# HELP conduwuit_db_operation_seconds_total Time spent in operations on a database tree\n",
+    );
+    out.push_str("# TYPE conduwuit_db_operation_seconds_total counter\n");
+    for (name, metrics) in trees.iter() {
+        for (op, metrics) in operations(metrics) {
+            let seconds = metrics.micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+            writeln!(
+                out,
+                "conduwuit_db_operation_seconds_total{{tree=\"{name}\",op=\"{op}\"}} {seconds}"
+            )
+            .unwrap();
+        }
+    }
+
+    out.push_str("# HELP conduwuit_db_get_misses_total Reads of keys that don't exist in a tree\n");
+    out.push_str("# TYPE conduwuit_db_get_misses_total counter\n");
+    for (name, metrics) in trees.iter() {
+        let misses = metrics.get_misses.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "conduwuit_db_get_misses_total{{tree=\"{name}\"}} {misses}"
+        )
+        .unwrap();
+    }
+
+    out.push_str("# HELP conduwuit_db_rows_read_total Rows read by iterating over a tree\n");
+    out.push_str("# TYPE conduwuit_db_rows_read_total counter\n");
+    for (name, metrics) in trees.iter() {
+        let rows = metrics.rows_read.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "conduwuit_db_rows_read_total{{tree=\"{name}\"}} {rows}"
+        )
+        .unwrap();
+    }
+
+    out
+}
+
+fn operations(metrics: &TreeMetrics) -> [(&'static str, &OperationMetrics); 5] {
+    [
+        ("get", &metrics.get),
+        ("insert", &metrics.insert),
+        ("remove", &metrics.remove),
+        ("increment", &metrics.increment),
+        ("iter", &metrics.iter),
+    ]
+}
@@ -1,8 +1,11 @@
 pub(crate) mod abstraction;
 pub(crate) mod key_value;
+pub(crate) mod metrics;
 
 use crate::{
-    service::rooms::{edus::presence::presence_handler, timeline::PduCount},
+    service::rooms::{
+        edus::presence::presence_handler, timeline::PduCount, user::LoggedNotification,
+    },
     services, utils, Config, Error, PduEvent, Result, Services, SERVICES,
 };
 use abstraction::{KeyValueDatabaseEngine, KvTree};
@@ -22,13 +25,14 @@ use ruma::{
     UserId,
 };
 use serde::Deserialize;
+use sha2::Digest;
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     fs::{self},
     io::Write,
     mem::size_of,
     path::Path,
-    sync::{Arc, Mutex, RwLock},
+    sync::{atomic, Arc, Mutex, RwLock},
     time::Duration,
 };
 use tokio::{sync::mpsc, time::interval};
@@ -41,16 +45,22 @@ pub struct KeyValueDatabase {
     //pub globals: globals::Globals,
     pub(super) global: Arc<dyn KvTree>,
     pub(super) server_signingkeys: Arc<dyn KvTree>,
+    pub(super) server_signedkeys: Arc<dyn KvTree>, // ServerName = latest key response as signed by the server
+    pub(super) blockedservernames: Arc<dyn KvTree>, // Servers we don't federate with
 
     //pub users: users::Users,
     pub(super) userid_password: Arc<dyn KvTree>,
     pub(super) userid_displayname: Arc<dyn KvTree>,
     pub(super) userid_avatarurl: Arc<dyn KvTree>,
     pub(super) userid_blurhash: Arc<dyn KvTree>,
+    pub(super) useridpolicyid_termsversion: Arc<dyn KvTree>,
+    pub(super) userid_email: Arc<dyn KvTree>,
+    pub(super) email_userid: Arc<dyn KvTree>,
     pub(super) userdeviceid_token: Arc<dyn KvTree>,
     pub(super) userdeviceid_metadata: Arc<dyn KvTree>, // This is also used to check if a device exists
     pub(super) userid_devicelistversion: Arc<dyn KvTree>, // DevicelistVersion = u64
     pub(super) token_userdeviceid: Arc<dyn KvTree>,
+    pub(super) userdeviceid_tokentimes: Arc<dyn KvTree>, // TokenTimes = Issued + LastUsed
 
     pub(super) onetimekeyid_onetimekeys: Arc<dyn KvTree>, // OneTimeKeyId = UserId + DeviceKeyId
     pub(super) userid_lastonetimekeyupdate: Arc<dyn KvTree>, // LastOneTimeKeyUpdate = Count
@@ -61,8 +71,13 @@ pub struct KeyValueDatabase {
     pub(super) userid_usersigningkeyid: Arc<dyn KvTree>,
 
     pub(super) userfilterid_filter: Arc<dyn KvTree>, // UserFilterId = UserId + FilterId
+    pub(super) oidcsubject_userid: Arc<dyn KvTree>,  // OidcSubject = sub claim of the OIDC provider
+    pub(super) guest_userids: Arc<dyn KvTree>,
+    pub(super) userthreepid_binding: Arc<dyn KvTree>, // UserThreepid = UserId + Medium + Address
 
     pub(super) todeviceid_events: Arc<dyn KvTree>, // ToDeviceId = UserId + DeviceId + Count
+    pub(super) todevicets_id: Arc<dyn KvTree>,     // ToDeviceTs = Timestamp + ToDeviceId
+    pub(super) todeviceid_ts: Arc<dyn KvTree>,     // ToDeviceId -> Timestamp
 
     //pub uiaa: uiaa::Uiaa,
     pub(super) userdevicesessionid_uiaainfo: Arc<dyn KvTree>, // User-interactive authentication
@@ -84,8 +99,11 @@ pub struct KeyValueDatabase {
     pub(super) alias_roomid: Arc<dyn KvTree>,
     pub(super) aliasid_alias: Arc<dyn KvTree>, // AliasId = RoomId + Count
     pub(super) publicroomids: Arc<dyn KvTree>,
+    pub(super) publicroomid_network: Arc<dyn KvTree>, // Network = AppserviceId + NetworkId
 
     pub(super) threadid_userids: Arc<dyn KvTree>, // ThreadId = RoomId + Count
+    pub(super) threadid_activity: Arc<dyn KvTree>,
+    pub(super) roomactivity_threadid: Arc<dyn KvTree>, // RoomActivity = ShortRoomId + Count
 
     pub(super) tokenids: Arc<dyn KvTree>, // TokenId = ShortRoomId + Token + PduIdCount
 
@@ -107,11 +125,21 @@ pub struct KeyValueDatabase {
 
     pub(super) bannedroomids: Arc<dyn KvTree>, // Rooms where local users are not allowed to join
 
+    pub(super) roomid_frozenpowerlevels: Arc<dyn KvTree>, // Power levels of frozen rooms before they were frozen
+
     pub(super) lazyloadedids: Arc<dyn KvTree>, // LazyLoadedIds = UserId + DeviceId + RoomId + LazyLoadedUserId
 
+    pub(super) userdeviceroomid_peekcount: Arc<dyn KvTree>, // PeekCount = u64
+
     pub(super) userroomid_notificationcount: Arc<dyn KvTree>, // NotifyCount = u64
     pub(super) userroomid_highlightcount: Arc<dyn KvTree>,    // HightlightCount = u64
     pub(super) roomuserid_lastnotificationread: Arc<dyn KvTree>, // LastNotificationRead = u64
+    pub(super) roomuserid_lastnotificationupdate: Arc<dyn KvTree>, // LastNotificationUpdate = u64
+    // UserThreadId = UserId + RoomId + ThreadId
+    pub(super) userthreadid_notificationcount: Arc<dyn KvTree>,
+    pub(super) userthreadid_highlightcount: Arc<dyn KvTree>,
+    pub(super) usercount_notification: Arc<dyn KvTree>, // UserCount = UserId + PduCount
+    pub(super) notificationts_usercount: Arc<dyn KvTree>, // NotificationTs = Timestamp + UserCount
 
     /// Remember the current state hash of a room.
     pub(super) roomid_shortstatehash: Arc<dyn KvTree>,
@@ -136,9 +164,15 @@ pub struct KeyValueDatabase {
     /// Any pdu that has passed the steps 1-8 in the incoming event /federation/send/txn.
     pub(super) eventid_outlierpdu: Arc<dyn KvTree>,
     pub(super) softfailedeventids: Arc<dyn KvTree>,
+    pub(super) roomid_softfailedeventid: Arc<dyn KvTree>, // RoomId + EventId -> time of soft fail
 
     /// ShortEventId + ShortEventId -> ().
     pub(super) tofrom_relation: Arc<dyn KvTree>,
+    /// Count + Type + 0xff + Key + 0xff + UserId -> how often the user sent the annotation.
+    pub(super) relationannotation_sendercount: Arc<dyn KvTree>,
+    /// Count + Type + 0xff + Key -> number of users that sent the annotation. Count alone -> ()
+    /// once the annotations of the event are counted.
+    pub(super) relationannotation_count: Arc<dyn KvTree>,
     /// RoomId + EventId -> Parent PDU EventId.
     pub(super) referencedevents: Arc<dyn KvTree>,
 
@@ -148,7 +182,11 @@ pub struct KeyValueDatabase {
 
     //pub media: media::Media,
     pub(super) mediaid_file: Arc<dyn KvTree>, // MediaId = MXC + WidthHeight + ContentDisposition + ContentType
+    pub(super) mediaid_contenthash: Arc<dyn KvTree>,
+    pub(super) contenthash_refcount: Arc<dyn KvTree>,
     pub(super) url_previews: Arc<dyn KvTree>,
+    pub(super) userid_mediaupload: Arc<dyn KvTree>, // MediaUpload = Timestamp + Size
+    pub(super) quarantined_media: Arc<dyn KvTree>,
     //pub key_backups: key_backups::KeyBackups,
     pub(super) backupid_algorithm: Arc<dyn KvTree>, // BackupId = UserId + Version(Count)
     pub(super) backupid_etag: Arc<dyn KvTree>,      // BackupId = UserId + Version(Count)
@@ -158,11 +196,14 @@ pub struct KeyValueDatabase {
     pub(super) userdevicetxnid_response: Arc<dyn KvTree>, // Response can be empty (/sendToDevice) or the event id (/send)
     //pub sending: sending::Sending,
     pub(super) servername_educount: Arc<dyn KvTree>, // EduCount: Count of last EDU sync
+    pub(super) servername_destinationstats: Arc<dyn KvTree>, // DestinationStats = JSON
     pub(super) servernameevent_data: Arc<dyn KvTree>, // ServernameEvent = (+ / $)SenderKey / ServerName / UserId + PduId / Id (for edus), Data = EDU content
     pub(super) servercurrentevent_data: Arc<dyn KvTree>, // ServerCurrentEvents = (+ / $)ServerName / UserId + PduId / Id (for edus), Data = EDU content
 
     //pub appservice: appservice::Appservice,
     pub(super) id_appserviceregistrations: Arc<dyn KvTree>,
+    pub(super) appserviceid_lasttxnid: Arc<dyn KvTree>, // LastTxnId = u64 of the last acknowledged transaction
+    pub(super) appserviceid_devicelistcount: Arc<dyn KvTree>, // DeviceListCount = Count up to which device list changes were sent
 
     //pub pusher: pusher::PushData,
     pub(super) senderkey_pusher: Arc<dyn KvTree>,
@@ -249,6 +290,13 @@ impl KeyValueDatabase {
             }
         };
 
+        let builder: Arc<dyn KeyValueDatabaseEngine> =
+            if config.enable_metrics || config.db_slow_operation_threshold_ms.is_some() {
+                Arc::new(metrics::MeteredEngine::new(builder, &config))
+            } else {
+                builder
+            };
+
         let (presence_sender, presence_receiver) = mpsc::unbounded_channel();
 
         let db_raw = Box::new(Self {
@@ -257,10 +305,14 @@ impl KeyValueDatabase {
             userid_displayname: builder.open_tree("userid_displayname")?,
             userid_avatarurl: builder.open_tree("userid_avatarurl")?,
             userid_blurhash: builder.open_tree("userid_blurhash")?,
+            useridpolicyid_termsversion: builder.open_tree("useridpolicyid_termsversion")?,
+            userid_email: builder.open_tree("userid_email")?,
+            email_userid: builder.open_tree("email_userid")?,
             userdeviceid_token: builder.open_tree("userdeviceid_token")?,
             userdeviceid_metadata: builder.open_tree("userdeviceid_metadata")?,
             userid_devicelistversion: builder.open_tree("userid_devicelistversion")?,
             token_userdeviceid: builder.open_tree("token_userdeviceid")?,
+            userdeviceid_tokentimes: builder.open_tree("userdeviceid_tokentimes")?,
             onetimekeyid_onetimekeys: builder.open_tree("onetimekeyid_onetimekeys")?,
             userid_lastonetimekeyupdate: builder.open_tree("userid_lastonetimekeyupdate")?,
             keychangeid_userid: builder.open_tree("keychangeid_userid")?,
@@ -269,7 +321,12 @@ impl KeyValueDatabase {
             userid_selfsigningkeyid: builder.open_tree("userid_selfsigningkeyid")?,
             userid_usersigningkeyid: builder.open_tree("userid_usersigningkeyid")?,
             userfilterid_filter: builder.open_tree("userfilterid_filter")?,
+            oidcsubject_userid: builder.open_tree("oidcsubject_userid")?,
+            guest_userids: builder.open_tree("guest_userids")?,
+            userthreepid_binding: builder.open_tree("userthreepid_binding")?,
             todeviceid_events: builder.open_tree("todeviceid_events")?,
+            todevicets_id: builder.open_tree("todevicets_id")?,
+            todeviceid_ts: builder.open_tree("todeviceid_ts")?,
 
             userdevicesessionid_uiaainfo: builder.open_tree("userdevicesessionid_uiaainfo")?,
             userdevicesessionid_uiaarequest: RwLock::new(BTreeMap::new()),
@@ -287,8 +344,11 @@ impl KeyValueDatabase {
             alias_roomid: builder.open_tree("alias_roomid")?,
             aliasid_alias: builder.open_tree("aliasid_alias")?,
             publicroomids: builder.open_tree("publicroomids")?,
+            publicroomid_network: builder.open_tree("publicroomid_network")?,
 
             threadid_userids: builder.open_tree("threadid_userids")?,
+            threadid_activity: builder.open_tree("threadid_activity")?,
+            roomactivity_threadid: builder.open_tree("roomactivity_threadid")?,
 
             tokenids: builder.open_tree("tokenids")?,
 
@@ -308,11 +368,21 @@ impl KeyValueDatabase {
 
             bannedroomids: builder.open_tree("bannedroomids")?,
 
+            roomid_frozenpowerlevels: builder.open_tree("roomid_frozenpowerlevels")?,
+
             lazyloadedids: builder.open_tree("lazyloadedids")?,
 
+            userdeviceroomid_peekcount: builder.open_tree("userdeviceroomid_peekcount")?,
+
             userroomid_notificationcount: builder.open_tree("userroomid_notificationcount")?,
             userroomid_highlightcount: builder.open_tree("userroomid_highlightcount")?,
             roomuserid_lastnotificationread: builder.open_tree("userroomid_highlightcount")?,
+            roomuserid_lastnotificationupdate: builder
+                .open_tree("roomuserid_lastnotificationupdate")?,
+            userthreadid_notificationcount: builder.open_tree("userthreadid_notificationcount")?,
+            userthreadid_highlightcount: builder.open_tree("userthreadid_highlightcount")?,
+            usercount_notification: builder.open_tree("usercount_notification")?,
+            notificationts_usercount: builder.open_tree("notificationts_usercount")?,
 
             statekey_shortstatekey: builder.open_tree("statekey_shortstatekey")?,
             shortstatekey_statekey: builder.open_tree("shortstatekey_statekey")?,
@@ -331,24 +401,36 @@ impl KeyValueDatabase {
 
             eventid_outlierpdu: builder.open_tree("eventid_outlierpdu")?,
             softfailedeventids: builder.open_tree("softfailedeventids")?,
+            roomid_softfailedeventid: builder.open_tree("roomid_softfailedeventid")?,
 
             tofrom_relation: builder.open_tree("tofrom_relation")?,
+            relationannotation_sendercount: builder.open_tree("relationannotation_sendercount")?,
+            relationannotation_count: builder.open_tree("relationannotation_count")?,
             referencedevents: builder.open_tree("referencedevents")?,
             roomuserdataid_accountdata: builder.open_tree("roomuserdataid_accountdata")?,
             roomusertype_roomuserdataid: builder.open_tree("roomusertype_roomuserdataid")?,
             mediaid_file: builder.open_tree("mediaid_file")?,
+            mediaid_contenthash: builder.open_tree("mediaid_contenthash")?,
+            contenthash_refcount: builder.open_tree("contenthash_refcount")?,
             url_previews: builder.open_tree("url_previews")?,
+            userid_mediaupload: builder.open_tree("userid_mediaupload")?,
+            quarantined_media: builder.open_tree("quarantined_media")?,
             backupid_algorithm: builder.open_tree("backupid_algorithm")?,
             backupid_etag: builder.open_tree("backupid_etag")?,
             backupkeyid_backup: builder.open_tree("backupkeyid_backup")?,
             userdevicetxnid_response: builder.open_tree("userdevicetxnid_response")?,
             servername_educount: builder.open_tree("servername_educount")?,
+            servername_destinationstats: builder.open_tree("servername_destinationstats")?,
             servernameevent_data: builder.open_tree("servernameevent_data")?,
             servercurrentevent_data: builder.open_tree("servercurrentevent_data")?,
             id_appserviceregistrations: builder.open_tree("id_appserviceregistrations")?,
+            appserviceid_lasttxnid: builder.open_tree("appserviceid_lasttxnid")?,
+            appserviceid_devicelistcount: builder.open_tree("appserviceid_devicelistcount")?,
             senderkey_pusher: builder.open_tree("senderkey_pusher")?,
             global: builder.open_tree("global")?,
             server_signingkeys: builder.open_tree("server_signingkeys")?,
+            server_signedkeys: builder.open_tree("server_signedkeys")?,
+            blockedservernames: builder.open_tree("blockedservernames")?,
 
             cached_registrations: Arc::new(RwLock::new(HashMap::new())),
             pdu_cache: Mutex::new(LruCache::new(
@@ -404,12 +486,7 @@ impl KeyValueDatabase {
         }
 
         // If the database has any data, perform data migrations before starting
-        // do not increment the db version if the user is not using sha256_media
-        let latest_database_version = if cfg!(feature = "sha256_media") {
-            14
-        } else {
-            13
-        };
+        let latest_database_version = 18;
 
         if services().users.count()? > 0 {
             // MIGRATIONS
@@ -955,6 +1032,143 @@ impl KeyValueDatabase {
                 warn!("Migration: 13 -> 14 finished");
             }
 
+            if services().globals.database_version()? < 15 {
+                // Store media by content hash, so identical files only exist once on disk. This
+                // also replaces the file names of the sha256_media feature.
+                let sha256_file_names = services().globals.database_version()? >= 14;
+                let mut failed = 0_usize;
+                for (key, _) in db.mediaid_file.iter() {
+                    if db.mediaid_contenthash.get(&key)?.is_some() {
+                        continue;
+                    }
+
+                    #[allow(deprecated)]
+                    let old_path = if sha256_file_names {
+                        services().globals.get_media_file_new(&key)
+                    } else {
+                        services().globals.get_media_file(&key)
+                    };
+
+                    let content = match tokio::fs::read(&old_path).await {
+                        Ok(content) => content,
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                            warn!("Migration: Skipping missing media file {:?}", old_path);
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("Migration: Failed to read media file {:?}: {}", old_path, e);
+                            failed += 1;
+                            continue;
+                        }
+                    };
+
+                    let hash = sha2::Sha256::digest(&content).to_vec();
+                    let refcount =
+                        utils::u64_from_bytes(&db.contenthash_refcount.increment(&hash)?)
+                            .map_err(|_| Error::bad_database("Invalid refcount in db."))?;
+
+                    let moved = if refcount == 1 {
+                        let path = services().globals.get_media_content_file(&hash);
+                        tokio::fs::rename(&old_path, &path).await
+                    } else {
+                        tokio::fs::remove_file(&old_path).await
+                    };
+                    if let Err(e) = moved {
+                        error!("Migration: Failed to move media file {:?}: {}", old_path, e);
+                        services().media.db.decrement_content_refcount(&hash)?;
+                        failed += 1;
+                        continue;
+                    }
+                    db.mediaid_contenthash.insert(&key, &hash)?;
+                }
+
+                if failed > 0 {
+                    // Migrated files are skipped when this runs again, so fixing the files and
+                    // restarting continues where this stopped
+                    return Err(Error::bad_database(
+                        "Migration 14 -> 15 failed for some media files, see the log for details. Fix them and restart.",
+                    ));
+                }
+
+                services().globals.bump_database_version(15)?;
+
+                warn!("Migration: 14 -> 15 finished");
+            }
+
+            if services().globals.database_version()? < 16 {
+                // Index threads by their latest activity, which is the latest reply bundled with
+                // the root or the root itself
+                for (root_id, _) in db.threadid_userids.iter() {
+                    let latest_reply = services()
+                        .rooms
+                        .timeline
+                        .get_pdu_json_from_id(&root_id)?
+                        .and_then(|root| {
+                            serde_json::to_value(root.get("unsigned")?).ok()?["m.relations"]
+                                ["m.thread"]["latest_event"]["event_id"]
+                                .as_str()
+                                .and_then(|event_id| EventId::parse(event_id).ok())
+                        });
+
+                    let activity = match latest_reply
+                        .map(|event_id| services().rooms.timeline.get_pdu_count(&event_id))
+                        .transpose()?
+                        .flatten()
+                    {
+                        Some(PduCount::Normal(count)) => count,
+                        _ => utils::u64_from_bytes(&root_id[size_of::<u64>()..]).map_err(|_| {
+                            Error::bad_database("Invalid pduid in threadid_userids.")
+                        })?,
+                    };
+
+                    services()
+                        .rooms
+                        .threads
+                        .db
+                        .update_thread_activity(&root_id, activity)?;
+                }
+
+                services().globals.bump_database_version(16)?;
+
+                warn!("Migration: 15 -> 16 finished");
+            }
+
+            if services().globals.database_version()? < 17 {
+                // Index undelivered to-device messages by age if they expire, existing ones count
+                // as new
+                if services().globals.to_device_message_max_age_s().is_some() {
+                    let now = utils::millis_since_unix_epoch().to_be_bytes();
+                    for (key, _) in db.todeviceid_events.iter() {
+                        let mut ts_key = now.to_vec();
+                        ts_key.extend_from_slice(&key);
+                        db.todevicets_id.insert(&ts_key, &[])?;
+                        db.todeviceid_ts.insert(&key, &now)?;
+                    }
+                }
+
+                services().globals.bump_database_version(17)?;
+
+                warn!("Migration: 16 -> 17 finished");
+            }
+
+            if services().globals.database_version()? < 18 {
+                // Index logged notifications by age, so old ones can be deleted
+                for (key, value) in db.usercount_notification.iter() {
+                    let Ok(notification) = serde_json::from_slice::<LoggedNotification>(&value)
+                    else {
+                        continue;
+                    };
+
+                    let mut ts_key = u64::from(notification.ts.get()).to_be_bytes().to_vec();
+                    ts_key.extend_from_slice(&key);
+                    db.notificationts_usercount.insert(&ts_key, &[])?;
+                }
+
+                services().globals.bump_database_version(18)?;
+
+                warn!("Migration: 17 -> 18 finished");
+            }
+
             assert_eq!(
                 services().globals.database_version().unwrap(),
                 latest_database_version
@@ -1049,6 +1263,14 @@ impl KeyValueDatabase {
         if services().globals.allow_check_for_updates() {
             Self::start_check_for_updates_task();
         }
+        if let Some(threshold) = services().globals.sync_low_memory_threshold_mb() {
+            Self::start_memory_check_task(threshold);
+        }
+        if services().globals.report_stats_endpoint().is_some() {
+            Self::start_report_stats_task();
+        } else if services().globals.config.report_stats {
+            warn!("report_stats is enabled but report_stats_endpoint is not set, not reporting");
+        }
         if services().globals.allow_local_presence() {
             Self::start_presence_handler(presence_receiver).await;
         }
@@ -1073,24 +1295,28 @@ impl KeyValueDatabase {
             let mut i = interval(timer_interval);
             loop {
                 i.tick().await;
-                let _ = Self::try_handle_updates().await;
+                if let Err(e) = Self::try_handle_updates().await {
+                    warn!("Failed to check for updates: {e}");
+                }
             }
         });
     }
 
+    /// Fetches the announcements from the configured update channel and posts the new ones in the
+    /// admin room. Security announcements mention the whole room.
     async fn try_handle_updates() -> Result<()> {
-        let response = services()
-            .globals
-            .default_client()
-            .get("https://pupbrain.dev/check-for-updates/stable")
-            .send()
-            .await?;
+        let url = services().globals.config.check_for_updates_url();
+        let response = services().globals.default_client().get(&url).send().await?;
 
         #[derive(Deserialize)]
         struct CheckForUpdatesResponseEntry {
             id: u64,
             date: String,
             message: String,
+            /// Version of the release the announcement is about
+            version: Option<String>,
+            #[serde(default)]
+            security: bool,
         }
         #[derive(Deserialize)]
         struct CheckForUpdatesResponse {
@@ -1099,7 +1325,7 @@ impl KeyValueDatabase {
 
         let response = serde_json::from_str::<CheckForUpdatesResponse>(&response.text().await?)
             .map_err(|e| {
-                error!("Bad check for updates response: {e}");
+                error!("Bad check for updates response from {url}: {e}");
                 Error::BadServerResponse("Bad version check response")
             })?;
 
@@ -1107,13 +1333,30 @@ impl KeyValueDatabase {
         for update in response.updates {
             last_update_id = last_update_id.max(update.id);
             if update.id > services().globals.last_check_for_updates_id()? {
-                error!("{}", update.message);
+                let release = update
+                    .version
+                    .map(|version| format!(" about conduwuit {version}"))
+                    .unwrap_or_default();
+
+                let message = if update.security {
+                    warn!("Security announcement{release}: {}", update.message);
+                    format!(
+                        "@room: security announcement{release} from the conduwuit puppy. it was \
+                         sent on '{}':\n\n{}",
+                        update.date, update.message
+                    )
+                } else {
+                    info!("Announcement{release}: {}", update.message);
+                    format!(
+                        "the following is a message{release} from the conduwuit puppy. it was \
+                         sent on '{}':\n\n{}",
+                        update.date, update.message
+                    )
+                };
+
                 services()
                     .admin
-                    .send_message(RoomMessageEventContent::text_plain(format!(
-                    "@room: the following is a message from the conduwuit puppy. it was sent on '{}':\n\n{}",
-                    update.date, update.message
-                )))
+                    .send_message(RoomMessageEventContent::text_plain(message));
             }
         }
         services()
@@ -1123,6 +1366,74 @@ impl KeyValueDatabase {
         Ok(())
     }
 
+    /// Checks every few seconds whether less memory is available than the threshold, instead of
+    /// reading it on every sync request.
+    #[tracing::instrument]
+    pub fn start_memory_check_task(threshold_mb: u64) {
+        tokio::spawn(async move {
+            let timer_interval = Duration::from_secs(5);
+            let mut i = interval(timer_interval);
+            loop {
+                i.tick().await;
+                let low = utils::available_memory()
+                    .map_or(false, |available| available < threshold_mb * 1024 * 1024);
+                services()
+                    .globals
+                    .memory_low
+                    .store(low, atomic::Ordering::Relaxed);
+            }
+        });
+    }
+
+    #[tracing::instrument]
+    pub fn start_report_stats_task() {
+        tokio::spawn(async move {
+            let timer_interval = Duration::from_secs(60 * 60 * 24);
+            let mut i = interval(timer_interval);
+            loop {
+                i.tick().await;
+                if let Err(e) = Self::try_report_stats().await {
+                    warn!("Failed to report usage statistics: {e}");
+                }
+            }
+        });
+    }
+
+    /// Sends anonymous statistics about this deployment to `report_stats_endpoint`. Nothing that
+    /// identifies the server or its users is included.
+    async fn try_report_stats() -> Result<()> {
+        let Some(endpoint) = services().globals.report_stats_endpoint() else {
+            return Ok(());
+        };
+
+        let stats = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "database_backend": services().globals.config.database_backend,
+            "local_users": services().users.active_local_users()?,
+            "rooms": services().rooms.metadata.iter_ids().count(),
+        });
+
+        let response = services()
+            .globals
+            .default_client()
+            .post(endpoint)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(stats.to_string())
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            warn!("Usage statistics endpoint returned {}", response.status());
+            return Err(Error::BadServerResponse(
+                "Usage statistics endpoint returned an error",
+            ));
+        }
+
+        debug!("Reported usage statistics: {stats}");
+
+        Ok(())
+    }
+
     #[tracing::instrument]
     pub async fn start_cleanup_task() {
         #[cfg(unix)]
@@ -1134,11 +1445,16 @@ impl KeyValueDatabase {
 
         fn perform_cleanup() {
             let start = Instant::now();
+            if let Err(e) = services().rooms.user.remove_expired_notifications() {
+                error!(target: "database-cleanup", "Failed to remove expired notifications: {}", e);
+            }
+            if let Err(e) = services().users.remove_expired_to_device_events() {
+                error!(target: "database-cleanup", "Failed to remove expired to-device events: {}", e);
+            }
             if let Err(e) = services().globals.cleanup() {
                 error!(target: "database-cleanup", "Ran into an error during cleanup: {}", e);
-            } else {
-                debug!(target: "database-cleanup", "Finished cleanup in {:#?}.", start.elapsed());
             }
+            debug!(target: "database-cleanup", "Finished cleanup in {:#?}.", start.elapsed());
         }
 
         tokio::spawn(async move {
@@ -6,11 +6,11 @@ mod utils;
 
 use std::sync::RwLock;
 
-pub use api::ruma_wrapper::{Ruma, RumaResponse};
+pub use api::ruma_wrapper::{Authenticated, Ruma, RumaResponse};
 pub use config::Config;
 pub use database::KeyValueDatabase;
 pub use service::{pdu::PduEvent, Services};
-pub use utils::error::{Error, Result};
+pub use utils::error::{Error, PduRejection, Result};
 
 pub static SERVICES: RwLock<Option<&'static Services<'static>>> = RwLock::new(None);
 
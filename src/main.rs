@@ -1,37 +1,51 @@
 use std::{
-    fs::Permissions, future::Future, io, net::SocketAddr, os::unix::fs::PermissionsExt,
-    sync::atomic, time::Duration,
+    convert::Infallible, fs::Permissions, future::Future, io, net::SocketAddr,
+    os::unix::fs::PermissionsExt, path::PathBuf, sync::atomic, time::Duration,
 };
 
 use axum::{
     extract::{DefaultBodyLimit, FromRequestParts, MatchedPath},
     response::IntoResponse,
-    routing::{get, on, MethodFilter},
+    routing::{get, on, post, MethodFilter, MethodRouter},
     Router,
 };
-use axum_server::{bind, bind_rustls, tls_rustls::RustlsConfig, Handle as ServerHandle};
-use conduit::api::{client_server, server_server};
+use axum_server::{
+    accept::DefaultAcceptor,
+    from_tcp,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+    Handle as ServerHandle,
+};
+use conduit::api::{
+    client_server,
+    proxy_protocol::{self, ProxiedUnixStream, ProxyProtocolAcceptor, TrustedProxies},
+    request_id::{self, REQUEST_ID_HEADER},
+    server_server,
+};
 use figment::{
     providers::{Env, Format, Toml},
     Figment,
 };
+use futures_util::{future, FutureExt};
 use http::{
-    header::{self, HeaderName},
+    header::{self, HeaderName, HeaderValue},
     Method, StatusCode, Uri,
 };
-use hyper::Server;
-use hyperlocal::SocketIncoming;
+use hyper::{service::make_service_fn, Server};
 use ruma::api::{
     client::{
         error::{Error as RumaError, ErrorBody, ErrorKind},
+        media::{create_content, get_content, get_content_as_filename},
+        sync::sync_events,
         uiaa::UiaaResponse,
     },
-    IncomingRequest,
+    IncomingRequest, Metadata,
 };
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{net::UnixListener, signal, sync::oneshot};
 use tower::ServiceBuilder;
 use tower_http::{
-    cors::{self, CorsLayer},
+    add_extension::AddExtension,
+    cors::{AllowHeaders, AllowOrigin, CorsLayer},
     trace::{DefaultOnFailure, TraceLayer},
     ServiceBuilderExt as _,
 };
@@ -40,7 +54,7 @@ use tracing_subscriber::{prelude::*, EnvFilter};
 
 use tokio::sync::oneshot::Sender;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 pub use conduit::*; // Re-export everything from the library crate
 
@@ -53,11 +67,34 @@ static GLOBAL: Jemalloc = Jemalloc;
 
 #[derive(Parser)]
 #[clap(version, about, long_about = None)]
-struct Args;
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Maintenance tasks that run instead of the server
+#[derive(Subcommand)]
+enum Command {
+    /// Copy all media files and their metadata into a directory
+    ///
+    /// The export can be imported into another server or storage backend with `media-import`.
+    MediaExport { dir: PathBuf },
+
+    /// Import media files and their metadata from a directory created by `media-export`
+    MediaImport { dir: PathBuf },
+
+    /// Check that every timeline PDU has state, that short event IDs resolve and that room aliases
+    /// match the alias lists of rooms
+    CheckDatabase {
+        /// Add missing reverse mappings and remove dangling entries
+        #[arg(long)]
+        repair: bool,
+    },
+}
 
 #[tokio::main]
 async fn main() {
-    Args::parse();
+    let args = Args::parse();
     // Initialize config
     let raw_config =
         Figment::new()
@@ -151,6 +188,11 @@ async fn main() {
     };
     info!("Database took {:?} to load", db_load_time.elapsed());
 
+    if let Some(command) = args.command {
+        run_command(command).await;
+        return;
+    }
+
     let config = &services().globals.config;
 
     /* ad-hoc config validation/checks */
@@ -171,6 +213,12 @@ async fn main() {
         return;
     }
 
+    // check if the user specified a registration shared secret as `""`
+    if config.registration_shared_secret == Some(String::new()) {
+        error!("Registration shared secret was specified but is empty (\"\")");
+        return;
+    }
+
     if config.max_request_size < 4096 {
         error!(?config.max_request_size, "Max request size is less than 4KB. Please increase it.");
     }
@@ -184,6 +232,7 @@ async fn main() {
     if config.allow_registration
         && !config.yes_i_am_very_very_sure_i_want_an_open_registration_server_prone_to_abuse
         && config.registration_token.is_none()
+        && !config.registration_requires_email
     {
         error!("!! You have `allow_registration` enabled without a token configured in your config which means you are allowing ANYONE to register on your conduwuit instance without any 2nd-step (e.g. registration token).\n
         If this is not the intended behaviour, please set a registration token with the `registration_token` config option.\n
@@ -195,11 +244,43 @@ async fn main() {
     if config.allow_registration
         && config.yes_i_am_very_very_sure_i_want_an_open_registration_server_prone_to_abuse
         && config.registration_token.is_none()
+        && !config.registration_requires_email
     {
         warn!("Open registration is enabled via setting `yes_i_am_very_very_sure_i_want_an_open_registration_server_prone_to_abuse` and `allow_registration` to true without a registration token configured. You are expected to be aware of the risks now.\n
         If this is not the desired behaviour, please set a registration token.");
     }
 
+    if config.jwt.is_some() && config.jwt_secret.is_some() {
+        error!("`jwt_secret` is replaced by the `jwt` section, please only set `secret` there.");
+        return;
+    }
+
+    if config
+        .jwt_config()
+        .map_or(false, |jwt| jwt.key_sources() != 1)
+    {
+        error!("JWT login requires exactly one of `secret`, `public_key_file` or `jwks_url`.");
+        return;
+    }
+
+    if config.registration_requires_email && config.smtp.is_none() {
+        error!("Requiring a verified email address for registration requires `smtp` to be set.");
+        return;
+    }
+
+    if cors_layer(config).is_err() {
+        return;
+    }
+
+    if TrustedProxies::parse(&config.proxy_protocol_trusted_proxies).is_err() {
+        return;
+    }
+
+    if config.oidc_issuer.is_some() && config.oidc_introspection_endpoint.is_none() {
+        error!("Delegated OIDC authentication requires `oidc_introspection_endpoint` to be set.");
+        return;
+    }
+
     if config.allow_outgoing_presence && !config.allow_local_presence {
         error!("Outgoing presence requires allowing local presence. Please enable \"allow_outgoing_presence\".");
         return;
@@ -242,15 +323,33 @@ async fn main() {
     }
 }
 
+async fn run_command(command: Command) {
+    match command {
+        Command::MediaExport { dir } => match services().media.export(&dir).await {
+            Ok(count) => info!("Exported {} media files to {}", count, dir.display()),
+            Err(error) => error!(?error, "Media export failed"),
+        },
+        Command::MediaImport { dir } => match services().media.import(&dir).await {
+            Ok(count) => info!("Imported {} media files from {}", count, dir.display()),
+            Err(error) => error!(?error, "Media import failed"),
+        },
+        Command::CheckDatabase { repair } => match services().globals.check_database(repair) {
+            Ok(check) => info!(
+                "Found {} database inconsistencies, repaired {}",
+                check.found, check.repaired
+            ),
+            Err(error) => error!(?error, "Database check failed"),
+        },
+    }
+}
+
 async fn run_server() -> io::Result<()> {
     let config = &services().globals.config;
-    let addr = SocketAddr::from((config.address, config.port));
-
-    let x_requested_with = HeaderName::from_static("x-requested-with");
 
     let middlewares = ServiceBuilder::new()
         .sensitive_headers([header::AUTHORIZATION])
         .layer(axum::middleware::from_fn(spawn_task))
+        .layer(axum::middleware::from_fn(add_request_id))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|request: &http::Request<_>| {
@@ -259,46 +358,35 @@ async fn run_server() -> io::Result<()> {
                     } else {
                         request.uri().path()
                     };
-
-                    tracing::info_span!("http_request", %path)
+                    let request_id = request_id::current().unwrap_or_default();
+
+                    // The user or origin server is recorded once the request is authenticated
+                    tracing::info_span!(
+                        "http_request",
+                        %path,
+                        %request_id,
+                        user = tracing::field::Empty,
+                        origin = tracing::field::Empty,
+                    )
                 })
                 .on_failure(DefaultOnFailure::new().level(Level::INFO)),
         )
         .layer(axum::middleware::from_fn(unrecognized_method))
-        .layer(
-            CorsLayer::new()
-                .allow_origin(cors::Any)
-                .allow_methods([
-                    Method::GET,
-                    Method::HEAD,
-                    Method::POST,
-                    Method::PUT,
-                    Method::DELETE,
-                    Method::OPTIONS,
-                ])
-                .allow_headers([
-                    header::ORIGIN,
-                    x_requested_with,
-                    header::CONTENT_TYPE,
-                    header::ACCEPT,
-                    header::AUTHORIZATION,
-                ])
-                .max_age(Duration::from_secs(86400)),
-        )
+        .layer(cors_layer(config).expect("CORS config was checked at startup"))
+        // The Ruma extractor checks the limit of the API a request belongs to
         .layer(DefaultBodyLimit::max(
             config
                 .max_request_size
+                .max(config.max_federation_request_size.unwrap_or_default())
                 .try_into()
                 .expect("failed to convert max request size"),
         ));
 
     let app = if cfg!(feature = "zstd_compression") && config.zstd_compression {
         debug!("zstd body compression is enabled");
-        routes()
-            .layer(middlewares.compression())
-            .into_make_service()
+        routes().layer(middlewares.compression())
     } else {
-        routes().layer(middlewares).into_make_service()
+        routes().layer(middlewares)
     };
 
     let handle = ServerHandle::new();
@@ -324,13 +412,18 @@ async fn run_server() -> io::Result<()> {
         tokio::fs::set_permissions(path, Permissions::from_mode(octal_perms))
             .await
             .unwrap();
-        let socket = SocketIncoming::from_listener(listener);
+        let incoming = proxy_protocol::unix_incoming(listener, config.proxy_protocol);
 
         #[cfg(feature = "systemd")]
         let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
 
         info!("Listening at {:?}", path);
-        let server = Server::builder(socket).serve(app);
+        let server = Server::builder(incoming).serve(make_service_fn(
+            move |connection: &ProxiedUnixStream| {
+                let service = AddExtension::new(app.clone(), connection.addr);
+                async move { Ok::<_, Infallible>(service) }
+            },
+        ));
         let graceful = server.with_graceful_shutdown(async {
             rx.await.ok();
         });
@@ -339,32 +432,122 @@ async fn run_server() -> io::Result<()> {
             error!("Server error: {:?}", e);
         }
     } else {
-        match &config.tls {
-            Some(tls) => {
-                let conf = RustlsConfig::from_pem_file(&tls.certs, &tls.key).await?;
-                let server = bind_rustls(addr, conf).handle(handle).serve(app);
-
-                #[cfg(feature = "systemd")]
-                let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
+        let addrs = config.listen_addrs();
+        let trusted_proxies = config.proxy_protocol.then(|| {
+            TrustedProxies::parse(&config.proxy_protocol_trusted_proxies)
+                .expect("trusted proxies were checked at startup")
+        });
+        let acceptor = ProxyProtocolAcceptor::new(DefaultAcceptor::new(), trusted_proxies);
+        let tls = match &config.tls {
+            Some(tls) => Some(RustlsConfig::from_pem_file(&tls.certs, &tls.key).await?),
+            None => None,
+        };
 
-                info!("Listening on {}", addr);
-                server.await?
-            }
-            None => {
-                let server = bind(addr).handle(handle).serve(app);
+        let mut servers = Vec::new();
+        for &addr in &addrs {
+            let server = from_tcp(bind_tcp(addr, &addrs)?).handle(handle.clone());
+            let make_service = app
+                .clone()
+                .into_make_service_with_connect_info::<SocketAddr>();
+
+            let server = match &tls {
+                // The PROXY protocol header comes before the TLS handshake
+                Some(tls) => server
+                    .acceptor(RustlsAcceptor::new(tls.clone()).acceptor(acceptor.clone()))
+                    .serve(make_service)
+                    .boxed(),
+                None => server
+                    .acceptor(acceptor.clone())
+                    .serve(make_service)
+                    .boxed(),
+            };
+
+            info!("Listening on {}", addr);
+            servers.push(server);
+        }
 
-                #[cfg(feature = "systemd")]
-                let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
+        #[cfg(feature = "systemd")]
+        let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
 
-                info!("Listening on {}", addr);
-                server.await?
-            }
-        }
+        future::try_join_all(servers).await?;
     }
 
     Ok(())
 }
 
+/// Binds a TCP listener. IPv6 addresses that share their port with an IPv4 address only accept
+/// IPv6 connections, as they would take the IPv4 connections too on dual-stack hosts.
+fn bind_tcp(addr: SocketAddr, addrs: &[SocketAddr]) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6()
+        && addrs
+            .iter()
+            .any(|other| other.is_ipv4() && other.port() == addr.port())
+    {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(socket.into())
+}
+
+/// Builds the CORS layer from the allowed origins and headers, where `*` allows any.
+fn cors_layer(config: &Config) -> Result<CorsLayer> {
+    let allow_origin = if config
+        .cors_allowed_origins
+        .iter()
+        .any(|origin| origin == "*")
+    {
+        AllowOrigin::any()
+    } else {
+        let origins = config
+            .cors_allowed_origins
+            .iter()
+            .map(|origin| {
+                // Browsers send origins without a trailing slash
+                HeaderValue::from_str(origin.trim_end_matches('/')).map_err(|_| {
+                    error!("Invalid CORS origin: {origin}");
+                    Error::bad_config("Invalid origin in `cors_allowed_origins`.")
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    let allow_headers = if config.cors_allowed_headers.iter().any(|name| name == "*") {
+        AllowHeaders::any()
+    } else {
+        let headers = config
+            .cors_allowed_headers
+            .iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                    error!("Invalid CORS header: {name}");
+                    Error::bad_config("Invalid header in `cors_allowed_headers`.")
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        AllowHeaders::list(headers)
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([
+            Method::GET,
+            Method::HEAD,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
+        .allow_headers(allow_headers)
+        .expose_headers([REQUEST_ID_HEADER.clone()])
+        .max_age(Duration::from_secs(config.cors_max_age_s)))
+}
+
 async fn spawn_task<B: Send + 'static>(
     req: axum::http::Request<B>,
     next: axum::middleware::Next<B>,
@@ -377,6 +560,22 @@ async fn spawn_task<B: Send + 'static>(
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
+/// Gives every request an ID, which is added to its tracing span and error responses, and sent
+/// back in the `X-Request-Id` header.
+async fn add_request_id<B: Send + 'static>(
+    req: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    let request_id = request_id::generate();
+    let header = HeaderValue::from_str(&request_id).expect("request IDs are alphanumeric");
+
+    let mut response = request_id::scope(request_id, next.run(req)).await;
+    response
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER.clone(), header);
+    response
+}
+
 async fn unrecognized_method<B: Send + 'static>(
     req: axum::http::Request<B>,
     next: axum::middleware::Next<B>,
@@ -411,6 +610,13 @@ fn routes() -> Router {
         .ruma_route(client_server::change_password_route)
         .ruma_route(client_server::deactivate_route)
         .ruma_route(client_server::third_party_route)
+        .ruma_route(client_server::bind_3pid_route)
+        .ruma_route(client_server::unbind_3pid_route)
+        .ruma_route(client_server::request_registration_token_via_email_route)
+        .route(
+            "/_matrix/client/unstable/registration/email/submit_token",
+            get(client_server::submit_registration_email_token_route),
+        )
         .ruma_route(client_server::request_3pid_management_token_via_email_route)
         .ruma_route(client_server::request_3pid_management_token_via_msisdn_route)
         .ruma_route(client_server::get_capabilities_route)
@@ -475,6 +681,7 @@ fn routes() -> Router {
         .ruma_route(client_server::invite_user_route)
         .ruma_route(client_server::set_room_visibility_route)
         .ruma_route(client_server::get_room_visibility_route)
+        .ruma_route(client_server::set_appservice_room_visibility_route)
         .ruma_route(client_server::get_public_rooms_route)
         .ruma_route(client_server::get_public_rooms_filtered_route)
         .ruma_route(client_server::search_users_route)
@@ -507,7 +714,11 @@ fn routes() -> Router {
             get(client_server::get_state_events_for_empty_key_route)
                 .put(client_server::send_state_event_for_empty_key_route),
         )
-        .ruma_route(client_server::sync_events_route)
+        // The sync response has a `peek` section (MSC2753) that Ruma's response type doesn't have
+        .raw_route(
+            sync_events::v3::Request::METADATA,
+            get(client_server::sync_events_route),
+        )
         .ruma_route(client_server::sync_events_v4_route)
         .ruma_route(client_server::get_context_route)
         .ruma_route(client_server::get_message_events_route)
@@ -516,9 +727,19 @@ fn routes() -> Router {
         .ruma_route(client_server::send_event_to_device_route)
         .ruma_route(client_server::get_media_config_route)
         .ruma_route(client_server::get_media_preview_route)
-        .ruma_route(client_server::create_content_route)
-        .ruma_route(client_server::get_content_route)
-        .ruma_route(client_server::get_content_as_filename_route)
+        // Media uploads and downloads are streamed, so they don't use the Ruma extractor
+        .raw_route(
+            create_content::v3::Request::METADATA,
+            post(client_server::create_content_route),
+        )
+        .raw_route(
+            get_content::v3::Request::METADATA,
+            get(client_server::get_content_route),
+        )
+        .raw_route(
+            get_content_as_filename::v3::Request::METADATA,
+            get(client_server::get_content_as_filename_route),
+        )
         .ruma_route(client_server::get_content_thumbnail_route)
         .ruma_route(client_server::get_devices_route)
         .ruma_route(client_server::get_device_route)
@@ -533,6 +754,7 @@ fn routes() -> Router {
         .ruma_route(client_server::get_key_changes_route)
         .ruma_route(client_server::get_pushers_route)
         .ruma_route(client_server::set_pushers_route)
+        .ruma_route(client_server::get_notifications_route)
         // .ruma_route(client_server::third_party_route)
         .ruma_route(client_server::upgrade_room_route)
         .ruma_route(client_server::get_threads_route)
@@ -549,6 +771,8 @@ fn routes() -> Router {
             "/_matrix/key/v2/server/:key_id",
             get(server_server::get_server_keys_deprecated_route),
         )
+        .ruma_route(server_server::get_remote_server_keys_route)
+        .ruma_route(server_server::get_remote_server_keys_batch_route)
         .ruma_route(server_server::get_public_rooms_route)
         .ruma_route(server_server::get_public_rooms_filtered_route)
         .ruma_route(server_server::send_transaction_message_route)
@@ -562,11 +786,21 @@ fn routes() -> Router {
         .ruma_route(server_server::create_join_event_v1_route)
         .ruma_route(server_server::create_join_event_v2_route)
         .ruma_route(server_server::create_invite_route)
+        .ruma_route(server_server::exchange_third_party_invite_route)
+        .ruma_route(server_server::third_party_bind_callback_route)
         .ruma_route(server_server::get_devices_route)
         .ruma_route(server_server::get_room_information_route)
         .ruma_route(server_server::get_profile_information_route)
         .ruma_route(server_server::get_keys_route)
         .ruma_route(server_server::claim_keys_route)
+        .route(
+            "/_matrix/client/unstable/org.matrix.msc2753/peek/:room_id_or_alias",
+            post(client_server::peek_route),
+        )
+        .route(
+            "/_matrix/client/unstable/org.matrix.msc2753/rooms/:room_id/unpeek",
+            post(client_server::unpeek_route),
+        )
         .route(
             "/_matrix/client/r0/rooms/:room_id/initialSync",
             get(initial_sync),
@@ -575,6 +809,11 @@ fn routes() -> Router {
             "/_matrix/client/v3/rooms/:room_id/initialSync",
             get(initial_sync),
         )
+        .route("/_conduwuit/metrics", get(client_server::get_metrics_route))
+        .route(
+            "/_conduwuit/client/v1/terms/accept",
+            post(client_server::accept_terms_route),
+        )
         .route(
             "/client/server.json",
             get(client_server::syncv3_client_server_json),
@@ -583,10 +822,19 @@ fn routes() -> Router {
             "/.well-known/matrix/client",
             get(client_server::well_known_client_route),
         )
+        .route(
+            "/_matrix/client/unstable/org.matrix.msc2965/auth_issuer",
+            get(client_server::get_auth_issuer_route),
+        )
         .route(
             "/.well-known/matrix/server",
             get(server_server::well_known_server_route),
         )
+        .route(
+            "/_synapse/admin/v1/register",
+            get(client_server::synapse_register_nonce_route)
+                .post(client_server::synapse_register_route),
+        )
         .route("/", get(it_works))
         .fallback(not_found)
 }
@@ -663,6 +911,9 @@ trait RouterExt {
     where
         H: RumaHandler<T>,
         T: 'static;
+
+    /// Adds a handler that doesn't use the `Ruma` extractor on all paths of an endpoint.
+    fn raw_route(self, metadata: Metadata, method_router: MethodRouter) -> Self;
 }
 
 impl RouterExt for Router {
@@ -673,6 +924,14 @@ impl RouterExt for Router {
     {
         handler.add_to_router(self)
     }
+
+    fn raw_route(mut self, metadata: Metadata, method_router: MethodRouter) -> Self {
+        for path in metadata.history.all_paths() {
+            self = self.route(path, method_router.clone());
+        }
+
+        self
+    }
 }
 
 pub trait RumaHandler<T> {
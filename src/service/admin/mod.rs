@@ -1,16 +1,23 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     convert::{TryFrom, TryInto},
+    path::{Component, Path},
     sync::{Arc, RwLock},
     time::Instant,
 };
 
-use std::fmt::Write;
+use std::{fmt::Write, io::Write as _};
 
+use base64::{engine::general_purpose, Engine as _};
 use clap::{Parser, Subcommand};
+use itertools::Itertools;
 use regex::Regex;
 use ruma::{
-    api::{appservice::Registration, client::error::ErrorKind},
+    api::{
+        appservice::Registration,
+        client::{device::Device, error::ErrorKind},
+        federation::event::get_event,
+    },
     events::{
         relation::InReplyTo,
         room::{
@@ -25,26 +32,69 @@ use ruma::{
             power_levels::RoomPowerLevelsEventContent,
             topic::RoomTopicEventContent,
         },
-        TimelineEventType,
+        AnyEphemeralRoomEvent, StateEventType, TimelineEventType,
     },
-    EventId, OwnedRoomAliasId, OwnedRoomId, OwnedUserId, RoomAliasId, RoomId, RoomOrAliasId,
+    serde::{Base64, Raw},
+    signatures::Verified,
+    CanonicalJsonObject, CanonicalJsonValue, EventId, Int, MilliSecondsSinceUnixEpoch, MxcUri,
+    OwnedMxcUri, OwnedRoomAliasId, OwnedRoomId, OwnedUserId, RoomAliasId, RoomId, RoomOrAliasId,
     RoomVersionId, ServerName, UserId,
 };
+use serde::Serialize;
 use serde_json::value::to_raw_value;
-use tokio::sync::{mpsc, Mutex};
+use tokio::{
+    fs,
+    runtime::Handle,
+    sync::{mpsc, Mutex},
+};
 use tracing::{debug, error, info, warn};
 
 use crate::{
-    api::client_server::{get_alias_helper, leave_all_rooms, leave_room, AUTO_GEN_PASSWORD_LENGTH},
+    api::client_server::{
+        get_alias_helper, leave_all_rooms, leave_room, update_joined_memberships,
+        AUTO_GEN_PASSWORD_LENGTH,
+    },
     services,
     utils::{self, HtmlEscape},
     Error, PduEvent, Result,
 };
 
-use super::pdu::PduBuilder;
+use super::{pdu::PduBuilder, rooms::timeline::PduCount};
 
 const PAGE_SIZE: usize = 100;
 
+/// Everything the server stores about a user except sent events and media files, see
+/// `export_user_data`
+#[derive(Serialize)]
+struct UserDataExport {
+    user_id: OwnedUserId,
+    exported_at: String,
+    displayname: Option<String>,
+    avatar_url: Option<OwnedMxcUri>,
+    blurhash: Option<String>,
+    deactivated: bool,
+    devices: Vec<Device>,
+    account_data: Vec<Raw<AnyEphemeralRoomEvent>>,
+    rooms: BTreeMap<OwnedRoomId, ExportedRoom>,
+    media: Vec<ExportedUpload>,
+}
+
+#[derive(Serialize)]
+struct ExportedRoom {
+    membership: &'static str,
+    account_data: Vec<Raw<AnyEphemeralRoomEvent>>,
+}
+
+#[derive(Serialize)]
+struct ExportedUpload {
+    mxc: String,
+    content_type: Option<String>,
+    size: u64,
+    uploaded_at: String,
+    /// Name of the file in the media directory of the export, None if the file is not available
+    file: Option<String>,
+}
+
 #[cfg_attr(test, derive(Debug))]
 #[derive(Parser)]
 #[command(name = "@conduit:server.name:", version = env!("CARGO_PKG_VERSION"))]
@@ -65,6 +115,10 @@ enum AdminCommand {
     /// - Commands for managing federation
     Federation(FederationCommand),
 
+    #[command(subcommand)]
+    /// - Commands for managing media
+    Media(MediaCommand),
+
     #[command(subcommand)]
     /// - Commands for managing the server
     Server(ServerCommand),
@@ -87,14 +141,30 @@ enum AppserviceCommand {
     ///
     /// Registering a new bridge using the ID of an existing bridge will replace
     /// the old one.
+    ///
+    /// Bridges with `org.matrix.msc3202: true` in their registration get device
+    /// list changes and one-time key counts in their transactions.
     Register,
 
     /// - Unregister an appservice using its ID
     ///
     /// You can find the ID using the `list-appservices` command.
+    ///
+    /// With --purge, the users in the exclusive namespaces of the appservice
+    /// leave their rooms and lose their devices, and the aliases in its
+    /// exclusive namespaces are removed. Admins and the server user are never
+    /// touched. Without --confirm, this only shows what would be removed.
     Unregister {
         /// The appservice to unregister
         appservice_identifier: String,
+
+        #[arg(long)]
+        /// Also remove the users and aliases of the appservice
+        purge: bool,
+
+        #[arg(long, requires = "purge")]
+        /// Actually unregister and purge instead of showing what would be removed
+        confirm: bool,
     },
 
     /// - Show an appservice's config using its ID
@@ -158,6 +228,73 @@ enum UserCommand {
 
     /// - List local users in the database
     List,
+
+    /// - Mark a user as having accepted the current version of all terms of service policies
+    ///
+    /// Useful after bumping a policy version with `terms_of_service_block_messages` enabled,
+    /// once the user has agreed to the new terms out of band.
+    AcceptTerms { user_id: Box<UserId> },
+
+    /// - Link a subject of the OIDC authorization server to an existing local user
+    ///
+    /// Subjects are never linked to existing accounts automatically, only new accounts are created
+    /// for them. Use this to let existing users log in through the authorization server.
+    LinkOidcSubject {
+        user_id: Box<UserId>,
+        subject: String,
+    },
+
+    /// - Grant admin privileges to a local user
+    ///
+    /// Invites the user to the admin room, joins them and raises their power level to 100.
+    MakeUserAdmin { user_id: Box<UserId> },
+
+    /// - Export everything the server stores about a local user, e.g. for a data access request
+    ///
+    /// Writes the profile, devices, account data and rooms of the user to `user.json`, the events
+    /// they sent to `messages.jsonl` and the files they uploaded to `media/` in a new tar archive
+    /// inside `user_export_path` on the server. The export runs in the background and reports
+    /// back once it is done.
+    ExportData {
+        user_id: Box<UserId>,
+
+        /// Name of the archive inside `user_export_path` to write the export to, without the
+        /// `.tar` extension
+        name: String,
+    },
+
+    /// - Revoke the admin privileges of a local user
+    ///
+    /// Removes the user's power level in the admin room and kicks them from it.
+    RevokeAdmin { user_id: Box<UserId> },
+
+    /// - Change the displayname of a local user, e.g. to stop impersonation
+    ///
+    /// The new displayname is also sent as a membership event into all rooms of the user.
+    SetDisplayname {
+        user_id: Box<UserId>,
+
+        /// The new displayname, which may contain spaces
+        #[arg(required = true)]
+        displayname: Vec<String>,
+    },
+
+    /// - Change the avatar of a local user to an MXC URI
+    ///
+    /// The new avatar is also sent as a membership event into all rooms of the user.
+    SetAvatar {
+        user_id: Box<UserId>,
+        avatar_url: String,
+    },
+
+    /// - Make a local user leave a room without their client
+    ///
+    /// Sends the leave event on behalf of the user, over federation if the server is not in the
+    /// room anymore. Useful for rooms a user can't leave themselves.
+    ForceLeave {
+        user_id: Box<UserId>,
+        room_id: Box<RoomId>,
+    },
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -166,6 +303,17 @@ enum RoomCommand {
     /// - List all rooms the server knows about
     List { page: Option<usize> },
 
+    /// - Show a summary of the state of a room
+    ///
+    /// Prints the name, canonical alias, room version, join rules, encryption, member counts and
+    /// the servers participating in the room.
+    RoomInfo { room_id: Box<RoomId> },
+
+    /// - Count the rooms the server knows about per room version
+    ///
+    /// Shows which room versions are still in use, e.g. before deciding to upgrade old rooms.
+    RoomVersions,
+
     #[command(subcommand)]
     /// - Manage moderation of remote or local rooms
     Moderation(RoomModeration),
@@ -211,6 +359,49 @@ enum RoomModeration {
         disable_federation: bool,
     },
 
+    /// - Blocks a room from local users joining or being invited to it, without evicting anyone
+    ///
+    /// Unlike ban-room, local users already in the room are left alone. Use unban-room to unblock it again.
+    BlockRoom {
+        /// The room in the format of `!roomid:example.com`
+        room_id: Box<RoomId>,
+    },
+
+    /// - Shuts down a room as a response to abuse
+    ///
+    /// Blocks the room so local users can't join it again and makes all local members leave it,
+    /// admins included. With --notice, the members are moved into a new read-only room that
+    /// contains the notice. With --purge, the local aliases and room directory entry of the room
+    /// are removed and incoming federation of the room is disabled.
+    ShutdownRoom {
+        #[arg(long)]
+        /// Message explaining the shutdown, sent to a new room the members are moved into
+        notice: Option<String>,
+
+        #[arg(long)]
+        /// Also remove the aliases and directory entry of the room and disable its federation
+        purge: bool,
+
+        /// The room in the format of `!roomid:example.com`
+        room_id: Box<RoomId>,
+    },
+
+    /// - Makes a room read-only, e.g. to clean it up after spam
+    ///
+    /// Raises the power level required to send events to the level of the most powerful local
+    /// member, which is as high as our users can set it. Local members with that level can still
+    /// send events. Use unfreeze-room to restore the previous levels.
+    FreezeRoom {
+        /// The room in the format of `!roomid:example.com`
+        room_id: Box<RoomId>,
+    },
+
+    /// - Restores the power levels a room had before it was frozen
+    UnfreezeRoom {
+        /// The room in the format of `!roomid:example.com`
+        room_id: Box<RoomId>,
+    },
+
     /// - Unbans a room to allow local users to join again
     ///
     /// To re-enable incoming federation of the room, use --enable-federation
@@ -285,6 +476,8 @@ enum RoomDirectoryCommand {
 #[derive(Subcommand)]
 enum FederationCommand {
     /// - List all rooms we are currently handling an incoming pdu from
+    ///
+    /// Also shows how many incoming pdus were rejected since startup, by reason.
     IncomingFederation,
 
     /// - Disables incoming federation handling for a room.
@@ -301,9 +494,112 @@ enum FederationCommand {
 
     /// - Verify json signatures
     ///
+    /// Fetches the keys of the signing servers and reports which signatures are valid and why the
+    /// others fail.
+    ///
     /// This command needs a JSON blob provided in a Markdown code block below
     /// the command.
     VerifyJson,
+
+    /// - Verify the signatures and content hash of a PDU
+    ///
+    /// Reports the event ID, whether the content hash matches and which signatures are valid. The
+    /// room version is taken from the room of the event if we know it.
+    ///
+    /// This command needs a JSON blob provided in a Markdown code block below
+    /// the command.
+    VerifyPdu {
+        /// Room version of the event, required if we don't know its room
+        #[arg(long)]
+        room_version: Option<RoomVersionId>,
+    },
+
+    /// - Show the outgoing federation send queue status
+    ///
+    /// Shows queued PDU/EDU counts, the last successful transaction, the current backoff and the
+    /// transaction in flight for the given destination, or for all known destinations.
+    FederationStatus {
+        destination: Option<Box<ServerName>>,
+    },
+
+    /// - Show the health of federation destinations
+    ///
+    /// Lists the last successful transaction, consecutive failures and average round trip time of
+    /// every destination we sent transactions to, kept across restarts. Destinations with the most
+    /// consecutive failures come first.
+    DestinationHealth {
+        #[arg(short, long, default_value_t = 50)]
+        limit: usize,
+    },
+
+    /// - Show the outgoing federation request metrics of destinations
+    ///
+    /// Lists the number of requests, average and maximum latency, response status codes and
+    /// retried transactions since startup for the given destination, or for all destinations with
+    /// the slowest average latency first. Helps to tell whether our server or theirs is slow.
+    RequestMetrics {
+        destination: Option<Box<ServerName>>,
+        #[arg(short, long, default_value_t = 50)]
+        limit: usize,
+    },
+
+    /// - Retry sending to a federation destination now
+    ///
+    /// Closes the circuit breaker of the destination and immediately tries to send its queue, for
+    /// when you know the remote server is back up.
+    RetryDestination { server_name: Box<ServerName> },
+
+    /// - Show the server ACL of a room
+    ///
+    /// Prints the allow and deny patterns, whether IP literals are allowed and which of the
+    /// servers currently in the room are denied by it.
+    ShowAcl { room_id: Box<RoomId> },
+
+    /// - Show the rooms and local users affected by a remote server, optionally blocking it
+    ///
+    /// Lists the rooms we share with the server and the local users in them. With --block, we stop
+    /// accepting requests from the server and sending requests to it.
+    ServerInfo {
+        server_name: Box<ServerName>,
+
+        #[arg(long)]
+        /// Blocks federation with the server
+        block: bool,
+
+        #[arg(long, requires = "block")]
+        /// Also removes the users of the server from our member lists of the rooms we share with
+        /// it, without sending any events
+        remove_memberships: bool,
+    },
+
+    /// - Unblock a server that was blocked with `server-info --block`
+    UnblockServer { server_name: Box<ServerName> },
+
+    /// - List all servers that were blocked with `server-info --block`
+    ListBlockedServers,
+}
+
+#[cfg_attr(test, derive(Debug))]
+#[derive(Subcommand)]
+enum MediaCommand {
+    /// - List the media uploaded by a local user, newest first
+    ///
+    /// Shows the MXC URI, size, content type and upload time of every file.
+    ListUserMedia {
+        user_id: Box<UserId>,
+        /// Maximum number of files to list
+        #[arg(short, long)]
+        limit: Option<usize>,
+    },
+
+    /// - Hide a file and its thumbnails without deleting them
+    ///
+    /// The media is reported as not found to everyone, including over federation, until it is
+    /// unquarantined. Remote media will not be fetched again while it is quarantined.
+    QuarantineMedia { mxc: String },
+
+    /// - Make a quarantined file available again
+    UnquarantineMedia { mxc: String },
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -330,8 +626,62 @@ enum DebugCommand {
         event_id: Box<EventId>,
     },
 
+    /// - Fetch a PDU from a remote server and verify it
+    ///
+    /// Prints the event with its signature and content hash report. With --handle, the event is
+    /// also processed like an incoming event from that server, which can repair rooms with
+    /// missing events.
+    GetRemotePdu {
+        /// The server to fetch the event from
+        server: Box<ServerName>,
+
+        /// An event ID (a $ followed by the base64 reference hash)
+        event_id: Box<EventId>,
+
+        /// Room version of the event, required if we don't know its room
+        #[arg(long)]
+        room_version: Option<RoomVersionId>,
+
+        /// Process the event as if the server had sent it to us
+        #[arg(long)]
+        handle: bool,
+    },
+
     /// - Forces device lists for all the local users to be updated
     ForceDeviceListUpdates,
+
+    /// - List the events of a room that were accepted but soft failed
+    ///
+    /// Soft failed events are kept as outliers but never show up in the timeline, which is
+    /// usually why a message someone sent is "missing".
+    ListSoftFailed {
+        /// The room ID
+        room_id: Box<RoomId>,
+    },
+
+    /// - Try again to add the outliers of a room to the timeline
+    ///
+    /// Events whose prev events were missing when they arrived are stored as outliers and never
+    /// retried. This handles the outliers whose prev and auth events are known by now, which can
+    /// take a while because the outliers of all rooms have to be searched.
+    ReprocessOutliers {
+        /// The room ID
+        room_id: Box<RoomId>,
+    },
+
+    /// - Recalculate the current state of a room and replace the stored one
+    ///
+    /// The state after each forward extremity of the room is resolved with the auth rules, which
+    /// can recover rooms with corrupted state. With --from, the state at the latest extremity is
+    /// also fetched from that server, which should be in the room.
+    RepairState {
+        /// The room ID
+        room_id: Box<RoomId>,
+
+        /// A server in the room to fetch the state from
+        #[arg(long)]
+        from: Option<Box<ServerName>>,
+    },
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -341,13 +691,41 @@ enum ServerCommand {
     ShowConfig,
 
     /// - Print database memory usage statistics
-    MemoryUsage,
+    MemoryUsage {
+        #[arg(long)]
+        /// Also print the estimated size of each cache
+        caches: bool,
+    },
 
     /// - Clears all of Conduit's database caches with index smaller than the amount
     ClearDatabaseCaches { amount: u32 },
 
     /// - Clears all of Conduit's service caches with index smaller than the amount
     ClearServiceCaches { amount: u32 },
+
+    /// - Clears a single cache by the name shown by `memory-usage --caches`
+    ClearCache { name: String },
+
+    /// - Check the consistency of the database
+    ///
+    /// Verifies that every timeline PDU has state and can be found by its event ID, that short
+    /// event IDs resolve and that room aliases match the alias lists of rooms. This goes through
+    /// large parts of the database and can take a long time.
+    CheckDatabase {
+        /// Add missing reverse mappings and remove dangling entries
+        #[arg(long)]
+        repair: bool,
+    },
+
+    /// - Enables read-only maintenance mode
+    ///
+    /// Clients can still sync and read, but all other requests of non-admins fail with a
+    /// retryable error containing the message. Without a message, `maintenance_message` from the
+    /// config or a default message is used. This does not change the config file.
+    EnableMaintenanceMode { message: Vec<String> },
+
+    /// - Disables read-only maintenance mode
+    DisableMaintenanceMode,
 }
 
 #[derive(Debug)]
@@ -407,14 +785,7 @@ impl Service {
                         }
                     };
 
-                    let mutex_state = Arc::clone(
-                        services().globals
-                            .roomid_mutex_state
-                            .write()
-                            .unwrap()
-                            .entry(conduit_room.to_owned())
-                            .or_default(),
-                    );
+                    let mutex_state = services().globals.roomid_mutex_state.mutex(&conduit_room);
 
                     let state_lock = mutex_state.lock().await;
 
@@ -527,8 +898,17 @@ impl Service {
                         let appservice_config = body[1..body.len() - 1].join("\n");
                         let parsed_config =
                             serde_yaml::from_str::<Registration>(&appservice_config);
+                        // Registration doesn't know about the MSC3202 opt-in
+                        let device_lists =
+                            serde_yaml::from_str::<serde_yaml::Value>(&appservice_config)
+                                .ok()
+                                .and_then(|config| config.get("org.matrix.msc3202")?.as_bool())
+                                .unwrap_or(false);
                         match parsed_config {
-                            Ok(yaml) => match services().appservice.register_appservice(yaml) {
+                            Ok(yaml) => match services()
+                                .appservice
+                                .register_appservice(yaml, device_lists)
+                            {
                                 Ok(id) => RoomMessageEventContent::text_plain(format!(
                                     "Appservice registered with ID: {id}."
                                 )),
@@ -548,15 +928,52 @@ impl Service {
                 }
                 AppserviceCommand::Unregister {
                     appservice_identifier,
-                } => match services()
-                    .appservice
-                    .unregister_appservice(&appservice_identifier)
-                {
-                    Ok(()) => RoomMessageEventContent::text_plain("Appservice unregistered."),
-                    Err(e) => RoomMessageEventContent::text_plain(format!(
-                        "Failed to unregister appservice: {e}"
-                    )),
-                },
+                    purge,
+                    confirm,
+                } => {
+                    let registration = services()
+                        .appservice
+                        .get_registration(&appservice_identifier)?;
+
+                    let purge = match registration.filter(|_| purge) {
+                        Some(registration) => Some((
+                            registration.id.clone(),
+                            services().appservice.exclusive_users(&registration)?,
+                            services().appservice.exclusive_aliases(&registration)?,
+                        )),
+                        None => None,
+                    };
+
+                    if let (Some((_, users, aliases)), false) = (&purge, confirm) {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "Purging would make {} users leave their rooms and remove their \
+                             devices, and remove {} aliases. Run the command again with \
+                             --confirm to unregister and purge the appservice.",
+                            users.len(),
+                            aliases.len()
+                        )));
+                    }
+
+                    match services()
+                        .appservice
+                        .unregister_appservice(&appservice_identifier)
+                    {
+                        Ok(()) => match purge {
+                            Some((id, users, aliases)) => {
+                                purge_appservice(&id, &users, &aliases).await?;
+                                RoomMessageEventContent::text_plain(format!(
+                                    "Appservice unregistered. Removed {} users and {} aliases.",
+                                    users.len(),
+                                    aliases.len()
+                                ))
+                            }
+                            None => RoomMessageEventContent::text_plain("Appservice unregistered."),
+                        },
+                        Err(e) => RoomMessageEventContent::text_plain(format!(
+                            "Failed to unregister appservice: {e}"
+                        )),
+                    }
+                }
                 AppserviceCommand::Show {
                     appservice_identifier,
                 } => {
@@ -641,20 +1058,25 @@ impl Service {
                             "Userid {user_id} already exists"
                         )));
                     }
-                    // Create user
-                    services().users.create(&user_id, Some(password.as_str()))?;
-
-                    // Default to pretty displayname
-                    let mut displayname = user_id.localpart().to_owned();
-
-                    // If enabled append lightning bolt to display name (default true)
-                    if services().globals.enable_lightning_bolt() {
-                        displayname.push_str(" ⚡️");
+                    let forbidden_usernames = services().globals.forbidden_usernames();
+                    let matches = forbidden_usernames.matches(user_id.localpart());
+                    if matches.matched_any() {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "Userid {user_id} matches the following forbidden username patterns: {}",
+                            matches
+                                .into_iter()
+                                .map(|x| &forbidden_usernames.patterns()[x])
+                                .join(", ")
+                        )));
                     }
+                    // Create user, the admin creating it stands in for the terms of service stage
+                    services().users.create(&user_id, Some(password.as_str()))?;
+                    services().users.accept_current_terms(&user_id)?;
 
+                    let displayname = services().globals.new_user_displayname(&user_id);
                     services()
                         .users
-                        .set_displayname(&user_id, Some(displayname))
+                        .set_displayname(&user_id, displayname)
                         .await?;
 
                     // Initial account data
@@ -666,7 +1088,7 @@ impl Service {
                             .into(),
                         &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
                             content: ruma::events::push_rules::PushRulesEventContent {
-                                global: ruma::push::Ruleset::server_default(&user_id),
+                                global: services().globals.default_push_ruleset(&user_id),
                             },
                         })
                         .expect("to json value always works"),
@@ -760,69 +1182,348 @@ impl Service {
                         )),
                     }
                 }
-                UserCommand::DeactivateAll { leave_rooms, force } => {
-                    if body.len() > 2
-                        && body[0].trim().starts_with("```")
-                        && body.last().unwrap().trim() == "```"
-                    {
-                        let usernames = body.clone().drain(1..body.len() - 1).collect::<Vec<_>>();
+                UserCommand::AcceptTerms { user_id } => {
+                    if user_id.server_name() != services().globals.server_name() {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} does not belong to our server."
+                        )));
+                    }
 
-                        let mut user_ids: Vec<&UserId> = Vec::new();
+                    if !services().users.exists(&user_id)? {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} doesn't exist on this server"
+                        )));
+                    }
 
-                        for &username in &usernames {
-                            match <&UserId>::try_from(username) {
-                                Ok(user_id) => user_ids.push(user_id),
-                                Err(e) => {
-                                    return Ok(RoomMessageEventContent::text_plain(format!(
-                                        "{username} is not a valid username: {e}"
-                                    )))
-                                }
-                            }
-                        }
+                    services().users.accept_current_terms(&user_id)?;
 
-                        let mut deactivation_count = 0;
-                        let mut admins = Vec::new();
+                    RoomMessageEventContent::text_plain(format!(
+                        "User {user_id} has accepted the current terms of service."
+                    ))
+                }
+                UserCommand::LinkOidcSubject { user_id, subject } => {
+                    if user_id.server_name() != services().globals.server_name() {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} does not belong to our server."
+                        )));
+                    }
 
-                        if !force {
-                            user_ids.retain(|&user_id| match services().users.is_admin(user_id) {
-                                Ok(is_admin) => match is_admin {
-                                    true => {
-                                        admins.push(user_id.localpart());
-                                        false
-                                    }
-                                    false => true,
-                                },
-                                Err(_) => false,
-                            })
-                        }
+                    if !services().users.exists(&user_id)? {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} doesn't exist on this server"
+                        )));
+                    }
 
-                        for &user_id in &user_ids {
-                            // check if user belongs to our server and skips over non-local users
-                            if user_id.server_name() != services().globals.server_name() {
-                                continue;
-                            }
+                    let conduit_user =
+                        UserId::parse(format!("@conduit:{}", services().globals.server_name()))
+                            .expect("@conduit:server_name is valid");
+                    if *user_id == *conduit_user {
+                        return Ok(RoomMessageEventContent::text_plain(
+                            "The server user can't be linked to a subject.",
+                        ));
+                    }
 
-                            if services().users.deactivate_account(user_id).is_ok() {
-                                deactivation_count += 1
-                            }
-                        }
+                    if let Some(linked) = services().users.oidc_subject_user(&subject)? {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "Subject {subject} is already linked to {linked}."
+                        )));
+                    }
 
-                        if leave_rooms {
-                            for &user_id in &user_ids {
-                                let _ = leave_all_rooms(user_id).await;
-                            }
-                        }
+                    services().users.set_oidc_subject_user(&subject, &user_id)?;
 
-                        if admins.is_empty() {
-                            RoomMessageEventContent::text_plain(format!(
-                                "Deactivated {deactivation_count} accounts."
-                            ))
-                        } else {
-                            RoomMessageEventContent::text_plain(format!("Deactivated {} accounts.\nSkipped admin accounts: {:?}. Use --force to deactivate admin accounts", deactivation_count, admins.join(", ")))
-                        }
-                    } else {
-                        RoomMessageEventContent::text_plain(
-                            "Expected code block in command body. Add --help for details.",
+                    RoomMessageEventContent::text_plain(format!(
+                        "Linked subject {subject} to {user_id}."
+                    ))
+                }
+                UserCommand::ExportData { user_id, name } => {
+                    let Some(export_folder) = services().globals.get_user_export_folder() else {
+                        return Ok(RoomMessageEventContent::text_plain(
+                            "User data exports are disabled, set user_export_path in the config \
+                             to enable them.",
+                        ));
+                    };
+
+                    let mut components = Path::new(&name).components();
+                    if !matches!(
+                        (components.next(), components.next()),
+                        (Some(Component::Normal(_)), None)
+                    ) {
+                        return Ok(RoomMessageEventContent::text_plain(
+                            "The export name must be a plain file name.",
+                        ));
+                    }
+
+                    let archive = export_folder.join(format!("{name}.tar"));
+                    if fs::try_exists(&archive).await? {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "An export named {name} already exists."
+                        )));
+                    }
+
+                    if user_id.server_name() != services().globals.server_name() {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} does not belong to our server."
+                        )));
+                    }
+
+                    if !services().users.exists(&user_id)? {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} doesn't exist on this server"
+                        )));
+                    }
+
+                    fs::create_dir_all(&export_folder).await?;
+
+                    // Walking the timelines of all rooms of the user takes a while, so the result
+                    // is reported in a separate message
+                    let message = format!(
+                        "Exporting the data of {user_id} to {}, this may take a while.",
+                        archive.display()
+                    );
+                    let runtime = Handle::current();
+                    tokio::spawn(async move {
+                        let result = {
+                            let user_id = user_id.clone();
+                            let archive = archive.clone();
+                            utils::spawn_blocking(move || {
+                                services()
+                                    .admin
+                                    .export_user_data(&user_id, &archive, &runtime)
+                            })
+                            .await
+                        };
+
+                        let message = match result {
+                            Ok((messages, files)) => format!(
+                                "Exported the data of {user_id} to {}, including {messages} sent \
+                                 events and {files} uploaded files.",
+                                archive.display()
+                            ),
+                            Err(e) => {
+                                let _ = fs::remove_file(&archive).await;
+                                format!("Failed to export the data of {user_id}: {e}")
+                            }
+                        };
+                        services()
+                            .admin
+                            .send_message(RoomMessageEventContent::text_plain(message));
+                    });
+
+                    RoomMessageEventContent::text_plain(message)
+                }
+                UserCommand::MakeUserAdmin { user_id } => {
+                    if user_id.server_name() != services().globals.server_name() {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} does not belong to our server."
+                        )));
+                    }
+
+                    if !services().users.exists(&user_id)? {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} doesn't exist on this server"
+                        )));
+                    }
+
+                    if services().users.is_admin(&user_id)? {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} is already an admin."
+                        )));
+                    }
+
+                    let displayname = services().users.displayname(&user_id)?;
+
+                    self.make_user_admin(&user_id, displayname).await?;
+
+                    RoomMessageEventContent::text_plain(format!(
+                        "User {user_id} has been granted admin privileges."
+                    ))
+                }
+                UserCommand::RevokeAdmin { user_id } => {
+                    if user_id.server_name() != services().globals.server_name() {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} does not belong to our server."
+                        )));
+                    }
+
+                    if user_id.localpart() == "conduit" {
+                        return Ok(RoomMessageEventContent::text_plain(
+                            "The server user can't lose its admin privileges.",
+                        ));
+                    }
+
+                    if !services().users.is_admin(&user_id)? {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} is not an admin."
+                        )));
+                    }
+
+                    self.revoke_user_admin(&user_id).await?;
+
+                    RoomMessageEventContent::text_plain(format!(
+                        "Admin privileges of {user_id} have been revoked."
+                    ))
+                }
+                UserCommand::SetDisplayname {
+                    user_id,
+                    displayname,
+                } => {
+                    if user_id.server_name() != services().globals.server_name() {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} does not belong to our server."
+                        )));
+                    }
+
+                    if !services().users.exists(&user_id)? {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} doesn't exist on this server"
+                        )));
+                    }
+
+                    let displayname = displayname.join(" ");
+                    services()
+                        .users
+                        .set_displayname(&user_id, Some(displayname.clone()))
+                        .await?;
+
+                    update_joined_memberships(&user_id, |content| RoomMemberEventContent {
+                        displayname: Some(displayname.clone()),
+                        ..content
+                    })
+                    .await;
+
+                    RoomMessageEventContent::text_plain(format!(
+                        "Displayname of {user_id} changed to {displayname}."
+                    ))
+                }
+                UserCommand::SetAvatar {
+                    user_id,
+                    avatar_url,
+                } => {
+                    if user_id.server_name() != services().globals.server_name() {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} does not belong to our server."
+                        )));
+                    }
+
+                    if !services().users.exists(&user_id)? {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} doesn't exist on this server"
+                        )));
+                    }
+
+                    let avatar_url = OwnedMxcUri::from(avatar_url);
+                    if !avatar_url.is_valid() {
+                        return Ok(RoomMessageEventContent::text_plain(
+                            "Invalid MXC URI, expected mxc://server.name/media_id",
+                        ));
+                    }
+
+                    services()
+                        .users
+                        .set_avatar_url(&user_id, Some(avatar_url.clone()))
+                        .await?;
+                    // The blurhash belonged to the previous avatar
+                    services().users.set_blurhash(&user_id, None).await?;
+
+                    update_joined_memberships(&user_id, |content| RoomMemberEventContent {
+                        avatar_url: Some(avatar_url.clone()),
+                        blurhash: None,
+                        ..content
+                    })
+                    .await;
+
+                    RoomMessageEventContent::text_plain(format!(
+                        "Avatar of {user_id} changed to {avatar_url}."
+                    ))
+                }
+                UserCommand::ForceLeave { user_id, room_id } => {
+                    if user_id.server_name() != services().globals.server_name() {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} does not belong to our server."
+                        )));
+                    }
+
+                    let state_cache = &services().rooms.state_cache;
+                    if !state_cache.is_joined(&user_id, &room_id)?
+                        && !state_cache.is_invited(&user_id, &room_id)?
+                    {
+                        return Ok(RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} is not in room {room_id}."
+                        )));
+                    }
+
+                    match leave_room(&user_id, &room_id, None).await {
+                        Ok(()) => RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} has left room {room_id}."
+                        )),
+                        Err(e) => RoomMessageEventContent::text_plain(format!(
+                            "Failed to make {user_id} leave {room_id}: {e}"
+                        )),
+                    }
+                }
+                UserCommand::DeactivateAll { leave_rooms, force } => {
+                    if body.len() > 2
+                        && body[0].trim().starts_with("```")
+                        && body.last().unwrap().trim() == "```"
+                    {
+                        let usernames = body.clone().drain(1..body.len() - 1).collect::<Vec<_>>();
+
+                        let mut user_ids: Vec<&UserId> = Vec::new();
+
+                        for &username in &usernames {
+                            match <&UserId>::try_from(username) {
+                                Ok(user_id) => user_ids.push(user_id),
+                                Err(e) => {
+                                    return Ok(RoomMessageEventContent::text_plain(format!(
+                                        "{username} is not a valid username: {e}"
+                                    )))
+                                }
+                            }
+                        }
+
+                        let mut deactivation_count = 0;
+                        let mut admins = Vec::new();
+
+                        if !force {
+                            user_ids.retain(|&user_id| match services().users.is_admin(user_id) {
+                                Ok(is_admin) => match is_admin {
+                                    true => {
+                                        admins.push(user_id.localpart());
+                                        false
+                                    }
+                                    false => true,
+                                },
+                                Err(_) => false,
+                            })
+                        }
+
+                        for &user_id in &user_ids {
+                            // check if user belongs to our server and skips over non-local users
+                            if user_id.server_name() != services().globals.server_name() {
+                                continue;
+                            }
+
+                            if services().users.deactivate_account(user_id).is_ok() {
+                                deactivation_count += 1
+                            }
+                        }
+
+                        if leave_rooms {
+                            for &user_id in &user_ids {
+                                let _ = leave_all_rooms(user_id).await;
+                            }
+                        }
+
+                        if admins.is_empty() {
+                            RoomMessageEventContent::text_plain(format!(
+                                "Deactivated {deactivation_count} accounts."
+                            ))
+                        } else {
+                            RoomMessageEventContent::text_plain(format!("Deactivated {} accounts.\nSkipped admin accounts: {:?}. Use --force to deactivate admin accounts", deactivation_count, admins.join(", ")))
+                        }
+                    } else {
+                        RoomMessageEventContent::text_plain(
+                            "Expected code block in command body. Add --help for details.",
                         )
                     }
                 }
@@ -1092,35 +1793,159 @@ impl Service {
                             ));
                         }
                     }
-                    RoomModeration::UnbanRoom {
-                        room,
-                        enable_federation,
-                    } => {
-                        let room_id = if room.is_room_id() {
-                            let room_id = match RoomId::parse(&room) {
-                                Ok(room_id) => room_id,
-                                Err(e) => return Ok(RoomMessageEventContent::text_plain(format!("Failed to parse room ID {room}. Please note that this requires a full room ID (`!awIh6gGInaS5wLQJwa:example.com`) or a room alias (`#roomalias:example.com`): {e}"))),
-                            };
+                    RoomModeration::BlockRoom { room_id } => {
+                        let admin_room_alias: Box<RoomAliasId> =
+                            format!("#admins:{}", services().globals.server_name())
+                                .try_into()
+                                .expect("#admins:server_name is a valid alias name");
+                        let admin_room_id = services()
+                            .rooms
+                            .alias
+                            .resolve_local_alias(&admin_room_alias)?
+                            .expect("Admin room must exist");
 
-                            debug!("Room specified is a room ID, unbanning room ID");
+                        if *room_id == *admin_room_id {
+                            return Ok(RoomMessageEventContent::text_plain(
+                                "Not allowed to block the admin room.",
+                            ));
+                        }
 
-                            services().rooms.metadata.ban_room(&room_id, false)?;
+                        services().rooms.metadata.ban_room(&room_id, true)?;
 
-                            room_id
-                        } else if room.is_room_alias_id() {
-                            let room_alias = match RoomAliasId::parse(&room) {
-                                Ok(room_alias) => room_alias,
-                                Err(e) => return Ok(RoomMessageEventContent::text_plain(format!("Failed to parse room ID {room}. Please note that this requires a full room ID (`!awIh6gGInaS5wLQJwa:example.com`) or a room alias (`#roomalias:example.com`): {e}"))),
-                            };
+                        RoomMessageEventContent::text_plain(format!(
+                            "Room {room_id} blocked, local users can no longer join or be invited to it."
+                        ))
+                    }
+                    RoomModeration::ShutdownRoom {
+                        notice,
+                        purge,
+                        room_id,
+                    } => {
+                        let admin_room_alias: Box<RoomAliasId> =
+                            format!("#admins:{}", services().globals.server_name())
+                                .try_into()
+                                .expect("#admins:server_name is a valid alias name");
+                        if services()
+                            .rooms
+                            .alias
+                            .resolve_local_alias(&admin_room_alias)?
+                            .is_some_and(|admin_room_id| *admin_room_id == *room_id)
+                        {
+                            return Ok(RoomMessageEventContent::text_plain(
+                                "Not allowed to shut down the admin room.",
+                            ));
+                        }
 
-                            debug!("Room specified is not a room ID, attempting to resolve room alias to a room ID locally, if not using get_alias_helper to fetch room ID remotely");
+                        let (evicted, notice_room_id) = services()
+                            .admin
+                            .shutdown_room(&room_id, notice.as_deref(), purge)
+                            .await?;
 
-                            let room_id = match services()
-                                .rooms
-                                .alias
-                                .resolve_local_alias(&room_alias)?
-                            {
-                                Some(room_id) => room_id,
+                        let mut msg = format!(
+                            "Room {room_id} shut down, {} local users were removed from it.",
+                            evicted.len()
+                        );
+                        if let Some(notice_room_id) = notice_room_id {
+                            write!(msg, " They were moved to {notice_room_id}.").unwrap();
+                        }
+                        if purge {
+                            msg.push_str(
+                                " Its aliases and directory entry were removed and its federation \
+                                 disabled.",
+                            );
+                        }
+
+                        RoomMessageEventContent::text_plain(msg)
+                    }
+                    RoomModeration::FreezeRoom { room_id } => {
+                        let admin_room_alias: Box<RoomAliasId> =
+                            format!("#admins:{}", services().globals.server_name())
+                                .try_into()
+                                .expect("#admins:server_name is a valid alias name");
+                        if services()
+                            .rooms
+                            .alias
+                            .resolve_local_alias(&admin_room_alias)?
+                            .is_some_and(|admin_room_id| *admin_room_id == *room_id)
+                        {
+                            return Ok(RoomMessageEventContent::text_plain(
+                                "Not allowed to freeze the admin room.",
+                            ));
+                        }
+
+                        if services()
+                            .rooms
+                            .metadata
+                            .frozen_power_levels(&room_id)?
+                            .is_some()
+                        {
+                            return Ok(RoomMessageEventContent::text_plain(
+                                "This room is already frozen.",
+                            ));
+                        }
+
+                        let still_allowed = services().admin.freeze_room(&room_id).await?;
+
+                        let mut msg = format!("Room {room_id} frozen.");
+                        if !still_allowed.is_empty() {
+                            write!(
+                                msg,
+                                " These local users have the highest power level in the room and \
+                                 can still send events: {}",
+                                still_allowed.iter().join(", ")
+                            )
+                            .unwrap();
+                        }
+
+                        RoomMessageEventContent::text_plain(msg)
+                    }
+                    RoomModeration::UnfreezeRoom { room_id } => {
+                        if services()
+                            .rooms
+                            .metadata
+                            .frozen_power_levels(&room_id)?
+                            .is_none()
+                        {
+                            return Ok(RoomMessageEventContent::text_plain(
+                                "This room is not frozen.",
+                            ));
+                        }
+
+                        services().admin.unfreeze_room(&room_id).await?;
+
+                        RoomMessageEventContent::text_plain(format!(
+                            "Room {room_id} unfrozen, the previous power levels were restored."
+                        ))
+                    }
+                    RoomModeration::UnbanRoom {
+                        room,
+                        enable_federation,
+                    } => {
+                        let room_id = if room.is_room_id() {
+                            let room_id = match RoomId::parse(&room) {
+                                Ok(room_id) => room_id,
+                                Err(e) => return Ok(RoomMessageEventContent::text_plain(format!("Failed to parse room ID {room}. Please note that this requires a full room ID (`!awIh6gGInaS5wLQJwa:example.com`) or a room alias (`#roomalias:example.com`): {e}"))),
+                            };
+
+                            debug!("Room specified is a room ID, unbanning room ID");
+
+                            services().rooms.metadata.ban_room(&room_id, false)?;
+
+                            room_id
+                        } else if room.is_room_alias_id() {
+                            let room_alias = match RoomAliasId::parse(&room) {
+                                Ok(room_alias) => room_alias,
+                                Err(e) => return Ok(RoomMessageEventContent::text_plain(format!("Failed to parse room ID {room}. Please note that this requires a full room ID (`!awIh6gGInaS5wLQJwa:example.com`) or a room alias (`#roomalias:example.com`): {e}"))),
+                            };
+
+                            debug!("Room specified is not a room ID, attempting to resolve room alias to a room ID locally, if not using get_alias_helper to fetch room ID remotely");
+
+                            let room_id = match services()
+                                .rooms
+                                .alias
+                                .resolve_local_alias(&room_alias)?
+                            {
+                                Some(room_id) => room_id,
                                 None => {
                                     debug!("We don't have this room alias to a room ID locally, attempting to fetch room ID over federation");
 
@@ -1235,6 +2060,123 @@ impl Service {
                     );
                     RoomMessageEventContent::text_html(output_plain, output_html)
                 }
+                RoomCommand::RoomInfo { room_id } => {
+                    if !services().rooms.metadata.exists(&room_id)? {
+                        return Ok(RoomMessageEventContent::text_plain(
+                            "This room is not known to the server.",
+                        ));
+                    }
+
+                    let state_content = |event_type: StateEventType| {
+                        services()
+                            .rooms
+                            .state_accessor
+                            .room_state_get(&room_id, &event_type, "")
+                            .ok()
+                            .flatten()
+                            .map(|pdu| pdu.content.clone())
+                    };
+
+                    let name = services()
+                        .rooms
+                        .state_accessor
+                        .get_name(&room_id)?
+                        .unwrap_or_else(|| "(none)".to_owned());
+                    let canonical_alias = state_content(StateEventType::RoomCanonicalAlias)
+                        .and_then(|content| {
+                            serde_json::from_str::<RoomCanonicalAliasEventContent>(content.get())
+                                .ok()
+                        })
+                        .and_then(|content| content.alias)
+                        .map_or_else(|| "(none)".to_owned(), |alias| alias.to_string());
+                    let room_version = services().rooms.state.get_room_version(&room_id)?;
+                    let join_rule = state_content(StateEventType::RoomJoinRules)
+                        .and_then(|content| {
+                            serde_json::from_str::<RoomJoinRulesEventContent>(content.get()).ok()
+                        })
+                        .map_or_else(
+                            || "(none)".to_owned(),
+                            |content| content.join_rule.as_str().to_owned(),
+                        );
+                    let encrypted = state_content(StateEventType::RoomEncryption).is_some();
+
+                    let (local_members, remote_members) = services()
+                        .rooms
+                        .state_cache
+                        .room_members(&room_id)
+                        .filter_map(|r| r.ok())
+                        .fold((0_usize, 0_usize), |(local, remote), user_id| {
+                            if user_id.server_name() == services().globals.server_name() {
+                                (local + 1, remote)
+                            } else {
+                                (local, remote + 1)
+                            }
+                        });
+                    let invited = services()
+                        .rooms
+                        .state_cache
+                        .room_invited_count(&room_id)?
+                        .unwrap_or(0);
+
+                    let servers: Vec<_> = services()
+                        .rooms
+                        .state_cache
+                        .room_servers(&room_id)
+                        .filter_map(|r| r.ok())
+                        .map(|server| server.to_string())
+                        .collect();
+
+                    RoomMessageEventContent::text_plain(format!(
+                        "Room: {room_id}
+Name: {name}
+Canonical alias: {canonical_alias}
+Room version: {room_version}
+Join rule: {join_rule}
+Encrypted: {encrypted}
+Joined members: {} ({local_members} local, {remote_members} remote)
+Invited members: {invited}
+Servers in the room ({}): {}",
+                        local_members + remote_members,
+                        servers.len(),
+                        servers.join(", ")
+                    ))
+                }
+                RoomCommand::RoomVersions => {
+                    let mut versions: BTreeMap<String, usize> = BTreeMap::new();
+                    for room_id in services().rooms.metadata.iter_ids().filter_map(|r| r.ok()) {
+                        let version = services()
+                            .rooms
+                            .state
+                            .get_room_version(&room_id)
+                            .map_or_else(|_| "unknown".to_owned(), |version| version.to_string());
+                        *versions.entry(version).or_default() += 1;
+                    }
+
+                    let mut msg = String::from("Rooms per room version:\n");
+                    for (version, count) in versions {
+                        let is_version = |v: &RoomVersionId| v.as_str() == version;
+                        let support = if services()
+                            .globals
+                            .stable_room_versions
+                            .iter()
+                            .any(is_version)
+                        {
+                            "stable"
+                        } else if services()
+                            .globals
+                            .unstable_room_versions
+                            .iter()
+                            .any(is_version)
+                        {
+                            "unstable"
+                        } else {
+                            "unsupported"
+                        };
+                        writeln!(msg, "{version} ({support}): {count}").unwrap();
+                    }
+
+                    RoomMessageEventContent::text_plain(msg)
+                }
                 RoomCommand::Alias(command) => match command {
                     RoomAliasCommand::Set {
                         ref room_alias_localpart,
@@ -1476,11 +2418,7 @@ impl Service {
                     RoomMessageEventContent::text_plain("Room enabled.")
                 }
                 FederationCommand::IncomingFederation => {
-                    let map = services()
-                        .globals
-                        .roomid_federationhandletime
-                        .read()
-                        .unwrap();
+                    let map = services().globals.roomid_federationhandletime.to_vec();
                     let mut msg: String = format!("Handling {} incoming pdus:\n", map.len());
 
                     for (r, (e, i)) in map.iter() {
@@ -1493,8 +2431,298 @@ impl Service {
                             elapsed.as_secs() % 60
                         );
                     }
+
+                    let rejected_pdus = services()
+                        .rooms
+                        .event_handler
+                        .rejected_pdus
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .map(|(reason, count)| format!("{reason}: {count}"))
+                        .collect::<Vec<_>>();
+                    if !rejected_pdus.is_empty() {
+                        msg += &format!(
+                            "\nRejected incoming pdus since startup: {}\n",
+                            rejected_pdus.join(", ")
+                        );
+                    }
+
                     RoomMessageEventContent::text_plain(&msg)
                 }
+                FederationCommand::FederationStatus { destination } => {
+                    let destinations = match destination {
+                        Some(destination) => vec![destination.into()],
+                        None => services().sending.known_destinations(),
+                    };
+
+                    let mut msg = format!(
+                        "Send queue status of {} destinations:\n",
+                        destinations.len()
+                    );
+
+                    for destination in destinations {
+                        let status = services().sending.destination_status(&destination);
+
+                        let state = if status.running { "sending" } else { "idle" };
+
+                        let last_success = match status.stats.last_success {
+                            Some(time) => format!(
+                                "{}s ago",
+                                utils::millis_since_unix_epoch().saturating_sub(time.get().into())
+                                    / 1000
+                            ),
+                            None => "never".to_owned(),
+                        };
+
+                        writeln!(
+                            msg,
+                            "{destination}: {state}, circuit {} after {} failures, {} queued PDUs, {} queued EDUs, {} events in current transaction ({}), last success {last_success}",
+                            status.circuit,
+                            status.failures,
+                            status.queued_pdus,
+                            status.queued_edus,
+                            status.active_events,
+                            status.transaction_id.as_deref().unwrap_or("none"),
+                        )
+                        .unwrap();
+                    }
+
+                    RoomMessageEventContent::text_plain(msg)
+                }
+                FederationCommand::DestinationHealth { limit } => {
+                    let now = utils::millis_since_unix_epoch();
+                    let ago = |time: Option<MilliSecondsSinceUnixEpoch>| match time {
+                        Some(time) => {
+                            format!("{}s ago", now.saturating_sub(time.get().into()) / 1000)
+                        }
+                        None => "never".to_owned(),
+                    };
+
+                    let mut destinations = services().sending.all_destination_stats();
+                    destinations.sort_by(|(a_name, a), (b_name, b)| {
+                        b.consecutive_failures
+                            .cmp(&a.consecutive_failures)
+                            .then_with(|| a_name.cmp(b_name))
+                    });
+
+                    let mut msg = format!(
+                        "Transaction statistics of {} destinations:\n",
+                        destinations.len()
+                    );
+
+                    for (destination, stats) in destinations.into_iter().take(limit) {
+                        writeln!(
+                            msg,
+                            "{destination}: last success {}, last failure {}, {} consecutive failures, {} of {} transactions failed, average RTT {}",
+                            ago(stats.last_success),
+                            ago(stats.last_failure),
+                            stats.consecutive_failures,
+                            stats.failed_transactions,
+                            stats.transactions,
+                            stats
+                                .average_rtt_ms
+                                .map_or_else(|| "unknown".to_owned(), |rtt| format!("{rtt}ms")),
+                        )
+                        .unwrap();
+                    }
+
+                    RoomMessageEventContent::text_plain(msg)
+                }
+                FederationCommand::RequestMetrics { destination, limit } => {
+                    let mut destinations = match destination {
+                        Some(destination) => services()
+                            .sending
+                            .request_metrics
+                            .get(&destination)
+                            .map(|metrics| (destination.into(), metrics))
+                            .into_iter()
+                            .collect(),
+                        None => services().sending.request_metrics.all(),
+                    };
+                    destinations.sort_by(|(a_name, a), (b_name, b)| {
+                        b.average_latency()
+                            .cmp(&a.average_latency())
+                            .then_with(|| a_name.cmp(b_name))
+                    });
+
+                    let mut msg = format!(
+                        "Outgoing federation requests to {} destinations since startup:\n",
+                        destinations.len()
+                    );
+
+                    for (destination, metrics) in destinations.into_iter().take(limit) {
+                        let mut responses: Vec<_> = metrics
+                            .status_codes
+                            .iter()
+                            .map(|(status, count)| format!("{status}: {count}"))
+                            .collect();
+                        if metrics.errors > 0 {
+                            responses.push(format!("error: {}", metrics.errors));
+                        }
+
+                        let average_latency = metrics
+                            .average_latency()
+                            .map_or_else(|| "unknown".to_owned(), |latency| format!("{latency:?}"));
+
+                        writeln!(
+                            msg,
+                            "{destination}: {} requests, average latency {average_latency}, max latency {:?}, {} retried transactions, responses {}",
+                            metrics.requests(),
+                            metrics.max_latency,
+                            metrics.retries,
+                            responses.join(", "),
+                        )
+                        .unwrap();
+                    }
+
+                    RoomMessageEventContent::text_plain(msg)
+                }
+                FederationCommand::RetryDestination { server_name } => {
+                    if services().sending.retry_destination(&server_name) {
+                        RoomMessageEventContent::text_plain(format!(
+                            "Cleared the backoff for {server_name}, retrying its send queue now."
+                        ))
+                    } else {
+                        RoomMessageEventContent::text_plain(format!(
+                            "{server_name} was not backing off, flushing pending EDUs anyway."
+                        ))
+                    }
+                }
+                FederationCommand::ShowAcl { room_id } => {
+                    match services().rooms.event_handler.room_acl(&room_id)? {
+                        None => RoomMessageEventContent::text_plain(
+                            "This room has no server ACL, or its ACL is invalid and ignored.",
+                        ),
+                        Some(acl) => {
+                            let mut msg = format!(
+                                "Allow: {}\nDeny: {}\nIP literals allowed: {}\n",
+                                acl.content.allow.join(", "),
+                                acl.content.deny.join(", "),
+                                acl.content.allow_ip_literals
+                            );
+
+                            let denied: Vec<_> = services()
+                                .rooms
+                                .state_cache
+                                .room_servers(&room_id)
+                                .filter_map(|r| r.ok())
+                                .filter(|server| !acl.is_allowed(server))
+                                .collect();
+
+                            if denied.is_empty() {
+                                msg += "No servers in the room are denied.";
+                            } else {
+                                writeln!(msg, "{} servers in the room are denied:", denied.len())
+                                    .unwrap();
+                                for server in denied {
+                                    writeln!(msg, "{server}").unwrap();
+                                }
+                            }
+
+                            RoomMessageEventContent::text_plain(msg)
+                        }
+                    }
+                }
+                FederationCommand::ServerInfo {
+                    server_name,
+                    block,
+                    remove_memberships,
+                } => {
+                    if block && &*server_name == services().globals.server_name() {
+                        return Ok(RoomMessageEventContent::text_plain(
+                            "Not allowed to block our own server.",
+                        ));
+                    }
+
+                    let rooms: Vec<_> = services()
+                        .rooms
+                        .state_cache
+                        .server_rooms(&server_name)
+                        .filter_map(|r| r.ok())
+                        .collect();
+                    let server_members = |room_id: &RoomId| -> Vec<OwnedUserId> {
+                        services()
+                            .rooms
+                            .state_cache
+                            .room_members(room_id)
+                            .filter_map(|r| r.ok())
+                            .filter(|user_id| user_id.server_name() == &*server_name)
+                            .collect()
+                    };
+
+                    let mut msg = format!("{server_name} shares {} rooms with us:\n", rooms.len());
+                    let mut local_users = BTreeSet::new();
+                    for room_id in &rooms {
+                        let room_local_users: Vec<_> = local_members(room_id).collect();
+                        writeln!(
+                            msg,
+                            "{room_id}: {} of their users, {} local users",
+                            server_members(room_id).len(),
+                            room_local_users.len()
+                        )
+                        .unwrap();
+                        local_users.extend(room_local_users);
+                    }
+                    writeln!(
+                        msg,
+                        "\nLocal users sharing rooms with {server_name} ({}): {}",
+                        local_users.len(),
+                        local_users.iter().join(", ")
+                    )
+                    .unwrap();
+
+                    if block {
+                        services().globals.block_server(&server_name, true)?;
+                        writeln!(msg, "\n{server_name} is now blocked.").unwrap();
+
+                        if remove_memberships {
+                            let mut removed = 0_usize;
+                            for room_id in &rooms {
+                                for user_id in server_members(room_id) {
+                                    services()
+                                        .rooms
+                                        .state_cache
+                                        .update_membership(
+                                            room_id,
+                                            &user_id,
+                                            RoomMemberEventContent::new(MembershipState::Leave),
+                                            &user_id,
+                                            None,
+                                            true,
+                                        )
+                                        .await?;
+                                    removed += 1;
+                                }
+                            }
+                            writeln!(msg, "Removed {removed} memberships of its users.").unwrap();
+                        }
+                    }
+
+                    RoomMessageEventContent::text_plain(msg)
+                }
+                FederationCommand::UnblockServer { server_name } => {
+                    services().globals.block_server(&server_name, false)?;
+                    RoomMessageEventContent::text_plain(format!(
+                        "{server_name} is no longer blocked."
+                    ))
+                }
+                FederationCommand::ListBlockedServers => {
+                    let servers: Vec<_> = services()
+                        .globals
+                        .blocked_servers()
+                        .collect::<Result<_>>()?;
+
+                    if servers.is_empty() {
+                        RoomMessageEventContent::text_plain("No servers are blocked.")
+                    } else {
+                        RoomMessageEventContent::text_plain(format!(
+                            "Blocked servers ({}):\n{}",
+                            servers.len(),
+                            servers.iter().join("\n")
+                        ))
+                    }
+                }
                 FederationCommand::SignJson => {
                     if body.len() > 2
                         && body[0].trim().starts_with("```")
@@ -1540,15 +2768,41 @@ impl Service {
                                     .await?;
 
                                 let pub_key_map = pub_key_map.read().unwrap();
-                                match ruma::signatures::verify_json(&pub_key_map, &value) {
-                                    Ok(_) => {
-                                        RoomMessageEventContent::text_plain("Signature correct")
-                                    }
-                                    Err(e) => RoomMessageEventContent::text_plain(format!(
-                                        "Signature verification failed: {e}"
-                                    )),
-                                }
+                                let verified = ruma::signatures::verify_json(&pub_key_map, &value);
+                                let result = match verified {
+                                    Ok(_) => "Signature correct".to_owned(),
+                                    Err(e) => format!("Signature verification failed: {e}"),
+                                };
+                                RoomMessageEventContent::text_plain(format!(
+                                    "{result}\n\n{}",
+                                    signature_report(&value, &pub_key_map)
+                                ))
+                            }
+                            Err(e) => {
+                                RoomMessageEventContent::text_plain(format!("Invalid json: {e}"))
                             }
+                        }
+                    } else {
+                        RoomMessageEventContent::text_plain(
+                            "Expected code block in command body. Add --help for details.",
+                        )
+                    }
+                }
+                FederationCommand::VerifyPdu { room_version } => {
+                    if body.len() > 2
+                        && body[0].trim().starts_with("```")
+                        && body.last().unwrap().trim() == "```"
+                    {
+                        let string = body[1..body.len() - 1].join("\n");
+                        match serde_json::from_str::<CanonicalJsonObject>(&string) {
+                            Ok(value) => match pdu_room_version(&value, room_version) {
+                                Some(room_version) => RoomMessageEventContent::text_plain(
+                                    verify_pdu(value, &room_version).await?,
+                                ),
+                                None => RoomMessageEventContent::text_plain(
+                                    "The room of the event is unknown, please pass --room-version.",
+                                ),
+                            },
                             Err(e) => {
                                 RoomMessageEventContent::text_plain(format!("Invalid json: {e}"))
                             }
@@ -1560,18 +2814,71 @@ impl Service {
                     }
                 }
             },
+            AdminCommand::Media(command) => match command {
+                MediaCommand::ListUserMedia { user_id, limit } => {
+                    let uploads = services().media.uploads_of_user(&user_id)?;
+                    let total_size: u64 = uploads.iter().map(|upload| upload.size).sum();
+
+                    let mut msg = format!(
+                        "{user_id} uploaded {} file(s) with a total size of {total_size} bytes:\n",
+                        uploads.len()
+                    );
+                    for upload in uploads.iter().take(limit.unwrap_or(usize::MAX)) {
+                        writeln!(
+                            msg,
+                            "{} - {} bytes, {}, uploaded {}",
+                            upload.mxc,
+                            upload.size,
+                            upload.content_type.as_deref().unwrap_or("unknown type"),
+                            utils::format_timestamp(upload.uploaded_at),
+                        )
+                        .unwrap();
+                    }
+
+                    RoomMessageEventContent::text_plain(msg)
+                }
+                MediaCommand::QuarantineMedia { mxc } => {
+                    if !<&MxcUri>::from(mxc.as_str()).is_valid() {
+                        return Ok(RoomMessageEventContent::text_plain(
+                            "Invalid MXC URI, expected mxc://server.name/media_id",
+                        ));
+                    }
+
+                    services().media.quarantine(&mxc)?;
+                    RoomMessageEventContent::text_plain(format!("Quarantined {mxc}."))
+                }
+                MediaCommand::UnquarantineMedia { mxc } => {
+                    if services().media.unquarantine(&mxc)? {
+                        RoomMessageEventContent::text_plain(format!("Unquarantined {mxc}."))
+                    } else {
+                        RoomMessageEventContent::text_plain(format!("{mxc} is not quarantined."))
+                    }
+                }
+            },
             AdminCommand::Server(command) => match command {
                 ServerCommand::ShowConfig => {
                     // Construct and send the response
                     RoomMessageEventContent::text_plain(format!("{}", services().globals.config))
                 }
-                ServerCommand::MemoryUsage => {
+                ServerCommand::MemoryUsage { caches } => {
                     let response1 = services().memory_usage();
                     let response2 = services().globals.db.memory_usage();
 
-                    RoomMessageEventContent::text_plain(format!(
-                        "Services:\n{response1}\n\nDatabase:\n{response2}"
-                    ))
+                    let mut msg = format!("Services:\n{response1}\n\nDatabase:\n{response2}");
+
+                    if caches {
+                        msg += "\n\nCaches (estimated size):\n";
+                        for (name, entries, bytes) in services().globals.cache_usage() {
+                            writeln!(
+                                msg,
+                                "{name}: {entries} entries, {:.1} KiB",
+                                bytes as f64 / 1024.0
+                            )
+                            .unwrap();
+                        }
+                    }
+
+                    RoomMessageEventContent::text_plain(msg)
                 }
                 ServerCommand::ClearDatabaseCaches { amount } => {
                     services().globals.db.clear_caches(amount);
@@ -1583,7 +2890,51 @@ impl Service {
 
                     RoomMessageEventContent::text_plain("Done.")
                 }
-            },
+                ServerCommand::ClearCache { name } => {
+                    if services().globals.clear_cache(&name) {
+                        RoomMessageEventContent::text_plain(format!("Cleared {name}."))
+                    } else {
+                        RoomMessageEventContent::text_plain(format!(
+                            "There is no cache called {name}, see `memory-usage --caches` for \
+                             the cache names."
+                        ))
+                    }
+                }
+                ServerCommand::CheckDatabase { repair } => {
+                    // Walks whole trees, which takes minutes on big databases
+                    let check =
+                        utils::spawn_blocking(move || services().globals.check_database(repair))
+                            .await?;
+
+                    let mut msg = format!(
+                        "Found {} inconsistencies, repaired {}.\n",
+                        check.found, check.repaired
+                    );
+                    for problem in check.problems.iter().take(PAGE_SIZE) {
+                        writeln!(msg, "{problem}").unwrap();
+                    }
+                    if check.found > PAGE_SIZE {
+                        writeln!(msg, "... see the log for more").unwrap();
+                    }
+
+                    RoomMessageEventContent::text_plain(msg)
+                }
+                ServerCommand::EnableMaintenanceMode { message } => {
+                    let message = (!message.is_empty()).then(|| message.join(" "));
+                    services().globals.set_maintenance_mode(true, message);
+                    let message = services().globals.maintenance_message().unwrap_or_default();
+
+                    RoomMessageEventContent::text_plain(format!(
+                        "Maintenance mode enabled, write requests of non-admins are refused with \
+                         \"{message}\"."
+                    ))
+                }
+                ServerCommand::DisableMaintenanceMode => {
+                    services().globals.set_maintenance_mode(false, None);
+
+                    RoomMessageEventContent::text_plain("Maintenance mode disabled.")
+                }
+            },
             AdminCommand::Debug(command) => match command {
                 DebugCommand::GetAuthChain { event_id } => {
                     let event_id = Arc::<EventId>::from(event_id);
@@ -1661,23 +3012,22 @@ impl Service {
                         Some(json) => {
                             let json_text = serde_json::to_string_pretty(&json)
                                 .expect("canonical json is valid json");
+                            let status = if !outlier {
+                                "PDU was accepted"
+                            } else if services()
+                                .rooms
+                                .pdu_metadata
+                                .is_event_soft_failed(&event_id)?
+                            {
+                                "PDU is outlier, it was soft failed"
+                            } else {
+                                "PDU is outlier"
+                            };
                             RoomMessageEventContent::text_html(
-                                format!(
-                                    "{}\n```json\n{}\n```",
-                                    if outlier {
-                                        "PDU is outlier"
-                                    } else {
-                                        "PDU was accepted"
-                                    },
-                                    json_text
-                                ),
+                                format!("{}\n```json\n{}\n```", status, json_text),
                                 format!(
                                     "<p>{}</p>\n<pre><code class=\"language-json\">{}\n</code></pre>\n",
-                                    if outlier {
-                                        "PDU is outlier"
-                                    } else {
-                                        "PDU was accepted"
-                                    },
+                                    status,
                                     HtmlEscape(&json_text)
                                 ),
                             )
@@ -1685,6 +3035,12 @@ impl Service {
                         None => RoomMessageEventContent::text_plain("PDU not found."),
                     }
                 }
+                DebugCommand::GetRemotePdu {
+                    server,
+                    event_id,
+                    room_version,
+                    handle,
+                } => get_remote_pdu(&server, &event_id, room_version, handle).await?,
                 DebugCommand::ForceDeviceListUpdates => {
                     // Force E2EE device list updates for all users
                     for user_id in services().users.iter().filter_map(|r| r.ok()) {
@@ -1694,6 +3050,86 @@ impl Service {
                         "Marked all devices for all users as having new keys to update",
                     )
                 }
+                DebugCommand::ListSoftFailed { room_id } => {
+                    let now = utils::millis_since_unix_epoch();
+
+                    let mut msg = String::new();
+                    for (event_id, time) in services()
+                        .rooms
+                        .pdu_metadata
+                        .soft_failed_events(&room_id)
+                        .filter_map(|r| r.ok())
+                    {
+                        let event = match services().rooms.timeline.get_pdu(&event_id)? {
+                            Some(pdu) => format!("{} from {}", pdu.kind, pdu.sender),
+                            None => "event not found".to_owned(),
+                        };
+                        writeln!(
+                            msg,
+                            "{event_id} ({event}), soft failed {}s ago",
+                            now.saturating_sub(time) / 1000
+                        )
+                        .unwrap();
+                    }
+
+                    if msg.is_empty() {
+                        RoomMessageEventContent::text_plain(
+                            "No soft failed events recorded for this room.",
+                        )
+                    } else {
+                        RoomMessageEventContent::text_plain(format!(
+                            "Soft failed events in {room_id}:\n{msg}"
+                        ))
+                    }
+                }
+                DebugCommand::ReprocessOutliers { room_id } => {
+                    if !services().rooms.metadata.exists(&room_id)? {
+                        return Ok(RoomMessageEventContent::text_plain("Room is unknown."));
+                    }
+
+                    let result = services()
+                        .rooms
+                        .event_handler
+                        .reprocess_outliers(&room_id)
+                        .await?;
+
+                    let mut msg = format!(
+                        "Added {} outlier(s) to the timeline, {} soft failed, {} still miss prev \
+                         or auth events and {} failed.\n",
+                        result.accepted.len(),
+                        result.soft_failed,
+                        result.waiting,
+                        result.failed.len()
+                    );
+                    for event_id in result.accepted {
+                        writeln!(msg, "{event_id}: Added").unwrap();
+                    }
+                    for (event_id, error) in result.failed {
+                        writeln!(msg, "{event_id}: {error}").unwrap();
+                    }
+
+                    RoomMessageEventContent::text_plain(msg)
+                }
+                DebugCommand::RepairState { room_id, from } => {
+                    if !services().rooms.metadata.exists(&room_id)? {
+                        return Ok(RoomMessageEventContent::text_plain("Room is unknown."));
+                    }
+
+                    let result = services()
+                        .rooms
+                        .event_handler
+                        .repair_state(&room_id, from.as_deref())
+                        .await?;
+
+                    let previous = result
+                        .previous_shortstatehash
+                        .map_or_else(|| "none".to_owned(), |s| s.to_string());
+                    RoomMessageEventContent::text_plain(format!(
+                        "Resolved {} state(s). Changed the room state from {previous} to {}, {} \
+                         entries were added or changed and {} removed.",
+                        result.forks, result.shortstatehash, result.changed, result.removed
+                    ))
+                }
             },
         };
 
@@ -1807,15 +3243,7 @@ impl Service {
 
         services().rooms.short.get_or_create_shortroomid(&room_id)?;
 
-        let mutex_state = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_state
-                .write()
-                .unwrap()
-                .entry(room_id.clone())
-                .or_default(),
-        );
+        let mutex_state = services().globals.roomid_mutex_state.mutex(&room_id);
         let state_lock = mutex_state.lock().await;
 
         // Create a user for the server
@@ -2054,13 +3482,247 @@ impl Service {
         Ok(())
     }
 
+    /// Create a public room owned by the server user with the given local alias.
+    ///
+    /// Used to create rooms listed in `auto_join_rooms` that do not exist yet.
+    pub(crate) async fn create_public_room_with_alias(
+        &self,
+        alias: &RoomAliasId,
+    ) -> Result<OwnedRoomId> {
+        let room_id = self
+            .create_server_room(
+                true,
+                JoinRule::Public,
+                RoomPowerLevelsEventContent::default(),
+                Some(alias.alias().to_owned()),
+                vec![
+                    // 6. Room alias
+                    PduBuilder {
+                        event_type: TimelineEventType::RoomCanonicalAlias,
+                        content: to_raw_value(&RoomCanonicalAliasEventContent {
+                            alias: Some(alias.to_owned()),
+                            alt_aliases: Vec::new(),
+                        })
+                        .expect("event is valid, we just created it"),
+                        unsigned: None,
+                        state_key: Some("".to_owned()),
+                        redacts: None,
+                    },
+                ],
+            )
+            .await?;
+
+        services().rooms.alias.set_alias(alias, &room_id)?;
+
+        Ok(room_id)
+    }
+
+    /// Creates a room owned by the server user, with invite or public join rules, shared history
+    /// and no guest access, and sends the given events as the server user. The server user gets
+    /// power level 100 in addition to the given power levels.
+    async fn create_server_room(
+        &self,
+        federate: bool,
+        join_rule: JoinRule,
+        mut power_levels: RoomPowerLevelsEventContent,
+        name: Option<String>,
+        events: Vec<PduBuilder>,
+    ) -> Result<OwnedRoomId> {
+        let room_id = RoomId::new(services().globals.server_name());
+
+        services().rooms.short.get_or_create_shortroomid(&room_id)?;
+
+        let mutex_state = services().globals.roomid_mutex_state.mutex(&room_id);
+        let state_lock = mutex_state.lock().await;
+
+        let conduit_user =
+            UserId::parse_with_server_name("conduit", services().globals.server_name())
+                .expect("@conduit:server_name is valid");
+
+        let room_version = services().globals.default_room_version();
+        let mut content = match room_version {
+            RoomVersionId::V1
+            | RoomVersionId::V2
+            | RoomVersionId::V3
+            | RoomVersionId::V4
+            | RoomVersionId::V5
+            | RoomVersionId::V6
+            | RoomVersionId::V7
+            | RoomVersionId::V8
+            | RoomVersionId::V9
+            | RoomVersionId::V10 => RoomCreateEventContent::new_v1(conduit_user.clone()),
+            RoomVersionId::V11 => RoomCreateEventContent::new_v11(),
+            _ => {
+                warn!("Unexpected or unsupported room version {}", room_version);
+                return Err(Error::BadRequest(
+                    ErrorKind::BadJson,
+                    "Unexpected or unsupported room version found",
+                ));
+            }
+        };
+
+        content.federate = federate;
+        content.predecessor = None;
+        content.room_version = room_version;
+
+        power_levels.users.insert(conduit_user.clone(), 100.into());
+
+        let mut initial_events = vec![
+            // 1. The room create event
+            PduBuilder {
+                event_type: TimelineEventType::RoomCreate,
+                content: to_raw_value(&content).expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            // 2. Make conduit bot join
+            PduBuilder {
+                event_type: TimelineEventType::RoomMember,
+                content: to_raw_value(&RoomMemberEventContent::new(MembershipState::Join))
+                    .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(conduit_user.to_string()),
+                redacts: None,
+            },
+            // 3. Power levels
+            PduBuilder {
+                event_type: TimelineEventType::RoomPowerLevels,
+                content: to_raw_value(&power_levels).expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            // 4.1 Join Rules
+            PduBuilder {
+                event_type: TimelineEventType::RoomJoinRules,
+                content: to_raw_value(&RoomJoinRulesEventContent::new(join_rule))
+                    .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            // 4.2 History Visibility
+            PduBuilder {
+                event_type: TimelineEventType::RoomHistoryVisibility,
+                content: to_raw_value(&RoomHistoryVisibilityEventContent::new(
+                    HistoryVisibility::Shared,
+                ))
+                .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            // 4.3 Guest Access
+            PduBuilder {
+                event_type: TimelineEventType::RoomGuestAccess,
+                content: to_raw_value(&RoomGuestAccessEventContent::new(GuestAccess::Forbidden))
+                    .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+        ];
+
+        // 5. Events implied by name
+        if let Some(name) = name {
+            initial_events.push(PduBuilder {
+                event_type: TimelineEventType::RoomName,
+                content: to_raw_value(&RoomNameEventContent::new(name))
+                    .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            });
+        }
+
+        for event in initial_events.into_iter().chain(events) {
+            services()
+                .rooms
+                .timeline
+                .build_and_append_pdu(event, &conduit_user, &room_id, &state_lock)
+                .await?;
+        }
+
+        Ok(room_id)
+    }
+
+    /// Send the configured `welcome_message` to a newly registered user in a new direct message
+    /// room with the server user. Does nothing if no welcome message is configured.
+    pub(crate) async fn send_welcome_message(&self, user_id: &UserId) -> Result<()> {
+        let Some(body) = services().globals.welcome_message() else {
+            return Ok(());
+        };
+
+        let fill_template = |template: &str| {
+            template
+                .replace("{user_id}", user_id.as_str())
+                .replace("{localpart}", user_id.localpart())
+                .replace("{server_name}", services().globals.server_name().as_str())
+        };
+
+        let message = match services().globals.welcome_message_html() {
+            Some(html) => {
+                RoomMessageEventContent::text_html(fill_template(body), fill_template(html))
+            }
+            None => RoomMessageEventContent::text_markdown(fill_template(body)),
+        };
+
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        power_levels.users.insert(user_id.to_owned(), 100.into());
+
+        let events = vec![
+            // 6. Invite the new user as a direct message
+            PduBuilder {
+                event_type: TimelineEventType::RoomMember,
+                content: to_raw_value(&RoomMemberEventContent {
+                    membership: MembershipState::Invite,
+                    displayname: None,
+                    avatar_url: None,
+                    is_direct: Some(true),
+                    third_party_invite: None,
+                    blurhash: None,
+                    reason: None,
+                    join_authorized_via_users_server: None,
+                })
+                .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(user_id.to_string()),
+                redacts: None,
+            },
+            // 7. The welcome message itself
+            PduBuilder {
+                event_type: TimelineEventType::RoomMessage,
+                content: to_raw_value(&message).expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: None,
+                redacts: None,
+            },
+        ];
+
+        self.create_server_room(
+            true,
+            JoinRule::Invite,
+            power_levels,
+            services()
+                .globals
+                .welcome_message_subject()
+                .as_deref()
+                .map(fill_template),
+            events,
+        )
+        .await?;
+
+        Ok(())
+    }
+
     /// Invite the user to the conduit admin room.
     ///
     /// In conduit, this is equivalent to granting admin privileges.
     pub(crate) async fn make_user_admin(
         &self,
         user_id: &UserId,
-        displayname: String,
+        displayname: Option<String>,
     ) -> Result<()> {
         let admin_room_alias: Box<RoomAliasId> =
             format!("#admins:{}", services().globals.server_name())
@@ -2072,15 +3734,7 @@ impl Service {
             .resolve_local_alias(&admin_room_alias)?
             .expect("Admin room must exist");
 
-        let mutex_state = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_state
-                .write()
-                .unwrap()
-                .entry(room_id.clone())
-                .or_default(),
-        );
+        let mutex_state = services().globals.roomid_mutex_state.mutex(&room_id);
         let state_lock = mutex_state.lock().await;
 
         // Use the server user to grant the new admin's power level
@@ -2123,7 +3777,7 @@ impl Service {
                     event_type: TimelineEventType::RoomMember,
                     content: to_raw_value(&RoomMemberEventContent {
                         membership: MembershipState::Join,
-                        displayname: Some(displayname),
+                        displayname,
                         avatar_url: None,
                         is_direct: None,
                         third_party_invite: None,
@@ -2142,10 +3796,12 @@ impl Service {
             )
             .await?;
 
-        // Set power level
-        let mut users = BTreeMap::new();
-        users.insert(conduit_user.to_owned(), 100.into());
-        users.insert(user_id.to_owned(), 100.into());
+        // Set power level, keeping the power levels of the other admins
+        let mut power_levels = admin_room_power_levels(&room_id)?;
+        power_levels
+            .users
+            .insert(conduit_user.to_owned(), 100.into());
+        power_levels.users.insert(user_id.to_owned(), 100.into());
 
         services()
             .rooms
@@ -2153,13 +3809,104 @@ impl Service {
             .build_and_append_pdu(
                 PduBuilder {
                     event_type: TimelineEventType::RoomPowerLevels,
-                    content: to_raw_value(&RoomPowerLevelsEventContent {
-                        users,
-                        ..Default::default()
+                    content: to_raw_value(&power_levels)
+                        .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some("".to_owned()),
+                    redacts: None,
+                },
+                &conduit_user,
+                &room_id,
+                &state_lock,
+            )
+            .await?;
+
+        // Tell the new admin how to use the admin room
+        services()
+            .rooms
+            .timeline
+            .build_and_append_pdu(
+                PduBuilder {
+                    event_type: TimelineEventType::RoomMessage,
+                    content: to_raw_value(&RoomMessageEventContent::text_markdown(format!(
+                        "For a list of available commands, send the following message in this room: `@conduit:{}: --help`",
+                        services().globals.server_name()
+                    )))
+                    .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: None,
+                    redacts: None,
+                },
+                &conduit_user,
+                &room_id,
+                &state_lock,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Revokes the admin privileges of a user by removing their power level in the admin room and
+    /// kicking them from it.
+    pub(crate) async fn revoke_user_admin(&self, user_id: &UserId) -> Result<()> {
+        let admin_room_alias: Box<RoomAliasId> =
+            format!("#admins:{}", services().globals.server_name())
+                .try_into()
+                .expect("#admins:server_name is a valid alias name");
+        let room_id = services()
+            .rooms
+            .alias
+            .resolve_local_alias(&admin_room_alias)?
+            .expect("Admin room must exist");
+
+        let mutex_state = services().globals.roomid_mutex_state.mutex(&room_id);
+        let state_lock = mutex_state.lock().await;
+
+        let conduit_user =
+            UserId::parse_with_server_name("conduit", services().globals.server_name())
+                .expect("@conduit:server_name is valid");
+
+        let mut power_levels = admin_room_power_levels(&room_id)?;
+        if power_levels.users.remove(user_id).is_some() {
+            services()
+                .rooms
+                .timeline
+                .build_and_append_pdu(
+                    PduBuilder {
+                        event_type: TimelineEventType::RoomPowerLevels,
+                        content: to_raw_value(&power_levels)
+                            .expect("event is valid, we just created it"),
+                        unsigned: None,
+                        state_key: Some("".to_owned()),
+                        redacts: None,
+                    },
+                    &conduit_user,
+                    &room_id,
+                    &state_lock,
+                )
+                .await?;
+        }
+
+        // Users are admins as long as they are in the admin room
+        services()
+            .rooms
+            .timeline
+            .build_and_append_pdu(
+                PduBuilder {
+                    event_type: TimelineEventType::RoomMember,
+                    content: to_raw_value(&RoomMemberEventContent {
+                        membership: MembershipState::Leave,
+                        displayname: None,
+                        avatar_url: None,
+                        is_direct: None,
+                        third_party_invite: None,
+                        blurhash: None,
+                        reason: Some("Admin privileges revoked".to_owned()),
+                        join_authorized_via_users_server: None,
                     })
                     .expect("event is valid, we just created it"),
                     unsigned: None,
-                    state_key: Some("".to_owned()),
+                    state_key: Some(user_id.to_string()),
                     redacts: None,
                 },
                 &conduit_user,
@@ -2168,26 +3915,723 @@ impl Service {
             )
             .await?;
 
-        // Send welcome message
-        services().rooms.timeline.build_and_append_pdu(
+        Ok(())
+    }
+
+    /// Makes a room read-only by raising the power level required to send any event to the level
+    /// of the most powerful local member, who sends the new power levels. Our users can't set
+    /// levels above their own, so local members with that level can still send events, they are
+    /// returned.
+    pub(crate) async fn freeze_room(&self, room_id: &RoomId) -> Result<Vec<OwnedUserId>> {
+        let power_levels_event = services()
+            .rooms
+            .state_accessor
+            .room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+            .ok_or(Error::BadRequest(
+                ErrorKind::NotFound,
+                "The room has no power levels event.",
+            ))?;
+        let mut power_levels: RoomPowerLevelsEventContent =
+            serde_json::from_str(power_levels_event.content.get())
+                .map_err(|_| Error::bad_database("Invalid power levels event in database."))?;
+
+        let (sender, level) = power_levels_sender(room_id, &power_levels)?;
+
+        power_levels.events_default = level;
+        power_levels.state_default = level;
+        for required in power_levels.events.values_mut() {
+            *required = level;
+        }
+
+        send_power_levels(room_id, &sender, &power_levels).await?;
+
+        services()
+            .rooms
+            .metadata
+            .set_frozen_power_levels(room_id, Some(power_levels_event.content.get()))?;
+
+        Ok(local_members(room_id)
+            .filter(|user_id| user_power_level(&power_levels, user_id) >= level)
+            .collect())
+    }
+
+    /// Restores the levels required to send events that a room had before it was frozen. Changes
+    /// to the power levels of users since then are kept.
+    pub(crate) async fn unfreeze_room(&self, room_id: &RoomId) -> Result<()> {
+        let Some(frozen) = services().rooms.metadata.frozen_power_levels(room_id)? else {
+            return Ok(());
+        };
+        let frozen: RoomPowerLevelsEventContent = serde_json::from_str(&frozen)
+            .map_err(|_| Error::bad_database("Invalid power levels of frozen room."))?;
+
+        let mut power_levels = room_power_levels(room_id)?.unwrap_or_default();
+        let (sender, _) = power_levels_sender(room_id, &power_levels)?;
+
+        power_levels.events_default = frozen.events_default;
+        power_levels.state_default = frozen.state_default;
+        power_levels.events = frozen.events;
+
+        send_power_levels(room_id, &sender, &power_levels).await?;
+
+        services()
+            .rooms
+            .metadata
+            .set_frozen_power_levels(room_id, None)
+    }
+
+    /// Blocks a room from being joined again and makes all local members leave it. If a notice is
+    /// given, the members are moved into a new room that contains it. With `purge`, the local
+    /// aliases and directory entry of the room are removed and its federation is disabled. Returns
+    /// the users that were removed and the notice room.
+    pub(crate) async fn shutdown_room(
+        &self,
+        room_id: &RoomId,
+        notice: Option<&str>,
+        purge: bool,
+    ) -> Result<(Vec<OwnedUserId>, Option<OwnedRoomId>)> {
+        services().rooms.metadata.ban_room(room_id, true)?;
+
+        let conduit_user =
+            UserId::parse_with_server_name("conduit", services().globals.server_name())
+                .expect("@conduit:server_name is valid");
+
+        let users: Vec<_> = local_members(room_id).collect();
+        for user_id in &users {
+            if let Err(e) = leave_room(user_id, room_id, None).await {
+                warn!("Failed to make {user_id} leave {room_id} during shutdown: {e}");
+            }
+        }
+
+        if purge {
+            for alias in services()
+                .rooms
+                .alias
+                .local_aliases_for_room(room_id)
+                .filter_map(Result::ok)
+                .collect::<Vec<_>>()
+            {
+                services().rooms.alias.remove_alias(&alias)?;
+            }
+            services().rooms.directory.set_not_public(room_id)?;
+            services().rooms.metadata.disable_room(room_id, true)?;
+        }
+
+        let notice_room_id = match notice {
+            Some(notice) => {
+                let members: Vec<_> = users
+                    .iter()
+                    .filter(|user_id| **user_id != conduit_user)
+                    .cloned()
+                    .collect();
+                Some(self.create_notice_room(notice, &members).await?)
+            }
+            None => None,
+        };
+
+        info!(
+            "Shut down room {room_id}, removed {} local users",
+            users.len()
+        );
+
+        Ok((users, notice_room_id))
+    }
+
+    /// Creates a read-only room owned by the server user that contains the notice and joins the
+    /// users to it.
+    async fn create_notice_room(&self, notice: &str, users: &[OwnedUserId]) -> Result<OwnedRoomId> {
+        let power_levels = RoomPowerLevelsEventContent {
+            // Only the server user can send events
+            events_default: 100.into(),
+            ..Default::default()
+        };
+
+        let mut events = vec![
+            // 6. The notice itself
             PduBuilder {
                 event_type: TimelineEventType::RoomMessage,
-                content: to_raw_value(&RoomMessageEventContent::text_html(
-                        format!("## Thank you for trying out conduwuit!\n\nconduwuit is a fork of upstream Conduit which is in Beta. This means you can join and participate in most Matrix rooms, but not all features are supported and you might run into bugs from time to time.\n\nHelpful links:\n> Git and Documentation: https://github.com/girlbossceo/conduit\n> Report issues: https://github.com/girlbossceo/conduwuit/issues\n\nFor a list of available commands, send the following message in this room: `@conduit:{}: --help`\n\nHere are some rooms you can join (by typing the command):\n\nconduwuit room (Ask questions and get notified on updates):\n`/join #conduwuit:puppygock.gay`", services().globals.server_name()),
-                        format!("<h2>Thank you for trying out conduwuit!</h2>\n<p>conduwuit is a fork of upstream Conduit which is in Beta. This means you can join and participate in most Matrix rooms, but not all features are supported and you might run into bugs from time to time.</p>\n<p>Helpful links:</p>\n<blockquote>\n<p>Git and Documentation: https://github.com/girlbossceo/conduit<br>Report issues: https://github.com/girlbossceo/conduwuit/issues</p>\n</blockquote>\n<p>For a list of available commands, send the following message in this room: <code>@conduit:{}: --help</code></p>\n<p>Here are some rooms you can join (by typing the command):</p>\n<p>conduwuit room (Ask questions and get notified on updates):<br><code>/join #conduwuit:puppygock.gay</code></p>\n", services().globals.server_name()),
-                ))
-                .expect("event is valid, we just created it"),
+                content: to_raw_value(&RoomMessageEventContent::text_markdown(notice))
+                    .expect("event is valid, we just created it"),
                 unsigned: None,
                 state_key: None,
                 redacts: None,
             },
-            &conduit_user,
-            &room_id,
+        ];
+
+        // 7. Invite the users
+        for user_id in users {
+            events.push(PduBuilder {
+                event_type: TimelineEventType::RoomMember,
+                content: to_raw_value(&RoomMemberEventContent::new(MembershipState::Invite))
+                    .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(user_id.to_string()),
+                redacts: None,
+            });
+        }
+
+        let room_id = self
+            .create_server_room(
+                false,
+                JoinRule::Invite,
+                power_levels,
+                Some("Room shut down".to_owned()),
+                events,
+            )
+            .await?;
+
+        let mutex_state = services().globals.roomid_mutex_state.mutex(&room_id);
+        let state_lock = mutex_state.lock().await;
+
+        // 8. Join the users in their name
+        for user_id in users {
+            let event = PduBuilder {
+                event_type: TimelineEventType::RoomMember,
+                content: to_raw_value(&RoomMemberEventContent {
+                    membership: MembershipState::Join,
+                    displayname: services().users.displayname(user_id)?,
+                    avatar_url: services().users.avatar_url(user_id)?,
+                    is_direct: None,
+                    third_party_invite: None,
+                    blurhash: services().users.blurhash(user_id)?,
+                    reason: None,
+                    join_authorized_via_users_server: None,
+                })
+                .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(user_id.to_string()),
+                redacts: None,
+            };
+
+            if let Err(e) = services()
+                .rooms
+                .timeline
+                .build_and_append_pdu(event, user_id, &room_id, &state_lock)
+                .await
+            {
+                warn!("Failed to move {user_id} to notice room {room_id}: {e}");
+            }
+        }
+
+        Ok(room_id)
+    }
+
+    /// Writes everything the server stores about a user to a tar archive: `user.json` with the
+    /// profile, devices, account data, rooms and uploads, `messages.jsonl` with the events the user
+    /// sent and `media/` with the uploaded files. Returns the number of events and files. This
+    /// walks the timelines of all rooms of the user, so it has to run on a blocking thread.
+    pub(crate) fn export_user_data(
+        &self,
+        user_id: &UserId,
+        path: &Path,
+        runtime: &Handle,
+    ) -> Result<(usize, usize)> {
+        let mut archive = tar::Builder::new(std::fs::File::create(path)?);
+        let mtime = utils::millis_since_unix_epoch() / 1000;
+
+        let mut rooms = BTreeMap::new();
+        let memberships = [
+            (
+                "join",
+                services()
+                    .rooms
+                    .state_cache
+                    .rooms_joined(user_id)
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            (
+                "invite",
+                services()
+                    .rooms
+                    .state_cache
+                    .rooms_invited(user_id)
+                    .map(|r| r.map(|(room_id, _)| room_id))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            (
+                "leave",
+                services()
+                    .rooms
+                    .state_cache
+                    .rooms_left(user_id)
+                    .map(|r| r.map(|(room_id, _)| room_id))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+        ];
+        for (membership, room_ids) in memberships {
+            for room_id in room_ids {
+                let account_data = services()
+                    .account_data
+                    .changes_since(Some(&room_id), user_id, 0)?
+                    .into_values()
+                    .collect();
+                rooms.insert(
+                    room_id,
+                    ExportedRoom {
+                        membership,
+                        account_data,
+                    },
+                );
+            }
+        }
+
+        // Entries of the archive need their size upfront, so the sent events are collected in a
+        // temporary file first
+        let messages_path = path.with_extension("messages.tmp");
+        let message_count =
+            write_sent_events(user_id, rooms.keys(), &messages_path).and_then(|count| {
+                archive.append_path_with_name(&messages_path, "messages.jsonl")?;
+                Ok(count)
+            });
+        let _ = std::fs::remove_file(&messages_path);
+        let message_count = message_count?;
+
+        let mut media = Vec::new();
+        let mut file_count = 0_usize;
+        for upload in services().media.uploads_of_user(user_id)? {
+            // Quarantined and deleted files are listed without a file
+            let media_file = runtime.block_on(async {
+                match services().media.open(upload.mxc.clone()).await {
+                    Ok(Some(media_file)) => Some(media_file.file.into_std().await),
+                    _ => None,
+                }
+            });
+            let file = match media_file {
+                Some(mut media_file) => {
+                    let name = general_purpose::URL_SAFE_NO_PAD.encode(&upload.mxc);
+                    archive.append_file(format!("media/{name}"), &mut media_file)?;
+                    file_count += 1;
+                    Some(name)
+                }
+                None => None,
+            };
+
+            media.push(ExportedUpload {
+                mxc: upload.mxc,
+                content_type: upload.content_type,
+                size: upload.size,
+                uploaded_at: utils::format_timestamp(upload.uploaded_at),
+                file,
+            });
+        }
+
+        let export = UserDataExport {
+            user_id: user_id.to_owned(),
+            exported_at: utils::format_timestamp(utils::millis_since_unix_epoch()),
+            displayname: services().users.displayname(user_id)?,
+            avatar_url: services().users.avatar_url(user_id)?,
+            blurhash: services().users.blurhash(user_id)?,
+            deactivated: services().users.is_deactivated(user_id)?,
+            devices: services()
+                .users
+                .all_devices_metadata(user_id)
+                .collect::<Result<_>>()?,
+            account_data: services()
+                .account_data
+                .changes_since(None, user_id, 0)?
+                .into_values()
+                .collect(),
+            rooms,
+            media,
+        };
+        let export = serde_json::to_vec_pretty(&export).expect("export can be serialized");
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(export.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        archive.append_data(&mut header, "user.json", export.as_slice())?;
+
+        archive.into_inner()?.sync_all()?;
+
+        Ok((message_count, file_count))
+    }
+}
+
+/// Writes the events a user sent to the rooms to a file, one per line. Returns the number of
+/// events.
+fn write_sent_events<'a>(
+    user_id: &UserId,
+    room_ids: impl Iterator<Item = &'a OwnedRoomId>,
+    path: &Path,
+) -> Result<usize> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut count = 0_usize;
+
+    for room_id in room_ids {
+        let pdus = services()
+            .rooms
+            .timeline
+            .pdus_after(user_id, room_id, PduCount::min())?
+            .filter_map(|r| r.ok())
+            .filter(|(_, pdu)| *pdu.sender == *user_id);
+        for (_, pdu) in pdus {
+            serde_json::to_writer(&mut file, &pdu.to_room_event())
+                .expect("events can be serialized");
+            file.write_all(b"\n")?;
+            count += 1;
+        }
+    }
+    file.flush()?;
+
+    Ok(count)
+}
+
+/// Sends a power levels event to a room on behalf of a local user.
+async fn send_power_levels(
+    room_id: &RoomId,
+    sender: &UserId,
+    power_levels: &RoomPowerLevelsEventContent,
+) -> Result<()> {
+    let mutex_state = services().globals.roomid_mutex_state.mutex(room_id);
+    let state_lock = mutex_state.lock().await;
+
+    services()
+        .rooms
+        .timeline
+        .build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomPowerLevels,
+                content: to_raw_value(power_levels).expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            sender,
+            room_id,
             &state_lock,
-        ).await?;
+        )
+        .await?;
 
-        Ok(())
+    Ok(())
+}
+
+/// Returns the current power levels of a room, if it has a power levels event.
+fn room_power_levels(room_id: &RoomId) -> Result<Option<RoomPowerLevelsEventContent>> {
+    services()
+        .rooms
+        .state_accessor
+        .room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+        .map(|event| {
+            serde_json::from_str(event.content.get())
+                .map_err(|_| Error::bad_database("Invalid power levels event in database."))
+        })
+        .transpose()
+}
+
+/// Returns the power level of a user according to the power levels of a room.
+fn user_power_level(power_levels: &RoomPowerLevelsEventContent, user_id: &UserId) -> Int {
+    power_levels
+        .users
+        .get(user_id)
+        .copied()
+        .unwrap_or(power_levels.users_default)
+}
+
+/// Returns the joined local members of a room.
+fn local_members(room_id: &RoomId) -> impl Iterator<Item = OwnedUserId> {
+    services()
+        .rooms
+        .state_cache
+        .room_members(room_id)
+        .filter_map(|r| r.ok())
+        .filter(|user_id| user_id.server_name() == services().globals.server_name())
+}
+
+/// Returns the local member with the highest power level that is allowed to change the power
+/// levels of the room, and their level.
+fn power_levels_sender(
+    room_id: &RoomId,
+    power_levels: &RoomPowerLevelsEventContent,
+) -> Result<(OwnedUserId, Int)> {
+    let required = power_levels
+        .events
+        .get(&TimelineEventType::RoomPowerLevels)
+        .copied()
+        .unwrap_or(power_levels.state_default);
+
+    local_members(room_id)
+        .map(|user_id| {
+            let level = user_power_level(power_levels, &user_id);
+            (user_id, level)
+        })
+        .filter(|(_, level)| *level >= required)
+        .max_by_key(|(_, level)| *level)
+        .ok_or(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "No local member of the room is allowed to change its power levels.",
+        ))
+}
+
+/// Returns the current power levels of the admin room.
+fn admin_room_power_levels(room_id: &RoomId) -> Result<RoomPowerLevelsEventContent> {
+    services()
+        .rooms
+        .state_accessor
+        .room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+        .map(|event| {
+            serde_json::from_str(event.content.get())
+                .map_err(|_| Error::bad_database("Invalid power levels event in admin room."))
+        })
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
+/// Makes the users of an appservice leave their rooms, removes their devices and removes its
+/// aliases.
+async fn purge_appservice(
+    id: &str,
+    users: &[OwnedUserId],
+    aliases: &[OwnedRoomAliasId],
+) -> Result<()> {
+    for user_id in users {
+        if let Err(e) = leave_all_rooms(user_id).await {
+            warn!("Failed to make {user_id} leave their rooms: {e}");
+        }
+
+        for device_id in services()
+            .users
+            .all_device_ids(user_id)
+            .filter_map(Result::ok)
+            .collect::<Vec<_>>()
+        {
+            services().users.remove_device(user_id, &device_id)?;
+        }
+    }
+
+    for alias in aliases {
+        services().rooms.alias.remove_alias(alias)?;
+    }
+
+    info!(
+        "Purged {} users and {} aliases of appservice {id}",
+        users.len(),
+        aliases.len()
+    );
+
+    Ok(())
+}
+
+/// Checks every signature of the JSON on its own and describes why the invalid ones fail.
+fn signature_report(
+    object: &CanonicalJsonObject,
+    pub_key_map: &BTreeMap<String, BTreeMap<String, Base64>>,
+) -> String {
+    let Some(CanonicalJsonValue::Object(signatures)) = object.get("signatures") else {
+        return "The JSON has no signatures.".to_owned();
+    };
+
+    let mut report = String::new();
+    for (server, server_signatures) in signatures {
+        let CanonicalJsonValue::Object(server_signatures) = server_signatures else {
+            writeln!(report, "{server}: Failed: the signatures are not an object").unwrap();
+            continue;
+        };
+
+        for (key_id, signature) in server_signatures {
+            let result = match pub_key_map.get(server).and_then(|keys| keys.get(key_id)) {
+                Some(key) => {
+                    // Verify the signature on its own, with only its key
+                    let mut single = object.clone();
+                    single.insert(
+                        "signatures".to_owned(),
+                        CanonicalJsonValue::Object(BTreeMap::from([(
+                            server.clone(),
+                            CanonicalJsonValue::Object(BTreeMap::from([(
+                                key_id.clone(),
+                                signature.clone(),
+                            )])),
+                        )])),
+                    );
+                    let keys = BTreeMap::from([(
+                        server.clone(),
+                        BTreeMap::from([(key_id.clone(), key.clone())]),
+                    )]);
+
+                    match ruma::signatures::verify_json(&keys, &single) {
+                        Ok(_) => "Valid".to_owned(),
+                        Err(e) => format!("Failed: {e}"),
+                    }
+                }
+                None => {
+                    "Failed: the key could not be fetched from the server or a notary".to_owned()
+                }
+            };
+
+            writeln!(report, "{server} {key_id}: {result}").unwrap();
+        }
+    }
+
+    report
+}
+
+/// Returns the given room version, or the version of the room of the PDU if we know it.
+fn pdu_room_version(
+    value: &CanonicalJsonObject,
+    room_version: Option<RoomVersionId>,
+) -> Option<RoomVersionId> {
+    room_version.or_else(|| match value.get("room_id") {
+        Some(CanonicalJsonValue::String(room_id)) => RoomId::parse(room_id)
+            .ok()
+            .and_then(|room_id| services().rooms.state.get_room_version(&room_id).ok()),
+        _ => None,
+    })
+}
+
+/// Returns the event ID of a PDU, which is its reference hash except in room versions 1 and 2,
+/// where it is only stored in the PDU.
+fn pdu_event_id(
+    value: &CanonicalJsonObject,
+    room_version: &RoomVersionId,
+) -> Result<String, String> {
+    match room_version {
+        RoomVersionId::V1 | RoomVersionId::V2 => match value.get("event_id") {
+            Some(CanonicalJsonValue::String(event_id)) => Ok(event_id.clone()),
+            _ => Err("The event has no event ID".to_owned()),
+        },
+        _ => ruma::signatures::reference_hash(value, room_version)
+            .map(|hash| format!("${hash}"))
+            .map_err(|e| format!("Could not calculate the event ID: {e}")),
+    }
+}
+
+/// Describes the event ID, content hash and signatures of a PDU.
+async fn verify_pdu(value: CanonicalJsonObject, room_version: &RoomVersionId) -> Result<String> {
+    let event_id = match pdu_event_id(&value, room_version) {
+        Ok(event_id) => event_id,
+        Err(e) => return Ok(e),
+    };
+
+    let pub_key_map = RwLock::new(BTreeMap::new());
+    services()
+        .rooms
+        .event_handler
+        .fetch_required_signing_keys([&value], &pub_key_map)
+        .await?;
+    let pub_key_map = pub_key_map.into_inner().unwrap();
+
+    let result = match ruma::signatures::verify_event(&pub_key_map, &value, room_version) {
+        Ok(Verified::All) => "Signatures and content hash are valid".to_owned(),
+        Ok(Verified::Signatures) => {
+            "Signatures are valid, but the content hash doesn't match, so it would be redacted"
+                .to_owned()
+        }
+        Err(e) => format!("Verification failed: {e}"),
+    };
+
+    // Signatures are calculated over the redacted event
+    let signatures = match ruma::canonical_json::redact(value, room_version, None) {
+        Ok(redacted) => signature_report(&redacted, &pub_key_map),
+        Err(e) => format!("Could not redact the event to check its signatures: {e}"),
+    };
+
+    Ok(format!(
+        "Event ID: {event_id}\nRoom version: {room_version}\n{result}\n\n{signatures}"
+    ))
+}
+
+/// Fetches a PDU over federation, verifies it and optionally processes it like an incoming event.
+async fn get_remote_pdu(
+    server: &ServerName,
+    event_id: &EventId,
+    room_version: Option<RoomVersionId>,
+    handle: bool,
+) -> Result<RoomMessageEventContent> {
+    let response = match services()
+        .sending
+        .send_federation_request(
+            server,
+            get_event::v1::Request {
+                event_id: event_id.to_owned(),
+            },
+        )
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            return Ok(RoomMessageEventContent::text_plain(format!(
+                "Failed to fetch the event from {server}: {e}"
+            )))
+        }
+    };
+
+    let value: CanonicalJsonObject = match serde_json::from_str(response.pdu.get()) {
+        Ok(value) => value,
+        Err(e) => {
+            return Ok(RoomMessageEventContent::text_plain(format!(
+                "{server} returned invalid json: {e}"
+            )))
+        }
+    };
+
+    let Some(room_version) = pdu_room_version(&value, room_version) else {
+        return Ok(RoomMessageEventContent::text_plain(
+            "The room of the event is unknown, please pass --room-version.",
+        ));
+    };
+
+    let mut report = verify_pdu(value.clone(), &room_version).await?;
+
+    if handle {
+        let result = handle_remote_pdu(server, event_id, value.clone(), &room_version).await;
+        report.push('\n');
+        report.push_str(&match result {
+            Ok(Some(_)) => "The event was added to the timeline".to_owned(),
+            Ok(None) => "The event was handled, but not added to the timeline".to_owned(),
+            Err(e) => format!("Failed to handle the event: {e}"),
+        });
+    }
+
+    let json_text = serde_json::to_string_pretty(&value).expect("canonical json is valid json");
+
+    Ok(RoomMessageEventContent::text_html(
+        format!("{report}\n```json\n{json_text}\n```"),
+        format!(
+            "<pre>{}</pre>\n<pre><code class=\"language-json\">{}\n</code></pre>\n",
+            HtmlEscape(&report),
+            HtmlEscape(&json_text)
+        ),
+    ))
+}
+
+/// Processes a PDU fetched from the server as if the server had sent it to us.
+async fn handle_remote_pdu(
+    server: &ServerName,
+    event_id: &EventId,
+    value: CanonicalJsonObject,
+    room_version: &RoomVersionId,
+) -> Result<Option<Vec<u8>>> {
+    let calculated_event_id = pdu_event_id(&value, room_version)
+        .map_err(|_| Error::BadServerResponse("Could not calculate the event ID."))?;
+    if calculated_event_id != event_id.as_str() {
+        return Err(Error::BadServerResponse(
+            "The server returned a different event than requested.",
+        ));
+    }
+
+    let room_id = match value.get("room_id") {
+        Some(CanonicalJsonValue::String(room_id)) => RoomId::parse(room_id).ok(),
+        _ => None,
     }
+    .ok_or(Error::BadServerResponse("The event has no valid room ID."))?;
+
+    let pub_key_map = RwLock::new(BTreeMap::new());
+    services()
+        .rooms
+        .event_handler
+        .fetch_required_signing_keys([&value], &pub_key_map)
+        .await?;
+
+    let mutex = services().globals.roomid_mutex_federation.mutex(&room_id);
+    let mutex_lock = mutex.lock().await;
+    let result = services()
+        .rooms
+        .event_handler
+        .handle_incoming_pdu(server, event_id, &room_id, value, true, &pub_key_map)
+        .await;
+    drop(mutex_lock);
+
+    result
 }
 
 fn escape_html(s: &str) -> String {
@@ -18,4 +18,17 @@ pub trait Data: Send + Sync {
     fn iter_ids<'a>(&'a self) -> Result<Box<dyn Iterator<Item = Result<String>> + 'a>>;
 
     fn all(&self) -> Result<Vec<(String, Registration)>>;
+
+    /// Returns the ID of the last transaction the appservice acknowledged, 0 if there was none.
+    fn last_txn_id(&self, id: &str) -> Result<u64>;
+
+    fn set_last_txn_id(&self, id: &str, txn_id: u64) -> Result<()>;
+
+    /// Returns the count up to which device list changes were sent to the appservice (MSC3202),
+    /// None if it didn't opt into them.
+    fn device_list_count(&self, id: &str) -> Result<Option<u64>>;
+
+    fn set_device_list_count(&self, id: &str, count: u64) -> Result<()>;
+
+    fn remove_device_list_count(&self, id: &str) -> Result<()>;
 }
@@ -1,18 +1,66 @@
 mod data;
 
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures_util::future::join_all;
+
 pub(crate) use data::Data;
-use ruma::api::appservice::Registration;
+use regex::Regex;
+use ruma::{
+    api::appservice::{self, Namespace, Registration},
+    thirdparty::Protocol,
+    OwnedRoomAliasId, OwnedRoomId, OwnedUserId, RoomAliasId, UserId,
+};
+use tracing::warn;
+
+use crate::{services, Result};
 
-use crate::Result;
+/// How long the third party protocols of the appservices are cached
+const PROTOCOLS_CACHE_DURATION: Duration = Duration::from_secs(5 * 60);
 
 pub struct Service {
     pub db: &'static dyn Data,
+
+    /// Local users with devices of the appservices that opted into MSC3202, filled on their first
+    /// transaction and kept up to date when devices are created or removed
+    device_users: Mutex<HashMap<String, BTreeSet<OwnedUserId>>>,
+
+    /// Third party protocols of the appservices and when they were queried
+    protocols: Mutex<Option<(Instant, BTreeMap<String, Protocol>)>>,
 }
 
 impl Service {
-    /// Registers an appservice and returns the ID to the caller
-    pub fn register_appservice(&self, yaml: Registration) -> Result<String> {
-        self.db.register_appservice(yaml)
+    pub fn build(db: &'static dyn Data) -> Self {
+        Self {
+            db,
+            device_users: Mutex::new(HashMap::new()),
+            protocols: Mutex::new(None),
+        }
+    }
+
+    /// Registers an appservice and returns the ID to the caller. `device_lists` is the MSC3202
+    /// opt-in (`org.matrix.msc3202: true` in the registration) into device lists and one-time key
+    /// counts in transactions.
+    pub fn register_appservice(&self, yaml: Registration, device_lists: bool) -> Result<String> {
+        let id = self.db.register_appservice(yaml)?;
+
+        if !device_lists {
+            self.db.remove_device_list_count(&id)?;
+        } else if self.db.device_list_count(&id)?.is_none() {
+            // Nothing changed before the registration as far as the appservice is concerned
+            self.db
+                .set_device_list_count(&id, services().globals.current_count()?)?;
+        }
+        self.device_users.lock().unwrap().remove(&id);
+        *self.protocols.lock().unwrap() = None;
+        // The namespaces decide which users count towards the registration user limit
+        services().users.forget_active_local_users();
+
+        Ok(id)
     }
 
     /// Remove an appservice registration
@@ -21,7 +69,13 @@ impl Service {
     ///
     /// * `service_name` - the name you send to register the service previously
     pub fn unregister_appservice(&self, service_name: &str) -> Result<()> {
-        self.db.unregister_appservice(service_name)
+        self.db.remove_device_list_count(service_name)?;
+        self.device_users.lock().unwrap().remove(service_name);
+        *self.protocols.lock().unwrap() = None;
+        self.db.unregister_appservice(service_name)?;
+        services().users.forget_active_local_users();
+
+        Ok(())
     }
 
     pub fn get_registration(&self, id: &str) -> Result<Option<Registration>> {
@@ -35,4 +89,322 @@ impl Service {
     pub fn all(&self) -> Result<Vec<(String, Registration)>> {
         self.db.all()
     }
+
+    /// Returns the ID of the last transaction the appservice acknowledged, 0 if there was none.
+    pub fn last_txn_id(&self, id: &str) -> Result<u64> {
+        self.db.last_txn_id(id)
+    }
+
+    pub fn set_last_txn_id(&self, id: &str, txn_id: u64) -> Result<()> {
+        self.db.set_last_txn_id(id, txn_id)
+    }
+
+    /// Returns the count up to which device list changes were sent to the appservice (MSC3202),
+    /// None if it didn't opt into them.
+    pub fn device_list_count(&self, id: &str) -> Result<Option<u64>> {
+        self.db.device_list_count(id)
+    }
+
+    pub fn set_device_list_count(&self, id: &str, count: u64) -> Result<()> {
+        self.db.set_device_list_count(id, count)
+    }
+
+    /// Returns the local users of the appservice that have at least one device (MSC3202). Only
+    /// the first call for an appservice looks at all users.
+    pub fn device_users(
+        &self,
+        id: &str,
+        registration: &Registration,
+    ) -> Result<BTreeSet<OwnedUserId>> {
+        if let Some(users) = self.device_users.lock().unwrap().get(id) {
+            return Ok(users.clone());
+        }
+
+        let users: BTreeSet<_> = self
+            .namespace_users(registration)?
+            .into_iter()
+            .filter(|user_id| services().users.all_device_ids(user_id).next().is_some())
+            .collect();
+
+        self.device_users
+            .lock()
+            .unwrap()
+            .insert(id.to_owned(), users.clone());
+
+        Ok(users)
+    }
+
+    /// Updates the device users of the appservices after a device of a local user was created or
+    /// removed.
+    pub fn update_device_user(&self, user_id: &UserId) -> Result<()> {
+        let mut device_users = self.device_users.lock().unwrap();
+        if device_users.is_empty() {
+            return Ok(());
+        }
+
+        let has_devices = services().users.all_device_ids(user_id).next().is_some();
+        for (id, users) in device_users.iter_mut() {
+            let Some(registration) = self.get_registration(id)? else {
+                continue;
+            };
+            if !is_namespace_user(&registration, user_id) {
+                continue;
+            }
+
+            if has_devices {
+                users.insert(user_id.to_owned());
+            } else {
+                users.remove(user_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the local users in the user namespaces of the appservice, including the user of
+    /// the appservice itself.
+    pub fn namespace_users(&self, registration: &Registration) -> Result<Vec<OwnedUserId>> {
+        let server_name = services().globals.server_name();
+
+        Ok(services()
+            .users
+            .iter()
+            .filter_map(Result::ok)
+            .filter(|user_id| user_id.server_name() == server_name)
+            .filter(|user_id| is_namespace_user(registration, user_id))
+            .collect())
+    }
+
+    /// Returns true if the local user is the user of an appservice or in one of its user
+    /// namespaces.
+    pub fn is_appservice_user(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.user_matcher()?(user_id))
+    }
+
+    /// Returns a function that checks whether a user is the user of an appservice or in one of its
+    /// user namespaces. The namespace regexes are only compiled once, so it can be used to check
+    /// many users.
+    pub fn user_matcher(&self) -> Result<impl Fn(&UserId) -> bool> {
+        let appservices: Vec<_> = self
+            .all()?
+            .into_iter()
+            .map(|(_id, registration)| {
+                let regexes: Vec<_> = registration
+                    .namespaces
+                    .users
+                    .iter()
+                    .filter_map(|namespace| Regex::new(namespace.regex.as_str()).ok())
+                    .collect();
+                (registration.sender_localpart, regexes)
+            })
+            .collect();
+
+        Ok(move |user_id: &UserId| {
+            appservices.iter().any(|(sender_localpart, regexes)| {
+                user_id.localpart() == sender_localpart
+                    || regexes.iter().any(|regex| regex.is_match(user_id.as_str()))
+            })
+        })
+    }
+
+    /// Returns the local users that only the appservice may manage: the user of the appservice
+    /// itself and the users in its exclusive user namespaces. Admins and the server user are never
+    /// included.
+    pub fn exclusive_users(&self, registration: &Registration) -> Result<Vec<OwnedUserId>> {
+        let server_name = services().globals.server_name();
+        let server_user = UserId::parse(format!("@conduit:{server_name}"))
+            .expect("@conduit:server_name is valid");
+        let namespaces = exclusive_namespaces(&registration.namespaces.users);
+
+        let mut users = Vec::new();
+        for user_id in services().users.iter().filter_map(Result::ok) {
+            if user_id.server_name() != server_name
+                || user_id == server_user
+                || !(user_id.localpart() == registration.sender_localpart
+                    || namespace_matches(&namespaces, user_id.as_str()))
+                || services().users.is_admin(&user_id)?
+            {
+                continue;
+            }
+
+            users.push(user_id);
+        }
+
+        Ok(users)
+    }
+
+    /// Returns the local aliases in the exclusive alias namespaces of the appservice.
+    pub fn exclusive_aliases(&self, registration: &Registration) -> Result<Vec<OwnedRoomAliasId>> {
+        let server_name = services().globals.server_name();
+        let namespaces = exclusive_namespaces(&registration.namespaces.aliases);
+
+        Ok(services()
+            .rooms
+            .alias
+            .all_local_aliases()
+            .filter_map(Result::ok)
+            .filter_map(|(_, localpart)| {
+                RoomAliasId::parse(format!("#{localpart}:{server_name}")).ok()
+            })
+            .filter(|alias| namespace_matches(&namespaces, alias.as_str()))
+            .collect())
+    }
+
+    /// Gives the appservices whose namespaces contain the alias a chance to create it, as the
+    /// appservice spec requires before an alias is reported as unknown. Returns the room the
+    /// alias points to afterwards.
+    pub async fn query_room_alias(&self, room_alias: &RoomAliasId) -> Result<Option<OwnedRoomId>> {
+        for (id, registration) in self.all()? {
+            if !namespace_matches(&registration.namespaces.aliases, room_alias.as_str()) {
+                continue;
+            }
+
+            match services()
+                .sending
+                .send_appservice_request(
+                    registration,
+                    appservice::query::query_room_alias::v1::Request {
+                        room_alias: room_alias.to_owned(),
+                    },
+                )
+                .await
+            {
+                Some(Ok(_)) => {
+                    let room_id = services().rooms.alias.resolve_local_alias(room_alias)?;
+                    if room_id.is_none() {
+                        warn!(
+                            "Appservice {id} claimed to create {room_alias}, but it doesn't exist"
+                        );
+                    }
+                    return Ok(room_id);
+                }
+                Some(Err(e)) => warn!("Appservice {id} does not know alias {room_alias}: {e}"),
+                None => {}
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Gives the appservices whose namespaces contain the user a chance to create it, as the
+    /// appservice spec requires before a user is reported as unknown. Returns true if the user
+    /// exists afterwards.
+    pub async fn query_user_id(&self, user_id: &UserId) -> Result<bool> {
+        for (id, registration) in self.all()? {
+            if !namespace_matches(&registration.namespaces.users, user_id.as_str()) {
+                continue;
+            }
+
+            match services()
+                .sending
+                .send_appservice_request(
+                    registration,
+                    appservice::query::query_user_id::v1::Request {
+                        user_id: user_id.to_owned(),
+                    },
+                )
+                .await
+            {
+                Some(Ok(_)) => return services().users.exists(user_id),
+                Some(Err(e)) => warn!("Appservice {id} does not know user {user_id}: {e}"),
+                None => {}
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Asks the appservices about the third party protocols they advertise in their registration.
+    /// Every instance of a protocol gets an ID that the public room directory can be filtered by.
+    /// The appservices are queried concurrently and the result is cached for a few minutes.
+    pub async fn protocols(&self) -> Result<BTreeMap<String, Protocol>> {
+        if let Some((queried_at, protocols)) = &*self.protocols.lock().unwrap() {
+            if queried_at.elapsed() < PROTOCOLS_CACHE_DURATION {
+                return Ok(protocols.clone());
+            }
+        }
+
+        let requests = self
+            .all()?
+            .into_iter()
+            .flat_map(|(id, registration)| {
+                registration
+                    .protocols
+                    .clone()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(move |protocol| (id.clone(), registration.clone(), protocol))
+            })
+            .map(|(id, registration, protocol)| async move {
+                let response = services()
+                    .sending
+                    .send_appservice_request(
+                        registration,
+                        appservice::thirdparty::get_protocol::v1::Request {
+                            protocol: protocol.clone(),
+                        },
+                    )
+                    .await;
+                (id, protocol, response)
+            });
+
+        let mut protocols = BTreeMap::new();
+        for (id, protocol, response) in join_all(requests).await {
+            match response {
+                Some(Ok(response)) => {
+                    let mut metadata = response.protocol;
+                    for instance in &mut metadata.instances {
+                        instance.instance_id = third_party_instance_id(&id, &instance.network_id);
+                    }
+
+                    // Several appservices can bridge the same protocol
+                    match protocols.entry(protocol) {
+                        Entry::Vacant(entry) => {
+                            entry.insert(metadata);
+                        }
+                        Entry::Occupied(mut entry) => {
+                            entry.get_mut().instances.extend(metadata.instances);
+                        }
+                    }
+                }
+                Some(Err(e)) => warn!("Appservice {id} does not know protocol {protocol}: {e}"),
+                None => {}
+            }
+        }
+
+        *self.protocols.lock().unwrap() = Some((Instant::now(), protocols.clone()));
+
+        Ok(protocols)
+    }
+}
+
+/// Returns the ID of an instance of a third party protocol, which identifies the network bridged
+/// by the appservice.
+pub fn third_party_instance_id(appservice_id: &str, network_id: &str) -> String {
+    format!("{appservice_id}|{network_id}")
+}
+
+/// Returns the appservice and network ID of a third party protocol instance.
+pub fn parse_third_party_instance_id(instance_id: &str) -> Option<(&str, &str)> {
+    instance_id.split_once('|')
+}
+
+fn is_namespace_user(registration: &Registration, user_id: &UserId) -> bool {
+    user_id.localpart() == registration.sender_localpart
+        || namespace_matches(&registration.namespaces.users, user_id.as_str())
+}
+
+fn exclusive_namespaces(namespaces: &[Namespace]) -> Vec<Namespace> {
+    namespaces
+        .iter()
+        .filter(|namespace| namespace.exclusive)
+        .cloned()
+        .collect()
+}
+
+fn namespace_matches(namespaces: &[Namespace], id: &str) -> bool {
+    namespaces
+        .iter()
+        .filter_map(|namespace| Regex::new(namespace.regex.as_str()).ok())
+        .any(|regex| regex.is_match(id))
 }
@@ -0,0 +1,330 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lettre::{
+    message::Mailbox, Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use ruma::{api::client::error::ErrorKind, ClientSecret, OwnedClientSecret, UInt};
+use tracing::{error, info, warn};
+
+use crate::{
+    api::client_server::{SESSION_ID_LENGTH, TOKEN_LENGTH},
+    services,
+    utils::{self, sharded_map::ShardedMap},
+    Config, Error, Result,
+};
+
+/// How long a validation session can be used after the email was sent
+const VALIDATION_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Shortest time between two validation emails to the same address
+const RESEND_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Window in which the validation emails per address and in total are limited
+const SEND_LIMIT_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Validation emails one IP address can request per window
+const IP_SEND_LIMIT: u32 = 10;
+
+/// Validation emails the server sends per window, so it can't be used to spam many addresses
+const TOTAL_SEND_LIMIT: u32 = 200;
+
+/// Number of tracked IP addresses above which expired windows are removed
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Path of the link in validation emails
+const SUBMIT_TOKEN_PATH: &str = "/_matrix/client/unstable/registration/email/submit_token";
+
+pub struct EmailValidation {
+    client_secret: OwnedClientSecret,
+    email: String,
+    token: String,
+    send_attempt: UInt,
+    next_link: Option<String>,
+    validated: bool,
+    /// UIAA session in which the address was used
+    uiaa_session: Option<String>,
+    sent_at: Instant,
+}
+
+/// Sends emails through the configured SMTP server and verifies that users own email addresses.
+pub struct Service {
+    mailer: Option<(AsyncSmtpTransport<Tokio1Executor>, Mailbox)>,
+    validations: Mutex<HashMap<String, EmailValidation>>,
+    /// Start of the current window and emails sent in it, per IP address and in total
+    sent_per_ip: ShardedMap<IpAddr, (Instant, u32)>,
+    sent_total: Mutex<(Instant, u32)>,
+}
+
+impl Service {
+    pub fn build(config: &Config) -> Result<Self> {
+        let mailer = config
+            .smtp
+            .as_ref()
+            .map(|smtp| {
+                let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&smtp.url)
+                    .map_err(|e| {
+                        error!("Invalid SMTP URL: {e}");
+                        Error::bad_config("Invalid SMTP URL.")
+                    })?
+                    .build();
+                let from = smtp
+                    .from
+                    .parse()
+                    .map_err(|_| Error::bad_config("Invalid SMTP sender address."))?;
+
+                Ok::<_, Error>((transport, from))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            mailer,
+            validations: Mutex::new(HashMap::new()),
+            sent_per_ip: ShardedMap::new(),
+            sent_total: Mutex::new((Instant::now(), 0)),
+        })
+    }
+
+    /// Sends a plain text email.
+    pub async fn send(&self, to: Mailbox, subject: &str, body: String) -> Result<()> {
+        let Some((transport, from)) = &self.mailer else {
+            return Err(Error::BadConfig("No SMTP server is configured."));
+        };
+
+        let message = Message::builder()
+            .from(from.clone())
+            .to(to)
+            .subject(subject)
+            .body(body)
+            .map_err(|e| {
+                warn!("Failed to build email: {e}");
+                Error::BadServerResponse("Failed to build the email.")
+            })?;
+
+        transport.send(message).await.map_err(|e| {
+            warn!("Failed to send email: {e}");
+            Error::BadServerResponse("Failed to send the email.")
+        })?;
+
+        Ok(())
+    }
+
+    /// Sends a link to verify the email address, unless the client already sent this attempt.
+    /// Returns the ID of the validation session.
+    pub async fn request_validation(
+        &self,
+        client_ip: Option<IpAddr>,
+        email: &str,
+        client_secret: &ClientSecret,
+        send_attempt: UInt,
+        next_link: Option<String>,
+    ) -> Result<String> {
+        let address: Address = email
+            .to_lowercase()
+            .parse()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Email address is invalid."))?;
+        let email = address.to_string();
+
+        if services().users.find_user_by_email(&email)?.is_some() {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidInUse,
+                "Email address is already in use.",
+            ));
+        }
+
+        let (sid, token) = {
+            let mut validations = self.validations.lock().unwrap();
+            validations.retain(|_, validation| validation.sent_at.elapsed() < VALIDATION_LIFETIME);
+
+            let existing = validations.iter().find(|(_, validation)| {
+                validation.client_secret.as_str() == client_secret.as_str()
+                    && validation.email == email
+            });
+            if let Some((sid, validation)) = existing {
+                if send_attempt <= validation.send_attempt {
+                    return Ok(sid.clone());
+                }
+            }
+
+            if validations.values().any(|validation| {
+                validation.email == email && validation.sent_at.elapsed() < RESEND_INTERVAL
+            }) {
+                return Err(Error::BadRequest(
+                    ErrorKind::LimitExceeded {
+                        retry_after_ms: Some(RESEND_INTERVAL),
+                    },
+                    "An email was sent to this address recently.",
+                ));
+            }
+
+            self.count_sent_email(client_ip)?;
+
+            let sid = utils::random_string(SESSION_ID_LENGTH);
+            let token = utils::random_string(TOKEN_LENGTH);
+
+            validations.retain(|_, validation| {
+                validation.client_secret.as_str() != client_secret.as_str()
+                    || validation.email != email
+            });
+            validations.insert(
+                sid.clone(),
+                EmailValidation {
+                    client_secret: client_secret.to_owned(),
+                    email: email.clone(),
+                    token: token.clone(),
+                    send_attempt,
+                    next_link,
+                    validated: false,
+                    uiaa_session: None,
+                    sent_at: Instant::now(),
+                },
+            );
+
+            (sid, token)
+        };
+
+        let base_url = services()
+            .globals
+            .well_known_client()
+            .clone()
+            .unwrap_or_else(|| format!("https://{}", services().globals.server_name()));
+        let link = format!(
+            "{}{SUBMIT_TOKEN_PATH}?sid={sid}&client_secret={client_secret}&token={token}",
+            base_url.trim_end_matches('/')
+        );
+        let server_name = services().globals.server_name();
+        let body = format!(
+            "Someone wants to register an account on {server_name} with this email address.\n\n\
+             Open this link to confirm that it was you, it is valid for one hour:\n\n{link}\n\n\
+             If it wasn't you, you can ignore this email.\n"
+        );
+
+        if let Err(e) = self
+            .send(
+                Mailbox::new(None, address),
+                &format!("Verify your email address for {server_name}"),
+                body,
+            )
+            .await
+        {
+            self.validations.lock().unwrap().remove(&sid);
+            return Err(e);
+        }
+
+        info!("Sent validation email for session {sid}");
+
+        Ok(sid)
+    }
+
+    /// Counts a validation email against the limits of the IP address and the server, unless one
+    /// of them is already used up.
+    fn count_sent_email(&self, client_ip: Option<IpAddr>) -> Result<()> {
+        let now = Instant::now();
+
+        let mut sent_total = self.sent_total.lock().unwrap();
+        if now.duration_since(sent_total.0) >= SEND_LIMIT_WINDOW {
+            *sent_total = (now, 0);
+        }
+        if sent_total.1 >= TOTAL_SEND_LIMIT {
+            warn!("Rejecting validation email because the server sent too many");
+            return Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: Some((sent_total.0 + SEND_LIMIT_WINDOW) - now),
+                },
+                "Too many validation emails were sent, try again later.",
+            ));
+        }
+
+        if let Some(ip) = client_ip {
+            if self.sent_per_ip.len() > PRUNE_THRESHOLD {
+                self.sent_per_ip
+                    .retain(|_, (start, _)| now.duration_since(*start) < SEND_LIMIT_WINDOW);
+            }
+
+            self.sent_per_ip.with_entry(ip, |entry| {
+                let sent = entry.or_insert((now, 0));
+                if now.duration_since(sent.0) >= SEND_LIMIT_WINDOW {
+                    *sent = (now, 0);
+                }
+                if sent.1 >= IP_SEND_LIMIT {
+                    return Err(Error::BadRequest(
+                        ErrorKind::LimitExceeded {
+                            retry_after_ms: Some((sent.0 + SEND_LIMIT_WINDOW) - now),
+                        },
+                        "Too many validation emails were requested, try again later.",
+                    ));
+                }
+
+                sent.1 += 1;
+                Ok(())
+            })?;
+        }
+
+        sent_total.1 += 1;
+        Ok(())
+    }
+
+    /// Marks the email address of the validation session as verified. Returns the link the client
+    /// wants the user to go to afterwards.
+    pub fn submit_token(
+        &self,
+        sid: &str,
+        client_secret: &str,
+        token: &str,
+    ) -> Result<Option<String>> {
+        let mut validations = self.validations.lock().unwrap();
+
+        let validation = validations
+            .get_mut(sid)
+            .filter(|validation| {
+                validation.sent_at.elapsed() < VALIDATION_LIFETIME
+                    && validation.client_secret.as_str() == client_secret
+                    && validation.token == token
+            })
+            .ok_or(Error::BadRequest(
+                ErrorKind::ThreepidAuthFailed,
+                "The link is invalid or has expired.",
+            ))?;
+
+        validation.validated = true;
+
+        Ok(validation.next_link.clone())
+    }
+
+    /// Returns the email address of the validation session once it is verified, and remembers
+    /// the UIAA session it was used in.
+    pub fn use_validated_email(
+        &self,
+        sid: &str,
+        client_secret: &ClientSecret,
+        uiaa_session: &str,
+    ) -> Option<String> {
+        let mut validations = self.validations.lock().unwrap();
+
+        let validation = validations.get_mut(sid).filter(|validation| {
+            validation.validated
+                && validation.sent_at.elapsed() < VALIDATION_LIFETIME
+                && validation.client_secret.as_str() == client_secret.as_str()
+        })?;
+        validation.uiaa_session = Some(uiaa_session.to_owned());
+
+        Some(validation.email.clone())
+    }
+
+    /// Returns the verified email address used in the UIAA session and ends its validation
+    /// session.
+    pub fn take_uiaa_session_email(&self, uiaa_session: &str) -> Option<String> {
+        let mut validations = self.validations.lock().unwrap();
+
+        let sid = validations
+            .iter()
+            .find(|(_, validation)| validation.uiaa_session.as_deref() == Some(uiaa_session))
+            .map(|(sid, _)| sid.clone())?;
+
+        validations.remove(&sid).map(|validation| validation.email)
+    }
+}
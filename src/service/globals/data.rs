@@ -3,10 +3,13 @@ use std::collections::BTreeMap;
 use async_trait::async_trait;
 use ruma::{
     api::federation::discovery::{ServerSigningKeys, VerifyKey},
+    serde::Raw,
     signatures::Ed25519KeyPair,
-    DeviceId, OwnedServerSigningKeyId, ServerName, UserId,
+    DeviceId, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedServerSigningKeyId, ServerName,
+    UserId,
 };
 
+use super::DatabaseCheck;
 use crate::Result;
 
 #[async_trait]
@@ -19,6 +22,12 @@ pub trait Data: Send + Sync {
     fn cleanup(&self) -> Result<()>;
     fn memory_usage(&self) -> String;
     fn clear_caches(&self, amount: u32);
+
+    /// Returns the name, number of entries and estimated size in bytes of each database cache.
+    fn cache_usage(&self) -> Vec<(&'static str, usize, usize)>;
+
+    /// Clears the database cache with the given name, returns false if there is no such cache.
+    fn clear_cache(&self, name: &str) -> bool;
     fn load_keypair(&self) -> Result<Ed25519KeyPair>;
     fn remove_keypair(&self) -> Result<()>;
     fn add_signing_key(
@@ -32,6 +41,27 @@ pub trait Data: Send + Sync {
         &self,
         origin: &ServerName,
     ) -> Result<BTreeMap<OwnedServerSigningKeyId, VerifyKey>>;
+
+    /// Returns until when each cached signing key of the server may be used to verify signatures.
+    fn signing_keys_valid_until(
+        &self,
+        origin: &ServerName,
+    ) -> Result<BTreeMap<OwnedServerSigningKeyId, MilliSecondsSinceUnixEpoch>>;
+
+    /// Stores the key response of a server exactly as signed by it, for serving as a notary.
+    fn add_signed_server_keys(
+        &self,
+        origin: &ServerName,
+        keys: &Raw<ServerSigningKeys>,
+    ) -> Result<()>;
+
+    fn signed_server_keys(&self, origin: &ServerName) -> Result<Option<Raw<ServerSigningKeys>>>;
+    fn is_server_blocked(&self, server_name: &ServerName) -> Result<bool>;
+    fn block_server(&self, server_name: &ServerName, blocked: bool) -> Result<()>;
+    fn blocked_servers<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedServerName>> + 'a>;
     fn database_version(&self) -> Result<u64>;
     fn bump_database_version(&self, new_version: u64) -> Result<()>;
+
+    /// Checks that trees referring to each other agree, and repairs what can be repaired.
+    fn check_database(&self, repair: bool) -> Result<DatabaseCheck>;
 }
@@ -3,15 +3,18 @@ use argon2::Argon2;
 pub use data::Data;
 use regex::RegexSet;
 use ruma::{
-    serde::Base64, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedServerName,
-    OwnedServerSigningKeyId, OwnedUserId,
+    serde::{Base64, Raw},
+    CanonicalJsonObject, CanonicalJsonValue, OwnedDeviceId, OwnedEventId, OwnedRoomId,
+    OwnedRoomOrAliasId, OwnedServerName, OwnedServerSigningKeyId, OwnedUserId,
 };
 
 use sha2::Digest;
 
-use crate::api::server_server::FedDest;
+use crate::api::{client_server::SyncResponse, server_server::FedDest};
 
-use crate::{services, Config, Error, Result};
+use crate::{
+    config::TermsOfServicePolicy, services, utils::sharded_map::ShardedMap, Config, Error, Result,
+};
 use futures_util::FutureExt;
 use hyper::{
     client::connect::dns::{GaiResolver, Name},
@@ -19,18 +22,18 @@ use hyper::{
 };
 use reqwest::dns::{Addrs, Resolve, Resolving};
 use ruma::{
-    api::{
-        client::sync::sync_events,
-        federation::discovery::{ServerSigningKeys, VerifyKey},
+    api::federation::discovery::{ServerSigningKeys, VerifyKey},
+    push::{
+        Action, NewPatternedPushRule, NewPushRule, NewSimplePushRule, RuleKind, Ruleset, Tweak,
     },
-    DeviceId, RoomVersionId, ServerName, UserId,
+    DeviceId, MilliSecondsSinceUnixEpoch, RoomId, RoomVersionId, ServerName, UserId,
 };
 use std::{
     collections::{BTreeMap, HashMap},
     error::Error as StdError,
     fs,
     future::{self, Future},
-    iter,
+    iter, mem,
     net::{IpAddr, SocketAddr},
     path::PathBuf,
     sync::{
@@ -40,49 +43,89 @@ use std::{
     time::{Duration, Instant},
 };
 use tokio::sync::{broadcast, watch::Receiver, Mutex as TokioMutex, Semaphore};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use trust_dns_resolver::TokioAsyncResolver;
 
 use base64::{engine::general_purpose, Engine as _};
 
-type WellKnownMap = HashMap<OwnedServerName, (FedDest, String)>;
-type TlsNameMap = HashMap<String, (Vec<IpAddr>, u16)>;
+type WellKnownMap = ShardedMap<OwnedServerName, (FedDest, String)>;
+type TlsNameMap = ShardedMap<String, (Vec<IpAddr>, u16)>;
 type RateLimitState = (Instant, u32); // Time if last failed try, number of failed tries
+type RoomMutexMap = ShardedMap<OwnedRoomId, Arc<TokioMutex<()>>>;
+
+const DEFAULT_MAINTENANCE_MESSAGE: &str =
+    "The server is in maintenance mode, please try again later.";
+
 type SyncHandle = (
-    Option<String>,                                      // since
-    Receiver<Option<Result<sync_events::v3::Response>>>, // rx
+    Option<String>,                         // since
+    Receiver<Option<Result<SyncResponse>>>, // rx
 );
 
 pub struct Service<'a> {
     pub db: &'static dyn Data,
 
-    pub actual_destination_cache: Arc<RwLock<WellKnownMap>>, // actual_destination, host
-    pub tls_name_override: Arc<RwLock<TlsNameMap>>,
+    pub actual_destination_cache: WellKnownMap, // actual_destination, host
+    pub tls_name_override: Arc<TlsNameMap>,
     pub config: Config,
     keypair: Arc<ruma::signatures::Ed25519KeyPair>,
     dns_resolver: TokioAsyncResolver,
-    jwt_decoding_key: Option<jsonwebtoken::DecodingKey>,
     url_preview_client: reqwest::Client,
     federation_client: reqwest::Client,
     default_client: reqwest::Client,
     pub stable_room_versions: Vec<RoomVersionId>,
     pub unstable_room_versions: Vec<RoomVersionId>,
-    pub bad_event_ratelimiter: Arc<RwLock<HashMap<OwnedEventId, RateLimitState>>>,
-    pub bad_signature_ratelimiter: Arc<RwLock<HashMap<Vec<String>, RateLimitState>>>,
-    pub bad_query_ratelimiter: Arc<RwLock<HashMap<OwnedServerName, RateLimitState>>>,
-    pub servername_ratelimiter: Arc<RwLock<HashMap<OwnedServerName, Arc<Semaphore>>>>,
-    pub sync_receivers: RwLock<HashMap<(OwnedUserId, OwnedDeviceId), SyncHandle>>,
-    pub roomid_mutex_insert: RwLock<HashMap<OwnedRoomId, Arc<TokioMutex<()>>>>,
-    pub roomid_mutex_state: RwLock<HashMap<OwnedRoomId, Arc<TokioMutex<()>>>>,
-    pub roomid_mutex_federation: RwLock<HashMap<OwnedRoomId, Arc<TokioMutex<()>>>>, // this lock will be held longer
-    pub roomid_federationhandletime: RwLock<HashMap<OwnedRoomId, (OwnedEventId, Instant)>>,
+    pub bad_event_ratelimiter: ShardedMap<OwnedEventId, RateLimitState>,
+    pub bad_signature_ratelimiter: ShardedMap<Vec<String>, RateLimitState>,
+    pub servername_ratelimiter: ShardedMap<OwnedServerName, Arc<Semaphore>>,
+    /// Allows one transaction of each server to be handled at a time, including transactions
+    /// that are still handled in the background after their request timed out
+    pub servername_transaction: ShardedMap<OwnedServerName, Arc<Semaphore>>,
+    pub sync_receivers: ShardedMap<(OwnedUserId, OwnedDeviceId), SyncHandle>,
+    /// Number of sync requests of each user that wait for new events
+    pub sync_long_polls: ShardedMap<OwnedUserId, usize>,
+    pub roomid_mutex_insert: RoomMutexMap,
+    pub roomid_mutex_state: RoomMutexMap,
+    pub roomid_mutex_federation: RoomMutexMap, // this lock will be held longer
+    pub roomid_federationhandletime: ShardedMap<OwnedRoomId, (OwnedEventId, Instant)>,
     pub stateres_mutex: Arc<Mutex<()>>,
+    pub shared_secret_registration_nonces: Mutex<HashMap<String, Instant>>,
+    maintenance_message: RwLock<Option<String>>, // Some while maintenance mode is enabled
     pub(crate) rotate: RotationHandler,
 
     pub shutdown: AtomicBool,
+    /// Whether less memory was available than `sync_low_memory_threshold_mb` when it was last
+    /// checked
+    pub memory_low: AtomicBool,
     pub argon: Argon2<'a>,
 }
 
+/// Result of `Service::check_database`
+#[derive(Default)]
+pub struct DatabaseCheck {
+    /// Descriptions of the first `DatabaseCheck::MAX_PROBLEMS` inconsistencies that were found
+    pub problems: Vec<String>,
+    /// Number of inconsistencies that were found
+    pub found: usize,
+    /// Number of inconsistencies that were repaired
+    pub repaired: usize,
+}
+
+impl DatabaseCheck {
+    /// Inconsistencies after this many are only counted, so a badly broken database doesn't
+    /// flood the log and the memory
+    pub const MAX_PROBLEMS: usize = 1000;
+
+    pub fn found(&mut self, problem: String) {
+        self.found += 1;
+        if self.problems.len() < Self::MAX_PROBLEMS {
+            warn!("Database inconsistency: {problem}");
+            self.problems.push(problem);
+        } else if self.found == Self::MAX_PROBLEMS + 1 {
+            warn!("Too many database inconsistencies, only counting the others");
+        }
+    }
+}
+
 /// Handles "rotation" of long-polling requests. "Rotation" in this context is similar to "rotation" of log files and the like.
 ///
 /// This is utilized to have sync workers return early and release read locks on the database.
@@ -113,13 +156,20 @@ impl Default for RotationHandler {
     }
 }
 
+impl RoomMutexMap {
+    /// Returns the mutex of the room, it is created on first use.
+    pub fn mutex(&self, room_id: &RoomId) -> Arc<TokioMutex<()>> {
+        self.get_or_default(room_id)
+    }
+}
+
 struct Resolver {
     inner: GaiResolver,
-    overrides: Arc<RwLock<TlsNameMap>>,
+    overrides: Arc<TlsNameMap>,
 }
 
 impl Resolver {
-    fn new(overrides: Arc<RwLock<TlsNameMap>>) -> Self {
+    fn new(overrides: Arc<TlsNameMap>) -> Self {
         Resolver {
             inner: GaiResolver::new(),
             overrides,
@@ -130,13 +180,11 @@ impl Resolver {
 impl Resolve for Resolver {
     fn resolve(&self, name: Name) -> Resolving {
         self.overrides
-            .read()
-            .expect("lock should not be poisoned")
             .get(name.as_str())
             .and_then(|(override_name, port)| {
                 override_name.first().map(|first_name| {
                     let x: Box<dyn Iterator<Item = SocketAddr> + Send> =
-                        Box::new(iter::once(SocketAddr::new(*first_name, *port)));
+                        Box::new(iter::once(SocketAddr::new(*first_name, port)));
                     let x: Resolving = Box::pin(future::ready(Ok(x)));
                     x
                 })
@@ -165,12 +213,14 @@ impl Service<'_> {
             }
         };
 
-        let tls_name_override = Arc::new(RwLock::new(TlsNameMap::new()));
+        let tls_name_override = Arc::new(TlsNameMap::new());
 
-        let jwt_decoding_key = config
-            .jwt_secret
-            .as_ref()
-            .map(|secret| jsonwebtoken::DecodingKey::from_secret(secret.as_bytes()));
+        let maintenance_message = config.maintenance_mode.then(|| {
+            config
+                .maintenance_message
+                .clone()
+                .unwrap_or_else(|| DEFAULT_MAINTENANCE_MESSAGE.to_owned())
+        });
 
         let url_preview_client = url_preview_reqwest_client_builder(&config)?.build()?;
         let default_client = reqwest_client_builder(&config)?.build()?;
@@ -212,30 +262,34 @@ impl Service<'_> {
                 );
                 Error::bad_config("Failed to set up trust dns resolver with system config.")
             })?,
-            actual_destination_cache: Arc::new(RwLock::new(WellKnownMap::new())),
+            actual_destination_cache: WellKnownMap::new(),
             tls_name_override,
             url_preview_client,
             federation_client,
             default_client,
-            jwt_decoding_key,
             stable_room_versions,
             unstable_room_versions,
-            bad_event_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
-            bad_signature_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
-            bad_query_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
-            servername_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
-            roomid_mutex_state: RwLock::new(HashMap::new()),
-            roomid_mutex_insert: RwLock::new(HashMap::new()),
-            roomid_mutex_federation: RwLock::new(HashMap::new()),
-            roomid_federationhandletime: RwLock::new(HashMap::new()),
+            bad_event_ratelimiter: ShardedMap::new(),
+            bad_signature_ratelimiter: ShardedMap::new(),
+            servername_ratelimiter: ShardedMap::new(),
+            servername_transaction: ShardedMap::new(),
+            roomid_mutex_state: RoomMutexMap::new(),
+            roomid_mutex_insert: RoomMutexMap::new(),
+            roomid_mutex_federation: RoomMutexMap::new(),
+            roomid_federationhandletime: ShardedMap::new(),
             stateres_mutex: Arc::new(Mutex::new(())),
-            sync_receivers: RwLock::new(HashMap::new()),
+            shared_secret_registration_nonces: Mutex::new(HashMap::new()),
+            maintenance_message: RwLock::new(maintenance_message),
+            sync_receivers: ShardedMap::new(),
+            sync_long_polls: ShardedMap::new(),
             rotate: RotationHandler::new(),
             shutdown: AtomicBool::new(false),
+            memory_low: AtomicBool::new(false),
             argon,
         };
 
         fs::create_dir_all(s.get_media_folder())?;
+        s.migrate_media_folder()?;
 
         if !s
             .supported_room_versions()
@@ -245,6 +299,17 @@ impl Service<'_> {
             s.config.default_room_version = crate::config::default_default_room_version();
         };
 
+        if s.config
+            .default_room_server_acl
+            .as_ref()
+            .is_some_and(|acl| !acl.is_allowed(&s.config.server_name))
+        {
+            error!("Default server ACL for new rooms denies this server, ignoring it");
+            s.config.default_room_server_acl = None;
+        }
+
+        s.validate_default_push_rules();
+
         Ok(s)
     }
 
@@ -300,6 +365,41 @@ impl Service<'_> {
         self.db.cleanup()
     }
 
+    /// Returns the name, number of entries and estimated size in bytes of the database caches and
+    /// the destination cache.
+    pub fn cache_usage(&self) -> Vec<(&'static str, usize, usize)> {
+        let mut caches = self.db.cache_usage();
+
+        caches.push((
+            "actual_destination_cache",
+            self.actual_destination_cache.len(),
+            self.actual_destination_cache
+                .sum_by(|server_name, (_, host)| {
+                    server_name.as_bytes().len() + mem::size_of::<FedDest>() + host.len()
+                }),
+        ));
+
+        caches
+    }
+
+    /// Clears the cache with the given name, see `cache_usage`. Returns false if there is no such
+    /// cache.
+    pub fn clear_cache(&self, name: &str) -> bool {
+        if name == "actual_destination_cache" {
+            self.actual_destination_cache.clear();
+            return true;
+        }
+
+        self.db.clear_cache(name)
+    }
+
+    /// Checks that every timeline PDU has state and can be found by its event ID, that short event
+    /// IDs resolve and that the aliases of rooms match. With `repair`, missing reverse mappings are
+    /// added and dangling entries removed. This goes through large parts of the database.
+    pub fn check_database(&self, repair: bool) -> Result<DatabaseCheck> {
+        self.db.check_database(repair)
+    }
+
     pub fn server_name(&self) -> &ServerName {
         self.config.server_name.as_ref()
     }
@@ -308,14 +408,127 @@ impl Service<'_> {
         self.config.max_request_size
     }
 
+    pub fn max_federation_request_size(&self) -> u32 {
+        self.config
+            .max_federation_request_size
+            .unwrap_or(self.config.max_request_size)
+    }
+
+    /// Returns the message that write requests are refused with if maintenance mode is enabled.
+    pub fn maintenance_message(&self) -> Option<String> {
+        self.maintenance_message.read().unwrap().clone()
+    }
+
+    /// Enables read-only maintenance mode with the message, or the configured or default one, or
+    /// disables it.
+    pub fn set_maintenance_mode(&self, enabled: bool, message: Option<String>) {
+        *self.maintenance_message.write().unwrap() = enabled.then(|| {
+            message
+                .or_else(|| self.config.maintenance_message.clone())
+                .unwrap_or_else(|| DEFAULT_MAINTENANCE_MESSAGE.to_owned())
+        });
+    }
+
     pub fn max_fetch_prev_events(&self) -> u16 {
         self.config.max_fetch_prev_events
     }
 
+    pub fn incoming_federation_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.incoming_federation_timeout_s)
+    }
+
+    pub fn registration_requires_email(&self) -> bool {
+        self.config.registration_requires_email
+    }
+
+    pub fn login_throttling(&self) -> bool {
+        self.config.login_throttling
+    }
+
+    pub fn login_lockout_threshold(&self) -> Option<u32> {
+        self.config.login_lockout_threshold
+    }
+
+    pub fn login_lockout_duration(&self) -> Duration {
+        Duration::from_secs(self.config.login_lockout_duration_s)
+    }
+
     pub fn allow_registration(&self) -> bool {
         self.config.allow_registration
     }
 
+    pub fn registration_user_limit(&self) -> Option<usize> {
+        self.config.registration_user_limit
+    }
+
+    pub fn registration_shared_secret(&self) -> &Option<String> {
+        &self.config.registration_shared_secret
+    }
+
+    pub fn auto_join_rooms(&self) -> &[OwnedRoomOrAliasId] {
+        &self.config.auto_join_rooms
+    }
+
+    pub fn auto_join_rooms_create_if_absent(&self) -> bool {
+        self.config.auto_join_rooms_create_if_absent
+    }
+
+    pub fn welcome_message(&self) -> &Option<String> {
+        &self.config.welcome_message
+    }
+
+    pub fn welcome_message_html(&self) -> &Option<String> {
+        &self.config.welcome_message_html
+    }
+
+    pub fn welcome_message_subject(&self) -> &Option<String> {
+        &self.config.welcome_message_subject
+    }
+
+    pub fn terms_of_service(&self) -> &BTreeMap<String, TermsOfServicePolicy> {
+        &self.config.terms_of_service
+    }
+
+    pub fn terms_of_service_block_messages(&self) -> bool {
+        self.config.terms_of_service_block_messages
+    }
+
+    pub fn max_joined_rooms_per_user(&self) -> Option<usize> {
+        self.config.max_joined_rooms_per_user
+    }
+
+    pub fn max_devices_per_user(&self) -> Option<usize> {
+        self.config.max_devices_per_user
+    }
+
+    pub fn evict_oldest_device_on_limit(&self) -> bool {
+        self.config.evict_oldest_device_on_limit
+    }
+
+    pub fn access_token_idle_timeout_s(&self) -> Option<u64> {
+        self.config.access_token_idle_timeout_s
+    }
+
+    pub fn access_token_lifetime_s(&self) -> Option<u64> {
+        self.config.access_token_lifetime_s
+    }
+
+    pub fn to_device_message_max_age_s(&self) -> Option<u64> {
+        self.config.to_device_message_max_age_s
+    }
+
+    pub fn notification_log_max_age_s(&self) -> u64 {
+        self.config.notification_log_max_age_s
+    }
+
+    pub fn remote_profile_cache_ttl_s(&self) -> u64 {
+        self.config.remote_profile_cache_ttl_s
+    }
+
+    pub fn max_remote_room_complexity(&self) -> Option<f64> {
+        self.config.max_remote_room_complexity
+    }
+
     pub fn allow_guest_registration(&self) -> bool {
         self.config.allow_guest_registration
     }
@@ -352,14 +565,105 @@ impl Service<'_> {
         self.config.default_room_version.clone()
     }
 
+    /// Returns the push rules new accounts start with: the server-default rules of the spec,
+    /// changed by the `default_push_rules_*` config options. Invalid options were already
+    /// dropped by `validate_default_push_rules` at startup.
+    pub fn default_push_ruleset(&self, user_id: &UserId) -> Ruleset {
+        let mut ruleset = Ruleset::server_default(user_id);
+
+        for rule_id in &self.config.default_push_rules_disabled {
+            disable_push_rule(&mut ruleset, rule_id);
+        }
+
+        for keyword in &self.config.default_push_rules_keywords {
+            let _ = ruleset.insert(keyword_push_rule(keyword), None, None);
+        }
+
+        for muted_user in &self.config.default_push_rules_muted_users {
+            let _ = ruleset.insert(muted_user_push_rule(muted_user), None, None);
+        }
+
+        ruleset
+    }
+
+    /// Drops `default_push_rules_*` config options that can't be applied to the default push
+    /// rules, so this is only reported once and not on every account creation.
+    fn validate_default_push_rules(&mut self) {
+        let server_user = UserId::parse(format!("@conduit:{}", self.server_name()))
+            .expect("@conduit:server_name is valid");
+        let mut ruleset = Ruleset::server_default(&server_user);
+
+        self.config.default_push_rules_disabled.retain(|rule_id| {
+            let exists = disable_push_rule(&mut ruleset, rule_id);
+            if !exists {
+                error!("Default push rule {rule_id} to disable does not exist, ignoring it");
+            }
+            exists
+        });
+
+        self.config.default_push_rules_keywords.retain(|keyword| {
+            match ruleset.insert(keyword_push_rule(keyword), None, None) {
+                Ok(()) => true,
+                Err(e) => {
+                    error!(
+                        "Failed to add default push rule for keyword {keyword}, ignoring it: {e}"
+                    );
+                    false
+                }
+            }
+        });
+
+        self.config
+            .default_push_rules_muted_users
+            .retain(|muted_user| {
+                match ruleset.insert(muted_user_push_rule(muted_user), None, None) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        error!(
+                            "Failed to add default push rule muting {muted_user}, ignoring it: {e}"
+                        );
+                        false
+                    }
+                }
+            });
+    }
+
+    pub fn enable_metrics(&self) -> bool {
+        self.config.enable_metrics
+    }
+
     pub fn enable_lightning_bolt(&self) -> bool {
         self.config.enable_lightning_bolt
     }
 
+    /// Returns the displayname a newly registered local user gets, if any.
+    pub fn new_user_displayname(&self, user_id: &UserId) -> Option<String> {
+        if !self.config.set_new_user_displayname {
+            return None;
+        }
+
+        Some(match &self.config.new_user_displayname_template {
+            Some(template) => template
+                .replace("{user_id}", user_id.as_str())
+                .replace("{localpart}", user_id.localpart())
+                .replace("{server_name}", self.server_name().as_str()),
+            None if self.enable_lightning_bolt() => format!("{} ⚡️", user_id.localpart()),
+            None => user_id.localpart().to_owned(),
+        })
+    }
+
     pub fn allow_check_for_updates(&self) -> bool {
         self.config.allow_check_for_updates
     }
 
+    /// Returns the endpoint to report usage statistics to, if reporting them is enabled.
+    pub fn report_stats_endpoint(&self) -> Option<&str> {
+        self.config
+            .report_stats_endpoint
+            .as_deref()
+            .filter(|_| self.config.report_stats)
+    }
+
     pub fn trusted_servers(&self) -> &[OwnedServerName] {
         &self.config.trusted_servers
     }
@@ -368,8 +672,12 @@ impl Service<'_> {
         &self.dns_resolver
     }
 
-    pub fn jwt_decoding_key(&self) -> Option<&jsonwebtoken::DecodingKey> {
-        self.jwt_decoding_key.as_ref()
+    pub fn oidc_issuer(&self) -> Option<&str> {
+        self.config.oidc_issuer.as_deref()
+    }
+
+    pub fn oidc_account_management_url(&self) -> Option<&str> {
+        self.config.oidc_account_management_url.as_deref()
     }
 
     pub fn turn_password(&self) -> &String {
@@ -428,6 +736,30 @@ impl Service<'_> {
         &self.config.forbidden_usernames
     }
 
+    pub fn banned_room_ids(&self) -> &[OwnedRoomId] {
+        &self.config.banned_room_ids
+    }
+
+    pub fn banned_room_patterns(&self) -> &RegexSet {
+        &self.config.banned_room_patterns
+    }
+
+    pub fn block_invites(&self) -> bool {
+        self.config.block_invites
+    }
+
+    pub fn invite_server_allowlist(&self) -> &[OwnedServerName] {
+        &self.config.invite_server_allowlist
+    }
+
+    pub fn invite_server_denylist(&self) -> &[OwnedServerName] {
+        &self.config.invite_server_denylist
+    }
+
+    pub fn invite_sender_denylist_patterns(&self) -> &RegexSet {
+        &self.config.invite_sender_denylist_patterns
+    }
+
     pub fn allow_local_presence(&self) -> bool {
         self.config.allow_local_presence
     }
@@ -436,6 +768,10 @@ impl Service<'_> {
         self.config.allow_incoming_presence
     }
 
+    pub fn incoming_presence_max_updates_per_minute(&self) -> usize {
+        self.config.incoming_presence_max_updates_per_minute
+    }
+
     pub fn allow_outgoing_presence(&self) -> bool {
         self.config.allow_outgoing_presence
     }
@@ -448,6 +784,24 @@ impl Service<'_> {
         self.config.presence_offline_timeout_s
     }
 
+    pub fn sync_max_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.sync_max_timeout_s)
+    }
+
+    pub fn sync_max_long_polls_per_user(&self) -> Option<usize> {
+        self.config.sync_max_long_polls_per_user
+    }
+
+    pub fn sync_low_memory_threshold_mb(&self) -> Option<u64> {
+        self.config.sync_low_memory_threshold_mb
+    }
+
+    /// Whether less memory is available than `sync_low_memory_threshold_mb`, in which case sync
+    /// requests don't wait for new events. Checked periodically by the memory check task.
+    pub fn sync_memory_low(&self) -> bool {
+        self.memory_low.load(atomic::Ordering::Relaxed)
+    }
+
     pub fn rocksdb_log_level(&self) -> &String {
         &self.config.rocksdb_log_level
     }
@@ -481,8 +835,6 @@ impl Service<'_> {
         room_versions
     }
 
-    /// TODO: the key valid until timestamp (`valid_until_ts`) is only honored in room version > 4
-    ///
     /// Remove the outdated keys and insert the new ones.
     ///
     /// This doesn't actually check that the keys provided are newer than the old set.
@@ -514,6 +866,129 @@ impl Service<'_> {
         Ok(keys)
     }
 
+    /// Returns true if the cached signing keys of the server with the given IDs may be used to
+    /// verify events sent at `ts`, i.e. each of them was valid until at least then according to
+    /// the server. Our own keys are always valid.
+    pub fn signing_keys_valid_at(
+        &self,
+        origin: &ServerName,
+        key_ids: &[String],
+        ts: MilliSecondsSinceUnixEpoch,
+    ) -> Result<bool> {
+        if origin == self.server_name() {
+            return Ok(true);
+        }
+
+        let valid_until: BTreeMap<_, _> = self
+            .db
+            .signing_keys_valid_until(origin)?
+            .into_iter()
+            .map(|(key_id, valid_until_ts)| (key_id.to_string(), valid_until_ts))
+            .collect();
+
+        Ok(key_ids.iter().all(|key_id| {
+            valid_until
+                .get(key_id)
+                .is_some_and(|valid_until_ts| *valid_until_ts >= ts)
+        }))
+    }
+
+    /// Parses the key response of a remote server, checking that it is about the server and
+    /// signed by one of the keys it lists as current.
+    pub fn verify_server_keys(
+        &self,
+        origin: &ServerName,
+        keys: &Raw<ServerSigningKeys>,
+    ) -> Result<ServerSigningKeys> {
+        let parsed = keys
+            .deserialize()
+            .map_err(|_| Error::BadServerResponse("Invalid server key response."))?;
+
+        if parsed.server_name != origin {
+            return Err(Error::BadServerResponse(
+                "Server key response is about another server.",
+            ));
+        }
+
+        let mut object: CanonicalJsonObject = serde_json::from_str(keys.json().get())
+            .map_err(|_| Error::BadServerResponse("Invalid server key response."))?;
+        let Some(CanonicalJsonValue::Object(signatures)) = object.remove("signatures") else {
+            return Err(Error::BadServerResponse(
+                "Server key response is not signed.",
+            ));
+        };
+        let Some(CanonicalJsonValue::Object(origin_signatures)) = signatures.get(origin.as_str())
+        else {
+            return Err(Error::BadServerResponse(
+                "Server key response is not signed by the server.",
+            ));
+        };
+
+        // Check the signatures on their own, other servers may have signed the response as well
+        let signed = parsed.verify_keys.iter().any(|(key_id, key)| {
+            let Some(signature) = origin_signatures.get(key_id.as_str()) else {
+                return false;
+            };
+
+            let mut object = object.clone();
+            object.insert(
+                "signatures".to_owned(),
+                CanonicalJsonValue::Object(BTreeMap::from([(
+                    origin.to_string(),
+                    CanonicalJsonValue::Object(BTreeMap::from([(
+                        key_id.to_string(),
+                        signature.clone(),
+                    )])),
+                )])),
+            );
+            let pub_key_map = BTreeMap::from([(
+                origin.to_string(),
+                BTreeMap::from([(key_id.to_string(), key.key.clone())]),
+            )]);
+
+            ruma::signatures::verify_json(&pub_key_map, &object).is_ok()
+        });
+
+        if !signed {
+            return Err(Error::BadServerResponse(
+                "Server key response is not signed by the server.",
+            ));
+        }
+
+        Ok(parsed)
+    }
+
+    /// Stores the key response of a remote server as signed by it, so we can serve it to other
+    /// servers as a notary.
+    pub fn add_signed_server_keys(
+        &self,
+        origin: &ServerName,
+        keys: &Raw<ServerSigningKeys>,
+    ) -> Result<()> {
+        self.db.add_signed_server_keys(origin, keys)
+    }
+
+    pub fn signed_server_keys(
+        &self,
+        origin: &ServerName,
+    ) -> Result<Option<Raw<ServerSigningKeys>>> {
+        self.db.signed_server_keys(origin)
+    }
+
+    /// Checks if a server was blocked with the `server-info --block` admin command. We neither
+    /// accept requests from blocked servers nor send requests to them.
+    pub fn is_server_blocked(&self, server_name: &ServerName) -> Result<bool> {
+        self.db.is_server_blocked(server_name)
+    }
+
+    pub fn block_server(&self, server_name: &ServerName, blocked: bool) -> Result<()> {
+        self.db.block_server(server_name, blocked)
+    }
+
+    pub fn blocked_servers<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedServerName>> + 'a> {
+        self.db.blocked_servers()
+    }
+
     pub fn database_version(&self) -> Result<u64> {
         self.db.database_version()
     }
@@ -523,18 +998,73 @@ impl Service<'_> {
     }
 
     pub fn get_media_folder(&self) -> PathBuf {
+        match &self.config.media_path {
+            Some(media_path) => PathBuf::from(media_path),
+            None => self.default_media_folder(),
+        }
+    }
+
+    /// Directory that user data exports are written to, if exports are enabled
+    pub fn get_user_export_folder(&self) -> Option<PathBuf> {
+        self.config.user_export_path.as_ref().map(PathBuf::from)
+    }
+
+    /// Media folder that is used if `media_path` is not set
+    fn default_media_folder(&self) -> PathBuf {
         let mut r = PathBuf::new();
         r.push(self.config.database_path.clone());
         r.push("media");
         r
     }
 
+    /// Moves media from the default media folder to `media_path`, if it was set after media was
+    /// already stored.
+    fn migrate_media_folder(&self) -> Result<()> {
+        let old_folder = self.default_media_folder();
+        let new_folder = self.get_media_folder();
+
+        if old_folder == new_folder || !old_folder.is_dir() {
+            return Ok(());
+        }
+
+        info!(
+            "Moving media from {} to {}",
+            old_folder.display(),
+            new_folder.display()
+        );
+
+        let mut moved = 0_usize;
+        for entry in fs::read_dir(&old_folder)? {
+            let entry = entry?;
+            let new_path = new_folder.join(entry.file_name());
+
+            // Renaming fails if the folders are on different file systems
+            if fs::rename(entry.path(), &new_path).is_err() {
+                fs::copy(entry.path(), &new_path)?;
+                fs::remove_file(entry.path())?;
+            }
+            moved += 1;
+        }
+
+        fs::remove_dir(&old_folder)?;
+
+        info!("Moved {moved} media files to {}", new_folder.display());
+
+        Ok(())
+    }
+
+    /// Path of media content, named after the SHA256 hash of the content. Files with the same
+    /// content share this path.
+    pub fn get_media_content_file(&self, hash: &[u8]) -> PathBuf {
+        let mut r = self.get_media_folder();
+        r.push(general_purpose::URL_SAFE_NO_PAD.encode(hash));
+        r
+    }
+
     /// new SHA256 file name media function, requires "sha256_media" feature flag enabled and database migrated
     /// uses SHA256 hash of the base64 key as the file name
     pub fn get_media_file_new(&self, key: &[u8]) -> PathBuf {
-        let mut r = PathBuf::new();
-        r.push(self.config.database_path.clone());
-        r.push("media");
+        let mut r = self.get_media_folder();
         // Using the hash of the base64 key as the filename
         // This is to prevent the total length of the path from exceeding the maximum length in most filesystems
         r.push(general_purpose::URL_SAFE_NO_PAD.encode(sha2::Sha256::digest(key)));
@@ -548,9 +1078,7 @@ impl Service<'_> {
     /// Please use `get_media_file_new` instead.
     #[deprecated(note = "Use get_media_file_new instead")]
     pub fn get_media_file(&self, key: &[u8]) -> PathBuf {
-        let mut r = PathBuf::new();
-        r.push(self.config.database_path.clone());
-        r.push("media");
+        let mut r = self.get_media_folder();
         r.push(general_purpose::URL_SAFE_NO_PAD.encode(key));
         r
     }
@@ -643,3 +1171,33 @@ fn url_preview_reqwest_client_builder(config: &Config) -> Result<reqwest::Client
 
     Ok(reqwest_client_builder)
 }
+
+/// Disables the push rule with the ID, whatever its kind. Returns false if there is no such rule.
+fn disable_push_rule(ruleset: &mut Ruleset, rule_id: &str) -> bool {
+    [
+        RuleKind::Override,
+        RuleKind::Content,
+        RuleKind::Room,
+        RuleKind::Sender,
+        RuleKind::Underride,
+    ]
+    .into_iter()
+    .any(|kind| ruleset.set_enabled(kind, rule_id, false).is_ok())
+}
+
+fn keyword_push_rule(keyword: &str) -> NewPushRule {
+    NewPushRule::Content(NewPatternedPushRule::new(
+        format!("keyword.{keyword}"),
+        keyword.to_owned(),
+        vec![
+            Action::Notify,
+            Action::SetTweak(Tweak::Sound("default".to_owned())),
+            Action::SetTweak(Tweak::Highlight(true)),
+        ],
+    ))
+}
+
+fn muted_user_push_rule(muted_user: &UserId) -> NewPushRule {
+    // Sender rules without actions don't notify
+    NewPushRule::Sender(NewSimplePushRule::new(muted_user.to_owned(), Vec::new()))
+}
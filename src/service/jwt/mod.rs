@@ -0,0 +1,341 @@
+use std::time::{Duration, Instant};
+
+use jsonwebtoken::{
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use ruma::api::client::error::ErrorKind;
+use serde_json::{Map, Value};
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+use crate::{config::JwtConfig, services, Config, Error, Result};
+
+/// How long the key set of the identity provider is used before it is fetched again
+const JWKS_CACHE_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Shortest time between two fetches of the key set because a token was signed with an unknown
+/// key, which happens when the identity provider rotates its keys
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Login with JSON web tokens signed by a shared secret, a public key or the keys of an identity
+/// provider.
+pub struct Service {
+    config: Option<JwtConfig>,
+    /// Key from the secret or public key file
+    static_key: Option<DecodingKey>,
+    jwks: Mutex<Option<(JwkSet, Instant)>>,
+}
+
+impl Service {
+    pub fn build(config: &Config) -> Result<Self> {
+        let jwt = config.jwt_config();
+
+        let static_key = match &jwt {
+            Some(
+                jwt @ JwtConfig {
+                    secret: Some(secret),
+                    ..
+                },
+            ) => {
+                if !matches!(
+                    jwt.algorithm(),
+                    Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+                ) {
+                    return Err(Error::bad_config("JWT secrets require an HMAC algorithm."));
+                }
+
+                Some(DecodingKey::from_secret(secret.as_bytes()))
+            }
+            Some(
+                jwt @ JwtConfig {
+                    public_key_file: Some(path),
+                    ..
+                },
+            ) => {
+                let pem = std::fs::read(path).map_err(|e| {
+                    error!("Failed to read JWT public key file {}: {e}", path.display());
+                    Error::bad_config("Failed to read the JWT public key file.")
+                })?;
+
+                let key = match jwt.algorithm() {
+                    Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                        return Err(Error::bad_config(
+                            "JWT public keys can't be used with HMAC algorithms.",
+                        ));
+                    }
+                    Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem),
+                    Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem),
+                    _ => DecodingKey::from_rsa_pem(&pem),
+                }
+                .map_err(|e| {
+                    error!("Invalid JWT public key: {e}");
+                    Error::bad_config("Invalid JWT public key.")
+                })?;
+
+                Some(key)
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            config: jwt,
+            static_key,
+            jwks: Mutex::new(None),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.is_some()
+    }
+
+    /// Validates a login token and returns the localpart of the user it was issued for.
+    pub async fn localpart(&self, token: &str) -> Result<String> {
+        let config = self.config.as_ref().ok_or(Error::BadRequest(
+            ErrorKind::Unknown,
+            "Token login is not supported (server has no jwt decoding key).",
+        ))?;
+
+        let key = match &self.static_key {
+            Some(key) => key.clone(),
+            None => {
+                let header = jsonwebtoken::decode_header(token).map_err(invalid_token)?;
+                self.jwks_key(config, header.kid.as_deref()).await?
+            }
+        };
+
+        decode_localpart(config, token, &key)
+    }
+
+    /// Returns the key of the identity provider with the ID, or its first key if there is none.
+    async fn jwks_key(&self, config: &JwtConfig, kid: Option<&str>) -> Result<DecodingKey> {
+        let mut jwks = self.jwks.lock().await;
+
+        let jwk = match cached_jwk(jwks.as_ref(), kid, Instant::now()) {
+            Some(jwk) => jwk,
+            None => {
+                let keys = fetch_jwks(config).await?;
+                let jwk = find_jwk(&keys, kid);
+                *jwks = Some((keys, Instant::now()));
+                jwk
+            }
+        }
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidUsername,
+            "Token is signed with an unknown key.",
+        ))?;
+
+        DecodingKey::from_jwk(&jwk).map_err(|e| {
+            warn!("Invalid key in the JWKS of the identity provider: {e}");
+            Error::BadServerResponse("Invalid key from the identity provider.")
+        })
+    }
+}
+
+fn invalid_token(e: jsonwebtoken::errors::Error) -> Error {
+    warn!("Failed to parse JWT token from user logging in: {e}");
+    Error::BadRequest(ErrorKind::InvalidUsername, "Token is invalid.")
+}
+
+/// Checks the signature, expiry, issuer and audience of a token and returns the lowercased
+/// localpart from its claims.
+fn decode_localpart(config: &JwtConfig, token: &str, key: &DecodingKey) -> Result<String> {
+    let mut validation = Validation::new(config.algorithm());
+    if let Some(issuer) = &config.issuer {
+        validation.set_issuer(&[issuer]);
+        validation.required_spec_claims.insert("iss".to_owned());
+    }
+    if config.audience.is_empty() {
+        validation.validate_aud = false;
+    } else {
+        validation.set_audience(&config.audience);
+        validation.required_spec_claims.insert("aud".to_owned());
+    }
+
+    let claims = jsonwebtoken::decode::<Map<String, Value>>(token, key, &validation)
+        .map_err(invalid_token)?
+        .claims;
+
+    claims
+        .get(&config.localpart_claim)
+        .and_then(Value::as_str)
+        .map(str::to_lowercase)
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidUsername,
+            "Token has no username.",
+        ))
+}
+
+fn find_jwk(jwks: &JwkSet, kid: Option<&str>) -> Option<Jwk> {
+    match kid {
+        Some(kid) => jwks.find(kid).cloned(),
+        None => jwks.keys.first().cloned(),
+    }
+}
+
+/// Looks up the key in the cached key set. Returns `None` if the key set has to be fetched first,
+/// because it expired or doesn't have the key and wasn't refreshed recently.
+fn cached_jwk(
+    cache: Option<&(JwkSet, Instant)>,
+    kid: Option<&str>,
+    now: Instant,
+) -> Option<Option<Jwk>> {
+    let (keys, fetched_at) = cache?;
+    let age = now.saturating_duration_since(*fetched_at);
+
+    if age > JWKS_CACHE_DURATION {
+        return None;
+    }
+
+    match find_jwk(keys, kid) {
+        Some(key) => Some(Some(key)),
+        None if age <= JWKS_REFRESH_INTERVAL => Some(None),
+        None => None,
+    }
+}
+
+async fn fetch_jwks(config: &JwtConfig) -> Result<JwkSet> {
+    let url = config
+        .jwks_url
+        .as_ref()
+        .expect("JWKS URL is set without a static key");
+
+    let response = services()
+        .globals
+        .default_client()
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| {
+            warn!("Failed to fetch JWKS from {url}: {e}");
+            Error::BadServerResponse("Failed to reach the identity provider.")
+        })?;
+
+    serde_json::from_str(&response.text().await?).map_err(|e| {
+        warn!("Invalid JWKS from {url}: {e}");
+        Error::BadServerResponse("Invalid key set from the identity provider.")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &str = "secret";
+
+    fn token(claims: Value) -> String {
+        jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn exp() -> u64 {
+        jsonwebtoken::get_current_timestamp() + 60
+    }
+
+    fn localpart(config: &JwtConfig, claims: Value) -> Result<String> {
+        decode_localpart(
+            config,
+            &token(claims),
+            &DecodingKey::from_secret(SECRET.as_bytes()),
+        )
+    }
+
+    #[test]
+    fn validates_signature_and_expiry() {
+        let config = JwtConfig::from_secret(SECRET.to_owned());
+
+        assert_eq!(
+            localpart(&config, json!({ "sub": "Alice", "exp": exp() })).unwrap(),
+            "alice"
+        );
+        assert!(localpart(&config, json!({ "sub": "alice", "exp": 1 })).is_err());
+        assert!(decode_localpart(
+            &config,
+            &token(json!({ "sub": "alice", "exp": exp() })),
+            &DecodingKey::from_secret(b"other secret"),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn validates_issuer_and_audience() {
+        let mut config = JwtConfig::from_secret(SECRET.to_owned());
+        config.issuer = Some("https://idp.example.com/".to_owned());
+        config.audience = vec!["matrix".to_owned()];
+
+        let claims =
+            |iss: &str, aud: &str| json!({ "sub": "alice", "exp": exp(), "iss": iss, "aud": aud });
+
+        assert!(localpart(&config, claims("https://idp.example.com/", "matrix")).is_ok());
+        assert!(localpart(&config, claims("https://evil.example.com/", "matrix")).is_err());
+        assert!(localpart(&config, claims("https://idp.example.com/", "other")).is_err());
+        assert!(localpart(&config, json!({ "sub": "alice", "exp": exp() })).is_err());
+
+        // Audiences of tokens are ignored if none are configured
+        config.audience.clear();
+        assert!(localpart(&config, claims("https://idp.example.com/", "other")).is_ok());
+    }
+
+    #[test]
+    fn reads_localpart_claim() {
+        let mut config = JwtConfig::from_secret(SECRET.to_owned());
+        config.localpart_claim = "preferred_username".to_owned();
+
+        assert_eq!(
+            localpart(
+                &config,
+                json!({ "sub": "1234", "preferred_username": "bob", "exp": exp() })
+            )
+            .unwrap(),
+            "bob"
+        );
+        assert!(localpart(&config, json!({ "sub": "1234", "exp": exp() })).is_err());
+    }
+
+    #[test]
+    fn refreshes_jwks_for_unknown_keys() {
+        let jwks: JwkSet = serde_json::from_value(json!({
+            "keys": [
+                { "kty": "oct", "kid": "a", "k": "c2VjcmV0" },
+                { "kty": "oct", "kid": "b", "k": "c2VjcmV0" },
+            ]
+        }))
+        .unwrap();
+        let fetched_at = Instant::now();
+        let cached = (jwks, fetched_at);
+        let cache = Some(&cached);
+        let kid = |jwk: Option<Option<Jwk>>| jwk.map(|jwk| jwk.and_then(|jwk| jwk.common.key_id));
+
+        // Nothing fetched yet
+        assert!(cached_jwk(None, Some("a"), fetched_at).is_none());
+
+        let soon = fetched_at + Duration::from_secs(1);
+        assert_eq!(
+            kid(cached_jwk(cache, Some("b"), soon)),
+            Some(Some("b".to_owned()))
+        );
+        assert_eq!(
+            kid(cached_jwk(cache, None, soon)),
+            Some(Some("a".to_owned()))
+        );
+        // Unknown keys don't fetch the key set again right after it was fetched
+        assert_eq!(kid(cached_jwk(cache, Some("c"), soon)), Some(None));
+
+        let later = fetched_at + JWKS_REFRESH_INTERVAL + Duration::from_secs(1);
+        assert_eq!(
+            kid(cached_jwk(cache, Some("a"), later)),
+            Some(Some("a".to_owned()))
+        );
+        assert!(cached_jwk(cache, Some("c"), later).is_none());
+
+        let expired = fetched_at + JWKS_CACHE_DURATION + Duration::from_secs(1);
+        assert!(cached_jwk(cache, Some("a"), expired).is_none());
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::hash_map::Entry,
+    hash::Hash,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use ruma::{
+    api::client::error::ErrorKind, events::room::message::RoomMessageEventContent, OwnedUserId,
+    UserId,
+};
+use tracing::warn;
+
+use crate::{services, utils::sharded_map::ShardedMap, Error, Result};
+
+/// Failed logins of an account before further attempts are delayed
+const FREE_USER_FAILURES: u32 = 3;
+
+/// Failed logins from an IP address before further attempts are delayed, higher than for accounts
+/// as many users can share an address
+const FREE_IP_FAILURES: u32 = 10;
+
+/// Longest delay between two login attempts
+const MAX_DELAY: Duration = Duration::from_secs(15 * 60);
+
+/// How long failed logins are remembered after the last attempt
+const FAILURE_MEMORY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Number of tracked accounts or addresses above which forgotten entries are removed
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Clone, Copy)]
+pub struct FailedLogins {
+    count: u32,
+    last_attempt: Instant,
+}
+
+/// Throttles password logins of accounts and IP addresses with failed attempts, so passwords can't
+/// be brute forced.
+pub struct Service {
+    pub users: ShardedMap<OwnedUserId, FailedLogins>,
+    pub ips: ShardedMap<IpAddr, FailedLogins>,
+}
+
+impl Service {
+    pub fn build() -> Self {
+        Self {
+            users: ShardedMap::new(),
+            ips: ShardedMap::new(),
+        }
+    }
+
+    /// Checks whether the account and address may try to log in now. Every attempt counts as a
+    /// failure until it succeeds, so attempts sent at the same time are throttled too.
+    pub fn check(&self, user_id: Option<&UserId>, ip: Option<IpAddr>) -> Result<()> {
+        if !services().globals.login_throttling() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+
+        if let Some(ip) = ip {
+            attempt(&self.ips, ip, now, |failures| {
+                delay(failures.count, FREE_IP_FAILURES)
+                    .map(|delay| (delay, "Too many failed logins, try again later."))
+            })?;
+        }
+
+        if let Some(user_id) = user_id {
+            // Admins are never locked, otherwise anyone could lock them out of the server
+            let lockout = services()
+                .globals
+                .login_lockout_threshold()
+                .filter(|_| !services().users.is_admin(user_id).unwrap_or(false))
+                .map(|threshold| (threshold, services().globals.login_lockout_duration()));
+
+            attempt(
+                &self.users,
+                user_id.to_owned(),
+                now,
+                |failures| match lockout {
+                    Some((threshold, duration)) if failures.count >= threshold => {
+                        Some((duration, "This account is temporarily locked."))
+                    }
+                    _ => delay(failures.count, FREE_USER_FAILURES)
+                        .map(|delay| (delay, "Too many failed logins, try again later.")),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Resets the failed logins of the account and takes back the attempt of the address.
+    pub fn succeeded(&self, user_id: &UserId, ip: Option<IpAddr>) {
+        self.users.remove(user_id);
+
+        if let Some(ip) = ip {
+            self.ips.with_entry(ip, |entry| {
+                if let Entry::Occupied(mut entry) = entry {
+                    let failures = entry.get_mut();
+                    failures.count = failures.count.saturating_sub(1);
+                }
+            });
+        }
+    }
+
+    /// Tells the admin room when the failed login of the account locked it. Admins are not locked,
+    /// but the admin room is still told about the failed logins.
+    pub fn failed(&self, user_id: &UserId) {
+        let Some(threshold) = services().globals.login_lockout_threshold() else {
+            return;
+        };
+        if !services().globals.login_throttling() {
+            return;
+        }
+
+        let count = self.users.get(user_id).map_or(0, |failures| failures.count);
+        if count != threshold {
+            return;
+        }
+
+        let message = if services().users.is_admin(user_id).unwrap_or(false) {
+            warn!("Admin {user_id} had {count} failed logins");
+            format!("Admin {user_id} had {count} failed logins in a row, admins are not locked.")
+        } else {
+            warn!("Locking {user_id} after {count} failed logins");
+            format!(
+                "{user_id} was locked for {} seconds after {count} failed logins in a row.",
+                services().globals.login_lockout_duration().as_secs()
+            )
+        };
+        services()
+            .admin
+            .send_message(RoomMessageEventContent::notice_plain(message));
+    }
+}
+
+/// How long to wait after the last attempt, once the free failures are used up.
+fn delay(count: u32, free: u32) -> Option<Duration> {
+    let doublings = count.checked_sub(free)?;
+    let delay = Duration::from_secs(2_u64.saturating_pow(doublings));
+    Some(delay.min(MAX_DELAY))
+}
+
+/// Counts an attempt of the key, unless `wait` returns how long it still has to wait after its
+/// last attempt.
+fn attempt<K: Hash + Eq>(
+    failed_logins: &ShardedMap<K, FailedLogins>,
+    key: K,
+    now: Instant,
+    wait: impl FnOnce(&FailedLogins) -> Option<(Duration, &'static str)>,
+) -> Result<()> {
+    let memory = FAILURE_MEMORY.max(services().globals.login_lockout_duration());
+
+    if failed_logins.len() > PRUNE_THRESHOLD {
+        failed_logins.retain(|_, failures| now.duration_since(failures.last_attempt) < memory);
+    }
+
+    failed_logins.with_entry(key, |entry| {
+        let failures = entry.or_insert(FailedLogins {
+            count: 0,
+            last_attempt: now,
+        });
+        if now.duration_since(failures.last_attempt) >= memory {
+            failures.count = 0;
+        }
+
+        if let Some((wait, message)) = wait(failures) {
+            let remaining = (failures.last_attempt + wait).saturating_duration_since(now);
+            if !remaining.is_zero() {
+                return Err(Error::BadRequest(
+                    ErrorKind::LimitExceeded {
+                        retry_after_ms: Some(remaining),
+                    },
+                    message,
+                ));
+            }
+        }
+
+        failures.count += 1;
+        failures.last_attempt = now;
+        Ok(())
+    })
+}
@@ -1,3 +1,5 @@
+use ruma::UserId;
+
 use crate::Result;
 
 pub trait Data: Send + Sync {
@@ -18,6 +20,22 @@ pub trait Data: Send + Sync {
         height: u32,
     ) -> Result<(Option<String>, Option<String>, Vec<u8>)>;
 
+    /// Returns the SHA256 hash of the content of the file or thumbnail.
+    fn content_hash(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn set_content_hash(&self, key: &[u8], hash: &[u8]) -> Result<()>;
+
+    /// Returns the number of files that share the content after adding a reference.
+    fn increment_content_refcount(&self, hash: &[u8]) -> Result<u64>;
+
+    /// Returns the number of files that still share the content after removing a reference.
+    fn decrement_content_refcount(&self, hash: &[u8]) -> Result<u64>;
+
+    /// Returns the metadata key and parsed metadata of all files and thumbnails.
+    fn all_file_metadata<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(Vec<u8>, super::MediaEntry)>> + 'a>;
+
     fn remove_url_preview(&self, url: &str) -> Result<()>;
 
     fn set_url_preview(
@@ -28,4 +46,17 @@ pub trait Data: Send + Sync {
     ) -> Result<()>;
 
     fn get_url_preview(&self, url: &str) -> Option<super::UrlPreviewData>;
+
+    fn set_quarantined(&self, mxc: &str, quarantined: bool) -> Result<()>;
+
+    fn is_quarantined(&self, mxc: &str) -> Result<bool>;
+
+    /// Remembers that the user uploaded the file with the given size.
+    fn add_upload(&self, user_id: &UserId, mxc: &str, size: u64) -> Result<()>;
+
+    /// Returns the MXC URI, upload timestamp and size of all files uploaded by the user.
+    fn uploads_of_user<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<(String, u64, u64)>> + 'a>;
 }
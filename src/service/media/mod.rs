@@ -1,22 +1,87 @@
 mod data;
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap},
+    ffi::OsStr,
+    fmt,
+    future::Future,
     io::Cursor,
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
-    time::SystemTime,
+    time::{Duration, Instant, SystemTime},
 };
 
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
 pub(crate) use data::Data;
-use serde::Serialize;
+use futures_util::{Stream, StreamExt};
+use lru_cache::LruCache;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
 
-use crate::{services, Result};
+use ruma::{api::client::error::ErrorKind, UserId};
+
+use crate::{services, utils, utils::sharded_map::ShardedMap, Error, Result};
 use image::imageops::FilterType;
 
 use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    fs::{self, File},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     sync::Mutex,
 };
+use tracing::{info, warn};
+
+/// Name of the index of an exported media store, one JSON object per line
+const EXPORT_INDEX: &str = "index.jsonl";
+
+/// Directory of an exported media store that contains the files
+const EXPORT_FILES: &str = "files";
+
+pub struct MediaUpload {
+    pub mxc: String,
+    pub content_type: Option<String>,
+    pub size: u64,
+    pub uploaded_at: u64, // milliseconds since the unix epoch
+}
+
+/// Thumbnail sizes that are generated, see `thumbnail_properties`
+const THUMBNAIL_SIZES: [(u32, u32); 5] = [(32, 32), (96, 96), (320, 240), (640, 480), (800, 600)];
+
+/// How long we stop asking a remote server for a file after fetching it failed
+const FAILED_FETCH_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// Verdict of the external media scanner
+#[derive(Deserialize)]
+struct ScanResult {
+    clean: bool,
+    info: Option<String>,
+}
+
+/// Metadata of a file or thumbnail in the media store.
+#[derive(Serialize, Deserialize)]
+pub struct MediaEntry {
+    pub mxc: String,
+    pub width: u32, // 0 if it's not a thumbnail
+    pub height: u32,
+    pub content_disposition: Option<String>,
+    pub content_type: Option<String>,
+}
+
+/// Entry of the index of an exported media store.
+#[derive(Serialize, Deserialize)]
+struct ExportedMedia {
+    #[serde(flatten)]
+    media: MediaEntry,
+    /// Name of the file in the files directory of the export
+    file: String,
+}
+
+/// A stored file that is read on demand instead of being loaded into memory.
+pub struct MediaFile {
+    pub content_disposition: Option<String>,
+    pub content_type: Option<String>,
+    pub file: File,
+    pub len: u64,
+}
 
 pub struct FileMeta {
     pub content_disposition: Option<String>,
@@ -61,31 +126,136 @@ pub struct UrlPreviewData {
 pub struct Service {
     pub db: &'static dyn Data,
     pub url_preview_mutex: RwLock<HashMap<String, Arc<Mutex<()>>>>,
+    /// Locks of content hashes whose refcount is being changed
+    pub content_locks: ShardedMap<Vec<u8>, Arc<Mutex<()>>>,
+    /// Remote MXC URIs that could not be fetched recently, with the time of the failure
+    pub failed_remote_fetches: std::sync::Mutex<LruCache<String, Instant>>,
 }
 
 impl Service {
-    /// Uploads a file.
-    pub async fn create(
+    /// Returns true if fetching the remote media failed recently and should not be retried yet.
+    pub fn remote_fetch_failed_recently(&self, mxc: &str) -> bool {
+        let mut failed_remote_fetches = self.failed_remote_fetches.lock().unwrap();
+        match failed_remote_fetches.get_mut(mxc) {
+            Some(failed_at) if failed_at.elapsed() < FAILED_FETCH_BACKOFF => true,
+            Some(_) => {
+                failed_remote_fetches.remove(mxc);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Remembers that fetching the remote media failed, see `remote_fetch_failed_recently`.
+    pub fn record_failed_remote_fetch(&self, mxc: String) {
+        self.failed_remote_fetches
+            .lock()
+            .unwrap()
+            .insert(mxc, Instant::now());
+    }
+
+    /// Returns the files uploaded by the user, newest first.
+    pub fn uploads_of_user(&self, user_id: &UserId) -> Result<Vec<MediaUpload>> {
+        let mut uploads = self
+            .db
+            .uploads_of_user(user_id)
+            .map(|upload| {
+                let (mxc, uploaded_at, size) = upload?;
+                let content_type = self
+                    .db
+                    .search_file_metadata(mxc.clone(), 0, 0)
+                    .ok()
+                    .and_then(|(_, content_type, _)| content_type)
+                    .filter(|content_type| !content_type.is_empty());
+
+                Ok(MediaUpload {
+                    mxc,
+                    content_type,
+                    size,
+                    uploaded_at,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        uploads.sort_unstable_by(|a, b| b.uploaded_at.cmp(&a.uploaded_at));
+
+        Ok(uploads)
+    }
+
+    /// Uploads a file that is read from a stream, without keeping the whole file in memory. Fails
+    /// if the file is larger than `max_size` bytes. The uploader is remembered for local uploads,
+    /// so admins can find all media of a user.
+    pub async fn create_from_stream<S, E>(
         &self,
+        sender_user: Option<&UserId>,
         mxc: String,
         content_disposition: Option<&str>,
         content_type: Option<&str>,
-        file: &[u8],
-    ) -> Result<()> {
-        // Width, Height = 0 if it's not a thumbnail
-        let key = self
-            .db
-            .create_file_metadata(mxc, 0, 0, content_disposition, content_type)?;
+        mut stream: S,
+        max_size: u64,
+    ) -> Result<()>
+    where
+        S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+        E: fmt::Display,
+    {
+        let path = services()
+            .globals
+            .get_media_folder()
+            .join(format!("upload-{}", utils::random_string(16)));
+
+        let written = async {
+            let mut file = File::create(&path).await?;
+            let mut hasher = sha2::Sha256::new();
+            let mut size = 0;
+
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(|e| {
+                    warn!("Failed to read upload: {}", e);
+                    Error::BadRequest(ErrorKind::Unknown, "Failed to read the uploaded file.")
+                })?;
+
+                size += chunk.len() as u64;
+                if size > max_size {
+                    return Err(Error::BadRequest(
+                        ErrorKind::TooLarge,
+                        "File is larger than the maximum upload size.",
+                    ));
+                }
 
-        let path = if cfg!(feature = "sha256_media") {
-            services().globals.get_media_file_new(&key)
-        } else {
-            #[allow(deprecated)]
-            services().globals.get_media_file(&key)
+                hasher.update(&chunk);
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+
+            Ok::<_, Error>((hasher.finalize().to_vec(), size))
+        }
+        .await;
+
+        let (hash, size) = match written {
+            Ok(written) => written,
+            Err(e) => {
+                let _ = fs::remove_file(&path).await;
+                return Err(e);
+            }
         };
 
-        let mut f = File::create(path).await?;
-        f.write_all(file).await?;
+        // Width, Height = 0 if it's not a thumbnail
+        let key =
+            self.db
+                .create_file_metadata(mxc.clone(), 0, 0, content_disposition, content_type)?;
+
+        self.store_temporary_file(&key, &hash, &path).await?;
+
+        if let Some(sender_user) = sender_user {
+            self.db.add_upload(sender_user, &mxc, size)?;
+        }
+
+        // The stored file is streamed to the scanner
+        if services().globals.config.media_scanner_url.is_some() {
+            let file = File::open(self.file_path(&key)?).await?;
+            self.scan(&mxc, content_type, file.into()).await?;
+        }
+
         Ok(())
     }
 
@@ -99,50 +269,290 @@ impl Service {
         height: u32,
         file: &[u8],
     ) -> Result<()> {
-        let key =
-            self.db
-                .create_file_metadata(mxc, width, height, content_disposition, content_type)?;
+        let key = self.db.create_file_metadata(
+            mxc.clone(),
+            width,
+            height,
+            content_disposition,
+            content_type,
+        )?;
+
+        self.store_file(&key, file).await?;
+
+        self.scan(&mxc, content_type, file.to_vec().into()).await
+    }
+
+    /// Sends a new file to the external media scanner, if one is configured, and quarantines it
+    /// unless it is clean. Files that could not be scanned are only quarantined if
+    /// `media_scanner_fail_closed` is set.
+    async fn scan(&self, mxc: &str, content_type: Option<&str>, file: reqwest::Body) -> Result<()> {
+        let config = &services().globals.config;
+        let Some(url) = &config.media_scanner_url else {
+            return Ok(());
+        };
+
+        let mut request = services()
+            .globals
+            .default_client()
+            .post(url)
+            .query(&[("mxc", mxc)])
+            .body(file);
+        if let Some(content_type) = content_type {
+            request = request.header(reqwest::header::CONTENT_TYPE, content_type);
+        }
+
+        let verdict = match request.send().await {
+            Ok(response) if response.status().is_success() => match response.text().await {
+                Ok(body) => serde_json::from_str::<ScanResult>(&body)
+                    .map_err(|e| format!("invalid response: {e}")),
+                Err(e) => Err(e.to_string()),
+            },
+            Ok(response) => Err(format!("status {}", response.status())),
+            Err(e) => Err(e.to_string()),
+        };
+
+        match verdict {
+            Ok(ScanResult { clean: true, .. }) => Ok(()),
+            Ok(ScanResult { info, .. }) => {
+                warn!(
+                    "Media scanner flagged {}, quarantining it: {}",
+                    mxc,
+                    info.as_deref().unwrap_or("no details")
+                );
+                self.quarantine(mxc)
+            }
+            Err(e) if config.media_scanner_fail_closed => {
+                warn!("Failed to scan {}, quarantining it: {}", mxc, e);
+                self.quarantine(mxc)
+            }
+            Err(e) => {
+                warn!("Failed to scan {}, serving it unscanned: {}", mxc, e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the maximum size of files the user may upload. Overrides for appservices, admins and
+    /// guests take precedence in that order. Uploads are streamed, so the limit may exceed the
+    /// maximum request size.
+    pub fn max_upload_size(&self, user_id: &UserId, appservice_id: Option<&str>) -> Result<u32> {
+        let config = &services().globals.config;
 
-        let path = if cfg!(feature = "sha256_media") {
-            services().globals.get_media_file_new(&key)
+        let limit = if let Some(limit) =
+            appservice_id.and_then(|id| config.appservice_max_upload_size.get(id))
+        {
+            Some(*limit)
+        } else if config.max_upload_size_admin.is_some() && services().users.is_admin(user_id)? {
+            config.max_upload_size_admin
+        } else if config.max_upload_size_guest.is_some() && services().users.is_guest(user_id)? {
+            config.max_upload_size_guest
         } else {
-            #[allow(deprecated)]
-            services().globals.get_media_file(&key)
+            config.max_upload_size
         };
 
-        let mut f = File::create(path).await?;
-        f.write_all(file).await?;
+        Ok(limit.unwrap_or(config.max_request_size))
+    }
+
+    /// Stores the content of a file under its hash, so identical files only exist once on disk.
+    /// Replaces the previous content of the metadata key.
+    async fn store_file(&self, key: &[u8], file: &[u8]) -> Result<()> {
+        let hash = sha2::Sha256::digest(file).to_vec();
+
+        if self.db.content_hash(key)?.as_ref() == Some(&hash) {
+            return Ok(());
+        }
+
+        let path = services()
+            .globals
+            .get_media_folder()
+            .join(format!("upload-{}", utils::random_string(16)));
+        let written = async {
+            let mut f = File::create(&path).await?;
+            f.write_all(file).await?;
+            f.flush().await?;
+            Ok::<_, Error>(())
+        }
+        .await;
+        if let Err(e) = written {
+            let _ = fs::remove_file(&path).await;
+            return Err(e);
+        }
+
+        self.store_temporary_file(key, &hash, &path).await
+    }
+
+    /// Like `store_file`, but moves an already written temporary file into place.
+    async fn store_temporary_file(&self, key: &[u8], hash: &[u8], path: &Path) -> Result<()> {
+        let previous_hash = self.db.content_hash(key)?;
+        if previous_hash.as_deref() == Some(hash) {
+            fs::remove_file(path).await?;
+            return Ok(());
+        }
+
+        self.acquire_content(hash, path).await?;
+
+        if let Err(e) = self.db.set_content_hash(key, hash) {
+            self.release_content(hash).await?;
+            return Err(e);
+        }
+
+        if let Some(previous_hash) = previous_hash {
+            self.release_content(&previous_hash).await?;
+        }
 
         Ok(())
     }
 
-    /// Downloads a file.
-    pub async fn get(&self, mxc: String) -> Result<Option<FileMeta>> {
+    /// Adds a reference to the content with the hash. If the content is new, the temporary file at
+    /// `path` is moved into place, otherwise it is removed. The refcount is not changed if this
+    /// fails.
+    async fn acquire_content(&self, hash: &[u8], path: &Path) -> Result<()> {
+        let result = self
+            .with_content_lock(hash, async {
+                if self.db.increment_content_refcount(hash)? > 1 {
+                    return Ok(false);
+                }
+
+                let content_path = services().globals.get_media_content_file(hash);
+                if let Err(e) = fs::rename(path, &content_path).await {
+                    self.db.decrement_content_refcount(hash)?;
+                    return Err(e.into());
+                }
+
+                Ok(true)
+            })
+            .await;
+
+        if !matches!(result, Ok(true)) {
+            let _ = fs::remove_file(path).await;
+        }
+
+        result.map(|_| ())
+    }
+
+    /// Drops a reference to stored content, deleting the file once nothing refers to it anymore.
+    async fn release_content(&self, hash: &[u8]) -> Result<()> {
+        self.with_content_lock(hash, async {
+            if self.db.decrement_content_refcount(hash)? == 0 {
+                let path = services().globals.get_media_content_file(hash);
+                if let Err(e) = fs::remove_file(&path).await {
+                    warn!(
+                        "Failed to remove unreferenced media file {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Runs `f` while holding the lock of the content hash. All refcount changes of a hash and
+    /// the creation and removal of its file happen under this lock, so a file is complete before
+    /// anyone else refers to it, and isn't removed while a new reference is added.
+    async fn with_content_lock<T>(
+        &self,
+        hash: &[u8],
+        f: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let mutex = self.content_locks.get_or_default(hash);
+        let result = {
+            let _guard = mutex.lock().await;
+            f.await
+        };
+        drop(mutex);
+
+        // Forget the lock unless someone else is waiting for it
+        self.content_locks.with_entry(hash.to_vec(), |entry| {
+            if let Entry::Occupied(entry) = entry {
+                if Arc::strong_count(entry.get()) == 1 {
+                    entry.remove();
+                }
+            }
+        });
+
+        result
+    }
+
+    /// Returns where the content of a file is stored.
+    fn file_path(&self, key: &[u8]) -> Result<PathBuf> {
+        let hash = self
+            .db
+            .content_hash(key)?
+            .ok_or_else(|| Error::bad_database("Media has no content hash."))?;
+
+        Ok(services().globals.get_media_content_file(&hash))
+    }
+
+    /// Hides a file and its thumbnails from everyone without deleting them.
+    pub fn quarantine(&self, mxc: &str) -> Result<()> {
+        self.db.set_quarantined(mxc, true)
+    }
+
+    /// Makes a quarantined file available again. Returns false if it was not quarantined.
+    pub fn unquarantine(&self, mxc: &str) -> Result<bool> {
+        if !self.db.is_quarantined(mxc)? {
+            return Ok(false);
+        }
+
+        self.db.set_quarantined(mxc, false)?;
+        Ok(true)
+    }
+
+    pub fn is_quarantined(&self, mxc: &str) -> Result<bool> {
+        self.db.is_quarantined(mxc)
+    }
+
+    /// Quarantined media is reported as not found, this also prevents fetching it again over
+    /// federation.
+    fn check_quarantine(&self, mxc: &str) -> Result<()> {
+        if self.is_quarantined(mxc)? {
+            return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
+        }
+
+        Ok(())
+    }
+
+    /// Opens a file for streaming it to the client.
+    pub async fn open(&self, mxc: String) -> Result<Option<MediaFile>> {
+        self.check_quarantine(&mxc)?;
+
         if let Ok((content_disposition, content_type, key)) =
             self.db.search_file_metadata(mxc, 0, 0)
         {
-            let path = if cfg!(feature = "sha256_media") {
-                services().globals.get_media_file_new(&key)
-            } else {
-                #[allow(deprecated)]
-                services().globals.get_media_file(&key)
-            };
+            let file = File::open(self.file_path(&key)?).await?;
+            let len = file.metadata().await?.len();
 
-            let mut file = Vec::new();
-            BufReader::new(File::open(path).await?)
-                .read_to_end(&mut file)
-                .await?;
-
-            Ok(Some(FileMeta {
+            Ok(Some(MediaFile {
                 content_disposition,
                 content_type,
                 file,
+                len,
             }))
         } else {
             Ok(None)
         }
     }
 
+    /// Generates the thumbnails of all sizes in the background, so the first request for each of
+    /// them doesn't have to wait for the image to be resized.
+    pub fn pregenerate_thumbnails(&self, mxc: String) {
+        tokio::spawn(async move {
+            for (width, height) in THUMBNAIL_SIZES {
+                if let Err(e) = services()
+                    .media
+                    .get_thumbnail(mxc.clone(), width, height)
+                    .await
+                {
+                    warn!("Failed to generate thumbnails of {}: {}", mxc, e);
+                    return;
+                }
+            }
+        });
+    }
+
     /// Returns width, height of the thumbnail and whether it should be cropped. Returns None when
     /// the server should send the original file.
     pub fn thumbnail_properties(&self, width: u32, height: u32) -> Option<(u32, u32, bool)> {
@@ -172,6 +582,8 @@ impl Service {
         width: u32,
         height: u32,
     ) -> Result<Option<FileMeta>> {
+        self.check_quarantine(&mxc)?;
+
         let (width, height, crop) = self
             .thumbnail_properties(width, height)
             .unwrap_or((0, 0, false)); // 0, 0 because that's the original file
@@ -180,15 +592,11 @@ impl Service {
             self.db.search_file_metadata(mxc.clone(), width, height)
         {
             // Using saved thumbnail
-            let path = if cfg!(feature = "sha256_media") {
-                services().globals.get_media_file_new(&key)
-            } else {
-                #[allow(deprecated)]
-                services().globals.get_media_file(&key)
-            };
-
             let mut file = Vec::new();
-            File::open(path).await?.read_to_end(&mut file).await?;
+            File::open(self.file_path(&key)?)
+                .await?
+                .read_to_end(&mut file)
+                .await?;
 
             Ok(Some(FileMeta {
                 content_disposition,
@@ -199,15 +607,11 @@ impl Service {
             self.db.search_file_metadata(mxc.clone(), 0, 0)
         {
             // Generate a thumbnail
-            let path = if cfg!(feature = "sha256_media") {
-                services().globals.get_media_file_new(&key)
-            } else {
-                #[allow(deprecated)]
-                services().globals.get_media_file(&key)
-            };
-
             let mut file = Vec::new();
-            File::open(path).await?.read_to_end(&mut file).await?;
+            File::open(self.file_path(&key)?)
+                .await?
+                .read_to_end(&mut file)
+                .await?;
 
             if let Ok(image) = image::load_from_memory(&file) {
                 let original_width = image.width();
@@ -275,15 +679,7 @@ impl Service {
                     content_type.as_deref(),
                 )?;
 
-                let path = if cfg!(feature = "sha256_media") {
-                    services().globals.get_media_file_new(&thumbnail_key)
-                } else {
-                    #[allow(deprecated)]
-                    services().globals.get_media_file(&thumbnail_key)
-                };
-
-                let mut f = File::create(path).await?;
-                f.write_all(&thumbnail_bytes).await?;
+                self.store_file(&thumbnail_key, &thumbnail_bytes).await?;
 
                 Ok(Some(FileMeta {
                     content_disposition,
@@ -318,6 +714,96 @@ impl Service {
             .expect("valid system time");
         self.db.set_url_preview(url, data, now)
     }
+
+    /// Copies all files and thumbnails with their metadata into a directory, so they can be
+    /// imported into another media store. Returns the number of exported files.
+    pub async fn export(&self, dir: &Path) -> Result<usize> {
+        let files_dir = dir.join(EXPORT_FILES);
+        fs::create_dir_all(&files_dir).await?;
+
+        let mut index = File::create(dir.join(EXPORT_INDEX)).await?;
+        let mut count = 0;
+
+        for entry in self.db.all_file_metadata() {
+            let (key, media) = entry?;
+            let name = general_purpose::URL_SAFE_NO_PAD.encode(sha2::Sha256::digest(&key));
+
+            if let Err(e) = fs::copy(self.file_path(&key)?, files_dir.join(&name)).await {
+                warn!(
+                    "Skipping {} ({}x{}): {}",
+                    media.mxc, media.width, media.height, e
+                );
+                continue;
+            }
+
+            let mut line = serde_json::to_vec(&ExportedMedia { media, file: name })
+                .expect("media entry can be serialized");
+            line.push(b'\n');
+            index.write_all(&line).await?;
+
+            count += 1;
+        }
+
+        index.flush().await?;
+        Ok(count)
+    }
+
+    /// Imports files and thumbnails that were exported with `export`. Existing media with the same
+    /// MXC URI and dimensions is replaced. Returns the number of imported files.
+    pub async fn import(&self, dir: &Path) -> Result<usize> {
+        let files_dir = dir.join(EXPORT_FILES);
+
+        let mut lines = BufReader::new(File::open(dir.join(EXPORT_INDEX)).await?).lines();
+        let mut count = 0;
+
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let exported: ExportedMedia = match serde_json::from_str(&line) {
+                Ok(exported) => exported,
+                Err(e) => {
+                    warn!("Skipping invalid line in media index: {}", e);
+                    continue;
+                }
+            };
+            let ExportedMedia { media, file } = exported;
+
+            // Don't follow paths out of the export directory
+            if Path::new(&file).file_name() != Some(OsStr::new(&file)) {
+                warn!("Skipping {} with invalid file name {}", media.mxc, file);
+                continue;
+            }
+
+            let key = self.db.create_file_metadata(
+                media.mxc.clone(),
+                media.width,
+                media.height,
+                media.content_disposition.as_deref(),
+                media.content_type.as_deref(),
+            )?;
+
+            let content = match fs::read(files_dir.join(&file)).await {
+                Ok(content) => content,
+                Err(e) => {
+                    warn!(
+                        "Failed to import {} ({}x{}): {}",
+                        media.mxc, media.width, media.height, e
+                    );
+                    continue;
+                }
+            };
+            self.store_file(&key, &content).await?;
+
+            count += 1;
+            if count % 1000 == 0 {
+                info!("Imported {} files", count);
+            }
+        }
+
+        Ok(count)
+    }
 }
 
 #[cfg(test)]
@@ -373,6 +859,28 @@ mod tests {
             todo!()
         }
 
+        fn content_hash(&self, _key: &[u8]) -> Result<Option<Vec<u8>>> {
+            todo!()
+        }
+
+        fn set_content_hash(&self, _key: &[u8], _hash: &[u8]) -> Result<()> {
+            todo!()
+        }
+
+        fn increment_content_refcount(&self, _hash: &[u8]) -> Result<u64> {
+            todo!()
+        }
+
+        fn decrement_content_refcount(&self, _hash: &[u8]) -> Result<u64> {
+            todo!()
+        }
+
+        fn all_file_metadata<'a>(
+            &'a self,
+        ) -> Box<dyn Iterator<Item = Result<(Vec<u8>, MediaEntry)>> + 'a> {
+            todo!()
+        }
+
         fn remove_url_preview(&self, _url: &str) -> Result<()> {
             todo!()
         }
@@ -389,6 +897,25 @@ mod tests {
         fn get_url_preview(&self, _url: &str) -> Option<UrlPreviewData> {
             todo!()
         }
+
+        fn set_quarantined(&self, _mxc: &str, _quarantined: bool) -> Result<()> {
+            todo!()
+        }
+
+        fn is_quarantined(&self, _mxc: &str) -> Result<bool> {
+            todo!()
+        }
+
+        fn add_upload(&self, _user_id: &UserId, _mxc: &str, _size: u64) -> Result<()> {
+            todo!()
+        }
+
+        fn uploads_of_user<'a>(
+            &'a self,
+            _user_id: &UserId,
+        ) -> Box<dyn Iterator<Item = Result<(String, u64, u64)>> + 'a> {
+            todo!()
+        }
     }
 
     #[tokio::test]
@@ -397,6 +924,8 @@ mod tests {
         let media = Service {
             db: &DB,
             url_preview_mutex: RwLock::new(HashMap::new()),
+            content_locks: ShardedMap::new(),
+            failed_remote_fetches: std::sync::Mutex::new(LruCache::new(1)),
         };
 
         let mxc = "mxc://example.com/ascERGshawAWawugaAcauga".to_owned();
@@ -5,14 +5,18 @@ use std::{
 
 use lru_cache::LruCache;
 
-use crate::{Config, Result};
+use crate::{utils::sharded_map::ShardedMap, Config, Result};
 
 pub(crate) mod account_data;
 pub(crate) mod admin;
 pub(crate) mod appservice;
+pub(crate) mod email;
 pub(crate) mod globals;
+pub(crate) mod jwt;
 pub(crate) mod key_backups;
+pub(crate) mod login_throttle;
 pub(crate) mod media;
+pub(crate) mod oidc;
 pub(crate) mod pdu;
 pub(crate) mod pusher;
 pub(crate) mod rooms;
@@ -30,9 +34,13 @@ pub struct Services<'a> {
     pub users: users::Service,
     pub account_data: account_data::Service,
     pub admin: Arc<admin::Service>,
+    pub email: email::Service,
     pub globals: globals::Service<'a>,
+    pub jwt: jwt::Service,
     pub key_backups: key_backups::Service,
+    pub login_throttle: login_throttle::Service,
     pub media: media::Service,
+    pub oidc: oidc::Service,
     pub sending: Arc<sending::Service>,
 }
 
@@ -55,18 +63,30 @@ impl Services<'_> {
         config: Config,
     ) -> Result<Self> {
         Ok(Self {
-            appservice: appservice::Service { db },
+            appservice: appservice::Service::build(db),
             pusher: pusher::Service { db },
             rooms: rooms::Service {
                 alias: rooms::alias::Service { db },
                 auth_chain: rooms::auth_chain::Service { db },
-                directory: rooms::directory::Service { db },
+                directory: rooms::directory::Service {
+                    db,
+                    index: RwLock::new(None),
+                },
                 edus: rooms::edus::Service {
-                    presence: rooms::edus::presence::Service { db },
+                    presence: rooms::edus::presence::Service {
+                        db,
+                        incoming_ratelimiter: Mutex::new(HashMap::new()),
+                    },
                     read_receipt: rooms::edus::read_receipt::Service { db },
                     typing: rooms::edus::typing::Service { db },
                 },
-                event_handler: rooms::event_handler::Service,
+                event_handler: rooms::event_handler::Service {
+                    preverified: Mutex::new(HashMap::new()),
+                    rejected_pdus: Mutex::new(BTreeMap::new()),
+                    acl_cache: Mutex::new(LruCache::new(
+                        (100.0 * config.conduit_cache_capacity_modifier) as usize,
+                    )),
+                },
                 lazy_loading: rooms::lazy_loading::Service {
                     db,
                     lazy_load_waiting: Mutex::new(HashMap::new()),
@@ -74,6 +94,7 @@ impl Services<'_> {
                 metadata: rooms::metadata::Service { db },
                 outlier: rooms::outlier::Service { db },
                 pdu_metadata: rooms::pdu_metadata::Service { db },
+                peeking: rooms::peeking::Service { db },
                 search: rooms::search::Service { db },
                 short: rooms::short::Service { db },
                 state: rooms::state::Service { db },
@@ -110,14 +131,26 @@ impl Services<'_> {
             users: users::Service {
                 db,
                 connections: Mutex::new(BTreeMap::new()),
+                remote_profiles: Mutex::new(LruCache::new(
+                    (1000.0 * config.conduit_cache_capacity_modifier) as usize,
+                )),
+                active_local_users: Mutex::new(None),
             },
             account_data: account_data::Service { db },
             admin: admin::Service::build(),
+            email: email::Service::build(&config)?,
+            jwt: jwt::Service::build(&config)?,
             key_backups: key_backups::Service { db },
+            login_throttle: login_throttle::Service::build(),
             media: media::Service {
                 db,
                 url_preview_mutex: RwLock::new(HashMap::new()),
+                content_locks: ShardedMap::new(),
+                failed_remote_fetches: Mutex::new(LruCache::new(
+                    (100.0 * config.conduit_cache_capacity_modifier) as usize,
+                )),
             },
+            oidc: oidc::Service::build((100.0 * config.conduit_cache_capacity_modifier) as usize),
             sending: sending::Service::build(db, &config),
 
             globals: globals::Service::load(db, config)?,
@@ -166,6 +199,9 @@ impl Services<'_> {
             .lock()
             .unwrap()
             .len();
+        let acl_cache = self.rooms.event_handler.acl_cache.lock().unwrap().len();
+        let oidc_token_cache = self.oidc.token_cache.lock().unwrap().len();
+        let failed_remote_fetches = self.media.failed_remote_fetches.lock().unwrap().len();
 
         format!(
             "\
@@ -174,7 +210,10 @@ server_visibility_cache: {server_visibility_cache}
 user_visibility_cache: {user_visibility_cache}
 stateinfo_cache: {stateinfo_cache}
 lasttimelinecount_cache: {lasttimelinecount_cache}
-roomid_spacechunk_cache: {roomid_spacechunk_cache}\
+roomid_spacechunk_cache: {roomid_spacechunk_cache}
+acl_cache: {acl_cache}
+oidc_token_cache: {oidc_token_cache}
+failed_remote_fetches: {failed_remote_fetches}\
             "
         )
     }
@@ -227,5 +266,14 @@ roomid_spacechunk_cache: {roomid_spacechunk_cache}\
                 .unwrap()
                 .clear();
         }
+        if amount > 6 {
+            self.rooms.event_handler.acl_cache.lock().unwrap().clear();
+        }
+        if amount > 7 {
+            self.oidc.token_cache.lock().unwrap().clear();
+        }
+        if amount > 8 {
+            self.media.failed_remote_fetches.lock().unwrap().clear();
+        }
     }
 }
@@ -0,0 +1,290 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lru_cache::LruCache;
+use ruma::{
+    api::client::error::ErrorKind, DeviceId, OwnedDeviceId, OwnedUserId, ServerName, UserId,
+};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    api::client_server::{create_local_user, new_local_user_id, welcome_local_user, TOKEN_LENGTH},
+    services, utils, Error, Result,
+};
+
+/// Scope that grants access to the whole client-server API
+const API_SCOPE: &str = "urn:matrix:org.matrix.msc2967.client:api:*";
+
+/// Prefix of the scope that binds a token to a device
+const DEVICE_SCOPE_PREFIX: &str = "urn:matrix:org.matrix.msc2967.client:device:";
+
+/// How long an introspected token is trusted before asking the authorization server again
+const INTROSPECTION_CACHE_DURATION: Duration = Duration::from_secs(2 * 60);
+
+/// Token introspection response as defined in RFC 7662
+#[derive(Deserialize)]
+struct IntrospectionResponse {
+    active: bool,
+    scope: Option<String>,
+    sub: Option<String>,
+    username: Option<String>,
+    exp: Option<u64>,
+}
+
+pub struct CachedToken {
+    user_id: OwnedUserId,
+    device_id: OwnedDeviceId,
+    valid_until: Instant,
+}
+
+/// Delegated authentication (MSC3861): access tokens are issued by an external OAuth 2.0
+/// authorization server and validated through its introspection endpoint.
+pub struct Service {
+    pub token_cache: Mutex<LruCache<String, CachedToken>>,
+}
+
+impl Service {
+    pub fn build(capacity: usize) -> Self {
+        Self {
+            token_cache: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Returns true if authentication is delegated to an authorization server.
+    pub fn enabled(&self) -> bool {
+        services().globals.oidc_issuer().is_some()
+    }
+
+    /// Find out which user and device an access token of the authorization server belongs to.
+    /// Creates the account and device if they don't exist yet.
+    pub async fn find_from_token(&self, token: &str) -> Result<Option<(OwnedUserId, String)>> {
+        let cached = self
+            .token_cache
+            .lock()
+            .unwrap()
+            .get_mut(token)
+            .filter(|cached| cached.valid_until > Instant::now())
+            .map(|cached| (cached.user_id.clone(), cached.device_id.to_string()));
+        if let Some((user_id, device_id)) = cached {
+            // The account may have been deactivated since the token was introspected
+            check_linked_account(
+                &user_id,
+                services().globals.server_name(),
+                services().users.is_deactivated(&user_id)?,
+            )?;
+            return Ok(Some((user_id, device_id)));
+        }
+
+        let response = self.introspect(token).await?;
+        if !response.active {
+            return Ok(None);
+        }
+
+        let scopes: Vec<_> = response
+            .scope
+            .as_deref()
+            .unwrap_or_default()
+            .split(' ')
+            .collect();
+        if !scopes.contains(&API_SCOPE) {
+            return Ok(None);
+        }
+
+        let mut device_ids = scopes
+            .iter()
+            .filter_map(|scope| scope.strip_prefix(DEVICE_SCOPE_PREFIX));
+        let device_id: OwnedDeviceId = match (device_ids.next(), device_ids.next()) {
+            (Some(device_id), None) => device_id.into(),
+            _ => {
+                warn!("Token from the authorization server is not bound to exactly one device");
+                return Ok(None);
+            }
+        };
+
+        let Some(subject) = response.sub else {
+            warn!("Token introspection response has no subject");
+            return Ok(None);
+        };
+
+        let user_id = self
+            .map_account(&subject, response.username.as_deref())
+            .await?;
+
+        if !services()
+            .users
+            .all_device_ids(&user_id)
+            .filter_map(Result::ok)
+            .any(|existing| existing == device_id)
+        {
+            // The local token of the device is never used, it exists so the device is complete
+            services().users.create_device(
+                &user_id,
+                &device_id,
+                &utils::random_string(TOKEN_LENGTH),
+                None,
+            )?;
+        }
+
+        let mut valid_until = Instant::now() + INTROSPECTION_CACHE_DURATION;
+        if let Some(exp) = response.exp {
+            let expires_in = Duration::from_secs(exp)
+                .saturating_sub(Duration::from_millis(utils::millis_since_unix_epoch()));
+            valid_until = valid_until.min(Instant::now() + expires_in);
+        }
+
+        self.token_cache.lock().unwrap().insert(
+            token.to_owned(),
+            CachedToken {
+                user_id: user_id.clone(),
+                device_id: device_id.clone(),
+                valid_until,
+            },
+        );
+
+        Ok(Some((user_id, device_id.to_string())))
+    }
+
+    async fn introspect(&self, token: &str) -> Result<IntrospectionResponse> {
+        let config = &services().globals.config;
+        let endpoint = config
+            .oidc_introspection_endpoint
+            .as_deref()
+            .ok_or(Error::BadConfig("oidc_introspection_endpoint is not set."))?;
+
+        let mut request = services()
+            .globals
+            .default_client()
+            .post(endpoint)
+            .form(&[("token", token), ("token_type_hint", "access_token")]);
+        if let Some(client_id) = &config.oidc_client_id {
+            request = request.basic_auth(client_id, config.oidc_client_secret.as_ref());
+        }
+
+        let response = request.send().await.map_err(|e| {
+            warn!("Failed to reach the token introspection endpoint: {}", e);
+            Error::BadRequest(
+                ErrorKind::Unknown,
+                "Failed to reach the authorization server.",
+            )
+        })?;
+
+        if !response.status().is_success() {
+            warn!(
+                "Token introspection endpoint returned {}",
+                response.status()
+            );
+            return Err(Error::BadRequest(
+                ErrorKind::Unknown,
+                "Failed to validate access token with the authorization server.",
+            ));
+        }
+
+        serde_json::from_str(&response.text().await?).map_err(|e| {
+            warn!("Invalid token introspection response: {}", e);
+            Error::BadRequest(
+                ErrorKind::Unknown,
+                "Invalid response from the authorization server.",
+            )
+        })
+    }
+
+    /// Returns the local account for a subject of the authorization server. Subjects are only
+    /// linked to accounts they created themselves or were linked to by an admin, never to an
+    /// existing account with the same name.
+    async fn map_account(&self, subject: &str, username: Option<&str>) -> Result<OwnedUserId> {
+        if let Some(user_id) = services().users.oidc_subject_user(subject)? {
+            if let Err(e) = check_linked_account(
+                &user_id,
+                services().globals.server_name(),
+                services().users.is_deactivated(&user_id)?,
+            ) {
+                warn!("Subject {subject} of the authorization server is linked to unusable account {user_id}");
+                return Err(e);
+            }
+
+            return Ok(user_id);
+        }
+
+        // Fails if the account exists already, so the subject can't take over e.g. an admin
+        let user_id = new_local_user_id(username.unwrap_or(subject))?;
+
+        create_local_user(&user_id, None, None).await?;
+        services().users.set_oidc_subject_user(subject, &user_id)?;
+
+        info!(
+            "Created user {} for subject {} of the authorization server",
+            user_id, subject
+        );
+
+        welcome_local_user(&user_id);
+
+        Ok(user_id)
+    }
+
+    /// Drops the cached introspections of the tokens of a device, so a logged out device isn't
+    /// accepted until the cache expires.
+    pub fn forget_device(&self, user_id: &UserId, device_id: &DeviceId) {
+        let mut token_cache = self.token_cache.lock().unwrap();
+        let tokens: Vec<_> = token_cache
+            .iter()
+            .filter(|(_, cached)| cached.user_id == user_id && cached.device_id == device_id)
+            .map(|(token, _)| token.clone())
+            .collect();
+
+        for token in tokens {
+            token_cache.remove(&token);
+        }
+    }
+}
+
+/// Checks that the account linked to a subject of the authorization server may be used. The
+/// server user and deactivated accounts never get devices through the authorization server.
+fn check_linked_account(
+    user_id: &UserId,
+    server_name: &ServerName,
+    deactivated: bool,
+) -> Result<()> {
+    if user_id.localpart() == "conduit" && user_id.server_name() == server_name {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This account can't be used through the authorization server.",
+        ));
+    }
+
+    if deactivated {
+        return Err(Error::BadRequest(
+            ErrorKind::UserDeactivated,
+            "The user has been deactivated",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{server_name, user_id};
+
+    use super::*;
+
+    #[test]
+    fn rejects_deactivated_and_server_accounts() {
+        let server_name = server_name!("example.com");
+
+        assert!(check_linked_account(user_id!("@alice:example.com"), server_name, false).is_ok());
+        assert!(matches!(
+            check_linked_account(user_id!("@alice:example.com"), server_name, true),
+            Err(Error::BadRequest(ErrorKind::UserDeactivated, _))
+        ));
+        assert!(matches!(
+            check_linked_account(user_id!("@conduit:example.com"), server_name, false),
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+        assert!(
+            check_linked_account(user_id!("@conduit:other.example"), server_name, false).is_ok()
+        );
+    }
+}
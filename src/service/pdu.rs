@@ -274,6 +274,18 @@ impl PduEvent {
         serde_json::from_value(json).expect("Raw::from_value always works")
     }
 
+    /// Returns the token of the third party invite that this membership event claims, if any.
+    pub fn third_party_invite_token(&self) -> Option<String> {
+        if self.kind != TimelineEventType::RoomMember {
+            return None;
+        }
+
+        serde_json::from_str::<RoomMemberEventContent>(self.content.get())
+            .ok()?
+            .third_party_invite
+            .map(|invite| invite.signed.token)
+    }
+
     /// This does not return a full `Pdu` it is only to satisfy ruma's types.
     #[tracing::instrument]
     pub fn convert_to_outgoing_federation_event(
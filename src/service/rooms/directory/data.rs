@@ -13,4 +13,24 @@ pub trait Data: Send + Sync {
 
     /// Returns the unsorted public room directory
     fn public_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>;
+
+    /// Adds the room to the room directory of a third party network bridged by an appservice
+    fn set_public_in_network(
+        &self,
+        room_id: &RoomId,
+        appservice_id: &str,
+        network_id: &str,
+    ) -> Result<()>;
+
+    /// Removes the room from the room directory of the third party network it was published in.
+    fn set_not_public_in_network(&self, room_id: &RoomId) -> Result<()>;
+
+    /// Returns the appservice and network ID of the third party network the room is published in.
+    fn public_room_network(&self, room_id: &RoomId) -> Result<Option<(String, String)>>;
+
+    /// Returns the unsorted rooms published in third party networks, with the appservice and
+    /// network ID.
+    fn network_public_rooms<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedRoomId, String, String)>> + 'a>;
 }
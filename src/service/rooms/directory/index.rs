@@ -0,0 +1,365 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap},
+    ops::Bound,
+};
+
+use ruma::{OwnedRoomId, RoomId};
+
+/// Which room directory to list, rooms published by appservices are only listed in the directory
+/// of their network
+#[derive(Clone, Copy, Debug)]
+pub enum DirectoryNetwork<'a> {
+    /// Rooms published in the Matrix directory
+    Matrix,
+    /// Rooms published in the Matrix directory or in any third party network
+    All,
+    /// Rooms published in the network of an appservice, by appservice and network ID
+    ThirdParty(&'a str, &'a str),
+}
+
+/// What the public room directory needs to know about a published room to order and filter it
+#[derive(Clone, Debug, Default)]
+pub struct IndexedRoom {
+    /// Published in the Matrix directory
+    pub public: bool,
+    /// Published in a third party network, by appservice and network ID
+    pub network: Option<(String, String)>,
+    pub joined_members: u64,
+    /// Lowercased name, topic and canonical alias, for search terms
+    pub search_text: String,
+}
+
+impl IndexedRoom {
+    fn is_listed_in(&self, network: DirectoryNetwork<'_>) -> bool {
+        match network {
+            DirectoryNetwork::Matrix => self.public,
+            DirectoryNetwork::All => self.public || self.network.is_some(),
+            DirectoryNetwork::ThirdParty(appservice_id, network_id) => self
+                .network
+                .as_ref()
+                .map_or(false, |(a, n)| a == appservice_id && n == network_id),
+        }
+    }
+}
+
+/// A position in the directory order, between two rooms. Pages continue from it in either
+/// direction, so rooms changing their member count don't shift the following pages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirectoryCursor {
+    pub joined_members: u64,
+    pub room_id: OwnedRoomId,
+}
+
+impl DirectoryCursor {
+    fn key(&self) -> (Reverse<u64>, OwnedRoomId) {
+        (Reverse(self.joined_members), self.room_id.clone())
+    }
+}
+
+/// One page of the directory
+#[derive(Debug, Default)]
+pub struct DirectoryPage {
+    pub rooms: Vec<OwnedRoomId>,
+    /// Cursor before the first room of the page, if there are matching rooms before it
+    pub prev: Option<DirectoryCursor>,
+    /// Cursor after the last room of the page, if there are matching rooms after it
+    pub next: Option<DirectoryCursor>,
+    /// Number of rooms in the network, unknown when searching
+    pub total: Option<usize>,
+}
+
+/// Published rooms ordered by joined member count, so directory pages can be read without
+/// loading and sorting every public room.
+#[derive(Default)]
+pub struct DirectoryIndex {
+    rooms: HashMap<OwnedRoomId, IndexedRoom>,
+    sorted: BTreeSet<(Reverse<u64>, OwnedRoomId)>,
+    /// Number of rooms in the Matrix directory
+    public_count: usize,
+    /// Number of rooms in each third party network
+    network_counts: HashMap<(String, String), usize>,
+}
+
+impl DirectoryIndex {
+    pub fn get(&self, room_id: &RoomId) -> Option<&IndexedRoom> {
+        self.rooms.get(room_id)
+    }
+
+    /// Adds or replaces a room, rooms that are not published anywhere are removed.
+    pub fn insert(&mut self, room_id: OwnedRoomId, room: IndexedRoom) {
+        self.remove(&room_id);
+
+        if room.public || room.network.is_some() {
+            if room.public {
+                self.public_count += 1;
+            }
+            if let Some(network) = &room.network {
+                *self.network_counts.entry(network.clone()).or_default() += 1;
+            }
+
+            self.sorted
+                .insert((Reverse(room.joined_members), room_id.clone()));
+            self.rooms.insert(room_id, room);
+        }
+    }
+
+    pub fn remove(&mut self, room_id: &RoomId) {
+        if let Some(room) = self.rooms.remove(room_id) {
+            if room.public {
+                self.public_count -= 1;
+            }
+            if let Some(network) = room.network {
+                if let Some(count) = self.network_counts.get_mut(&network) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.network_counts.remove(&network);
+                    }
+                }
+            }
+
+            self.sorted
+                .remove(&(Reverse(room.joined_members), room_id.to_owned()));
+        }
+    }
+
+    /// Returns the number of rooms listed in the network.
+    pub fn count(&self, network: DirectoryNetwork<'_>) -> usize {
+        match network {
+            DirectoryNetwork::Matrix => self.public_count,
+            DirectoryNetwork::All => self.rooms.len(),
+            DirectoryNetwork::ThirdParty(appservice_id, network_id) => self
+                .network_counts
+                .get(&(appservice_id.to_owned(), network_id.to_owned()))
+                .copied()
+                .unwrap_or(0),
+        }
+    }
+
+    /// Returns one page of the directory, biggest rooms first. The page starts after the cursor,
+    /// or ends before it when going backwards.
+    pub fn page(
+        &self,
+        network: DirectoryNetwork<'_>,
+        search_term: Option<&str>,
+        from: Option<&DirectoryCursor>,
+        backwards: bool,
+        limit: usize,
+    ) -> DirectoryPage {
+        let search_term = search_term.map(str::to_lowercase);
+
+        let matches = |(_, room_id): &&(Reverse<u64>, OwnedRoomId)| {
+            self.rooms.get(room_id).map_or(false, |room| {
+                room.is_listed_in(network)
+                    && search_term
+                        .as_ref()
+                        .map_or(true, |term| room.search_text.contains(term.as_str()))
+            })
+        };
+        let cursor =
+            |(Reverse(joined_members), room_id): &(Reverse<u64>, OwnedRoomId)| DirectoryCursor {
+                joined_members: *joined_members,
+                room_id: room_id.clone(),
+            };
+
+        let from = from.map_or(Bound::Unbounded, |from| Bound::Excluded(from.key()));
+
+        // Reads one room more than requested to know if there is another page
+        let mut page: Vec<_> = if backwards {
+            self.sorted
+                .range((Bound::Unbounded, from))
+                .rev()
+                .filter(&matches)
+                .take(limit + 1)
+                .collect()
+        } else {
+            self.sorted
+                .range((from, Bound::Unbounded))
+                .filter(&matches)
+                .take(limit + 1)
+                .collect()
+        };
+        let more = page.len() > limit;
+        page.truncate(limit);
+        if backwards {
+            page.reverse();
+        }
+
+        let (prev, next) = match (page.first(), page.last()) {
+            (Some(&first), Some(&last)) => {
+                let (more_before, more_after) = if backwards {
+                    (more, self.matches_after(last, &matches))
+                } else {
+                    (self.matches_before(first, &matches), more)
+                };
+                (
+                    more_before.then(|| cursor(first)),
+                    more_after.then(|| cursor(last)),
+                )
+            }
+            _ => (None, None),
+        };
+
+        DirectoryPage {
+            rooms: page
+                .into_iter()
+                .map(|(_, room_id)| room_id.clone())
+                .collect(),
+            prev,
+            next,
+            total: search_term.is_none().then(|| self.count(network)),
+        }
+    }
+
+    fn matches_before(
+        &self,
+        key: &(Reverse<u64>, OwnedRoomId),
+        mut matches: impl FnMut(&&(Reverse<u64>, OwnedRoomId)) -> bool,
+    ) -> bool {
+        self.sorted
+            .range((Bound::Unbounded, Bound::Excluded(key)))
+            .rev()
+            .any(|entry| matches(&entry))
+    }
+
+    fn matches_after(
+        &self,
+        key: &(Reverse<u64>, OwnedRoomId),
+        mut matches: impl FnMut(&&(Reverse<u64>, OwnedRoomId)) -> bool,
+    ) -> bool {
+        self.sorted
+            .range((Bound::Excluded(key), Bound::Unbounded))
+            .any(|entry| matches(&entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(public: bool, network: Option<(&str, &str)>, joined_members: u64) -> IndexedRoom {
+        IndexedRoom {
+            public,
+            network: network.map(|(a, n)| (a.to_owned(), n.to_owned())),
+            joined_members,
+            search_text: String::new(),
+        }
+    }
+
+    fn room_id(n: u64) -> OwnedRoomId {
+        RoomId::parse(format!("!room{n}:example.org")).unwrap()
+    }
+
+    /// Public rooms 1 to 5 with 1 to 5 members
+    fn index() -> DirectoryIndex {
+        let mut index = DirectoryIndex::default();
+        for n in 1..=5 {
+            index.insert(room_id(n), room(true, None, n));
+        }
+        index
+    }
+
+    #[test]
+    fn pages_forwards_and_backwards() {
+        let index = index();
+
+        let first = index.page(DirectoryNetwork::Matrix, None, None, false, 2);
+        assert_eq!(first.rooms, vec![room_id(5), room_id(4)]);
+        assert_eq!(first.prev, None);
+        assert_eq!(first.total, Some(5));
+
+        let second = index.page(
+            DirectoryNetwork::Matrix,
+            None,
+            first.next.as_ref(),
+            false,
+            2,
+        );
+        assert_eq!(second.rooms, vec![room_id(3), room_id(2)]);
+
+        let last = index.page(
+            DirectoryNetwork::Matrix,
+            None,
+            second.next.as_ref(),
+            false,
+            2,
+        );
+        assert_eq!(last.rooms, vec![room_id(1)]);
+        assert_eq!(last.next, None);
+
+        let back = index.page(DirectoryNetwork::Matrix, None, last.prev.as_ref(), true, 2);
+        assert_eq!(back.rooms, second.rooms);
+        assert_eq!(back.next, second.next);
+        assert_eq!(back.prev, second.prev);
+    }
+
+    #[test]
+    fn cursor_survives_member_count_changes() {
+        let mut index = index();
+
+        let first = index.page(DirectoryNetwork::Matrix, None, None, false, 2);
+        // A room of the next page grows past the cursor, the room after it is not skipped
+        index.insert(room_id(3), room(true, None, 10));
+
+        let second = index.page(
+            DirectoryNetwork::Matrix,
+            None,
+            first.next.as_ref(),
+            false,
+            2,
+        );
+        assert_eq!(second.rooms, vec![room_id(2), room_id(1)]);
+    }
+
+    #[test]
+    fn counts_per_network() {
+        let mut index = index();
+        index.insert(room_id(6), room(false, Some(("bridge", "irc")), 6));
+        index.insert(room_id(7), room(true, Some(("bridge", "irc")), 7));
+        index.insert(room_id(8), room(false, Some(("bridge", "xmpp")), 8));
+
+        assert_eq!(index.count(DirectoryNetwork::Matrix), 6);
+        assert_eq!(index.count(DirectoryNetwork::All), 8);
+        assert_eq!(
+            index.count(DirectoryNetwork::ThirdParty("bridge", "irc")),
+            2
+        );
+
+        index.insert(room_id(7), room(false, None, 7));
+        index.remove(&room_id(8));
+
+        assert_eq!(index.count(DirectoryNetwork::Matrix), 5);
+        assert_eq!(index.count(DirectoryNetwork::All), 6);
+        assert_eq!(
+            index.count(DirectoryNetwork::ThirdParty("bridge", "irc")),
+            1
+        );
+        assert_eq!(
+            index.count(DirectoryNetwork::ThirdParty("bridge", "xmpp")),
+            0
+        );
+
+        let page = index.page(
+            DirectoryNetwork::ThirdParty("bridge", "irc"),
+            None,
+            None,
+            false,
+            10,
+        );
+        assert_eq!(page.rooms, vec![room_id(6)]);
+        assert_eq!((page.prev, page.next), (None, None));
+    }
+
+    #[test]
+    fn searches_without_total() {
+        let mut index = index();
+        let mut matrix_hq = room(true, None, 3);
+        matrix_hq.search_text = "matrix hq".to_owned();
+        index.insert(room_id(3), matrix_hq);
+
+        let page = index.page(DirectoryNetwork::Matrix, Some("Matrix"), None, false, 10);
+        assert_eq!(page.rooms, vec![room_id(3)]);
+        assert_eq!(page.total, None);
+        assert_eq!((page.prev, page.next), (None, None));
+    }
+}
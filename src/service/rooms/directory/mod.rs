@@ -1,23 +1,37 @@
 mod data;
+mod index;
+
+use std::sync::RwLock;
 
 pub use data::Data;
-use ruma::{OwnedRoomId, RoomId};
+pub use index::{DirectoryCursor, DirectoryIndex, DirectoryNetwork, DirectoryPage, IndexedRoom};
+use ruma::{
+    events::{
+        room::{canonical_alias::RoomCanonicalAliasEventContent, topic::RoomTopicEventContent},
+        StateEventType,
+    },
+    OwnedRoomId, RoomId,
+};
 
-use crate::Result;
+use crate::{services, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
+    /// Published rooms ordered for the public room directory, loaded on first use
+    pub index: RwLock<Option<DirectoryIndex>>,
 }
 
 impl Service {
     #[tracing::instrument(skip(self))]
     pub fn set_public(&self, room_id: &RoomId) -> Result<()> {
-        self.db.set_public(room_id)
+        self.db.set_public(room_id)?;
+        self.update_index(room_id)
     }
 
     #[tracing::instrument(skip(self))]
     pub fn set_not_public(&self, room_id: &RoomId) -> Result<()> {
-        self.db.set_not_public(room_id)
+        self.db.set_not_public(room_id)?;
+        self.update_index(room_id)
     }
 
     #[tracing::instrument(skip(self))]
@@ -29,4 +43,176 @@ impl Service {
     pub fn public_rooms(&self) -> impl Iterator<Item = Result<OwnedRoomId>> + '_ {
         self.db.public_rooms()
     }
+
+    #[tracing::instrument(skip(self))]
+    pub fn set_public_in_network(
+        &self,
+        room_id: &RoomId,
+        appservice_id: &str,
+        network_id: &str,
+    ) -> Result<()> {
+        self.db
+            .set_public_in_network(room_id, appservice_id, network_id)?;
+        self.update_index(room_id)
+    }
+
+    /// Returns the appservice and network ID of the third party network the room is published in.
+    #[tracing::instrument(skip(self))]
+    pub fn public_room_network(&self, room_id: &RoomId) -> Result<Option<(String, String)>> {
+        self.db.public_room_network(room_id)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn set_not_public_in_network(&self, room_id: &RoomId) -> Result<()> {
+        self.db.set_not_public_in_network(room_id)?;
+        self.update_index(room_id)
+    }
+
+    /// Returns the rooms published in the network of an appservice, or in all third party
+    /// networks if no network is given.
+    #[tracing::instrument(skip(self))]
+    pub fn network_public_rooms<'a>(
+        &'a self,
+        network: Option<(&'a str, &'a str)>,
+    ) -> impl Iterator<Item = Result<OwnedRoomId>> + 'a {
+        self.db
+            .network_public_rooms()
+            .filter_map(move |entry| match entry {
+                Ok((room_id, appservice_id, network_id)) => {
+                    let in_network = network.map_or(true, |(wanted_appservice, wanted_network)| {
+                        wanted_appservice == appservice_id && wanted_network == network_id
+                    });
+                    in_network.then_some(Ok(room_id))
+                }
+                Err(e) => Some(Err(e)),
+            })
+    }
+
+    /// Returns one page of the public room directory, biggest rooms first, starting after the
+    /// cursor or ending before it when going backwards.
+    #[tracing::instrument(skip(self))]
+    pub fn public_rooms_page(
+        &self,
+        network: DirectoryNetwork<'_>,
+        search_term: Option<&str>,
+        from: Option<&DirectoryCursor>,
+        backwards: bool,
+        limit: usize,
+    ) -> Result<DirectoryPage> {
+        if let Some(index) = self.index.read().unwrap().as_ref() {
+            return Ok(index.page(network, search_term, from, backwards, limit));
+        }
+
+        let mut index = self.index.write().unwrap();
+        if index.is_none() {
+            *index = Some(self.load_index()?);
+        }
+
+        Ok(index.as_ref().expect("index was just loaded").page(
+            network,
+            search_term,
+            from,
+            backwards,
+            limit,
+        ))
+    }
+
+    /// Refreshes the member count and search terms of a published room after its state changed.
+    #[tracing::instrument(skip(self))]
+    pub fn update_room(&self, room_id: &RoomId) -> Result<()> {
+        let mut index = self.index.write().unwrap();
+
+        if let Some(index) = index.as_mut() {
+            if index.get(room_id).is_some() {
+                index.insert(room_id.to_owned(), self.indexed_room(room_id)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reindexes the room from the database, if the index was loaded already. The lock is held
+    /// while reading the room, so concurrent updates can't overwrite it with older state.
+    fn update_index(&self, room_id: &RoomId) -> Result<()> {
+        let mut index = self.index.write().unwrap();
+
+        if let Some(index) = index.as_mut() {
+            index.insert(room_id.to_owned(), self.indexed_room(room_id)?);
+        }
+
+        Ok(())
+    }
+
+    fn load_index(&self) -> Result<DirectoryIndex> {
+        let mut index = DirectoryIndex::default();
+
+        for room_id in self.db.public_rooms() {
+            let room_id = room_id?;
+            let room = self.indexed_room(&room_id)?;
+            index.insert(room_id, room);
+        }
+
+        for entry in self.db.network_public_rooms() {
+            let (room_id, _, _) = entry?;
+            if index.get(&room_id).is_none() {
+                let room = self.indexed_room(&room_id)?;
+                index.insert(room_id, room);
+            }
+        }
+
+        Ok(index)
+    }
+
+    fn indexed_room(&self, room_id: &RoomId) -> Result<IndexedRoom> {
+        let public = self.db.is_public_room(room_id)?;
+        let network = self.db.public_room_network(room_id)?;
+
+        if !public && network.is_none() {
+            return Ok(IndexedRoom::default());
+        }
+
+        let joined_members = services()
+            .rooms
+            .state_cache
+            .room_joined_count(room_id)?
+            .unwrap_or(0);
+
+        let name = services().rooms.state_accessor.get_name(room_id)?;
+        let topic = room_state_content::<RoomTopicEventContent>(room_id, StateEventType::RoomTopic)
+            .map(|c| c.topic);
+        let canonical_alias = room_state_content::<RoomCanonicalAliasEventContent>(
+            room_id,
+            StateEventType::RoomCanonicalAlias,
+        )
+        .and_then(|c| c.alias)
+        .map(|alias| alias.to_string());
+
+        let search_text = [name, topic, canonical_alias]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join("\n")
+            .to_lowercase();
+
+        Ok(IndexedRoom {
+            public,
+            network,
+            joined_members,
+            search_text,
+        })
+    }
+}
+
+/// Invalid state events only leave the room out of search results for that term.
+fn room_state_content<T: serde::de::DeserializeOwned>(
+    room_id: &RoomId,
+    event_type: StateEventType,
+) -> Option<T> {
+    services()
+        .rooms
+        .state_accessor
+        .room_state_get(room_id, &event_type, "")
+        .ok()
+        .flatten()
+        .and_then(|pdu| serde_json::from_str(pdu.content.get()).ok())
 }
@@ -1,13 +1,18 @@
 mod data;
 
-use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
 pub use data::Data;
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use ruma::{
+    api::federation::transactions::edu::PresenceUpdate,
     events::presence::{PresenceEvent, PresenceEventContent},
     presence::PresenceState,
-    OwnedUserId, RoomId, UInt, UserId,
+    OwnedServerName, OwnedUserId, RoomId, ServerName, UInt, UserId,
 };
 use serde::{Deserialize, Serialize};
 use tokio::{sync::mpsc, time::sleep};
@@ -77,8 +82,15 @@ impl Presence {
     }
 }
 
+/// Window for rate limiting incoming federated presence updates
+const INCOMING_RATELIMIT_WINDOW: Duration = Duration::from_secs(60);
+
 pub struct Service {
     pub db: &'static dyn Data,
+
+    /// Start of the current rate limit window and the number of presence updates accepted in it,
+    /// per origin server
+    pub incoming_ratelimiter: Mutex<HashMap<OwnedServerName, (Instant, usize)>>,
 }
 
 impl Service {
@@ -121,6 +133,71 @@ impl Service {
         self.db.remove_presence(user_id)
     }
 
+    /// Applies presence updates received over federation from `origin`.
+    ///
+    /// - Updates for users that don't belong to `origin` are ignored
+    /// - Only the last update for each user in the EDU is applied
+    /// - At most `incoming_presence_max_updates_per_minute` updates are applied per origin, the
+    /// oldest updates of an EDU are dropped first
+    pub fn handle_incoming_presence(
+        &self,
+        origin: &ServerName,
+        updates: Vec<PresenceUpdate>,
+    ) -> Result<()> {
+        // Later updates in the EDU are newer, so walk it backwards and keep the first per user
+        let mut seen = HashSet::new();
+        let mut updates: Vec<_> = updates
+            .into_iter()
+            .rev()
+            .filter(|update| update.user_id.server_name() == origin)
+            .filter(|update| seen.insert(update.user_id.clone()))
+            .collect();
+
+        let allowed = {
+            let mut ratelimiter = self.incoming_ratelimiter.lock().unwrap();
+            let (window_start, count) = ratelimiter
+                .entry(origin.to_owned())
+                .or_insert((Instant::now(), 0));
+
+            if window_start.elapsed() >= INCOMING_RATELIMIT_WINDOW {
+                *window_start = Instant::now();
+                *count = 0;
+            }
+
+            let allowed = services()
+                .globals
+                .incoming_presence_max_updates_per_minute()
+                .saturating_sub(*count)
+                .min(updates.len());
+            *count += allowed;
+
+            allowed
+        };
+
+        if allowed < updates.len() {
+            debug!(
+                "Dropping {} presence updates from {origin} because of rate limiting",
+                updates.len() - allowed
+            );
+            updates.truncate(allowed);
+        }
+
+        for update in updates.into_iter().rev() {
+            for room_id in services().rooms.state_cache.rooms_joined(&update.user_id) {
+                self.set_presence(
+                    &room_id?,
+                    &update.user_id,
+                    update.presence.clone(),
+                    Some(update.currently_active),
+                    Some(update.last_active_ago),
+                    update.status_msg.clone(),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Returns the most recent presence updates that happened after the event with id `since`.
     pub fn presence_since(
         &self,
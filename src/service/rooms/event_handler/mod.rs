@@ -1,28 +1,29 @@
 /// An async function that can recursively call itself.
 type AsyncRecursiveType<'a, T> = Pin<Box<dyn Future<Output = T> + 'a + Send>>;
 
+use lru_cache::LruCache;
+use regex::RegexSet;
 use ruma::{
     api::federation::discovery::{get_remote_server_keys, get_server_keys},
-    CanonicalJsonObject, CanonicalJsonValue, OwnedServerName, OwnedServerSigningKeyId,
-    RoomVersionId,
+    signatures::Verified,
+    CanonicalJsonObject, CanonicalJsonValue, OwnedEventId, OwnedServerName,
+    OwnedServerSigningKeyId, RoomVersionId,
 };
 use std::{
     collections::{hash_map, BTreeMap, HashMap, HashSet},
+    fmt::Write,
     pin::Pin,
-    sync::{Arc, RwLock, RwLockWriteGuard},
+    sync::{Arc, Mutex, RwLock, RwLockWriteGuard},
     time::{Duration, Instant, SystemTime},
 };
 use tokio::sync::Semaphore;
 
 use futures_util::{stream::FuturesUnordered, Future, StreamExt};
 use ruma::{
-    api::{
-        client::error::ErrorKind,
-        federation::{
-            discovery::get_remote_server_keys_batch::{self, v2::QueryCriteria},
-            event::{get_event, get_room_state_ids},
-            membership::create_join_event,
-        },
+    api::federation::{
+        discovery::get_remote_server_keys_batch::{self, v2::QueryCriteria},
+        event::{get_event, get_room_state_ids},
+        membership::create_join_event,
     },
     events::{
         room::{create::RoomCreateEventContent, server_acl::RoomServerAclEventContent},
@@ -31,23 +32,127 @@ use ruma::{
     int,
     serde::Base64,
     state_res::{self, RoomVersion, StateMap},
-    uint, EventId, MilliSecondsSinceUnixEpoch, RoomId, ServerName,
+    uint, EventId, MilliSecondsSinceUnixEpoch, RoomId, ServerName, UInt,
 };
 use serde_json::value::RawValue as RawJsonValue;
 use tracing::{debug, error, info, trace, warn};
 
-use crate::{service::*, services, Error, PduEvent, Result};
-
-use super::state_compressor::CompressedStateEvent;
+use crate::{service::*, services, Error, PduEvent, PduRejection, Result};
 
 type AsyncRecursiveCanonicalJsonVec<'a> =
     AsyncRecursiveType<'a, Vec<(Arc<PduEvent>, Option<BTreeMap<String, CanonicalJsonValue>>)>>;
 type AsyncRecursiveCanonicalJsonResult<'a> =
     AsyncRecursiveType<'a, Result<(Arc<PduEvent>, BTreeMap<String, CanonicalJsonValue>)>>;
 
-pub struct Service;
+type PubKeyMap = BTreeMap<String, BTreeMap<String, Base64>>;
+
+pub struct Service {
+    /// Signature check results of PDUs verified ahead of time by `verify_pdus`, together with the
+    /// event they were calculated for
+    pub preverified: Mutex<HashMap<OwnedEventId, (CanonicalJsonObject, Option<Verified>)>>,
+
+    /// Number of PDUs received in transactions that were not accepted since startup, by reason
+    pub rejected_pdus: Mutex<BTreeMap<&'static str, u64>>,
+
+    /// Server ACLs by the shortstatehash of the room state they are from
+    pub acl_cache: Mutex<LruCache<u64, Option<Arc<CompiledAcl>>>>,
+}
+
+/// Outcome of `Service::reprocess_outliers`
+#[derive(Default)]
+pub struct ReprocessedOutliers {
+    /// Events that were added to the timeline
+    pub accepted: Vec<OwnedEventId>,
+    /// Events that passed auth but failed it against the current state of the room
+    pub soft_failed: usize,
+    /// Events that are still missing prev or auth events
+    pub waiting: usize,
+    /// Events that were rejected, with the reason
+    pub failed: Vec<(OwnedEventId, String)>,
+}
+
+/// Outcome of `Service::repair_state`
+pub struct RepairedState {
+    pub previous_shortstatehash: Option<u64>,
+    pub shortstatehash: u64,
+    /// Number of states that were resolved
+    pub forks: usize,
+    /// State entries that were added or point to another event now
+    pub changed: usize,
+    /// State entries that were removed
+    pub removed: usize,
+}
+
+/// A room's `m.room.server_acl` with its server name globs compiled
+pub struct CompiledAcl {
+    pub content: RoomServerAclEventContent,
+    allow: RegexSet,
+    deny: RegexSet,
+}
+
+impl CompiledAcl {
+    fn new(content: RoomServerAclEventContent) -> Result<Self, regex::Error> {
+        let compile =
+            |globs: &[String]| RegexSet::new(globs.iter().map(|glob| glob_to_regex(glob)));
+
+        Ok(Self {
+            allow: compile(&content.allow)?,
+            deny: compile(&content.deny)?,
+            content,
+        })
+    }
+
+    /// Same as `RoomServerAclEventContent::is_allowed`, but without parsing the globs every time
+    pub fn is_allowed(&self, server_name: &ServerName) -> bool {
+        if !self.content.allow_ip_literals && server_name.is_ip_literal() {
+            return false;
+        }
+
+        let host = server_name.host();
+        !self.deny.is_match(host) && self.allow.is_match(host)
+    }
+}
+
+/// Converts a server ACL glob, where `*` matches any characters and `?` a single character, to an
+/// anchored regex.
+fn glob_to_regex(glob: &str) -> String {
+    format!(
+        "^{}$",
+        regex::escape(glob).replace(r"\*", ".*").replace(r"\?", ".")
+    )
+}
 
 impl Service {
+    /// Counts a PDU from a transaction that failed, `None` meaning an unexpected error.
+    pub fn count_rejected_pdu(&self, reason: Option<PduRejection>) {
+        *self
+            .rejected_pdus
+            .lock()
+            .unwrap()
+            .entry(reason.map_or("other", |reason| reason.as_str()))
+            .or_default() += 1;
+    }
+
+    /// Returns the number of rejected PDUs by reason in the Prometheus text format.
+    pub fn render_rejected_pdus(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP conduwuit_federation_rejected_pdus_total PDUs from transactions that failed \
+             to be handled, by reason\n",
+        );
+        out.push_str("# TYPE conduwuit_federation_rejected_pdus_total counter\n");
+        for (reason, count) in self.rejected_pdus.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "conduwuit_federation_rejected_pdus_total{{reason=\"{reason}\"}} {count}"
+            )
+            .unwrap();
+        }
+
+        out
+    }
+
     /// When receiving an event one needs to:
     /// 0. Check the server is in the room
     /// 1. Skip the PDU if we already know about it
@@ -83,16 +188,16 @@ impl Service {
     ) -> Result<Option<Vec<u8>>> {
         // 0. Check the server is in the room
         if !services().rooms.metadata.exists(room_id)? {
-            return Err(Error::BadRequest(
-                ErrorKind::NotFound,
+            return Err(Error::RejectedPdu(
+                PduRejection::UnknownRoom,
                 "Room is unknown to this server",
             ));
         }
 
         if services().rooms.metadata.is_disabled(room_id)? {
             info!("Federaton of room {room_id} is currently disabled on this server. Request by origin {origin} and event ID {event_id}");
-            return Err(Error::BadRequest(
-                ErrorKind::Forbidden,
+            return Err(Error::RejectedPdu(
+                PduRejection::RoomDisabled,
                 "Federation of this room is currently disabled on this server.",
             ));
         }
@@ -164,21 +269,15 @@ impl Service {
             // Check for disabled again because it might have changed
             if services().rooms.metadata.is_disabled(room_id)? {
                 info!("Federaton of room {room_id} is currently disabled on this server. Request by origin {origin} and event ID {event_id}");
-                return Err(Error::BadRequest(
-                    ErrorKind::Forbidden,
+                return Err(Error::RejectedPdu(
+                    PduRejection::RoomDisabled,
                     "Federation of this room is currently disabled on this server.",
                 ));
             }
 
-            if let Some((time, tries)) = services()
-                .globals
-                .bad_event_ratelimiter
-                .read()
-                .unwrap()
-                .get(&*prev_id)
-            {
+            if let Some((time, tries)) = services().globals.bad_event_ratelimiter.get(&*prev_id) {
                 // Exponential backoff
-                let mut min_elapsed_duration = Duration::from_secs(5 * 60) * (*tries) * (*tries);
+                let mut min_elapsed_duration = Duration::from_secs(5 * 60) * tries * tries;
                 if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
                     min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
                 }
@@ -203,8 +302,6 @@ impl Service {
                 services()
                     .globals
                     .roomid_federationhandletime
-                    .write()
-                    .unwrap()
                     .insert(room_id.to_owned(), ((*prev_id).to_owned(), start_time));
 
                 if let Err(e) = self
@@ -220,28 +317,23 @@ impl Service {
                 {
                     errors += 1;
                     warn!("Prev event {} failed: {}", prev_id, e);
-                    match services()
-                        .globals
-                        .bad_event_ratelimiter
-                        .write()
-                        .unwrap()
-                        .entry((*prev_id).to_owned())
-                    {
-                        hash_map::Entry::Vacant(e) => {
-                            e.insert((Instant::now(), 1));
-                        }
-                        hash_map::Entry::Occupied(mut e) => {
-                            *e.get_mut() = (Instant::now(), e.get().1 + 1)
-                        }
-                    }
+                    services().globals.bad_event_ratelimiter.with_entry(
+                        (*prev_id).to_owned(),
+                        |entry| match entry {
+                            hash_map::Entry::Vacant(e) => {
+                                e.insert((Instant::now(), 1));
+                            }
+                            hash_map::Entry::Occupied(mut e) => {
+                                *e.get_mut() = (Instant::now(), e.get().1 + 1)
+                            }
+                        },
+                    )
                 }
                 let elapsed = start_time.elapsed();
                 services()
                     .globals
                     .roomid_federationhandletime
-                    .write()
-                    .unwrap()
-                    .remove(&room_id.to_owned());
+                    .remove(room_id);
                 debug!(
                     "Handling prev event {} took {}m{}s",
                     prev_id,
@@ -257,8 +349,6 @@ impl Service {
         services()
             .globals
             .roomid_federationhandletime
-            .write()
-            .unwrap()
             .insert(room_id.to_owned(), (event_id.to_owned(), start_time));
         let r = services()
             .rooms
@@ -275,13 +365,278 @@ impl Service {
         services()
             .globals
             .roomid_federationhandletime
-            .write()
-            .unwrap()
-            .remove(&room_id.to_owned());
+            .remove(room_id);
 
         r
     }
 
+    /// Tries again to add the outliers of a room to the timeline whose prev events are in the
+    /// timeline and whose auth events are known by now. Outliers are handled from the lowest depth
+    /// up, so events that only waited for other outliers are accepted too. Soft failed and old
+    /// events are left alone.
+    pub async fn reprocess_outliers(&self, room_id: &RoomId) -> Result<ReprocessedOutliers> {
+        let create_event = services()
+            .rooms
+            .state_accessor
+            .room_state_get(room_id, &StateEventType::RoomCreate, "")?
+            .ok_or_else(|| Error::bad_database("Failed to find create event in db."))?;
+
+        let first_pdu_in_room = services()
+            .rooms
+            .timeline
+            .first_pdu_in_room(room_id)?
+            .ok_or_else(|| Error::bad_database("Failed to find first pdu in db."))?;
+
+        let mut outliers = Vec::new();
+        for r in services().rooms.outlier.room_outlier_pdus(room_id) {
+            let (pdu, json) = r?;
+            if pdu.origin_server_ts >= first_pdu_in_room.origin_server_ts
+                && !services()
+                    .rooms
+                    .pdu_metadata
+                    .is_event_soft_failed(&pdu.event_id)?
+            {
+                outliers.push((pdu, json));
+            }
+        }
+        outliers.sort_by_key(|(pdu, _)| (pdu.depth, pdu.origin_server_ts));
+
+        let mut result = ReprocessedOutliers::default();
+        for (pdu, json) in outliers {
+            let mut prev_events_known = true;
+            for prev_event in &pdu.prev_events {
+                prev_events_known &= services().rooms.timeline.get_pdu_id(prev_event)?.is_some();
+            }
+            let mut auth_events_known = true;
+            for auth_event in &pdu.auth_events {
+                auth_events_known &= services()
+                    .rooms
+                    .timeline
+                    .get_pdu_json(auth_event)?
+                    .is_some();
+            }
+
+            if !prev_events_known || !auth_events_known {
+                result.waiting += 1;
+                continue;
+            }
+
+            let pub_key_map = RwLock::new(BTreeMap::new());
+            let event_id = pdu.event_id.clone();
+            let origin = pdu.sender.server_name().to_owned();
+
+            let keys = self
+                .fetch_required_signing_keys([&json], &pub_key_map)
+                .await;
+            let r = match keys {
+                Ok(()) => {
+                    let mutex = services().globals.roomid_mutex_federation.mutex(room_id);
+                    let mutex_lock = mutex.lock().await;
+                    let r = self
+                        .upgrade_outlier_to_timeline_pdu(
+                            Arc::new(pdu),
+                            json,
+                            &create_event,
+                            &origin,
+                            room_id,
+                            &pub_key_map,
+                        )
+                        .await;
+                    drop(mutex_lock);
+                    r
+                }
+                Err(e) => Err(e),
+            };
+
+            match r {
+                Ok(_) => result.accepted.push((*event_id).to_owned()),
+                Err(Error::RejectedPdu(PduRejection::SoftFailed, _)) => result.soft_failed += 1,
+                Err(e) => {
+                    warn!("Reprocessing outlier {event_id} failed: {e}");
+                    result.failed.push(((*event_id).to_owned(), e.to_string()));
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Recalculates the current state of a room by resolving the state after each forward
+    /// extremity, and the state the server returns for the latest one if given, and replaces the
+    /// stored room state with the result.
+    pub async fn repair_state(
+        &self,
+        room_id: &RoomId,
+        from: Option<&ServerName>,
+    ) -> Result<RepairedState> {
+        let create_event = services()
+            .rooms
+            .state_accessor
+            .room_state_get(room_id, &StateEventType::RoomCreate, "")?
+            .ok_or_else(|| Error::bad_database("Failed to find create event in db."))?;
+        let room_version_id = services().rooms.state.get_room_version(room_id)?;
+
+        let mut fork_states = Vec::new();
+
+        if let Some(server) = from {
+            let latest = services()
+                .rooms
+                .state
+                .get_forward_extremities(room_id)?
+                .iter()
+                .filter_map(|id| services().rooms.timeline.get_pdu(id).ok().flatten())
+                .max_by_key(|pdu| pdu.depth)
+                .ok_or_else(|| Error::bad_database("Room has no known forward extremities."))?;
+
+            let res = services()
+                .sending
+                .send_federation_request(
+                    server,
+                    get_room_state_ids::v1::Request {
+                        room_id: room_id.to_owned(),
+                        event_id: (*latest.event_id).to_owned(),
+                    },
+                )
+                .await?;
+
+            let pub_key_map = RwLock::new(BTreeMap::new());
+            let state_vec = self
+                .fetch_and_handle_outliers(
+                    server,
+                    &res.pdu_ids
+                        .iter()
+                        .map(|x| Arc::from(&**x))
+                        .collect::<Vec<_>>(),
+                    &create_event,
+                    room_id,
+                    &room_version_id,
+                    &pub_key_map,
+                )
+                .await;
+
+            let mut state = HashMap::new();
+            // This is the state before the event, so the event itself is added too
+            for pdu in state_vec.into_iter().map(|(pdu, _)| pdu).chain([latest]) {
+                if let Some(state_key) = &pdu.state_key {
+                    let shortstatekey = services()
+                        .rooms
+                        .short
+                        .get_or_create_shortstatekey(&pdu.kind.to_string().into(), state_key)?;
+                    state.insert(shortstatekey, pdu.event_id.clone());
+                }
+            }
+            fork_states.push(state);
+        }
+
+        let mutex_state = services().globals.roomid_mutex_state.mutex(room_id);
+        let state_lock = mutex_state.lock().await;
+
+        for extremity in services().rooms.state.get_forward_extremities(room_id)? {
+            let (Some(pdu), Some(shortstatehash)) = (
+                services().rooms.timeline.get_pdu(&extremity)?,
+                services()
+                    .rooms
+                    .state_accessor
+                    .pdu_shortstatehash(&extremity)?,
+            ) else {
+                warn!("Skipping forward extremity {extremity} without known state");
+                continue;
+            };
+
+            let mut state = services()
+                .rooms
+                .state_accessor
+                .state_full_ids(shortstatehash)
+                .await?;
+            if let Some(state_key) = &pdu.state_key {
+                let shortstatekey = services()
+                    .rooms
+                    .short
+                    .get_or_create_shortstatekey(&pdu.kind.to_string().into(), state_key)?;
+                state.insert(shortstatekey, extremity);
+            }
+            fork_states.push(state);
+        }
+
+        if fork_states.is_empty() {
+            return Err(Error::bad_database(
+                "The state at the forward extremities of the room is unknown.",
+            ));
+        }
+
+        let forks = fork_states.len();
+        let new_state = self
+            .resolve_state(room_id, &room_version_id, fork_states)
+            .await?;
+
+        let create_shortstatekey = services()
+            .rooms
+            .short
+            .get_shortstatekey(&StateEventType::RoomCreate, "")?
+            .ok_or_else(|| Error::bad_database("The create event has no short state key."))?;
+        if new_state.get(&create_shortstatekey) != Some(&create_event.event_id) {
+            return Err(Error::bad_database(
+                "The resolved state doesn't contain the create event of the room.",
+            ));
+        }
+
+        let previous_shortstatehash = services().rooms.state.get_room_shortstatehash(room_id)?;
+        let previous_state = match previous_shortstatehash {
+            Some(shortstatehash) => {
+                services()
+                    .rooms
+                    .state_accessor
+                    .state_full_ids(shortstatehash)
+                    .await?
+            }
+            None => HashMap::new(),
+        };
+        let changed = new_state
+            .iter()
+            .filter(|(k, id)| previous_state.get(*k) != Some(*id))
+            .count();
+        let removed = previous_state
+            .keys()
+            .filter(|k| !new_state.contains_key(*k))
+            .count();
+
+        let new_state_compressed = Arc::new(
+            new_state
+                .iter()
+                .map(|(shortstatekey, id)| {
+                    services()
+                        .rooms
+                        .state_compressor
+                        .compress_state_event(*shortstatekey, id)
+                })
+                .collect::<Result<_>>()?,
+        );
+        let (shortstatehash, new, removed_compressed) = services()
+            .rooms
+            .state_compressor
+            .save_state(room_id, new_state_compressed)?;
+        services()
+            .rooms
+            .state
+            .force_state(
+                room_id,
+                shortstatehash,
+                new,
+                removed_compressed,
+                &state_lock,
+            )
+            .await?;
+        drop(state_lock);
+
+        Ok(RepairedState {
+            previous_shortstatehash,
+            shortstatehash,
+            forks,
+            changed,
+            removed,
+        })
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn handle_outlier_pdu<'a>(
         &'a self,
@@ -311,27 +666,45 @@ impl Service {
             let room_version =
                 RoomVersion::new(room_version_id).expect("room version is supported");
 
-            let mut val = match ruma::signatures::verify_event(
-                &pub_key_map.read().expect("RwLock is poisoned."),
-                &value,
-                room_version_id,
-            ) {
-                Err(e) => {
+            let preverified = self
+                .preverified
+                .lock()
+                .expect("locked")
+                .remove(event_id)
+                .filter(|(verified_value, _)| *verified_value == value)
+                .map(|(_, verified)| verified);
+
+            let verified = match preverified {
+                Some(verified) => verified,
+                None => {
+                    let keys = Arc::new(pub_key_map.read().expect("RwLock is poisoned.").clone());
+                    let (verified, checked_value) =
+                        verify_event(keys, value, room_version_id.clone()).await;
+                    value = checked_value;
+                    verified
+                }
+            };
+
+            let mut val = match verified {
+                None => {
                     // Drop
-                    warn!("Dropping bad event {}: {}", event_id, e,);
-                    return Err(Error::BadRequest(
-                        ErrorKind::InvalidParam,
+                    warn!(
+                        "Dropping bad event {}: signature verification failed",
+                        event_id
+                    );
+                    return Err(Error::RejectedPdu(
+                        PduRejection::InvalidSignature,
                         "Signature verification failed",
                     ));
                 }
-                Ok(ruma::signatures::Verified::Signatures) => {
+                Some(Verified::Signatures) => {
                     // Redact
                     warn!("Calculated hash does not match: {}", event_id);
                     let obj = match ruma::canonical_json::redact(value, room_version_id, None) {
                         Ok(obj) => obj,
                         Err(_) => {
-                            return Err(Error::BadRequest(
-                                ErrorKind::InvalidParam,
+                            return Err(Error::RejectedPdu(
+                                PduRejection::InvalidEvent,
                                 "Redaction failed",
                             ))
                         }
@@ -339,15 +712,15 @@ impl Service {
 
                     // Skip the PDU if it is redacted and we already have it as an outlier event
                     if services().rooms.timeline.get_pdu_json(event_id)?.is_some() {
-                        return Err(Error::BadRequest(
-                            ErrorKind::InvalidParam,
+                        return Err(Error::RejectedPdu(
+                            PduRejection::InvalidEvent,
                             "Event was redacted and we already knew about it",
                         ));
                     }
 
                     obj
                 }
-                Ok(ruma::signatures::Verified::All) => value,
+                Some(Verified::All) => value,
             };
 
             // Now that we have checked the signature and hashes we can add the eventID and convert
@@ -359,7 +732,9 @@ impl Service {
             let incoming_pdu = serde_json::from_value::<PduEvent>(
                 serde_json::to_value(&val).expect("CanonicalJsonObj is a valid JsonValue"),
             )
-            .map_err(|_| Error::bad_database("Event is not a valid PDU."))?;
+            .map_err(|_| {
+                Error::RejectedPdu(PduRejection::InvalidEvent, "Event is not a valid PDU.")
+            })?;
 
             self.check_room_id(room_id, &incoming_pdu)?;
 
@@ -413,8 +788,8 @@ impl Service {
                         v.insert(auth_event);
                     }
                     hash_map::Entry::Occupied(_) => {
-                        return Err(Error::BadRequest(
-                            ErrorKind::InvalidParam,
+                        return Err(Error::RejectedPdu(
+                            PduRejection::InvalidEvent,
                             "Auth event's type and state_key combination exists multiple times.",
                         ));
                     }
@@ -428,22 +803,26 @@ impl Service {
                     .map(|a| a.as_ref()),
                 Some(_) | None
             ) {
-                return Err(Error::BadRequest(
-                    ErrorKind::InvalidParam,
+                return Err(Error::RejectedPdu(
+                    PduRejection::InvalidEvent,
                     "Incoming event refers to wrong create event.",
                 ));
             }
 
+            let third_party_invite = incoming_pdu
+                .third_party_invite_token()
+                .and_then(|token| auth_events.get(&(StateEventType::RoomThirdPartyInvite, token)));
+
             if !state_res::event_auth::auth_check(
                 &room_version,
                 &incoming_pdu,
-                None::<PduEvent>, // TODO: third party invite
+                third_party_invite,
                 |k, s| auth_events.get(&(k.to_string().into(), s.to_owned())),
             )
-            .map_err(|_e| Error::BadRequest(ErrorKind::InvalidParam, "Auth check failed"))?
+            .map_err(|_e| Error::RejectedPdu(PduRejection::AuthFailed, "Auth check failed"))?
             {
-                return Err(Error::BadRequest(
-                    ErrorKind::InvalidParam,
+                return Err(Error::RejectedPdu(
+                    PduRejection::AuthFailed,
                     "Auth check failed",
                 ));
             }
@@ -481,8 +860,8 @@ impl Service {
             .pdu_metadata
             .is_event_soft_failed(&incoming_pdu.event_id)?
         {
-            return Err(Error::BadRequest(
-                ErrorKind::InvalidParam,
+            return Err(Error::RejectedPdu(
+                PduRejection::SoftFailed,
                 "Event has been soft failed",
             ));
         }
@@ -721,7 +1100,8 @@ impl Service {
                     if state.get(&create_shortstatekey).map(|id| id.as_ref())
                         != Some(&create_event.event_id)
                     {
-                        return Err(Error::bad_database(
+                        return Err(Error::RejectedPdu(
+                            PduRejection::InvalidEvent,
                             "Incoming event refers to wrong create event.",
                         ));
                     }
@@ -730,7 +1110,10 @@ impl Service {
                 }
                 Err(e) => {
                     warn!("Fetching state for event failed: {}", e);
-                    return Err(e);
+                    return Err(Error::RejectedPdu(
+                        PduRejection::MissingPrevEvents,
+                        "Could not fetch the state at the event",
+                    ));
                 }
             };
         }
@@ -740,10 +1123,23 @@ impl Service {
 
         debug!("Starting auth check");
         // 11. Check the auth of the event passes based on the state of the event
+        let third_party_invite = incoming_pdu
+            .third_party_invite_token()
+            .and_then(|token| {
+                services()
+                    .rooms
+                    .short
+                    .get_shortstatekey(&StateEventType::RoomThirdPartyInvite, &token)
+                    .ok()
+                    .flatten()
+            })
+            .and_then(|shortstatekey| state_at_incoming_event.get(&shortstatekey))
+            .and_then(|event_id| services().rooms.timeline.get_pdu(event_id).ok().flatten());
+
         let check_result = state_res::event_auth::auth_check(
             &room_version,
             &incoming_pdu,
-            None::<PduEvent>, // TODO: third party invite
+            third_party_invite,
             |k, s| {
                 services()
                     .rooms
@@ -755,10 +1151,11 @@ impl Service {
                     .and_then(|event_id| services().rooms.timeline.get_pdu(event_id).ok().flatten())
             },
         )
-        .map_err(|_e| Error::BadRequest(ErrorKind::InvalidParam, "Auth check failed."))?;
+        .map_err(|_e| Error::RejectedPdu(PduRejection::AuthFailed, "Auth check failed."))?;
 
         if !check_result {
-            return Err(Error::bad_database(
+            return Err(Error::RejectedPdu(
+                PduRejection::AuthFailed,
                 "Event has failed auth check with state at the event.",
             ));
         }
@@ -773,26 +1170,22 @@ impl Service {
             &incoming_pdu.content,
         )?;
 
+        let third_party_invite = incoming_pdu
+            .third_party_invite_token()
+            .and_then(|token| auth_events.get(&(StateEventType::RoomThirdPartyInvite, token)));
+
         let soft_fail = !state_res::event_auth::auth_check(
             &room_version,
             &incoming_pdu,
-            None::<PduEvent>,
+            third_party_invite,
             |k, s| auth_events.get(&(k.clone(), s.to_owned())),
         )
-        .map_err(|_e| Error::BadRequest(ErrorKind::InvalidParam, "Auth check failed."))?;
+        .map_err(|_e| Error::RejectedPdu(PduRejection::AuthFailed, "Auth check failed."))?;
 
         // 13. Use state resolution to find new room state
 
         // We start looking at current room state now, so lets lock the room
-        let mutex_state = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_state
-                .write()
-                .unwrap()
-                .entry(room_id.to_owned())
-                .or_default(),
-        );
+        let mutex_state = services().globals.roomid_mutex_state.mutex(room_id);
         let state_lock = mutex_state.lock().await;
 
         // Now we calculate the set of extremities this room has after the incoming event has been
@@ -846,10 +1239,39 @@ impl Service {
                 state_after.insert(shortstatekey, Arc::from(&*incoming_pdu.event_id));
             }
 
+            debug!("Loading current room state ids");
+            let current_sstatehash = services()
+                .rooms
+                .state
+                .get_room_shortstatehash(room_id)?
+                .ok_or_else(|| Error::bad_database("The room has no current state."))?;
+            let current_state_ids = services()
+                .rooms
+                .state_accessor
+                .state_full_ids(current_sstatehash)
+                .await?;
+
             let new_room_state = self
-                .resolve_state(room_id, room_version_id, state_after)
+                .resolve_state(
+                    room_id,
+                    room_version_id,
+                    vec![current_state_ids, state_after],
+                )
                 .await?;
 
+            debug!("State resolution done. Compressing state");
+            let new_room_state = Arc::new(
+                new_room_state
+                    .iter()
+                    .map(|(shortstatekey, event_id)| {
+                        services()
+                            .rooms
+                            .state_compressor
+                            .compress_state_event(*shortstatekey, event_id)
+                    })
+                    .collect::<Result<_>>()?,
+            );
+
             // Set the new room state to the resolved state
             debug!("Forcing new room state");
 
@@ -887,9 +1309,9 @@ impl Service {
             services()
                 .rooms
                 .pdu_metadata
-                .mark_event_soft_failed(&incoming_pdu.event_id)?;
-            return Err(Error::BadRequest(
-                ErrorKind::InvalidParam,
+                .mark_event_soft_failed(room_id, &incoming_pdu.event_id)?;
+            return Err(Error::RejectedPdu(
+                PduRejection::SoftFailed,
                 "Event has been soft failed",
             ));
         }
@@ -921,34 +1343,21 @@ impl Service {
         Ok(pdu_id)
     }
 
+    /// Resolves the state of any number of forks of a room, e.g. its current state and the state
+    /// after an incoming event.
     async fn resolve_state(
         &self,
         room_id: &RoomId,
         room_version_id: &RoomVersionId,
-        incoming_state: HashMap<u64, Arc<EventId>>,
-    ) -> Result<Arc<HashSet<CompressedStateEvent>>> {
-        debug!("Loading current room state ids");
-        let current_sstatehash = services()
-            .rooms
-            .state
-            .get_room_shortstatehash(room_id)?
-            .expect("every room has state");
-
-        let current_state_ids = services()
-            .rooms
-            .state_accessor
-            .state_full_ids(current_sstatehash)
-            .await?;
-
-        let fork_states = [current_state_ids, incoming_state];
-
-        let mut auth_chain_sets = Vec::new();
+        fork_states: Vec<HashMap<u64, Arc<EventId>>>,
+    ) -> Result<HashMap<u64, Arc<EventId>>> {
+        let mut auth_chain_sets = Vec::with_capacity(fork_states.len());
         for state in &fork_states {
             auth_chain_sets.push(
                 services()
                     .rooms
                     .auth_chain
-                    .get_auth_chain(room_id, state.iter().map(|(_, id)| id.clone()).collect())
+                    .get_auth_chain(room_id, state.values().cloned().collect())
                     .await?
                     .collect(),
             );
@@ -983,6 +1392,7 @@ impl Service {
                 }
                 res.ok().flatten()
             });
+        drop(lock);
 
         let state = match state_resolve {
             Ok(new_state) => new_state,
@@ -992,25 +1402,16 @@ impl Service {
             }
         };
 
-        drop(lock);
-
-        debug!("State resolution done. Compressing state");
-
-        let new_room_state = state
+        state
             .into_iter()
             .map(|((event_type, state_key), event_id)| {
                 let shortstatekey = services()
                     .rooms
                     .short
                     .get_or_create_shortstatekey(&event_type.to_string().into(), &state_key)?;
-                services()
-                    .rooms
-                    .state_compressor
-                    .compress_state_event(shortstatekey, &event_id)
+                Ok((shortstatekey, event_id))
             })
-            .collect::<Result<_>>()?;
-
-        Ok(Arc::new(new_room_state))
+            .collect()
     }
 
     /// Find the event and auth it. Once the event is validated (steps 1 - 8)
@@ -1033,17 +1434,18 @@ impl Service {
         pub_key_map: &'a RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
     ) -> AsyncRecursiveCanonicalJsonVec<'a> {
         Box::pin(async move {
-            let back_off = |id| match services()
-                .globals
-                .bad_event_ratelimiter
-                .write()
-                .unwrap()
-                .entry(id)
-            {
-                hash_map::Entry::Vacant(e) => {
-                    e.insert((Instant::now(), 1));
-                }
-                hash_map::Entry::Occupied(mut e) => *e.get_mut() = (Instant::now(), e.get().1 + 1),
+            let back_off = |id| {
+                services()
+                    .globals
+                    .bad_event_ratelimiter
+                    .with_entry(id, |entry| match entry {
+                        hash_map::Entry::Vacant(e) => {
+                            e.insert((Instant::now(), 1));
+                        }
+                        hash_map::Entry::Occupied(mut e) => {
+                            *e.get_mut() = (Instant::now(), e.get().1 + 1)
+                        }
+                    });
             };
 
             let mut events_with_auth_events = vec![];
@@ -1065,16 +1467,11 @@ impl Service {
                 let mut events_all = HashSet::new();
                 let mut i = 0;
                 while let Some(next_id) = todo_auth_events.pop() {
-                    if let Some((time, tries)) = services()
-                        .globals
-                        .bad_event_ratelimiter
-                        .read()
-                        .unwrap()
-                        .get(&*next_id)
+                    if let Some((time, tries)) =
+                        services().globals.bad_event_ratelimiter.get(&*next_id)
                     {
                         // Exponential backoff
-                        let mut min_elapsed_duration =
-                            Duration::from_secs(5 * 60) * (*tries) * (*tries);
+                        let mut min_elapsed_duration = Duration::from_secs(5 * 60) * tries * tries;
                         if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
                             min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
                         }
@@ -1183,16 +1580,11 @@ impl Service {
                     pdus.push((local_pdu, None));
                 }
                 for (next_id, value) in events_in_reverse_order.iter().rev() {
-                    if let Some((time, tries)) = services()
-                        .globals
-                        .bad_event_ratelimiter
-                        .read()
-                        .unwrap()
-                        .get(&**next_id)
+                    if let Some((time, tries)) =
+                        services().globals.bad_event_ratelimiter.get(&**next_id)
                     {
                         // Exponential backoff
-                        let mut min_elapsed_duration =
-                            Duration::from_secs(5 * 60) * (*tries) * (*tries);
+                        let mut min_elapsed_duration = Duration::from_secs(5 * 60) * tries * tries;
                         if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
                             min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
                         }
@@ -1337,6 +1729,48 @@ impl Service {
         Ok((sorted, eventid_info))
     }
 
+    /// Checks the signatures and content hashes of a batch of PDUs in parallel on the blocking
+    /// thread pool, so that `handle_incoming_pdu` doesn't have to do it one by one on the async
+    /// executor. The keys have to be fetched beforehand.
+    ///
+    /// The results are kept until the returned guard is dropped, which has to outlive the handling
+    /// of the PDUs.
+    pub(crate) async fn verify_pdus<'a, E>(
+        &self,
+        pdus: E,
+        pub_key_map: &RwLock<PubKeyMap>,
+    ) -> PreverifiedGuard
+    where
+        E: IntoIterator<Item = (&'a EventId, &'a CanonicalJsonObject, &'a RoomId)>,
+    {
+        let keys = Arc::new(pub_key_map.read().expect("RwLock is poisoned.").clone());
+
+        let checks = pdus.into_iter().filter_map(|(event_id, value, room_id)| {
+            let room_version_id = services().rooms.state.get_room_version(room_id).ok()?;
+
+            // Same as in handle_outlier_pdu
+            let mut value = value.clone();
+            value.remove("unsigned");
+
+            let keys = Arc::clone(&keys);
+            Some(async move {
+                let (verified, value) = verify_event(keys, value, room_version_id).await;
+                (event_id.to_owned(), value, verified)
+            })
+        });
+
+        let results = futures_util::future::join_all(checks).await;
+
+        let mut preverified = self.preverified.lock().expect("locked");
+        let mut event_ids = Vec::with_capacity(results.len());
+        for (event_id, value, verified) in results {
+            event_ids.push(event_id.clone());
+            preverified.insert(event_id, (value, verified));
+        }
+
+        PreverifiedGuard { event_ids }
+    }
+
     #[tracing::instrument(skip_all)]
     pub(crate) async fn fetch_required_signing_keys<'a, E>(
         &'a self,
@@ -1347,9 +1781,17 @@ impl Service {
         E: IntoIterator<Item = &'a BTreeMap<String, CanonicalJsonValue>>,
     {
         let mut server_key_ids = HashMap::new();
+        let mut server_valid_at = HashMap::new();
 
         for event in events.into_iter() {
             debug!("Fetching keys for event: {event:?}");
+            let room_version = event
+                .get("room_id")
+                .and_then(CanonicalJsonValue::as_str)
+                .and_then(|room_id| RoomId::parse(room_id).ok())
+                .and_then(|room_id| services().rooms.state.get_room_version(&room_id).ok());
+            let origin_server_ts = keys_valid_at(event, room_version.as_ref());
+
             for (signature_server, signature) in event
                 .get("signatures")
                 .ok_or(Error::BadServerResponse(
@@ -1370,6 +1812,14 @@ impl Service {
                         .or_insert_with(HashSet::new)
                         .insert(signature_id.clone());
                 }
+
+                // The keys have to be valid for the latest event signed by the server
+                if let Some(origin_server_ts) = origin_server_ts {
+                    server_valid_at
+                        .entry(signature_server.clone())
+                        .and_modify(|ts| *ts = origin_server_ts.max(*ts))
+                        .or_insert(origin_server_ts);
+                }
             }
         }
 
@@ -1391,6 +1841,7 @@ impl Service {
         let mut server_keys: FuturesUnordered<_> = server_key_ids
             .into_iter()
             .map(|(signature_server, signature_ids)| async {
+                let valid_at = server_valid_at.get(&signature_server).copied();
                 let signature_server2 = signature_server.clone();
                 let fetch_res = self
                     .fetch_signing_keys_for_server(
@@ -1404,6 +1855,7 @@ impl Service {
                             )
                         })?,
                         signature_ids.into_iter().collect(), // HashSet to Vec
+                        valid_at,
                     )
                     .await;
 
@@ -1456,15 +1908,9 @@ impl Service {
         let event_id = <&EventId>::try_from(event_id.as_str())
             .expect("ruma's reference hashes are valid event ids");
 
-        if let Some((time, tries)) = services()
-            .globals
-            .bad_event_ratelimiter
-            .read()
-            .unwrap()
-            .get(event_id)
-        {
+        if let Some((time, tries)) = services().globals.bad_event_ratelimiter.get(event_id) {
             // Exponential backoff
-            let mut min_elapsed_duration = Duration::from_secs(5 * 60) * (*tries) * (*tries);
+            let mut min_elapsed_duration = Duration::from_secs(5 * 60) * tries * tries;
             if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
                 min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
             }
@@ -1485,6 +1931,8 @@ impl Service {
                 "Invalid signatures object in server response pdu.",
             ))?;
 
+        let origin_server_ts = keys_valid_at(&value, Some(room_version));
+
         for (signature_server, signature) in signatures {
             let signature_object = signature.as_object().ok_or(Error::BadServerResponse(
                 "Invalid signatures content object in server response pdu.",
@@ -1501,7 +1949,15 @@ impl Service {
                 Error::BadServerResponse("Invalid servername in signatures of server response pdu.")
             })?;
 
-            if servers.contains_key(origin) || pub_key_map.contains_key(origin.as_str()) {
+            let keys_valid = origin_server_ts.map_or(Ok(true), |ts| {
+                services()
+                    .globals
+                    .signing_keys_valid_at(origin, &signature_ids, ts)
+            })?;
+
+            if servers.contains_key(origin)
+                || (keys_valid && pub_key_map.contains_key(origin.as_str()))
+            {
                 continue;
             }
 
@@ -1514,7 +1970,7 @@ impl Service {
                 .map(|(k, v)| (k.to_string(), v.key))
                 .collect();
 
-            if !contains_all_ids(&result) {
+            if !contains_all_ids(&result) || !keys_valid {
                 debug!("Signing key not loaded for {}", origin);
                 servers.insert(origin.to_owned(), BTreeMap::new());
             }
@@ -1625,7 +2081,13 @@ impl Service {
             info!("Received new result");
             if let (Ok(get_keys_response), origin) = result {
                 info!("Result is from {origin}");
-                if let Ok(key) = get_keys_response.server_key.deserialize() {
+                if let Ok(key) = services()
+                    .globals
+                    .verify_server_keys(&origin, &get_keys_response.server_key)
+                {
+                    services()
+                        .globals
+                        .add_signed_server_keys(&origin, &get_keys_response.server_key)?;
                     let result: BTreeMap<_, _> = services()
                         .globals
                         .add_signing_key(&origin, key)?
@@ -1648,40 +2110,11 @@ impl Service {
 
     /// Returns Ok if the acl allows the server
     pub fn acl_check(&self, server_name: &ServerName, room_id: &RoomId) -> Result<()> {
-        let acl_event = match services().rooms.state_accessor.room_state_get(
-            room_id,
-            &StateEventType::RoomServerAcl,
-            "",
-        )? {
-            Some(acl) => {
-                debug!("ACL event found: {acl:?}");
-                acl
-            }
-            None => {
-                info!("No ACL event found");
-                return Ok(());
-            }
-        };
-
-        let acl_event_content: RoomServerAclEventContent =
-            match serde_json::from_str(acl_event.content.get()) {
-                Ok(content) => {
-                    debug!("Found ACL event contents: {content:?}");
-                    content
-                }
-                Err(e) => {
-                    warn!("Invalid ACL event: {e}");
-                    return Ok(());
-                }
-            };
-
-        if acl_event_content.allow.is_empty() {
-            warn!("Ignoring broken ACL event (allow key is empty)");
-            // Ignore broken acl events
+        let Some(acl) = self.room_acl(room_id)? else {
             return Ok(());
-        }
+        };
 
-        if acl_event_content.is_allowed(server_name) {
+        if acl.is_allowed(server_name) {
             debug!("server {server_name} is allowed by ACL");
             Ok(())
         } else {
@@ -1689,69 +2122,113 @@ impl Service {
                 "Server {} was denied by room ACL in {}",
                 server_name, room_id
             );
-            Err(Error::BadRequest(
-                ErrorKind::Forbidden,
+            Err(Error::RejectedPdu(
+                PduRejection::ServerAcl,
                 "Server was denied by room ACL",
             ))
         }
     }
 
-    /// Search the DB for the signing keys of the given server, if we don't have them
-    /// fetch them from the server and save to our DB.
+    /// Returns the server ACL of the room with its globs compiled, or None if the room has no
+    /// usable ACL. Cached by the current state of the room.
+    pub fn room_acl(&self, room_id: &RoomId) -> Result<Option<Arc<CompiledAcl>>> {
+        let Some(shortstatehash) = services().rooms.state.get_room_shortstatehash(room_id)? else {
+            return Ok(None);
+        };
+
+        if let Some(acl) = self.acl_cache.lock().unwrap().get_mut(&shortstatehash) {
+            return Ok(acl.clone());
+        }
+
+        let acl = services()
+            .rooms
+            .state_accessor
+            .state_get(shortstatehash, &StateEventType::RoomServerAcl, "")?
+            .and_then(|acl_event| {
+                let content: RoomServerAclEventContent =
+                    match serde_json::from_str(acl_event.content.get()) {
+                        Ok(content) => content,
+                        Err(e) => {
+                            warn!("Invalid ACL event in {room_id}: {e}");
+                            return None;
+                        }
+                    };
+
+                if content.allow.is_empty() {
+                    // Ignore broken acl events
+                    warn!("Ignoring broken ACL event in {room_id} (allow key is empty)");
+                    return None;
+                }
+
+                match CompiledAcl::new(content) {
+                    Ok(acl) => Some(Arc::new(acl)),
+                    Err(e) => {
+                        warn!("Failed to compile ACL of {room_id}: {e}");
+                        None
+                    }
+                }
+            });
+
+        self.acl_cache
+            .lock()
+            .unwrap()
+            .insert(shortstatehash, acl.clone());
+
+        Ok(acl)
+    }
+
+    /// Search the DB for the signing keys of the given server, if we don't have them or they
+    /// expired before `valid_at` (if given), fetch them from the server and save to our DB.
     #[tracing::instrument(skip_all)]
     pub async fn fetch_signing_keys_for_server(
         &self,
         origin: &ServerName,
         signature_ids: Vec<String>,
+        valid_at: Option<MilliSecondsSinceUnixEpoch>,
     ) -> Result<BTreeMap<String, Base64>> {
         let contains_all_ids =
             |keys: &BTreeMap<String, Base64>| signature_ids.iter().all(|id| keys.contains_key(id));
+        let keys_usable = |keys: &BTreeMap<String, Base64>| -> Result<bool> {
+            Ok(contains_all_ids(keys)
+                && valid_at.map_or(Ok(true), |ts| {
+                    services()
+                        .globals
+                        .signing_keys_valid_at(origin, &signature_ids, ts)
+                })?)
+        };
 
-        let permit = services()
-            .globals
-            .servername_ratelimiter
-            .read()
-            .unwrap()
-            .get(origin)
-            .map(|s| Arc::clone(s).acquire_owned());
-
-        let permit = match permit {
-            Some(p) => p,
-            None => {
-                let mut write = services().globals.servername_ratelimiter.write().unwrap();
-                let s = Arc::clone(
-                    write
-                        .entry(origin.to_owned())
-                        .or_insert_with(|| Arc::new(Semaphore::new(1))),
-                );
-
-                s.acquire_owned()
-            }
-        }
-        .await;
+        let semaphore = match services().globals.servername_ratelimiter.get(origin) {
+            Some(s) => s,
+            None => services()
+                .globals
+                .servername_ratelimiter
+                .with_entry(origin.to_owned(), |entry| {
+                    Arc::clone(entry.or_insert_with(|| Arc::new(Semaphore::new(1))))
+                }),
+        };
+        let permit = semaphore.acquire_owned().await;
 
-        let back_off = |id| match services()
-            .globals
-            .bad_signature_ratelimiter
-            .write()
-            .unwrap()
-            .entry(id)
-        {
-            hash_map::Entry::Vacant(e) => {
-                e.insert((Instant::now(), 1));
-            }
-            hash_map::Entry::Occupied(mut e) => *e.get_mut() = (Instant::now(), e.get().1 + 1),
+        let back_off = |id| {
+            services()
+                .globals
+                .bad_signature_ratelimiter
+                .with_entry(id, |entry| match entry {
+                    hash_map::Entry::Vacant(e) => {
+                        e.insert((Instant::now(), 1));
+                    }
+                    hash_map::Entry::Occupied(mut e) => {
+                        *e.get_mut() = (Instant::now(), e.get().1 + 1)
+                    }
+                });
         };
 
         if let Some((time, tries)) = services()
             .globals
             .bad_signature_ratelimiter
-            .read()
-            .unwrap()
             .get(&signature_ids)
         {
             // Exponential backoff
-            let mut min_elapsed_duration = Duration::from_secs(5 * 60) * (*tries) * (*tries);
+            let mut min_elapsed_duration = Duration::from_secs(5 * 60) * tries * tries;
             if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
                 min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
             }
@@ -1771,22 +2248,31 @@ impl Service {
             .map(|(k, v)| (k.to_string(), v.key))
             .collect();
 
-        if contains_all_ids(&result) {
+        if keys_usable(&result)? {
             return Ok(result);
         }
 
         debug!("Fetching signing keys for {} over federation", origin);
 
-        if let Some(server_key) = services()
+        if let Some((server_key, raw_server_key)) = services()
             .sending
             .send_federation_request(origin, get_server_keys::v2::Request::new())
             .await
             .ok()
-            .and_then(|resp| resp.server_key.deserialize().ok())
+            .and_then(|resp| {
+                let server_key = services()
+                    .globals
+                    .verify_server_keys(origin, &resp.server_key)
+                    .ok()?;
+                Some((server_key, resp.server_key))
+            })
         {
             services()
                 .globals
                 .add_signing_key(origin, server_key.clone())?;
+            services()
+                .globals
+                .add_signed_server_keys(origin, &raw_server_key)?;
 
             result.extend(
                 server_key
@@ -1801,11 +2287,20 @@ impl Service {
                     .map(|(k, v)| (k.to_string(), v.key)),
             );
 
-            if contains_all_ids(&result) {
+            if keys_usable(&result)? {
                 return Ok(result);
             }
         }
 
+        let minimum_valid_until_ts = MilliSecondsSinceUnixEpoch::from_system_time(
+            SystemTime::now()
+                .checked_add(Duration::from_secs(3600))
+                .expect("SystemTime too large"),
+        )
+        .expect("time is valid");
+        let minimum_valid_until_ts =
+            valid_at.map_or(minimum_valid_until_ts, |ts| ts.max(minimum_valid_until_ts));
+
         for server in services().globals.trusted_servers() {
             debug!("Asking {} for {}'s signing key", server, origin);
             if let Some(server_keys) = services()
@@ -1814,12 +2309,7 @@ impl Service {
                     server,
                     get_remote_server_keys::v2::Request::new(
                         origin.to_owned(),
-                        MilliSecondsSinceUnixEpoch::from_system_time(
-                            SystemTime::now()
-                                .checked_add(Duration::from_secs(3600))
-                                .expect("SystemTime too large"),
-                        )
-                        .expect("time is valid"),
+                        minimum_valid_until_ts,
                     ),
                 )
                 .await
@@ -1846,7 +2336,7 @@ impl Service {
                     );
                 }
 
-                if contains_all_ids(&result) {
+                if keys_usable(&result)? {
                     return Ok(result);
                 }
             }
@@ -1865,11 +2355,62 @@ impl Service {
     fn check_room_id(&self, room_id: &RoomId, pdu: &PduEvent) -> Result<()> {
         if pdu.room_id != room_id {
             warn!("Found event from room {} in room {}", pdu.room_id, room_id);
-            return Err(Error::BadRequest(
-                ErrorKind::InvalidParam,
+            return Err(Error::RejectedPdu(
+                PduRejection::InvalidEvent,
                 "Event has wrong room id",
             ));
         }
         Ok(())
     }
 }
+
+/// Drops the signature check results of a batch of PDUs verified by `verify_pdus` once the batch is
+/// handled, or the handling is cancelled. Results of PDUs that were not handled, e.g. because we
+/// already had them, would leak otherwise.
+#[must_use]
+pub(crate) struct PreverifiedGuard {
+    event_ids: Vec<OwnedEventId>,
+}
+
+impl Drop for PreverifiedGuard {
+    fn drop(&mut self) {
+        if let Ok(mut preverified) = services().rooms.event_handler.preverified.lock() {
+            for event_id in &self.event_ids {
+                preverified.remove(event_id);
+            }
+        }
+    }
+}
+
+/// Checks the signatures and content hash of an event on the blocking thread pool. Returns `None`
+/// if the signatures are invalid, along with the event itself.
+async fn verify_event(
+    keys: Arc<PubKeyMap>,
+    value: CanonicalJsonObject,
+    room_version_id: RoomVersionId,
+) -> (Option<Verified>, CanonicalJsonObject) {
+    tokio::task::spawn_blocking(move || {
+        let verified = ruma::signatures::verify_event(&keys, &value, &room_version_id)
+            .map_err(|e| debug!("Signature verification failed: {e}"))
+            .ok();
+        (verified, value)
+    })
+    .await
+    .expect("signature verification task panicked")
+}
+
+/// Returns the time the signing keys of an event have to be valid at, which is when it was sent.
+/// Room versions before 5 don't enforce `valid_until_ts`, and neither do rooms of unknown version.
+fn keys_valid_at(
+    event: &CanonicalJsonObject,
+    room_version: Option<&RoomVersionId>,
+) -> Option<MilliSecondsSinceUnixEpoch> {
+    match room_version? {
+        RoomVersionId::V1 | RoomVersionId::V2 | RoomVersionId::V3 | RoomVersionId::V4 => None,
+        _ => event
+            .get("origin_server_ts")
+            .and_then(CanonicalJsonValue::as_integer)
+            .and_then(|ts| UInt::try_from(ts).ok())
+            .map(MilliSecondsSinceUnixEpoch),
+    }
+}
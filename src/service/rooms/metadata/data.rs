@@ -9,4 +9,6 @@ pub trait Data: Send + Sync {
     fn is_banned(&self, room_id: &RoomId) -> Result<bool>;
     fn ban_room(&self, room_id: &RoomId, banned: bool) -> Result<()>;
     fn list_banned_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>;
+    fn frozen_power_levels(&self, room_id: &RoomId) -> Result<Option<String>>;
+    fn set_frozen_power_levels(&self, room_id: &RoomId, power_levels: Option<&str>) -> Result<()>;
 }
@@ -3,7 +3,7 @@ mod data;
 pub use data::Data;
 use ruma::{OwnedRoomId, RoomId};
 
-use crate::Result;
+use crate::{services, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -28,7 +28,22 @@ impl Service {
         self.db.disable_room(room_id, disabled)
     }
 
+    /// Checks if a room is banned, either through the `ban-room`/`block-room` admin
+    /// commands or through the `banned_room_ids`/`banned_room_patterns` config options.
     pub fn is_banned(&self, room_id: &RoomId) -> Result<bool> {
+        if services()
+            .globals
+            .banned_room_ids()
+            .iter()
+            .any(|banned_room_id| banned_room_id == room_id)
+            || services()
+                .globals
+                .banned_room_patterns()
+                .is_match(room_id.as_str())
+        {
+            return Ok(true);
+        }
+
         self.db.is_banned(room_id)
     }
 
@@ -39,4 +54,19 @@ impl Service {
     pub fn list_banned_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> {
         self.db.list_banned_rooms()
     }
+
+    /// Returns the JSON of the power levels a room had before it was frozen, or None if the room
+    /// is not frozen.
+    pub fn frozen_power_levels(&self, room_id: &RoomId) -> Result<Option<String>> {
+        self.db.frozen_power_levels(room_id)
+    }
+
+    /// Marks a room as frozen, remembering its previous power levels, or as not frozen.
+    pub fn set_frozen_power_levels(
+        &self,
+        room_id: &RoomId,
+        power_levels: Option<&str>,
+    ) -> Result<()> {
+        self.db.set_frozen_power_levels(room_id, power_levels)
+    }
 }
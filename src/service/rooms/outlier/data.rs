@@ -1,4 +1,4 @@
-use ruma::{CanonicalJsonObject, EventId};
+use ruma::{CanonicalJsonObject, EventId, RoomId};
 
 use crate::{PduEvent, Result};
 
@@ -6,4 +6,10 @@ pub trait Data: Send + Sync {
     fn get_outlier_pdu_json(&self, event_id: &EventId) -> Result<Option<CanonicalJsonObject>>;
     fn get_outlier_pdu(&self, event_id: &EventId) -> Result<Option<PduEvent>>;
     fn add_pdu_outlier(&self, event_id: &EventId, pdu: &CanonicalJsonObject) -> Result<()>;
+
+    /// Returns the outliers of a room. Goes through the outliers of all rooms.
+    fn room_outlier_pdus<'a>(
+        &'a self,
+        room_id: &RoomId,
+    ) -> Box<dyn Iterator<Item = Result<(PduEvent, CanonicalJsonObject)>> + 'a>;
 }
@@ -1,7 +1,7 @@
 mod data;
 
 pub use data::Data;
-use ruma::{CanonicalJsonObject, EventId};
+use ruma::{CanonicalJsonObject, EventId, RoomId};
 
 use crate::{PduEvent, Result};
 
@@ -25,4 +25,13 @@ impl Service {
     pub fn add_pdu_outlier(&self, event_id: &EventId, pdu: &CanonicalJsonObject) -> Result<()> {
         self.db.add_pdu_outlier(event_id, pdu)
     }
+
+    /// Returns the PDUs of the room that are only stored as outliers, with their json. This has to
+    /// go through the outliers of all rooms, so it is slow.
+    pub fn room_outlier_pdus<'a>(
+        &'a self,
+        room_id: &RoomId,
+    ) -> impl Iterator<Item = Result<(PduEvent, CanonicalJsonObject)>> + 'a {
+        self.db.room_outlier_pdus(room_id)
+    }
 }
@@ -4,7 +4,7 @@ use crate::{
     service::rooms::timeline::{data::PduData, PduCount},
     Result,
 };
-use ruma::{EventId, RoomId, UserId};
+use ruma::{EventId, OwnedEventId, RoomId, UserId};
 
 pub trait Data: Send + Sync {
     fn add_relation(&self, from: u64, to: u64) -> Result<()>;
@@ -15,8 +15,44 @@ pub trait Data: Send + Sync {
         target: u64,
         until: PduCount,
     ) -> PduData<'a>;
+    fn relations_after<'a>(
+        &'a self,
+        user_id: &'a UserId,
+        room_id: u64,
+        target: u64,
+        after: PduCount,
+    ) -> PduData<'a>;
+
+    /// Returns true if the annotations of the event with the count are counted by
+    /// `count_annotation`.
+    fn annotations_counted(&self, target: u64) -> Result<bool>;
+
+    /// Marks the annotations of the event with the count as counted.
+    fn mark_annotations_counted(&self, target: u64) -> Result<()>;
+
+    /// Counts an annotation of the sender that was added to or removed from the event with the
+    /// count. Every sender is only counted once per annotation.
+    fn count_annotation(
+        &self,
+        target: u64,
+        kind: &str,
+        key: &str,
+        sender: &UserId,
+        added: bool,
+    ) -> Result<()>;
+
+    /// Returns how many users sent each annotation of the event with the count, by event type and
+    /// key.
+    fn annotation_counts(&self, target: u64) -> Result<Vec<((String, String), u64)>>;
+
     fn mark_as_referenced(&self, room_id: &RoomId, event_ids: &[Arc<EventId>]) -> Result<()>;
     fn is_event_referenced(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool>;
-    fn mark_event_soft_failed(&self, event_id: &EventId) -> Result<()>;
+    fn mark_event_soft_failed(&self, room_id: &RoomId, event_id: &EventId) -> Result<()>;
+
+    /// Returns the soft failed events of a room with the time they were soft failed at.
+    fn soft_failed_events<'a>(
+        &'a self,
+        room_id: &RoomId,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedEventId, u64)>> + 'a>;
     fn is_event_soft_failed(&self, event_id: &EventId) -> Result<bool>;
 }
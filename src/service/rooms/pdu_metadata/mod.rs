@@ -1,18 +1,33 @@
 mod data;
-use std::sync::Arc;
+use std::{
+    cmp::Ordering,
+    collections::{binary_heap::PeekMut, BinaryHeap},
+    sync::Arc,
+};
 
 pub use data::Data;
 use ruma::{
-    api::client::relations::get_relating_events,
-    events::{relation::RelationType, TimelineEventType},
-    EventId, RoomId, UserId,
+    api::{client::relations::get_relating_events, Direction},
+    canonical_json::to_canonical_value,
+    events::{
+        relation::{Annotation, RelationType},
+        room::encrypted::{Relation, Replacement},
+        TimelineEventType,
+    },
+    CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedEventId, RoomId, UInt, UserId,
 };
 use serde::Deserialize;
+use serde_json::json;
+use tracing::warn;
 
-use crate::{services, PduEvent, Result};
+use crate::{services, Error, PduEvent, Result};
 
 use super::timeline::PduCount;
 
+/// How deep relations of relations are followed when a client asks for recursion. The spec
+/// recommends at least 3.
+const MAX_RELATIONS_RECURSION_DEPTH: u8 = 3;
+
 pub struct Service {
     pub db: &'static dyn Data,
 }
@@ -27,6 +42,22 @@ struct ExtractRelatesToEventId {
     relates_to: ExtractRelType,
 }
 
+/// The relation in the content of an event
+#[derive(Deserialize)]
+pub(crate) struct ExtractRelatesTo {
+    #[serde(rename = "m.relates_to")]
+    pub(crate) relates_to: Relation,
+}
+
+impl ExtractRelatesTo {
+    /// Returns the relation of the event, if it has a valid one.
+    pub(crate) fn relation(pdu: &PduEvent) -> Option<Relation> {
+        serde_json::from_str::<Self>(pdu.content.get())
+            .ok()
+            .map(|content| content.relates_to)
+    }
+}
+
 impl Service {
     #[tracing::instrument(skip(self, from, to))]
     pub fn add_relation(&self, from: PduCount, to: PduCount) -> Result<()> {
@@ -48,124 +79,313 @@ impl Service {
         target: &EventId,
         filter_event_type: Option<TimelineEventType>,
         filter_rel_type: Option<RelationType>,
-        from: PduCount,
-        to: Option<PduCount>,
-        limit: usize,
+        from: Option<&str>,
+        to: Option<&str>,
+        limit: Option<UInt>,
+        recurse: bool,
+        dir: Direction,
     ) -> Result<get_relating_events::v1::Response> {
-        let next_token;
+        let from = match from {
+            Some(from) => PduCount::try_from_string(from)?,
+            None => match dir {
+                Direction::Forward => PduCount::min(),
+                Direction::Backward => PduCount::max(),
+            },
+        };
 
-        //TODO: Fix ruma: match body.dir {
-        match ruma::api::Direction::Backward {
-            ruma::api::Direction::Forward => {
-                let events_after: Vec<_> = services()
-                    .rooms
-                    .pdu_metadata
-                    .relations_until(sender_user, room_id, target, from)? // TODO: should be relations_after
-                    .filter(|r| {
-                        r.as_ref().map_or(true, |(_, pdu)| {
-                            filter_event_type.as_ref().map_or(true, |t| &pdu.kind == t)
-                                && if let Ok(content) =
-                                    serde_json::from_str::<ExtractRelatesToEventId>(
-                                        pdu.content.get(),
-                                    )
-                                {
-                                    filter_rel_type
-                                        .as_ref()
-                                        .map_or(true, |r| &content.relates_to.rel_type == r)
-                                } else {
-                                    false
-                                }
-                        })
-                    })
-                    .take(limit)
-                    .filter_map(|r| r.ok()) // Filter out buggy events
-                    .filter(|(_, pdu)| {
-                        services()
-                            .rooms
-                            .state_accessor
-                            .user_can_see_event(sender_user, room_id, &pdu.event_id)
-                            .unwrap_or(false)
-                    })
-                    .take_while(|&(k, _)| Some(k) != to) // Stop at `to`
-                    .collect();
-
-                next_token = events_after.last().map(|(count, _)| count).copied();
-
-                let events_after: Vec<_> = events_after
-                    .into_iter()
-                    .rev() // relations are always most recent first
-                    .map(|(_, pdu)| pdu.to_message_like_event())
-                    .collect();
-
-                Ok(get_relating_events::v1::Response {
-                    chunk: events_after,
-                    next_batch: next_token.map(|t| t.stringify()),
-                    prev_batch: Some(from.stringify()),
-                })
-            }
-            ruma::api::Direction::Backward => {
-                let events_before: Vec<_> = services()
+        let to = to.and_then(|t| PduCount::try_from_string(t).ok());
+
+        // Use limit or else 10, with maximum 100
+        let limit = limit
+            .and_then(|u| u32::try_from(u).ok())
+            .map_or(10_usize, |u| u as usize)
+            .min(100);
+
+        let depth = if recurse {
+            MAX_RELATIONS_RECURSION_DEPTH
+        } else {
+            1
+        };
+
+        let events: Vec<_> = self
+            .relations(sender_user, room_id, target, from, dir, depth)?
+            .filter(|(_, pdu, _)| {
+                filter_event_type.as_ref().map_or(true, |t| &pdu.kind == t)
+                    && if let Ok(content) =
+                        serde_json::from_str::<ExtractRelatesToEventId>(pdu.content.get())
+                    {
+                        filter_rel_type
+                            .as_ref()
+                            .map_or(true, |r| &content.relates_to.rel_type == r)
+                    } else {
+                        false
+                    }
+            })
+            .filter(|(_, pdu, _)| {
+                services()
                     .rooms
-                    .pdu_metadata
-                    .relations_until(sender_user, room_id, target, from)?
-                    .filter(|r| {
-                        r.as_ref().map_or(true, |(_, pdu)| {
-                            filter_event_type.as_ref().map_or(true, |t| &pdu.kind == t)
-                                && if let Ok(content) =
-                                    serde_json::from_str::<ExtractRelatesToEventId>(
-                                        pdu.content.get(),
-                                    )
-                                {
-                                    filter_rel_type
-                                        .as_ref()
-                                        .map_or(true, |r| &content.relates_to.rel_type == r)
-                                } else {
-                                    false
-                                }
-                        })
-                    })
-                    .take(limit)
-                    .filter_map(|r| r.ok()) // Filter out buggy events
-                    .filter(|(_, pdu)| {
-                        services()
-                            .rooms
-                            .state_accessor
-                            .user_can_see_event(sender_user, room_id, &pdu.event_id)
-                            .unwrap_or(false)
-                    })
-                    .take_while(|&(k, _)| Some(k) != to) // Stop at `to`
-                    .collect();
-
-                next_token = events_before.last().map(|(count, _)| count).copied();
-
-                let events_before: Vec<_> = events_before
-                    .into_iter()
-                    .map(|(_, pdu)| pdu.to_message_like_event())
-                    .collect();
-
-                Ok(get_relating_events::v1::Response {
-                    chunk: events_before,
-                    next_batch: next_token.map(|t| t.stringify()),
-                    prev_batch: Some(from.stringify()),
+                    .state_accessor
+                    .user_can_see_event(sender_user, room_id, &pdu.event_id)
+                    .unwrap_or(false)
+            })
+            .take_while(|&(k, _, _)| Some(k) != to) // Stop at `to`
+            .take(limit)
+            .collect();
+
+        let next_token = if events.len() == limit {
+            events.last().map(|(count, _, _)| *count)
+        } else {
+            None
+        };
+
+        // The depth of the deepest relation that was returned
+        let reached_depth = events.iter().map(|&(_, _, depth)| depth).max().unwrap_or(1);
+
+        Ok(get_relating_events::v1::Response {
+            chunk: events
+                .into_iter()
+                .map(|(_, pdu, _)| pdu.to_message_like_event())
+                .collect(),
+            next_batch: next_token.map(|t| t.stringify()),
+            prev_batch: Some(from.stringify()),
+            recursion_depth: recurse.then(|| reached_depth.into()),
+        })
+    }
+
+    /// Returns the events relating to the target in the direction from `from`, ordered in that
+    /// direction, with the depth they were found at. Relations of relations are followed up to
+    /// `max_depth` levels. The relations are loaded lazily, so only as many as are consumed are
+    /// read from the database.
+    pub fn relations<'a>(
+        &'a self,
+        user_id: &'a UserId,
+        room_id: &RoomId,
+        target: &EventId,
+        from: PduCount,
+        dir: Direction,
+        max_depth: u8,
+    ) -> Result<Relations<'a>> {
+        let shortroomid = services().rooms.short.get_or_create_shortroomid(room_id)?;
+        let mut relations = Relations {
+            db: self.db,
+            user_id,
+            shortroomid,
+            from,
+            dir,
+            max_depth,
+            sources: Vec::new(),
+            next: BinaryHeap::new(),
+        };
+
+        // TODO: Support backfilled relations
+        if let Some(PduCount::Normal(target)) = services().rooms.timeline.get_pdu_count(target)? {
+            relations.add_source(target, 1)?;
+        }
+
+        Ok(relations)
+    }
+
+    /// Updates the aggregations bundled in the `unsigned.m.relations` of the event the new event
+    /// relates to.
+    ///
+    /// - `m.replace`: the latest valid edit
+    /// - `m.annotation`: how often each annotation was sent, counting every user once
+    #[tracing::instrument(skip(self, pdu))]
+    pub fn add_to_bundled_aggregations(&self, relation: &Relation, pdu: &PduEvent) -> Result<()> {
+        match relation {
+            Relation::Replacement(Replacement { event_id, .. }) => self
+                .update_bundled_aggregations(event_id, |target, _, bundled| {
+                    if !is_valid_edit(target, pdu) {
+                        return Ok(false);
+                    }
+
+                    let newer = bundled
+                        .get(RelationType::Replacement.as_str())
+                        .and_then(edit_order)
+                        .map_or(true, |latest| {
+                            (pdu.origin_server_ts, pdu.event_id.as_str())
+                                > (latest.0, latest.1.as_str())
+                        });
+                    if newer {
+                        bundle_edit(bundled, Some(pdu.clone()))?;
+                    }
+
+                    Ok(newer)
+                }),
+            Relation::Annotation(Annotation { event_id, key, .. }) => self
+                .update_bundled_aggregations(event_id, |target, count, bundled| {
+                    self.bundle_annotations(target, count, bundled, Some((pdu, key, true)))?;
+                    Ok(true)
+                }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Updates the aggregations bundled with the event a relating event relates to, after the
+    /// relating event was redacted. `relation` is the relation of the event before the redaction.
+    #[tracing::instrument(skip(self, pdu))]
+    pub fn remove_from_bundled_aggregations(
+        &self,
+        relation: &Relation,
+        pdu: &PduEvent,
+    ) -> Result<()> {
+        match relation {
+            Relation::Replacement(Replacement { event_id, .. }) => {
+                self.update_bundled_aggregations(event_id, |target, count, bundled| {
+                    // Only the latest edit is bundled, so nothing changes if another one is gone
+                    let bundled_edit = bundled
+                        .get(RelationType::Replacement.as_str())
+                        .and_then(edit_order);
+                    if bundled_edit.map_or(true, |(_, event_id)| event_id != pdu.event_id.as_str())
+                    {
+                        return Ok(false);
+                    }
+
+                    let latest_edit = self
+                        .db
+                        .relations_until(
+                            &target.sender,
+                            target_shortroomid(target)?,
+                            count,
+                            PduCount::max(),
+                        )?
+                        .filter_map(Result::ok)
+                        .map(|(_, pdu)| pdu)
+                        .filter(|edit| is_valid_edit(target, edit))
+                        .max_by(|a, b| {
+                            (a.origin_server_ts, &a.event_id)
+                                .cmp(&(b.origin_server_ts, &b.event_id))
+                        });
+                    bundle_edit(bundled, latest_edit)?;
+
+                    Ok(true)
                 })
             }
+            Relation::Annotation(Annotation { event_id, key, .. }) => self
+                .update_bundled_aggregations(event_id, |target, count, bundled| {
+                    self.bundle_annotations(target, count, bundled, Some((pdu, key, false)))?;
+                    Ok(true)
+                }),
+            _ => Ok(()),
         }
     }
 
-    pub fn relations_until<'a>(
-        &'a self,
-        user_id: &'a UserId,
-        room_id: &'a RoomId,
-        target: &'a EventId,
-        until: PduCount,
-    ) -> Result<impl Iterator<Item = Result<(PduCount, PduEvent)>> + 'a> {
-        let room_id = services().rooms.short.get_or_create_shortroomid(room_id)?;
-        let target = match services().rooms.timeline.get_pdu_count(target)? {
-            Some(PduCount::Normal(c)) => c,
+    /// Runs `f` on the bundled aggregations of the event and stores the event again if `f`
+    /// returns true. `f` gets the event, its count and the bundled aggregations.
+    fn update_bundled_aggregations(
+        &self,
+        target: &EventId,
+        f: impl FnOnce(&PduEvent, u64, &mut CanonicalJsonObject) -> Result<bool>,
+    ) -> Result<()> {
+        let Some(pdu_id) = services().rooms.timeline.get_pdu_id(target)? else {
+            return Ok(());
+        };
+        let Some(PduCount::Normal(count)) = services().rooms.timeline.get_pdu_count(target)? else {
             // TODO: Support backfilled relations
-            _ => 0, // This will result in an empty iterator
+            return Ok(());
         };
-        self.db.relations_until(user_id, room_id, target, until)
+
+        let target_pdu = services()
+            .rooms
+            .timeline
+            .get_pdu_from_id(&pdu_id)?
+            .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
+        let mut target_json = services()
+            .rooms
+            .timeline
+            .get_pdu_json_from_id(&pdu_id)?
+            .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
+
+        let CanonicalJsonValue::Object(unsigned) = target_json
+            .entry("unsigned".to_owned())
+            .or_insert_with(|| CanonicalJsonValue::Object(Default::default()))
+        else {
+            return Ok(());
+        };
+        let CanonicalJsonValue::Object(bundled) = unsigned
+            .entry("m.relations".to_owned())
+            .or_insert_with(|| CanonicalJsonValue::Object(Default::default()))
+        else {
+            return Ok(());
+        };
+
+        if !f(&target_pdu, count, bundled)? {
+            return Ok(());
+        }
+
+        if bundled.is_empty() {
+            unsigned.remove("m.relations");
+        }
+
+        services()
+            .rooms
+            .timeline
+            .replace_pdu(&pdu_id, &target_json, &target_pdu)
+    }
+
+    /// Counts the annotation that was added or removed and bundles the annotation counts of the
+    /// event. Annotations that were sent before the event had its annotations counted are counted
+    /// once from its relations.
+    fn bundle_annotations(
+        &self,
+        target: &PduEvent,
+        count: u64,
+        bundled: &mut CanonicalJsonObject,
+        change: Option<(&PduEvent, &str, bool)>,
+    ) -> Result<()> {
+        if self.db.annotations_counted(count)? {
+            if let Some((pdu, key, added)) = change {
+                self.db
+                    .count_annotation(count, &pdu.kind.to_string(), key, &pdu.sender, added)?;
+            }
+        } else {
+            // The relations already include added annotations and no longer removed ones
+            for (_, pdu) in self
+                .db
+                .relations_until(
+                    &target.sender,
+                    target_shortroomid(target)?,
+                    count,
+                    PduCount::max(),
+                )?
+                .filter_map(Result::ok)
+            {
+                if let Some(Relation::Annotation(Annotation { event_id, key, .. })) =
+                    ExtractRelatesTo::relation(&pdu)
+                {
+                    if *event_id == *target.event_id {
+                        self.db.count_annotation(
+                            count,
+                            &pdu.kind.to_string(),
+                            &key,
+                            &pdu.sender,
+                            true,
+                        )?;
+                    }
+                }
+            }
+            self.db.mark_annotations_counted(count)?;
+        }
+
+        let mut annotations = self.db.annotation_counts(count)?;
+        if annotations.is_empty() {
+            bundled.remove(RelationType::Annotation.as_str());
+        } else {
+            // Most used first
+            annotations.sort_by(|(_, a), (_, b)| b.cmp(a));
+            let chunk: Vec<_> = annotations
+                .into_iter()
+                .map(|((kind, key), count)| json!({ "type": kind, "key": key, "count": count }))
+                .collect();
+            bundled.insert(
+                RelationType::Annotation.to_string(),
+                json!({ "chunk": chunk })
+                    .try_into()
+                    .expect("annotations are valid canonical json"),
+            );
+        }
+
+        Ok(())
     }
 
     #[tracing::instrument(skip(self, room_id, event_ids))]
@@ -179,8 +399,17 @@ impl Service {
     }
 
     #[tracing::instrument(skip(self))]
-    pub fn mark_event_soft_failed(&self, event_id: &EventId) -> Result<()> {
-        self.db.mark_event_soft_failed(event_id)
+    pub fn mark_event_soft_failed(&self, room_id: &RoomId, event_id: &EventId) -> Result<()> {
+        self.db.mark_event_soft_failed(room_id, event_id)
+    }
+
+    /// Returns the soft failed events of a room with the time (in milliseconds since the unix
+    /// epoch) they were soft failed at. Only events soft failed since this was tracked are returned.
+    pub fn soft_failed_events<'a>(
+        &'a self,
+        room_id: &RoomId,
+    ) -> impl Iterator<Item = Result<(OwnedEventId, u64)>> + 'a {
+        self.db.soft_failed_events(room_id)
     }
 
     #[tracing::instrument(skip(self))]
@@ -188,3 +417,185 @@ impl Service {
         self.db.is_event_soft_failed(event_id)
     }
 }
+
+fn target_shortroomid(target: &PduEvent) -> Result<u64> {
+    services()
+        .rooms
+        .short
+        .get_or_create_shortroomid(&target.room_id)
+}
+
+/// Returns true if the event is an edit of the original event that may be bundled with it: it must
+/// be sent by the same user, have the same type and neither of them may be a state event or an
+/// edit itself.
+fn is_valid_edit(original: &PduEvent, edit: &PduEvent) -> bool {
+    let Some(Relation::Replacement(Replacement { event_id, .. })) =
+        ExtractRelatesTo::relation(edit)
+    else {
+        return false;
+    };
+
+    *event_id == *original.event_id
+        && edit.sender == original.sender
+        && edit.kind == original.kind
+        && edit.state_key.is_none()
+        && original.state_key.is_none()
+        && !matches!(
+            ExtractRelatesTo::relation(original),
+            Some(Relation::Replacement(_))
+        )
+}
+
+/// Returns the timestamp and event ID of a bundled edit, which decide which edit is the latest.
+fn edit_order(edit: &CanonicalJsonValue) -> Option<(UInt, String)> {
+    let CanonicalJsonValue::Object(edit) = edit else {
+        return None;
+    };
+    let Some(CanonicalJsonValue::Integer(origin_server_ts)) = edit.get("origin_server_ts") else {
+        return None;
+    };
+    let Some(CanonicalJsonValue::String(event_id)) = edit.get("event_id") else {
+        return None;
+    };
+
+    Some((UInt::try_from(*origin_server_ts).ok()?, event_id.clone()))
+}
+
+/// Bundles the edit as the latest one, or removes the bundled edit if there is none.
+fn bundle_edit(bundled: &mut CanonicalJsonObject, edit: Option<PduEvent>) -> Result<()> {
+    match edit {
+        Some(mut edit) => {
+            edit.remove_transaction_id()?;
+            bundled.insert(
+                RelationType::Replacement.to_string(),
+                to_canonical_value(edit.to_message_like_event())
+                    .expect("event is valid canonical json"),
+            );
+        }
+        None => {
+            bundled.remove(RelationType::Replacement.as_str());
+        }
+    }
+
+    Ok(())
+}
+
+/// Lazy iterator over the relations of an event, including relations of relations. Every event
+/// whose relations are followed gets its own database iterator, and the next items of all of
+/// them are merged in count order.
+pub struct Relations<'a> {
+    db: &'static dyn Data,
+    user_id: &'a UserId,
+    shortroomid: u64,
+    from: PduCount,
+    dir: Direction,
+    max_depth: u8,
+    sources: Vec<Box<dyn Iterator<Item = Result<(PduCount, PduEvent)>> + 'a>>,
+    next: BinaryHeap<NextRelation>,
+}
+
+/// The next relation of one of the sources of `Relations`
+struct NextRelation {
+    count: PduCount,
+    pdu: PduEvent,
+    depth: u8,
+    source: usize,
+    forward: bool,
+    /// Whether the relations of this relation are already being followed
+    expanded: bool,
+}
+
+impl Relations<'_> {
+    /// Starts following the relations of the event with the count, which are `depth` levels below
+    /// the original target.
+    fn add_source(&mut self, target: u64, depth: u8) -> Result<()> {
+        let source = match self.dir {
+            Direction::Forward => {
+                self.db
+                    .relations_after(self.user_id, self.shortroomid, target, self.from)?
+            }
+            Direction::Backward => {
+                self.db
+                    .relations_until(self.user_id, self.shortroomid, target, self.from)?
+            }
+        };
+
+        self.sources.push(source);
+        self.advance(self.sources.len() - 1, depth);
+
+        Ok(())
+    }
+
+    /// Queues the next relation of the source.
+    fn advance(&mut self, source: usize, depth: u8) {
+        if let Some((count, pdu)) = self.sources[source].by_ref().find_map(Result::ok) {
+            self.next.push(NextRelation {
+                count,
+                pdu,
+                depth,
+                source,
+                forward: self.dir == Direction::Forward,
+                expanded: false,
+            });
+        }
+    }
+}
+
+impl Iterator for Relations<'_> {
+    type Item = (PduCount, PduEvent, u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut next = self.next.peek_mut()?;
+
+            // Relations of an event come after it in the timeline, so when going backwards they
+            // have to be found before the event itself is returned
+            if !next.expanded && next.depth < self.max_depth {
+                next.expanded = true;
+                let (count, depth) = (next.count, next.depth);
+                drop(next);
+
+                if let PduCount::Normal(c) = count {
+                    if let Err(e) = self.add_source(c, depth + 1) {
+                        warn!("Failed to load relations of relation {count:?}: {e}");
+                    }
+                }
+                continue;
+            }
+
+            let next = PeekMut::pop(next);
+            self.advance(next.source, next.depth);
+            return Some((next.count, next.pdu, next.depth));
+        }
+    }
+}
+
+impl Ord for NextRelation {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // The heap returns the largest item first, so it has to be the earliest one when going
+        // forwards
+        let ordering = self
+            .count
+            .cmp(&other.count)
+            .then(self.source.cmp(&other.source));
+        if self.forward {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+impl PartialOrd for NextRelation {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for NextRelation {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for NextRelation {}
@@ -0,0 +1,21 @@
+use crate::Result;
+use ruma::{DeviceId, OwnedRoomId, RoomId, UserId};
+
+pub trait Data: Send + Sync {
+    fn add_peek(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        room_id: &RoomId,
+        count: u64,
+    ) -> Result<()>;
+
+    fn remove_peek(&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId) -> Result<()>;
+
+    /// Returns the rooms the device peeks into, with the count at which the peek started.
+    fn peeked_rooms<'a>(
+        &'a self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedRoomId, u64)>> + 'a>;
+}
@@ -0,0 +1,56 @@
+mod data;
+
+pub use data::Data;
+use ruma::{api::client::error::ErrorKind, DeviceId, OwnedRoomId, RoomId, UserId};
+
+use crate::{services, Error, Result};
+
+/// Peeking into rooms with world readable history without joining them (MSC2753). Peeks belong to
+/// a device, the peeked rooms are sent in the `peek` section of its syncs.
+pub struct Service {
+    pub db: &'static dyn Data,
+}
+
+impl Service {
+    /// Starts peeking into a room. Only rooms this server participates in can be peeked into.
+    pub fn peek(&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId) -> Result<()> {
+        if !services()
+            .rooms
+            .state_cache
+            .server_in_room(services().globals.server_name(), room_id)?
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "This server does not know the room.",
+            ));
+        }
+
+        if !services().rooms.state_accessor.is_world_readable(room_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "The history of this room is not world readable.",
+            ));
+        }
+
+        // The next sync of the device will be an initial sync for this room
+        self.db.add_peek(
+            user_id,
+            device_id,
+            room_id,
+            services().globals.next_count()?,
+        )
+    }
+
+    pub fn unpeek(&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId) -> Result<()> {
+        self.db.remove_peek(user_id, device_id, room_id)
+    }
+
+    /// Returns the rooms the device peeks into, with the count at which the peek started.
+    pub fn peeked_rooms<'a>(
+        &'a self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> impl Iterator<Item = Result<(OwnedRoomId, u64)>> + 'a {
+        self.db.peeked_rooms(user_id, device_id)
+    }
+}
@@ -105,6 +105,8 @@ impl Service {
         self.db
             .set_room_state(room_id, shortstatehash, state_lock)?;
 
+        services().rooms.directory.update_room(room_id)?;
+
         Ok(())
     }
 
@@ -318,7 +320,10 @@ impl Service {
         shortstatehash: u64,
         mutex_lock: &MutexGuard<'_, ()>, // Take mutex guard to make sure users get the room state mutex
     ) -> Result<()> {
-        self.db.set_room_state(room_id, shortstatehash, mutex_lock)
+        self.db
+            .set_room_state(room_id, shortstatehash, mutex_lock)?;
+
+        services().rooms.directory.update_room(room_id)
     }
 
     /// Returns the room's version.
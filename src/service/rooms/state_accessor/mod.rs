@@ -221,8 +221,13 @@ impl Service {
     /// the room's history_visibility at that event's state.
     #[tracing::instrument(skip(self, user_id, room_id))]
     pub fn user_can_see_state_events(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
-        let currently_member = services().rooms.state_cache.is_joined(user_id, room_id)?;
+        Ok(services().rooms.state_cache.is_joined(user_id, room_id)?
+            || self.is_world_readable(room_id)?)
+    }
 
+    /// Whether anyone can read the room without joining it, based on its current
+    /// history_visibility.
+    pub fn is_world_readable(&self, room_id: &RoomId) -> Result<bool> {
         let history_visibility = self
             .room_state_get(room_id, &StateEventType::RoomHistoryVisibility, "")?
             .map_or(Ok(HistoryVisibility::Shared), |s| {
@@ -233,7 +238,7 @@ impl Service {
                     })
             })?;
 
-        Ok(currently_member || history_visibility == HistoryVisibility::WorldReadable)
+        Ok(history_visibility == HistoryVisibility::WorldReadable)
     }
 
     /// Returns the state hash for this pdu.
@@ -7,7 +7,6 @@ use ruma::{
     api::appservice::Registration,
     events::{
         direct::DirectEvent,
-        ignored_user_list::IgnoredUserListEvent,
         room::{
             create::RoomCreateEventContent,
             member::{MembershipState, RoomMemberEventContent},
@@ -18,6 +17,7 @@ use ruma::{
     serde::Raw,
     OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
 };
+use serde::Deserialize;
 use tracing::warn;
 
 use crate::{services, Error, Result};
@@ -183,32 +183,11 @@ impl Service {
                 self.db.mark_as_joined(user_id, room_id)?;
             }
             MembershipState::Invite => {
-                // We want to know if the sender is ignored by the receiver
-                let is_ignored = services()
-                    .account_data
-                    .get(
-                        None,    // Ignored users are in global account data
-                        user_id, // Receiver
-                        GlobalAccountDataEventType::IgnoredUserList
-                            .to_string()
-                            .into(),
-                    )?
-                    .map(|event| {
-                        serde_json::from_str::<IgnoredUserListEvent>(event.get()).map_err(|e| {
-                            warn!("Invalid account data event in db: {e:?}");
-                            Error::BadDatabase("Invalid account data event in db.")
-                        })
-                    })
-                    .transpose()?
-                    .map_or(false, |ignored| {
-                        ignored
-                            .content
-                            .ignored_users
-                            .iter()
-                            .any(|(user, _details)| user == sender)
-                    });
-
-                if is_ignored {
+                // Invites of ignored users and refused invites are dropped
+                if services().users.user_is_ignored(sender, user_id)?
+                    || (user_id.server_name() == services().globals.server_name()
+                        && !services().users.is_invite_allowed(sender, user_id)?)
+                {
                     return Ok(());
                 }
 
@@ -349,6 +328,27 @@ impl Service {
         self.db.invite_state(user_id, room_id)
     }
 
+    /// Returns the sender of the pending invite of the user, as recorded in the invite state.
+    pub fn invite_sender(&self, user_id: &UserId, room_id: &RoomId) -> Result<Option<OwnedUserId>> {
+        #[derive(Deserialize)]
+        struct ExtractMember {
+            #[serde(rename = "type")]
+            kind: StateEventType,
+            state_key: String,
+            sender: OwnedUserId,
+        }
+
+        Ok(self
+            .invite_state(user_id, room_id)?
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|event| event.deserialize_as::<ExtractMember>().ok())
+            .find(|event| {
+                event.kind == StateEventType::RoomMember && event.state_key == user_id.as_str()
+            })
+            .map(|event| event.sender))
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn left_state(
         &self,
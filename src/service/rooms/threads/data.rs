@@ -4,6 +4,8 @@ use ruma::{api::client::threads::get_threads::v1::IncludeThreads, OwnedUserId, R
 type PduEventIterResult<'a> = Result<Box<dyn Iterator<Item = Result<(u64, PduEvent)>> + 'a>>;
 
 pub trait Data: Send + Sync {
+    /// Returns the threads of the room ordered by their latest activity, most recent first,
+    /// together with the count of that activity.
     fn threads_until<'a>(
         &'a self,
         user_id: &'a UserId,
@@ -13,5 +15,6 @@ pub trait Data: Send + Sync {
     ) -> PduEventIterResult<'a>;
 
     fn update_participants(&self, root_id: &[u8], participants: &[OwnedUserId]) -> Result<()>;
+    fn update_thread_activity(&self, root_id: &[u8], count: u64) -> Result<()>;
     fn get_participants(&self, root_id: &[u8]) -> Result<Option<Vec<OwnedUserId>>>;
 }
@@ -4,11 +4,9 @@ pub use data::Data;
 use ruma::{
     api::client::{error::ErrorKind, threads::get_threads::v1::IncludeThreads},
     events::relation::BundledThread,
-    uint, CanonicalJsonValue, EventId, RoomId, UserId,
+    uint, CanonicalJsonObject, CanonicalJsonValue, EventId, RoomId, UserId,
 };
 
-use serde_json::json;
-
 use crate::{services, Error, PduEvent, Result};
 
 pub struct Service {
@@ -26,7 +24,9 @@ impl Service {
         self.db.threads_until(user_id, room_id, until, include)
     }
 
-    pub fn add_to_thread(&self, root_event_id: &EventId, pdu: &PduEvent) -> Result<()> {
+    /// Adds a reply to a thread. `count` is the count of the reply, which is the latest activity
+    /// of the thread.
+    pub fn add_to_thread(&self, root_event_id: &EventId, pdu: &PduEvent, count: u64) -> Result<()> {
         let root_id = &services()
             .rooms
             .timeline
@@ -70,14 +70,7 @@ impl Service {
                 relations.count += uint!(1);
                 relations.latest_event = pdu.to_message_like_event();
 
-                let content = serde_json::to_value(relations).expect("to_value always works");
-
-                unsigned.insert(
-                    "m.relations".to_owned(),
-                    json!({ "m.thread": content })
-                        .try_into()
-                        .expect("thread is valid json"),
-                );
+                insert_bundled_thread(unsigned, relations);
             } else {
                 // New thread
                 let relations = BundledThread {
@@ -86,14 +79,7 @@ impl Service {
                     current_user_participated: true,
                 };
 
-                let content = serde_json::to_value(relations).expect("to_value always works");
-
-                unsigned.insert(
-                    "m.relations".to_owned(),
-                    json!({ "m.thread": content })
-                        .try_into()
-                        .expect("thread is valid json"),
-                );
+                insert_bundled_thread(unsigned, relations);
             }
 
             services()
@@ -111,6 +97,23 @@ impl Service {
             users.push(pdu.sender.clone());
         }
 
-        self.db.update_participants(root_id, &users)
+        self.db.update_participants(root_id, &users)?;
+        self.db.update_thread_activity(root_id, count)
+    }
+}
+
+/// Bundles the thread summary in the `m.relations` of the root, next to its other aggregations.
+fn insert_bundled_thread(unsigned: &mut CanonicalJsonObject, thread: BundledThread) {
+    if let CanonicalJsonValue::Object(bundled) = unsigned
+        .entry("m.relations".to_owned())
+        .or_insert_with(|| CanonicalJsonValue::Object(Default::default()))
+    {
+        bundled.insert(
+            "m.thread".to_owned(),
+            serde_json::to_value(thread)
+                .expect("to_value always works")
+                .try_into()
+                .expect("thread is valid json"),
+        );
     }
 }
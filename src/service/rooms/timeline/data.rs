@@ -75,9 +75,11 @@ pub trait Data: Send + Sync {
     /// in chronological order.
     fn pdus_after<'a>(&'a self, user_id: &UserId, room_id: &RoomId, from: PduCount) -> PduData<'a>;
 
+    /// Increments the counts of the room and, for events in a thread, of the thread.
     fn increment_notification_counts(
         &self,
         room_id: &RoomId,
+        thread_id: Option<&EventId>,
         notifies: Vec<OwnedUserId>,
         highlights: Vec<OwnedUserId>,
     ) -> Result<()>;
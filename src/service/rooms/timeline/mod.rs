@@ -25,12 +25,13 @@ use ruma::{
         },
         GlobalAccountDataEventType, StateEventType, TimelineEventType,
     },
-    push::{Action, Ruleset, Tweak},
+    push::{Action, Tweak},
     serde::Base64,
     state_res,
     state_res::{Event, RoomVersion},
-    uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedEventId, OwnedRoomId,
-    OwnedServerName, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
+    uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch,
+    OwnedEventId, OwnedRoomId, OwnedServerName, RoomAliasId, RoomId, RoomVersionId, ServerName,
+    UserId,
 };
 use serde::Deserialize;
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
@@ -39,11 +40,14 @@ use tracing::{error, info, warn};
 
 use crate::{
     api::server_server,
-    service::pdu::{EventHash, PduBuilder},
+    service::{
+        pdu::{EventHash, PduBuilder},
+        rooms::user::LoggedNotification,
+    },
     services, utils, Error, PduEvent, Result,
 };
 
-use super::state_compressor::CompressedStateEvent;
+use super::{pdu_metadata::ExtractRelatesTo, state_compressor::CompressedStateEvent};
 
 #[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
 pub enum PduCount {
@@ -290,15 +294,7 @@ impl Service {
             .state
             .set_forward_extremities(&pdu.room_id, leaves, state_lock)?;
 
-        let mutex_insert = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_insert
-                .write()
-                .unwrap()
-                .entry(pdu.room_id.clone())
-                .or_default(),
-        );
+        let mutex_insert = services().globals.roomid_mutex_insert.mutex(&pdu.room_id);
         let insert_lock = mutex_insert.lock().await;
 
         let count1 = services().globals.next_count()?;
@@ -364,6 +360,11 @@ impl Service {
                 continue;
             }
 
+            // Don't notify the user of events of users they ignore, like invites or calls
+            if services().users.user_is_ignored(&pdu.sender, user)? {
+                continue;
+            }
+
             let rules_for_user = services()
                 .account_data
                 .get(
@@ -379,18 +380,20 @@ impl Service {
                 })
                 .transpose()?
                 .map(|ev: PushRulesEvent| ev.content.global)
-                .unwrap_or_else(|| Ruleset::server_default(user));
+                .unwrap_or_else(|| services().globals.default_push_ruleset(user));
 
             let mut highlight = false;
             let mut notify = false;
 
-            for action in services().pusher.get_actions(
+            let actions = services().pusher.get_actions(
                 user,
                 &rules_for_user,
                 &power_levels,
                 &sync_pdu,
                 &pdu.room_id,
-            )? {
+            )?;
+
+            for action in actions {
                 match action {
                     Action::Notify => notify = true,
                     Action::SetTweak(Tweak::Highlight(true)) => {
@@ -402,6 +405,18 @@ impl Service {
 
             if notify {
                 notifies.push(user.clone());
+
+                services().rooms.user.add_notification(
+                    user,
+                    count2,
+                    &LoggedNotification {
+                        room_id: pdu.room_id.clone(),
+                        event_id: pdu.event_id.as_ref().to_owned(),
+                        actions: actions.to_vec(),
+                        highlight,
+                        ts: MilliSecondsSinceUnixEpoch::now(),
+                    },
+                )?;
             }
 
             if highlight {
@@ -413,8 +428,20 @@ impl Service {
             }
         }
 
-        self.db
-            .increment_notification_counts(&pdu.room_id, notifies, highlights)?;
+        // Notifications of events in a thread are also counted for the thread (MSC3773)
+        let thread_id = match serde_json::from_str::<ExtractRelatesTo>(pdu.content.get()) {
+            Ok(ExtractRelatesTo {
+                relates_to: Relation::Thread(thread),
+            }) => Some(thread.event_id),
+            _ => None,
+        };
+
+        self.db.increment_notification_counts(
+            &pdu.room_id,
+            thread_id.as_deref(),
+            notifies,
+            highlights,
+        )?;
 
         match pdu.kind {
             TimelineEventType::RoomRedaction => {
@@ -546,12 +573,6 @@ impl Service {
         }
 
         // Update Relationships
-        #[derive(Deserialize)]
-        struct ExtractRelatesTo {
-            #[serde(rename = "m.relates_to")]
-            relates_to: Relation,
-        }
-
         #[derive(Clone, Debug, Deserialize)]
         struct ExtractEventId {
             event_id: OwnedEventId,
@@ -575,8 +596,8 @@ impl Service {
             }
         }
 
-        if let Ok(content) = serde_json::from_str::<ExtractRelatesTo>(pdu.content.get()) {
-            match content.relates_to {
+        if let Some(relation) = ExtractRelatesTo::relation(pdu) {
+            match &relation {
                 Relation::Reply { in_reply_to } => {
                     // We need to do it again here, because replies don't have
                     // event_id as a top level field
@@ -595,7 +616,13 @@ impl Service {
                     services()
                         .rooms
                         .threads
-                        .add_to_thread(&thread.event_id, pdu)?;
+                        .add_to_thread(&thread.event_id, pdu, count2)?;
+                }
+                Relation::Replacement(_) | Relation::Annotation(_) => {
+                    services()
+                        .rooms
+                        .pdu_metadata
+                        .add_to_bundled_aggregations(&relation, pdu)?;
                 }
                 _ => {} // TODO: Aggregate other types
             }
@@ -792,12 +819,13 @@ impl Service {
             signatures: None,
         };
 
-        let auth_check = state_res::auth_check(
-            &room_version,
-            &pdu,
-            None::<PduEvent>, // TODO: third_party_invite
-            |k, s| auth_events.get(&(k.clone(), s.to_owned())),
-        )
+        let third_party_invite = pdu
+            .third_party_invite_token()
+            .and_then(|token| auth_events.get(&(StateEventType::RoomThirdPartyInvite, token)));
+
+        let auth_check = state_res::auth_check(&room_version, &pdu, third_party_invite, |k, s| {
+            auth_events.get(&(k.clone(), s.to_owned()))
+        })
         .map_err(|e| {
             error!("Auth check failed: {:?}", e);
             Error::bad_database("Auth check failed.")
@@ -1098,6 +1126,10 @@ impl Service {
             let mut pdu = self
                 .get_pdu_from_id(&pdu_id)?
                 .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
+
+            // The relation is lost in the redaction
+            let relation = ExtractRelatesTo::relation(&pdu);
+
             let room_version_id = services().rooms.state.get_room_version(&pdu.room_id)?;
             pdu.redact(room_version_id, reason)?;
             self.replace_pdu(
@@ -1108,6 +1140,13 @@ impl Service {
                 })?,
                 &pdu,
             )?;
+
+            if let Some(relation) = relation {
+                services()
+                    .rooms
+                    .pdu_metadata
+                    .remove_from_bundled_aggregations(&relation, &pdu)?;
+            }
         }
         // If event does not exist, just noop
         Ok(())
@@ -1188,15 +1227,7 @@ impl Service {
         let (event_id, value, room_id) = server_server::parse_incoming_pdu(&pdu)?;
 
         // Lock so we cannot backfill the same pdu twice at the same time
-        let mutex = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_federation
-                .write()
-                .unwrap()
-                .entry(room_id.to_owned())
-                .or_default(),
-        );
+        let mutex = services().globals.roomid_mutex_federation.mutex(&room_id);
         let mutex_lock = mutex.lock().await;
 
         // Skip the PDU if we already have it as a timeline event
@@ -1226,15 +1257,7 @@ impl Service {
             .get_shortroomid(&room_id)?
             .expect("room exists");
 
-        let mutex_insert = Arc::clone(
-            services()
-                .globals
-                .roomid_mutex_insert
-                .write()
-                .unwrap()
-                .entry(room_id.clone())
-                .or_default(),
-        );
+        let mutex_insert = services().globals.roomid_mutex_insert.mutex(&room_id);
         let insert_lock = mutex_insert.lock().await;
 
         let count = services().globals.next_count()?;
@@ -1,9 +1,27 @@
+use super::LoggedNotification;
 use crate::Result;
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
 
 pub trait Data: Send + Sync {
     fn reset_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()>;
 
+    /// Resets the counts of the main timeline, the notifications in threads stay unread
+    fn reset_main_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()>;
+
+    fn reset_thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        thread_id: &EventId,
+    ) -> Result<()>;
+
+    /// Returns the notification and highlight counts of every thread with unread notifications
+    fn thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Vec<(OwnedEventId, u64, u64)>>;
+
     fn notification_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
 
     fn highlight_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
@@ -11,6 +29,28 @@ pub trait Data: Send + Sync {
     // Returns the count at which the last reset_notification_counts was called
     fn last_notification_read(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
 
+    // Returns the count at which any of the notification counts was last reset
+    fn last_notification_update(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
+
+    /// Adds a notification to the notification log of the user. The count is the one of the pdu
+    fn add_notification(
+        &self,
+        user_id: &UserId,
+        count: u64,
+        notification: &LoggedNotification,
+    ) -> Result<()>;
+
+    /// Removes logged notifications of all users that are older than the timestamp and returns
+    /// how many were removed.
+    fn remove_notifications_before(&self, until: u64) -> Result<usize>;
+
+    /// Returns the logged notifications of the user older than `until`, newest first
+    fn notifications_until<'a>(
+        &'a self,
+        user_id: &UserId,
+        until: u64,
+    ) -> Box<dyn Iterator<Item = Result<(u64, LoggedNotification)>> + 'a>;
+
     fn associate_token_shortstatehash(
         &self,
         room_id: &RoomId,
@@ -1,9 +1,25 @@
 mod data;
 
 pub use data::Data;
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use ruma::{
+    push::Action, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId,
+    RoomId, UserId,
+};
+use serde::{Deserialize, Serialize};
 
-use crate::Result;
+use tracing::debug;
+
+use crate::{services, utils, Result};
+
+/// An event that notified a user, as recorded by the push rule evaluation
+#[derive(Deserialize, Serialize)]
+pub struct LoggedNotification {
+    pub room_id: OwnedRoomId,
+    pub event_id: OwnedEventId,
+    pub actions: Vec<Action>,
+    pub highlight: bool,
+    pub ts: MilliSecondsSinceUnixEpoch,
+}
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -14,6 +30,28 @@ impl Service {
         self.db.reset_notification_counts(user_id, room_id)
     }
 
+    pub fn reset_main_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        self.db.reset_main_notification_counts(user_id, room_id)
+    }
+
+    pub fn reset_thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        thread_id: &EventId,
+    ) -> Result<()> {
+        self.db
+            .reset_thread_notification_counts(user_id, room_id, thread_id)
+    }
+
+    pub fn thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Vec<(OwnedEventId, u64, u64)>> {
+        self.db.thread_notification_counts(user_id, room_id)
+    }
+
     pub fn notification_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
         self.db.notification_count(user_id, room_id)
     }
@@ -26,6 +64,40 @@ impl Service {
         self.db.last_notification_read(user_id, room_id)
     }
 
+    pub fn last_notification_update(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
+        self.db.last_notification_update(user_id, room_id)
+    }
+
+    pub fn add_notification(
+        &self,
+        user_id: &UserId,
+        count: u64,
+        notification: &LoggedNotification,
+    ) -> Result<()> {
+        self.db.add_notification(user_id, count, notification)
+    }
+
+    /// Removes logged notifications that are older than `notification_log_max_age_s`.
+    pub fn remove_expired_notifications(&self) -> Result<()> {
+        let max_age = services().globals.notification_log_max_age_s();
+        let until = utils::millis_since_unix_epoch().saturating_sub(max_age.saturating_mul(1000));
+
+        let removed = self.db.remove_notifications_before(until)?;
+        if removed > 0 {
+            debug!("Removed {removed} expired notifications");
+        }
+
+        Ok(())
+    }
+
+    pub fn notifications_until<'a>(
+        &'a self,
+        user_id: &UserId,
+        until: u64,
+    ) -> impl Iterator<Item = Result<(u64, LoggedNotification)>> + 'a {
+        self.db.notifications_until(user_id, until)
+    }
+
     pub fn associate_token_shortstatehash(
         &self,
         room_id: &RoomId,
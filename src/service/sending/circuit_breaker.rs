@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Write},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use ruma::{OwnedServerName, ServerName};
+
+/// Number of consecutive failed requests after which we stop sending requests to a destination
+const FAILURE_THRESHOLD: u32 = 3;
+
+/// Backoff after the circuit opened for the first time, doubled for every further failure
+const BASE_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long a trial request may take before another one is allowed in the half-open state
+const TRIAL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Copy, Debug)]
+pub enum CircuitState {
+    /// Requests are sent normally
+    Closed,
+    /// Requests fail immediately until the backoff is over
+    Open { until: Instant },
+    /// The backoff is over and a single trial request is in flight
+    HalfOpen { since: Instant },
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CircuitState::Closed => write!(f, "closed"),
+            CircuitState::Open { until } => write!(
+                f,
+                "open (retrying in {}s)",
+                until.saturating_duration_since(Instant::now()).as_secs()
+            ),
+            CircuitState::HalfOpen { .. } => write!(f, "half-open"),
+        }
+    }
+}
+
+struct Breaker {
+    state: CircuitState,
+    failures: u32, // consecutive failed requests
+}
+
+/// Per-destination circuit breaker for outgoing federation requests.
+///
+/// After `FAILURE_THRESHOLD` consecutive failures the circuit opens and requests to the destination
+/// fail immediately for a jittered, exponentially growing backoff capped at
+/// `federation_max_retry_interval_s`. Afterwards a single trial request is let through, which
+/// closes the circuit again on success or reopens it on failure.
+pub struct CircuitBreaker {
+    breakers: Mutex<HashMap<OwnedServerName, Breaker>>,
+    max_retry_interval: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(max_retry_interval: Duration) -> Self {
+        Self {
+            breakers: Mutex::new(HashMap::new()),
+            max_retry_interval,
+        }
+    }
+
+    /// Returns true if a request to the destination may be sent now. Moves an open circuit whose
+    /// backoff is over to half-open, so the caller's request is the trial request.
+    pub fn allow_request(&self, destination: &ServerName) -> bool {
+        let mut breakers = self.breakers.lock().unwrap();
+        let Some(breaker) = breakers.get_mut(destination) else {
+            return true;
+        };
+
+        match breaker.state {
+            CircuitState::Closed => true,
+            CircuitState::Open { until } if Instant::now() >= until => {
+                breaker.state = CircuitState::HalfOpen {
+                    since: Instant::now(),
+                };
+                true
+            }
+            CircuitState::HalfOpen { since } if since.elapsed() >= TRIAL_TIMEOUT => {
+                // The trial request never finished, try another one
+                breaker.state = CircuitState::HalfOpen {
+                    since: Instant::now(),
+                };
+                true
+            }
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => false,
+        }
+    }
+
+    /// Like `allow_request`, but returns a guard that records the outcome of the request. The
+    /// request counts as failed if the guard is dropped without recording a success, so returning
+    /// early can never leave a trial request in flight.
+    pub fn start_request<'a>(&'a self, destination: &'a ServerName) -> Option<RequestGuard<'a>> {
+        self.allow_request(destination).then_some(RequestGuard {
+            breaker: self,
+            destination,
+            done: false,
+        })
+    }
+
+    /// Returns true if the circuit of the destination is open and its backoff is not over yet.
+    /// Unlike `allow_request`, this never starts a trial request.
+    pub fn is_open(&self, destination: &ServerName) -> bool {
+        matches!(
+            self.breakers.lock().unwrap().get(destination),
+            Some(Breaker {
+                state: CircuitState::Open { until },
+                ..
+            }) if Instant::now() < *until
+        )
+    }
+
+    /// Records a successful request, closing the circuit.
+    pub fn record_success(&self, destination: &ServerName) {
+        self.breakers.lock().unwrap().remove(destination);
+    }
+
+    /// Records a failed request, opening the circuit if the destination keeps failing.
+    pub fn record_failure(&self, destination: &ServerName) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(destination.to_owned()).or_insert(Breaker {
+            state: CircuitState::Closed,
+            failures: 0,
+        });
+
+        breaker.failures = breaker.failures.saturating_add(1);
+
+        if breaker.failures >= FAILURE_THRESHOLD {
+            breaker.state = CircuitState::Open {
+                until: Instant::now() + self.backoff(breaker.failures),
+            };
+        }
+    }
+
+    /// Forgets all failures of the destination.
+    pub fn reset(&self, destination: &ServerName) -> bool {
+        self.breakers.lock().unwrap().remove(destination).is_some()
+    }
+
+    /// Returns the circuit state and number of consecutive failures of the destination.
+    pub fn state(&self, destination: &ServerName) -> (CircuitState, u32) {
+        self.breakers
+            .lock()
+            .unwrap()
+            .get(destination)
+            .map_or((CircuitState::Closed, 0), |breaker| {
+                (breaker.state, breaker.failures)
+            })
+    }
+
+    /// Returns all destinations that have failed recently.
+    pub fn failing_destinations(&self) -> Vec<(OwnedServerName, CircuitState, u32)> {
+        self.breakers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(destination, breaker)| (destination.clone(), breaker.state, breaker.failures))
+            .collect()
+    }
+
+    /// Returns the circuit state (0 closed, 1 half-open, 2 open) and consecutive failures of the
+    /// failing destinations in the Prometheus text format. Destinations that are not listed are
+    /// closed.
+    pub fn render(&self) -> String {
+        let mut destinations = self.failing_destinations();
+        destinations.sort_by(|(a, ..), (b, ..)| a.cmp(b));
+
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP conduwuit_federation_circuit_state Circuit breaker state of a destination, 0 \
+             is closed, 1 half-open and 2 open\n",
+        );
+        out.push_str("# TYPE conduwuit_federation_circuit_state gauge\n");
+        for (destination, state, _) in &destinations {
+            let state = match state {
+                CircuitState::Closed => 0,
+                CircuitState::HalfOpen { .. } => 1,
+                CircuitState::Open { .. } => 2,
+            };
+            writeln!(
+                out,
+                "conduwuit_federation_circuit_state{{destination=\"{destination}\"}} {state}"
+            )
+            .unwrap();
+        }
+
+        out.push_str(
+            "# HELP conduwuit_federation_consecutive_failures Failed requests to a destination \
+             since the last successful one\n",
+        );
+        out.push_str("# TYPE conduwuit_federation_consecutive_failures gauge\n");
+        for (destination, _, failures) in &destinations {
+            writeln!(
+                out,
+                "conduwuit_federation_consecutive_failures{{destination=\"{destination}\"}} {failures}"
+            )
+            .unwrap();
+        }
+
+        out
+    }
+
+    fn backoff(&self, failures: u32) -> Duration {
+        let exponent = (failures - FAILURE_THRESHOLD).min(16);
+        let backoff = (BASE_BACKOFF * 2_u32.pow(exponent)).min(self.max_retry_interval);
+
+        // Spread out retries so destinations that failed at the same time don't retry in lockstep
+        backoff
+            .mul_f64(rand::thread_rng().gen_range(0.8..1.2))
+            .min(self.max_retry_interval)
+    }
+}
+
+/// Records the outcome of a request allowed by `CircuitBreaker::start_request`.
+pub struct RequestGuard<'a> {
+    breaker: &'a CircuitBreaker,
+    destination: &'a ServerName,
+    done: bool,
+}
+
+impl RequestGuard<'_> {
+    pub fn success(mut self) {
+        self.done = true;
+        self.breaker.record_success(self.destination);
+    }
+
+    pub fn failure(mut self) {
+        self.done = true;
+        self.breaker.record_failure(self.destination);
+    }
+}
+
+impl Drop for RequestGuard<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.breaker.record_failure(self.destination);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::server_name;
+
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn opens_after_threshold() {
+        let breaker = CircuitBreaker::new(HOUR);
+        let destination = server_name!("example.com");
+
+        for _ in 1..FAILURE_THRESHOLD {
+            breaker.record_failure(destination);
+            assert!(breaker.allow_request(destination));
+            assert!(matches!(
+                breaker.state(destination),
+                (CircuitState::Closed, _)
+            ));
+        }
+
+        breaker.record_failure(destination);
+        assert!(breaker.is_open(destination));
+        assert!(!breaker.allow_request(destination));
+        assert!(matches!(
+            breaker.state(destination),
+            (CircuitState::Open { .. }, FAILURE_THRESHOLD)
+        ));
+    }
+
+    #[test]
+    fn lets_one_trial_request_through_after_backoff() {
+        // The backoff is capped at zero, so it is over immediately
+        let breaker = CircuitBreaker::new(Duration::ZERO);
+        let destination = server_name!("example.com");
+
+        for _ in 0..FAILURE_THRESHOLD {
+            breaker.record_failure(destination);
+        }
+        assert!(!breaker.is_open(destination));
+
+        assert!(breaker.allow_request(destination));
+        assert!(matches!(
+            breaker.state(destination),
+            (CircuitState::HalfOpen { .. }, _)
+        ));
+        assert!(!breaker.allow_request(destination));
+    }
+
+    #[test]
+    fn trial_success_closes_circuit() {
+        let breaker = CircuitBreaker::new(Duration::ZERO);
+        let destination = server_name!("example.com");
+
+        for _ in 0..FAILURE_THRESHOLD {
+            breaker.record_failure(destination);
+        }
+        assert!(breaker.allow_request(destination));
+
+        breaker.record_success(destination);
+        assert!(matches!(
+            breaker.state(destination),
+            (CircuitState::Closed, 0)
+        ));
+        assert!(breaker.failing_destinations().is_empty());
+    }
+
+    #[test]
+    fn trial_failure_reopens_circuit() {
+        let breaker = CircuitBreaker::new(HOUR);
+        let destination = server_name!("example.com");
+
+        for _ in 0..FAILURE_THRESHOLD {
+            breaker.record_failure(destination);
+        }
+        breaker
+            .breakers
+            .lock()
+            .unwrap()
+            .get_mut(destination)
+            .unwrap()
+            .state = CircuitState::HalfOpen {
+            since: Instant::now(),
+        };
+
+        breaker.record_failure(destination);
+        assert!(breaker.is_open(destination));
+        assert_eq!(breaker.state(destination).1, FAILURE_THRESHOLD + 1);
+    }
+
+    #[test]
+    fn dropped_request_counts_as_failure() {
+        let breaker = CircuitBreaker::new(Duration::ZERO);
+        let destination = server_name!("example.com");
+
+        for _ in 0..FAILURE_THRESHOLD {
+            breaker.record_failure(destination);
+        }
+
+        // The trial request returns early without recording anything
+        drop(breaker.start_request(destination).unwrap());
+        assert_eq!(breaker.state(destination).1, FAILURE_THRESHOLD + 1);
+
+        breaker.start_request(destination).unwrap().success();
+        assert!(matches!(
+            breaker.state(destination),
+            (CircuitState::Closed, 0)
+        ));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let breaker = CircuitBreaker::new(HOUR);
+
+        assert!(breaker.backoff(FAILURE_THRESHOLD) <= BASE_BACKOFF.mul_f64(1.2));
+        assert!(breaker.backoff(u32::MAX) <= HOUR);
+    }
+
+    #[test]
+    fn renders_failing_destinations() {
+        let breaker = CircuitBreaker::new(HOUR);
+        breaker.record_failure(server_name!("a.example.com"));
+        for _ in 0..FAILURE_THRESHOLD {
+            breaker.record_failure(server_name!("b.example.com"));
+        }
+
+        let rendered = breaker.render();
+        assert!(rendered
+            .contains("conduwuit_federation_circuit_state{destination=\"a.example.com\"} 0\n"));
+        assert!(rendered
+            .contains("conduwuit_federation_circuit_state{destination=\"b.example.com\"} 2\n"));
+        assert!(rendered.contains(
+            "conduwuit_federation_consecutive_failures{destination=\"b.example.com\"} 3\n"
+        ));
+    }
+}
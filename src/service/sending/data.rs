@@ -1,8 +1,8 @@
-use ruma::ServerName;
+use ruma::{OwnedServerName, ServerName};
 
 use crate::Result;
 
-use super::{OutgoingKind, SendingEventType};
+use super::{DestinationStats, OutgoingKind, SendingEventType};
 
 type OutgoingSendingIter<'a> =
     Box<dyn Iterator<Item = Result<(Vec<u8>, OutgoingKind, SendingEventType)>> + 'a>;
@@ -25,4 +25,13 @@ pub trait Data: Send + Sync {
     fn mark_as_active(&self, events: &[(SendingEventType, Vec<u8>)]) -> Result<()>;
     fn set_latest_educount(&self, server_name: &ServerName, educount: u64) -> Result<()>;
     fn get_latest_educount(&self, server_name: &ServerName) -> Result<u64>;
+    fn destination_stats(&self, server_name: &ServerName) -> Result<Option<DestinationStats>>;
+    fn set_destination_stats(
+        &self,
+        server_name: &ServerName,
+        stats: &DestinationStats,
+    ) -> Result<()>;
+    fn all_destination_stats<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedServerName, DestinationStats)>> + 'a>;
 }
@@ -1,17 +1,23 @@
+mod circuit_breaker;
 mod data;
+mod request_metrics;
 
+pub use circuit_breaker::{CircuitBreaker, CircuitState};
 pub use data::Data;
 use ipaddress::IPAddress;
+pub use request_metrics::{DestinationRequestMetrics, RequestMetrics};
 
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
-    fmt::Debug,
-    sync::Arc,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::{Debug, Write},
+    mem,
+    sync::{Arc, Mutex as StdMutex},
     time::{Duration, Instant},
 };
 
 use crate::{
     api::{appservice_server, server_server},
+    service::rooms::timeline::PduCount,
     services,
     utils::calculate_hash,
     Config, Error, PduEvent, Result,
@@ -23,7 +29,7 @@ use base64::{engine::general_purpose, Engine as _};
 
 use ruma::{
     api::{
-        appservice::{self, Registration},
+        appservice::{self, event::push_events::v1::DeviceLists, Registration},
         federation::{
             self,
             transactions::edu::{
@@ -35,17 +41,25 @@ use ruma::{
     },
     device_id,
     events::{
-        push_rules::PushRulesEvent, receipt::ReceiptType, AnySyncEphemeralRoomEvent,
-        GlobalAccountDataEventType,
+        push_rules::PushRulesEvent, receipt::ReceiptType, room::member::MembershipState,
+        AnySyncEphemeralRoomEvent, GlobalAccountDataEventType, TimelineEventType,
     },
-    push, uint, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedUserId, ServerName, UInt, UserId,
+    uint, DeviceKeyAlgorithm, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedServerName,
+    OwnedUserId, RoomId, ServerName, UInt, UserId,
 };
+use serde::{Deserialize, Serialize};
 use tokio::{
     select,
     sync::{mpsc, Mutex, Semaphore},
 };
 use tracing::{debug, error, info, warn};
 
+/// Maximum number of PDUs in a single federation transaction, as defined by the spec
+const MAX_PDUS_PER_TRANSACTION: usize = 50;
+
+/// Maximum number of EDUs in a single federation transaction, as defined by the spec
+const MAX_EDUS_PER_TRANSACTION: usize = 100;
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum OutgoingKind {
     Appservice(String),
@@ -94,6 +108,24 @@ pub struct Service {
     pub(super) maximum_requests: Arc<Semaphore>,
     pub sender: mpsc::UnboundedSender<(OutgoingKind, SendingEventType, Vec<u8>)>,
     receiver: Mutex<mpsc::UnboundedReceiver<(OutgoingKind, SendingEventType, Vec<u8>)>>,
+
+    /// Destinations that should get a transaction with just EDUs, see `flush_room_edus`
+    edu_flush_sender: mpsc::UnboundedSender<OwnedServerName>,
+    edu_flush_receiver: Mutex<mpsc::UnboundedReceiver<OwnedServerName>>,
+    pending_edu_flushes: StdMutex<HashSet<OwnedServerName>>,
+    edu_flush_delay: Duration,
+
+    /// Failed appservice transactions that are due for a retry
+    retry_sender: mpsc::UnboundedSender<OutgoingKind>,
+    retry_receiver: Mutex<mpsc::UnboundedReceiver<OutgoingKind>>,
+
+    current_transaction_status: StdMutex<HashMap<OutgoingKind, TransactionStatus>>,
+    destinations: StdMutex<HashMap<OwnedServerName, DestinationInfo>>,
+
+    /// Shared by the transaction sender and all other outgoing federation requests
+    pub circuit_breaker: CircuitBreaker,
+    /// Latency, status codes and retries of all outgoing federation requests
+    pub request_metrics: RequestMetrics,
 }
 
 enum TransactionStatus {
@@ -102,14 +134,58 @@ enum TransactionStatus {
     Retrying(u32),        // number of times failed
 }
 
+/// What we know about the transaction currently sent to a destination
+#[derive(Default)]
+struct DestinationInfo {
+    transaction_id: Option<String>, // transaction currently in flight
+}
+
+/// Statistics of the transactions sent to a federation destination, kept across restarts
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct DestinationStats {
+    pub last_success: Option<MilliSecondsSinceUnixEpoch>,
+    pub last_failure: Option<MilliSecondsSinceUnixEpoch>,
+    pub consecutive_failures: u32,
+    pub transactions: u64,
+    pub failed_transactions: u64,
+    /// Moving average of the round trip time of successful transactions
+    pub average_rtt_ms: Option<u64>,
+}
+
+/// Snapshot of the send queue of a federation destination
+pub struct DestinationStatus {
+    pub queued_pdus: usize,
+    pub queued_edus: usize,
+    pub active_events: usize,
+    pub running: bool,
+    pub circuit: CircuitState,
+    pub failures: u32,
+    pub transaction_id: Option<String>,
+    pub stats: DestinationStats,
+}
+
 impl Service {
     pub fn build(db: &'static dyn Data, config: &Config) -> Arc<Self> {
         let (sender, receiver) = mpsc::unbounded_channel();
+        let (edu_flush_sender, edu_flush_receiver) = mpsc::unbounded_channel();
+        let (retry_sender, retry_receiver) = mpsc::unbounded_channel();
         Arc::new(Self {
             db,
             sender,
             receiver: Mutex::new(receiver),
             maximum_requests: Arc::new(Semaphore::new(config.max_concurrent_requests as usize)),
+            edu_flush_sender,
+            edu_flush_receiver: Mutex::new(edu_flush_receiver),
+            pending_edu_flushes: StdMutex::new(HashSet::new()),
+            edu_flush_delay: Duration::from_millis(config.read_receipt_coalesce_window_ms),
+            retry_sender,
+            retry_receiver: Mutex::new(retry_receiver),
+            current_transaction_status: StdMutex::new(HashMap::new()),
+            destinations: StdMutex::new(HashMap::new()),
+            circuit_breaker: CircuitBreaker::new(Duration::from_secs(
+                config.federation_max_retry_interval_s,
+            )),
+            request_metrics: RequestMetrics::default(),
         })
     }
 
@@ -122,11 +198,11 @@ impl Service {
 
     async fn handler(&self) -> Result<()> {
         let mut receiver = self.receiver.lock().await;
+        let mut edu_flush_receiver = self.edu_flush_receiver.lock().await;
+        let mut retry_receiver = self.retry_receiver.lock().await;
 
         let mut futures = FuturesUnordered::new();
 
-        let mut current_transaction_status = HashMap::<OutgoingKind, TransactionStatus>::new();
-
         // Retry requests we could not finish yet
         let mut initial_transactions = HashMap::<OutgoingKind, Vec<SendingEventType>>::new();
 
@@ -135,7 +211,16 @@ impl Service {
                 .entry(outgoing_kind.clone())
                 .or_default();
 
-            if entry.len() > 30 {
+            let limit = match event {
+                SendingEventType::Pdu(_) => MAX_PDUS_PER_TRANSACTION,
+                SendingEventType::Edu(_) => MAX_EDUS_PER_TRANSACTION,
+            };
+            if entry
+                .iter()
+                .filter(|e| mem::discriminant(*e) == mem::discriminant(&event))
+                .count()
+                >= limit
+            {
                 warn!(
                     "Dropping some current events: {:?} {:?} {:?}",
                     key, outgoing_kind, event
@@ -148,7 +233,10 @@ impl Service {
         }
 
         for (outgoing_kind, events) in initial_transactions {
-            current_transaction_status.insert(outgoing_kind.clone(), TransactionStatus::Running);
+            self.current_transaction_status
+                .lock()
+                .unwrap()
+                .insert(outgoing_kind.clone(), TransactionStatus::Running);
             futures.push(Self::handle_events(outgoing_kind.clone(), events));
         }
 
@@ -159,25 +247,47 @@ impl Service {
                         Ok(outgoing_kind) => {
                             self.db.delete_all_active_requests_for(&outgoing_kind)?;
 
+                            if let OutgoingKind::Normal(server_name) = &outgoing_kind {
+                                if let Some(info) = self.destinations.lock().unwrap().get_mut(server_name) {
+                                    info.transaction_id = None;
+                                }
+                            }
+
                             // Find events that have been added since starting the last request
-                            let new_events = self.db.queued_requests(&outgoing_kind).filter_map(|r| r.ok()).take(30).collect::<Vec<_>>();
+                            let new_events = self.take_queued_requests(&outgoing_kind);
 
                             if !new_events.is_empty() {
                                 // Insert pdus we found
                                 self.db.mark_as_active(&new_events)?;
 
-                                futures.push(
-                                    Self::handle_events(
-                                        outgoing_kind.clone(),
-                                        new_events.into_iter().map(|(event, _)| event).collect(),
-                                    )
-                                );
+                                let mut events: Vec<_> = new_events.into_iter().map(|(event, _)| event).collect();
+
+                                if let OutgoingKind::Normal(server_name) = &outgoing_kind {
+                                    self.add_transaction_edus(server_name, &mut events)?;
+                                }
+
+                                futures.push(Self::handle_events(outgoing_kind.clone(), events));
                             } else {
-                                current_transaction_status.remove(&outgoing_kind);
+                                self.current_transaction_status.lock().unwrap().remove(&outgoing_kind);
                             }
                         }
                         Err((outgoing_kind, _)) => {
-                            current_transaction_status.entry(outgoing_kind).and_modify(|e| *e = match e {
+                            if let OutgoingKind::Normal(server_name) = &outgoing_kind {
+                                if let Some(info) = self.destinations.lock().unwrap().get_mut(server_name) {
+                                    info.transaction_id = None;
+                                }
+                            }
+
+                            // Transactions of only EDUs leave nothing to retry, the EDUs are
+                            // selected again for the next transaction
+                            let idle = self.db.active_requests_for(&outgoing_kind).next().is_none();
+
+                            let mut current_transaction_status = self.current_transaction_status.lock().unwrap();
+                            if idle {
+                                current_transaction_status.remove(&outgoing_kind);
+                                continue;
+                            }
+                            current_transaction_status.entry(outgoing_kind.clone()).and_modify(|e| *e = match e {
                                 TransactionStatus::Running => TransactionStatus::Failed(1, Instant::now()),
                                 TransactionStatus::Retrying(n) => TransactionStatus::Failed(*n+1, Instant::now()),
                                 TransactionStatus::Failed(_, _) => {
@@ -185,48 +295,338 @@ impl Service {
                                     return
                                 },
                             });
+
+                            // Appservices often fail because the bridge restarts, so retry on our
+                            // own instead of waiting for the next event
+                            if let (OutgoingKind::Appservice(_), Some(TransactionStatus::Failed(tries, _))) =
+                                (&outgoing_kind, current_transaction_status.get(&outgoing_kind))
+                            {
+                                let delay = appservice_backoff_duration(*tries);
+                                let retry_sender = self.retry_sender.clone();
+                                let retry_kind = outgoing_kind.clone();
+                                tokio::spawn(async move {
+                                    tokio::time::sleep(delay).await;
+                                    let _ = retry_sender.send(retry_kind);
+                                });
+                            }
                         }
                     };
                 },
                 Some((outgoing_kind, event, key)) = receiver.recv() => {
-                    if let Ok(Some(events)) = self.select_events(
-                        &outgoing_kind,
-                        vec![(event, key)],
-                        &mut current_transaction_status,
-                    ) {
+                    if let Ok(Some(events)) = self.select_events(&outgoing_kind, vec![(event, key)]) {
                         futures.push(Self::handle_events(outgoing_kind, events));
                     }
                 }
+                Some(outgoing_kind) = retry_receiver.recv() => {
+                    if let Ok(Some(events)) = self.select_events(&outgoing_kind, Vec::new()) {
+                        if events.is_empty() {
+                            self.current_transaction_status.lock().unwrap().remove(&outgoing_kind);
+                        } else {
+                            futures.push(Self::handle_events(outgoing_kind, events));
+                        }
+                    }
+                }
+                Some(server_name) = edu_flush_receiver.recv() => {
+                    let outgoing_kind = OutgoingKind::Normal(server_name);
+
+                    // If a transaction is running, the EDUs go out with the next one instead
+                    if let Ok(Some(events)) = self.select_events(&outgoing_kind, Vec::new()) {
+                        if events.is_empty() {
+                            self.current_transaction_status.lock().unwrap().remove(&outgoing_kind);
+                        } else {
+                            futures.push(Self::handle_events(outgoing_kind, events));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends pending EDUs (like read receipts) to the servers in the room without waiting for the
+    /// next PDU.
+    ///
+    /// The transaction is delayed by `read_receipt_coalesce_window_ms`, so that all EDUs for a
+    /// destination within that window are combined into a single transaction.
+    pub fn flush_room_edus(&self, room_id: &RoomId) -> Result<()> {
+        for server_name in services().rooms.state_cache.room_servers(room_id) {
+            let server_name = server_name?;
+
+            if server_name == services().globals.server_name()
+                || !self
+                    .pending_edu_flushes
+                    .lock()
+                    .unwrap()
+                    .insert(server_name.clone())
+            {
+                continue;
+            }
+
+            let delay = self.edu_flush_delay;
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+
+                let sending = &services().sending;
+                sending
+                    .pending_edu_flushes
+                    .lock()
+                    .unwrap()
+                    .remove(&server_name);
+                let _ = sending.edu_flush_sender.send(server_name);
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the backoff for a destination and immediately retries sending its queue.
+    ///
+    /// Returns false if the destination was not backing off.
+    pub fn retry_destination(&self, server_name: &ServerName) -> bool {
+        let was_backing_off = self.circuit_breaker.reset(server_name);
+
+        let _ = self.edu_flush_sender.send(server_name.to_owned());
+
+        was_backing_off
+    }
+
+    /// Returns the destinations we have sent to, are sending to, or have events queued for.
+    pub fn known_destinations(&self) -> Vec<OwnedServerName> {
+        let mut destinations: HashSet<_> =
+            self.destinations.lock().unwrap().keys().cloned().collect();
+
+        destinations.extend(
+            self.current_transaction_status
+                .lock()
+                .unwrap()
+                .keys()
+                .filter_map(|kind| match kind {
+                    OutgoingKind::Normal(server_name) => Some(server_name.clone()),
+                    _ => None,
+                }),
+        );
+
+        destinations.extend(
+            self.circuit_breaker
+                .failing_destinations()
+                .into_iter()
+                .map(|(server_name, _, _)| server_name),
+        );
+
+        destinations.extend(self.db.active_requests().filter_map(|r| match r {
+            Ok((_, OutgoingKind::Normal(server_name), _)) => Some(server_name),
+            _ => None,
+        }));
+
+        let mut destinations: Vec<_> = destinations.into_iter().collect();
+        destinations.sort();
+        destinations
+    }
+
+    /// Returns the current state of the send queue for a federation destination.
+    pub fn destination_status(&self, server_name: &ServerName) -> DestinationStatus {
+        let outgoing_kind = OutgoingKind::Normal(server_name.to_owned());
+
+        let (mut queued_pdus, mut queued_edus) = (0, 0);
+        for (event, _) in self
+            .db
+            .queued_requests(&outgoing_kind)
+            .filter_map(|r| r.ok())
+        {
+            match event {
+                SendingEventType::Pdu(_) => queued_pdus += 1,
+                SendingEventType::Edu(_) => queued_edus += 1,
+            }
+        }
+
+        let active_events = self.db.active_requests_for(&outgoing_kind).count();
+
+        let running = matches!(
+            self.current_transaction_status
+                .lock()
+                .unwrap()
+                .get(&outgoing_kind),
+            Some(TransactionStatus::Running | TransactionStatus::Retrying(_))
+        );
+
+        let (circuit, failures) = self.circuit_breaker.state(server_name);
+
+        let transaction_id = self
+            .destinations
+            .lock()
+            .unwrap()
+            .get(server_name)
+            .and_then(|info| info.transaction_id.clone());
+
+        let stats = self
+            .db
+            .destination_stats(server_name)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+
+        DestinationStatus {
+            queued_pdus,
+            queued_edus,
+            active_events,
+            running,
+            circuit,
+            failures,
+            transaction_id,
+            stats,
+        }
+    }
+
+    /// Returns the transaction statistics of all destinations we ever sent a transaction to.
+    pub fn all_destination_stats(&self) -> Vec<(OwnedServerName, DestinationStats)> {
+        self.db
+            .all_destination_stats()
+            .filter_map(|r| r.ok())
+            .collect()
+    }
+
+    /// Returns the transaction statistics of all destinations as gauges in the Prometheus text
+    /// format.
+    pub fn render_destination_stats(&self) -> String {
+        let mut destinations = self.all_destination_stats();
+        destinations.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP conduwuit_federation_destination_last_success_timestamp_seconds Time of the \
+             last successful transaction to a destination\n",
+        );
+        out.push_str(
+            "# TYPE conduwuit_federation_destination_last_success_timestamp_seconds gauge\n",
+        );
+        for (destination, stats) in &destinations {
+            if let Some(last_success) = stats.last_success {
+                writeln!(
+                    out,
+                    "conduwuit_federation_destination_last_success_timestamp_seconds{{destination=\"{destination}\"}} {}",
+                    u64::from(last_success.get()) as f64 / 1000.0
+                )
+                .unwrap();
+            }
+        }
+
+        out.push_str(
+            "# HELP conduwuit_federation_destination_consecutive_failed_transactions Failed \
+             transactions to a destination since the last successful one\n",
+        );
+        out.push_str(
+            "# TYPE conduwuit_federation_destination_consecutive_failed_transactions gauge\n",
+        );
+        for (destination, stats) in &destinations {
+            writeln!(
+                out,
+                "conduwuit_federation_destination_consecutive_failed_transactions{{destination=\"{destination}\"}} {}",
+                stats.consecutive_failures
+            )
+            .unwrap();
+        }
+
+        out.push_str(
+            "# HELP conduwuit_federation_destination_average_rtt_milliseconds Moving average of \
+             the round trip time of successful transactions to a destination\n",
+        );
+        out.push_str("# TYPE conduwuit_federation_destination_average_rtt_milliseconds gauge\n");
+        for (destination, stats) in &destinations {
+            if let Some(average_rtt_ms) = stats.average_rtt_ms {
+                writeln!(
+                    out,
+                    "conduwuit_federation_destination_average_rtt_milliseconds{{destination=\"{destination}\"}} {average_rtt_ms}"
+                )
+                .unwrap();
             }
         }
+
+        out
+    }
+
+    /// Updates the statistics of a destination after a transaction was sent to it.
+    fn record_transaction(&self, server_name: &ServerName, success: bool, rtt: Duration) {
+        let mut stats = self
+            .db
+            .destination_stats(server_name)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+
+        stats.transactions += 1;
+        if success {
+            let rtt_ms = u64::try_from(rtt.as_millis()).unwrap_or(u64::MAX);
+            stats.last_success = Some(MilliSecondsSinceUnixEpoch::now());
+            stats.consecutive_failures = 0;
+            stats.average_rtt_ms = Some(match stats.average_rtt_ms {
+                Some(average) => average.saturating_mul(7).saturating_add(rtt_ms) / 8,
+                None => rtt_ms,
+            });
+        } else {
+            stats.last_failure = Some(MilliSecondsSinceUnixEpoch::now());
+            stats.consecutive_failures += 1;
+            stats.failed_transactions += 1;
+        }
+
+        if let Err(e) = self.db.set_destination_stats(server_name, &stats) {
+            warn!("Failed to store the transaction statistics of {server_name}: {e}");
+        }
     }
 
-    #[tracing::instrument(skip(self, outgoing_kind, new_events, current_transaction_status))]
+    /// Starts a transaction to the destination with the new events, or retries its previous
+    /// transaction. Returns `None` if a transaction is already running or the destination is
+    /// backing off.
+    #[tracing::instrument(skip(self, outgoing_kind, new_events))]
     fn select_events(
         &self,
         outgoing_kind: &OutgoingKind,
         new_events: Vec<(SendingEventType, Vec<u8>)>, // Events we want to send: event and full key
-        current_transaction_status: &mut HashMap<OutgoingKind, TransactionStatus>,
     ) -> Result<Option<Vec<SendingEventType>>> {
+        let Some(retry) = self.start_transaction(outgoing_kind) else {
+            return Ok(None);
+        };
+
+        // The status lock is not held while reading the database
+        let events = self.transaction_events(outgoing_kind, new_events, retry);
+        if events.is_err() {
+            self.current_transaction_status
+                .lock()
+                .unwrap()
+                .remove(outgoing_kind);
+        }
+
+        events.map(Some)
+    }
+
+    /// Marks a transaction to the destination as running. Returns whether the previous
+    /// transaction is retried, or `None` if a transaction is already running or the destination
+    /// is backing off.
+    fn start_transaction(&self, outgoing_kind: &OutgoingKind) -> Option<bool> {
         let mut retry = false;
         let mut allow = true;
 
-        let entry = current_transaction_status.entry(outgoing_kind.clone());
-
-        entry
+        self.current_transaction_status
+            .lock()
+            .unwrap()
+            .entry(outgoing_kind.clone())
             .and_modify(|e| match e {
                 TransactionStatus::Running | TransactionStatus::Retrying(_) => {
                     allow = false; // already running
                 }
                 TransactionStatus::Failed(tries, time) => {
-                    // Fail if a request has failed recently (exponential backoff)
-                    let mut min_elapsed_duration =
-                        Duration::from_secs(5 * 60) * (*tries) * (*tries);
-                    if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
-                        min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
-                    }
+                    // Fail if a request has failed recently (exponential backoff). Federation
+                    // destinations are backed off by the circuit breaker instead.
+                    let backing_off = match outgoing_kind {
+                        OutgoingKind::Normal(server_name) => {
+                            self.circuit_breaker.is_open(server_name)
+                        }
+                        OutgoingKind::Appservice(_) => {
+                            time.elapsed() < appservice_backoff_duration(*tries)
+                        }
+                        OutgoingKind::Push(_, _) => time.elapsed() < backoff_duration(*tries),
+                    };
 
-                    if time.elapsed() < min_elapsed_duration {
+                    if backing_off {
                         allow = false;
                     } else {
                         retry = true;
@@ -236,13 +636,24 @@ impl Service {
             })
             .or_insert(TransactionStatus::Running);
 
-        if !allow {
-            return Ok(None);
-        }
+        allow.then_some(retry)
+    }
 
+    /// Returns the events of a transaction that was just started: the events of the previous
+    /// transaction when retrying, otherwise the new events and the pending EDUs.
+    fn transaction_events(
+        &self,
+        outgoing_kind: &OutgoingKind,
+        new_events: Vec<(SendingEventType, Vec<u8>)>,
+        retry: bool,
+    ) -> Result<Vec<SendingEventType>> {
         let mut events = Vec::new();
 
         if retry {
+            if let OutgoingKind::Normal(server_name) = outgoing_kind {
+                self.request_metrics.record_retry(server_name);
+            }
+
             // We retry the previous transaction
             for (_, e) in self
                 .db
@@ -258,40 +669,118 @@ impl Service {
             }
 
             if let OutgoingKind::Normal(server_name) = outgoing_kind {
-                if let Ok((select_edus, last_count)) = self.select_edus(server_name) {
-                    events.extend(select_edus.into_iter().map(SendingEventType::Edu));
+                self.add_transaction_edus(server_name, &mut events)?;
+            }
+        }
 
-                    self.db.set_latest_educount(server_name, last_count)?;
-                }
+        Ok(events)
+    }
+
+    /// Takes queued events for the next transaction, in order, up to the per-transaction PDU and
+    /// EDU limits. Events over the limits stay queued for a later transaction.
+    fn take_queued_requests(
+        &self,
+        outgoing_kind: &OutgoingKind,
+    ) -> Vec<(SendingEventType, Vec<u8>)> {
+        let mut pdus = 0;
+        let mut edus = 0;
+        let mut events = Vec::new();
+
+        for (event, key) in self
+            .db
+            .queued_requests(outgoing_kind)
+            .filter_map(|r| r.ok())
+        {
+            if pdus >= MAX_PDUS_PER_TRANSACTION && edus >= MAX_EDUS_PER_TRANSACTION {
+                break;
+            }
+
+            match event {
+                SendingEventType::Pdu(_) if pdus < MAX_PDUS_PER_TRANSACTION => pdus += 1,
+                SendingEventType::Edu(_) if edus < MAX_EDUS_PER_TRANSACTION => edus += 1,
+                _ => continue,
             }
+
+            events.push((event, key));
         }
 
-        Ok(Some(events))
+        events
     }
 
+    /// Fills the remaining EDU slots of a transaction with receipts, device list updates and
+    /// presence for the destination. Queued EDUs (to-device messages) always come first.
+    fn add_transaction_edus(
+        &self,
+        server_name: &ServerName,
+        events: &mut Vec<SendingEventType>,
+    ) -> Result<()> {
+        let queued_edus = events
+            .iter()
+            .filter(|e| matches!(e, SendingEventType::Edu(_)))
+            .count();
+
+        // More events are waiting than fit into this transaction
+        let backlogged = self
+            .db
+            .queued_requests(&OutgoingKind::Normal(server_name.to_owned()))
+            .next()
+            .is_some();
+
+        if let Ok((select_edus, last_count)) = self.select_edus(
+            server_name,
+            MAX_EDUS_PER_TRANSACTION.saturating_sub(queued_edus),
+            backlogged,
+        ) {
+            events.extend(select_edus.into_iter().map(SendingEventType::Edu));
+
+            self.db.set_latest_educount(server_name, last_count)?;
+        }
+
+        Ok(())
+    }
+
+    /// Selects up to `max_edus` EDUs for the destination since the last transaction.
+    ///
+    /// Read receipts (combined into one EDU) take priority over device list updates, which take
+    /// priority over presence.
+    /// Only the latest presence of each user is sent, and presence is skipped entirely while the
+    /// destination is `backlogged`, since it would be stale by the time it arrives.
     #[tracing::instrument(skip(self, server_name))]
-    pub fn select_edus(&self, server_name: &ServerName) -> Result<(Vec<Vec<u8>>, u64)> {
+    pub fn select_edus(
+        &self,
+        server_name: &ServerName,
+        max_edus: usize,
+        backlogged: bool,
+    ) -> Result<(Vec<Vec<u8>>, u64)> {
         // u64: count of last edu
         let since = self.db.get_latest_educount(server_name)?;
         let mut events = Vec::new();
         let mut max_edu_count = since;
-        let mut device_list_changes = HashSet::new();
+        // Earliest and latest change of each user, a user changing their keys repeatedly gets one
+        // update
+        let mut device_list_changes = HashMap::<OwnedUserId, (u64, u64)>::new();
+        let mut presence_updates = HashMap::<OwnedUserId, (u64, PresenceUpdate)>::new();
+        let mut receipts = BTreeMap::new();
 
-        'outer: for room_id in services().rooms.state_cache.server_rooms(server_name) {
+        for room_id in services().rooms.state_cache.server_rooms(server_name) {
             let room_id = room_id?;
             // Look for device list updates in this room
-            device_list_changes.extend(
-                services()
-                    .users
-                    .keys_changed(room_id.as_ref(), since, None)
-                    .filter_map(|r| r.ok())
-                    .filter(|user_id| user_id.server_name() == services().globals.server_name()),
-            );
+            for (count, user_id) in services()
+                .users
+                .keys_changed_with_counts(room_id.as_ref(), since, None)
+                .filter_map(|r| r.ok())
+            {
+                if user_id.server_name() != services().globals.server_name() {
+                    continue;
+                }
+
+                let (first, latest) = device_list_changes.entry(user_id).or_insert((count, count));
+                *first = (*first).min(count);
+                *latest = (*latest).max(count);
+            }
 
             if services().globals.allow_outgoing_presence() {
                 // Look for presence updates in this room
-                let mut presence_updates = Vec::new();
-
                 for (user_id, count, presence_event) in services()
                     .rooms
                     .edus
@@ -302,23 +791,37 @@ impl Service {
                         max_edu_count = count;
                     }
 
-                    if user_id.server_name() != services().globals.server_name() {
+                    if user_id.server_name() != services().globals.server_name() || backlogged {
                         continue;
                     }
 
-                    presence_updates.push(PresenceUpdate {
-                        user_id,
-                        presence: presence_event.content.presence,
-                        currently_active: presence_event.content.currently_active.unwrap_or(false),
-                        last_active_ago: presence_event.content.last_active_ago.unwrap_or(uint!(0)),
-                        status_msg: presence_event.content.status_msg,
-                    });
-                }
+                    if presence_updates
+                        .get(&user_id)
+                        .is_some_and(|(latest, _)| *latest >= count)
+                    {
+                        continue;
+                    }
 
-                let presence_content = Edu::Presence(PresenceContent::new(presence_updates));
-                events.push(
-                    serde_json::to_vec(&presence_content).expect("PresenceEvent can be serialized"),
-                );
+                    presence_updates.insert(
+                        user_id.clone(),
+                        (
+                            count,
+                            PresenceUpdate {
+                                user_id,
+                                presence: presence_event.content.presence,
+                                currently_active: presence_event
+                                    .content
+                                    .currently_active
+                                    .unwrap_or(false),
+                                last_active_ago: presence_event
+                                    .content
+                                    .last_active_ago
+                                    .unwrap_or(uint!(0)),
+                                status_msg: presence_event.content.status_msg,
+                            },
+                        ),
+                    );
+                }
             }
 
             // Look for read receipts in this room
@@ -341,10 +844,8 @@ impl Service {
                 let event: AnySyncEphemeralRoomEvent =
                     serde_json::from_str(read_receipt.json().get())
                         .map_err(|_| Error::bad_database("Invalid edu event in read_receipts."))?;
-                let federation_event = match event {
+                match event {
                     AnySyncEphemeralRoomEvent::Receipt(r) => {
-                        let mut read = BTreeMap::new();
-
                         let (event_id, mut receipt) = r
                             .content
                             .0
@@ -357,36 +858,47 @@ impl Service {
                             .remove(&user_id)
                             .expect("our read receipts always have the user here");
 
-                        read.insert(
-                            user_id,
-                            ReceiptData {
-                                data: receipt.clone(),
-                                event_ids: vec![event_id.clone()],
-                            },
-                        );
-
-                        let receipt_map = ReceiptMap { read };
-
-                        let mut receipts = BTreeMap::new();
-                        receipts.insert(room_id.clone(), receipt_map);
-
-                        Edu::Receipt(ReceiptContent { receipts })
+                        // Later receipts of the same user replace earlier ones
+                        receipts
+                            .entry(room_id.clone())
+                            .or_insert_with(|| ReceiptMap {
+                                read: BTreeMap::new(),
+                            })
+                            .read
+                            .insert(
+                                user_id,
+                                ReceiptData {
+                                    data: receipt.clone(),
+                                    event_ids: vec![event_id.clone()],
+                                },
+                            );
                     }
                     _ => {
                         Error::bad_database("Invalid event type in read_receipts");
                         continue;
                     }
-                };
-
-                events.push(serde_json::to_vec(&federation_event).expect("json can be serialized"));
-
-                if events.len() >= 20 {
-                    break 'outer;
                 }
             }
         }
 
-        for user_id in device_list_changes {
+        // All read receipts for the destination go into a single EDU
+        if !receipts.is_empty() && events.len() < max_edus {
+            let federation_event = Edu::Receipt(ReceiptContent { receipts });
+            events.push(serde_json::to_vec(&federation_event).expect("json can be serialized"));
+        }
+
+        // One update per user is enough, as the remote resyncs all devices of the user anyway.
+        // Users whose updates don't fit into this transaction keep the EDU count before their
+        // first change, so they are sent with the next one.
+        let mut device_list_changes: Vec<_> = device_list_changes.into_iter().collect();
+        device_list_changes.sort_unstable_by_key(|(_, (first, _))| *first);
+        for (user_id, (first, latest)) in device_list_changes {
+            if events.len() >= max_edus {
+                max_edu_count = max_edu_count.min(first - 1);
+                continue;
+            }
+            max_edu_count = max_edu_count.max(latest);
+
             // Empty prev id forces synapse to resync: https://github.com/matrix-org/synapse/blob/98aec1cc9da2bd6b8e34ffb282c85abf9b8b42ca/synapse/handlers/device.py#L767
             // Because synapse resyncs, we can just insert dummy data
             let edu = Edu::DeviceListUpdate(DeviceListUpdateContent {
@@ -402,6 +914,18 @@ impl Service {
             events.push(serde_json::to_vec(&edu).expect("json can be serialized"));
         }
 
+        if !presence_updates.is_empty() && events.len() < max_edus {
+            let presence_content = Edu::Presence(PresenceContent::new(
+                presence_updates
+                    .into_values()
+                    .map(|(_, update)| update)
+                    .collect(),
+            ));
+            events.push(
+                serde_json::to_vec(&presence_content).expect("PresenceEvent can be serialized"),
+            );
+        }
+
         Ok((events, max_edu_count))
     }
 
@@ -518,32 +1042,55 @@ impl Service {
                     }
                 }
 
+                // Transaction IDs count up, so the appservice can tell a retried transaction apart
+                // from a new one, even after a restart
+                let txn_id = services()
+                    .appservice
+                    .last_txn_id(id)
+                    .map_err(|e| (kind.clone(), e))?
+                    + 1;
+
+                let registration = services()
+                    .appservice
+                    .get_registration(id)
+                    .map_err(|e| (kind.clone(), e))?
+                    .ok_or_else(|| {
+                        (
+                            kind.clone(),
+                            Error::bad_database(
+                                "[Appservice] Could not load registration from db.",
+                            ),
+                        )
+                    })?;
+
+                // Only appservices that opted into MSC3202 get device lists and key counts
+                let device_list_since = services()
+                    .appservice
+                    .device_list_count(id)
+                    .map_err(|e| (kind.clone(), e))?;
+                let device_list_count = services()
+                    .globals
+                    .current_count()
+                    .map_err(|e| (kind.clone(), e))?;
+                let (device_lists, device_one_time_keys_count) = match device_list_since {
+                    Some(since) => {
+                        appservice_device_data(id, &registration, since, device_list_count)
+                            .map_err(|e| (kind.clone(), e))?
+                    }
+                    None => Default::default(),
+                };
+
                 let permit = services().sending.maximum_requests.acquire().await;
 
                 let response = match appservice_server::send_request(
-                    services()
-                        .appservice
-                        .get_registration(id)
-                        .map_err(|e| (kind.clone(), e))?
-                        .ok_or_else(|| {
-                            (
-                                kind.clone(),
-                                Error::bad_database(
-                                    "[Appservice] Could not load registration from db.",
-                                ),
-                            )
-                        })?,
+                    registration,
                     appservice::event::push_events::v1::Request {
                         events: pdu_jsons,
-                        txn_id: (&*general_purpose::URL_SAFE_NO_PAD.encode(calculate_hash(
-                            &events
-                                .iter()
-                                .map(|e| match e {
-                                    SendingEventType::Edu(b) | SendingEventType::Pdu(b) => &**b,
-                                })
-                                .collect::<Vec<_>>(),
-                        )))
-                            .into(),
+                        txn_id: txn_id.to_string().into(),
+                        device_lists,
+                        device_one_time_keys_count,
+                        // Fallback keys are not yet supported
+                        device_unused_fallback_key_types: BTreeMap::new(),
                     },
                 )
                 .await
@@ -556,6 +1103,19 @@ impl Service {
 
                 drop(permit);
 
+                if response.is_ok() {
+                    services()
+                        .appservice
+                        .set_last_txn_id(id, txn_id)
+                        .map_err(|e| (kind.clone(), e))?;
+                    if device_list_since.is_some() {
+                        services()
+                            .appservice
+                            .set_device_list_count(id, device_list_count)
+                            .map_err(|e| (kind.clone(), e))?;
+                    }
+                }
+
                 response
             }
             OutgoingKind::Push(userid, pushkey) => {
@@ -616,7 +1176,7 @@ impl Service {
                         .unwrap_or_default()
                         .and_then(|event| serde_json::from_str::<PushRulesEvent>(event.get()).ok())
                         .map(|ev: PushRulesEvent| ev.content.global)
-                        .unwrap_or_else(|| push::Ruleset::server_default(userid));
+                        .unwrap_or_else(|| services().globals.default_push_ruleset(userid));
 
                     let unread: UInt = services()
                         .rooms
@@ -672,8 +1232,27 @@ impl Service {
                     }
                 }
 
+                let transaction_id = general_purpose::URL_SAFE_NO_PAD.encode(calculate_hash(
+                    &events
+                        .iter()
+                        .map(|e| match e {
+                            SendingEventType::Edu(b) | SendingEventType::Pdu(b) => &**b,
+                        })
+                        .collect::<Vec<_>>(),
+                ));
+
+                services()
+                    .sending
+                    .destinations
+                    .lock()
+                    .unwrap()
+                    .entry(server.clone())
+                    .or_default()
+                    .transaction_id = Some(transaction_id.clone());
+
                 let permit = services().sending.maximum_requests.acquire().await;
 
+                let started = Instant::now();
                 let response = server_server::send_request(
                     server,
                     send_transaction_message::v1::Request {
@@ -681,17 +1260,7 @@ impl Service {
                         pdus: pdu_jsons,
                         edus: edu_jsons,
                         origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
-                        transaction_id: (&*general_purpose::URL_SAFE_NO_PAD.encode(
-                            calculate_hash(
-                                &events
-                                    .iter()
-                                    .map(|e| match e {
-                                        SendingEventType::Edu(b) | SendingEventType::Pdu(b) => &**b,
-                                    })
-                                    .collect::<Vec<_>>(),
-                            ),
-                        ))
-                            .into(),
+                        transaction_id: (&*transaction_id).into(),
                     },
                 )
                 .await
@@ -707,6 +1276,10 @@ impl Service {
 
                 drop(permit);
 
+                services()
+                    .sending
+                    .record_transaction(server, response.is_ok(), started.elapsed());
+
                 response
             }
         }
@@ -768,6 +1341,71 @@ impl Service {
         response
     }
 
+    /// Like `send_federation_request`, but with a custom timeout and a limit on the size of the
+    /// response body. Used for requests whose responses can be arbitrarily large, like media.
+    #[tracing::instrument(skip(self, destination, request))]
+    pub async fn send_federation_request_limited<T: OutgoingRequest>(
+        &self,
+        destination: &ServerName,
+        request: T,
+        max_body_size: u64,
+        timeout: Duration,
+    ) -> Result<T::IncomingResponse>
+    where
+        T: Debug,
+    {
+        debug!("Waiting for permit");
+        let permit = self.maximum_requests.acquire().await;
+        debug!("Got permit");
+        let response = tokio::time::timeout(
+            timeout,
+            server_server::send_request_limited(destination, request, Some(max_body_size)),
+        )
+        .await
+        .map_err(|_| {
+            warn!(
+                "Timeout after {} seconds waiting for server response of {destination}",
+                timeout.as_secs()
+            );
+            Error::BadServerResponse("Timeout waiting for server response")
+        })?;
+        drop(permit);
+
+        response
+    }
+
+    /// Like `send_federation_request`, but returns a successful response without reading its body,
+    /// so it can be streamed. The timeout only covers waiting for the response headers.
+    #[tracing::instrument(skip(self, destination, request))]
+    pub async fn send_federation_request_streamed<T: OutgoingRequest>(
+        &self,
+        destination: &ServerName,
+        request: T,
+        timeout: Duration,
+    ) -> Result<reqwest::Response>
+    where
+        T: Debug,
+    {
+        debug!("Waiting for permit");
+        let permit = self.maximum_requests.acquire().await;
+        debug!("Got permit");
+        let response = tokio::time::timeout(
+            timeout,
+            server_server::send_request_streamed(destination, request),
+        )
+        .await
+        .map_err(|_| {
+            warn!(
+                "Timeout after {} seconds waiting for server response of {destination}",
+                timeout.as_secs()
+            );
+            Error::BadServerResponse("Timeout waiting for server response")
+        })?;
+        drop(permit);
+
+        response
+    }
+
     /// Sends a request to an appservice
     ///
     /// Only returns None if there is no url specified in the appservice registration file
@@ -786,3 +1424,137 @@ impl Service {
         response
     }
 }
+
+type OneTimeKeyCounts =
+    BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, BTreeMap<DeviceKeyAlgorithm, UInt>>>;
+
+/// Collects the device list changes since the last transaction and the one-time key counts for the
+/// users of an appservice that have devices, so encrypted bridges can manage their devices
+/// (MSC3202). Only the rooms of those users are looked at, and only the changes since `since`.
+fn appservice_device_data(
+    id: &str,
+    registration: &Registration,
+    since: u64,
+    until: u64,
+) -> Result<(DeviceLists, OneTimeKeyCounts)> {
+    let users = services().appservice.device_users(id, registration)?;
+
+    let mut one_time_keys_count = BTreeMap::new();
+    let mut rooms = HashSet::new();
+    let mut changed = BTreeSet::new();
+
+    for user_id in &users {
+        let mut counts = BTreeMap::new();
+        for device_id in services()
+            .users
+            .all_device_ids(user_id)
+            .filter_map(|r| r.ok())
+        {
+            let count = services().users.count_one_time_keys(user_id, &device_id)?;
+            counts.insert(device_id, count);
+        }
+        one_time_keys_count.insert(user_id.clone(), counts);
+
+        rooms.extend(
+            services()
+                .rooms
+                .state_cache
+                .rooms_joined(user_id)
+                .filter_map(|r| r.ok()),
+        );
+
+        if since < until {
+            changed.extend(
+                services()
+                    .users
+                    .keys_changed(user_id.as_str(), since, Some(until))
+                    .filter_map(|r| r.ok()),
+            );
+        }
+    }
+
+    let mut left = BTreeSet::new();
+    if since < until {
+        let sender_user = UserId::parse_with_server_name(
+            registration.sender_localpart.as_str(),
+            services().globals.server_name(),
+        )
+        .map_err(|_| Error::bad_database("Appservice has an invalid sender_localpart."))?;
+
+        for room_id in &rooms {
+            changed.extend(
+                services()
+                    .users
+                    .keys_changed(room_id.as_str(), since, Some(until))
+                    .filter_map(|r| r.ok()),
+            );
+
+            // Users that left since the last transaction
+            for (_, pdu) in services()
+                .rooms
+                .timeline
+                .pdus_after(&sender_user, room_id, PduCount::Normal(since))?
+                .filter_map(|r| r.ok())
+                .take_while(|(count, _)| *count <= PduCount::Normal(until))
+            {
+                if pdu.kind != TimelineEventType::RoomMember {
+                    continue;
+                }
+                let Some(user_id) = pdu
+                    .state_key
+                    .as_deref()
+                    .and_then(|state_key| UserId::parse(state_key).ok())
+                else {
+                    continue;
+                };
+                if users.contains(&user_id) {
+                    continue;
+                }
+
+                let membership = serde_json::from_str::<ExtractMembership>(pdu.content.get())
+                    .map(|content| content.membership);
+                if matches!(
+                    membership,
+                    Ok(MembershipState::Leave | MembershipState::Ban)
+                ) {
+                    left.insert(user_id);
+                }
+            }
+        }
+
+        // Only users that don't share any other room with the users of the appservice
+        left.retain(|user_id| {
+            !services()
+                .rooms
+                .state_cache
+                .rooms_joined(user_id)
+                .filter_map(|r| r.ok())
+                .any(|room_id| rooms.contains(&room_id))
+        });
+    }
+
+    Ok((
+        DeviceLists {
+            changed: changed.difference(&left).cloned().collect(),
+            left: left.into_iter().collect(),
+        },
+        one_time_keys_count,
+    ))
+}
+
+#[derive(Deserialize)]
+struct ExtractMembership {
+    membership: MembershipState,
+}
+
+/// How long to wait before retrying a push destination after `tries` failed transactions
+fn backoff_duration(tries: u32) -> Duration {
+    (Duration::from_secs(5 * 60) * tries * tries).min(Duration::from_secs(60 * 60 * 24))
+}
+
+/// How long to wait before retrying an appservice after `tries` failed transactions. Starts short
+/// because most failures are bridges restarting.
+fn appservice_backoff_duration(tries: u32) -> Duration {
+    (Duration::from_secs(2) * 2_u32.pow(tries.saturating_sub(1).min(10)))
+        .min(Duration::from_secs(10 * 60))
+}
@@ -0,0 +1,135 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::Mutex,
+    time::Duration,
+};
+
+use ruma::{OwnedServerName, ServerName};
+
+/// Outgoing federation requests to one destination since startup
+#[derive(Clone, Default)]
+pub struct DestinationRequestMetrics {
+    /// Responses by HTTP status code
+    pub status_codes: BTreeMap<u16, u64>,
+    /// Requests that got no response, like connection errors and timeouts
+    pub errors: u64,
+    /// Transactions that were sent again after failing
+    pub retries: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl DestinationRequestMetrics {
+    pub fn requests(&self) -> u64 {
+        self.status_codes.values().sum::<u64>() + self.errors
+    }
+
+    pub fn average_latency(&self) -> Option<Duration> {
+        let requests = u32::try_from(self.requests()).unwrap_or(u32::MAX);
+        (requests > 0).then(|| self.total_latency / requests)
+    }
+}
+
+/// Latency, status codes and retries of outgoing federation requests per destination, to tell
+/// apart slow remote servers from a slow server of our own.
+#[derive(Default)]
+pub struct RequestMetrics {
+    destinations: Mutex<HashMap<OwnedServerName, DestinationRequestMetrics>>,
+}
+
+impl RequestMetrics {
+    /// Records a finished request, `status` is None if there was no response.
+    pub fn record_request(&self, destination: &ServerName, status: Option<u16>, latency: Duration) {
+        let mut destinations = self.destinations.lock().unwrap();
+        let metrics = destinations.entry(destination.to_owned()).or_default();
+
+        match status {
+            Some(status) => *metrics.status_codes.entry(status).or_default() += 1,
+            None => metrics.errors += 1,
+        }
+        metrics.total_latency += latency;
+        metrics.max_latency = metrics.max_latency.max(latency);
+    }
+
+    /// Records that a failed transaction to the destination is sent again.
+    pub fn record_retry(&self, destination: &ServerName) {
+        self.destinations
+            .lock()
+            .unwrap()
+            .entry(destination.to_owned())
+            .or_default()
+            .retries += 1;
+    }
+
+    pub fn get(&self, destination: &ServerName) -> Option<DestinationRequestMetrics> {
+        self.destinations.lock().unwrap().get(destination).cloned()
+    }
+
+    pub fn all(&self) -> Vec<(OwnedServerName, DestinationRequestMetrics)> {
+        self.destinations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(destination, metrics)| (destination.clone(), metrics.clone()))
+            .collect()
+    }
+
+    /// Returns the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut destinations = self.all();
+        destinations.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut out = String::new();
+
+        out.push_str("# HELP conduwuit_federation_requests_total Outgoing federation requests\n");
+        out.push_str("# TYPE conduwuit_federation_requests_total counter\n");
+        for (destination, metrics) in &destinations {
+            for (status, count) in &metrics.status_codes {
+                writeln!(
+                    out,
+                    "conduwuit_federation_requests_total{{destination=\"{destination}\",status=\"{status}\"}} {count}"
+                )
+                .unwrap();
+            }
+            if metrics.errors > 0 {
+                writeln!(
+                    out,
+                    "conduwuit_federation_requests_total{{destination=\"{destination}\",status=\"error\"}} {}",
+                    metrics.errors
+                )
+                .unwrap();
+            }
+        }
+
+        out.push_str(
+            "# HELP conduwuit_federation_request_seconds_total Time spent waiting for responses \
+             of other servers\n",
+        );
+        out.push_str("# TYPE conduwuit_federation_request_seconds_total counter\n");
+        for (destination, metrics) in &destinations {
+            writeln!(
+                out,
+                "conduwuit_federation_request_seconds_total{{destination=\"{destination}\"}} {}",
+                metrics.total_latency.as_secs_f64()
+            )
+            .unwrap();
+        }
+
+        out.push_str(
+            "# HELP conduwuit_federation_transaction_retries_total Failed transactions that were \
+             sent again\n",
+        );
+        out.push_str("# TYPE conduwuit_federation_transaction_retries_total counter\n");
+        for (destination, metrics) in &destinations {
+            writeln!(
+                out,
+                "conduwuit_federation_transaction_retries_total{{destination=\"{destination}\"}} {}",
+                metrics.retries
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
@@ -6,7 +6,7 @@ pub use data::Data;
 use ruma::{
     api::client::{
         error::ErrorKind,
-        uiaa::{AuthData, AuthType, Password, UiaaInfo, UserIdentifier},
+        uiaa::{AuthData, AuthType, EmailIdentity, Password, UiaaInfo, UserIdentifier},
     },
     CanonicalJsonValue, DeviceId, UserId,
 };
@@ -80,8 +80,16 @@ impl Service {
                 )
                 .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "User ID is invalid."))?;
 
+                let hash = services().users.password_hash(&user_id)?;
+
+                // Throttled like logins, so a stolen access token can't be used to guess the
+                // password
+                services()
+                    .login_throttle
+                    .check(hash.as_ref().map(|_| &*user_id), None)?;
+
                 // Check if password is correct
-                if let Some(hash) = services().users.password_hash(&user_id)? {
+                if let Some(hash) = hash {
                     let hash_matches = services()
                         .globals
                         .argon
@@ -92,6 +100,7 @@ impl Service {
                         .is_ok();
 
                     if !hash_matches {
+                        services().login_throttle.failed(&user_id);
                         uiaainfo.auth_error = Some(ruma::api::client::error::StandardErrorBody {
                             kind: ErrorKind::Forbidden,
                             message: "Invalid username or password.".to_owned(),
@@ -101,6 +110,7 @@ impl Service {
                 }
 
                 // Password was correct! Let's add it to `completed`
+                services().login_throttle.succeeded(&user_id, None);
                 uiaainfo.completed.push(AuthType::Password);
             }
             AuthData::RegistrationToken(t) => {
@@ -117,6 +127,30 @@ impl Service {
             AuthData::Dummy(_) => {
                 uiaainfo.completed.push(AuthType::Dummy);
             }
+            AuthData::EmailIdentity(EmailIdentity {
+                thirdparty_id_creds,
+                ..
+            }) => {
+                let verified = services().email.use_validated_email(
+                    thirdparty_id_creds.sid.as_str(),
+                    &thirdparty_id_creds.client_secret,
+                    uiaainfo.session.as_deref().expect("session is always set"),
+                );
+
+                if verified.is_some() {
+                    uiaainfo.completed.push(AuthType::EmailIdentity);
+                } else {
+                    uiaainfo.auth_error = Some(ruma::api::client::error::StandardErrorBody {
+                        kind: ErrorKind::ThreepidAuthFailed,
+                        message: "The email address has not been verified yet.".to_owned(),
+                    });
+                    return Ok((false, uiaainfo));
+                }
+            }
+            // m.login.terms has no parameters, the policies were shown in the UiaaInfo params
+            k if k.auth_type() == Some(AuthType::from("m.login.terms")) => {
+                uiaainfo.completed.push(AuthType::from("m.login.terms"));
+            }
             k => error!("type not supported: {:?}", k),
         }
 
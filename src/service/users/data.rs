@@ -1,9 +1,11 @@
+use super::ThreepidBinding;
 use crate::Result;
 use ruma::{
     api::client::{device::Device, filter::FilterDefinition},
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::AnyToDeviceEvent,
     serde::Raw,
+    thirdparty::Medium,
     DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri,
     OwnedUserId, UInt, UserId,
 };
@@ -54,6 +56,29 @@ pub trait Data: Send + Sync {
     /// Sets a new avatar_url or removes it if avatar_url is None.
     fn set_blurhash(&self, user_id: &UserId, blurhash: Option<String>) -> Result<()>;
 
+    /// Returns the version of the terms of service policy the user has accepted, if any.
+    fn accepted_terms_version(&self, user_id: &UserId, policy_id: &str) -> Result<Option<String>>;
+
+    /// Records that the user has accepted the given version of a terms of service policy.
+    fn set_accepted_terms_version(
+        &self,
+        user_id: &UserId,
+        policy_id: &str,
+        version: &str,
+    ) -> Result<()>;
+
+    /// Returns the verified email address of the user, if any.
+    fn email(&self, user_id: &UserId) -> Result<Option<String>>;
+
+    /// Returns the user with the verified email address, if any.
+    fn find_user_by_email(&self, email: &str) -> Result<Option<OwnedUserId>>;
+
+    /// Stores the verified email address of the user.
+    fn set_email(&self, user_id: &UserId, email: &str) -> Result<()>;
+
+    /// Removes the verified email address of the user, so it can be used for another account.
+    fn remove_email(&self, user_id: &UserId) -> Result<()>;
+
     /// Adds a new device to a user.
     fn create_device(
         &self,
@@ -75,6 +100,17 @@ pub trait Data: Send + Sync {
     /// Replaces the access token of one device.
     fn set_token(&self, user_id: &UserId, device_id: &DeviceId, token: &str) -> Result<()>;
 
+    /// Returns when the access token of the device was issued and last used, in milliseconds.
+    fn token_times(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<(u64, u64)>>;
+
+    fn set_token_times(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        issued: u64,
+        last_used: u64,
+    ) -> Result<()>;
+
     fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -129,6 +165,14 @@ pub trait Data: Send + Sync {
         to: Option<u64>,
     ) -> Box<dyn Iterator<Item = Result<OwnedUserId>> + 'a>;
 
+    /// Like `keys_changed`, with the count of each change
+    fn keys_changed_with_counts<'a>(
+        &'a self,
+        user_or_room_id: &str,
+        from: u64,
+        to: Option<u64>,
+    ) -> Box<dyn Iterator<Item = Result<(u64, OwnedUserId)>> + 'a>;
+
     fn mark_device_key_update(&self, user_id: &UserId) -> Result<()>;
 
     fn get_device_keys(
@@ -189,6 +233,9 @@ pub trait Data: Send + Sync {
         until: u64,
     ) -> Result<()>;
 
+    /// Removes the to-device events that were added before `until` and returns how many
+    fn remove_to_device_events_before(&self, until: u64) -> Result<usize>;
+
     fn update_device_metadata(
         &self,
         user_id: &UserId,
@@ -211,4 +258,29 @@ pub trait Data: Send + Sync {
     fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String>;
 
     fn get_filter(&self, user_id: &UserId, filter_id: &str) -> Result<Option<FilterDefinition>>;
+
+    /// Returns the user that is mapped to a subject of the OIDC provider.
+    fn oidc_subject_user(&self, subject: &str) -> Result<Option<OwnedUserId>>;
+
+    fn set_oidc_subject_user(&self, subject: &str, user_id: &UserId) -> Result<()>;
+
+    /// Marks the user as a guest account.
+    fn set_guest(&self, user_id: &UserId) -> Result<()>;
+
+    fn is_guest(&self, user_id: &UserId) -> Result<bool>;
+
+    /// Remembers that the user bound a third party identifier on an identity server.
+    fn add_threepid_binding(&self, user_id: &UserId, binding: &ThreepidBinding) -> Result<()>;
+
+    fn remove_threepid_binding(
+        &self,
+        user_id: &UserId,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<()>;
+
+    fn threepid_bindings<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<ThreepidBinding>> + 'a>;
 }
@@ -3,27 +3,90 @@ use std::{
     collections::{BTreeMap, BTreeSet},
     mem,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 pub use data::Data;
+use lru_cache::LruCache;
 use ruma::{
-    api::client::{
-        device::Device,
-        error::ErrorKind,
-        filter::FilterDefinition,
-        sync::sync_events::{
-            self,
-            v4::{ExtensionsConfig, SyncRequestList},
+    api::{
+        client::{
+            device::Device,
+            error::ErrorKind,
+            filter::FilterDefinition,
+            sync::sync_events::{
+                self,
+                v4::{ExtensionsConfig, SyncRequestList},
+            },
         },
+        federation::query::get_profile_information,
     },
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
-    events::AnyToDeviceEvent,
+    events::{
+        ignored_user_list::IgnoredUserListEvent, AnyToDeviceEvent, GlobalAccountDataEventType,
+    },
     serde::Raw,
-    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri,
-    OwnedRoomId, OwnedUserId, RoomAliasId, UInt, UserId,
+    thirdparty::Medium,
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
+    OwnedDeviceKeyId, OwnedMxcUri, OwnedRoomId, OwnedUserId, RoomAliasId, UInt, UserId,
 };
+use serde::{Deserialize, Serialize};
+
+use tracing::{debug, info, warn};
+
+use crate::{services, utils, Error, Result};
 
-use crate::{services, Error, Result};
+/// A third party identifier that a user bound to their account on an identity server.
+#[derive(Serialize, Deserialize)]
+pub struct ThreepidBinding {
+    pub medium: Medium,
+    pub address: String,
+    /// Server name of the identity server, as sent by the client
+    pub id_server: String,
+    pub added_at: MilliSecondsSinceUnixEpoch,
+}
+
+/// How often the last use of an access token is written to the database
+const TOKEN_USE_RESOLUTION_MS: u64 = 60 * 1000;
+
+/// Global account data type through which users choose which invites they accept
+pub const INVITE_POLICY_EVENT_TYPE: &str = "im.conduwuit.invite_policy";
+
+/// Which invites a user accepts
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvitePolicy {
+    #[default]
+    All,
+    /// Only invites of users that share a room with the user
+    SharedRooms,
+    None,
+}
+
+#[derive(Deserialize)]
+struct InvitePolicyEvent {
+    content: InvitePolicyContent,
+}
+
+#[derive(Deserialize)]
+struct InvitePolicyContent {
+    #[serde(default)]
+    policy: InvitePolicy,
+}
+
+/// Profile of a user on another server
+#[derive(Clone)]
+pub struct RemoteProfile {
+    pub displayname: Option<String>,
+    pub avatar_url: Option<OwnedMxcUri>,
+    pub blurhash: Option<String>,
+}
+
+pub struct CachedRemoteProfile {
+    profile: RemoteProfile,
+    fetched_at: Instant,
+    refreshing: bool,
+}
 
 pub struct SlidingSyncCache {
     lists: BTreeMap<String, SyncRequestList>,
@@ -38,6 +101,11 @@ type DbConnections =
 pub struct Service {
     pub db: &'static dyn Data,
     pub connections: DbConnections,
+    pub remote_profiles: Mutex<LruCache<OwnedUserId, CachedRemoteProfile>>,
+
+    /// Number of local users that count towards the registration user limit, counted on first use
+    /// and kept up to date when passwords are set or accounts are deactivated
+    pub active_local_users: Mutex<Option<usize>>,
 }
 
 impl Service {
@@ -270,10 +338,117 @@ impl Service {
             .is_joined(user_id, &admin_room_id)
     }
 
+    /// Returns true if the recipient has the sender on their ignore list
+    pub fn user_is_ignored(&self, sender_user: &UserId, recipient_user: &UserId) -> Result<bool> {
+        Ok(services()
+            .account_data
+            .get(
+                None,
+                recipient_user,
+                GlobalAccountDataEventType::IgnoredUserList
+                    .to_string()
+                    .into(),
+            )?
+            .map(|event| {
+                serde_json::from_str::<IgnoredUserListEvent>(event.get()).map_err(|e| {
+                    warn!("Invalid account data event in db: {e:?}");
+                    Error::BadDatabase("Invalid account data event in db.")
+                })
+            })
+            .transpose()?
+            .map_or(false, |ignored| {
+                ignored.content.ignored_users.contains_key(sender_user)
+            }))
+    }
+
+    /// Returns which invites the user accepts
+    pub fn invite_policy(&self, user_id: &UserId) -> Result<InvitePolicy> {
+        Ok(services()
+            .account_data
+            .get(None, user_id, INVITE_POLICY_EVENT_TYPE.into())?
+            .and_then(|event| {
+                serde_json::from_str::<InvitePolicyEvent>(event.get())
+                    .map_err(|e| warn!("Invalid invite policy of {user_id}: {e}"))
+                    .ok()
+            })
+            .map(|event| event.content.policy)
+            .unwrap_or_default())
+    }
+
+    /// Checks if the sender may invite the local recipient, based on the invite filtering of the
+    /// server and the invite policy of the recipient
+    pub fn is_invite_allowed(&self, sender_user: &UserId, recipient_user: &UserId) -> Result<bool> {
+        if !self.is_invite_allowed_by_server(sender_user)? {
+            return Ok(false);
+        }
+
+        match self.invite_policy(recipient_user)? {
+            InvitePolicy::All => Ok(true),
+            InvitePolicy::SharedRooms => Ok(services()
+                .rooms
+                .user
+                .get_shared_rooms(vec![sender_user.to_owned(), recipient_user.to_owned()])?
+                .next()
+                .is_some()),
+            InvitePolicy::None => Ok(false),
+        }
+    }
+
+    fn is_invite_allowed_by_server(&self, sender_user: &UserId) -> Result<bool> {
+        let globals = &services().globals;
+        let sender_server = sender_user.server_name();
+        let is_local = sender_server == globals.server_name();
+
+        if globals.block_invites() {
+            return Ok(is_local && self.is_admin(sender_user)?);
+        }
+
+        if globals
+            .invite_sender_denylist_patterns()
+            .is_match(sender_user.as_str())
+        {
+            return Ok(false);
+        }
+
+        if is_local {
+            return Ok(true);
+        }
+
+        if globals
+            .invite_server_denylist()
+            .iter()
+            .any(|server| server == sender_server)
+        {
+            return Ok(false);
+        }
+
+        let allowlist = globals.invite_server_allowlist();
+        Ok(allowlist.is_empty() || allowlist.iter().any(|server| server == sender_server))
+    }
+
     /// Create a new user account on this homeserver.
     pub fn create(&self, user_id: &UserId, password: Option<&str>) -> Result<()> {
-        self.db.set_password(user_id, password)?;
-        Ok(())
+        self.set_password(user_id, password)
+    }
+
+    /// Creates a new local user account, unless the registration user limit has been reached. The
+    /// limit is checked and the account created under the same lock, so concurrent registrations
+    /// can't exceed it.
+    pub fn create_limited(&self, user_id: &UserId, password: Option<&str>) -> Result<()> {
+        let mut active_local_users = self.active_local_users.lock().unwrap();
+
+        if let Some(limit) = services().globals.registration_user_limit() {
+            let count = self.count_active_local_users(&mut active_local_users)?;
+            if count >= limit && !services().appservice.is_appservice_user(user_id)? {
+                info!("Registration user limit of {limit} reached, rejecting registration of {user_id}");
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "This server has reached its maximum number of users.",
+                ));
+            }
+        }
+
+        self.set_password_counted(&mut active_local_users, user_id, password)
     }
 
     /// Returns the number of users registered on this server.
@@ -281,6 +456,71 @@ impl Service {
         self.db.count()
     }
 
+    /// Returns the number of local users that are not deactivated and don't belong to an
+    /// appservice.
+    pub fn active_local_users(&self) -> Result<usize> {
+        self.count_active_local_users(&mut self.active_local_users.lock().unwrap())
+    }
+
+    /// Makes the next use of the active local user count recount all users, e.g. because the
+    /// appservice namespaces changed.
+    pub fn forget_active_local_users(&self) {
+        *self.active_local_users.lock().unwrap() = None;
+    }
+
+    /// Returns the active local user count, counting all users once if it isn't known yet.
+    fn count_active_local_users(&self, active_local_users: &mut Option<usize>) -> Result<usize> {
+        if let Some(count) = *active_local_users {
+            return Ok(count);
+        }
+
+        let server_name = services().globals.server_name();
+        let is_appservice_user = services().appservice.user_matcher()?;
+
+        let mut count = 0;
+        for user_id in self.iter().filter_map(Result::ok) {
+            if user_id.server_name() == server_name
+                && !self.db.is_deactivated(&user_id)?
+                && !is_appservice_user(&user_id)
+            {
+                count += 1;
+            }
+        }
+
+        *active_local_users = Some(count);
+        Ok(count)
+    }
+
+    /// Returns whether the user counts towards the registration user limit.
+    fn is_active_local_user(&self, user_id: &UserId) -> Result<bool> {
+        Ok(user_id.server_name() == services().globals.server_name()
+            && self.db.exists(user_id)?
+            && !self.db.is_deactivated(user_id)?
+            && !services().appservice.is_appservice_user(user_id)?)
+    }
+
+    /// Sets the password and updates the active local user count if it is known.
+    fn set_password_counted(
+        &self,
+        active_local_users: &mut Option<usize>,
+        user_id: &UserId,
+        password: Option<&str>,
+    ) -> Result<()> {
+        let Some(count) = active_local_users else {
+            return self.db.set_password(user_id, password);
+        };
+
+        let was_active = self.is_active_local_user(user_id)?;
+        self.db.set_password(user_id, password)?;
+        match (was_active, self.is_active_local_user(user_id)?) {
+            (false, true) => *count += 1,
+            (true, false) => *count = count.saturating_sub(1),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
     /// Find out which user an access token belongs to.
     pub fn find_from_token(&self, token: &str) -> Result<Option<(OwnedUserId, String)>> {
         self.db.find_from_token(token)
@@ -305,7 +545,15 @@ impl Service {
 
     /// Hash and set the user's password to the Argon2 hash
     pub fn set_password(&self, user_id: &UserId, password: Option<&str>) -> Result<()> {
-        self.db.set_password(user_id, password)
+        if user_id.server_name() != services().globals.server_name() {
+            return self.db.set_password(user_id, password);
+        }
+
+        self.set_password_counted(
+            &mut self.active_local_users.lock().unwrap(),
+            user_id,
+            password,
+        )
     }
 
     /// Returns the displayname of a user on this homeserver.
@@ -346,7 +594,134 @@ impl Service {
         self.db.set_blurhash(user_id, blurhash)
     }
 
+    /// Returns the profile of a user on another server. Profiles are fetched over federation and
+    /// cached for `remote_profile_cache_ttl_s`. After that the cached profile is still returned,
+    /// but refreshed in the background.
+    pub async fn remote_profile(&self, user_id: &UserId) -> Result<RemoteProfile> {
+        let ttl = Duration::from_secs(services().globals.remote_profile_cache_ttl_s());
+
+        if let Some(cached) = self.remote_profiles.lock().unwrap().get_mut(user_id) {
+            if cached.fetched_at.elapsed() > ttl && !cached.refreshing {
+                cached.refreshing = true;
+
+                let user_id = user_id.to_owned();
+                tokio::spawn(async move {
+                    if let Err(e) = services().users.fetch_remote_profile(&user_id).await {
+                        debug!("Failed to refresh profile of {user_id}: {e}");
+                        if let Some(cached) = services()
+                            .users
+                            .remote_profiles
+                            .lock()
+                            .unwrap()
+                            .get_mut(&user_id)
+                        {
+                            cached.refreshing = false;
+                        }
+                    }
+                });
+            }
+
+            return Ok(cached.profile.clone());
+        }
+
+        self.fetch_remote_profile(user_id).await
+    }
+
+    async fn fetch_remote_profile(&self, user_id: &UserId) -> Result<RemoteProfile> {
+        let response = services()
+            .sending
+            .send_federation_request(
+                user_id.server_name(),
+                get_profile_information::v1::Request {
+                    user_id: user_id.to_owned(),
+                    field: None,
+                },
+            )
+            .await?;
+
+        let profile = RemoteProfile {
+            displayname: response.displayname,
+            avatar_url: response.avatar_url,
+            blurhash: response.blurhash,
+        };
+
+        self.remote_profiles.lock().unwrap().insert(
+            user_id.to_owned(),
+            CachedRemoteProfile {
+                profile: profile.clone(),
+                fetched_at: Instant::now(),
+                refreshing: false,
+            },
+        );
+
+        Ok(profile)
+    }
+
+    /// Records that the user has accepted the currently configured version of every terms of
+    /// service policy.
+    pub fn accept_current_terms(&self, user_id: &UserId) -> Result<()> {
+        for (policy_id, policy) in services().globals.terms_of_service() {
+            self.db
+                .set_accepted_terms_version(user_id, policy_id, &policy.version)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if the user has accepted the currently configured version of every terms of
+    /// service policy.
+    pub fn has_accepted_current_terms(&self, user_id: &UserId) -> Result<bool> {
+        for (policy_id, policy) in services().globals.terms_of_service() {
+            if self.db.accepted_terms_version(user_id, policy_id)?.as_ref() != Some(&policy.version)
+            {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Fails with `M_FORBIDDEN` if `terms_of_service_block_messages` is enabled and the user has
+    /// not accepted the current terms yet. Used by every client route that sends events into rooms.
+    pub fn check_current_terms(&self, user_id: &UserId, from_appservice: bool) -> Result<()> {
+        if services().globals.terms_of_service_block_messages()
+            && !from_appservice
+            && !self.has_accepted_current_terms(user_id)?
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "You have to accept the latest terms of service first, with \
+                 POST /_conduwuit/client/v1/terms/accept.",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the verified email address of the user, if any.
+    pub fn email(&self, user_id: &UserId) -> Result<Option<String>> {
+        self.db.email(user_id)
+    }
+
+    /// Returns the user with the verified email address, if any.
+    pub fn find_user_by_email(&self, email: &str) -> Result<Option<OwnedUserId>> {
+        self.db.find_user_by_email(email)
+    }
+
+    /// Stores the verified email address of the user.
+    pub fn set_email(&self, user_id: &UserId, email: &str) -> Result<()> {
+        self.db.set_email(user_id, email)
+    }
+
+    /// Removes the verified email address of the user, so it can be used for another account.
+    pub fn remove_email(&self, user_id: &UserId) -> Result<()> {
+        self.db.remove_email(user_id)
+    }
+
     /// Adds a new device to a user.
+    ///
+    /// If the user already has `max_devices_per_user` devices, the new device is refused or their
+    /// least recently seen devices are removed, depending on `evict_oldest_device_on_limit`.
     pub fn create_device(
         &self,
         user_id: &UserId,
@@ -354,13 +729,41 @@ impl Service {
         token: &str,
         initial_device_display_name: Option<String>,
     ) -> Result<()> {
+        if let Some(max_devices) = services().globals.max_devices_per_user() {
+            let mut devices: Vec<_> = self
+                .all_devices_metadata(user_id)
+                .filter_map(|r| r.ok())
+                .collect();
+
+            if devices.len() >= max_devices {
+                if !services().globals.evict_oldest_device_on_limit() {
+                    return Err(Error::resource_limit_exceeded(
+                        "You have the maximum number of devices allowed on this homeserver, log \
+                         out of another device first.",
+                    ));
+                }
+
+                devices.sort_by_key(|device| device.last_seen_ts);
+                for device in devices.iter().take(devices.len() + 1 - max_devices) {
+                    info!(
+                        "Removing device {} of {} because they reached the device limit",
+                        device.device_id, user_id
+                    );
+                    self.remove_device(user_id, &device.device_id)?;
+                }
+            }
+        }
+
         self.db
-            .create_device(user_id, device_id, token, initial_device_display_name)
+            .create_device(user_id, device_id, token, initial_device_display_name)?;
+        services().appservice.update_device_user(user_id)
     }
 
     /// Removes a device from a user.
     pub fn remove_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
-        self.db.remove_device(user_id, device_id)
+        services().oidc.forget_device(user_id, device_id);
+        self.db.remove_device(user_id, device_id)?;
+        services().appservice.update_device_user(user_id)
     }
 
     /// Returns an iterator over all device ids of this user.
@@ -376,6 +779,34 @@ impl Service {
         self.db.set_token(user_id, device_id, token)
     }
 
+    /// Records a use of the access token of the device. Returns false if the token is past the
+    /// configured idle timeout or lifetime instead.
+    pub fn record_token_use(&self, user_id: &UserId, device_id: &DeviceId) -> Result<bool> {
+        let idle_timeout = services().globals.access_token_idle_timeout_s();
+        let lifetime = services().globals.access_token_lifetime_s();
+        if idle_timeout.is_none() && lifetime.is_none() {
+            return Ok(true);
+        }
+
+        let now = utils::millis_since_unix_epoch();
+        let times = self.db.token_times(user_id, device_id)?;
+        // Tokens from before the times were recorded count as new
+        let (issued, last_used) = times.unwrap_or((now, now));
+
+        let expired = |since: u64, limit_s: Option<u64>| {
+            limit_s.is_some_and(|limit_s| now.saturating_sub(since) > limit_s.saturating_mul(1000))
+        };
+        if expired(issued, lifetime) || expired(last_used, idle_timeout) {
+            return Ok(false);
+        }
+
+        if times.is_none() || now.saturating_sub(last_used) > TOKEN_USE_RESOLUTION_MS {
+            self.db.set_token_times(user_id, device_id, issued, now)?;
+        }
+
+        Ok(true)
+    }
+
     pub fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -453,6 +884,15 @@ impl Service {
         self.db.keys_changed(user_or_room_id, from, to)
     }
 
+    pub fn keys_changed_with_counts<'a>(
+        &'a self,
+        user_or_room_id: &str,
+        from: u64,
+        to: Option<u64>,
+    ) -> impl Iterator<Item = Result<(u64, OwnedUserId)>> + 'a {
+        self.db.keys_changed_with_counts(user_or_room_id, from, to)
+    }
+
     pub fn mark_device_key_update(&self, user_id: &UserId) -> Result<()> {
         self.db.mark_device_key_update(user_id)
     }
@@ -542,6 +982,21 @@ impl Service {
         self.db.remove_to_device_events(user_id, device_id, until)
     }
 
+    /// Removes undelivered to-device events that are older than `to_device_message_max_age_s`.
+    pub fn remove_expired_to_device_events(&self) -> Result<()> {
+        let Some(max_age) = services().globals.to_device_message_max_age_s() else {
+            return Ok(());
+        };
+
+        let until = utils::millis_since_unix_epoch().saturating_sub(max_age.saturating_mul(1000));
+        let removed = self.db.remove_to_device_events_before(until)?;
+        if removed > 0 {
+            debug!("Removed {removed} expired to-device events");
+        }
+
+        Ok(())
+    }
+
     pub fn update_device_metadata(
         &self,
         user_id: &UserId,
@@ -581,7 +1036,10 @@ impl Service {
         // Set the password to "" to indicate a deactivated account. Hashes will never result in an
         // empty string, so the user will not be able to log in again. Systems like changing the
         // password without logging in should check if the account is deactivated.
-        self.db.set_password(user_id, None)?;
+        self.set_password(user_id, None)?;
+
+        // The address can be used to register again
+        self.remove_email(user_id)?;
 
         // TODO: Unhook 3PID
         Ok(())
@@ -599,6 +1057,43 @@ impl Service {
     ) -> Result<Option<FilterDefinition>> {
         self.db.get_filter(user_id, filter_id)
     }
+
+    /// Returns the user that is mapped to a subject of the OIDC provider.
+    pub fn oidc_subject_user(&self, subject: &str) -> Result<Option<OwnedUserId>> {
+        self.db.oidc_subject_user(subject)
+    }
+
+    pub fn set_oidc_subject_user(&self, subject: &str, user_id: &UserId) -> Result<()> {
+        self.db.set_oidc_subject_user(subject, user_id)
+    }
+
+    /// Marks the user as a guest account.
+    pub fn set_guest(&self, user_id: &UserId) -> Result<()> {
+        self.db.set_guest(user_id)
+    }
+
+    /// Check if the user registered as a guest
+    pub fn is_guest(&self, user_id: &UserId) -> Result<bool> {
+        self.db.is_guest(user_id)
+    }
+
+    pub fn add_threepid_binding(&self, user_id: &UserId, binding: &ThreepidBinding) -> Result<()> {
+        self.db.add_threepid_binding(user_id, binding)
+    }
+
+    pub fn remove_threepid_binding(
+        &self,
+        user_id: &UserId,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<()> {
+        self.db.remove_threepid_binding(user_id, medium, address)
+    }
+
+    /// Returns the third party identifiers the user bound on identity servers.
+    pub fn threepid_bindings(&self, user_id: &UserId) -> Result<Vec<ThreepidBinding>> {
+        self.db.threepid_bindings(user_id).collect()
+    }
 }
 
 /// Ensure that a user only sees signatures from themselves and the target user
@@ -0,0 +1,236 @@
+use std::fmt::Write;
+
+/// Content types that browsers can display without running scripts, so they are served inline.
+/// Everything else, notably HTML and SVG, is served as an attachment so it can't execute in the
+/// origin of the homeserver.
+const INLINE_CONTENT_TYPES: &[&str] = &[
+    "image/jpeg",
+    "image/png",
+    "image/gif",
+    "image/webp",
+    "image/apng",
+    "image/avif",
+    "video/mp4",
+    "video/webm",
+    "video/ogg",
+    "video/quicktime",
+    "audio/mpeg",
+    "audio/mp4",
+    "audio/aac",
+    "audio/ogg",
+    "audio/opus",
+    "audio/wav",
+    "audio/x-wav",
+    "audio/webm",
+    "audio/flac",
+    "text/plain",
+    "text/csv",
+    "application/json",
+    "application/ld+json",
+];
+
+/// Returns true if media of the content type may be displayed inline by the browser.
+pub(crate) fn is_inline_content_type(content_type: &str) -> bool {
+    // Ignore parameters like `; charset=utf-8`
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    INLINE_CONTENT_TYPES.contains(&essence.as_str())
+}
+
+/// Returns the content type that media is served with. Only content types that are safe to
+/// display inline are sent as they are, everything else is sent as a generic binary file.
+pub(crate) fn safe_content_type(content_type: Option<&str>) -> String {
+    match content_type {
+        Some(content_type) if is_inline_content_type(content_type) => content_type.to_owned(),
+        _ => "application/octet-stream".to_owned(),
+    }
+}
+
+/// Returns the `Content-Disposition` header value that media is served with: `inline` for safe
+/// content types and `attachment` otherwise, with the sanitized file name if there is one.
+pub(crate) fn make_content_disposition(
+    content_type: Option<&str>,
+    filename: Option<&str>,
+) -> String {
+    let disposition_type = match content_type {
+        Some(content_type) if is_inline_content_type(content_type) => "inline",
+        _ => "attachment",
+    };
+
+    match filename.map(sanitize_filename).filter(|f| !f.is_empty()) {
+        Some(filename) if filename.is_ascii() => {
+            format!("{disposition_type}; filename=\"{filename}\"")
+        }
+        Some(filename) => {
+            // RFC 6266 extended parameter for non-ASCII file names
+            let mut encoded = String::new();
+            for byte in filename.bytes() {
+                if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+                    encoded.push(byte as char);
+                } else {
+                    write!(encoded, "%{byte:02X}").expect("writing to a string never fails");
+                }
+            }
+            format!("{disposition_type}; filename*=utf-8''{encoded}")
+        }
+        None => disposition_type.to_owned(),
+    }
+}
+
+/// Extracts the file name from a `Content-Disposition` header value, if there is one. Follows
+/// RFC 6266: `filename*` takes precedence over `filename`, and quoted values may contain `;`
+/// and escaped characters.
+pub(crate) fn filename_from_content_disposition(content_disposition: &str) -> Option<String> {
+    let mut filename = None;
+    let mut extended_filename = None;
+
+    // Skip the disposition type
+    let (_, mut rest) = content_disposition.split_once(';')?;
+    loop {
+        let Some((name, after_name)) = rest.split_once('=') else {
+            break;
+        };
+        let after_name = after_name.trim_start();
+
+        let (value, remaining) = match after_name.strip_prefix('"') {
+            Some(quoted) => parse_quoted_string(quoted)?,
+            None => {
+                let end = after_name.find(';').unwrap_or(after_name.len());
+                (after_name[..end].to_owned(), &after_name[end..])
+            }
+        };
+
+        let name = name.trim();
+        if name.eq_ignore_ascii_case("filename*") {
+            extended_filename = extended_filename.or_else(|| decode_ext_value(value.trim()));
+        } else if name.eq_ignore_ascii_case("filename") {
+            filename = filename.or_else(|| Some(value.trim().to_owned()));
+        }
+
+        // Skip everything up to the next parameter
+        rest = match remaining.split_once(';') {
+            Some((_, next)) => next,
+            None => break,
+        };
+    }
+
+    extended_filename.or(filename)
+}
+
+/// Parses the rest of a quoted string after the opening quote. Returns the unescaped value and
+/// the remaining input after the closing quote.
+fn parse_quoted_string(input: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &input[i + 1..])),
+            '\\' => value.push(chars.next()?.1),
+            c => value.push(c),
+        }
+    }
+
+    None
+}
+
+/// Decodes an RFC 8187 extended value like `utf-8'en'na%C3%AFve.txt`. Only UTF-8 is supported,
+/// which is the only charset that has to be.
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let encoded = parts.next()?;
+    if !charset.eq_ignore_ascii_case("utf-8") {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut input = encoded.bytes();
+    while let Some(byte) = input.next() {
+        if byte == b'%' {
+            let hex = [input.next()?, input.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+/// Removes everything from a file name that could be used to escape the header parameter or to
+/// write outside of the download directory of the client: path components, quotes, backslashes
+/// and control characters.
+pub(crate) fn sanitize_filename(filename: &str) -> String {
+    let filename = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+
+    let sanitized: String = filename
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .collect();
+
+    sanitized.trim().trim_start_matches('.').to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{filename_from_content_disposition, make_content_disposition};
+
+    #[test]
+    fn ascii_filename_round_trips() {
+        let header = make_content_disposition(Some("image/png"), Some("cat; dog.png"));
+        assert_eq!(header, "inline; filename=\"cat; dog.png\"");
+        assert_eq!(
+            filename_from_content_disposition(&header).as_deref(),
+            Some("cat; dog.png")
+        );
+    }
+
+    #[test]
+    fn non_ascii_filename_round_trips() {
+        let header = make_content_disposition(Some("text/html"), Some("naïve résumé.html"));
+        assert!(header.starts_with("attachment; filename*=utf-8''"));
+        assert_eq!(
+            filename_from_content_disposition(&header).as_deref(),
+            Some("naïve résumé.html")
+        );
+    }
+
+    #[test]
+    fn extended_filename_takes_precedence() {
+        assert_eq!(
+            filename_from_content_disposition(
+                "attachment; filename=\"fallback.txt\"; filename*=UTF-8'en'%E2%82%AC.txt"
+            )
+            .as_deref(),
+            Some("€.txt")
+        );
+    }
+
+    #[test]
+    fn quoted_filename_is_unescaped() {
+        assert_eq!(
+            filename_from_content_disposition(r#"attachment; filename="a \"b\" \\ c.txt"; size=3"#)
+                .as_deref(),
+            Some(r#"a "b" \ c.txt"#)
+        );
+    }
+
+    #[test]
+    fn unquoted_and_missing_filenames() {
+        assert_eq!(
+            filename_from_content_disposition("attachment; filename=plain.txt").as_deref(),
+            Some("plain.txt")
+        );
+        assert_eq!(filename_from_content_disposition("inline"), None);
+        assert_eq!(
+            filename_from_content_disposition("attachment; filename=\"unterminated"),
+            None
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use std::convert::Infallible;
+use std::{convert::Infallible, time::Duration};
 
 use http::StatusCode;
 use ruma::{
@@ -11,7 +11,7 @@ use ruma::{
 use thiserror::Error;
 use tracing::{error, info};
 
-use crate::RumaResponse;
+use crate::{api::request_id, services, RumaResponse};
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -69,6 +69,49 @@ pub enum Error {
     RedactionError(OwnedServerName, ruma::canonical_json::RedactionError),
     #[error("{0} in {1}")]
     InconsistentRoomState(&'static str, ruma::OwnedRoomId),
+    #[error("{0}: {1}")]
+    RejectedPdu(PduRejection, &'static str),
+    /// A write request while maintenance mode is enabled, with the maintenance message
+    #[error("{0}")]
+    Maintenance(String),
+    /// A request of another server that took longer than `incoming_federation_timeout_s`
+    #[error("{0}")]
+    Timeout(&'static str),
+}
+
+/// Why an incoming PDU was not accepted. Included in the error of the PDU in `/send` responses
+/// so the sending server can tell the failures apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PduRejection {
+    UnknownRoom,
+    RoomDisabled,
+    ServerAcl,
+    InvalidSignature,
+    InvalidEvent,
+    AuthFailed,
+    SoftFailed,
+    MissingPrevEvents,
+}
+
+impl PduRejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PduRejection::UnknownRoom => "unknown_room",
+            PduRejection::RoomDisabled => "room_disabled",
+            PduRejection::ServerAcl => "server_acl",
+            PduRejection::InvalidSignature => "invalid_signature",
+            PduRejection::InvalidEvent => "invalid_event",
+            PduRejection::AuthFailed => "auth_failed",
+            PduRejection::SoftFailed => "soft_failed",
+            PduRejection::MissingPrevEvents => "missing_prev_events",
+        }
+    }
+}
+
+impl std::fmt::Display for PduRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 impl Error {
@@ -81,6 +124,19 @@ impl Error {
         error!("BadConfig: {}", message);
         Self::BadConfig(message)
     }
+
+    /// M_RESOURCE_LIMIT_EXCEEDED with the configured admin contact. The error code requires a
+    /// contact, so this is M_FORBIDDEN if there is none.
+    pub fn resource_limit_exceeded(message: &'static str) -> Self {
+        let kind = match &services().globals.config.admin_contact {
+            Some(admin_contact) => ErrorKind::ResourceLimitExceeded {
+                admin_contact: admin_contact.clone(),
+            },
+            None => ErrorKind::Forbidden,
+        };
+
+        Self::BadRequest(kind, message)
+    }
 }
 
 impl Error {
@@ -93,7 +149,7 @@ impl Error {
             let mut error = error.clone();
             error.body = ErrorBody::Standard {
                 kind: Unknown,
-                message: format!("Answer from {origin}: {error}"),
+                message: with_request_id(format!("Answer from {origin}: {error}")),
             };
             return RumaResponse(UiaaResponse::MatrixError(error));
         }
@@ -118,14 +174,31 @@ impl Error {
                     _ => StatusCode::BAD_REQUEST,
                 },
             ),
+            Self::RejectedPdu(reason, _) => match reason {
+                PduRejection::UnknownRoom => (NotFound, StatusCode::NOT_FOUND),
+                PduRejection::RoomDisabled | PduRejection::ServerAcl => {
+                    (Forbidden, StatusCode::FORBIDDEN)
+                }
+                _ => (InvalidParam, StatusCode::BAD_REQUEST),
+            },
+            Self::Maintenance(_) => (
+                LimitExceeded {
+                    retry_after_ms: Some(Duration::from_secs(60)),
+                },
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
             Self::Conflict(_) => (Unknown, StatusCode::CONFLICT),
+            Self::Timeout(_) => (Unknown, StatusCode::SERVICE_UNAVAILABLE),
             _ => (Unknown, StatusCode::INTERNAL_SERVER_ERROR),
         };
 
         info!("Returning an error: {}: {}", status_code, message);
 
         RumaResponse(UiaaResponse::MatrixError(RumaError {
-            body: ErrorBody::Standard { kind, message },
+            body: ErrorBody::Standard {
+                kind,
+                message: with_request_id(message),
+            },
             status_code,
         }))
     }
@@ -147,6 +220,15 @@ impl Error {
     }
 }
 
+/// Adds the ID of the request that is currently handled to an error message, so users can report
+/// errors with a reference to the server logs.
+fn with_request_id(message: String) -> String {
+    match request_id::current() {
+        Some(request_id) => format!("{message} (request ID: {request_id})"),
+        None => message,
+    }
+}
+
 impl From<Infallible> for Error {
     fn from(i: Infallible) -> Self {
         match i {}
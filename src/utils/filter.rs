@@ -0,0 +1,216 @@
+use ruma::{
+    api::client::filter::{EventFormat, FilterDefinition, RoomEventFilter, UrlFilter},
+    serde::Raw,
+};
+use serde_json::{value::to_raw_value, Map, Value};
+
+use crate::PduEvent;
+
+/// Returns true if the event passes the type, sender and `contains_url` checks of the filter.
+pub(crate) fn room_event_filter_matches(filter: &RoomEventFilter, pdu: &PduEvent) -> bool {
+    let kind = pdu.kind.to_string();
+
+    if filter
+        .not_types
+        .iter()
+        .any(|pattern| type_matches(pattern, &kind))
+    {
+        return false;
+    }
+
+    if let Some(types) = &filter.types {
+        if !types.iter().any(|pattern| type_matches(pattern, &kind)) {
+            return false;
+        }
+    }
+
+    if filter.not_senders.contains(&pdu.sender) {
+        return false;
+    }
+
+    if let Some(senders) = &filter.senders {
+        if !senders.contains(&pdu.sender) {
+            return false;
+        }
+    }
+
+    match filter.url_filter {
+        Some(UrlFilter::EventsWithUrl) => has_url(pdu),
+        Some(UrlFilter::EventsWithoutUrl) => !has_url(pdu),
+        _ => true,
+    }
+}
+
+/// Event types in filters may end with a `*` to match all types with that prefix
+fn type_matches(pattern: &str, kind: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => kind.starts_with(prefix),
+        None => pattern == kind,
+    }
+}
+
+fn has_url(pdu: &PduEvent) -> bool {
+    serde_json::from_str::<Map<String, Value>>(pdu.content.get())
+        .map(|content| content.contains_key("url"))
+        .unwrap_or(false)
+}
+
+/// Applies the `event_format` and `event_fields` of the filter to an event that was already
+/// serialized in the client format.
+pub(crate) fn format_event<T>(filter: &FilterDefinition, pdu: &PduEvent, event: Raw<T>) -> Raw<T> {
+    if filter.event_fields.is_none() && !matches!(filter.event_format, EventFormat::Federation) {
+        return event;
+    }
+
+    let value = if matches!(filter.event_format, EventFormat::Federation) {
+        serde_json::to_value(pdu)
+    } else {
+        serde_json::from_str(event.json().get())
+    };
+    let Ok(Value::Object(mut object)) = value else {
+        return event;
+    };
+
+    if let Some(fields) = &filter.event_fields {
+        let paths: Vec<_> = fields.iter().map(|field| split_field(field)).collect();
+        object = retain_fields(object, &paths);
+    }
+
+    Raw::from_json(to_raw_value(&object).expect("json object can be serialized"))
+}
+
+/// Splits a filter field like `content.m\.relates_to` into its path segments. Dots can be escaped
+/// with a backslash.
+fn split_field(field: &str) -> Vec<String> {
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut chars = field.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+            }
+            '.' => segments.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    segments.push(current);
+
+    segments
+}
+
+/// Keeps only the parts of the object that are selected by one of the paths.
+fn retain_fields(object: Map<String, Value>, paths: &[Vec<String>]) -> Map<String, Value> {
+    let mut retained = Map::new();
+
+    for (key, value) in object {
+        let sub_paths: Vec<_> = paths
+            .iter()
+            .filter(|path| path.first() == Some(&key))
+            .map(|path| path[1..].to_vec())
+            .collect();
+
+        if sub_paths.is_empty() {
+            continue;
+        }
+
+        if sub_paths.iter().any(Vec::is_empty) {
+            // The whole field was selected
+            retained.insert(key, value);
+        } else if let Value::Object(inner) = value {
+            let inner = retain_fields(inner, &sub_paths);
+            if !inner.is_empty() {
+                retained.insert(key, Value::Object(inner));
+            }
+        }
+    }
+
+    retained
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn object(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(object) => object,
+            _ => panic!("not an object"),
+        }
+    }
+
+    fn paths(fields: &[&str]) -> Vec<Vec<String>> {
+        fields.iter().map(|field| split_field(field)).collect()
+    }
+
+    #[test]
+    fn splits_fields_at_unescaped_dots() {
+        assert_eq!(split_field("content.body"), ["content", "body"]);
+        assert_eq!(
+            split_field(r"content.m\.relates_to.rel_type"),
+            ["content", "m.relates_to", "rel_type"]
+        );
+        assert_eq!(split_field(r"a\\.b"), [r"a\", "b"]);
+        assert_eq!(split_field("type"), ["type"]);
+    }
+
+    #[test]
+    fn retains_nested_fields() {
+        let event = object(json!({
+            "type": "m.room.message",
+            "sender": "@alice:example.com",
+            "content": {
+                "body": "hello",
+                "msgtype": "m.text",
+                "m.relates_to": { "rel_type": "m.thread", "event_id": "$root" },
+            },
+        }));
+
+        let retained = retain_fields(
+            event,
+            &paths(&["type", "content.body", r"content.m\.relates_to.rel_type"]),
+        );
+
+        assert_eq!(
+            Value::Object(retained),
+            json!({
+                "type": "m.room.message",
+                "content": {
+                    "body": "hello",
+                    "m.relates_to": { "rel_type": "m.thread" },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn ignores_missing_paths() {
+        let event = object(json!({
+            "type": "m.room.message",
+            "content": { "body": "hello" },
+        }));
+
+        let retained = retain_fields(
+            event,
+            &paths(&["state_key", "content.url", "type.nested", "unsigned.age"]),
+        );
+
+        // A path through a non-object value selects nothing, and objects left empty are dropped
+        assert!(retained.is_empty());
+    }
+
+    #[test]
+    fn matches_event_type_wildcards() {
+        assert!(type_matches("m.room.message", "m.room.message"));
+        assert!(!type_matches("m.room.message", "m.room.member"));
+        assert!(type_matches("m.room.*", "m.room.message"));
+        assert!(!type_matches("m.room.*", "m.reaction"));
+        assert!(type_matches("*", "org.example.custom"));
+        assert!(!type_matches("m.room", "m.room.message"));
+    }
+}
@@ -1,4 +1,7 @@
+pub(crate) mod content_disposition;
 pub(crate) mod error;
+pub(crate) mod filter;
+pub(crate) mod sharded_map;
 
 use crate::{services, Error, Result};
 use argon2::{password_hash::SaltString, PasswordHasher};
@@ -13,6 +16,19 @@ use std::{
     str::FromStr,
     time::{SystemTime, UNIX_EPOCH},
 };
+use tracing::error;
+
+/// Runs iteration-heavy database work, like scanning all users, on the blocking thread pool so
+/// the async runtime workers stay free for other requests. Other database operations are not
+/// dispatched, see `KvTree`.
+pub(crate) async fn spawn_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(f).await.map_err(|e| {
+        error!("Blocking database task failed: {e}");
+        Error::BadDatabase("Blocking database task failed.")
+    })?
+}
 
 pub(crate) fn millis_since_unix_epoch() -> u64 {
     SystemTime::now()
@@ -21,6 +37,30 @@ pub(crate) fn millis_since_unix_epoch() -> u64 {
         .as_millis() as u64
 }
 
+/// Formats milliseconds since the unix epoch as `YYYY-MM-DD hh:mm:ss UTC`.
+pub(crate) fn format_timestamp(millis: u64) -> String {
+    let secs = millis / 1000;
+    let (days, time) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
 pub(crate) fn increment(old: Option<&[u8]>) -> Option<Vec<u8>> {
     let number = match old.map(|bytes| bytes.try_into()) {
         Some(Ok(bytes)) => {
@@ -89,6 +129,22 @@ pub fn calculate_hash(keys: &[&[u8]]) -> Vec<u8> {
     hash.as_ref().to_owned()
 }
 
+/// Returns how many bytes of memory the kernel estimates to be available for new allocations.
+/// Only known on Linux.
+pub(crate) fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kib: u64 = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()?;
+
+    Some(kib * 1024)
+}
+
 pub(crate) fn common_elements(
     mut iterators: impl Iterator<Item = impl Iterator<Item = Vec<u8>>>,
     check_order: impl Fn(&[u8], &[u8]) -> Ordering,
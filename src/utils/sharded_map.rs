@@ -0,0 +1,245 @@
+use std::{
+    borrow::Borrow,
+    collections::{
+        hash_map::{Entry, RandomState},
+        HashMap,
+    },
+    hash::{BuildHasher, Hash},
+    sync::RwLock,
+};
+
+/// Number of shards, a power of two so the shard can be picked from the bits of the hash
+const SHARDS: usize = 32;
+
+/// Hash map split into shards that are locked separately, so requests working on different keys
+/// don't wait for each other. Locks are only held for the duration of one method call.
+pub struct ShardedMap<K, V> {
+    shards: Box<[RwLock<HashMap<K, V>>]>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V> ShardedMap<K, V> {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard<Q>(&self, key: &Q) -> &RwLock<HashMap<K, V>>
+    where
+        Q: Hash + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash & (SHARDS - 1)]
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).write().unwrap().insert(key, value)
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    /// Returns the value of the key, inserting the default value first if there is none.
+    pub fn get_or_default<Q>(&self, key: &Q) -> V
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+        V: Clone + Default,
+    {
+        let shard = self.shard(key);
+
+        if let Some(value) = shard.read().unwrap().get(key) {
+            return value.clone();
+        }
+
+        shard
+            .write()
+            .unwrap()
+            .entry(key.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// Runs `f` on the entry of the key while only its shard is locked.
+    pub fn with_entry<R>(&self, key: K, f: impl FnOnce(Entry<'_, K, V>) -> R) -> R {
+        f(self.shard(&key).write().unwrap().entry(key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards
+            .iter()
+            .all(|shard| shard.read().unwrap().is_empty())
+    }
+
+    /// Removes all entries `f` returns false for, the shards are locked one after the other.
+    pub fn retain(&self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        for shard in self.shards.iter() {
+            shard.write().unwrap().retain(&mut f);
+        }
+    }
+
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.write().unwrap().clear();
+        }
+    }
+
+    /// Adds up `f` for all entries without copying them, the shards are locked one after the
+    /// other.
+    pub fn sum_by(&self, f: impl Fn(&K, &V) -> usize) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|(k, v)| f(k, v))
+                    .sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Returns a copy of all entries, the shards are locked one after the other.
+    pub fn to_vec(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+impl<K: Hash + Eq, V> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn insert_get_remove() {
+        let map = ShardedMap::new();
+        assert!(map.is_empty());
+
+        for i in 0..100 {
+            assert_eq!(map.insert(i.to_string(), i), None);
+        }
+        assert_eq!(map.insert("42".to_owned(), 0), Some(42));
+
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.get("42"), Some(0));
+        assert_eq!(map.get("100"), None);
+
+        assert_eq!(map.remove("42"), Some(0));
+        assert_eq!(map.remove("42"), None);
+        assert_eq!(map.len(), 99);
+
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn entries_and_defaults() {
+        let map: ShardedMap<String, usize> = ShardedMap::new();
+
+        assert_eq!(map.get_or_default("a"), 0);
+        assert_eq!(map.len(), 1);
+
+        map.with_entry("a".to_owned(), |entry| *entry.or_default() += 2);
+        map.with_entry("b".to_owned(), |entry| *entry.or_default() += 1);
+        assert_eq!(map.get("a"), Some(2));
+        assert_eq!(map.get_or_default("b"), 1);
+
+        let removed = map.with_entry("b".to_owned(), |entry| match entry {
+            Entry::Occupied(entry) => Some(entry.remove()),
+            Entry::Vacant(_) => None,
+        });
+        assert_eq!(removed, Some(1));
+        assert_eq!(map.get("b"), None);
+    }
+
+    #[test]
+    fn retain_sum_and_copy() {
+        let map = ShardedMap::new();
+        for i in 0..100_usize {
+            map.insert(i, i);
+        }
+
+        map.retain(|k, v| {
+            *v *= 2;
+            k % 2 == 0
+        });
+
+        assert_eq!(map.len(), 50);
+        assert_eq!(
+            map.sum_by(|_, v| *v),
+            (0..100).filter(|i| i % 2 == 0).sum::<usize>() * 2
+        );
+
+        let mut entries = map.to_vec();
+        entries.sort_unstable();
+        assert_eq!(entries.first(), Some(&(0, 0)));
+        assert_eq!(entries.last(), Some(&(98, 196)));
+    }
+
+    #[test]
+    fn concurrent_updates() {
+        let map = Arc::new(ShardedMap::<usize, usize>::new());
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let map = Arc::clone(&map);
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        map.with_entry(i % 10, |entry| *entry.or_default() += 1);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(map.len(), 10);
+        assert!((0..10).all(|i| map.get(&i) == Some(800)));
+    }
+}